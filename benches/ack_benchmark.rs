@@ -45,7 +45,7 @@ fn benchmark_ack_queue(c: &mut Criterion) {
 
         b.iter(|| {
             let mut bench_q = q.clone();
-            black_box(bench_q.pop_for_mtu(1400, 0));
+            black_box(bench_q.pop_for_mtu(1400, 0, usize::MAX));
         })
     });
 
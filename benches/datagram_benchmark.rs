@@ -0,0 +1,76 @@
+use bytes::Bytes;
+use criterion::{BatchSize, Criterion, black_box, criterion_group, criterion_main};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Instant;
+use tokio_raknet::protocol::constants::DatagramFlags;
+use tokio_raknet::protocol::datagram::{Datagram, DatagramPayload};
+use tokio_raknet::protocol::encapsulated_packet::EncapsulatedPacket;
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24};
+use tokio_raknet::session::manager::ManagedSession;
+
+fn single_frame_datagram(seq: u32) -> Datagram {
+    Datagram {
+        header: DatagramHeader {
+            flags: DatagramFlags::VALID,
+            sequence: Sequence24::new(seq),
+        },
+        payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+            header: EncapsulatedPacketHeader {
+                reliability: Reliability::Unreliable,
+                is_split: false,
+                needs_bas: false,
+            },
+            bit_length: 64 << 3,
+            reliable_index: None,
+            sequence_index: None,
+            ordering_index: None,
+            ordering_channel: None,
+            split: None,
+            payload: Bytes::from(vec![0xfe; 64]),
+        }]),
+    }
+}
+
+fn benchmark_single_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handle_single_frame_datagram");
+    let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 19132));
+    let now = Instant::now();
+
+    group.bench_function("collect_vec", |b| {
+        let mut session = ManagedSession::new(peer, 1400, now);
+        let mut seq = 0u32;
+        b.iter_batched(
+            || {
+                seq = seq.wrapping_add(1);
+                single_frame_datagram(seq)
+            },
+            |dgram| black_box(session.handle_datagram(dgram, now).unwrap()),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("callback", |b| {
+        let mut session = ManagedSession::new(peer, 1400, now);
+        let mut seq = 0u32;
+        b.iter_batched(
+            || {
+                seq = seq.wrapping_add(1);
+                single_frame_datagram(seq)
+            },
+            |dgram| {
+                session
+                    .handle_datagram_with(dgram, now, |pkt| {
+                        black_box(pkt);
+                    })
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_single_frame);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+use bytes::{Bytes, BytesMut};
+use criterion::{BatchSize, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use std::time::Instant;
+use tokio_raknet::protocol::constants::DatagramFlags;
+use tokio_raknet::protocol::datagram::{Datagram, DatagramPayload};
+use tokio_raknet::protocol::encapsulated_packet::EncapsulatedPacket;
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24};
+use tokio_raknet::session::Session;
+use tokio_raknet::transport::Message;
+use tokio_raknet::transport::mux::into_received_message;
+
+const MESSAGES: u32 = 1000;
+const PAYLOAD: usize = 1000;
+
+/// One datagram per message, as a relay would read them off the socket.
+fn wire_datagrams() -> Vec<Bytes> {
+    (0..MESSAGES)
+        .map(|i| {
+            let mut payload = vec![0xfe];
+            payload.resize(PAYLOAD, i as u8);
+            let dgram = Datagram {
+                header: DatagramHeader {
+                    flags: DatagramFlags::VALID,
+                    sequence: Sequence24::new(i),
+                },
+                payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+                    header: EncapsulatedPacketHeader {
+                        reliability: Reliability::Reliable,
+                        is_split: false,
+                        needs_bas: false,
+                    },
+                    bit_length: (PAYLOAD as u16) << 3,
+                    reliable_index: Some(Sequence24::new(i)),
+                    sequence_index: None,
+                    ordering_index: None,
+                    ordering_channel: None,
+                    split: None,
+                    payload: Bytes::from(payload),
+                }]),
+            };
+            let mut buf = BytesMut::new();
+            dgram.encode(&mut buf).unwrap();
+            buf.freeze()
+        })
+        .collect()
+}
+
+fn benchmark_relay(c: &mut Criterion) {
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    let datagrams = wire_datagrams();
+    let now = Instant::now();
+
+    // Receive on one session and queue on the other, the way a proxy
+    // forwards each message it reads.
+    group.bench_function("receive_and_forward_1k", |b| {
+        b.iter_batched(
+            || (Session::new(1400), Session::new(1400)),
+            |(mut inbound, mut outbound)| {
+                for bytes in &datagrams {
+                    let dgram = Datagram::decode(&mut bytes.clone()).unwrap();
+                    let DatagramPayload::EncapsulatedPackets(frames) = dgram.payload else {
+                        unreachable!()
+                    };
+                    inbound
+                        .handle_data_datagram_with(dgram.header.sequence, frames, now, |pkt| {
+                            let msg = Message::from(into_received_message(pkt).unwrap());
+                            black_box(outbound.queue_encoded(
+                                msg.buffer,
+                                msg.reliability,
+                                msg.channel,
+                                msg.priority,
+                            ));
+                        })
+                        .unwrap();
+                }
+                (inbound, outbound)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_relay);
+criterion_main!(benches);
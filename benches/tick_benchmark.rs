@@ -0,0 +1,46 @@
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Instant;
+use tokio_raknet::session::manager::ManagedSession;
+
+const IDLE_SESSIONS: u16 = 10_000;
+
+fn idle_sessions(now: Instant) -> Vec<ManagedSession> {
+    (0..IDLE_SESSIONS)
+        .map(|i| {
+            let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 20_000 + i));
+            ManagedSession::new(peer, 1400, now)
+        })
+        .collect()
+}
+
+fn benchmark_idle_tick(c: &mut Criterion) {
+    let mut group = c.benchmark_group("idle_tick_10k");
+    let now = Instant::now();
+
+    group.bench_function("on_tick_every_session", |b| {
+        let mut sessions = idle_sessions(now);
+        b.iter(|| {
+            for s in sessions.iter_mut() {
+                black_box(s.on_tick(now));
+            }
+        })
+    });
+
+    group.bench_function("skip_until_deadline", |b| {
+        let mut sessions = idle_sessions(now);
+        let deadlines: Vec<Instant> = sessions.iter().map(|s| s.next_deadline(now)).collect();
+        b.iter(|| {
+            for (s, at) in sessions.iter_mut().zip(&deadlines) {
+                if *at <= now {
+                    black_box(s.on_tick(now));
+                }
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_idle_tick);
+criterion_main!(benches);
@@ -2,7 +2,8 @@ use mimalloc::MiMalloc;
 use std::error::Error;
 use std::net::SocketAddr;
 use tokio::net::lookup_host;
-use tokio_raknet::transport::{Message, RaknetListener, RaknetStream};
+use tokio_raknet::proxy::{self, RelayOptions};
+use tokio_raknet::transport::{RaknetListener, RaknetStream};
 use tracing::Level;
 use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -43,7 +44,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 }
 
 async fn handle_connection(
-    mut client: RaknetStream,
+    client: RaknetStream,
     target_host: String,
 ) -> Result<(), Box<dyn Error>> {
     let client_addr = client.peer_addr();
@@ -55,65 +56,22 @@ async fn handle_connection(
     let remote_addr = addrs.next().ok_or("Failed to resolve target host")?;
 
     tracing::info!("[{}] Connecting to server {}...", client_addr, remote_addr);
-    let mut server = RaknetStream::connect(remote_addr).await?;
+    let server = RaknetStream::connect(remote_addr).await?;
     tracing::info!("[{}] Connected to server!", client_addr);
 
-    // Split the streams locally to manage concurrent read/writes
-    // Since RaknetStream doesn't support 'split()' yet or Clone, we have to wrap in Arc<Mutex>
-    // OR just use a select loop with &mut.
-    // Using select! loop is cleaner than Arc<Mutex> for this case.
-
-    loop {
-        tokio::select! {
-            // Client -> Server
-            res = client.recv_msg() => {
-                match res {
-                    Some(Ok(packet)) => {
-                        let outbound = Message::new(packet.buffer)
-                            .reliability(packet.reliability)
-                            .channel(packet.channel);
-                        server.send(outbound).await?;
-                    }
-                    Some(Err(e)) => {
-                        tracing::info!("[{}] Client error: {:?}", client_addr, e);
-                        break;
-                    }
-                    None => {
-                        tracing::info!("[{}] Client disconnected", client_addr);
-                        break;
-                    }
-                }
-            }
-
-            // Server -> Client
-            res = server.recv_msg() => {
-                match res {
-                    Some(Ok(packet)) => {
-                        let outbound = Message::new(packet.buffer)
-                            .reliability(packet.reliability)
-                            .channel(packet.channel);
-                        client.send(outbound).await?;
-                    }
-                    Some(Err(e)) => {
-                        tracing::info!("[{}] Server error: {:?}", client_addr, e);
-                        break;
-                    }
-                    None => {
-                        tracing::info!("[{}] Server disconnected", client_addr);
-                        break;
-                    }
-                }
-            }
-        }
-    }
-
-    tracing::info!("[{}] Closing connection...", client_addr);
-    // Send disconnect packet (0x15) to both ends just in case
-    // This ensures the client gets a clean disconnect even if the Listener doesn't detect the drop immediately.
-    let disconnect_msg = Message::new(vec![0x15]);
-    let _ = client.send(disconnect_msg.clone()).await;
-    let _ = server.send(disconnect_msg).await;
-
-    tracing::info!("[{}] Connection closed", client_addr);
+    // Pumps both directions until one side leaves, then disconnects the
+    // other side with the same reason.
+    let result = proxy::relay(client, server, RelayOptions::new()).await;
+
+    tracing::info!(
+        "[{}] Connection closed by {:?} ({:?}): {} messages / {} bytes up, {} messages / {} bytes down",
+        client_addr,
+        result.closed_by,
+        result.reason(),
+        result.a_to_b.messages,
+        result.a_to_b.bytes,
+        result.b_to_a.messages,
+        result.b_to_a.bytes,
+    );
     Ok(())
 }
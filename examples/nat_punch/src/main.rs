@@ -0,0 +1,96 @@
+//! UDP hole punching between two clients behind NATs, then a RakNet
+//! connection over the punched path.
+//!
+//! Everything runs on the in-memory network: two port-restricted NATs and a
+//! public rendezvous socket that tells each peer the other's public address.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::timeout;
+use tokio_raknet::transport::RaknetClient;
+use tokio_raknet::transport::memory::{MemoryNetwork, MemorySocket};
+use tokio_raknet::transport::socket::DatagramSocket;
+
+const PUNCH: &[u8] = b"punch";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let net = MemoryNetwork::new();
+    net.add_nat("192.168.1.10".parse()?, "203.0.113.1".parse()?);
+    net.add_nat("10.0.0.10".parse()?, "198.51.100.7".parse()?);
+
+    let rendezvous = net.bind("192.0.2.1:6000".parse()?)?;
+    let alice = RaknetClient::with_socket(net.bind("192.168.1.10:0".parse()?)?);
+    let bob = RaknetClient::with_socket(net.bind("10.0.0.10:0".parse()?)?);
+
+    // Both register with the rendezvous, which sees their public addresses
+    // and sends each one the other's.
+    let rv = rendezvous.local_addr()?;
+    alice.send_unconnected(rv, b"register").await?;
+    bob.send_unconnected(rv, b"register").await?;
+    introduce(&rendezvous).await?;
+    let bob_public = learn_peer(&alice).await?;
+    let alice_public = learn_peer(&bob).await?;
+    println!("alice sees bob at {bob_public}, bob sees alice at {alice_public}");
+
+    // Simultaneous open: both keep punching until they hear from the other.
+    let (alice, bob) = tokio::try_join!(punch(alice, bob_public), punch(bob, alice_public))?;
+    println!("both NATs are open");
+
+    // Bob accepts on his punched socket, Alice connects on hers.
+    let mut listener = bob.listen(Default::default())?;
+    let (client, server) = tokio::join!(alice.connect(bob_public), listener.accept());
+    let mut client = client?;
+    let mut server = server.ok_or("listener closed")?;
+    println!("connected: bob's side sees alice at {}", server.peer_addr());
+
+    client.send(&b"\xfehello bob"[..]).await?;
+    if let Some(Ok(msg)) = server.recv().await {
+        println!("bob got {:?}", String::from_utf8_lossy(&msg[1..]));
+    }
+    server.send(&b"\xfehello alice"[..]).await?;
+    if let Some(Ok(msg)) = client.recv().await {
+        println!("alice got {:?}", String::from_utf8_lossy(&msg[1..]));
+    }
+    Ok(())
+}
+
+/// Wait for two registrations and send each peer the other's address.
+async fn introduce(rendezvous: &MemorySocket) -> std::io::Result<()> {
+    let mut buf = [0u8; 64];
+    let (_, first) = rendezvous.recv_from(&mut buf).await?;
+    let (_, second) = rendezvous.recv_from(&mut buf).await?;
+    rendezvous
+        .send_to(second.to_string().as_bytes(), first)
+        .await?;
+    rendezvous
+        .send_to(first.to_string().as_bytes(), second)
+        .await?;
+    Ok(())
+}
+
+async fn learn_peer(client: &RaknetClient<MemorySocket>) -> Result<SocketAddr, Box<dyn Error>> {
+    let (bytes, _) = client.recv_unconnected().await?;
+    Ok(std::str::from_utf8(&bytes)?.parse()?)
+}
+
+/// Send punches to `peer` until one of its punches arrives.
+async fn punch(
+    client: RaknetClient<MemorySocket>,
+    peer: SocketAddr,
+) -> std::io::Result<RaknetClient<MemorySocket>> {
+    loop {
+        client.send_unconnected(peer, PUNCH).await?;
+        match timeout(Duration::from_millis(100), client.recv_unconnected()).await {
+            Ok(Ok((bytes, from))) if from == peer && bytes == PUNCH => {
+                // One more, in case ours arrived before the peer's NAT opened.
+                client.send_unconnected(peer, PUNCH).await?;
+                return Ok(client);
+            }
+            Ok(Err(e)) => return Err(e),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Print the session events in a pcap written by `Capture::pcap`.
+//!
+//! Usage: `replay <capture.pcap> <local port>`. Datagrams addressed to the
+//! local port count as inbound, everything else as outbound.
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio_raknet::analysis::replay;
+use tokio_raknet::transport::capture::Direction;
+
+const PCAP_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+const UDP_HEADER_LEN: usize = 8;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(path), Some(port)) = (args.next(), args.next()) else {
+        return Err("usage: replay <capture.pcap> <local port>".into());
+    };
+    let port: u16 = port.parse()?;
+    let file = std::fs::read(path)?;
+
+    let start = Instant::now();
+    let datagrams = records(&file)?.into_iter().map(|(ts, dst_port, bytes)| {
+        let direction = if dst_port == port {
+            Direction::Inbound
+        } else {
+            Direction::Outbound
+        };
+        (direction, start + ts, bytes)
+    });
+
+    for event in replay(datagrams) {
+        let at = event.at.duration_since(start);
+        println!(
+            "{:>10.3}ms {:?} {:?}",
+            at.as_secs_f64() * 1000.0,
+            event.direction,
+            event.kind
+        );
+    }
+    Ok(())
+}
+
+/// A UDP payload with its offset from the first record and destination port.
+type Record = (Duration, u16, Bytes);
+
+fn records(file: &[u8]) -> Result<Vec<Record>, Box<dyn Error>> {
+    let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+    if file.len() < PCAP_HEADER_LEN || u32_at(0) != 0xa1b2_c3d4 {
+        return Err("not a little-endian microsecond pcap".into());
+    }
+
+    let mut out = Vec::new();
+    let mut first = None;
+    let mut at = PCAP_HEADER_LEN;
+    while at + RECORD_HEADER_LEN <= file.len() {
+        let ts = Duration::new(u32_at(at) as u64, u32_at(at + 4) * 1000);
+        let len = u32_at(at + 8) as usize;
+        at += RECORD_HEADER_LEN;
+        let Some(packet) = file.get(at..at + len) else {
+            return Err("truncated record".into());
+        };
+        at += len;
+
+        let ip_len = match packet.first().map(|b| b >> 4) {
+            Some(4) => ((packet[0] & 0x0f) as usize) * 4,
+            Some(6) => 40,
+            _ => continue,
+        };
+        let Some(udp) = packet
+            .get(ip_len..)
+            .filter(|udp| udp.len() >= UDP_HEADER_LEN)
+        else {
+            continue;
+        };
+        let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+        let first = *first.get_or_insert(ts);
+        out.push((
+            ts.saturating_sub(first),
+            dst_port,
+            Bytes::copy_from_slice(&udp[UDP_HEADER_LEN..]),
+        ));
+    }
+    Ok(out)
+}
@@ -0,0 +1,501 @@
+//! Offline analysis of captured RakNet traffic.
+//!
+//! `replay` takes the raw datagrams of one connection, as recorded by a
+//! `Capture` or pulled out of a pcap, and runs them through a fresh pair of
+//! `ManagedSession`s: one receives what the capturing side received, the
+//! other what it sent. Nothing is sent and no timers run, so the same input
+//! always gives the same events. What comes out is a timeline of deliveries,
+//! retransmissions, ordering stalls, ACK progress and state changes, in the
+//! order the datagrams were captured.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::protocol::ack::SequenceRange;
+use crate::protocol::constants::{DatagramFlags, MAXIMUM_MTU_SIZE};
+use crate::protocol::datagram::{Datagram, DatagramPayload};
+use crate::protocol::reliability::Reliability;
+use crate::protocol::types::Sequence24;
+use crate::session::manager::{ConnectionState, ManagedSession, SessionConfig, SessionRole};
+use crate::transport::capture::Direction;
+
+/// Something that happened to the traffic going one way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionEvent {
+    /// Capture time of the datagram that caused it.
+    pub at: Instant,
+    /// Which way the traffic it is about was going, as seen by the side
+    /// that captured it.
+    pub direction: Direction,
+    pub kind: SessionEventKind,
+}
+
+/// What happened. Sequence numbers are datagram sequence numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionEventKind {
+    /// An offline (unconnected) packet, e.g. a ping or a handshake request.
+    Offline { id: u8 },
+    /// The receiving session released a packet to the layer above.
+    Delivered {
+        id: u8,
+        len: usize,
+        reliability: Reliability,
+        channel: Option<u8>,
+    },
+    /// Datagram `sequence` carries reliable frames at or below the newest
+    /// one seen so far: a resend of something lost or unacknowledged, or a
+    /// late arrival.
+    Retransmit { sequence: u32 },
+    /// The receiver reported `start..=end` missing.
+    LossReported { start: u32, end: u32 },
+    /// An ACK came back; `in_flight_bytes` of reliable payload are still
+    /// unacknowledged.
+    WindowChanged { in_flight_bytes: usize },
+    /// Ordered data arrived ahead of a gap and is being held back.
+    OrderingStalled { buffered_bytes: usize },
+    /// The gap was filled and nothing is held back any more.
+    OrderingResumed,
+    /// The receiving session changed state.
+    StateChanged {
+        from: ConnectionState,
+        to: ConnectionState,
+    },
+    /// The datagram, or a frame in it, could not be decoded.
+    Undecodable,
+}
+
+/// Decode the datagrams of one connection into events.
+///
+/// `Inbound` datagrams were received by the capturing side, `Outbound` ones
+/// sent by it. A capture that contains the offline handshake tells which side
+/// is the client; one that starts mid-connection is taken to be from the
+/// server, with the connection already established.
+pub fn replay(
+    datagrams: impl IntoIterator<Item = (Direction, Instant, Bytes)>,
+) -> Vec<SessionEvent> {
+    let datagrams: Vec<_> = datagrams.into_iter().collect();
+    let Some(&(_, start, _)) = datagrams.first() else {
+        return Vec::new();
+    };
+    // Whoever sends OpenConnectionRequest1/2 is the client.
+    let handshake = datagrams
+        .iter()
+        .find(|(_, _, bytes)| matches!(bytes.first(), Some(0x05 | 0x07)));
+    let local_is_client = matches!(handshake, Some((Direction::Outbound, _, _)));
+
+    let mut replay = Replay {
+        // Receives what the capturing side received.
+        inbound: Receiver::new(!local_is_client, start),
+        // Receives what the capturing side sent.
+        outbound: Receiver::new(local_is_client, start),
+        events: Vec::new(),
+    };
+    for receiver in [&mut replay.inbound, &mut replay.outbound] {
+        if handshake.is_none() {
+            receiver.session.assume_connected();
+        } else if receiver.session.config().role == SessionRole::Client {
+            // Only the state matters; the queued `ConnectionRequest` is
+            // never built.
+            let _ = receiver.session.start_client_handshake(0, start, false);
+        }
+        receiver.state = receiver.session.state();
+    }
+    for (direction, at, bytes) in datagrams {
+        replay.datagram(direction, at, bytes);
+    }
+    replay.events
+}
+
+/// The session receiving one direction of traffic, plus what the replay
+/// last saw of it.
+struct Receiver {
+    session: ManagedSession,
+    state: ConnectionState,
+    reorder_bytes: usize,
+    newest_reliable: Option<Sequence24>,
+    /// Reliable indexes carried by each datagram not yet ACKed or NAKed.
+    datagrams: HashMap<u32, Vec<Sequence24>>,
+    /// Payload size of each reliable frame not yet ACKed.
+    unacked: HashMap<Sequence24, usize>,
+}
+
+impl Receiver {
+    fn new(server: bool, now: Instant) -> Self {
+        let config = SessionConfig {
+            role: if server {
+                SessionRole::Server
+            } else {
+                SessionRole::Client
+            },
+            ..Default::default()
+        };
+        let peer = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let session = ManagedSession::with_config(peer, MAXIMUM_MTU_SIZE as usize, now, config);
+        Self {
+            state: session.state(),
+            session,
+            reorder_bytes: 0,
+            newest_reliable: None,
+            datagrams: HashMap::new(),
+            unacked: HashMap::new(),
+        }
+    }
+
+    /// Tracked datagrams covered by `ranges`.
+    fn covered(&self, ranges: &[SequenceRange]) -> Vec<u32> {
+        self.datagrams
+            .keys()
+            .copied()
+            .filter(|&seq| {
+                let seq = Sequence24::new(seq);
+                ranges
+                    .iter()
+                    .any(|r| r.start.distance_to(seq) <= r.start.distance_to(r.end))
+            })
+            .collect()
+    }
+}
+
+struct Replay {
+    inbound: Receiver,
+    outbound: Receiver,
+    events: Vec<SessionEvent>,
+}
+
+impl Replay {
+    fn receiver(&mut self, direction: Direction) -> &mut Receiver {
+        match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        }
+    }
+
+    fn datagram(&mut self, direction: Direction, at: Instant, bytes: Bytes) {
+        let push = |events: &mut Vec<SessionEvent>, direction, kind| {
+            events.push(SessionEvent {
+                at,
+                direction,
+                kind,
+            })
+        };
+
+        let Some(&first) = bytes.first() else {
+            return;
+        };
+        if !DatagramFlags::from_bits_truncate(first).contains(DatagramFlags::VALID) {
+            push(
+                &mut self.events,
+                direction,
+                SessionEventKind::Offline { id: first },
+            );
+            return;
+        }
+        let Ok(dgram) = Datagram::decode(&mut bytes.clone()) else {
+            push(&mut self.events, direction, SessionEventKind::Undecodable);
+            return;
+        };
+
+        // ACKs and NAKs are about the traffic going the other way.
+        let data_direction = match direction {
+            Direction::Inbound => Direction::Outbound,
+            Direction::Outbound => Direction::Inbound,
+        };
+        match dgram.payload {
+            DatagramPayload::Ack(payload) => {
+                let receiver = self.receiver(data_direction);
+                let before = receiver.unacked.len();
+                for seq in receiver.covered(&payload.ranges) {
+                    for ridx in receiver.datagrams.remove(&seq).unwrap_or_default() {
+                        receiver.unacked.remove(&ridx);
+                    }
+                }
+                if receiver.unacked.len() != before {
+                    let in_flight_bytes = receiver.unacked.values().sum();
+                    push(
+                        &mut self.events,
+                        data_direction,
+                        SessionEventKind::WindowChanged { in_flight_bytes },
+                    );
+                }
+            }
+            DatagramPayload::Nak(payload) => {
+                // The sender resends NAKed frames under new sequence numbers.
+                let receiver = self.receiver(data_direction);
+                for seq in receiver.covered(&payload.ranges) {
+                    receiver.datagrams.remove(&seq);
+                }
+                for range in payload.ranges {
+                    push(
+                        &mut self.events,
+                        data_direction,
+                        SessionEventKind::LossReported {
+                            start: range.start.value(),
+                            end: range.end.value(),
+                        },
+                    );
+                }
+            }
+            DatagramPayload::EncapsulatedPackets(ref frames) => {
+                let sequence = dgram.header.sequence.value();
+                let receiver = self.receiver(direction);
+                let mut retransmit = false;
+                let mut reliable = Vec::new();
+                for frame in frames {
+                    let Some(ridx) = frame.reliable_index else {
+                        continue;
+                    };
+                    match receiver.newest_reliable {
+                        Some(newest) if ridx <= newest => retransmit = true,
+                        _ => receiver.newest_reliable = Some(ridx),
+                    }
+                    receiver.unacked.entry(ridx).or_insert(frame.payload.len());
+                    reliable.push(ridx);
+                }
+                if !reliable.is_empty() {
+                    receiver.datagrams.insert(sequence, reliable);
+                }
+
+                let mut delivered = Vec::new();
+                let res = receiver.session.handle_datagram_with(dgram, at, |pkt| {
+                    let mut buf = BytesMut::new();
+                    let len = pkt.packet.encode(&mut buf).map(|_| buf.len()).unwrap_or(0);
+                    delivered.push(SessionEventKind::Delivered {
+                        id: pkt.packet.id(),
+                        len,
+                        reliability: pkt.reliability,
+                        channel: pkt.ordering_channel,
+                    });
+                });
+
+                let reorder_bytes = receiver.session.stats().memory_usage().reorder_bytes;
+                let ordering = match (receiver.reorder_bytes, reorder_bytes) {
+                    (0, 0) => None,
+                    (0, buffered_bytes) => {
+                        Some(SessionEventKind::OrderingStalled { buffered_bytes })
+                    }
+                    (_, 0) => Some(SessionEventKind::OrderingResumed),
+                    _ => None,
+                };
+                receiver.reorder_bytes = reorder_bytes;
+
+                let state = receiver.session.state();
+                let from = std::mem::replace(&mut receiver.state, state);
+
+                if retransmit {
+                    push(
+                        &mut self.events,
+                        direction,
+                        SessionEventKind::Retransmit { sequence },
+                    );
+                }
+                if res.is_err() {
+                    push(&mut self.events, direction, SessionEventKind::Undecodable);
+                }
+                for kind in delivered {
+                    push(&mut self.events, direction, kind);
+                }
+                if let Some(kind) = ordering {
+                    push(&mut self.events, direction, kind);
+                }
+                if from != state {
+                    push(
+                        &mut self.events,
+                        direction,
+                        SessionEventKind::StateChanged { from, to: state },
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory::{MemoryNetwork, SimulatedLink};
+    use crate::transport::{Capture, RaknetListener, RaknetListenerConfig, RaknetStream};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+    use tokio::time::{sleep, timeout};
+
+    type Captured = Arc<Mutex<Vec<(Direction, SystemTime, Bytes)>>>;
+
+    fn recorder() -> (Capture, Captured) {
+        let captured = Captured::default();
+        let sink = captured.clone();
+        let capture = Capture::new(move |dgram| {
+            sink.lock().unwrap().push((
+                dgram.direction,
+                dgram.timestamp,
+                Bytes::copy_from_slice(dgram.bytes),
+            ));
+        });
+        (capture, captured)
+    }
+
+    /// Replay with capture timestamps mapped onto `Instant`s.
+    fn replay_captured(captured: &Captured) -> Vec<SessionEvent> {
+        let captured = captured.lock().unwrap().clone();
+        let base = Instant::now();
+        let origin = captured
+            .first()
+            .map(|c| c.1)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        replay(captured.into_iter().map(|(direction, ts, bytes)| {
+            let offset = ts.duration_since(origin).unwrap_or_default();
+            (direction, base + offset, bytes)
+        }))
+    }
+
+    fn user_data(events: &[SessionEvent], direction: Direction) -> Vec<usize> {
+        events
+            .iter()
+            .filter(|e| e.direction == direction)
+            .filter_map(|e| match e.kind {
+                SessionEventKind::Delivered { id: 0xfe, len, .. } => Some(len),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replaying_a_server_capture_matches_what_was_delivered() {
+        let net = MemoryNetwork::new();
+        let (capture, captured) = recorder();
+        let config = RaknetListenerConfig {
+            capture: Some(capture),
+            ..Default::default()
+        };
+        let mut listener = RaknetListener::with_socket(
+            net.bind("10.0.0.1:19132".parse().unwrap()).unwrap(),
+            config,
+        )
+        .unwrap();
+        let (client, server) = tokio::join!(
+            RaknetStream::connect_on(
+                net.bind_any().unwrap(),
+                listener.local_addr(),
+                Default::default()
+            ),
+            listener.accept()
+        );
+        let (client, mut server) = (client.unwrap(), server.unwrap());
+        // Lose a stretch of the client's traffic so some of it is resent.
+        net.set_link(
+            client.local_addr(),
+            listener.local_addr(),
+            SimulatedLink::new().loss(1.0),
+        );
+
+        for i in 0..20u8 {
+            client.send(vec![0xfe, i]).await.unwrap();
+            if i == 9 {
+                sleep(Duration::from_millis(50)).await;
+                net.set_link(
+                    client.local_addr(),
+                    listener.local_addr(),
+                    SimulatedLink::new(),
+                );
+            }
+        }
+        for _ in 0..20 {
+            timeout(Duration::from_secs(5), server.recv())
+                .await
+                .unwrap();
+        }
+        server.send(&b"\xfereply"[..]).await.unwrap();
+        sleep(Duration::from_secs(1)).await;
+
+        let events = replay_captured(&captured);
+        assert_eq!(user_data(&events, Direction::Inbound), vec![2; 20]);
+        assert_eq!(user_data(&events, Direction::Outbound), vec![6]);
+        assert!(events.iter().any(|e| e.direction == Direction::Inbound
+            && e.kind == SessionEventKind::Offline { id: 0x05 }));
+        let connected = |direction| {
+            events.iter().any(|e| {
+                e.direction == direction
+                    && e.kind
+                        == SessionEventKind::StateChanged {
+                            from: ConnectionState::OnlineHandshake,
+                            to: ConnectionState::Connected,
+                        }
+            })
+        };
+        assert!(connected(Direction::Inbound) && connected(Direction::Outbound));
+
+        let inbound = |f: fn(&SessionEventKind) -> bool| {
+            events
+                .iter()
+                .any(|e| e.direction == Direction::Inbound && f(&e.kind))
+        };
+        assert!(inbound(|k| matches!(
+            k,
+            SessionEventKind::LossReported { .. }
+        )));
+        assert!(inbound(|k| matches!(
+            k,
+            SessionEventKind::OrderingStalled { .. }
+        )));
+        assert!(inbound(|k| matches!(k, SessionEventKind::OrderingResumed)));
+        assert!(inbound(|k| matches!(
+            k,
+            SessionEventKind::Retransmit { .. }
+        )));
+        assert!(inbound(|k| matches!(
+            k,
+            SessionEventKind::WindowChanged { in_flight_bytes: 0 }
+        )));
+    }
+
+    #[test]
+    fn a_capture_without_the_handshake_is_taken_as_established() {
+        let dgram = Datagram {
+            header: crate::protocol::types::DatagramHeader {
+                flags: DatagramFlags::VALID,
+                sequence: crate::protocol::types::Sequence24::new(7),
+            },
+            payload: DatagramPayload::EncapsulatedPackets(vec![
+                crate::protocol::encapsulated_packet::EncapsulatedPacket {
+                    header: crate::protocol::types::EncapsulatedPacketHeader {
+                        reliability: Reliability::Reliable,
+                        is_split: false,
+                        needs_bas: false,
+                    },
+                    bit_length: 16,
+                    reliable_index: Some(crate::protocol::types::Sequence24::new(0)),
+                    sequence_index: None,
+                    ordering_index: None,
+                    ordering_channel: None,
+                    split: None,
+                    payload: Bytes::from_static(b"\xfe\x01"),
+                },
+            ]),
+        };
+        let mut buf = BytesMut::new();
+        dgram.encode(&mut buf).unwrap();
+        let bytes = buf.freeze();
+        let now = Instant::now();
+
+        let events = replay([
+            (Direction::Inbound, now, bytes.clone()),
+            (Direction::Inbound, now, bytes),
+        ]);
+        let kinds: Vec<_> = events.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                SessionEventKind::Delivered {
+                    id: 0xfe,
+                    len: 2,
+                    reliability: Reliability::Reliable,
+                    channel: None,
+                },
+                SessionEventKind::Retransmit { sequence: 7 },
+            ]
+        );
+    }
+}
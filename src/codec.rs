@@ -0,0 +1,537 @@
+//! Adapters for [`tokio_util::codec`] (requires the `codec` feature).
+//!
+//! * [`DatagramCodec`] encodes and decodes raw [`Datagram`]s, for running a
+//!   session layer or a test harness of your own over
+//!   [`UdpFramed`](tokio_util::udp::UdpFramed).
+//! * [`RaknetMessageCodec`] frames user payloads (packet ID + body) with a
+//!   length prefix, e.g. to record messages to a file or carry them over TCP.
+//! * [`RaknetIo`] turns a connection into an `AsyncRead + AsyncWrite` byte
+//!   stream, so an application protocol of your own can run on it through
+//!   [`Framed`](tokio_util::codec::Framed).
+//!
+//! Sniffing datagrams off a UDP socket:
+//!
+//! ```rust,no_run
+//! use futures::StreamExt;
+//! use tokio::net::UdpSocket;
+//! use tokio_raknet::codec::DatagramCodec;
+//! use tokio_util::udp::UdpFramed;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let socket = UdpSocket::bind("0.0.0.0:19133").await?;
+//! let mut frames = UdpFramed::new(socket, DatagramCodec::new());
+//! while let Some(frame) = frames.next().await {
+//!     match frame {
+//!         Ok((datagram, from)) => println!("{from}: seq {:?}", datagram.header.sequence),
+//!         Err(e) => println!("not a datagram: {e}"),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A length-delimited protocol over a connection:
+//!
+//! ```rust,no_run
+//! use futures::{SinkExt, StreamExt};
+//! use tokio_raknet::RaknetStream;
+//! use tokio_raknet::codec::RaknetIo;
+//! use tokio_util::codec::{Framed, LengthDelimitedCodec};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let stream = RaknetStream::connect("127.0.0.1:19132".parse()?).await?;
+//! let mut framed = Framed::new(RaknetIo::new(stream, 0xfe), LengthDelimitedCodec::new());
+//! framed.send("hello".into()).await?;
+//! let reply = framed.next().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::sync::PollSender;
+
+use crate::RaknetError;
+use crate::protocol::constants::DEFAULT_MAX_FRAMES_PER_DATAGRAM;
+use crate::protocol::datagram::Datagram;
+use crate::protocol::state::DisconnectReason;
+use crate::transport::stream::RaknetSender;
+use crate::transport::{Message, Outbound, RaknetStream};
+
+/// Encodes and decodes one RakNet datagram per UDP frame.
+#[derive(Debug, Clone)]
+pub struct DatagramCodec {
+    max_frames: usize,
+}
+
+impl Default for DatagramCodec {
+    fn default() -> Self {
+        Self {
+            max_frames: DEFAULT_MAX_FRAMES_PER_DATAGRAM,
+        }
+    }
+}
+
+impl DatagramCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encapsulated frames accepted in one datagram; more is a decode error.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+}
+
+impl Decoder for DatagramCodec {
+    type Item = Datagram;
+    type Error = RaknetError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Datagram>, RaknetError> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        // UDP hands us exactly one datagram; whatever it holds is consumed.
+        let mut frame = src.split().freeze();
+        Ok(Some(Datagram::decode_with_limit(
+            &mut frame,
+            self.max_frames,
+        )?))
+    }
+}
+
+impl Encoder<Datagram> for DatagramCodec {
+    type Error = RaknetError;
+
+    fn encode(&mut self, item: Datagram, dst: &mut BytesMut) -> Result<(), RaknetError> {
+        Encoder::<&Datagram>::encode(self, &item, dst)
+    }
+}
+
+impl Encoder<&Datagram> for DatagramCodec {
+    type Error = RaknetError;
+
+    fn encode(&mut self, item: &Datagram, dst: &mut BytesMut) -> Result<(), RaknetError> {
+        item.encode(dst)?;
+        Ok(())
+    }
+}
+
+/// Frames user payloads with a big-endian `u32` length prefix.
+///
+/// Only the payload is framed; reliability, channel and priority stay with
+/// whoever sends the decoded [`Message`] on, which gets the `Message::new`
+/// defaults.
+#[derive(Debug, Clone)]
+pub struct RaknetMessageCodec {
+    max_length: usize,
+}
+
+impl Default for RaknetMessageCodec {
+    fn default() -> Self {
+        Self {
+            max_length: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl RaknetMessageCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Longest payload encoded or decoded; longer is `MessageTooLarge`.
+    pub fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    fn check(&self, size: usize) -> Result<(), RaknetError> {
+        if size > self.max_length || u32::try_from(size).is_err() {
+            return Err(RaknetError::MessageTooLarge {
+                size,
+                max: self.max_length,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for RaknetMessageCodec {
+    type Item = Message;
+    type Error = RaknetError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, RaknetError> {
+        let Some(prefix) = src.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        self.check(len)?;
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        Ok(Some(Message::new(src.split_to(len).freeze())))
+    }
+}
+
+impl Encoder<Message> for RaknetMessageCodec {
+    type Error = RaknetError;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), RaknetError> {
+        let len = item.buffer.len();
+        self.check(len)?;
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        dst.put_slice(&item.buffer);
+        Ok(())
+    }
+}
+
+/// Largest write sent as one message: fits a single datagram at the minimum
+/// MTU, after IP/UDP, datagram and reliable-ordered frame headers plus the
+/// packet ID.
+const MAX_WRITE: usize = 512;
+
+/// A connection as a byte stream.
+///
+/// Writes are sent as `ReliableOrdered` messages on channel 0, each starting
+/// with the packet ID given to [`RaknetIo::new`]; reads strip the first byte
+/// of every received message and yield the rest in order. Both ends must
+/// therefore agree on using the connection this way.
+///
+/// The peer disconnecting normally reads as end of file; any other reason is
+/// an error. Shutting down only stops writes; the connection stays up until
+/// the stream is dropped or taken back with [`into_inner`](Self::into_inner).
+pub struct RaknetIo {
+    stream: RaknetStream,
+    outbound: RaknetSender,
+    tx: PollSender<Outbound>,
+    id: u8,
+    /// Received bytes not yet read.
+    unread: Bytes,
+}
+
+impl RaknetIo {
+    pub fn new(stream: RaknetStream, id: u8) -> Self {
+        let outbound = stream.sender();
+        let tx = PollSender::new(outbound.channel());
+        Self {
+            stream,
+            outbound,
+            tx,
+            id,
+            unread: Bytes::new(),
+        }
+    }
+
+    /// Returns the connection. Bytes received but not yet read are lost.
+    pub fn into_inner(self) -> RaknetStream {
+        self.stream
+    }
+}
+
+impl AsyncRead for RaknetIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.unread.is_empty() {
+            match ready!(this.stream.poll_recv(cx)) {
+                Some(Ok(msg)) => this.unread = msg.buffer.slice(1..),
+                None
+                | Some(Err(
+                    RaknetError::ConnectionClosed
+                    | RaknetError::Disconnected(
+                        DisconnectReason::Disconnected | DisconnectReason::ClosedByRemotePeer,
+                    ),
+                )) => return Poll::Ready(Ok(())),
+                Some(Err(e)) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, e)));
+                }
+            }
+        }
+        let n = this.unread.len().min(buf.remaining());
+        buf.put_slice(&this.unread.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RaknetIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        if ready!(this.tx.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = buf.len().min(MAX_WRITE);
+        let mut payload = BytesMut::with_capacity(n + 1);
+        payload.put_u8(this.id);
+        payload.put_slice(&buf[..n]);
+        let msg = this
+            .outbound
+            .message(Message::new(payload.freeze()))
+            .expect("channel 0 exists")
+            .expect("payload starts with the packet ID");
+        if let Err(e) = this.outbound.admit(&msg) {
+            this.tx.abort_send();
+            return Poll::Ready(Err(io::Error::other(e)));
+        }
+        if this.tx.send_item(msg.into()).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Writes are handed to the muxer as they happen; there is nothing to
+    /// flush here.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::constants::DatagramFlags;
+    use crate::protocol::datagram::DatagramPayload;
+    use crate::protocol::encapsulated_packet::EncapsulatedPacket;
+    use crate::protocol::reliability::Reliability;
+    use crate::protocol::types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24};
+    use crate::transport::memory::MemoryNetwork;
+    use crate::transport::{RaknetListener, RaknetListenerConfig, RaknetStreamConfig};
+    use futures::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+    use tokio_util::udp::UdpFramed;
+
+    const WAIT: Duration = Duration::from_secs(60);
+
+    fn datagram(seq: u32, payload: &'static [u8]) -> Datagram {
+        Datagram {
+            header: DatagramHeader {
+                flags: DatagramFlags::VALID,
+                sequence: Sequence24::new(seq),
+            },
+            payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+                header: EncapsulatedPacketHeader {
+                    reliability: Reliability::Unreliable,
+                    is_split: false,
+                    needs_bas: false,
+                },
+                bit_length: (payload.len() << 3) as u16,
+                reliable_index: None,
+                sequence_index: None,
+                ordering_index: None,
+                ordering_channel: None,
+                split: None,
+                payload: Bytes::from_static(payload),
+            }]),
+        }
+    }
+
+    fn encoded(d: &Datagram) -> BytesMut {
+        let mut buf = BytesMut::new();
+        d.encode(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn datagram_codec_round_trips() {
+        let original = datagram(7, b"\xfehello");
+        let mut codec = DatagramCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(&original, &mut buf).unwrap();
+        assert_eq!(buf, encoded(&original));
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(encoded(&decoded), encoded(&original));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn datagram_codec_enforces_the_frame_limit() {
+        let mut codec = DatagramCodec::new().max_frames(0);
+        let mut buf = encoded(&datagram(1, b"\xfe"));
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(RaknetError::Decode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn datagrams_cross_udp_framed() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to = b.local_addr().unwrap();
+        let mut a = UdpFramed::new(a, DatagramCodec::new());
+        let mut b = UdpFramed::new(b, DatagramCodec::new());
+
+        for seq in 0..3 {
+            a.send((datagram(seq, b"\xfeping"), to)).await.unwrap();
+        }
+        for seq in 0..3 {
+            let (d, _) = timeout(WAIT, b.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(d.header.sequence, Sequence24::new(seq));
+        }
+    }
+
+    #[test]
+    fn message_codec_round_trips_and_waits_for_whole_frames() {
+        let mut codec = RaknetMessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Message::new(&b"\xfeone"[..]), &mut buf)
+            .unwrap();
+        codec
+            .encode(Message::new(&b"\x86two!"[..]), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 4]);
+
+        let mut partial = buf.split_to(6);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let mut buf = partial;
+
+        let one = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&one.buffer[..], b"\xfeone");
+        assert_eq!(one.reliability, Reliability::ReliableOrdered);
+        let two = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&two.buffer[..], b"\x86two!");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn message_codec_rejects_oversized_frames() {
+        let mut codec = RaknetMessageCodec::new().max_length(4);
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            codec.encode(Message::new(vec![0xfe; 5]), &mut buf),
+            Err(RaknetError::MessageTooLarge { size: 5, max: 4 })
+        ));
+
+        // A huge prefix is refused before anything is buffered for it.
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xff][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(RaknetError::MessageTooLarge { .. })
+        ));
+    }
+
+    async fn pair() -> (RaknetStream, RaknetStream, RaknetListener) {
+        let net = MemoryNetwork::new();
+        let socket = net.bind("10.0.0.1:19132".parse().unwrap()).unwrap();
+        let mut listener =
+            RaknetListener::with_socket(socket, RaknetListenerConfig::default()).unwrap();
+        let (client, server) = tokio::join!(
+            RaknetStream::connect_on(
+                net.bind_any().unwrap(),
+                listener.local_addr(),
+                RaknetStreamConfig::default()
+            ),
+            listener.accept()
+        );
+        (client.unwrap(), server.unwrap(), listener)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn framed_protocol_runs_over_a_connection() {
+        let (client, server, _listener) = pair().await;
+        let mut client = Framed::new(RaknetIo::new(client, 0xfe), LengthDelimitedCodec::new());
+        let mut server = Framed::new(RaknetIo::new(server, 0xfe), LengthDelimitedCodec::new());
+
+        // Larger than one write, so it spans several messages.
+        let big: Bytes = (0..5000u32).map(|i| i as u8).collect();
+        client.send(Bytes::from_static(b"small")).await.unwrap();
+        client.send(big.clone()).await.unwrap();
+
+        assert_eq!(
+            &timeout(WAIT, server.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap()[..],
+            b"small"
+        );
+        assert_eq!(
+            timeout(WAIT, server.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap(),
+            big
+        );
+
+        server.send(Bytes::from_static(b"reply")).await.unwrap();
+        assert_eq!(
+            &timeout(WAIT, client.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap()[..],
+            b"reply"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_disconnect_reads_as_eof() {
+        let (client, server, _listener) = pair().await;
+        let mut client = RaknetIo::new(client, 0xfe);
+
+        let mut server = RaknetIo::new(server, 0xfe);
+        server.write_all(b"bye").await.unwrap();
+        // The stream's own disconnect queues behind its writes, unlike
+        // `RaknetListener::disconnect`, which goes ahead of them.
+        let closing = server
+            .into_inner()
+            .disconnect(DisconnectReason::Disconnected);
+
+        let mut read = Vec::new();
+        let (closed, eof) = tokio::join!(closing, timeout(WAIT, client.read_to_end(&mut read)));
+        closed.unwrap();
+        eof.unwrap().unwrap();
+        assert_eq!(read, b"bye");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abnormal_disconnect_is_an_error() {
+        let (client, server, listener) = pair().await;
+        let mut client = RaknetIo::new(client, 0xfe);
+        listener
+            .disconnect(server.peer_addr(), DisconnectReason::BadPacket)
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = timeout(WAIT, client.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
@@ -1,31 +1,320 @@
+use std::fmt;
+use std::time::Duration;
+
 use thiserror::Error;
 
+use crate::protocol::packet::{DecodeError, EncodeError};
 use crate::protocol::state::DisconnectReason;
+use crate::transport::Message;
 
+/// Errors surfaced by connections, listeners and the connect handshake.
+///
+/// Each variant has one meaning across the crate:
+///
+/// * Failures while connecting are `HandshakeFailed`, `Timeout`,
+///   `ServerFull`, `Banned` or `IncompatibleProtocol`, depending on how the
+///   server answered (or didn't).
+/// * An established connection ends with `Disconnected(reason)`, whether the
+///   peer sent the reason, we did, or the session timed out
+///   (`DisconnectReason::TimedOut`).
+/// * `QueueFull` is about the channel into the muxer, `SendQueueFull` about
+///   a session's outgoing queue hitting its `send_queue_limit`.
+/// * `InvalidChannel` turns away a message for an ordering channel the
+///   protocol doesn't have; nothing was queued.
+/// * `InvalidConfig` is returned by `connect` when the configuration could
+///   never work; nothing was sent.
+/// * `Shutdown` means the `CancellationToken` from the listener or client
+///   config was cancelled and the muxer wound the connection down.
+/// * `UnknownPeer` means a listener was asked to act on a session it
+///   doesn't have.
+/// * `ConnectionClosed` means the handle outlived its muxer task: the session
+///   is already gone and there is nothing more to report.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum RaknetError {
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("handshake failed during {phase}")]
+    HandshakeFailed {
+        phase: HandshakePhase,
+        #[source]
+        cause: HandshakeFailure,
+    },
+    #[error("timed out during {phase}")]
+    Timeout { phase: HandshakePhase },
+    #[error("disconnected: {0:?}")]
+    Disconnected(DisconnectReason),
+    #[error("server full")]
+    ServerFull,
+    #[error("banned by the server")]
+    Banned,
+    /// The server with `server_guid` speaks RakNet protocol `theirs`; retry
+    /// with `RaknetStreamConfig::protocol_version` set to it if supported.
+    #[error("incompatible protocol version: we speak {ours}, the server speaks {theirs}")]
+    IncompatibleProtocol {
+        ours: u8,
+        theirs: u8,
+        server_guid: u64,
+    },
+    #[error("send queue full")]
+    QueueFull,
+    #[error("message of {size} bytes exceeds the limit of {max}")]
+    MessageTooLarge { size: usize, max: usize },
+    #[error("message lost")]
+    MessageLost,
+    /// Ordering channels run from 0 to `MAXIMUM_ORDERING_CHANNELS - 1`.
+    #[error("ordering channel {channel} out of range")]
+    InvalidChannel { channel: u8 },
+    #[error("session send queue full")]
+    SendQueueFull,
     #[error("packet decode error: {0}")]
-    Decode(#[from] crate::protocol::packet::DecodeError),
+    Decode(#[from] DecodeError),
     #[error("packet encode error: {0}")]
-    Encode(#[from] crate::protocol::packet::EncodeError),
-    #[error("connection request failed")]
-    ConnectionRequestFailed,
-    #[error("already connected")]
-    AlreadyConnected,
-    #[error("incompatible protocol version")]
-    IncompatibleProtocolVersion,
-    #[error("ip recently connected")]
-    IpRecentlyConnected,
-    #[error("connection banned")]
-    ConnectionBanned,
-    #[error("server full")]
-    ServerFull, // NoFreeIncomingConnections
-    #[error("connection aborted")]
-    ConnectionAborted,
+    Encode(#[from] EncodeError),
+    #[error("invalid config: {0}")]
+    InvalidConfig(#[from] ConfigError),
+    #[error("shut down")]
+    Shutdown,
+    #[error("no session with that peer")]
+    UnknownPeer,
+    /// `RaknetStream::reconnect` on a stream from `accept`, which has no
+    /// server to connect back to.
+    #[error("only streams from connect can reconnect")]
+    NotReconnectable,
+    /// `RaknetStream::start_send` without room reserved by a
+    /// `poll_send_ready` that returned ready; a bug in the caller, not a
+    /// full queue.
+    #[error("start_send without a reservation from poll_send_ready")]
+    SendNotReserved,
     #[error("connection closed")]
     ConnectionClosed,
+}
+
+impl RaknetError {
+    /// Whether the connection (or listener) this came from is gone.
+    ///
+    /// Non-fatal errors are about a single message; the connection can keep
+    /// being used.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            RaknetError::QueueFull
+                | RaknetError::MessageTooLarge { .. }
+                | RaknetError::MessageLost
+                | RaknetError::InvalidChannel { .. }
+                | RaknetError::SendQueueFull
+                | RaknetError::UnknownPeer
+                | RaknetError::NotReconnectable
+                | RaknetError::SendNotReserved
+                | RaknetError::Decode(_)
+                | RaknetError::Encode(_)
+        )
+    }
+}
+
+/// Why `try_send` handed a message back instead of queueing it.
+#[derive(Error, Debug)]
+pub enum TrySendError {
+    /// The outbound queue is at capacity; the muxer hasn't caught up.
+    #[error("send queue full")]
+    Full(Message),
+    /// The session is gone.
+    #[error("connection closed")]
+    Closed(Message),
+    /// The message's ordering channel is out of range.
+    #[error("ordering channel {} out of range", .0.channel)]
+    InvalidChannel(Message),
+}
+
+impl TrySendError {
+    /// The message that was not sent.
+    pub fn into_inner(self) -> Message {
+        match self {
+            TrySendError::Full(msg)
+            | TrySendError::Closed(msg)
+            | TrySendError::InvalidChannel(msg) => msg,
+        }
+    }
+}
+
+impl From<TrySendError> for RaknetError {
+    fn from(err: TrySendError) -> Self {
+        match err {
+            TrySendError::Full(_) => RaknetError::QueueFull,
+            TrySendError::Closed(_) => RaknetError::ConnectionClosed,
+            TrySendError::InvalidChannel(msg) => RaknetError::InvalidChannel {
+                channel: msg.channel,
+            },
+        }
+    }
+}
+
+/// Why `recv_timeout` returned without a message.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum RecvTimeoutError {
+    /// Nothing arrived in time. The connection may still be up, and a
+    /// message arriving later is kept for the next receive.
+    #[error("timed out waiting for a message")]
+    TimedOut,
+    /// The connection ended with `reason`.
     #[error("disconnected: {0:?}")]
     Disconnected(DisconnectReason),
+    /// The connection ended some other way, e.g. `RaknetError::Shutdown`.
+    #[error(transparent)]
+    Closed(RaknetError),
+}
+
+impl From<RaknetError> for RecvTimeoutError {
+    fn from(err: RaknetError) -> Self {
+        match err {
+            RaknetError::Disconnected(reason) => RecvTimeoutError::Disconnected(reason),
+            err => RecvTimeoutError::Closed(err),
+        }
+    }
+}
+
+/// Why `transport::ping` got no answer.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum PingError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    /// No pong arrived in time.
+    #[error("timed out waiting for a pong")]
+    Timeout,
+    /// The server answered with a pong that doesn't parse, e.g. one with
+    /// the wrong magic.
+    #[error("bad pong: {0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// A step of the client's connect handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HandshakePhase {
+    /// `OpenConnectionRequest1`/`Reply1`: finding a server and an MTU.
+    MtuDiscovery,
+    /// `OpenConnectionRequest2`/`Reply2`: reserving a session on the server.
+    OpenConnection,
+    /// `ConnectionRequest`/`ConnectionRequestAccepted` over the reliable layer.
+    ConnectionRequest,
+}
+
+impl fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandshakePhase::MtuDiscovery => "mtu discovery",
+            HandshakePhase::OpenConnection => "open connection",
+            HandshakePhase::ConnectionRequest => "connection request",
+        })
+    }
+}
+
+/// Why the server turned a handshake down.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HandshakeFailure {
+    /// The server answered `ConnectionRequestFailed`.
+    #[error("connection request rejected")]
+    Rejected,
+    /// The server with `server_guid` still has a session for our address or
+    /// GUID, usually one that disconnected moments ago. See
+    /// `HandshakeRetry::recently_connected_delay` for retrying it.
+    #[error("already connected to server {server_guid:#x}")]
+    AlreadyConnected { server_guid: u64 },
+    /// Too many recent attempts from our IP; retry later.
+    #[error("ip recently connected")]
+    IpRecentlyConnected,
+    /// The server proposed an MTU below the protocol minimum.
+    #[error("server proposed MTU {0}, below the minimum")]
+    MtuTooSmall(u16),
+    /// The server settled on an MTU above the one the client asked for.
+    #[error("server settled on MTU {0}, above the one requested")]
+    MtuTooLarge(u16),
+}
+
+/// A `SessionConfig` that was rejected before any session was created.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// Sessions would time out before ever being marked stale.
+    #[error("session_stale ({stale:?}) must be shorter than session_timeout ({timeout:?})")]
+    StaleNotBeforeTimeout { stale: Duration, timeout: Duration },
+    /// Idle sessions would time out between two keepalive pings.
+    #[error(
+        "keepalive_interval ({keepalive:?}) must be shorter than session_timeout ({timeout:?})"
+    )]
+    KeepaliveNotBeforeTimeout {
+        keepalive: Duration,
+        timeout: Duration,
+    },
+    /// Old name for `KeepaliveNotBeforeTimeout`; no longer returned.
+    #[deprecated(note = "use `KeepaliveNotBeforeTimeout`")]
+    #[error("ping_interval ({ping:?}) must be shorter than session_timeout ({timeout:?})")]
+    PingNotBeforeTimeout { ping: Duration, timeout: Duration },
+    /// A numeric setting outside the range the protocol can work with.
+    #[error("{field} must be between {min} and {max}, got {value}")]
+    OutOfRange {
+        field: &'static str,
+        value: u64,
+        min: u64,
+        max: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn only_per_message_errors_are_recoverable() {
+        assert!(!RaknetError::QueueFull.is_fatal());
+        assert!(!RaknetError::MessageTooLarge { size: 2, max: 1 }.is_fatal());
+        assert!(!RaknetError::MessageLost.is_fatal());
+        assert!(!RaknetError::SendQueueFull.is_fatal());
+        assert!(!RaknetError::UnknownPeer.is_fatal());
+        assert!(!RaknetError::NotReconnectable.is_fatal());
+        assert!(!RaknetError::SendNotReserved.is_fatal());
+        assert!(!RaknetError::Decode(DecodeError::UnexpectedEof).is_fatal());
+
+        assert!(RaknetError::ConnectionClosed.is_fatal());
+        assert!(RaknetError::Shutdown.is_fatal());
+        assert!(
+            RaknetError::from(ConfigError::OutOfRange {
+                field: "reliable_window",
+                value: 0,
+                min: 1,
+                max: 2,
+            })
+            .is_fatal()
+        );
+        assert!(RaknetError::Disconnected(DisconnectReason::TimedOut).is_fatal());
+        assert!(
+            RaknetError::Timeout {
+                phase: HandshakePhase::MtuDiscovery
+            }
+            .is_fatal()
+        );
+        assert!(RaknetError::Io(std::io::ErrorKind::NetworkDown.into()).is_fatal());
+    }
+
+    #[test]
+    fn source_exposes_the_underlying_error() {
+        let err = RaknetError::HandshakeFailed {
+            phase: HandshakePhase::OpenConnection,
+            cause: HandshakeFailure::AlreadyConnected { server_guid: 1 },
+        };
+        assert_eq!(err.to_string(), "handshake failed during open connection");
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "already connected to server 0x1"
+        );
+
+        let err = RaknetError::from(std::io::Error::other("boom"));
+        assert_eq!(err.source().unwrap().to_string(), "boom");
+
+        assert!(RaknetError::ServerFull.source().is_none());
+    }
 }
@@ -43,11 +43,20 @@
 //!     Ok(())
 //! }
 //! ```
-#[doc = include_str!("../README.md")]
+#[cfg(any(test, feature = "analysis"))]
+pub mod analysis;
+#[cfg(any(test, feature = "codec"))]
+pub mod codec;
 pub mod error;
 pub mod protocol;
+pub mod proxy;
 pub mod session;
 pub mod transport;
 
-pub use error::RaknetError;
-pub use transport::{RaknetListener, RaknetStream};
+/// Runs the README examples as doctests.
+#[cfg(doctest)]
+#[doc = include_str!("../README.md")]
+pub struct ReadmeDoctests;
+
+pub use error::{PingError, RaknetError, RecvTimeoutError, TrySendError};
+pub use transport::{PingResponse, RaknetListener, RaknetStream, ping};
@@ -2,7 +2,7 @@ use bytes::{Buf, BufMut};
 
 use crate::protocol::{
     constants::MAX_ACK_SEQUENCES,
-    packet::{DecodeError, RaknetEncodable},
+    packet::{DecodeError, EncodeError, RaknetEncodable},
     types::Sequence24,
 };
 
@@ -13,10 +13,7 @@ pub struct SequenceRange {
 }
 
 impl RaknetEncodable for SequenceRange {
-    fn encode_raknet(
-        &self,
-        dst: &mut impl BufMut,
-    ) -> Result<(), crate::protocol::packet::EncodeError> {
+    fn encode_raknet(&self, dst: &mut impl BufMut) -> Result<(), EncodeError> {
         let singleton = self.start == self.end;
         singleton.encode_raknet(dst)?;
         self.start.encode_raknet(dst)?;
@@ -87,11 +84,12 @@ pub struct AckNackPayload {
 }
 
 impl RaknetEncodable for AckNackPayload {
-    fn encode_raknet(
-        &self,
-        dst: &mut impl BufMut,
-    ) -> Result<(), crate::protocol::packet::EncodeError> {
+    fn encode_raknet(&self, dst: &mut impl BufMut) -> Result<(), EncodeError> {
         let total_records: usize = self.ranges.iter().map(|r| r.record_count()).sum();
+        // Anything longer is refused on decode, ours included.
+        if total_records > MAX_ACK_SEQUENCES as usize {
+            return Err(EncodeError::TooManyAckRecords(total_records));
+        }
         dst.put_u16(total_records as u16);
 
         for r in &self.ranges {
@@ -186,4 +184,23 @@ mod tests {
         assert_eq!(buf.as_ref(), expected);
         Ok(())
     }
+
+    #[test]
+    fn refuses_more_records_than_a_decoder_accepts() {
+        let single = |i: u32| SequenceRange {
+            start: Sequence24::new(i * 2),
+            end: Sequence24::new(i * 2),
+        };
+        let mut payload = AckNackPayload {
+            ranges: (0..MAX_ACK_SEQUENCES as u32).map(single).collect(),
+        };
+        let mut buf = BytesMut::new();
+        payload.encode_raknet(&mut buf).unwrap();
+
+        payload.ranges.push(single(MAX_ACK_SEQUENCES as u32));
+        assert!(matches!(
+            payload.encode_raknet(&mut BytesMut::new()),
+            Err(EncodeError::TooManyAckRecords(n)) if n == MAX_ACK_SEQUENCES as usize + 1
+        ));
+    }
 }
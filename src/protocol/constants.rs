@@ -22,8 +22,8 @@ pub const RAKNET_PROTOCOL_VERSION: u8 = 11;
 pub const MINIMUM_MTU_SIZE: u16 = 576;
 /// Maximum supported MTU as used during negotiation.
 pub const MAXIMUM_MTU_SIZE: u16 = 1400;
-/// Candidate MTU sizes to probe, from most used by client.
-pub const MTU_SIZES: &[u16] = &[1200, MAXIMUM_MTU_SIZE, MINIMUM_MTU_SIZE];
+/// MTU sizes a client probes in turn, largest first, until the server answers.
+pub const MTU_SIZES: &[u16] = &[MAXIMUM_MTU_SIZE, 1200, MINIMUM_MTU_SIZE];
 
 const _: () = {
     assert!(
@@ -40,6 +40,15 @@ pub const MAXIMUM_ENCAPSULATED_HEADER_SIZE: usize = 28;
 
 pub const MAX_ACK_SEQUENCES: u16 = 8192;
 
+/// Default cap on encapsulated frames accepted in one datagram. Real traffic
+/// stays far below this; a hostile peer can fit hundreds of empty frames
+/// into a single MTU.
+pub const DEFAULT_MAX_FRAMES_PER_DATAGRAM: usize = 512;
+
+/// Default cap on payload bytes buffered across splits being reassembled:
+/// room for one message of the default 8192 parts at `MAXIMUM_MTU_SIZE`.
+pub const DEFAULT_MAX_SPLIT_BYTES: usize = 16 * 1024 * 1024;
+
 /// Size of a UDP header on the wire.
 pub const UDP_HEADER_SIZE: usize = 8;
 /// Size of an IPv4 header without options. We pessimistically subtract this
@@ -63,6 +72,16 @@ pub const SESSION_TIMEOUT: Duration = Duration::from_millis(10000);
 /// Time after which a session is considered stale due to no activity.
 pub const SESSION_STALE: Duration = Duration::from_millis(5000);
 
+/// Time without sending anything after which a connected session pings its
+/// peer, leaving room for the pong to arrive before the session goes stale.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(2500);
+
+/// Default lower bound on the retransmission timeout.
+pub const MIN_RTO: Duration = Duration::from_millis(50);
+
+/// Default upper bound on the retransmission timeout.
+pub const MAX_RTO: Duration = Duration::from_millis(CC_MAXIMUM_THRESHOLD as u64);
+
 // === Packet limits / congestion ===
 
 /// Maximum number of datagram packets each address can send within one RakNet tick (10ms).
@@ -160,5 +179,6 @@ mod tests {
     fn mtu_bounds_are_consistent() {
         assert!(MTU_SIZES.contains(&MAXIMUM_MTU_SIZE));
         assert!(MTU_SIZES.contains(&MINIMUM_MTU_SIZE));
+        assert!(MTU_SIZES.windows(2).all(|pair| pair[0] > pair[1]));
     }
 }
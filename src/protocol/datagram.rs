@@ -2,7 +2,7 @@ use bytes::{Buf, BufMut};
 
 use crate::protocol::{
     ack::AckNackPayload,
-    constants::{DatagramFlags, RAKNET_DATAGRAM_HEADER_SIZE},
+    constants::{DEFAULT_MAX_FRAMES_PER_DATAGRAM, DatagramFlags, RAKNET_DATAGRAM_HEADER_SIZE},
     encapsulated_packet::EncapsulatedPacket,
     packet::{DecodeError, EncodeError, RaknetEncodable},
     types::{DatagramHeader, Sequence24},
@@ -49,8 +49,15 @@ impl Datagram {
     /// Decodes a datagram from the source buffer.
     ///
     /// This function reads the header flags to determine how to
-    /// parse the rest of the buffer.
+    /// parse the rest of the buffer. At most
+    /// `DEFAULT_MAX_FRAMES_PER_DATAGRAM` encapsulated frames are accepted.
     pub fn decode(src: &mut impl Buf) -> Result<Self, DecodeError> {
+        Self::decode_with_limit(src, DEFAULT_MAX_FRAMES_PER_DATAGRAM)
+    }
+
+    /// Like `decode`, but fails with `DecodeError::TooManyFrames` once more
+    /// than `max_frames` encapsulated frames have been read.
+    pub fn decode_with_limit(src: &mut impl Buf, max_frames: usize) -> Result<Self, DecodeError> {
         if !src.has_remaining() {
             return Err(DecodeError::UnexpectedEof);
         }
@@ -85,6 +92,9 @@ impl Datagram {
 
         let mut packets = Vec::new();
         while src.has_remaining() {
+            if packets.len() == max_frames {
+                return Err(DecodeError::TooManyFrames(max_frames));
+            }
             packets.push(EncapsulatedPacket::decode_raknet(src)?);
         }
         Ok(Self {
@@ -106,3 +116,40 @@ impl Datagram {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    /// Data datagram header followed by `frames` empty unreliable frames
+    /// (3 bytes each: flags + zero bit length).
+    fn empty_frames(frames: usize) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(DatagramFlags::VALID.bits());
+        buf.put_slice(&[0, 0, 0]);
+        for _ in 0..frames {
+            buf.put_slice(&[0x00, 0x00, 0x00]);
+        }
+        buf
+    }
+
+    #[test]
+    fn frame_count_is_capped() {
+        let ok = Datagram::decode_with_limit(&mut empty_frames(8).freeze(), 8).unwrap();
+        assert!(matches!(
+            ok.payload,
+            DatagramPayload::EncapsulatedPackets(ref p) if p.len() == 8
+        ));
+
+        let err = Datagram::decode_with_limit(&mut empty_frames(9).freeze(), 8).unwrap_err();
+        assert!(matches!(err, DecodeError::TooManyFrames(8)));
+    }
+
+    #[test]
+    fn default_cap_rejects_mtu_full_of_empty_frames() {
+        let err = Datagram::decode(&mut empty_frames(DEFAULT_MAX_FRAMES_PER_DATAGRAM + 1).freeze())
+            .unwrap_err();
+        assert!(matches!(err, DecodeError::TooManyFrames(_)));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::protocol::{
+    constants::MAXIMUM_ORDERING_CHANNELS,
     packet::{DecodeError, RaknetEncodable},
     types::{EncapsulatedPacketHeader, Sequence24},
 };
@@ -137,6 +138,9 @@ impl RaknetEncodable for EncapsulatedPacket {
         let (ordering_index, ordering_channel) = if rel.is_ordered() || rel.is_sequenced() {
             let idx = Sequence24::decode_raknet(src)?;
             let ch = u8::decode_raknet(src)?;
+            if ch >= MAXIMUM_ORDERING_CHANNELS {
+                return Err(DecodeError::InvalidOrderingChannel(ch));
+            }
             (Some(idx), Some(ch))
         } else {
             (None, None)
@@ -170,3 +174,56 @@ impl RaknetEncodable for EncapsulatedPacket {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::reliability::Reliability;
+    use bytes::BytesMut;
+
+    fn frame_on_channel(reliability: Reliability, channel: u8) -> BytesMut {
+        let pkt = EncapsulatedPacket {
+            header: EncapsulatedPacketHeader {
+                reliability,
+                is_split: false,
+                needs_bas: false,
+            },
+            bit_length: 3 << 3,
+            reliable_index: Some(Sequence24::new(1)),
+            sequence_index: Some(Sequence24::new(1)),
+            ordering_index: Some(Sequence24::new(2)),
+            ordering_channel: Some(channel),
+            split: None,
+            payload: Bytes::from_static(&[0x86, 1, 2]),
+        };
+        let mut buf = BytesMut::new();
+        pkt.encode_raknet(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn ordering_channel_must_be_in_range() {
+        for reliability in [
+            Reliability::ReliableOrdered,
+            Reliability::UnreliableSequenced,
+            Reliability::ReliableSequenced,
+        ] {
+            for channel in 0..MAXIMUM_ORDERING_CHANNELS {
+                let mut frame = frame_on_channel(reliability, channel);
+                let decoded = EncapsulatedPacket::decode_raknet(&mut frame).unwrap();
+                assert_eq!(decoded.ordering_channel, Some(channel));
+            }
+            for channel in MAXIMUM_ORDERING_CHANNELS..=u8::MAX {
+                let frame = frame_on_channel(reliability, channel);
+                assert!(matches!(
+                    EncapsulatedPacket::decode_raknet(&mut frame.clone()),
+                    Err(DecodeError::InvalidOrderingChannel(c)) if c == channel
+                ));
+                // No prefix of a bad frame panics either.
+                for len in 0..frame.len() {
+                    assert!(EncapsulatedPacket::decode_raknet(&mut &frame[..len]).is_err());
+                }
+            }
+        }
+    }
+}
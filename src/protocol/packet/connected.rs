@@ -177,13 +177,11 @@ impl Packet for ConnectionBanned {
         Ok(())
     }
 
+    // Clients only need to recognise the ban; the body is kept opaque.
     fn decode_body(src: &mut impl Buf) -> Result<Self, super::DecodeError> {
         let remaining = src.remaining();
-        let payload = src.copy_to_bytes(remaining);
-
-        Err(super::DecodeError::UnimplementedPacket {
-            id: Self::ID,
-            payload,
+        Ok(Self {
+            payload: src.copy_to_bytes(remaining),
         })
     }
 }
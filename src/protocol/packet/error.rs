@@ -14,6 +14,8 @@ pub enum EncodeError {
     MissingOrderingIndex,
     #[error("Ordering channel missing for ordered/sequenced packet.")]
     MissingOrderingChannel,
+    #[error("ACK/NACK payload of {0} records exceeds the maximum.")]
+    TooManyAckRecords(usize),
 }
 
 /// Errors that may occur while decoding RakNet protocol values or packets.
@@ -72,4 +74,10 @@ pub enum DecodeError {
     MissingSplitInfo,
     #[error("Invalid magic value for offline/unconnected packet.")]
     InvalidMagic,
+    #[error("Datagram carries more than {0} encapsulated frames.")]
+    TooManyFrames(usize),
+    #[error("Ordering channel {0} is out of range.")]
+    InvalidOrderingChannel(u8),
+    #[error("Reliable index {0} is beyond the receive window.")]
+    ReliableIndexBeyondWindow(u32),
 }
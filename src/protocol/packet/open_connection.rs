@@ -65,8 +65,8 @@ impl Packet for OpenConnectionReply1 {
         self.magic.encode_raknet(dst)?;
         self.server_guid.encode_raknet(dst)?;
         self.cookie.is_some().encode_raknet(dst)?; // security bool
-        if self.cookie.is_some() {
-            self.cookie.unwrap().encode_raknet(dst)?;
+        if let Some(cookie) = self.cookie {
+            cookie.encode_raknet(dst)?;
         }
         self.mtu.encode_raknet(dst)?;
         Ok(())
@@ -127,22 +127,33 @@ impl Packet for OpenConnectionRequest2 {
         let rest: Bytes = src.copy_to_bytes(src.remaining());
         let mut cursor = &rest[..];
 
+        // Whether a cookie and client-proof byte come first can't be told
+        // from the first byte alone, as a cookie may start with 4 or 6 just
+        // like an address. The lengths tell: the rest is a v4 or v6
+        // address, the MTU and the GUID.
+        const V4_BODY: usize = 7 + 2 + 8;
+        const V6_BODY: usize = 29 + 2 + 8;
+        const COOKIE_LEN: usize = 4 + 1;
+        let Some(&first) = cursor.first() else {
+            return Err(super::DecodeError::UnexpectedEof);
+        };
+        let has_cookie = match cursor.len() {
+            V4_BODY | V6_BODY => false,
+            n if n == V4_BODY + COOKIE_LEN || n == V6_BODY + COOKIE_LEN => true,
+            _ => first != 4 && first != 6,
+        };
+
         let mut cookie = None;
-        if let Some(&first) = cursor.first() {
-            if first != 4 && first != 6 {
-                if cursor.len() < 4 {
-                    return Err(super::DecodeError::UnexpectedEof);
-                }
-                cookie = Some(u32::from_be_bytes([
-                    cursor[0], cursor[1], cursor[2], cursor[3],
-                ]));
-                cursor = &cursor[4..];
-                if !cursor.is_empty() {
-                    cursor = &cursor[1..]; // skip reserved/client proof byte
-                }
+        let mut client_proof = false;
+        if has_cookie {
+            if cursor.len() < COOKIE_LEN {
+                return Err(super::DecodeError::UnexpectedEof);
             }
-        } else {
-            return Err(super::DecodeError::UnexpectedEof);
+            cookie = Some(u32::from_be_bytes([
+                cursor[0], cursor[1], cursor[2], cursor[3],
+            ]));
+            client_proof = cursor[4] != 0;
+            cursor = &cursor[COOKIE_LEN..];
         }
 
         let server_addr = SocketAddr::decode_raknet(&mut cursor)?;
@@ -151,7 +162,7 @@ impl Packet for OpenConnectionRequest2 {
         Ok(Self {
             magic,
             cookie,
-            client_proof: false,
+            client_proof,
             server_addr,
             mtu,
             client_guid,
@@ -443,4 +454,33 @@ mod tests {
         assert_eq!(decoded.timestamp.0, pkt.timestamp.0);
         assert_eq!(decoded.secure, pkt.secure);
     }
+
+    #[test]
+    fn request2_cookie_roundtrips_whatever_its_first_byte() {
+        let v4: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:19132".parse().unwrap();
+        for (cookie, server_addr) in [
+            (None, v4),
+            (None, v6),
+            (Some(0x0400_0001), v4),
+            (Some(0x0600_0001), v6),
+            (Some(0x1234_5678), v4),
+        ] {
+            let pkt = OpenConnectionRequest2 {
+                magic: DEFAULT_UNCONNECTED_MAGIC,
+                cookie,
+                client_proof: cookie.is_some(),
+                server_addr,
+                mtu: 1400,
+                client_guid: 7,
+            };
+            let mut buf = BytesMut::new();
+            pkt.encode_body(&mut buf).unwrap();
+            let decoded = OpenConnectionRequest2::decode_body(&mut buf.freeze()).unwrap();
+            assert_eq!(decoded.cookie, cookie);
+            assert_eq!(decoded.client_proof, cookie.is_some());
+            assert_eq!(decoded.server_addr, server_addr);
+            assert_eq!((decoded.mtu, decoded.client_guid), (1400, 7));
+        }
+    }
 }
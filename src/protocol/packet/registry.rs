@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut};
 
 use crate::protocol::packet::{DecodeError, Packet};
 
@@ -41,11 +41,11 @@ macro_rules! define_raknet_packets {
                             RaknetPacket::$name(<$name as Packet>::decode_body(src)?)
                         }
                     )+
-                    other => {
-                        let mut tmp = BytesMut::with_capacity(src.remaining());
-                        tmp.put(src);
-                        RaknetPacket::UserData { id: other, payload: tmp.freeze() }
-                    }
+                    // Zero-copy when `src` is `Bytes`.
+                    other => RaknetPacket::UserData {
+                        id: other,
+                        payload: src.copy_to_bytes(src.remaining()),
+                    },
                 })
             }
 
@@ -56,9 +56,15 @@ pub enum Event {
     NewIncomingConnection,
 }
 
+/// Order in which queued frames are packed into datagrams.
+///
+/// Whatever the priority, a send is packed and written to the socket in the
+/// muxer turn that queues it, as far as the congestion window allows; it
+/// never waits for the next tick.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum RakPriority {
+    /// Ahead of everything else queued.
     Immediate = 0,
     High = 1,
     Normal = 2,
@@ -0,0 +1,575 @@
+//! Relaying a connection between two RakNet peers.
+//!
+//! [`relay`] is the core of a RakNet proxy: accept a client on a
+//! [`RaknetListener`](crate::RaknetListener), connect onwards with a
+//! [`RaknetStream`], and hand both to `relay`, which forwards until either
+//! side goes away and then closes the other with the same reason.
+//!
+//! ```rust,no_run
+//! use tokio_raknet::proxy::{self, RelayOptions};
+//! use tokio_raknet::{RaknetListener, RaknetStream};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let mut listener = RaknetListener::bind("0.0.0.0:19132".parse()?).await?;
+//!     while let Some(client) = listener.accept().await {
+//!         let server = RaknetStream::connect("10.0.0.2:19132".parse()?).await?;
+//!         tokio::spawn(async move {
+//!             let result = proxy::relay(client, server, RelayOptions::new()).await;
+//!             println!("relay ended: {result:?}");
+//!         });
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::RaknetError;
+use crate::protocol::state::{DisconnectReason, RakPriority};
+use crate::transport::stream::RaknetSender;
+use crate::transport::{Message, RaknetStream};
+
+/// How long a pump waits before offering a message again to a side whose
+/// outgoing queue is at its `send_queue_limit`.
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Rewrites a message on its way through the relay; `None` drops it.
+pub type Interceptor = Box<dyn FnMut(Message) -> Option<Message> + Send>;
+
+/// How [`relay`] forwards messages.
+pub struct RelayOptions {
+    /// Priority forwarded messages are sent with. Priority only affects the
+    /// sender's scheduling and is not carried on the wire, so the relay
+    /// cannot preserve the original one.
+    pub priority: RakPriority,
+    /// Applied to every message travelling from `a` to `b`.
+    pub a_to_b: Option<Interceptor>,
+    /// Applied to every message travelling from `b` to `a`.
+    pub b_to_a: Option<Interceptor>,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            priority: RakPriority::Normal,
+            a_to_b: None,
+            b_to_a: None,
+        }
+    }
+}
+
+impl fmt::Debug for RelayOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayOptions")
+            .field("priority", &self.priority)
+            .field("a_to_b", &self.a_to_b.is_some())
+            .field("b_to_a", &self.b_to_a.is_some())
+            .finish()
+    }
+}
+
+impl RelayOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn priority(mut self, priority: RakPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn intercept_a_to_b(
+        mut self,
+        f: impl FnMut(Message) -> Option<Message> + Send + 'static,
+    ) -> Self {
+        self.a_to_b = Some(Box::new(f));
+        self
+    }
+
+    pub fn intercept_b_to_a(
+        mut self,
+        f: impl FnMut(Message) -> Option<Message> + Send + 'static,
+    ) -> Self {
+        self.b_to_a = Some(Box::new(f));
+        self
+    }
+}
+
+/// Traffic forwarded in one direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayCounts {
+    /// Messages delivered to the far side, after interception.
+    pub messages: u64,
+    /// Payload bytes of those messages, packet ID included.
+    pub bytes: u64,
+}
+
+/// One of the two streams handed to [`relay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+/// How a [`relay`] ended.
+#[derive(Debug)]
+pub struct RelayResult {
+    pub a_to_b: RelayCounts,
+    pub b_to_a: RelayCounts,
+    /// The side whose connection ended first. The other side was closed by
+    /// the relay.
+    pub closed_by: Side,
+    /// The error that side reported, or `None` if its stream simply ended.
+    pub error: Option<RaknetError>,
+}
+
+impl RelayResult {
+    /// The reason the relay passed on to the side that was still connected.
+    pub fn reason(&self) -> DisconnectReason {
+        match self.error {
+            Some(RaknetError::Disconnected(reason)) => reason,
+            _ => DisconnectReason::Disconnected,
+        }
+    }
+}
+
+/// Forward messages between `a` and `b` until one of them disconnects.
+///
+/// Each message keeps its reliability and ordering channel. The two
+/// directions run independently, but each only reads as fast as the far
+/// side accepts sends, so a slow peer pushes back on the fast one instead of
+/// growing a queue in between. A side whose outgoing queue is at its
+/// `send_queue_limit` is offered the message again once it has had time to
+/// drain; nothing is dropped for it.
+///
+/// When one side goes away, the other is disconnected gracefully with the
+/// same [`DisconnectReason`] (or [`DisconnectReason::Disconnected`] if the
+/// side closed without one): everything already forwarded to it is delivered
+/// first, and `relay` returns once it has ACKed that or its
+/// `shutdown_timeout` passes.
+pub async fn relay(mut a: RaknetStream, mut b: RaknetStream, opts: RelayOptions) -> RelayResult {
+    let RelayOptions {
+        priority,
+        a_to_b: mut intercept_a,
+        b_to_a: mut intercept_b,
+    } = opts;
+    let to_a = a.sender();
+    let to_b = b.sender();
+    let mut a_to_b = RelayCounts::default();
+    let mut b_to_a = RelayCounts::default();
+
+    // Both pumps run to the end, so a message one of them has already
+    // received is never dropped halfway through its send.
+    let ended = CancellationToken::new();
+    let (from_a, from_b) = tokio::join!(
+        pump(
+            &mut a,
+            &to_b,
+            priority,
+            &mut intercept_a,
+            &mut a_to_b,
+            &ended
+        ),
+        pump(
+            &mut b,
+            &to_a,
+            priority,
+            &mut intercept_b,
+            &mut b_to_a,
+            &ended
+        ),
+    );
+    let (closed_by, error) = match (from_a, from_b) {
+        (Some(error), _) => (Side::A, error),
+        (None, from_b) => (
+            Side::B,
+            from_b.expect("a pump only stops once the other has ended"),
+        ),
+    };
+
+    let result = RelayResult {
+        a_to_b,
+        b_to_a,
+        closed_by,
+        error,
+    };
+    let survivor = match closed_by {
+        Side::A => b,
+        Side::B => a,
+    };
+    tracing::debug!(?closed_by, reason = ?result.reason(), "relay ended");
+    if let Err(e) = survivor.disconnect(result.reason()).await {
+        // Both went at once; the survivor's own error is already on its way.
+        tracing::debug!(error = ?e, "other side already closed");
+    }
+    result
+}
+
+/// Forward from `source` to `sink` until `source` ends or `ended` is
+/// cancelled by the other direction's pump.
+///
+/// Returns `Some` with `source`'s error if it ended first, cancelling
+/// `ended`, and `None` if this pump stopped because the other side went
+/// away. A message already received is always sent (or refused by a closed
+/// `sink`) before stopping.
+async fn pump(
+    source: &mut RaknetStream,
+    sink: &RaknetSender,
+    priority: RakPriority,
+    intercept: &mut Option<Interceptor>,
+    counts: &mut RelayCounts,
+    ended: &CancellationToken,
+) -> Option<Option<RaknetError>> {
+    loop {
+        let received = tokio::select! {
+            biased;
+            _ = ended.cancelled() => return None,
+            received = source.recv_msg() => received,
+        };
+        let received = match received {
+            Some(Ok(received)) => received,
+            Some(Err(e)) => {
+                ended.cancel();
+                return Some(Some(e));
+            }
+            None => {
+                ended.cancel();
+                return Some(None);
+            }
+        };
+        let msg = Message::from(received).priority(priority);
+        let Some(msg) = (match intercept {
+            Some(f) => f(msg),
+            None => Some(msg),
+        }) else {
+            continue;
+        };
+
+        let len = msg.buffer.len() as u64;
+        loop {
+            match sink.send(msg.clone()).await {
+                Ok(()) => {
+                    counts.messages += 1;
+                    counts.bytes += len;
+                    break;
+                }
+                Err(RaknetError::SendQueueFull) => tokio::time::sleep(SEND_RETRY_DELAY).await,
+                Err(e) if e.is_fatal() => {
+                    // The sink is gone; its own pump reports why.
+                    ended.cancelled().await;
+                    return None;
+                }
+                Err(e) => {
+                    tracing::debug!(error = ?e, "message not forwarded");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::reliability::Reliability;
+    use crate::session::manager::{QueueLimitPolicy, SendQueueLimit};
+    use crate::transport::memory::{MemoryNetwork, SimulatedLink};
+    use crate::transport::{RaknetListener, RaknetListenerConfig, RaknetStreamConfig};
+    use crate::transport::{RaknetStream, ReceivedMessage};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    const FRONT: &str = "10.0.0.1:19132";
+    const BACK: &str = "10.0.0.2:19132";
+    const WAIT: Duration = Duration::from_secs(60);
+
+    /// client <-> proxy (relay) <-> backend, all on one in-memory network.
+    struct Chain {
+        client: RaknetStream,
+        backend: RaknetStream,
+        backend_listener: RaknetListener,
+        relay: JoinHandle<RelayResult>,
+        net: MemoryNetwork,
+    }
+
+    impl Chain {
+        async fn connect(opts: RelayOptions) -> Self {
+            Self::connect_with(opts, RaknetStreamConfig::default()).await
+        }
+
+        /// Like `connect`, with `upstream` configuring the proxy's stream to
+        /// the backend.
+        async fn connect_with(opts: RelayOptions, upstream: RaknetStreamConfig) -> Self {
+            let net = MemoryNetwork::new();
+            let mut front = listen(&net, FRONT);
+            let mut backend_listener = listen(&net, BACK);
+
+            let (client, accepted) = tokio::join!(
+                RaknetStream::connect_on(
+                    net.bind_any().unwrap(),
+                    front.local_addr(),
+                    RaknetStreamConfig::default()
+                ),
+                front.accept()
+            );
+            let (upstream, backend) = tokio::join!(
+                RaknetStream::connect_on(
+                    net.bind_any().unwrap(),
+                    backend_listener.local_addr(),
+                    upstream
+                ),
+                backend_listener.accept()
+            );
+            let relay = tokio::spawn(relay(accepted.unwrap(), upstream.unwrap(), opts));
+            // The front listener only has to outlive the accepted stream.
+            tokio::spawn(async move {
+                let _front = front;
+                std::future::pending::<()>().await
+            });
+            Self {
+                client: client.expect("client connects"),
+                backend: backend.expect("backend accepts"),
+                backend_listener,
+                relay,
+                net,
+            }
+        }
+
+        async fn finish(self) -> RelayResult {
+            timeout(WAIT, self.relay).await.unwrap().unwrap()
+        }
+    }
+
+    fn listen(net: &MemoryNetwork, addr: &str) -> RaknetListener {
+        let addr: SocketAddr = addr.parse().unwrap();
+        RaknetListener::with_socket(net.bind(addr).unwrap(), RaknetListenerConfig::default())
+            .unwrap()
+    }
+
+    async fn recv(stream: &mut RaknetStream) -> Result<ReceivedMessage, RaknetError> {
+        timeout(WAIT, stream.recv_msg())
+            .await
+            .expect("timed out")
+            .expect("stream ended")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forwards_both_ways_keeping_reliability_and_channel() {
+        let mut chain = Chain::connect(RelayOptions::new()).await;
+
+        chain
+            .client
+            .send(
+                Message::new(vec![0xfe, 1, 2, 3])
+                    .reliability(Reliability::ReliableSequenced)
+                    .channel(4),
+            )
+            .await
+            .unwrap();
+        let got = recv(&mut chain.backend).await.unwrap();
+        assert_eq!(&got.buffer[..], &[0xfe, 1, 2, 3]);
+        assert_eq!(got.reliability, Reliability::ReliableSequenced);
+        assert_eq!(got.channel, 4);
+
+        for i in 0..3u8 {
+            chain
+                .backend
+                .send(Message::new(vec![0xfe, i]).channel(2))
+                .await
+                .unwrap();
+        }
+        for i in 0..3u8 {
+            let got = recv(&mut chain.client).await.unwrap();
+            assert_eq!(&got.buffer[..], &[0xfe, i]);
+            assert_eq!(got.reliability, Reliability::ReliableOrdered);
+            assert_eq!(got.channel, 2);
+        }
+
+        chain
+            .client
+            .close(DisconnectReason::Disconnected)
+            .await
+            .unwrap();
+        let result = chain.finish().await;
+        assert_eq!(result.closed_by, Side::A);
+        assert_eq!(
+            result.a_to_b,
+            RelayCounts {
+                messages: 1,
+                bytes: 4
+            }
+        );
+        assert_eq!(
+            result.b_to_a,
+            RelayCounts {
+                messages: 3,
+                bytes: 6
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interceptors_rewrite_and_drop_messages() {
+        let opts = RelayOptions::new()
+            .intercept_a_to_b(|msg| (msg.buffer[1] % 2 == 0).then_some(msg))
+            .intercept_b_to_a(|msg| {
+                let mut bytes = msg.buffer.to_vec();
+                bytes.push(0xaa);
+                Some(Message::new(bytes).channel(msg.channel))
+            });
+        let mut chain = Chain::connect(opts).await;
+
+        for i in 0..6u8 {
+            chain
+                .client
+                .send(Message::new(vec![0xfe, i]))
+                .await
+                .unwrap();
+        }
+        for i in [0u8, 2, 4] {
+            assert_eq!(
+                &recv(&mut chain.backend).await.unwrap().buffer[..],
+                &[0xfe, i]
+            );
+        }
+
+        chain
+            .backend
+            .send(Message::new(vec![0xfe, 9]))
+            .await
+            .unwrap();
+        assert_eq!(
+            &recv(&mut chain.client).await.unwrap().buffer[..],
+            &[0xfe, 9, 0xaa]
+        );
+
+        chain
+            .backend
+            .close(DisconnectReason::Disconnected)
+            .await
+            .unwrap();
+        let result = chain.finish().await;
+        assert_eq!(result.closed_by, Side::B);
+        assert_eq!(result.a_to_b.messages, 3);
+        assert_eq!(result.b_to_a.bytes, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forwarded_messages_arrive_before_the_disconnect() {
+        let Chain {
+            client,
+            mut backend,
+            backend_listener: _backend_listener,
+            relay,
+            net,
+        } = Chain::connect(RelayOptions::new()).await;
+        // A slow backend link, so much of this is still queued at the proxy
+        // when the client leaves.
+        let slow = SimulatedLink::new().latency(Duration::from_millis(20));
+        net.set_link_both(backend.peer_addr(), BACK.parse().unwrap(), slow);
+
+        let msg = |i: u8| Message::new([vec![0xfe, i], vec![i; 4000]].concat());
+        for i in 0..20u8 {
+            client.send(msg(i)).await.unwrap();
+        }
+        client
+            .disconnect(DisconnectReason::Disconnected)
+            .await
+            .unwrap();
+
+        for i in 0..20u8 {
+            assert_eq!(recv(&mut backend).await.unwrap().buffer, msg(i).buffer);
+        }
+        assert!(matches!(
+            recv(&mut backend).await,
+            Err(RaknetError::Disconnected(DisconnectReason::Disconnected))
+        ));
+        let result = timeout(WAIT, relay).await.unwrap().unwrap();
+        assert_eq!(result.a_to_b.messages, 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_send_queue_holds_messages_back_instead_of_stalling() {
+        let upstream = RaknetStreamConfig {
+            send_queue_limit: Some(SendQueueLimit {
+                max_bytes: 16 * 1024,
+                max_frames: 64,
+                policy: QueueLimitPolicy::Reject,
+            }),
+            ..Default::default()
+        };
+        let mut chain = Chain::connect_with(RelayOptions::new(), upstream).await;
+        let slow = SimulatedLink::new().latency(Duration::from_millis(50));
+        chain
+            .net
+            .set_link_both(chain.backend.peer_addr(), BACK.parse().unwrap(), slow);
+
+        let msg = |i: u8| Message::new([vec![0xfe, i], vec![i; 2000]].concat());
+        for i in 0..50u8 {
+            chain.client.send(msg(i)).await.unwrap();
+        }
+        for i in 0..50u8 {
+            assert_eq!(
+                recv(&mut chain.backend).await.unwrap().buffer,
+                msg(i).buffer
+            );
+        }
+
+        chain
+            .client
+            .close(DisconnectReason::Disconnected)
+            .await
+            .unwrap();
+        assert_eq!(chain.finish().await.a_to_b.messages, 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backend_disconnect_reason_reaches_the_client() {
+        let mut chain = Chain::connect(RelayOptions::new()).await;
+
+        chain
+            .backend_listener
+            .disconnect(chain.backend.peer_addr(), DisconnectReason::ShuttingDown)
+            .await
+            .unwrap();
+
+        let err = recv(&mut chain.client).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+            ),
+            "{err:?}"
+        );
+        let result = chain.finish().await;
+        assert_eq!(result.closed_by, Side::B);
+        assert!(matches!(result.reason(), DisconnectReason::ShuttingDown));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_disconnect_reason_reaches_the_backend() {
+        let mut chain = Chain::connect(RelayOptions::new()).await;
+
+        chain
+            .client
+            .close(DisconnectReason::IncompatibleProtocolVersion)
+            .await
+            .unwrap();
+
+        let err = recv(&mut chain.backend).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                RaknetError::Disconnected(DisconnectReason::IncompatibleProtocolVersion)
+            ),
+            "{err:?}"
+        );
+        assert_eq!(chain.finish().await.closed_by, Side::A);
+    }
+}
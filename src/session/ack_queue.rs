@@ -3,8 +3,11 @@ use std::collections::VecDeque;
 use crate::protocol::ack::SequenceRange;
 
 /// Maintains a bounded, merged queue of ACK/NACK ranges.
-/// Keeps payload size within MTU by merging adjacent ranges
-/// and slicing on demand.
+/// Keeps payload size within MTU by merging adjacent and overlapping
+/// ranges and slicing on demand.
+///
+/// Ranges that wrap past 2^24 are stored as two, so every queued range has
+/// `start <= end`; the two halves are never merged back together.
 #[derive(Clone, Debug)]
 pub struct AckQueue {
     max_ranges: usize,
@@ -19,44 +22,67 @@ impl AckQueue {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
     pub fn push(&mut self, range: SequenceRange) {
-        if self.queue.len() >= self.max_ranges {
+        if let Some((tail, head)) = range.split_wrapping() {
+            self.push(tail);
+            self.push(head);
             return;
         }
 
-        if let Some(last) = self.queue.back_mut() {
-            if last.end.next() == range.start {
-                last.end = range.end;
-                return;
-            }
+        if let Some(last) = self.queue.back_mut()
+            && merge(last, range)
+        {
+            return;
+        }
 
-            // Merge overlapping or contiguous
-            if last.end >= range.start && range.end >= last.start {
-                last.end = std::cmp::max(last.end, range.end);
-                last.start = std::cmp::min(last.start, range.start);
+        if self.queue.len() >= self.max_ranges {
+            // Out-of-order arrivals leave mergeable ranges behind the last.
+            self.coalesce();
+            if self.queue.len() >= self.max_ranges {
                 return;
             }
         }
+        self.queue.push_back(range);
+    }
 
-        // Normalize wrapping ranges by splitting if needed
-        if let Some((tail, head)) = range.split_wrapping() {
-            self.push(tail);
-            self.push(head);
+    /// Sort the queue oldest first and merge every pair of ranges that
+    /// overlap or touch.
+    fn coalesce(&mut self) {
+        let Some(oldest) = self.queue.iter().map(|r| r.start).min() else {
             return;
+        };
+        let mut ranges: Vec<_> = self.queue.drain(..).collect();
+        ranges.sort_unstable_by_key(|r| oldest.distance_to(r.start));
+        for range in ranges {
+            if let Some(last) = self.queue.back_mut()
+                && merge(last, range)
+            {
+                continue;
+            }
+            self.queue.push_back(range);
         }
-
-        self.queue.push_back(range);
     }
 
-    /// Pop a set of ranges whose encoded size (plus base_overhead bytes)
-    /// fits within the provided MTU.
-    pub fn pop_for_mtu(&mut self, mtu: usize, base_overhead: usize) -> Vec<SequenceRange> {
+    /// Pop, oldest first, at most `max_records` merged ranges whose encoded
+    /// size (plus base_overhead bytes) fits within the provided MTU. What is
+    /// left goes out in the next payload.
+    pub fn pop_for_mtu(
+        &mut self,
+        mtu: usize,
+        base_overhead: usize,
+        max_records: usize,
+    ) -> Vec<SequenceRange> {
+        self.coalesce();
         let mut ranges = Vec::new();
         let mut used = base_overhead;
 
         while let Some(front) = self.queue.front() {
             let size = front.encoded_size();
-            if !ranges.is_empty() && used + size > mtu {
+            if ranges.len() >= max_records || (!ranges.is_empty() && used + size > mtu) {
                 break;
             }
             used += size;
@@ -67,6 +93,22 @@ impl AckQueue {
     }
 }
 
+/// Widen `into` to cover `range` if the two overlap or touch. Compares raw
+/// values, so the last sequence before the wrap never joins the first after.
+fn merge(into: &mut SequenceRange, range: SequenceRange) -> bool {
+    let (start, end) = (range.start.value(), range.end.value());
+    if start > into.end.value() + 1 || into.start.value() > end + 1 {
+        return false;
+    }
+    if start < into.start.value() {
+        into.start = range.start;
+    }
+    if end > into.end.value() {
+        into.end = range.end;
+    }
+    true
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,7 +126,7 @@ mod tests {
             end: Sequence24::new(2),
         });
 
-        let out = q.pop_for_mtu(1024, 0);
+        let out = q.pop_for_mtu(1024, 0, usize::MAX);
         assert_eq!(out.len(), 1);
         assert_eq!(out[0].start.value(), 1);
         assert_eq!(out[0].end.value(), 2);
@@ -102,7 +144,82 @@ mod tests {
 
         // Each single range encodes to 4 bytes, so with base_overhead 2,
         // mtu 6 only allows one.
-        let out = q.pop_for_mtu(6, 2);
+        let out = q.pop_for_mtu(6, 2, usize::MAX);
         assert_eq!(out.len(), 1);
     }
+
+    fn range(start: u32, end: u32) -> SequenceRange {
+        SequenceRange {
+            start: Sequence24::new(start),
+            end: Sequence24::new(end),
+        }
+    }
+
+    fn bounds(ranges: &[SequenceRange]) -> Vec<(u32, u32)> {
+        ranges
+            .iter()
+            .map(|r| (r.start.value(), r.end.value()))
+            .collect()
+    }
+
+    #[test]
+    fn merges_out_of_order_and_overlapping_ranges() {
+        let mut q = AckQueue::new(16);
+        for (start, end) in [(5, 5), (1, 2), (8, 9), (3, 3), (4, 6), (12, 12), (9, 10)] {
+            q.push(range(start, end));
+        }
+
+        let out = q.pop_for_mtu(1024, 0, usize::MAX);
+        assert_eq!(bounds(&out), [(1, 6), (8, 10), (12, 12)]);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn a_burst_of_single_datagrams_acks_as_one_range() {
+        let mut q = AckQueue::new(1024);
+        // Every other datagram first, then the ones in between.
+        for i in (0..60).step_by(2).chain((1..60).step_by(2)) {
+            q.push(range(i, i));
+        }
+
+        let out = q.pop_for_mtu(1400, 0, usize::MAX);
+        assert_eq!(bounds(&out), [(0, 59)]);
+    }
+
+    #[test]
+    fn keeps_wraparound_oldest_first_in_two_ranges() {
+        let mut q = AckQueue::new(16);
+        for seq in [1, 0x00FF_FFFF, 0, 0x00FF_FFFE] {
+            q.push(range(seq, seq));
+        }
+        q.push(range(0x00FF_FFFD, 2));
+
+        let out = q.pop_for_mtu(1024, 0, usize::MAX);
+        assert_eq!(bounds(&out), [(0x00FF_FFFD, 0x00FF_FFFF), (0, 2)]);
+    }
+
+    #[test]
+    fn caps_the_ranges_per_payload_and_continues_in_the_next() {
+        let mut q = AckQueue::new(16);
+        for i in 0..10 {
+            q.push(range(i * 2, i * 2));
+        }
+
+        let sizes: Vec<usize> =
+            std::iter::from_fn(|| Some(q.pop_for_mtu(1024, 0, 4).len()).filter(|n| *n > 0))
+                .collect();
+        assert_eq!(sizes, [4, 4, 2]);
+    }
+
+    #[test]
+    fn a_full_queue_makes_room_by_merging() {
+        let mut q = AckQueue::new(2);
+        q.push(range(3, 3));
+        q.push(range(1, 1));
+        q.push(range(2, 2));
+        q.push(range(5, 5));
+
+        let out = q.pop_for_mtu(1024, 0, usize::MAX);
+        assert_eq!(bounds(&out), [(1, 3), (5, 5)]);
+    }
 }
@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+
+use crate::protocol::types::Sequence24;
+
+/// Remembers which recent datagram sequence numbers have arrived, to tell
+/// duplicates apart from late arrivals.
+///
+/// Only the newest `size` sequence numbers are tracked; anything older is
+/// reported as new since there is no way to tell.
+pub struct DatagramWindow {
+    newest: Option<Sequence24>,
+    /// `seen[i]` is whether `newest - i` has arrived.
+    seen: VecDeque<bool>,
+    size: usize,
+}
+
+impl DatagramWindow {
+    pub fn new(size: usize) -> Self {
+        Self {
+            newest: None,
+            seen: VecDeque::new(),
+            size: size.max(1),
+        }
+    }
+
+    /// Record the arrival of `seq`. Returns `false` if it had arrived before.
+    pub fn observe(&mut self, seq: Sequence24) -> bool {
+        let newest = match self.newest {
+            Some(newest) if seq <= newest => newest,
+            Some(newest) => {
+                let gap = newest.distance_to(seq) as usize - 1;
+                for _ in 0..gap.min(self.size) {
+                    self.seen.push_front(false);
+                }
+                return self.advance(seq);
+            }
+            None => return self.advance(seq),
+        };
+
+        match self.seen.get_mut(seq.distance_to(newest) as usize) {
+            Some(true) => false,
+            Some(slot) => {
+                *slot = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn advance(&mut self, seq: Sequence24) -> bool {
+        self.seen.push_front(true);
+        self.seen.truncate(self.size);
+        self.newest = Some(seq);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(v: u32) -> Sequence24 {
+        Sequence24::new(v)
+    }
+
+    #[test]
+    fn repeats_are_duplicates_late_arrivals_are_not() {
+        let mut w = DatagramWindow::new(16);
+        assert!(w.observe(seq(0)));
+        assert!(w.observe(seq(3)));
+        assert!(!w.observe(seq(3)));
+        // 1 and 2 were skipped, not seen.
+        assert!(w.observe(seq(2)));
+        assert!(!w.observe(seq(2)));
+        assert!(w.observe(seq(1)));
+        assert!(!w.observe(seq(0)));
+    }
+
+    #[test]
+    fn forgets_what_falls_out_of_the_window() {
+        let mut w = DatagramWindow::new(4);
+        assert!(w.observe(seq(0)));
+        assert!(w.observe(seq(10)));
+        assert!(!w.observe(seq(10)));
+        // Too old to judge.
+        assert!(w.observe(seq(0)));
+        assert!(w.observe(seq(9)));
+        assert!(!w.observe(seq(9)));
+    }
+
+    #[test]
+    fn wraps_around_the_sequence_space() {
+        let mut w = DatagramWindow::new(16);
+        assert!(w.observe(seq(0xff_fffe)));
+        assert!(w.observe(seq(1)));
+        assert!(!w.observe(seq(0xff_fffe)));
+        assert!(w.observe(seq(0xff_ffff)));
+        assert!(w.observe(seq(0)));
+        assert!(!w.observe(seq(0)));
+    }
+}
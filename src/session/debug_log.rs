@@ -0,0 +1,124 @@
+//! Opt-in ring buffer of recent events for a single session.
+//!
+//! Meant for chasing one misbehaving connection without turning on TRACE
+//! logging for the whole process: the muxer appends a compact `DebugEvent`
+//! for every datagram, ACK/NACK range, retransmission, ordering stall and
+//! state change, and the application snapshots the last few thousand on
+//! demand. Everything here is compiled out without the `debug-log` feature.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::manager::ConnectionState;
+
+/// Events kept per session when no capacity is configured.
+pub const DEFAULT_DEBUG_LOG_CAPACITY: usize = 2048;
+
+/// One entry in a session's debug log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugEvent {
+    /// Muxer clock at the time of the event.
+    pub at: Instant,
+    pub kind: DebugEventKind,
+}
+
+/// What happened. Sequence numbers are datagram sequence numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DebugEventKind {
+    /// A data datagram went out for the first time.
+    DatagramSent { sequence: u32, size: usize },
+    /// A data datagram went out again after a NACK or a resend timeout.
+    DatagramResent { sequence: u32, size: usize },
+    /// A data datagram arrived.
+    DatagramReceived { sequence: u32, size: usize },
+    /// We acknowledged `start..=end`.
+    AckSent { start: u32, end: u32 },
+    /// We reported `start..=end` missing.
+    NakSent { start: u32, end: u32 },
+    /// The peer acknowledged `start..=end`.
+    AckReceived { start: u32, end: u32 },
+    /// The peer reported `start..=end` missing.
+    NakReceived { start: u32, end: u32 },
+    /// Ordered data arrived ahead of a gap and is being held back.
+    OrderingStalled { buffered_bytes: usize },
+    /// The gap was filled and nothing is held back any more.
+    OrderingResumed,
+    /// The connection state changed.
+    StateChanged {
+        from: ConnectionState,
+        to: ConnectionState,
+    },
+}
+
+/// Bounded log of the most recent `DebugEvent`s of one session.
+///
+/// Once full, each new event evicts the oldest.
+#[derive(Debug)]
+pub struct DebugLog {
+    capacity: usize,
+    events: Mutex<VecDeque<DebugEvent>>,
+}
+
+impl DebugLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Append an event. Only the muxer records, so the lock is uncontended
+    /// except while a snapshot is being copied out.
+    pub(crate) fn record(&self, at: Instant, kind: DebugEventKind) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(DebugEvent { at, kind });
+    }
+
+    /// Copy of the logged events, oldest first.
+    pub fn snapshot(&self) -> Vec<DebugEvent> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_most_recent_events() {
+        let log = DebugLog::new(3);
+        let now = Instant::now();
+        for sequence in 0..5 {
+            log.record(now, DebugEventKind::DatagramSent { sequence, size: 1 });
+        }
+        let sequences: Vec<u32> = log
+            .snapshot()
+            .into_iter()
+            .map(|e| match e.kind {
+                DebugEventKind::DatagramSent { sequence, .. } => sequence,
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(sequences, [2, 3, 4]);
+    }
+
+    #[test]
+    fn zero_capacity_records_nothing() {
+        let log = DebugLog::new(0);
+        log.record(Instant::now(), DebugEventKind::OrderingResumed);
+        assert!(log.snapshot().is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::Instant;
 
 use crate::protocol::{
+    constants::MAX_ACK_SEQUENCES,
     encapsulated_packet::EncapsulatedPacket,
     packet::{DecodeError, RaknetPacket},
     types::Sequence24,
@@ -9,7 +10,9 @@ use bytes::Bytes;
 
 use crate::protocol::ack::{AckNackPayload, SequenceRange};
 
-use super::{IncomingPacket, Session};
+use super::manager::SessionError;
+use super::reliable_tracker::IndexStatus;
+use super::{Arrival, IncomingPacket, Session};
 
 impl Session {
     /// Handle an incoming data payload (a list of encapsulated packets).
@@ -17,76 +20,130 @@ impl Session {
         &mut self,
         packets: Vec<EncapsulatedPacket>,
         now: Instant,
-    ) -> Result<Vec<IncomingPacket>, DecodeError> {
+    ) -> Result<Vec<IncomingPacket>, SessionError> {
         let mut out = Vec::new();
+        self.handle_data_payload_with(packets, now, |pkt| out.push(pkt))?;
+        Ok(out)
+    }
 
+    /// Like `handle_data_payload`, but hands each packet to `f` as soon as
+    /// the reliability/ordering layers release it instead of collecting them.
+    pub fn handle_data_payload_with(
+        &mut self,
+        packets: Vec<EncapsulatedPacket>,
+        now: Instant,
+        mut f: impl FnMut(IncomingPacket),
+    ) -> Result<(), SessionError> {
         self.sliding.on_packet_received(now);
 
+        let arrival = Arrival {
+            at: now,
+            datagram_sequence: None,
+        };
         for enc in packets.into_iter() {
-            self.handle_encapsulated(enc, now, &mut out)?;
+            self.handle_encapsulated(enc, arrival, true, &mut f)?;
         }
 
-        Ok(out)
+        Ok(())
+    }
+
+    /// `handle_data_payload_with` for the frames of data datagram `seq`,
+    /// counting a repeated sequence number as a duplicate datagram.
+    ///
+    /// Frames of a duplicate datagram are still deduplicated, but only count
+    /// as duplicate frames when they arrive in a datagram that is new.
+    pub fn handle_data_datagram_with(
+        &mut self,
+        seq: Sequence24,
+        packets: Vec<EncapsulatedPacket>,
+        now: Instant,
+        mut f: impl FnMut(IncomingPacket),
+    ) -> Result<(), SessionError> {
+        let fresh = self.datagram_window.observe(seq);
+        if !fresh {
+            self.duplicate_datagrams += 1;
+        }
+        self.sliding.on_packet_received(now);
+
+        let arrival = Arrival {
+            at: now,
+            datagram_sequence: Some(seq.value()),
+        };
+        for enc in packets.into_iter() {
+            self.handle_encapsulated(enc, arrival, fresh, &mut f)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hand `f` the ordered packets `on_tick` released by giving up on a
+    /// split, as `handle_data_datagram_with` would have. One that fails to
+    /// decode is dropped.
+    pub fn deliver_released_with(&mut self, mut f: impl FnMut(IncomingPacket)) {
+        for (enc, arrival) in std::mem::take(&mut self.released) {
+            if let Err(e) = self.decode_and_push(enc, arrival, &mut f) {
+                tracing::debug!(error = %e, "dropping undecodable released packet");
+            }
+        }
     }
 
     /// Handle an incoming dedicated ACK payload.
     pub fn handle_ack_payload(&mut self, payload: AckNackPayload) {
-        self.incoming_acks.extend(payload.ranges);
+        self.incoming_acks
+            .extend(payload.ranges.into_iter().filter(plausible_range));
     }
 
     /// Handle an incoming dedicated NACK payload.
     pub fn handle_nack_payload(&mut self, payload: AckNackPayload) {
-        self.incoming_naks.extend(payload.ranges);
+        self.incoming_naks
+            .extend(payload.ranges.into_iter().filter(plausible_range));
     }
 
     fn handle_encapsulated(
         &mut self,
         enc: EncapsulatedPacket,
-        now: Instant,
-        out: &mut Vec<IncomingPacket>,
-    ) -> Result<(), DecodeError> {
+        arrival: Arrival,
+        count_duplicates: bool,
+        out: &mut impl FnMut(IncomingPacket),
+    ) -> Result<(), SessionError> {
         // Reliability Logic:
-        // - For non-split reliable packets:
-        //   1) Deduplicate by reliable index; drop duplicates early.
-        //   2) Decode/deliver; mark reliable index as seen on success.
-        //
-        // - For split packets:
-        //   Do NOT mark reliable-indexes as seen until the split is fully assembled.
-        //   Each split part has its own reliable index, and marking parts as seen
-        //   would prevent retransmission if we later drop the split due to timeout.
-        //   We rely on split_assembler to filter duplicate parts per (id,index).
-
-        let is_split = enc.header.is_split;
-        let ridx = if enc.header.reliability.is_reliable() && !is_split {
+        // Every reliable frame, split part or not, is deduplicated by its
+        // reliable index and marked as seen once it has been taken in (handed
+        // on, or buffered by the split assembler). The datagram carrying it
+        // gets ACKed either way, so the sender never sends it again except by
+        // mistake; a part seen again after its split was reassembled must
+        // not start the split over. An index too far ahead to track fails the
+        // datagram instead, so it is not ACKed.
+        let ridx = if enc.header.reliability.is_reliable() {
             enc.reliable_index
         } else {
             None
         };
 
-        if let Some(idx) = ridx
-            && self.reliable_tracker.has_seen(idx)
-        {
-            // Duplicate non-split reliable; drop silently.
-            return Ok(());
+        if let Some(idx) = ridx {
+            match self.reliable_tracker.status(idx) {
+                IndexStatus::New => {}
+                IndexStatus::Duplicate => {
+                    // Duplicate reliable frame; drop silently.
+                    if count_duplicates {
+                        self.duplicate_frames += 1;
+                    }
+                    return Ok(());
+                }
+                IndexStatus::TooFarAhead => {
+                    // Refused rather than dropped: the datagram goes un-ACKed,
+                    // and the peer's resend is taken in once the window has
+                    // caught up.
+                    return Err(DecodeError::ReliableIndexBeyondWindow(idx.value()).into());
+                }
+            }
         }
 
-        // Attempt to add to split assembler (or pass through if not split)
-        // Note: add() consumes the packet.
-        let assembled_opt = match self.split_assembler.add(enc, now) {
-            Ok(v) => v,
-            Err(e) => {
-                // If buffer is full, we return Error.
-                // We have NOT marked the reliable index as seen.
-                // Sender will timeout and retransmit. Ideally buffer clears by then.
-                return Err(e);
-            }
-        };
+        // Attempt to add to split assembler (or pass through if not split).
+        // A part it refuses breaks a session limit, and the session closes.
+        let assembled_opt = self.split_assembler.add(enc, arrival.at)?;
 
-        // If we reached here, the packet was either buffered or reassembled successfully.
-        // For non-split reliable packets, commit the reliable index now.
-        // For split packets, we avoid marking per-part indexes as seen; duplicates
-        // are handled by split_assembler itself.
-        if !is_split && let Some(idx) = ridx {
+        if let Some(idx) = ridx {
             self.reliable_tracker.see(idx);
         }
 
@@ -95,10 +152,11 @@ impl Session {
             None => return Ok(()), // Buffered partial split
         };
 
+        // A reassembled split counts as arriving with its last part.
         if enc.header.reliability.is_ordered() {
-            self.handle_ordered(enc, out)?;
+            self.handle_ordered(enc, arrival, out)?;
         } else {
-            self.decode_and_push(enc, out)?;
+            self.decode_and_push(enc, arrival, out)?;
         }
 
         Ok(())
@@ -107,7 +165,8 @@ impl Session {
     pub(crate) fn decode_and_push(
         &mut self,
         enc: EncapsulatedPacket,
-        out: &mut Vec<IncomingPacket>,
+        arrival: Arrival,
+        out: &mut impl FnMut(IncomingPacket),
     ) -> Result<(), DecodeError> {
         let mut buf = enc.payload.clone();
         let reliability = enc.header.reliability;
@@ -127,18 +186,23 @@ impl Session {
         };
 
         if let RaknetPacket::EncapsulatedAck(payload) = pkt {
-            self.incoming_acks.extend(payload.0.ranges);
+            self.incoming_acks
+                .extend(payload.0.ranges.into_iter().filter(plausible_range));
             return Ok(());
         }
         if let RaknetPacket::EncapsulatedNak(payload) = pkt {
-            self.incoming_naks.extend(payload.0.ranges);
+            self.incoming_naks
+                .extend(payload.0.ranges.into_iter().filter(plausible_range));
             return Ok(());
         }
 
-        out.push(IncomingPacket {
+        out(IncomingPacket {
             packet: pkt,
             reliability,
             ordering_channel,
+            raw: enc.payload,
+            recv_time: arrival.at,
+            datagram_sequence: arrival.datagram_sequence,
         });
         Ok(())
     }
@@ -148,46 +212,102 @@ impl Session {
         self.process_incoming_naks(now);
     }
 
-    fn process_incoming_acks(&mut self, now: Instant) {
+    pub(crate) fn process_incoming_acks(&mut self, now: Instant) {
         while let Some(range) = self.incoming_acks.pop_front() {
-            Self::for_each_sequence_in_range(range, |seq| {
-                if let Some(tracked) = self.sent_datagrams.remove(&seq)
-                    && let crate::protocol::datagram::DatagramPayload::EncapsulatedPackets(_) =
+            self.receipts.datagrams_acked(range);
+            for seq in self.tracked_in_range(range) {
+                // An ACK of a copy sent before a fast retransmit clears it too.
+                let seq = self.resent_as.remove(&seq).unwrap_or(seq);
+                if let Some(tracked) = self.sent_datagrams.remove(&seq) {
+                    for old in &tracked.earlier {
+                        self.resent_as.remove(old);
+                    }
+                    self.resend_bytes = self.resend_bytes.saturating_sub(tracked.datagram.size());
+                    for id in &tracked.receipts {
+                        self.receipts.frame_acked(*id);
+                    }
+                    if let crate::protocol::datagram::DatagramPayload::EncapsulatedPackets(_) =
                         &tracked.datagram.payload
-                {
-                    self.sliding
-                        .on_ack(now, &tracked.datagram, seq, tracked.send_time);
+                    {
+                        self.sliding.on_ack(&tracked.datagram, seq);
+                        if tracked.resends == 0 {
+                            self.sliding
+                                .sample_rtt(now.saturating_duration_since(tracked.send_time));
+                        }
+                    }
                 }
-            });
+            }
         }
     }
 
-    fn process_incoming_naks(&mut self, now: Instant) {
+    /// Act on the NAKs received so far: with `fast_retransmit`, queue the
+    /// NAKed datagrams to go out again right away, else mark them due for
+    /// the next tick.
+    pub(crate) fn process_incoming_naks(&mut self, now: Instant) {
         while let Some(range) = self.incoming_naks.pop_front() {
-            Self::for_each_sequence_in_range(range, |seq| {
-                if let Some(mut tracked) = self.sent_datagrams.remove(&seq)
+            self.receipts.datagrams_lost(range);
+            for seq in self.tracked_in_range(range) {
+                if let Some(tracked) = self.sent_datagrams.get_mut(&seq)
                     && let crate::protocol::datagram::DatagramPayload::EncapsulatedPackets(_) =
                         &tracked.datagram.payload
                 {
                     self.sliding.on_nak();
-                    tracked.next_send = now;
-                    self.sent_datagrams.insert(seq, tracked);
+                    if self.fast_retransmit {
+                        self.retransmit_now(seq, now);
+                    } else {
+                        tracked.next_send = now;
+                    }
                 }
-            });
+            }
         }
     }
 
-    fn for_each_sequence_in_range<F>(range: SequenceRange, mut f: F)
-    where
-        F: FnMut(Sequence24),
-    {
+    /// Sequences in `range` that may have a tracked datagram, or be the old
+    /// sequence of one that was fast retransmitted.
+    ///
+    /// Walks whichever is shorter, the range itself or `sent_datagrams`, so
+    /// a wide range costs no more than the datagrams actually in flight.
+    fn tracked_in_range(&self, range: SequenceRange) -> Vec<Sequence24> {
+        let len = range_len(range) as usize;
+        if len > self.sent_datagrams.len() + self.resent_as.len() {
+            return self
+                .sent_datagrams
+                .keys()
+                .chain(self.resent_as.keys())
+                .copied()
+                .filter(|seq| range.start.distance_to(*seq) < len as u32)
+                .collect();
+        }
+
+        let mut out = Vec::with_capacity(len);
         let mut seq = range.start;
         loop {
-            f(seq);
+            out.push(seq);
             if seq == range.end {
                 break;
             }
             seq = seq.next();
         }
+        out
+    }
+}
+
+/// Number of sequences covered by `range`, wrapping at 2^24.
+fn range_len(range: SequenceRange) -> u32 {
+    range.start.distance_to(range.end) + 1
+}
+
+/// Whether `range` could describe datagrams we actually sent. No sender has
+/// more than `MAX_ACK_SEQUENCES` datagrams in flight, so anything wider is
+/// malformed or hostile and dropped before it costs any work.
+fn plausible_range(range: &SequenceRange) -> bool {
+    let ok = range_len(*range) <= MAX_ACK_SEQUENCES as u32;
+    if !ok {
+        tracing::debug!(
+            start = range.start.value(),
+            end = range.end.value(),
+            "dropping implausible ack/nak range"
+        );
     }
+    ok
 }
@@ -1,24 +1,44 @@
+/// Append a `DebugEventKind` to the session's debug log, if it keeps one.
+/// Expands to nothing without the `debug-log` feature.
+macro_rules! debug_event {
+    ($session:expr, $now:expr, $kind:expr) => {
+        #[cfg(any(test, feature = "debug-log"))]
+        {
+            if let Some(log) = $session.stats.debug_log() {
+                log.record($now, $kind);
+            }
+        }
+    };
+}
+
 mod control;
 mod tick;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use bytes::{Bytes, BytesMut};
 use thiserror::Error;
 
+use crate::error::ConfigError;
 use crate::protocol::{
-    constants::{SESSION_STALE, SESSION_TIMEOUT},
+    constants::{KEEPALIVE_INTERVAL, MAXIMUM_ORDERING_CHANNELS, SESSION_STALE, SESSION_TIMEOUT},
     datagram::{Datagram, DatagramPayload},
     packet::{DecodeError, RaknetPacket},
     reliability::Reliability,
     state::{DisconnectReason, RakPriority},
+    types::RaknetTime,
 };
 
-use super::{Session, SessionTunables};
+#[cfg(any(test, feature = "debug-log"))]
+use super::debug_log::DebugEventKind;
+use super::{AckPolicy, ReceiptOutcome, SendOptions, Session, SessionTunables, stats::SharedStats};
 
 /// High-level connection state for a managed RakNet session.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum ConnectionState {
+    #[default]
     Unconnected,
     OnlineHandshake,
     Connected,
@@ -39,6 +59,14 @@ pub enum SessionError {
         msg: &'static str,
     },
 
+    #[error("session send queue full")]
+    SendQueueFull,
+
+    /// The peer left a gap on an ordering channel for longer than its
+    /// `ReorderLimit` lets the channel hold back.
+    #[error("ordering channel {0} buffered more than its reorder limit")]
+    ReorderBufferFull(u8),
+
     #[error(transparent)]
     Protocol(#[from] DecodeError),
 }
@@ -51,31 +79,298 @@ pub enum SessionRole {
 }
 
 /// Configuration for the high-level session manager.
+///
+/// Listeners and clients build one from their own config unless
+/// `session_config` is set there, in which case it is used as is apart from
+/// `role` and `guid`.
 #[derive(Debug, Clone)]
 pub struct SessionConfig {
     pub role: SessionRole,
     pub guid: u64,
     pub session_stale: Duration,
     pub session_timeout: Duration,
+    /// How long a connected session may go without sending anything before
+    /// it pings the peer to keep the connection alive. `None` sends no
+    /// automatic pings, for applications with a heartbeat of their own.
+    pub keepalive_interval: Option<Duration>,
+    /// Old name for `keepalive_interval`; a value other than the default
+    /// overrides it.
+    #[deprecated(note = "use `keepalive_interval`")]
     pub ping_interval: Duration,
     pub max_queued_reliable_bytes: Option<usize>,
+    /// Cap on application data waiting in the outgoing queue; `None` leaves
+    /// it unbounded.
+    pub send_queue_limit: Option<SendQueueLimit>,
+    /// Close the session with `QueueTooLong` when the peer falls too far
+    /// behind for too long; `None` never does.
+    pub backlog_limit: Option<BacklogLimit>,
     pub session: SessionTunables,
+    /// The session's `DebugLog`; kept off unless `debug_log_capacity` turns
+    /// it on.
+    pub debug_log: DebugLogConfig,
 }
 
 impl Default for SessionConfig {
+    #[allow(deprecated)]
     fn default() -> Self {
         Self {
             role: SessionRole::Client,
             guid: 0,
             session_stale: SESSION_STALE,
             session_timeout: SESSION_TIMEOUT,
-            ping_interval: Duration::from_millis(500),
+            keepalive_interval: Some(KEEPALIVE_INTERVAL),
+            ping_interval: KEEPALIVE_INTERVAL,
             max_queued_reliable_bytes: None,
+            send_queue_limit: None,
+            backlog_limit: None,
             session: SessionTunables::default(),
+            debug_log: DebugLogConfig::default(),
+        }
+    }
+}
+
+/// Whether and how much a session records in its `DebugLog`.
+///
+/// Opaque so that the configs holding it look the same with the
+/// `debug-log` feature on or off; with it, set the capacity through the
+/// configs' `debug_log_capacity`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DebugLogConfig {
+    /// Events kept; 0 keeps none.
+    #[cfg_attr(not(any(test, feature = "debug-log")), allow(dead_code))]
+    pub(crate) capacity: usize,
+}
+
+/// Cap on a session's outgoing queue: frames packed into no datagram yet,
+/// each part of a split counting as one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQueueLimit {
+    /// Queued bytes, frame headers included.
+    pub max_bytes: usize,
+    /// Queued frames.
+    pub max_frames: usize,
+    /// What happens to a message that would take the queue past either cap.
+    pub policy: QueueLimitPolicy,
+}
+
+/// How far behind a peer may fall: frames not sent yet plus datagrams sent
+/// but not ACKed. A peer whose downlink can't keep up stays above the cap;
+/// a burst that drains again within `grace` is let through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BacklogLimit {
+    /// Cap on queued frames plus unACKed datagrams, counted together: a
+    /// datagram carrying several frames counts once, a frame still queued
+    /// counts on its own.
+    pub max_backlog: usize,
+    /// How long the backlog may stay above `max_backlog` before the session
+    /// is closed with `DisconnectReason::QueueTooLong`.
+    pub grace: Duration,
+}
+
+impl Default for BacklogLimit {
+    fn default() -> Self {
+        Self {
+            max_backlog: 8192,
+            grace: Duration::from_secs(10),
         }
     }
 }
 
+/// How a session treats a message that doesn't fit under its `SendQueueLimit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueLimitPolicy {
+    /// Drop the message and fail the send with `SendQueueFull`.
+    #[default]
+    Reject,
+    /// Close the session with `DisconnectReason::QueueTooLong`.
+    Disconnect,
+}
+
+/// Hand `pkt` straight to `f` if it is application data the session may
+/// deliver now and nothing before it was held back; defer it otherwise.
+fn route(
+    pkt: crate::session::IncomingPacket,
+    app_allowed: bool,
+    deferred: &mut Vec<crate::session::IncomingPacket>,
+    f: &mut impl FnMut(crate::session::IncomingPacket),
+) {
+    if !deferred.is_empty() || !is_app_packet(&pkt.packet) || !app_allowed {
+        deferred.push(pkt);
+    } else {
+        f(pkt);
+    }
+}
+
+/// Whether a frame-handling error means the peer is misbehaving or
+/// attacking the reassembly and reordering buffers, not just sending one
+/// bad packet, so the session is closed rather than the frame dropped.
+fn breaks_session_limits(e: &SessionError) -> bool {
+    matches!(
+        e,
+        SessionError::Protocol(
+            DecodeError::MissingSplitInfo
+                | DecodeError::SplitTooLarge
+                | DecodeError::SplitIndexOutOfRange
+                | DecodeError::SplitCountMismatch
+                | DecodeError::SplitBufferFull
+        ) | SessionError::ReorderBufferFull(_)
+    )
+}
+
+/// Largest reliable window `Sequence24` comparisons can tell apart.
+const MAX_RELIABLE_WINDOW: u64 = 1 << 23;
+
+/// Longest `AckPolicy::Delayed` wait, in ms; much more and a peer resends
+/// what already arrived.
+const MAX_ACK_DELAY_MS: u64 = 500;
+
+/// Reliable application data a session holds while its handshake finishes.
+const MAX_HELD_EARLY_BYTES: usize = 256 * 1024;
+
+impl SessionConfig {
+    /// Keep the last `capacity` protocol events in the session's `DebugLog`;
+    /// 0 keeps none.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn debug_log_capacity(mut self, capacity: usize) -> Self {
+        self.debug_log = DebugLogConfig { capacity };
+        self
+    }
+
+    /// `keepalive_interval`, unless the deprecated `ping_interval` was
+    /// changed from its default.
+    pub(crate) fn keepalive(&self) -> Option<Duration> {
+        #[allow(deprecated)]
+        let ping = self.ping_interval;
+        if ping != KEEPALIVE_INTERVAL {
+            Some(ping)
+        } else {
+            self.keepalive_interval
+        }
+    }
+
+    /// Reject combinations that would only show up later as sessions timing
+    /// out or refusing every packet.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.session_stale >= self.session_timeout {
+            return Err(ConfigError::StaleNotBeforeTimeout {
+                stale: self.session_stale,
+                timeout: self.session_timeout,
+            });
+        }
+        if let Some(keepalive) = self.keepalive()
+            && keepalive >= self.session_timeout
+        {
+            return Err(ConfigError::KeepaliveNotBeforeTimeout {
+                keepalive,
+                timeout: self.session_timeout,
+            });
+        }
+
+        let t = &self.session;
+        check_range(
+            "max_ordering_channels",
+            t.max_ordering_channels as u64,
+            1,
+            MAXIMUM_ORDERING_CHANNELS as u64,
+        )?;
+        check_range(
+            "ack_queue_capacity",
+            t.ack_queue_capacity as u64,
+            1,
+            u64::MAX,
+        )?;
+        check_range(
+            "reliable_window",
+            t.reliable_window as u64,
+            1,
+            MAX_RELIABLE_WINDOW,
+        )?;
+        check_range("max_split_parts", t.max_split_parts as u64, 1, u64::MAX)?;
+        check_range(
+            "max_concurrent_splits",
+            t.max_concurrent_splits as u64,
+            1,
+            u64::MAX,
+        )?;
+        check_range("max_split_bytes", t.max_split_bytes as u64, 1, u64::MAX)?;
+        check_range(
+            "min_rto",
+            t.min_rto.as_millis() as u64,
+            1,
+            t.max_rto.as_millis() as u64,
+        )?;
+        check_range("max_resends", t.max_resends as u64, 1, u64::MAX)?;
+        if let AckPolicy::Delayed(delay) = t.ack_policy {
+            check_range(
+                "ack_policy.delay",
+                delay.as_millis() as u64,
+                0,
+                MAX_ACK_DELAY_MS,
+            )?;
+        }
+        check_range(
+            "reorder_limit.max_bytes",
+            t.reorder_limit.max_bytes as u64,
+            1,
+            u64::MAX,
+        )?;
+        check_range(
+            "reorder_limit.max_frames",
+            t.reorder_limit.max_frames as u64,
+            1,
+            u64::MAX,
+        )?;
+        if let Some(limit) = &self.backlog_limit {
+            check_range(
+                "backlog_limit.max_backlog",
+                limit.max_backlog as u64,
+                1,
+                u64::MAX,
+            )?;
+        }
+        if let Some(limit) = &self.send_queue_limit {
+            check_range(
+                "send_queue_limit.max_bytes",
+                limit.max_bytes as u64,
+                1,
+                u64::MAX,
+            )?;
+            check_range(
+                "send_queue_limit.max_frames",
+                limit.max_frames as u64,
+                1,
+                u64::MAX,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn check_range(field: &'static str, value: u64, min: u64, max: u64) -> Result<(), ConfigError> {
+    if (min..=max).contains(&value) {
+        Ok(())
+    } else {
+        Err(ConfigError::OutOfRange {
+            field,
+            value,
+            min,
+            max,
+        })
+    }
+}
+
+/// The round trip of the online handshake, as the side that measured it
+/// saw it: the client from `ConnectionRequest` to `ConnectionRequestAccepted`,
+/// the server from that to `NewIncomingConnection`.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeTiming {
+    pub rtt: Duration,
+    /// Our timestamp, echoed back by the peer.
+    pub request_timestamp: RaknetTime,
+    /// The peer's timestamp.
+    pub accepted_timestamp: RaknetTime,
+}
+
 /// Higher-level wrapper around `Session` that tracks connection state,
 /// last activity and enforces a few simple state rules.
 pub struct ManagedSession {
@@ -85,11 +380,30 @@ pub struct ManagedSession {
     last_activity: Instant,
     config: SessionConfig,
     last_pong_received: Instant,
+    /// When a datagram last went out, or a ping was queued.
+    last_sent: Instant,
     last_ping_sent: Option<Instant>,
     current_ping_nonce: Option<u64>,
     queued_reliable_bytes: usize,
     remote_guid: Option<u64>,
+    /// Our address as the peer sees it, from its `NewIncomingConnection`.
+    server_addr: Option<SocketAddr>,
     last_disconnect_reason: Option<DisconnectReason>,
+    /// When we sent the handshake packet the peer's next one answers.
+    handshake_sent: Option<Instant>,
+    handshake_timing: Option<HandshakeTiming>,
+    stats: Arc<SharedStats>,
+    /// Reliable data that overtook the last handshake packet, delivered once
+    /// the handshake completes.
+    early: Vec<crate::session::IncomingPacket>,
+    early_bytes: usize,
+    /// When a graceful disconnect gives up waiting for ACKs.
+    close_deadline: Option<Instant>,
+    /// Since when the backlog has been above `backlog_limit`.
+    backlog_since: Option<Instant>,
+    /// Last state written to the debug log.
+    #[cfg(any(test, feature = "debug-log"))]
+    logged_state: ConnectionState,
 }
 
 impl ManagedSession {
@@ -98,6 +412,18 @@ impl ManagedSession {
     }
 
     pub fn with_config(peer: SocketAddr, mtu: usize, now: Instant, config: SessionConfig) -> Self {
+        #[cfg(any(test, feature = "debug-log"))]
+        let stats = match config.debug_log.capacity {
+            0 => SharedStats::new(),
+            capacity => SharedStats::with_debug_log(capacity),
+        };
+        #[cfg(not(any(test, feature = "debug-log")))]
+        let stats = SharedStats::new();
+        if let Some(limit) = config.send_queue_limit
+            && limit.policy == QueueLimitPolicy::Reject
+        {
+            stats.set_send_limit(limit, mtu);
+        }
         Self {
             inner: Session::with_tunables(mtu, config.session.clone()),
             peer,
@@ -107,12 +433,23 @@ impl ManagedSession {
             config,
 
             last_pong_received: now,
+            last_sent: now,
             last_ping_sent: None,
             current_ping_nonce: None,
 
             queued_reliable_bytes: 0,
             remote_guid: None,
+            server_addr: None,
             last_disconnect_reason: None,
+            handshake_sent: None,
+            handshake_timing: None,
+            stats: Arc::new(stats),
+            early: Vec::new(),
+            early_bytes: 0,
+            close_deadline: None,
+            backlog_since: None,
+            #[cfg(any(test, feature = "debug-log"))]
+            logged_state: ConnectionState::Unconnected,
         }
     }
 
@@ -128,14 +465,63 @@ impl ManagedSession {
         self.state
     }
 
+    /// The online handshake's round trip, once it completed.
+    pub fn handshake_timing(&self) -> Option<HandshakeTiming> {
+        self.handshake_timing
+    }
+
+    /// Our address as the peer sees it, once its `NewIncomingConnection`
+    /// has arrived.
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        self.server_addr
+    }
+
+    /// When the peer last sent anything or a message was last queued for
+    /// it, whichever is later; the session times out counting from here.
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
     pub fn config(&self) -> &SessionConfig {
         &self.config
     }
 
+    /// Counters shared with application handles for this session.
+    pub fn stats(&self) -> &Arc<SharedStats> {
+        &self.stats
+    }
+
     pub fn last_disconnect_reason(&self) -> Option<DisconnectReason> {
         self.last_disconnect_reason
     }
 
+    /// The error a closed session reports to its application.
+    pub fn close_error(&self) -> crate::RaknetError {
+        match self.last_disconnect_reason {
+            Some(reason) => crate::RaknetError::Disconnected(reason),
+            None => crate::RaknetError::ConnectionClosed,
+        }
+    }
+
+    /// Whether everything queued has been sent and every reliable datagram ACKed.
+    pub fn is_drained(&self) -> bool {
+        self.inner.outgoing_queue_len() == 0 && self.inner.unacked_datagrams() == 0
+    }
+
+    /// Mark that `flushed_to` reaches once everything queued so far has
+    /// been sent.
+    pub fn flush_mark(&self) -> u64 {
+        self.inner.frames_queued()
+    }
+
+    /// Every `flush_mark` at or below this is flushed: the frames queued
+    /// before it have all been sent and, if reliable, ACKed.
+    pub fn flushed_to(&self) -> u64 {
+        self.inner
+            .oldest_pending_frame()
+            .unwrap_or_else(|| self.inner.frames_queued())
+    }
+
     pub fn is_connected(&self) -> bool {
         matches!(
             self.state,
@@ -143,6 +529,12 @@ impl ManagedSession {
         )
     }
 
+    /// Treat the handshake as done, for replaying traffic captured after it.
+    #[cfg(any(test, feature = "analysis"))]
+    pub(crate) fn assume_connected(&mut self) {
+        self.state = ConnectionState::Connected;
+    }
+
     /// Queue a high-level RakNet packet for sending.
     ///
     /// This applies basic state checks (e.g. prevent unconnected packets when
@@ -154,13 +546,80 @@ impl ManagedSession {
         rel: Reliability,
         channel: u8,
         priority: RakPriority,
+        now: Instant,
+    ) -> Result<(), SessionError> {
+        let user_data = matches!(pkt, RaknetPacket::UserData { .. });
+        self.check_queue(user_data, is_unconnected_packet(&pkt), channel)?;
+        let mut buffer = BytesMut::new();
+        if pkt.encode(&mut buffer).is_err() {
+            // Nothing to queue, as with `Session::queue_packet`.
+            return Ok(());
+        }
+        self.check_send_queue_limit(buffer.len(), rel)?;
+        let added = self
+            .inner
+            .queue_encoded(buffer.freeze(), rel, channel, priority);
+        self.note_queued(added, now);
+        Ok(())
+    }
+
+    /// Queue an application message that is already encoded (ID byte +
+    /// body), sharing `buffer` with the outgoing frames instead of copying it.
+    pub fn queue_app_message(
+        &mut self,
+        buffer: Bytes,
+        rel: Reliability,
+        channel: u8,
+        priority: RakPriority,
+        now: Instant,
+    ) -> Result<(), SessionError> {
+        self.check_queue(true, false, channel)?;
+        self.check_send_queue_limit(buffer.len(), rel)?;
+        let added = self.inner.queue_encoded(buffer, rel, channel, priority);
+        self.note_queued(added, now);
+        Ok(())
+    }
+
+    /// Like `queue_app_message`, with a receipt and/or an expiry; see
+    /// `Session::queue_encoded_with`. A message turned away is reported
+    /// `Lost` to its receipt.
+    pub fn queue_app_message_with(
+        &mut self,
+        buffer: Bytes,
+        rel: Reliability,
+        channel: u8,
+        priority: RakPriority,
+        opts: SendOptions,
+        now: Instant,
+    ) -> Result<(), SessionError> {
+        let checked = self
+            .check_queue(true, false, channel)
+            .and_then(|()| self.check_send_queue_limit(buffer.len(), rel));
+        if let Err(e) = checked {
+            if let Some(done) = opts.receipt {
+                let _ = done.send(ReceiptOutcome::Lost);
+            }
+            return Err(e);
+        }
+        let added = self
+            .inner
+            .queue_encoded_with(buffer, rel, channel, priority, opts, now);
+        self.note_queued(added, now);
+        Ok(())
+    }
+
+    fn check_queue(
+        &self,
+        user_data: bool,
+        unconnected: bool,
+        channel: u8,
     ) -> Result<(), SessionError> {
         match self.state {
             ConnectionState::Closed | ConnectionState::Closing => {
                 return Err(SessionError::Closed);
             }
             ConnectionState::Unconnected | ConnectionState::OnlineHandshake => {
-                if matches!(pkt, RaknetPacket::UserData { .. }) {
+                if user_data {
                     return Err(SessionError::InvalidState {
                         state: self.state,
                         msg: "cannot send user data before connection is established",
@@ -168,7 +627,7 @@ impl ManagedSession {
                 }
             }
             ConnectionState::Connected | ConnectionState::Stale => {
-                if is_unconnected_packet(&pkt) {
+                if unconnected {
                     return Err(SessionError::InvalidState {
                         state: self.state,
                         msg: "cannot send unconnected packet on a connected session",
@@ -183,13 +642,38 @@ impl ManagedSession {
                 msg: "ordering channel out of range",
             });
         }
+        Ok(())
+    }
 
-        let added = self.inner.queue_packet(pkt, rel, channel, priority);
+    /// Apply `send_queue_limit` to a `len`-byte application message.
+    fn check_send_queue_limit(&mut self, len: usize, rel: Reliability) -> Result<(), SessionError> {
+        let Some(limit) = self.config.send_queue_limit else {
+            return Ok(());
+        };
+        let (frames, bytes) = self.inner.queue_cost(len, rel);
+        if self.inner.outgoing_queue_len() + frames <= limit.max_frames
+            && self.inner.outgoing_queue_bytes() + bytes <= limit.max_bytes
+        {
+            return Ok(());
+        }
+        match limit.policy {
+            QueueLimitPolicy::Reject => {
+                self.stats.set_send_queue_full(true);
+                Err(SessionError::SendQueueFull)
+            }
+            QueueLimitPolicy::Disconnect => {
+                self.close_queue_too_long();
+                Err(SessionError::Closed)
+            }
+        }
+    }
+
+    fn note_queued(&mut self, added: usize, now: Instant) {
         self.queued_reliable_bytes = self.queued_reliable_bytes.saturating_add(added);
         // Treat an outbound enqueue as activity to avoid stale self timeouts
-        self.last_activity = Instant::now();
-
-        Ok(())
+        self.last_activity = now;
+        self.stats.record_message_sent();
+        self.sync_stats();
     }
 
     /// Handle an incoming datagram, updating state and returning any
@@ -199,52 +683,323 @@ impl ManagedSession {
         dgram: Datagram,
         now: Instant,
     ) -> Result<Vec<crate::session::IncomingPacket>, SessionError> {
+        let mut out = Vec::new();
+        self.handle_datagram_with(dgram, now, |pkt| out.push(pkt))?;
+        Ok(out)
+    }
+
+    /// Deliver the ordered packets `on_tick` released by giving up on a
+    /// split they waited behind, with the same checks `handle_datagram_with`
+    /// applies.
+    pub fn deliver_released_with(
+        &mut self,
+        now: Instant,
+        mut f: impl FnMut(crate::session::IncomingPacket),
+    ) {
         if self.state == ConnectionState::Closed {
-            return Ok(Vec::new());
+            return;
+        }
+        let app_allowed = self.is_connected();
+        let mut deferred = Vec::new();
+        let mut delivered = 0;
+        self.inner.deliver_released_with(|pkt| {
+            delivered += 1;
+            route(pkt, app_allowed, &mut deferred, &mut f);
+        });
+        self.dispatch_deferred(deferred, now, &mut f);
+        self.stats.record_messages_received(delivered);
+    }
+
+    /// Handle control packets, and application data that had to wait for
+    /// them or for the handshake, in the order they arrived.
+    fn dispatch_deferred(
+        &mut self,
+        deferred: Vec<crate::session::IncomingPacket>,
+        now: Instant,
+        f: &mut impl FnMut(crate::session::IncomingPacket),
+    ) {
+        for pkt in deferred {
+            let accepted = if is_app_packet(&pkt.packet) {
+                self.accepts(&pkt.packet)
+            } else {
+                self.handle_control_packet(&pkt.packet, now)
+            };
+            if accepted {
+                f(pkt);
+            } else if is_app_packet(&pkt.packet) {
+                self.hold_early(pkt);
+            }
+            if self.is_connected() && !self.early.is_empty() {
+                self.early_bytes = 0;
+                self.early.drain(..).for_each(&mut *f);
+            }
+        }
+    }
+
+    /// Like `handle_datagram`, but hands packets to `f` instead of collecting them.
+    ///
+    /// Application packets are passed on as soon as the reliability/ordering
+    /// layers release them. Once a session control packet shows up, it and
+    /// everything after it in the datagram are processed in order after the
+    /// datagram is accepted, so data following e.g. `NewIncomingConnection`
+    /// sees the updated state. Reliable data that arrives while the handshake
+    /// is still completing is held and passed on right after it; other
+    /// packets not allowed in the current state are dropped and counted.
+    pub fn handle_datagram_with(
+        &mut self,
+        dgram: Datagram,
+        now: Instant,
+        mut f: impl FnMut(crate::session::IncomingPacket),
+    ) -> Result<(), SessionError> {
+        if self.state == ConnectionState::Closed {
+            return Ok(());
         }
 
         self.last_activity = now;
         if self.state == ConnectionState::Stale {
             self.state = ConnectionState::Connected;
         }
+        self.stats.record_datagram_received(dgram.size());
+        self.log_datagram(now, &dgram, true);
+        #[cfg(any(test, feature = "debug-log"))]
+        let reorder_before = self.inner.memory_usage().reorder_bytes;
 
-        match dgram.payload {
+        let res = match dgram.payload {
             DatagramPayload::EncapsulatedPackets(packets) => {
-                let pkts = self.inner.handle_data_payload(packets, now)?;
+                let mut delivered = 0;
+                let app_allowed = self.is_connected();
+                // Control packets and data before the handshake is done are
+                // rare, so this only allocates when one shows up.
+                let mut deferred = Vec::new();
+                let seq = dgram.header.sequence;
+                let handled = self
+                    .inner
+                    .handle_data_datagram_with(seq, packets, now, |pkt| {
+                        delivered += 1;
+                        route(pkt, app_allowed, &mut deferred, &mut f);
+                    });
+                if let Err(e) = &handled
+                    && breaks_session_limits(e)
+                {
+                    tracing::warn!(error = %e, "peer broke a session limit, disconnecting");
+                    self.close_now(DisconnectReason::BadPacket);
+                    return handled;
+                }
 
                 // Only DATA datagrams participate in sequence/NACK tracking.
                 // We process sequence AFTER handling payload so that if handling fails
-                // (e.g. split buffer full), we don't ACK the datagram, forcing a resend.
-                self.inner.process_datagram_sequence(dgram.header.sequence);
-
-                for pkt in &pkts {
-                    self.handle_control_packet(&pkt.packet, now);
+                // (e.g. a reliable index beyond the window), we don't ACK the
+                // datagram, forcing a resend. Frames it delivered are already
+                // recorded, so the resend only brings in the rest.
+                if handled.is_ok() {
+                    self.inner.acknowledge(dgram.header.sequence, now);
                 }
-                Ok(pkts)
+
+                self.dispatch_deferred(deferred, now, &mut f);
+                self.stats.record_messages_received(delivered);
+                handled
             }
             DatagramPayload::Ack(payload) => {
+                self.stats.record_ack_received();
                 self.inner.handle_ack_payload(payload);
-                Ok(Vec::new())
+                // Sampled on arrival, not at the next tick, so the RTT
+                // measures the link and the peer's `AckPolicy` only.
+                self.inner.process_incoming_acks(now);
+                Ok(())
             }
             DatagramPayload::Nak(payload) => {
+                self.stats.record_nak_received();
                 self.inner.handle_nack_payload(payload);
-                Ok(Vec::new())
+                // Fast retransmits queued here go out with the next
+                // `build_datagram`, in this same muxer turn.
+                self.inner.process_incoming_naks(now);
+                Ok(())
+            }
+        };
+
+        #[cfg(any(test, feature = "debug-log"))]
+        {
+            let reorder_after = self.inner.memory_usage().reorder_bytes;
+            if reorder_before == 0 && reorder_after > 0 {
+                debug_event!(
+                    self,
+                    now,
+                    DebugEventKind::OrderingStalled {
+                        buffered_bytes: reorder_after
+                    }
+                );
+            } else if reorder_before > 0 && reorder_after == 0 {
+                debug_event!(self, now, DebugEventKind::OrderingResumed);
             }
         }
+        self.note_state(now);
+        self.sync_stats();
+        res
     }
 
-    /// Build the next outgoing datagram, if any.
-    /// ACKs/NACKs/Resends are built in `on_tick`.
+    /// Keep reliable data that overtook the end of the handshake (e.g. sent
+    /// right after `ConnectionRequestAccepted` and delivered before the
+    /// client's `NewIncomingConnection`): it has been ACKed, so dropping it
+    /// would lose it for good. Anything else too early is refused.
+    fn hold_early(&mut self, pkt: crate::session::IncomingPacket) {
+        let fits = self.early_bytes + pkt.raw.len() <= MAX_HELD_EARLY_BYTES;
+        if self.state == ConnectionState::OnlineHandshake && pkt.reliability.is_reliable() && fits {
+            self.early_bytes += pkt.raw.len();
+            self.early.push(pkt);
+        } else {
+            self.stats.record_out_of_state_packet();
+        }
+    }
+
+    /// Build the next outgoing datagram, if any: ACKs that are due, a fast
+    /// retransmit of something NAKed, else fresh data.
+    /// NACKs and timed resends are built in `on_tick`.
     pub fn build_datagram(&mut self, now: Instant) -> Option<Datagram> {
+        self.note_state(now);
+        if self.inner.ack_deadline(now).is_some_and(|at| at <= now)
+            && let Some(dgram) = self.inner.build_ack_datagram(now)
+        {
+            // Counts as sending, as the ACKs `on_tick` sends do.
+            self.last_sent = now;
+            self.log_datagram(now, &dgram, false);
+            self.stats.record_datagram_sent(dgram.size());
+            return Some(dgram);
+        }
+        if let Some(dgram) = self.inner.take_retransmit() {
+            // Its frames were debited when they first went out.
+            self.last_sent = now;
+            self.log_datagram(now, &dgram, false);
+            self.stats.record_datagram_resent();
+            self.stats.record_datagram_sent(dgram.size());
+            self.sync_stats();
+            return Some(dgram);
+        }
         let dgram = self.inner.build_data_datagram(now)?;
+        self.stats.set_send_queue_full(false);
+        self.last_sent = now;
 
         if let DatagramPayload::EncapsulatedPackets(packets) = &dgram.payload {
             self.debit_reliable_bytes(packets);
         }
 
+        self.stats.record_datagram_sent(dgram.size());
+        debug_event!(
+            self,
+            now,
+            DebugEventKind::DatagramSent {
+                sequence: dgram.header.sequence.value(),
+                size: dgram.size(),
+            }
+        );
+        self.sync_stats();
         Some(dgram)
     }
 
+    /// ACK datagrams for everything received so far, for a session about to
+    /// be dropped. Unlike `on_tick` this sends no resends, NACKs or pings.
+    pub fn final_acks(&mut self, now: Instant) -> Vec<Datagram> {
+        let mut out = Vec::new();
+        while let Some(d) = self.inner.build_ack_datagram(now) {
+            self.stats.record_datagram_sent(d.size());
+            self.log_datagram(now, &d, false);
+            out.push(d);
+        }
+        out
+    }
+
+    /// Log a state change made since the last call.
+    #[cfg(any(test, feature = "debug-log"))]
+    fn note_state(&mut self, now: Instant) {
+        if self.state != self.logged_state {
+            let from = std::mem::replace(&mut self.logged_state, self.state);
+            debug_event!(
+                self,
+                now,
+                DebugEventKind::StateChanged {
+                    from,
+                    to: self.state
+                }
+            );
+        }
+    }
+
+    #[cfg(not(any(test, feature = "debug-log")))]
+    fn note_state(&mut self, _now: Instant) {}
+
+    /// Log `dgram`: one event for data, one per range for ACKs and NACKs.
+    ///
+    /// Outgoing data only comes through here from `on_tick`, which makes it a
+    /// resend; first sends are logged by `build_datagram`.
+    #[cfg(any(test, feature = "debug-log"))]
+    fn log_datagram(&self, now: Instant, dgram: &Datagram, received: bool) {
+        let Some(log) = self.stats.debug_log() else {
+            return;
+        };
+        match &dgram.payload {
+            DatagramPayload::EncapsulatedPackets(_) => {
+                let sequence = dgram.header.sequence.value();
+                let size = dgram.size();
+                log.record(
+                    now,
+                    if received {
+                        DebugEventKind::DatagramReceived { sequence, size }
+                    } else {
+                        DebugEventKind::DatagramResent { sequence, size }
+                    },
+                );
+            }
+            DatagramPayload::Ack(payload) => {
+                for r in &payload.ranges {
+                    let (start, end) = (r.start.value(), r.end.value());
+                    log.record(
+                        now,
+                        if received {
+                            DebugEventKind::AckReceived { start, end }
+                        } else {
+                            DebugEventKind::AckSent { start, end }
+                        },
+                    );
+                }
+            }
+            DatagramPayload::Nak(payload) => {
+                for r in &payload.ranges {
+                    let (start, end) = (r.start.value(), r.end.value());
+                    log.record(
+                        now,
+                        if received {
+                            DebugEventKind::NakReceived { start, end }
+                        } else {
+                            DebugEventKind::NakSent { start, end }
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(not(any(test, feature = "debug-log")))]
+    fn log_datagram(&self, _now: Instant, _dgram: &Datagram, _received: bool) {}
+
+    /// Publish gauges (RTT, queue depths) that are read rather than counted.
+    pub(crate) fn sync_stats(&self) {
+        self.stats.set_state(self.state);
+        self.stats.set_rtt(self.inner.rtt());
+        self.stats.set_rto(self.inner.rto());
+        self.stats.set_queue_depths(
+            self.inner.outgoing_queue_len(),
+            self.inner.unacked_datagrams(),
+            self.inner.reorder_frames(),
+        );
+        self.stats.set_memory_usage(&self.inner.memory_usage());
+        self.stats.set_duplicates(
+            self.inner.duplicate_datagrams(),
+            self.inner.duplicate_frames(),
+        );
+        self.stats
+            .set_messages_expired(self.inner.expired_messages());
+        self.stats.set_splits_expired(self.inner.expired_splits());
+    }
+
     /// Filter a batch of decoded packets down to game-level packets that
     /// should be delivered to the application. Currently this keeps only
     /// user-data packets (IDs >= 0x80).
@@ -257,6 +1012,18 @@ impl ManagedSession {
     }
 }
 
+impl Drop for ManagedSession {
+    /// However the muxer lets go of the session, its handles see it closed
+    /// and those waiting on `SharedStats::ended` hear why.
+    fn drop(&mut self) {
+        self.stats.set_state(ConnectionState::Closed);
+        self.stats.mark_ended(
+            self.last_disconnect_reason
+                .unwrap_or(DisconnectReason::Disconnected),
+        );
+    }
+}
+
 fn is_unconnected_packet(pkt: &RaknetPacket) -> bool {
     matches!(
         pkt,
@@ -275,7 +1042,9 @@ mod tests {
     use super::*;
     use crate::protocol::{
         datagram::DatagramPayload,
-        packet::{ConnectionRequest, ConnectionRequestAccepted, DisconnectionNotification},
+        packet::{
+            ConnectedPong, ConnectionRequest, ConnectionRequestAccepted, DisconnectionNotification,
+        },
         state::DisconnectReason,
         types::RaknetTime,
     };
@@ -287,6 +1056,9 @@ mod tests {
 
         let mut ms = ManagedSession::new(peer, 1200, Instant::now());
         assert_eq!(ms.state(), ConnectionState::Unconnected);
+        ms.start_client_handshake(0x02, Instant::now(), false)
+            .unwrap();
+        assert_eq!(ms.state(), ConnectionState::OnlineHandshake);
 
         let ctrl = RaknetPacket::ConnectionRequestAccepted(ConnectionRequestAccepted {
             address: "127.0.0.1:19132".parse().unwrap(),
@@ -326,6 +1098,60 @@ mod tests {
         assert!(matches!(pkt, RaknetPacket::ConnectionRequest(_)));
     }
 
+    #[test]
+    fn handshake_round_trips_seed_the_rtt() {
+        let peer: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let t0 = Instant::now();
+        let accepted = RaknetPacket::ConnectionRequestAccepted(ConnectionRequestAccepted {
+            address: peer,
+            system_index: 0,
+            system_addresses: [peer; 10],
+            request_timestamp: RaknetTime(5),
+            accepted_timestamp: RaknetTime(9000),
+        });
+
+        let mut client = ManagedSession::new(peer, 1200, t0);
+        client.start_client_handshake(0x02, t0, false).unwrap();
+        let _ = client.build_datagram(t0);
+        client.handle_control_packet(&accepted, t0 + Duration::from_millis(30));
+        let timing = client.handshake_timing().unwrap();
+        assert_eq!(timing.rtt, Duration::from_millis(30));
+        assert_eq!(
+            (timing.request_timestamp.0, timing.accepted_timestamp.0),
+            (5, 9000)
+        );
+        assert_eq!(client.inner.rtt(), Some(Duration::from_millis(30)));
+        // The server's timestamp goes back to it in NewIncomingConnection.
+        let dgram = client
+            .build_datagram(t0 + Duration::from_millis(30))
+            .unwrap();
+        let RaknetPacket::NewIncomingConnection(nic) = decode_first_packet(&dgram) else {
+            panic!("expected NewIncomingConnection");
+        };
+        assert_eq!(nic.request_timestamp.0, 9000);
+
+        let config = SessionConfig {
+            role: SessionRole::Server,
+            ..Default::default()
+        };
+        let mut server = ManagedSession::with_config(peer, 1200, t0, config);
+        let request = RaknetPacket::ConnectionRequest(ConnectionRequest {
+            client_guid: 0xaa,
+            timestamp: RaknetTime(1),
+            secure: false,
+        });
+        server.handle_control_packet(&request, t0);
+        server.handle_control_packet(
+            &RaknetPacket::NewIncomingConnection(nic),
+            t0 + Duration::from_millis(40),
+        );
+        assert_eq!(
+            server.handshake_timing().map(|t| t.rtt),
+            Some(Duration::from_millis(40))
+        );
+        assert_eq!(server.inner.rtt(), Some(Duration::from_millis(40)));
+    }
+
     #[test]
     fn server_handles_connection_request_and_sends_accept() {
         let peer: SocketAddr = "127.0.0.1:19134".parse().unwrap();
@@ -353,12 +1179,61 @@ mod tests {
         assert!(matches!(pkt, RaknetPacket::ConnectionRequestAccepted(_)));
     }
 
+    #[test]
+    fn backlog_limit_only_applies_while_connected() {
+        let peer: SocketAddr = "127.0.0.1:19135".parse().unwrap();
+        let t0 = Instant::now();
+        let config = SessionConfig {
+            backlog_limit: Some(BacklogLimit {
+                max_backlog: 4,
+                grace: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        };
+        let backlogged = |draining: bool| {
+            let mut ms = ManagedSession::with_config(peer, 1200, t0, config.clone());
+            ms.state = ConnectionState::Connected;
+            for _ in 0..10 {
+                ms.queue_app_message(
+                    Bytes::from_static(b"\xfe"),
+                    Reliability::ReliableOrdered,
+                    0,
+                    RakPriority::Normal,
+                    t0,
+                )
+                .unwrap();
+            }
+            if draining {
+                let deadline = t0 + Duration::from_secs(60);
+                ms.disconnect_gracefully(DisconnectReason::Disconnected, deadline)
+                    .unwrap();
+            }
+            let _ = ms.on_tick(t0);
+            let _ = ms.on_tick(t0 + Duration::from_secs(2));
+            ms
+        };
+
+        let stuck = backlogged(false);
+        assert_eq!(stuck.state(), ConnectionState::Closed);
+        assert!(matches!(
+            stuck.last_disconnect_reason(),
+            Some(DisconnectReason::QueueTooLong)
+        ));
+
+        let closing = backlogged(true);
+        assert_eq!(closing.state(), ConnectionState::Closing);
+        assert!(matches!(
+            closing.last_disconnect_reason(),
+            Some(DisconnectReason::Disconnected)
+        ));
+    }
+
     #[test]
     fn ping_is_scheduled_during_tick() {
         let peer: SocketAddr = "127.0.0.1:19135".parse().unwrap();
         let now = Instant::now();
         let config = SessionConfig {
-            ping_interval: Duration::from_millis(0),
+            keepalive_interval: Some(Duration::ZERO),
             ..Default::default()
         };
 
@@ -375,6 +1250,149 @@ mod tests {
         assert!(matches!(pkt, RaknetPacket::ConnectedPing(_)));
     }
 
+    #[test]
+    fn keepalive_pings_only_idle_sessions() {
+        let peer: SocketAddr = "127.0.0.1:19135".parse().unwrap();
+        let t0 = Instant::now();
+        let keepalive = Duration::from_secs(2);
+        let config = SessionConfig {
+            keepalive_interval: Some(keepalive),
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, t0, config);
+        ms.state = ConnectionState::Connected;
+
+        let _ = ms.on_tick(t0 + Duration::from_secs(1));
+        assert!(ms.build_datagram(t0 + Duration::from_secs(1)).is_none());
+        assert_eq!(ms.next_deadline(t0), t0 + keepalive);
+
+        // Sending restarts the wait.
+        let t1 = t0 + Duration::from_millis(1500);
+        let pkt = RaknetPacket::UserData {
+            id: 0x86,
+            payload: Bytes::from_static(b"x"),
+        };
+        ms.queue_app_packet(pkt, Reliability::Unreliable, 0, RakPriority::Normal, t1)
+            .unwrap();
+        assert!(ms.build_datagram(t1).is_some());
+        let _ = ms.on_tick(t0 + keepalive);
+        assert!(ms.build_datagram(t0 + keepalive).is_none());
+
+        // A ping whose pong never comes doesn't stop the next one.
+        for at in [t1 + keepalive, t1 + keepalive * 2] {
+            let _ = ms.on_tick(at);
+            let dgram = ms.build_datagram(at).expect("expected a keepalive ping");
+            assert!(matches!(
+                decode_first_packet(&dgram),
+                RaknetPacket::ConnectedPing(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn an_ack_restarts_the_keepalive_wait() {
+        use crate::protocol::{
+            constants::DatagramFlags,
+            encapsulated_packet::EncapsulatedPacket,
+            types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24},
+        };
+
+        let peer: SocketAddr = "127.0.0.1:19135".parse().unwrap();
+        let t0 = Instant::now();
+        let keepalive = Duration::from_secs(2);
+        let config = SessionConfig {
+            keepalive_interval: Some(keepalive),
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, t0, config);
+        ms.state = ConnectionState::Connected;
+
+        let t1 = t0 + Duration::from_millis(1500);
+        let data = Datagram {
+            header: DatagramHeader {
+                flags: DatagramFlags::VALID,
+                sequence: Sequence24::new(0),
+            },
+            payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+                header: EncapsulatedPacketHeader {
+                    reliability: Reliability::Reliable,
+                    is_split: false,
+                    needs_bas: false,
+                },
+                bit_length: 8,
+                reliable_index: Some(Sequence24::new(0)),
+                sequence_index: None,
+                ordering_index: None,
+                ordering_channel: None,
+                split: None,
+                payload: Bytes::from_static(b"\x86"),
+            }]),
+        };
+        ms.handle_datagram_with(data, t1, |_| {}).unwrap();
+        let acked = ms.next_deadline(t1);
+        let ack = ms.build_datagram(acked).expect("expected an ACK");
+        assert!(matches!(ack.payload, DatagramPayload::Ack(_)));
+        assert_eq!(ms.next_deadline(acked), acked + keepalive);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_ping_interval_still_sets_the_keepalive() {
+        let config = SessionConfig {
+            keepalive_interval: None,
+            ping_interval: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert_eq!(config.keepalive(), Some(Duration::from_secs(1)));
+
+        let config = SessionConfig {
+            keepalive_interval: None,
+            ..Default::default()
+        };
+        assert_eq!(config.keepalive(), None);
+    }
+
+    #[test]
+    fn keepalive_can_be_turned_off() {
+        let peer: SocketAddr = "127.0.0.1:19135".parse().unwrap();
+        let t0 = Instant::now();
+        let config = SessionConfig {
+            keepalive_interval: None,
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, t0, config);
+        ms.state = ConnectionState::Connected;
+
+        let later = t0 + Duration::from_secs(4);
+        let _ = ms.on_tick(later);
+        assert!(ms.build_datagram(later).is_none());
+        assert_eq!(ms.next_deadline(t0), t0 + SESSION_STALE);
+    }
+
+    #[test]
+    fn pongs_keep_the_session_alive_and_measure_the_rtt() {
+        let peer: SocketAddr = "127.0.0.1:19135".parse().unwrap();
+        let t0 = Instant::now();
+        let mut ms = ManagedSession::new(peer, 1200, t0);
+        ms.state = ConnectionState::Connected;
+
+        let sent = t0 + KEEPALIVE_INTERVAL;
+        let _ = ms.on_tick(sent);
+        let dgram = ms.build_datagram(sent).unwrap();
+        let RaknetPacket::ConnectedPing(ping) = decode_first_packet(&dgram) else {
+            panic!("expected a keepalive ping");
+        };
+        let pong = RaknetPacket::ConnectedPong(ConnectedPong {
+            ping_time: ping.ping_time,
+            pong_time: RaknetTime(0),
+        });
+        let answered = sent + Duration::from_millis(25);
+        ms.handle_control_packet(&pong, answered);
+
+        assert_eq!(ms.last_activity(), answered);
+        assert_eq!(ms.inner.rtt(), Some(Duration::from_millis(25)));
+    }
+
     #[test]
     fn rejects_user_data_before_handshake() {
         let peer: SocketAddr = "127.0.0.1:19136".parse().unwrap();
@@ -386,7 +1404,7 @@ mod tests {
             payload: Bytes::from_static(b"test"),
         };
 
-        let res = ms.queue_app_packet(pkt, Reliability::Reliable, 0, RakPriority::High);
+        let res = ms.queue_app_packet(pkt, Reliability::Reliable, 0, RakPriority::High, now);
         assert!(matches!(res, Err(SessionError::InvalidState { .. })));
     }
 
@@ -402,10 +1420,587 @@ mod tests {
             payload: Bytes::from_static(b"test"),
         };
 
-        let res = ms.queue_app_packet(pkt, Reliability::Reliable, 99, RakPriority::High);
+        let res = ms.queue_app_packet(pkt, Reliability::Reliable, 99, RakPriority::High, now);
         assert!(matches!(res, Err(SessionError::InvalidState { .. })));
     }
 
+    #[test]
+    fn handle_datagram_with_releases_ordered_backlog_in_order() {
+        use crate::protocol::{
+            constants::DatagramFlags,
+            encapsulated_packet::EncapsulatedPacket,
+            types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24},
+        };
+
+        fn ordered(seq: u32, index: u32, id: u8) -> Datagram {
+            Datagram {
+                header: DatagramHeader {
+                    flags: DatagramFlags::VALID,
+                    sequence: Sequence24::new(seq),
+                },
+                payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+                    header: EncapsulatedPacketHeader {
+                        reliability: Reliability::ReliableOrdered,
+                        is_split: false,
+                        needs_bas: false,
+                    },
+                    bit_length: 8,
+                    reliable_index: Some(Sequence24::new(index)),
+                    sequence_index: None,
+                    ordering_index: Some(Sequence24::new(index)),
+                    ordering_channel: Some(0),
+                    split: None,
+                    payload: Bytes::from(vec![id]),
+                }]),
+            }
+        }
+
+        let peer: SocketAddr = "127.0.0.1:19138".parse().unwrap();
+        let now = Instant::now();
+        let mut ms = ManagedSession::new(peer, 1200, now);
+        ms.state = ConnectionState::Connected;
+
+        let mut ids = Vec::new();
+        let mut collect = |pkt: crate::session::IncomingPacket| {
+            if let RaknetPacket::UserData { id, .. } = pkt.packet {
+                ids.push(id);
+            }
+        };
+
+        ms.handle_datagram_with(ordered(0, 1, 0x81), now, &mut collect)
+            .unwrap();
+        ms.handle_datagram_with(ordered(1, 0, 0x80), now, &mut collect)
+            .unwrap();
+        assert_eq!(ids, vec![0x80, 0x81]);
+        assert_eq!(ms.stats().snapshot().messages_received, 2);
+    }
+
+    #[test]
+    fn reliable_data_beyond_the_window_waits_for_a_resend() {
+        use crate::protocol::{
+            constants::DatagramFlags,
+            encapsulated_packet::EncapsulatedPacket,
+            types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24},
+        };
+
+        fn reliable(seq: u32, index: u32) -> Datagram {
+            Datagram {
+                header: DatagramHeader {
+                    flags: DatagramFlags::VALID,
+                    sequence: Sequence24::new(seq),
+                },
+                payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+                    header: EncapsulatedPacketHeader {
+                        reliability: Reliability::Reliable,
+                        is_split: false,
+                        needs_bas: false,
+                    },
+                    bit_length: 8,
+                    reliable_index: Some(Sequence24::new(index)),
+                    sequence_index: None,
+                    ordering_index: None,
+                    ordering_channel: None,
+                    split: None,
+                    payload: Bytes::from(vec![0x80 + index as u8]),
+                }]),
+            }
+        }
+        fn acks(out: &[Datagram]) -> Vec<u32> {
+            out.iter()
+                .filter_map(|d| match &d.payload {
+                    DatagramPayload::Ack(p) => Some(p.ranges.iter()),
+                    _ => None,
+                })
+                .flatten()
+                .flat_map(|r| r.start.value()..=r.end.value())
+                .collect()
+        }
+
+        let peer: SocketAddr = "127.0.0.1:19139".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            session: SessionTunables {
+                reliable_window: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        ms.state = ConnectionState::Connected;
+
+        let mut ids = Vec::new();
+        let mut collect = |pkt: crate::session::IncomingPacket| {
+            if let RaknetPacket::UserData { id, .. } = pkt.packet {
+                ids.push(id);
+            }
+        };
+
+        // Index 5 is more than 4 past index 0, which hasn't arrived.
+        let res = ms.handle_datagram_with(reliable(0, 5), now, &mut collect);
+        assert!(matches!(
+            res,
+            Err(SessionError::Protocol(
+                DecodeError::ReliableIndexBeyondWindow(5)
+            ))
+        ));
+        assert_eq!(ms.state(), ConnectionState::Connected);
+        for (seq, index) in (1..).zip(0..5) {
+            ms.handle_datagram_with(reliable(seq, index), now, &mut collect)
+                .unwrap();
+        }
+        // The peer resends index 5 once its first datagram goes un-ACKed.
+        ms.handle_datagram_with(reliable(6, 5), now, &mut collect)
+            .unwrap();
+        ms.handle_datagram_with(reliable(7, 5), now, &mut collect)
+            .unwrap();
+
+        assert_eq!(ids, [0x80, 0x81, 0x82, 0x83, 0x84, 0x85]);
+        let batched = now + Duration::from_millis(10);
+        assert_eq!(acks(&ms.on_tick(batched)), [1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    /// Wrap each packet in its own unreliable frame, one datagram per packet.
+    fn datagrams_for(pkts: &[RaknetPacket]) -> Vec<Datagram> {
+        use crate::protocol::{
+            constants::DatagramFlags,
+            encapsulated_packet::EncapsulatedPacket,
+            types::{DatagramHeader, EncapsulatedPacketHeader, Sequence24},
+        };
+
+        pkts.iter()
+            .enumerate()
+            .map(|(seq, pkt)| {
+                let mut buf = bytes::BytesMut::new();
+                pkt.encode(&mut buf).unwrap();
+                Datagram {
+                    header: DatagramHeader {
+                        flags: DatagramFlags::VALID,
+                        sequence: Sequence24::new(seq as u32),
+                    },
+                    payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+                        header: EncapsulatedPacketHeader {
+                            reliability: Reliability::Unreliable,
+                            is_split: false,
+                            needs_bas: false,
+                        },
+                        bit_length: (buf.len() as u16) << 3,
+                        reliable_index: None,
+                        sequence_index: None,
+                        ordering_index: None,
+                        ordering_channel: None,
+                        split: None,
+                        payload: buf.freeze(),
+                    }]),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn server_handshake_packets_in_every_order() {
+        use crate::protocol::packet::NewIncomingConnection;
+
+        let peer: SocketAddr = "127.0.0.1:19139".parse().unwrap();
+        let make = |i: usize| match i {
+            0 => RaknetPacket::ConnectionRequest(ConnectionRequest {
+                client_guid: 0x77,
+                timestamp: RaknetTime(1),
+                secure: false,
+            }),
+            1 => RaknetPacket::NewIncomingConnection(NewIncomingConnection {
+                server_address: peer,
+                system_addresses: [peer; 10],
+                request_timestamp: RaknetTime(2),
+                accepted_timestamp: RaknetTime(1),
+            }),
+            _ => RaknetPacket::UserData {
+                id: 0x80,
+                payload: Bytes::from_static(b"hi"),
+            },
+        };
+
+        let orders: [[usize; 3]; 6] = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        for order in orders {
+            let pkts: Vec<RaknetPacket> = order.iter().map(|&i| make(i)).collect();
+            let now = Instant::now();
+            let config = SessionConfig {
+                role: SessionRole::Server,
+                ..Default::default()
+            };
+            let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+
+            let mut app = 0;
+            for dgram in datagrams_for(&pkts) {
+                ms.handle_datagram_with(dgram, now, |pkt| {
+                    if is_app_packet(&pkt.packet) {
+                        app += 1;
+                    }
+                })
+                .unwrap();
+            }
+
+            let pos = |i: usize| order.iter().position(|&x| x == i).unwrap();
+            let connects = pos(0) < pos(1);
+            let delivers = connects && pos(1) < pos(2);
+            assert_eq!(ms.is_connected(), connects, "order {order:?}");
+            assert_eq!(app, delivers as usize, "order {order:?}");
+
+            // The request is always taken; NIC before it and data before NIC are refused.
+            let refused = (!connects) as u64 + (!delivers) as u64;
+            assert_eq!(
+                ms.stats().snapshot().packets_out_of_state,
+                refused,
+                "order {order:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn client_refuses_accept_before_requesting() {
+        let peer: SocketAddr = "127.0.0.1:19140".parse().unwrap();
+        let now = Instant::now();
+        let accepted = RaknetPacket::ConnectionRequestAccepted(ConnectionRequestAccepted {
+            address: peer,
+            system_index: 0,
+            system_addresses: [peer; 10],
+            request_timestamp: RaknetTime(0),
+            accepted_timestamp: RaknetTime(0),
+        });
+
+        let mut ms = ManagedSession::new(peer, 1200, now);
+        assert!(!ms.handle_control_packet(&accepted, now));
+        assert_eq!(ms.state(), ConnectionState::Unconnected);
+
+        ms.start_client_handshake(0x02, now, false).unwrap();
+        assert!(ms.handle_control_packet(&accepted, now));
+        assert!(ms.is_connected());
+
+        // A replayed accept after connecting is refused rather than re-running the handshake.
+        assert!(!ms.handle_control_packet(&accepted, now));
+        assert_eq!(ms.stats().snapshot().packets_out_of_state, 2);
+    }
+
+    #[test]
+    fn data_after_new_incoming_connection_in_same_datagram_is_delivered() {
+        use crate::protocol::packet::NewIncomingConnection;
+
+        let peer: SocketAddr = "127.0.0.1:19141".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            role: SessionRole::Server,
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        let request = RaknetPacket::ConnectionRequest(ConnectionRequest {
+            client_guid: 0x77,
+            timestamp: RaknetTime(1),
+            secure: false,
+        });
+        assert!(ms.handle_control_packet(&request, now));
+
+        let mut dgrams = datagrams_for(&[
+            RaknetPacket::NewIncomingConnection(NewIncomingConnection {
+                server_address: peer,
+                system_addresses: [peer; 10],
+                request_timestamp: RaknetTime(2),
+                accepted_timestamp: RaknetTime(1),
+            }),
+            RaknetPacket::UserData {
+                id: 0x80,
+                payload: Bytes::from_static(b"hi"),
+            },
+        ]);
+        let second = dgrams.pop().unwrap();
+        let mut merged = dgrams.pop().unwrap();
+        if let (
+            DatagramPayload::EncapsulatedPackets(frames),
+            DatagramPayload::EncapsulatedPackets(more),
+        ) = (&mut merged.payload, second.payload)
+        {
+            frames.extend(more);
+        }
+
+        let mut app = 0;
+        ms.handle_datagram_with(merged, now, |pkt| {
+            if is_app_packet(&pkt.packet) {
+                app += 1;
+            }
+        })
+        .unwrap();
+        assert!(ms.is_connected());
+        assert_eq!(app, 1);
+    }
+
+    #[test]
+    fn reliable_data_overtaking_new_incoming_connection_is_held() {
+        use crate::protocol::packet::NewIncomingConnection;
+
+        let peer: SocketAddr = "127.0.0.1:19142".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            role: SessionRole::Server,
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        let request = RaknetPacket::ConnectionRequest(ConnectionRequest {
+            client_guid: 0x77,
+            timestamp: RaknetTime(1),
+            secure: false,
+        });
+        assert!(ms.handle_control_packet(&request, now));
+
+        let data = |id| RaknetPacket::UserData {
+            id,
+            payload: Bytes::from_static(b"hi"),
+        };
+        let mut dgrams = datagrams_for(&[
+            data(0x80),
+            data(0x81),
+            RaknetPacket::NewIncomingConnection(NewIncomingConnection {
+                server_address: peer,
+                system_addresses: [peer; 10],
+                request_timestamp: RaknetTime(2),
+                accepted_timestamp: RaknetTime(1),
+            }),
+        ]);
+        // The first message is reliable and has been ACKed by now; the
+        // second is not and can just be dropped.
+        if let DatagramPayload::EncapsulatedPackets(frames) = &mut dgrams[0].payload {
+            frames[0].header.reliability = Reliability::Reliable;
+            frames[0].reliable_index = Some(crate::protocol::types::Sequence24::new(0));
+        }
+
+        let mut seen = Vec::new();
+        for dgram in dgrams {
+            ms.handle_datagram_with(dgram, now, |pkt| seen.push(pkt.packet.id()))
+                .unwrap();
+        }
+        assert!(ms.is_connected());
+        assert_eq!(seen, [0x13, 0x80]);
+        assert_eq!(ms.stats().snapshot().packets_out_of_state, 1);
+    }
+
+    #[test]
+    fn handshake_abandoned_halfway_times_out() {
+        let peer: SocketAddr = "127.0.0.1:19143".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            role: SessionRole::Server,
+            ..Default::default()
+        };
+        let timeout = config.session_timeout;
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        let request = RaknetPacket::ConnectionRequest(ConnectionRequest {
+            client_guid: 0x77,
+            timestamp: RaknetTime(1),
+            secure: false,
+        });
+        assert!(ms.handle_control_packet(&request, now));
+
+        // The client never follows up with `NewIncomingConnection`.
+        ms.on_tick(now + timeout / 2);
+        assert_eq!(ms.state(), ConnectionState::OnlineHandshake);
+        assert!(ms.next_deadline(now) <= now + timeout);
+        ms.on_tick(now + timeout);
+        assert_eq!(ms.state(), ConnectionState::Closed);
+        assert!(matches!(
+            ms.last_disconnect_reason(),
+            Some(DisconnectReason::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn pings_are_answered_while_the_window_is_full() {
+        use crate::protocol::packet::ConnectedPing;
+
+        let peer: SocketAddr = "127.0.0.1:19140".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            role: SessionRole::Server,
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        ms.state = ConnectionState::Connected;
+
+        // A transfer with nothing acknowledged yet: the congestion window
+        // fills up and the rest of the data has to wait. Small messages
+        // leave less room in the window than a pong needs.
+        for _ in 0..256 {
+            let pkt = RaknetPacket::UserData {
+                id: 0x80,
+                payload: Bytes::new(),
+            };
+            ms.queue_app_packet(
+                pkt,
+                Reliability::ReliableOrdered,
+                0,
+                RakPriority::Normal,
+                now,
+            )
+            .unwrap();
+        }
+        while ms.build_datagram(now).is_some() {}
+        assert!(ms.inner.outgoing_queue_len() > 0);
+
+        // The pong skips both the backlog and the window, without a tick.
+        let ping = RaknetPacket::ConnectedPing(ConnectedPing {
+            ping_time: RaknetTime(7),
+        });
+        for dgram in datagrams_for(&[ping]) {
+            ms.handle_datagram_with(dgram, now, |_| {}).unwrap();
+        }
+        let dgram = ms.build_datagram(now).expect("pong goes out");
+        match decode_first_packet(&dgram) {
+            RaknetPacket::ConnectedPong(pong) => assert_eq!(pong.ping_time.0, 7),
+            other => panic!("expected pong, got {other:?}"),
+        }
+        assert!(ms.build_datagram(now).is_none());
+    }
+
+    #[test]
+    fn send_queue_limit_counts_every_split_part() {
+        let peer: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            send_queue_limit: Some(SendQueueLimit {
+                max_bytes: usize::MAX,
+                max_frames: 4,
+                policy: QueueLimitPolicy::Reject,
+            }),
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        ms.state = ConnectionState::Connected;
+        let queue = |ms: &mut ManagedSession, len: usize, opts: SendOptions| {
+            ms.queue_app_message_with(
+                Bytes::from(vec![0x80; len]),
+                Reliability::ReliableOrdered,
+                0,
+                RakPriority::Normal,
+                opts,
+                now,
+            )
+        };
+
+        // Three parts fit; three more would make six frames.
+        queue(&mut ms, 3000, SendOptions::default()).unwrap();
+        assert_eq!(ms.inner.outgoing_queue_len(), 3);
+        let (done, mut outcome) = tokio::sync::oneshot::channel();
+        let opts = SendOptions {
+            receipt: Some(done),
+            ..Default::default()
+        };
+        assert!(matches!(
+            queue(&mut ms, 3000, opts),
+            Err(SessionError::SendQueueFull)
+        ));
+        assert_eq!(outcome.try_recv(), Ok(ReceiptOutcome::Lost));
+        assert!(ms.stats().is_send_queue_full());
+
+        // A single frame still fits, and sending anything clears the flag.
+        queue(&mut ms, 100, SendOptions::default()).unwrap();
+        assert!(ms.build_datagram(now).is_some());
+        assert!(!ms.stats().is_send_queue_full());
+        assert_eq!(ms.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn send_queue_limit_applies_to_queued_packets() {
+        let peer: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let now = Instant::now();
+        let config = SessionConfig {
+            send_queue_limit: Some(SendQueueLimit {
+                max_bytes: usize::MAX,
+                max_frames: 2,
+                policy: QueueLimitPolicy::Reject,
+            }),
+            ..Default::default()
+        };
+        let mut ms = ManagedSession::with_config(peer, 1200, now, config);
+        ms.state = ConnectionState::Connected;
+        let queue = |ms: &mut ManagedSession| {
+            let pkt = RaknetPacket::UserData {
+                id: 0x80,
+                payload: Bytes::from_static(b"hi"),
+            };
+            ms.queue_app_packet(
+                pkt,
+                Reliability::ReliableOrdered,
+                0,
+                RakPriority::Normal,
+                now,
+            )
+        };
+
+        queue(&mut ms).unwrap();
+        queue(&mut ms).unwrap();
+        assert!(matches!(queue(&mut ms), Err(SessionError::SendQueueFull)));
+        assert_eq!(ms.inner.outgoing_queue_len(), 2);
+        assert!(ms.stats().is_send_queue_full());
+    }
+
+    #[test]
+    fn validate_rejects_unworkable_configs() {
+        assert_eq!(SessionConfig::default().validate(), Ok(()));
+
+        let config = SessionConfig {
+            session_stale: Duration::from_secs(10),
+            session_timeout: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::StaleNotBeforeTimeout { .. })
+        ));
+
+        let config = SessionConfig {
+            keepalive_interval: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::KeepaliveNotBeforeTimeout { .. })
+        ));
+
+        let config = SessionConfig {
+            session: SessionTunables {
+                max_ordering_channels: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::OutOfRange {
+                field: "max_ordering_channels",
+                ..
+            })
+        ));
+
+        let config = SessionConfig {
+            send_queue_limit: Some(SendQueueLimit {
+                max_bytes: 0,
+                max_frames: 16,
+                policy: QueueLimitPolicy::Reject,
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::OutOfRange {
+                field: "send_queue_limit.max_bytes",
+                ..
+            })
+        ));
+    }
+
     fn decode_first_packet(dgram: &crate::protocol::datagram::Datagram) -> RaknetPacket {
         if let DatagramPayload::EncapsulatedPackets(packets) = &dgram.payload {
             let encap = packets
@@ -12,7 +12,7 @@ use crate::protocol::reliability::Reliability;
 use crate::protocol::state::{DisconnectReason, RakPriority};
 use crate::protocol::types::RaknetTime;
 
-use super::{ConnectionState, ManagedSession, SessionError, SessionRole};
+use super::{ConnectionState, HandshakeTiming, ManagedSession, SessionError, SessionRole};
 
 impl ManagedSession {
     /// Begin the client-side online handshake by emitting a `ConnectionRequest`.
@@ -48,6 +48,7 @@ impl ManagedSession {
         });
 
         self.queue_control_packet(pkt, Reliability::Reliable, 0, RakPriority::Immediate);
+        self.handshake_sent = Some(now);
 
         Ok(())
     }
@@ -60,6 +61,8 @@ impl ManagedSession {
 
         let pkt = RaknetPacket::DisconnectionNotification(DisconnectionNotification { reason });
 
+        // Vanilla RakNet sends this at LOW priority behind queued data; we
+        // drop the session soon after, so it has to jump the queue instead.
         self.queue_control_packet(pkt, Reliability::Reliable, 0, RakPriority::Immediate);
         self.state = ConnectionState::Closing;
         self.last_disconnect_reason = Some(reason);
@@ -67,7 +70,77 @@ impl ManagedSession {
         Ok(())
     }
 
-    pub(super) fn handle_control_packet(&mut self, pkt: &RaknetPacket, now: Instant) {
+    /// Queue a `DisconnectionNotification` with `reason` behind everything
+    /// already queued, as vanilla RakNet does, and stop taking new data.
+    ///
+    /// The session stays `Closing` until the notification and the data
+    /// before it are ACKed or `deadline` passes, and is then `Closed` on the
+    /// next tick.
+    pub fn disconnect_gracefully(
+        &mut self,
+        reason: DisconnectReason,
+        deadline: Instant,
+    ) -> Result<(), SessionError> {
+        if matches!(
+            self.state,
+            ConnectionState::Closing | ConnectionState::Closed
+        ) {
+            return Err(SessionError::Closed);
+        }
+
+        let pkt = RaknetPacket::DisconnectionNotification(DisconnectionNotification { reason });
+        self.queue_control_packet(pkt, Reliability::ReliableOrdered, 0, RakPriority::Low);
+        self.state = ConnectionState::Closing;
+        self.last_disconnect_reason = Some(reason);
+        self.close_deadline = Some(deadline);
+
+        Ok(())
+    }
+
+    /// Whether `pkt` may be processed in the current state and role.
+    ///
+    /// Handshake packets only move the session forward one step at a time;
+    /// anything arriving out of turn is refused instead of leaving the
+    /// session half-open until it times out.
+    pub(super) fn accepts(&self, pkt: &RaknetPacket) -> bool {
+        use ConnectionState::*;
+
+        let role = self.config.role;
+        match pkt {
+            RaknetPacket::ConnectionRequest(_) => {
+                role == SessionRole::Server && matches!(self.state, Unconnected | OnlineHandshake)
+            }
+            RaknetPacket::ConnectionRequestAccepted(_)
+            | RaknetPacket::ConnectionRequestFailed(_) => {
+                role == SessionRole::Client && self.state == OnlineHandshake
+            }
+            RaknetPacket::NewIncomingConnection(_) => {
+                role == SessionRole::Server && self.state == OnlineHandshake
+            }
+            RaknetPacket::ConnectedPing(_) | RaknetPacket::ConnectedPong(_) => {
+                matches!(self.state, OnlineHandshake | Connected | Stale)
+            }
+            RaknetPacket::DisconnectionNotification(_) => self.state != Closed,
+            RaknetPacket::UserData { .. } => matches!(self.state, Connected | Stale),
+            _ => true,
+        }
+    }
+
+    /// Apply a control packet to the session, returning `false` if it was
+    /// refused as out of state.
+    pub(super) fn handle_control_packet(&mut self, pkt: &RaknetPacket, now: Instant) -> bool {
+        if !self.accepts(pkt) {
+            tracing::debug!(
+                state = ?self.state,
+                role = ?self.config.role,
+                peer = %self.peer,
+                id = pkt.id(),
+                "dropping out-of-state packet"
+            );
+            self.stats.record_out_of_state_packet();
+            return false;
+        }
+
         match pkt {
             RaknetPacket::ConnectionRequest(req) => self.handle_connection_request(req, now),
             RaknetPacket::ConnectionRequestAccepted(acc) => {
@@ -84,13 +157,10 @@ impl ManagedSession {
             }
             _ => self.handle_generic_control_states(pkt),
         }
+        true
     }
 
     fn handle_connection_request(&mut self, req: &ConnectionRequest, now: Instant) {
-        if self.config.role != SessionRole::Server {
-            return;
-        }
-
         self.remote_guid = Some(req.client_guid);
 
         self.state = ConnectionState::OnlineHandshake;
@@ -115,7 +185,13 @@ impl ManagedSession {
             accepted_timestamp: accepted_ts,
         });
 
-        self.queue_control_packet(packet, Reliability::Unreliable, 0, RakPriority::Immediate);
+        self.queue_control_packet(
+            packet,
+            Reliability::ReliableOrdered,
+            0,
+            RakPriority::Immediate,
+        );
+        self.handshake_sent = Some(now);
         self.trace_control("send_conn_request_accepted");
     }
 
@@ -125,21 +201,18 @@ impl ManagedSession {
         pkt: &ConnectionRequestAccepted,
         now: Instant,
     ) {
-        if self.config.role != SessionRole::Client {
-            return;
-        }
-
-        self.state = ConnectionState::OnlineHandshake;
-
         self.last_activity = now;
         self.last_pong_received = now;
+        self.record_handshake_rtt(pkt.request_timestamp, pkt.accepted_timestamp, now);
 
+        // Like vanilla RakNet, answer with the server's timestamp so it can
+        // measure the round trip too.
         let packet = RaknetPacket::NewIncomingConnection(NewIncomingConnection {
             server_address: self.peer,
             system_addresses: Self::default_system_addresses(self.peer),
-            request_timestamp: Self::current_raknet_time(now),
+            request_timestamp: pkt.accepted_timestamp,
 
-            accepted_timestamp: pkt.request_timestamp,
+            accepted_timestamp: Self::current_raknet_time(now),
         });
 
         self.queue_control_packet(
@@ -157,10 +230,33 @@ impl ManagedSession {
         self.last_disconnect_reason = Some(DisconnectReason::ConnectionRequestFailed);
     }
 
-    fn handle_new_incoming_connection(&mut self, _pkt: &NewIncomingConnection, now: Instant) {
+    fn handle_new_incoming_connection(&mut self, pkt: &NewIncomingConnection, now: Instant) {
+        self.server_addr = Some(pkt.server_address);
         self.state = ConnectionState::Connected;
         self.last_activity = now;
         self.last_pong_received = now;
+        self.record_handshake_rtt(pkt.request_timestamp, pkt.accepted_timestamp, now);
+    }
+
+    /// Note the handshake's round trip, ending now, and start the RTT
+    /// estimate from it. Measured on our clock: the peer's timestamps are
+    /// only kept, as its clock may run differently.
+    fn record_handshake_rtt(
+        &mut self,
+        request_timestamp: RaknetTime,
+        accepted_timestamp: RaknetTime,
+        now: Instant,
+    ) {
+        let Some(sent) = self.handshake_sent.take() else {
+            return;
+        };
+        let rtt = now.saturating_duration_since(sent);
+        self.inner.seed_rtt(rtt);
+        self.handshake_timing = Some(HandshakeTiming {
+            rtt,
+            request_timestamp,
+            accepted_timestamp,
+        });
     }
 
     fn handle_connected_ping(&mut self, pkt: &ConnectedPing, now: Instant) {
@@ -183,6 +279,9 @@ impl ManagedSession {
             .unwrap_or(false)
         {
             self.current_ping_nonce = None;
+            if let Some(sent) = self.last_ping_sent {
+                self.inner.sample_rtt(now.saturating_duration_since(sent));
+            }
         }
     }
 
@@ -207,6 +306,11 @@ impl ManagedSession {
         }
     }
 
+    /// Queue a packet generated by the session itself.
+    ///
+    /// Handshake packets, pings and pongs all go at `Immediate` priority as
+    /// in vanilla RakNet, so they overtake application data queued at any
+    /// level; unreliable ones also skip the congestion window.
     pub(super) fn queue_control_packet(
         &mut self,
 
@@ -219,7 +323,7 @@ impl ManagedSession {
         if channel as usize >= self.config.session.max_ordering_channels {
             return;
         }
-        let added = self.inner.queue_packet(pkt, rel, channel, priority);
+        let added = self.inner.queue_control(pkt, rel, channel, priority);
         self.queued_reliable_bytes = self.queued_reliable_bytes.saturating_add(added);
     }
 
@@ -1,7 +1,7 @@
 use std::time::Instant;
 
 use crate::protocol::{
-    datagram::Datagram,
+    datagram::{Datagram, DatagramPayload},
     encapsulated_packet::EncapsulatedPacket,
     packet::{ConnectedPing, RaknetPacket},
     reliability::Reliability,
@@ -13,15 +13,17 @@ use super::{ConnectionState, ManagedSession};
 impl ManagedSession {
     /// Run periodic maintenance and return any datagrams that should be sent.
     pub fn on_tick(&mut self, now: Instant) -> Vec<Datagram> {
-        if self.is_connected() {
-            let idle = now.saturating_duration_since(self.last_activity);
-            if idle >= self.config.session_timeout {
+        // A peer can go quiet in any state, including halfway through the
+        // handshake, and the session has to end then too.
+        let idle = now.saturating_duration_since(self.last_activity);
+        if self.state != ConnectionState::Closed && idle >= self.config.session_timeout {
+            if self.is_connected() {
                 let _ = self.send_disconnect(DisconnectReason::TimedOut);
-                self.state = ConnectionState::Closed;
-                self.last_disconnect_reason = Some(DisconnectReason::TimedOut);
-            } else if idle >= self.config.session_stale {
-                self.state = ConnectionState::Stale;
             }
+            self.state = ConnectionState::Closed;
+            self.last_disconnect_reason = Some(DisconnectReason::TimedOut);
+        } else if self.state == ConnectionState::Connected && idle >= self.config.session_stale {
+            self.state = ConnectionState::Stale;
         }
 
         if self.should_send_ping(now) {
@@ -29,21 +31,87 @@ impl ManagedSession {
         }
 
         self.enforce_queue_limit();
+        self.enforce_backlog_limit(now);
 
-        self.inner.on_tick(now)
+        let out = self.inner.on_tick(now);
+        if self.inner.resends_exhausted() && self.state != ConnectionState::Closed {
+            tracing::debug!("peer stopped acknowledging, timing out");
+            self.close_now(DisconnectReason::TimedOut);
+        }
+        if !out.is_empty() {
+            self.last_sent = now;
+        }
+        if self.state == ConnectionState::Closing
+            && self
+                .close_deadline
+                .is_some_and(|at| now >= at || self.is_drained())
+        {
+            self.state = ConnectionState::Closed;
+        }
+        for d in &out {
+            self.log_datagram(now, d, false);
+            // Data datagrams emitted by the tick are always resends; fresh
+            // data goes out through `build_datagram`.
+            if matches!(d.payload, DatagramPayload::EncapsulatedPackets(_)) {
+                self.stats.record_datagram_resent();
+            }
+            self.stats.record_datagram_sent(d.size());
+        }
+        self.note_state(now);
+        self.sync_stats();
+        out
     }
 
+    /// Earliest instant at which `on_tick` needs to run for this session.
+    ///
+    /// Inbound datagrams and outbound enqueues can bring this forward, so
+    /// callers must re-query after any activity.
+    pub fn next_deadline(&self, now: Instant) -> Instant {
+        if self.state == ConnectionState::Closed {
+            return now;
+        }
+
+        let mut deadline = self.last_activity + self.config.session_timeout;
+        if self.state == ConnectionState::Connected {
+            deadline = deadline.min(self.last_activity + self.config.session_stale);
+        }
+        if self.is_connected()
+            && let Some(keepalive) = self.config.keepalive()
+        {
+            deadline = deadline.min(self.last_sent + keepalive);
+        }
+        if let Some(inner) = self.inner.next_deadline(now) {
+            deadline = deadline.min(inner);
+        }
+        if let (Some(since), Some(limit)) = (self.backlog_since, self.config.backlog_limit) {
+            deadline = deadline.min(since + limit.grace);
+        }
+        if self.state == ConnectionState::Closing
+            && let Some(at) = self.close_deadline
+        {
+            // Once everything is ACKed there is nothing left to wait for.
+            deadline = deadline.min(if self.is_drained() { now } else { at });
+        }
+        deadline.max(now)
+    }
+
+    /// When the ACKs waiting under the `AckPolicy` are due, if any wait.
+    /// `build_datagram` sends them from then on.
+    pub fn ack_deadline(&self, now: Instant) -> Option<Instant> {
+        self.inner.ack_deadline(now)
+    }
+
+    /// Whether the session has been quiet long enough to need a keepalive.
+    ///
+    /// An unanswered ping doesn't hold the next one back: it may just have
+    /// been lost, and the next one is what keeps the peer from timing out.
     pub(crate) fn should_send_ping(&self, now: Instant) -> bool {
         if !self.is_connected() {
             return false;
         }
-        if self.current_ping_nonce.is_some() {
-            return false;
-        }
-        match self.last_ping_sent {
-            Some(last) => now.saturating_duration_since(last) >= self.config.ping_interval,
-            None => true,
-        }
+        self.config
+            .keepalive()
+            .is_some_and(|keepalive| now.saturating_duration_since(self.last_sent) >= keepalive)
     }
 
     pub(crate) fn send_connected_ping(&mut self, now: Instant) {
@@ -55,6 +123,7 @@ impl ManagedSession {
 
         self.queue_control_packet(pkt, Reliability::Unreliable, 0, RakPriority::Immediate);
         self.last_ping_sent = Some(now);
+        self.last_sent = now;
         self.current_ping_nonce = Some(timestamp.0);
     }
 
@@ -62,11 +131,49 @@ impl ManagedSession {
         if let Some(limit) = self.config.max_queued_reliable_bytes
             && self.queued_reliable_bytes > limit
         {
-            let _ = self.send_disconnect(DisconnectReason::QueueTooLong);
+            self.close_queue_too_long();
+        }
+    }
 
-            self.state = ConnectionState::Closed;
-            self.last_disconnect_reason = Some(DisconnectReason::QueueTooLong);
+    /// Close with `QueueTooLong` once the backlog has stayed above
+    /// `backlog_limit` for its grace period.
+    fn enforce_backlog_limit(&mut self, now: Instant) {
+        let Some(limit) = self.config.backlog_limit else {
+            return;
+        };
+        // A handshake, a graceful disconnect draining, or a peer gone quiet
+        // are not a peer falling behind.
+        if self.state != ConnectionState::Connected || self.backlog() <= limit.max_backlog {
+            self.backlog_since = None;
+            return;
         }
+        let since = *self.backlog_since.get_or_insert(now);
+        if now.saturating_duration_since(since) >= limit.grace {
+            tracing::debug!(
+                backlog = self.backlog(),
+                "peer can't keep up, disconnecting"
+            );
+            self.close_queue_too_long();
+        }
+    }
+
+    /// Frames waiting for their first send plus datagrams waiting for an ACK.
+    fn backlog(&self) -> usize {
+        self.inner.outgoing_queue_len() + self.inner.unacked_datagrams()
+    }
+
+    /// Tell the peer `QueueTooLong` and close at once.
+    pub(crate) fn close_queue_too_long(&mut self) {
+        self.close_now(DisconnectReason::QueueTooLong);
+    }
+
+    /// Queue a disconnect for the peer and close the session on the spot.
+    pub(crate) fn close_now(&mut self, reason: DisconnectReason) {
+        let _ = self.send_disconnect(reason);
+
+        self.state = ConnectionState::Closed;
+        self.last_disconnect_reason = Some(reason);
+        self.sync_stats();
     }
 
     pub(crate) fn debit_reliable_bytes(&mut self, packets: &[EncapsulatedPacket]) {
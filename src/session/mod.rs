@@ -10,42 +10,72 @@
 //! - Congestion Control (sliding window)
 
 pub mod ack_queue;
+mod datagram_window;
+#[cfg(any(test, feature = "debug-log"))]
+pub mod debug_log;
 mod inbound;
 pub mod manager;
 mod ordering_channels;
 mod outbound;
+mod receipts;
 mod reliable_tracker;
 mod sliding_window;
 pub mod split_assembler;
+pub mod stats;
 mod tick;
 
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, BinaryHeap, VecDeque},
+    collections::{BTreeMap, BinaryHeap, HashMap, VecDeque},
     time::{Duration, Instant},
 };
 
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
 use crate::protocol::{
     constants::{self, MAX_ACK_SEQUENCES},
     datagram::Datagram,
     encapsulated_packet::EncapsulatedPacket,
-    packet::{DecodeError, RaknetPacket},
+    packet::RaknetPacket,
     reliability::Reliability,
     types::Sequence24,
 };
 
 use crate::protocol::ack::SequenceRange;
 use ack_queue::AckQueue;
+use datagram_window::DatagramWindow;
+use manager::SessionError;
 use ordering_channels::OrderingChannels;
+pub use receipts::ReceiptOutcome;
+use receipts::Receipts;
 use reliable_tracker::ReliableTracker;
 use sliding_window::SlidingWindow;
 use split_assembler::SplitAssembler;
+use stats::MemoryUsage;
 
 /// Packet decoded out of a session along with delivery metadata.
 pub struct IncomingPacket {
     pub packet: RaknetPacket,
     pub reliability: Reliability,
     pub ordering_channel: Option<u8>,
+    /// The frame `packet` was decoded from (ID byte + body). User data
+    /// payloads are slices of it.
+    pub raw: Bytes,
+    /// When the datagram carrying it was received. Ordered packets held back
+    /// for a gap keep their own arrival time, not the time they were released.
+    pub recv_time: Instant,
+    /// Sequence number of that datagram; for a split packet, of the one that
+    /// completed it. `None` for frames handed in without a datagram, through
+    /// `Session::handle_data_payload`.
+    pub datagram_sequence: Option<u32>,
+}
+
+/// When, and in which datagram, a frame reached the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Arrival {
+    pub at: Instant,
+    pub datagram_sequence: Option<u32>,
 }
 
 /// Tunable low-level session parameters to mirror Cloudburst configurability.
@@ -57,6 +87,81 @@ pub struct SessionTunables {
     pub reliable_window: u32,
     pub max_split_parts: u32,
     pub max_concurrent_splits: usize,
+    /// Payload bytes buffered across every split still being reassembled.
+    pub max_split_bytes: usize,
+    pub reorder_limit: ReorderLimit,
+    /// Lower bound on the retransmission timeout computed from the RTT;
+    /// until a round trip is measured it is one second, within the bounds.
+    pub min_rto: Duration,
+    /// Upper bound on the retransmission timeout, and on the intervals it
+    /// doubles into while a datagram goes unACKed.
+    pub max_rto: Duration,
+    /// Times one datagram is resent before the peer is considered gone.
+    pub max_resends: u32,
+    /// Resend the frames of a NAKed datagram at once, under a new sequence
+    /// number, instead of on the next tick.
+    pub fast_retransmit: bool,
+    /// When the ACKs for received datagrams are sent.
+    pub ack_policy: AckPolicy,
+}
+
+/// When a session sends the ACKs for the datagrams it receives.
+///
+/// An ACK that fills a gap the session reported with a NAK always goes out
+/// at once: the sender is resending until it arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckPolicy {
+    /// In the same muxer turn as the datagram, ahead of any data queued.
+    /// The sender's RTT estimate and resends see no extra delay, at the
+    /// cost of an ACK datagram per received datagram.
+    Immediate,
+    /// Batched for up to this long, so a burst is acknowledged with a few
+    /// merged ranges; the delay counts towards the sender's RTT.
+    Delayed(Duration),
+}
+
+impl Default for AckPolicy {
+    /// RakNet's own update interval.
+    fn default() -> Self {
+        Self::Delayed(Duration::from_millis(10))
+    }
+}
+
+/// Cap on what one ordering channel holds back while waiting for a missing
+/// packet; a peer that never fills the gap could otherwise grow it forever.
+///
+/// Each channel is capped on its own, so a session holds up to
+/// `max_ordering_channels` times this in all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderLimit {
+    /// Buffered payload bytes, per channel.
+    pub max_bytes: usize,
+    /// Buffered packets, per channel.
+    pub max_frames: usize,
+    /// What happens to the packet that would take a channel past either cap.
+    pub policy: ReorderLimitPolicy,
+}
+
+impl Default for ReorderLimit {
+    fn default() -> Self {
+        Self {
+            // Room for the largest message `max_split_parts` lets through.
+            max_bytes: 16 * 1024 * 1024,
+            max_frames: 2048,
+            policy: ReorderLimitPolicy::Disconnect,
+        }
+    }
+}
+
+/// How a session treats an ordering channel that outgrows its `ReorderLimit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReorderLimitPolicy {
+    /// Close the session with `DisconnectReason::BadPacket`.
+    #[default]
+    Disconnect,
+    /// Stop waiting for the gap: discard the channel's oldest buffered
+    /// packets, and everything missing before them, until it fits again.
+    DropOldest,
 }
 
 impl Default for SessionTunables {
@@ -64,11 +169,18 @@ impl Default for SessionTunables {
         Self {
             max_ordering_channels: constants::MAXIMUM_ORDERING_CHANNELS as usize,
             ack_queue_capacity: 1024,
-            // Reduced split timeout to clear dead buffers faster
-            split_timeout: Duration::from_secs(30),
+            // Parts of a live split arrive well within this, resends included.
+            split_timeout: Duration::from_secs(5),
             reliable_window: constants::MAX_ACK_SEQUENCES as u32,
             max_split_parts: 8192,
             max_concurrent_splits: 4096,
+            max_split_bytes: constants::DEFAULT_MAX_SPLIT_BYTES,
+            reorder_limit: ReorderLimit::default(),
+            min_rto: constants::MIN_RTO,
+            max_rto: constants::MAX_RTO,
+            max_resends: 10,
+            fast_retransmit: true,
+            ack_policy: AckPolicy::default(),
         }
     }
 }
@@ -77,15 +189,53 @@ struct TrackedDatagram {
     datagram: Datagram,
     send_time: Instant,
     next_send: Instant,
+    /// Times it has been resent. An ACK for a datagram sent more than once
+    /// can't tell which copy it is for and says nothing about the round trip.
+    resends: u32,
+    /// Receipt ids of the frames it carries, one entry per frame.
+    receipts: Vec<u64>,
+    /// Sequence numbers it went out under before a fast retransmit moved it
+    /// to this one; each is a key of `Session::resent_as`.
+    earlier: Vec<Sequence24>,
+    /// Lowest queue number of the frames it carries.
+    first_frame: u64,
 }
 
 struct QueuedEncap {
     weight: u64,
+    /// Order it was queued in, counted across the session.
+    number: u64,
     pkt: EncapsulatedPacket,
+    /// Unreliable `Immediate` control frames (pings, pongs) go out even when the
+    /// congestion window is full, as in vanilla RakNet; they are never
+    /// resent, so they cannot add to what is in flight.
+    bypasses_window: bool,
+    tags: FrameTags,
+}
+
+/// What a queued frame carries over from the message it belongs to.
+#[derive(Debug, Clone, Copy, Default)]
+struct FrameTags {
+    /// Receipt waiting on this frame's ACK, if sent with one.
+    receipt: Option<u64>,
+    /// Dropped instead of packed into a datagram from then on. Only ever set
+    /// on frames without a reliable index.
+    expires: Option<Instant>,
+    /// Generated by the session itself rather than the application.
+    control: bool,
+}
+
+/// Extras for a packet queued with `Session::queue_encoded_with`.
+#[derive(Debug, Default)]
+pub struct SendOptions {
+    /// Told whether the packet was ACKed or lost.
+    pub receipt: Option<oneshot::Sender<ReceiptOutcome>>,
+    /// Drop the packet instead of sending it if it is still queued by then.
+    pub expires: Option<Instant>,
 }
 impl PartialEq for QueuedEncap {
     fn eq(&self, other: &Self) -> bool {
-        self.weight == other.weight
+        self.cmp(other) == Ordering::Equal
     }
 }
 impl Eq for QueuedEncap {}
@@ -94,9 +244,13 @@ impl PartialOrd for QueuedEncap {
         Some(self.cmp(other))
     }
 }
+/// Frames that skip the congestion window come first, so a full window
+/// never holds them behind application data of the same priority.
 impl Ord for QueuedEncap {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.weight.cmp(&self.weight)
+        self.bypasses_window
+            .cmp(&other.bypasses_window)
+            .then_with(|| other.weight.cmp(&self.weight))
     }
 }
 
@@ -112,14 +266,39 @@ pub struct Session {
     split_assembler: SplitAssembler,
     ordering: OrderingChannels,
     reliable_tracker: ReliableTracker,
+    receipts: Receipts,
     outgoing_heap: BinaryHeap<QueuedEncap>,
+    outgoing_queue_bytes: usize,
+    /// Frames ever queued; the next one gets this as its number.
+    frames_queued: u64,
     outgoing_packet_next_weights: [u64; 4],
     last_min_weight: u64,
     sent_datagrams: BTreeMap<Sequence24, TrackedDatagram>,
+    resend_bytes: usize,
     incoming_acks: VecDeque<SequenceRange>,
     incoming_naks: VecDeque<SequenceRange>,
     outgoing_acks: AckQueue,
+    ack_policy: AckPolicy,
+    /// When the queued ACKs must be sent by; `None` with ACKs queued means
+    /// right away.
+    ack_due: Option<Instant>,
     outgoing_naks: AckQueue,
+    datagram_window: DatagramWindow,
+    duplicate_datagrams: u64,
+    duplicate_frames: u64,
+    expired_messages: u64,
+    expired_splits: u64,
+    /// Ordered packets `on_tick` unblocked by giving up on a split, waiting
+    /// for `deliver_released_with`.
+    released: Vec<(EncapsulatedPacket, Arrival)>,
+    max_resends: u32,
+    resends_exhausted: bool,
+    fast_retransmit: bool,
+    /// Datagrams repackaged from NAKs, sent before anything else.
+    retransmits: VecDeque<Datagram>,
+    /// Old sequence number of a fast-retransmitted datagram to the one it is
+    /// tracked under now, so a late ACK of the first copy still counts.
+    resent_as: HashMap<Sequence24, Sequence24>,
 }
 
 impl Session {
@@ -138,7 +317,7 @@ impl Session {
     pub fn with_tunables(mtu: usize, tunables: SessionTunables) -> Self {
         let mut s = Self {
             mtu,
-            sliding: SlidingWindow::new(mtu),
+            sliding: SlidingWindow::new(mtu, tunables.min_rto, tunables.max_rto),
             split_index: 0,
             datagram_read_index: Sequence24::new(0),
             datagram_write_index: Sequence24::new(0),
@@ -148,17 +327,35 @@ impl Session {
                 tunables.split_timeout,
                 tunables.max_split_parts,
                 tunables.max_concurrent_splits,
+                tunables.max_split_bytes,
             ),
-            ordering: OrderingChannels::new(tunables.max_ordering_channels),
+            ordering: OrderingChannels::new(tunables.max_ordering_channels, tunables.reorder_limit),
             reliable_tracker: ReliableTracker::new(tunables.reliable_window as usize),
+            receipts: Receipts::default(),
             outgoing_heap: BinaryHeap::new(),
+            outgoing_queue_bytes: 0,
+            frames_queued: 0,
             outgoing_packet_next_weights: [0; 4],
             last_min_weight: 0,
             sent_datagrams: BTreeMap::new(),
+            resend_bytes: 0,
             incoming_acks: VecDeque::new(),
             incoming_naks: VecDeque::new(),
             outgoing_acks: AckQueue::new(tunables.ack_queue_capacity),
+            ack_policy: tunables.ack_policy,
+            ack_due: None,
             outgoing_naks: AckQueue::new(tunables.ack_queue_capacity),
+            datagram_window: DatagramWindow::new(MAX_ACK_SEQUENCES as usize),
+            duplicate_datagrams: 0,
+            duplicate_frames: 0,
+            expired_messages: 0,
+            expired_splits: 0,
+            released: Vec::new(),
+            max_resends: tunables.max_resends,
+            resends_exhausted: false,
+            fast_retransmit: tunables.fast_retransmit,
+            retransmits: VecDeque::new(),
+            resent_as: HashMap::new(),
         };
 
         for level in 0..4 {
@@ -172,6 +369,126 @@ impl Session {
         self.mtu
     }
 
+    /// Smoothed round-trip time as tracked by the congestion window.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.sliding.estimated_rtt()
+    }
+
+    /// How long a reliable datagram goes unACKed before it is resent.
+    pub fn rto(&self) -> Duration {
+        self.sliding.get_rto_for_retransmission()
+    }
+
+    /// Whether a datagram went unACKed through `max_resends` resends, a sign
+    /// the peer is gone even if it still sends something now and then.
+    pub fn resends_exhausted(&self) -> bool {
+        self.resends_exhausted
+    }
+
+    /// Start the RTT estimate from `rtt` if no ACK has set it yet.
+    pub(crate) fn seed_rtt(&mut self, rtt: Duration) {
+        self.sliding.seed_rtt(rtt);
+    }
+
+    /// Add a round trip measured outside the ACK path to the estimate.
+    pub(crate) fn sample_rtt(&mut self, rtt: Duration) {
+        self.sliding.sample_rtt(rtt);
+    }
+
+    /// Number of frames queued but not yet packed into a datagram.
+    pub fn outgoing_queue_len(&self) -> usize {
+        self.outgoing_heap.len()
+    }
+
+    /// Bytes of the frames counted by `outgoing_queue_len`.
+    pub fn outgoing_queue_bytes(&self) -> usize {
+        self.outgoing_queue_bytes
+    }
+
+    /// Number the next queued frame will get.
+    pub fn frames_queued(&self) -> u64 {
+        self.frames_queued
+    }
+
+    /// Number of the earliest-queued frame not yet sent or, if reliable, not
+    /// yet ACKed.
+    pub fn oldest_pending_frame(&self) -> Option<u64> {
+        let queued = self.outgoing_heap.iter().map(|q| q.number);
+        let in_flight = self.sent_datagrams.values().map(|t| t.first_frame);
+        queued.chain(in_flight).min()
+    }
+
+    /// Number of reliable datagrams awaiting an ACK.
+    pub fn unacked_datagrams(&self) -> usize {
+        self.sent_datagrams.len()
+    }
+
+    /// Ordered packets held back waiting for an earlier index.
+    pub fn reorder_frames(&self) -> usize {
+        self.ordering.buffered_frames()
+    }
+
+    /// Number of delivery receipts still waiting on ACKs.
+    pub fn pending_receipts(&self) -> usize {
+        self.receipts.len()
+    }
+
+    /// Data datagrams received again under a sequence number already seen.
+    pub fn duplicate_datagrams(&self) -> u64 {
+        self.duplicate_datagrams
+    }
+
+    /// Reliable frames received again inside datagrams that were themselves
+    /// new, i.e. the peer retransmitting under fresh sequence numbers, plus
+    /// split parts received again under a new reliable index.
+    pub fn duplicate_frames(&self) -> u64 {
+        self.duplicate_frames + self.split_assembler.duplicate_parts()
+    }
+
+    /// Packets dropped unsent because their `SendOptions::expires` passed.
+    pub fn expired_messages(&self) -> u64 {
+        self.expired_messages
+    }
+
+    /// Split packets given up on after `split_timeout` without a new part.
+    pub fn expired_splits(&self) -> u64 {
+        self.expired_splits
+    }
+
+    /// Bytes currently buffered by this session, per area.
+    ///
+    /// `incoming_channel_bytes` lives outside the session and is left at 0.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            resend_bytes: self.resend_bytes,
+            reorder_bytes: self.ordering.buffered_bytes(),
+            split_reassembly_bytes: self.split_assembler.buffered_bytes(),
+            outgoing_queue_bytes: self.outgoing_queue_bytes,
+            incoming_channel_bytes: 0,
+        }
+    }
+
+    /// Queue the ACK for data datagram `seq`, received at `now`, to go out
+    /// as the `AckPolicy` says.
+    pub(crate) fn acknowledge(&mut self, seq: Sequence24, now: Instant) {
+        // From behind the read index: it fills a gap we NAKed.
+        let fills_gap = self.datagram_read_index > seq;
+        self.process_datagram_sequence(seq);
+        let at = match self.ack_policy {
+            AckPolicy::Delayed(delay) if !fills_gap => now + delay,
+            _ => now,
+        };
+        self.ack_due = Some(self.ack_due.map_or(at, |due| due.min(at)));
+    }
+
+    /// When the queued ACKs are due, if any are queued.
+    pub(crate) fn ack_deadline(&self, now: Instant) -> Option<Instant> {
+        if self.outgoing_acks.is_empty() {
+            return None;
+        }
+        Some(self.ack_due.unwrap_or(now))
+    }
+
     /// Process datagram sequence for ACK/NACK generation (Cloudburst-style).
     #[tracing::instrument(skip_all, level = "trace")]
     pub fn process_datagram_sequence(&mut self, seq: Sequence24) {
@@ -242,12 +559,18 @@ impl Session {
     fn handle_ordered(
         &mut self,
         enc: EncapsulatedPacket,
-        out: &mut Vec<IncomingPacket>,
-    ) -> Result<(), DecodeError> {
-        if let Some(ready) = self.ordering.handle_ordered(enc) {
-            for pkt in ready {
-                self.decode_and_push(pkt, out)?;
-            }
+        arrival: Arrival,
+        out: &mut impl FnMut(IncomingPacket),
+    ) -> Result<(), SessionError> {
+        let Some(channel) = enc.ordering_channel else {
+            return Ok(());
+        };
+        if let Some(pkt) = self.ordering.handle_ordered(enc, arrival)? {
+            self.decode_and_push(pkt, arrival, out)?;
+        }
+        // Dropping the oldest packets on a full channel can unblock it too.
+        while let Some((pkt, arrival)) = self.ordering.pop_ready(channel) {
+            self.decode_and_push(pkt, arrival, out)?;
         }
         Ok(())
     }
@@ -256,6 +579,7 @@ impl Session {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::types::EncapsulatedPacketHeader;
     use std::time::Instant;
 
     #[test]
@@ -285,4 +609,701 @@ mod tests {
             panic!("expected ack datagram");
         }
     }
+
+    #[test]
+    fn memory_usage_follows_queue_and_resend_buffers() {
+        use crate::protocol::ack::AckNackPayload;
+        use crate::protocol::state::RakPriority;
+        use bytes::Bytes;
+
+        let mut session = Session::new(1200);
+        let now = Instant::now();
+
+        session.queue_packet(
+            RaknetPacket::UserData {
+                id: 0x80,
+                payload: Bytes::from_static(b"payload"),
+            },
+            Reliability::Reliable,
+            0,
+            RakPriority::Normal,
+        );
+        assert!(session.memory_usage().outgoing_queue_bytes > 0);
+
+        let dgram = session.build_data_datagram(now).expect("datagram");
+        let usage = session.memory_usage();
+        assert_eq!(usage.outgoing_queue_bytes, 0);
+        assert_eq!(usage.resend_bytes, dgram.size());
+
+        session.handle_ack_payload(AckNackPayload {
+            ranges: vec![SequenceRange {
+                start: dgram.header.sequence,
+                end: dgram.header.sequence,
+            }],
+        });
+        session.on_tick(now);
+        assert_eq!(session.memory_usage().total(), 0);
+    }
+
+    fn send_reliable(session: &mut Session, now: Instant) -> Sequence24 {
+        use crate::protocol::state::RakPriority;
+        use bytes::Bytes;
+
+        session.queue_packet(
+            RaknetPacket::UserData {
+                id: 0x80,
+                payload: Bytes::from_static(b"payload"),
+            },
+            Reliability::Reliable,
+            0,
+            RakPriority::Normal,
+        );
+        session
+            .build_data_datagram(now)
+            .expect("datagram")
+            .header
+            .sequence
+    }
+
+    fn ack(session: &mut Session, seq: Sequence24) {
+        use crate::protocol::ack::AckNackPayload;
+
+        session.handle_ack_payload(AckNackPayload {
+            ranges: vec![SequenceRange {
+                start: seq,
+                end: seq,
+            }],
+        });
+    }
+
+    #[test]
+    fn rto_follows_the_measured_round_trips() {
+        let mut session = Session::new(1200);
+        let start = Instant::now();
+        assert_eq!(session.rto(), Duration::from_secs(1));
+
+        // First sample: SRTT = R, RTTVAR = R/2, so RTO = 3R.
+        let seq = send_reliable(&mut session, start);
+        ack(&mut session, seq);
+        session.on_tick(start + Duration::from_millis(200));
+        assert_eq!(session.rtt(), Some(Duration::from_millis(200)));
+        assert_eq!(session.rto(), Duration::from_millis(600));
+
+        // A steady 200 ms link shrinks the variation towards the floor.
+        let mut now = start;
+        for _ in 0..40 {
+            let seq = send_reliable(&mut session, now);
+            now += Duration::from_millis(200);
+            ack(&mut session, seq);
+            session.on_tick(now);
+        }
+        let rto = session.rto();
+        assert!(
+            rto >= Duration::from_millis(230) && rto < Duration::from_millis(260),
+            "{rto:?}"
+        );
+    }
+
+    #[test]
+    fn rto_is_clamped_to_the_configured_bounds() {
+        let tunables = SessionTunables {
+            min_rto: Duration::from_millis(100),
+            max_rto: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let mut session = Session::with_tunables(1200, tunables.clone());
+        assert_eq!(session.rto(), Duration::from_millis(500));
+        session.sample_rtt(Duration::from_millis(2));
+        assert_eq!(session.rto(), Duration::from_millis(100));
+
+        let mut session = Session::with_tunables(1200, tunables);
+        session.sample_rtt(Duration::from_millis(400));
+        assert_eq!(session.rto(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn resends_back_off_then_give_up() {
+        let tunables = SessionTunables {
+            max_rto: Duration::from_millis(800),
+            max_resends: 5,
+            ..Default::default()
+        };
+        let mut session = Session::with_tunables(1200, tunables);
+        // RTO = 50 + 4 * 25 = 150 ms.
+        session.sample_rtt(Duration::from_millis(50));
+        let start = Instant::now();
+        send_reliable(&mut session, start);
+
+        let mut now = start;
+        let mut gaps = Vec::new();
+        while !session.resends_exhausted() {
+            let next = session.next_deadline(now).expect("resend pending");
+            let resent = session.on_tick(next);
+            if !resent.is_empty() {
+                gaps.push((next - now).as_millis());
+            }
+            now = next;
+        }
+        assert_eq!(gaps, [150, 300, 600, 800, 800]);
+        assert_eq!(session.unacked_datagrams(), 1);
+    }
+
+    #[test]
+    fn acks_for_resent_datagrams_are_not_sampled() {
+        let mut session = Session::new(1200);
+        let start = Instant::now();
+        let seq = send_reliable(&mut session, start);
+
+        // Resent after the initial RTO; the ACK might be for either copy.
+        let resend_at = start + session.rto();
+        let out = session.on_tick(resend_at);
+        assert_eq!(out.len(), 1);
+        ack(&mut session, seq);
+        session.on_tick(resend_at + Duration::from_millis(10));
+
+        assert_eq!(session.unacked_datagrams(), 0);
+        assert_eq!(session.rtt(), None);
+    }
+
+    #[test]
+    fn a_nak_resends_at_once_and_an_ack_of_either_copy_clears_it() {
+        use crate::protocol::ack::AckNackPayload;
+        use crate::protocol::constants::DatagramFlags;
+        use crate::protocol::datagram::DatagramPayload;
+
+        let now = Instant::now();
+        for ack_old_copy in [true, false] {
+            let mut session = Session::new(1200);
+            let old = send_reliable(&mut session, now);
+            session.handle_nack_payload(AckNackPayload {
+                ranges: vec![SequenceRange {
+                    start: old,
+                    end: old,
+                }],
+            });
+            session.process_incoming_naks(now);
+
+            let resent = session.take_retransmit().expect("no fast retransmit");
+            assert_ne!(resent.header.sequence, old);
+            assert!(resent.header.flags.contains(DatagramFlags::CONTINUOUS_SEND));
+            assert!(matches!(
+                &resent.payload,
+                DatagramPayload::EncapsulatedPackets(frames) if frames.len() == 1
+            ));
+            assert!(session.take_retransmit().is_none());
+            assert_eq!(session.unacked_datagrams(), 1);
+
+            let acked = if ack_old_copy {
+                old
+            } else {
+                resent.header.sequence
+            };
+            ack(&mut session, acked);
+            session.on_tick(now + Duration::from_millis(10));
+            assert_eq!(session.unacked_datagrams(), 0, "acked {acked:?}");
+            assert!(session.resent_as.is_empty());
+        }
+    }
+
+    #[test]
+    fn a_fast_retransmit_shrinks_the_congestion_window() {
+        use crate::protocol::ack::AckNackPayload;
+
+        let now = Instant::now();
+        let mut session = Session::new(1200);
+        for _ in 0..4 {
+            let seq = send_reliable(&mut session, now);
+            ack(&mut session, seq);
+            session.on_tick(now);
+        }
+        assert!(session.sliding.get_transmission_bandwidth() > 2 * 1200);
+
+        let lost = send_reliable(&mut session, now);
+        session.handle_nack_payload(AckNackPayload {
+            ranges: vec![SequenceRange {
+                start: lost,
+                end: lost,
+            }],
+        });
+        session.process_incoming_naks(now);
+        assert!(session.take_retransmit().is_some());
+        assert!(session.sliding.get_transmission_bandwidth() < 1200);
+    }
+
+    #[test]
+    fn ack_range_wider_than_any_window_is_dropped() {
+        use crate::protocol::ack::AckNackPayload;
+
+        let mut session = Session::new(1200);
+        let now = Instant::now();
+        send_reliable(&mut session, now);
+
+        session.handle_ack_payload(AckNackPayload {
+            ranges: vec![SequenceRange {
+                start: Sequence24::new(0),
+                end: Sequence24::new(0xFF_FFFF),
+            }],
+        });
+        session.on_tick(now);
+        assert_eq!(session.unacked_datagrams(), 1);
+    }
+
+    #[test]
+    fn wide_ack_range_acks_only_what_was_sent() {
+        use crate::protocol::ack::AckNackPayload;
+
+        let mut session = Session::new(1200);
+        let now = Instant::now();
+        let first = send_reliable(&mut session, now);
+        for _ in 0..2 {
+            send_reliable(&mut session, now);
+        }
+        assert_eq!(session.unacked_datagrams(), 3);
+
+        // Wrapping range that covers the three datagrams and thousands more.
+        session.handle_ack_payload(AckNackPayload {
+            ranges: vec![SequenceRange {
+                start: Sequence24::new(first.value().wrapping_sub(4000)),
+                end: Sequence24::new(first.value() + 4000),
+            }],
+        });
+        session.on_tick(now);
+        assert_eq!(session.unacked_datagrams(), 0);
+    }
+
+    #[test]
+    fn split_receipt_resolves_only_after_every_part_is_acked() {
+        use crate::protocol::ack::AckNackPayload;
+        use crate::protocol::state::RakPriority;
+        use tokio::sync::oneshot;
+
+        let mut session = Session::new(600);
+        let now = Instant::now();
+        let (done, mut acked) = oneshot::channel();
+        session.queue_encoded_with(
+            Bytes::from(vec![0x80; 1500]),
+            Reliability::ReliableOrdered,
+            0,
+            RakPriority::Normal,
+            SendOptions {
+                receipt: Some(done),
+                expires: None,
+            },
+            now,
+        );
+        let ack = |session: &mut Session, seq: Sequence24| {
+            session.handle_ack_payload(AckNackPayload {
+                ranges: vec![SequenceRange {
+                    start: seq,
+                    end: seq,
+                }],
+            });
+            session.on_tick(now);
+        };
+        // The congestion window opens as parts are ACKed one by one.
+        let mut parts = 0;
+        while let Some(dgram) = session.build_data_datagram(now) {
+            assert!(acked.try_recv().is_err());
+            assert_eq!(session.pending_receipts(), 1);
+            ack(&mut session, dgram.header.sequence);
+            parts += 1;
+        }
+        assert!(parts > 1);
+        assert!(acked.try_recv().is_ok());
+        assert_eq!(session.pending_receipts(), 0);
+    }
+
+    #[test]
+    fn unreliable_receipt_frames_are_reported_lost_not_resent() {
+        use crate::protocol::ack::AckNackPayload;
+        use crate::protocol::datagram::DatagramPayload;
+        use crate::protocol::state::RakPriority;
+        use tokio::sync::oneshot;
+
+        let mut session = Session::new(1200);
+        let now = Instant::now();
+        let (done, mut outcome) = oneshot::channel();
+        session.queue_encoded_with(
+            Bytes::from_static(b"\x80telemetry"),
+            Reliability::UnreliableWithAckReceipt,
+            0,
+            RakPriority::Normal,
+            SendOptions {
+                receipt: Some(done),
+                expires: None,
+            },
+            now,
+        );
+        // Shares the datagram with a reliable frame, which is resent.
+        let seq = send_reliable(&mut session, now);
+
+        session.handle_nack_payload(AckNackPayload {
+            ranges: vec![SequenceRange {
+                start: seq,
+                end: seq,
+            }],
+        });
+        let resent = session.on_tick(now);
+        assert_eq!(outcome.try_recv(), Ok(ReceiptOutcome::Lost));
+        let DatagramPayload::EncapsulatedPackets(frames) = &resent[0].payload else {
+            panic!("expected data datagram");
+        };
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].header.reliability.is_reliable());
+
+        // On its own, it is not waited on for a resend but still times out.
+        let (done, mut outcome) = oneshot::channel();
+        session.queue_encoded_with(
+            Bytes::from_static(b"\x80telemetry"),
+            Reliability::UnreliableWithAckReceipt,
+            0,
+            RakPriority::Normal,
+            SendOptions {
+                receipt: Some(done),
+                expires: None,
+            },
+            now,
+        );
+        session.build_data_datagram(now).expect("datagram");
+        assert_eq!(session.unacked_datagrams(), 1);
+        let deadline = session.next_deadline(now).expect("receipt deadline");
+        session.on_tick(deadline);
+        assert_eq!(outcome.try_recv(), Ok(ReceiptOutcome::Lost));
+    }
+
+    #[test]
+    fn only_frames_without_a_reliable_index_expire_in_the_queue() {
+        use crate::protocol::datagram::DatagramPayload;
+        use crate::protocol::state::RakPriority;
+        use tokio::sync::oneshot;
+
+        let mut session = Session::new(1200);
+        let now = Instant::now();
+        let later = now + Duration::from_millis(100);
+        let queue = |session: &mut Session, reliability, receipt| {
+            session.queue_encoded_with(
+                Bytes::from_static(b"\x80position"),
+                reliability,
+                0,
+                RakPriority::Normal,
+                SendOptions {
+                    receipt,
+                    expires: Some(later),
+                },
+                now,
+            )
+        };
+        let (done, mut outcome) = oneshot::channel();
+        queue(&mut session, Reliability::Unreliable, None);
+        queue(
+            &mut session,
+            Reliability::UnreliableWithAckReceipt,
+            Some(done),
+        );
+        queue(&mut session, Reliability::ReliableOrdered, None);
+        assert_eq!(session.outgoing_queue_len(), 3);
+
+        let dgram = session.build_data_datagram(later).expect("datagram");
+        let DatagramPayload::EncapsulatedPackets(frames) = dgram.payload else {
+            panic!("expected data datagram");
+        };
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].reliable_index.is_some());
+        assert_eq!(session.expired_messages(), 2);
+        assert_eq!(outcome.try_recv(), Ok(ReceiptOutcome::Lost));
+        assert_eq!(session.memory_usage().outgoing_queue_bytes, 0);
+
+        // Already expired when queued: dropped before taking any index.
+        assert_eq!(
+            session.queue_encoded_with(
+                Bytes::from_static(b"\x80position"),
+                Reliability::ReliableOrdered,
+                0,
+                RakPriority::Normal,
+                SendOptions {
+                    receipt: None,
+                    expires: Some(now),
+                },
+                now,
+            ),
+            0
+        );
+        assert_eq!(session.outgoing_queue_len(), 0);
+        assert_eq!(session.expired_messages(), 3);
+    }
+
+    #[test]
+    fn counts_duplicate_datagrams_and_frames_separately() {
+        use crate::protocol::datagram::DatagramPayload;
+
+        let mut sender = Session::new(1200);
+        let mut receiver = Session::new(1200);
+        let now = Instant::now();
+        send_reliable(&mut sender, now);
+        let dgram = sender
+            .sent_datagrams
+            .values()
+            .next()
+            .unwrap()
+            .datagram
+            .clone();
+        let DatagramPayload::EncapsulatedPackets(frames) = dgram.payload else {
+            panic!("expected data datagram");
+        };
+        let seq = dgram.header.sequence;
+
+        let mut delivered = 0;
+        let mut deliver = |frames, seq| {
+            receiver
+                .handle_data_datagram_with(seq, frames, now, |_| delivered += 1)
+                .unwrap();
+        };
+        deliver(frames.clone(), seq);
+        // The same datagram again: one duplicate datagram.
+        deliver(frames.clone(), seq);
+        // The same frame under a new sequence number: one duplicate frame.
+        deliver(frames, seq.next());
+
+        assert_eq!(delivered, 1);
+        assert_eq!(receiver.duplicate_datagrams(), 1);
+        assert_eq!(receiver.duplicate_frames(), 1);
+    }
+
+    #[test]
+    fn split_resent_after_reassembly_is_not_delivered_again() {
+        use crate::protocol::encapsulated_packet::SplitInfo;
+        use crate::protocol::types::EncapsulatedPacketHeader;
+
+        let part = |index: u32| EncapsulatedPacket {
+            header: EncapsulatedPacketHeader {
+                reliability: Reliability::Reliable,
+                is_split: true,
+                needs_bas: false,
+            },
+            bit_length: 8,
+            reliable_index: Some(Sequence24::new(index)),
+            sequence_index: None,
+            ordering_index: None,
+            ordering_channel: None,
+            split: Some(SplitInfo {
+                count: 3,
+                id: 0,
+                index,
+            }),
+            payload: Bytes::from(vec![if index == 0 { 0xfe } else { 0xab }]),
+        };
+
+        // Every part arrives, then all of them again as if their ACKs were lost.
+        let mut receiver = Session::new(1200);
+        let now = Instant::now();
+        let mut delivered = 0;
+        for seq in 0..6 {
+            receiver
+                .handle_data_datagram_with(Sequence24::new(seq), vec![part(seq % 3)], now, |_| {
+                    delivered += 1
+                })
+                .unwrap();
+        }
+        assert_eq!(delivered, 1);
+        assert_eq!(receiver.duplicate_frames(), 3);
+        assert_eq!(receiver.memory_usage().split_reassembly_bytes, 0);
+    }
+
+    fn ordered(index: u32) -> EncapsulatedPacket {
+        EncapsulatedPacket {
+            header: EncapsulatedPacketHeader {
+                reliability: Reliability::ReliableOrdered,
+                is_split: false,
+                needs_bas: false,
+            },
+            bit_length: 16,
+            reliable_index: Some(Sequence24::new(index)),
+            sequence_index: None,
+            ordering_index: Some(Sequence24::new(index)),
+            ordering_channel: Some(0),
+            split: None,
+            payload: Bytes::from(vec![0xfe, index as u8]),
+        }
+    }
+
+    fn reliable(index: u32) -> EncapsulatedPacket {
+        EncapsulatedPacket {
+            header: EncapsulatedPacketHeader {
+                reliability: Reliability::Reliable,
+                is_split: false,
+                needs_bas: false,
+            },
+            bit_length: 16,
+            reliable_index: Some(Sequence24::new(index)),
+            sequence_index: None,
+            ordering_index: None,
+            ordering_channel: None,
+            split: None,
+            payload: Bytes::from(vec![0xfe, index as u8]),
+        }
+    }
+
+    #[test]
+    fn resent_datagrams_deliver_each_message_once_across_the_wrap() {
+        let mut session = Session::new(1200);
+        session.reliable_tracker =
+            ReliableTracker::starting_at(Sequence24::new(0xff_fffc), MAX_ACK_SEQUENCES as usize);
+        let now = Instant::now();
+
+        // Pairs of messages straddling the wrap, each datagram resent under
+        // a new sequence number now and then, and some of them out of order.
+        let datagrams = [
+            [0xff_fffc, 0xff_fffd],
+            [0xff_fffe, 0xff_ffff],
+            [0, 1],
+            [2, 3],
+        ];
+        let schedule = [0, 1, 0, 3, 1, 2, 0, 3, 2, 1];
+        let mut delivered = Vec::new();
+        for (seq, &i) in schedule.iter().enumerate() {
+            let frames = datagrams[i].iter().map(|&index| reliable(index)).collect();
+            session
+                .handle_data_datagram_with(Sequence24::new(seq as u32), frames, now, |pkt| {
+                    delivered.push(pkt.raw[1])
+                })
+                .unwrap();
+        }
+
+        assert_eq!(delivered, [0xfc, 0xfd, 0xfe, 0xff, 2, 3, 0, 1]);
+        assert_eq!(session.duplicate_frames(), 12);
+    }
+
+    #[test]
+    fn ordered_packets_released_late_keep_their_own_arrival() {
+        let mut session = Session::new(1200);
+        let start = Instant::now();
+        let later = start + Duration::from_millis(250);
+        let mut delivered = Vec::new();
+
+        // Index 1 arrives first in datagram 7 and is held back...
+        session
+            .handle_data_datagram_with(Sequence24::new(7), vec![ordered(1)], start, |pkt| {
+                delivered.push(pkt)
+            })
+            .unwrap();
+        assert!(delivered.is_empty());
+        // ...until index 0 fills the gap in datagram 8.
+        session
+            .handle_data_datagram_with(Sequence24::new(8), vec![ordered(0)], later, |pkt| {
+                delivered.push(pkt)
+            })
+            .unwrap();
+
+        let got: Vec<_> = delivered
+            .iter()
+            .map(|p| (p.raw[1], p.datagram_sequence, p.recv_time))
+            .collect();
+        assert_eq!(got, [(0, Some(8), later), (1, Some(7), start)]);
+    }
+
+    #[test]
+    fn a_full_reorder_buffer_can_skip_past_the_gap() {
+        let tunables = SessionTunables {
+            reorder_limit: ReorderLimit {
+                max_bytes: usize::MAX,
+                max_frames: 3,
+                policy: ReorderLimitPolicy::DropOldest,
+            },
+            ..Default::default()
+        };
+        let mut session = Session::with_tunables(1200, tunables);
+        let now = Instant::now();
+        let mut delivered = Vec::new();
+
+        // Index 0 never arrives; 2..=4 wait behind it.
+        for index in 2..=4 {
+            session
+                .handle_data_datagram_with(
+                    Sequence24::new(index),
+                    vec![ordered(index)],
+                    now,
+                    |pkt| delivered.push(pkt.raw[1]),
+                )
+                .unwrap();
+        }
+        assert!(delivered.is_empty());
+        assert_eq!(session.reorder_frames(), 3);
+
+        // A fourth gives up on 0 and 1 by dropping 2, which frees 3 and 4.
+        session
+            .handle_data_datagram_with(Sequence24::new(6), vec![ordered(6)], now, |pkt| {
+                delivered.push(pkt.raw[1])
+            })
+            .unwrap();
+        assert_eq!(delivered, [3, 4]);
+        assert_eq!(session.reorder_frames(), 1);
+
+        // The late ones are stale now; 5 releases 6.
+        for index in [0, 1, 5] {
+            session
+                .handle_data_datagram_with(
+                    Sequence24::new(7 + index),
+                    vec![ordered(index)],
+                    now,
+                    |pkt| delivered.push(pkt.raw[1]),
+                )
+                .unwrap();
+        }
+        assert_eq!(delivered, [3, 4, 5, 6]);
+        assert_eq!(session.memory_usage().reorder_bytes, 0);
+    }
+
+    #[test]
+    fn stalled_splits_expire_and_release_what_waited_behind_them() {
+        use crate::protocol::encapsulated_packet::SplitInfo;
+
+        let mut session = Session::new(1200);
+        let start = Instant::now();
+        let mut delivered = Vec::new();
+
+        // Nine of ten parts of ordered message 0, then messages 1 and 2.
+        let parts = (0..9).map(|index| {
+            let mut part = ordered(0);
+            part.header.is_split = true;
+            part.reliable_index = Some(Sequence24::new(10 + index));
+            part.split = Some(SplitInfo {
+                count: 10,
+                id: 7,
+                index,
+            });
+            part
+        });
+        session
+            .handle_data_datagram_with(Sequence24::new(0), parts.collect(), start, |pkt| {
+                delivered.push(pkt.raw[1])
+            })
+            .unwrap();
+        session
+            .handle_data_datagram_with(Sequence24::new(1), vec![ordered(1)], start, |pkt| {
+                delivered.push(pkt.raw[1])
+            })
+            .unwrap();
+        assert!(session.memory_usage().split_reassembly_bytes > 0);
+        assert!(delivered.is_empty());
+
+        let timeout = SessionTunables::default().split_timeout;
+        assert_eq!(session.next_deadline(start), Some(start + timeout));
+        session.on_tick(start + timeout);
+        assert_eq!(session.memory_usage().split_reassembly_bytes, 0);
+        assert_eq!(session.expired_splits(), 1);
+
+        // Message 0 is skipped, and 1 comes out without waiting for more
+        // traffic on the channel.
+        session.deliver_released_with(|pkt| delivered.push(pkt.raw[1]));
+        assert_eq!(delivered, [1]);
+        session
+            .handle_data_datagram_with(
+                Sequence24::new(2),
+                vec![ordered(2)],
+                start + timeout,
+                |pkt| delivered.push(pkt.raw[1]),
+            )
+            .unwrap();
+        assert_eq!(delivered, [1, 2]);
+    }
 }
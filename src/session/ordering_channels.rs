@@ -4,10 +4,14 @@ use std::collections::BinaryHeap;
 use crate::protocol::encapsulated_packet::EncapsulatedPacket;
 use crate::protocol::types::Sequence24;
 
+use super::manager::SessionError;
+use super::{Arrival, ReorderLimit, ReorderLimitPolicy};
+
 #[derive(Eq, PartialEq)]
 struct OrderedEncap {
     index: Sequence24,
     pkt: EncapsulatedPacket,
+    arrival: Arrival,
 }
 
 impl Ord for OrderedEncap {
@@ -27,14 +31,21 @@ pub struct OrderingChannels {
     order_read: Vec<Sequence24>,
     order_write: Vec<Sequence24>,
     heaps: Vec<BinaryHeap<Reverse<OrderedEncap>>>,
+    /// Payload bytes buffered per channel, checked against `limit`.
+    channel_bytes: Vec<usize>,
+    buffered_bytes: usize,
+    limit: ReorderLimit,
 }
 
 impl OrderingChannels {
-    pub fn new(max_channels: usize) -> Self {
+    pub fn new(max_channels: usize, limit: ReorderLimit) -> Self {
         Self {
             order_read: vec![Sequence24::new(0); max_channels],
             order_write: vec![Sequence24::new(0); max_channels],
             heaps: (0..max_channels).map(|_| BinaryHeap::new()).collect(),
+            channel_bytes: vec![0; max_channels],
+            buffered_bytes: 0,
+            limit,
         }
     }
 
@@ -42,6 +53,17 @@ impl OrderingChannels {
         self.order_read.len()
     }
 
+    /// Payload bytes held back waiting for an earlier ordering index.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Packets held back waiting for an earlier ordering index, over all
+    /// channels.
+    pub fn buffered_frames(&self) -> usize {
+        self.heaps.iter().map(BinaryHeap::len).sum()
+    }
+
     pub fn next_order_index(&mut self, channel: u8) -> Option<Sequence24> {
         let ch = channel as usize;
         if ch >= self.order_write.len() {
@@ -52,85 +74,108 @@ impl OrderingChannels {
         Some(idx)
     }
 
-    /// Force advance the read index if it matches the given index and
-    /// return any buffered packets that become ready as a result.
+    /// Move the read index past `index` if it is the one being waited for,
+    /// as when the packet at it was given up on (e.g. split timeout).
     ///
-    /// Used when an ordered packet is dropped (e.g. split timeout) so that
-    /// downstream code can immediately deliver any newly unblocked packets.
-    pub fn skip_index(
-        &mut self,
-        channel: u8,
-        index: Sequence24,
-    ) -> Option<Vec<EncapsulatedPacket>> {
+    /// Follow up with `pop_ready` on the channel for the packets this
+    /// unblocks.
+    pub fn skip_index(&mut self, channel: u8, index: Sequence24) {
         let ch = channel as usize;
-        if ch >= self.order_read.len() {
-            return None;
+        if self.order_read.get(ch) != Some(&index) {
+            return;
         }
-        if self.order_read[ch] != index {
-            return None;
-        }
-
-        self.order_read[ch] = self.order_read[ch].next();
-
-        let mut ready = Vec::new();
-        while let Some(top) = self.heaps[ch].peek() {
-            if top.0.index == self.order_read[ch] {
-                let Reverse(OrderedEncap { index: _, pkt }) = self.heaps[ch].pop().unwrap();
-                self.order_read[ch] = self.order_read[ch].next();
-                ready.push(pkt);
-            } else {
-                break;
-            }
-        }
-
-        tracing::trace!(
-            channel = ch,
-            skipped = index.value(),
-            released = ready.len(),
-            "ordering_skip_release"
-        );
-        Some(ready)
+        self.order_read[ch] = index.next();
+        tracing::trace!(channel = ch, skipped = index.value(), "ordering_skip");
     }
 
-    /// Handle an ordered packet; returns a list of packets ready for decode in-order.
-    pub fn handle_ordered(&mut self, enc: EncapsulatedPacket) -> Option<Vec<EncapsulatedPacket>> {
-        let ch = enc.ordering_channel? as usize;
+    /// Handle an ordered packet; returns it if it is next in line.
+    ///
+    /// Delivering a packet may unblock buffered ones, so follow up with
+    /// `pop_ready` on the same channel until it returns `None`; under
+    /// `ReorderLimitPolicy::DropOldest` that holds even when nothing is
+    /// returned. Buffered packets come back with the `arrival` they were
+    /// handed in with.
+    ///
+    /// Fails with `ReorderBufferFull` when buffering the packet would take
+    /// its channel past the `ReorderLimit` under the `Disconnect` policy.
+    pub(crate) fn handle_ordered(
+        &mut self,
+        enc: EncapsulatedPacket,
+        arrival: Arrival,
+    ) -> Result<Option<EncapsulatedPacket>, SessionError> {
+        let (Some(channel), Some(idx)) = (enc.ordering_channel, enc.ordering_index) else {
+            return Ok(None);
+        };
+        let ch = channel as usize;
         if ch >= self.heaps.len() {
-            return None;
+            return Ok(None);
         }
-        let idx = enc.ordering_index?;
 
         if self.order_read[ch] < idx {
-            // Prevent unbounded growth if a client skips sequences or floods.
-            if self.heaps[ch].len() >= 2048 {
-                tracing::warn!(
-                    channel = ch,
-                    "dropping ordered packet, buffer full (len=2048)"
-                );
-                return Some(Vec::new());
+            let len = enc.payload.len();
+            let fits = self.heaps[ch].len() < self.limit.max_frames
+                && self.channel_bytes[ch] + len <= self.limit.max_bytes;
+            if !fits && self.limit.policy == ReorderLimitPolicy::Disconnect {
+                return Err(SessionError::ReorderBufferFull(channel));
             }
 
+            self.channel_bytes[ch] += len;
+            self.buffered_bytes += len;
             self.heaps[ch].push(Reverse(OrderedEncap {
                 index: idx,
                 pkt: enc,
+                arrival,
             }));
-            return Some(Vec::new());
+            if !fits {
+                self.drop_oldest(ch);
+            }
+            return Ok(None);
         } else if self.order_read[ch] > idx {
-            return Some(Vec::new());
+            return Ok(None);
         }
 
         self.order_read[ch] = self.order_read[ch].next();
-        let mut ready = vec![enc];
-
-        while let Some(top) = self.heaps[ch].peek() {
-            if top.0.index == self.order_read[ch] {
-                let Reverse(OrderedEncap { index: _, pkt }) = self.heaps[ch].pop().unwrap();
-                self.order_read[ch] = self.order_read[ch].next();
-                ready.push(pkt);
-            } else {
+        Ok(Some(enc))
+    }
+
+    /// Give up on the gap holding channel `ch` back: discard its oldest
+    /// buffered packets, moving the read index past each, until the channel
+    /// is back under the limit.
+    fn drop_oldest(&mut self, ch: usize) {
+        let mut dropped = 0;
+        while self.heaps[ch].len() > self.limit.max_frames
+            || self.channel_bytes[ch] > self.limit.max_bytes
+        {
+            let Some(Reverse(oldest)) = self.heaps[ch].pop() else {
                 break;
-            }
+            };
+            self.release_bytes(ch, oldest.pkt.payload.len());
+            self.order_read[ch] = oldest.index.next();
+            dropped += 1;
+        }
+        tracing::warn!(
+            channel = ch,
+            dropped,
+            read_index = self.order_read[ch].value(),
+            "reorder buffer full, skipped past the gap"
+        );
+    }
+
+    fn release_bytes(&mut self, ch: usize, len: usize) {
+        self.channel_bytes[ch] = self.channel_bytes[ch].saturating_sub(len);
+        self.buffered_bytes = self.buffered_bytes.saturating_sub(len);
+    }
+
+    /// Pop the buffered packet at the channel's read index, if it has arrived.
+    pub(crate) fn pop_ready(&mut self, channel: u8) -> Option<(EncapsulatedPacket, Arrival)> {
+        let ch = channel as usize;
+        let heap = self.heaps.get_mut(ch)?;
+        if heap.peek()?.0.index != self.order_read[ch] {
+            return None;
         }
-        Some(ready)
+        let Reverse(OrderedEncap { pkt, arrival, .. }) = heap.pop()?;
+        self.release_bytes(ch, pkt.payload.len());
+        self.order_read[ch] = self.order_read[ch].next();
+        Some((pkt, arrival))
     }
 }
@@ -1,6 +1,6 @@
 use std::time::Instant;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
 use crate::protocol::{
     ack::AckNackPayload,
@@ -13,7 +13,7 @@ use crate::protocol::{
     types::{EncapsulatedPacketHeader, Sequence24},
 };
 
-use super::{QueuedEncap, Session, TrackedDatagram};
+use super::{FrameTags, QueuedEncap, ReceiptOutcome, SendOptions, Session, TrackedDatagram};
 
 impl Session {
     pub fn queue_packet(
@@ -23,24 +23,138 @@ impl Session {
         channel: u8,
         priority: RakPriority,
     ) -> usize {
-        if channel as usize >= self.ordering.max_channels() {
-            return 0;
-        }
+        self.queue_packet_tagged(pkt, reliability, channel, priority, FrameTags::default())
+    }
+
+    /// Like `queue_packet`, for a packet the session generates itself. Only
+    /// these may skip the congestion window: unreliable `Immediate` control
+    /// packets (pings, pongs) go out even when it is full.
+    pub fn queue_control(
+        &mut self,
+        pkt: RaknetPacket,
+        reliability: Reliability,
+        channel: u8,
+        priority: RakPriority,
+    ) -> usize {
+        let tags = FrameTags {
+            control: true,
+            ..FrameTags::default()
+        };
+        self.queue_packet_tagged(pkt, reliability, channel, priority, tags)
+    }
+
+    /// Encode `pkt` and queue it with `tags`. A packet that fails to encode
+    /// queues nothing.
+    fn queue_packet_tagged(
+        &mut self,
+        pkt: RaknetPacket,
+        reliability: Reliability,
+        channel: u8,
+        priority: RakPriority,
+        tags: FrameTags,
+    ) -> usize {
         let mut payload_buf = BytesMut::new();
         if pkt.encode(&mut payload_buf).is_err() {
             return 0;
         }
-        let payload = payload_buf.freeze();
+        self.queue_tagged(payload_buf.freeze(), reliability, channel, priority, tags)
+    }
 
-        let max_len = self.max_encapsulated_payload_len(reliability, false).max(1);
+    /// Like `queue_packet`, for a packet already encoded as ID byte + body.
+    /// Frames share `payload` rather than copying it.
+    pub fn queue_encoded(
+        &mut self,
+        payload: Bytes,
+        reliability: Reliability,
+        channel: u8,
+        priority: RakPriority,
+    ) -> usize {
+        self.queue_tagged(
+            payload,
+            reliability,
+            channel,
+            priority,
+            FrameTags::default(),
+        )
+    }
+
+    /// Like `queue_encoded`, with a receipt and/or an expiry.
+    ///
+    /// A receipt is told `Acked` once every datagram carrying a frame of the
+    /// packet has been ACKed; for a split packet, that is all of its parts.
+    /// `UnreliableWithAckReceipt` packets that fit in one frame are sent
+    /// unreliably and reported `Lost` if their datagram is NACKed or not
+    /// ACKed within the retransmission timeout. Other unreliable packets are
+    /// sent reliably, as they are when split, since nothing else would be
+    /// ACKed. If nothing is queued the receipt is dropped straight away.
+    ///
+    /// A packet already expired at `now` is dropped (and its receipt told
+    /// `Lost`). After that, only frames without a reliable index are dropped
+    /// when they expire in the queue: skipping a reliable index would leave
+    /// a hole the peer waits on forever.
+    pub fn queue_encoded_with(
+        &mut self,
+        payload: Bytes,
+        reliability: Reliability,
+        channel: u8,
+        priority: RakPriority,
+        opts: SendOptions,
+        now: Instant,
+    ) -> usize {
+        if opts.expires.is_some_and(|at| at <= now) {
+            self.expired_messages += 1;
+            if let Some(done) = opts.receipt {
+                let _ = done.send(ReceiptOutcome::Lost);
+            }
+            return 0;
+        }
+        let mut tags = FrameTags {
+            expires: opts.expires,
+            ..FrameTags::default()
+        };
+        let Some(done) = opts.receipt else {
+            return self.queue_tagged(payload, reliability, channel, priority, tags);
+        };
+
+        let reliability = match reliability {
+            Reliability::UnreliableWithAckReceipt => reliability,
+            other => normalize_reliability_for_split(other),
+        };
+        let id = self.receipts.next_id();
+        tags.receipt = Some(id);
+        let queued = self.outgoing_heap.len();
+        let added = self.queue_tagged(payload, reliability, channel, priority, tags);
+        self.receipts
+            .track(id, self.outgoing_heap.len() - queued, done);
+        added
+    }
+
+    fn queue_tagged(
+        &mut self,
+        payload: Bytes,
+        reliability: Reliability,
+        channel: u8,
+        priority: RakPriority,
+        tags: FrameTags,
+    ) -> usize {
+        if channel as usize >= self.ordering.max_channels() {
+            return 0;
+        }
+        let max_len = max_encapsulated_payload_len(self.mtu, reliability, false).max(1);
 
         if payload.len() <= max_len {
-            self.enqueue_single_encap(payload, reliability, channel, priority)
+            self.enqueue_single_encap(payload, reliability, channel, priority, tags)
         } else {
-            self.enqueue_fragmented_encaps(payload, reliability, channel, priority)
+            self.enqueue_fragmented_encaps(payload, reliability, channel, priority, tags)
         }
     }
 
+    /// Frames and queue bytes a `len`-byte packet would add if queued now,
+    /// counting every part of a split.
+    pub fn queue_cost(&self, len: usize, reliability: Reliability) -> (usize, usize) {
+        queue_cost(self.mtu, len, reliability)
+    }
+
     /// Build the next DATA datagram to send, if any, respecting MTU and sliding window.
     pub fn build_data_datagram(&mut self, now: Instant) -> Option<Datagram> {
         if self.outgoing_heap.is_empty() {
@@ -48,18 +162,24 @@ impl Session {
         }
 
         let mut transmission_bw = self.sliding.get_transmission_bandwidth();
-        if transmission_bw == 0 {
-            return None;
-        }
 
         let mut packets = Vec::new();
+        let mut receipts = Vec::new();
+        let mut unreliable_receipts = Vec::new();
         // Account for IP + UDP headers so the full on-wire packet stays within the
         // negotiated MTU and avoids downstream fragmentation.
         let mut current_size = constants::IPV4_HEADER_SIZE
             + constants::UDP_HEADER_SIZE
             + constants::RAKNET_DATAGRAM_HEADER_SIZE;
 
-        self.fill_datagram(&mut packets, &mut current_size, &mut transmission_bw);
+        let first_frame = self.fill_datagram(
+            now,
+            &mut packets,
+            &mut receipts,
+            &mut unreliable_receipts,
+            &mut current_size,
+            &mut transmission_bw,
+        );
 
         if packets.is_empty() {
             return None;
@@ -88,23 +208,44 @@ impl Session {
             payload: DatagramPayload::EncapsulatedPackets(packets),
         };
 
+        if !unreliable_receipts.is_empty() {
+            let deadline = now + self.sliding.get_rto_for_retransmission();
+            self.receipts.watch(seq, unreliable_receipts, deadline);
+        }
         if has_reliable {
-            return Some(self.track_sent_datagram(dgram, seq, now));
+            let first_frame = first_frame.expect("reliable frames were packed");
+            return Some(self.track_sent_datagram(dgram, seq, receipts, first_frame, now));
         }
 
         Some(dgram)
     }
 
+    /// Pack queued frames into `packets` until the datagram or the window is
+    /// full, returning the lowest number among the reliable ones.
     fn fill_datagram(
         &mut self,
+        now: Instant,
         packets: &mut Vec<EncapsulatedPacket>,
+        receipts: &mut Vec<u64>,
+        unreliable_receipts: &mut Vec<u64>,
         current_size: &mut usize,
         transmission_bw: &mut usize,
-    ) {
+    ) -> Option<u64> {
+        let mut first_reliable: Option<u64> = None;
         while let Some(top) = self.outgoing_heap.peek() {
             let pkt_size = top.pkt.size();
 
-            if *transmission_bw < pkt_size {
+            if top.tags.expires.is_some_and(|at| at <= now) {
+                let stale = self.outgoing_heap.pop().unwrap();
+                self.outgoing_queue_bytes = self.outgoing_queue_bytes.saturating_sub(pkt_size);
+                self.expired_messages += 1;
+                if let Some(id) = stale.tags.receipt {
+                    self.receipts.message_lost(id);
+                }
+                continue;
+            }
+
+            if !top.bypasses_window && *transmission_bw < pkt_size {
                 break;
             }
             if *current_size + pkt_size > self.mtu {
@@ -112,21 +253,35 @@ impl Session {
             }
 
             let queued = self.outgoing_heap.pop().unwrap();
-            *transmission_bw -= pkt_size;
+            self.outgoing_queue_bytes = self.outgoing_queue_bytes.saturating_sub(pkt_size);
+            if !queued.bypasses_window {
+                *transmission_bw -= pkt_size;
+            }
             *current_size += pkt_size;
+            if queued.pkt.header.reliability.is_reliable() {
+                receipts.extend(queued.tags.receipt);
+                first_reliable =
+                    Some(first_reliable.map_or(queued.number, |n| n.min(queued.number)));
+            } else {
+                unreliable_receipts.extend(queued.tags.receipt);
+            }
             packets.push(queued.pkt);
         }
+        first_reliable
     }
 
     pub(crate) fn build_ack_datagram(&mut self, _now: Instant) -> Option<Datagram> {
-        let mut ranges = self.outgoing_acks.pop_for_mtu(
+        let ranges = self.outgoing_acks.pop_for_mtu(
             self.mtu,
             constants::IPV4_HEADER_SIZE + constants::UDP_HEADER_SIZE + 2 + 1,
+            constants::MAX_ACK_SEQUENCES as usize,
         );
+        if self.outgoing_acks.is_empty() {
+            self.ack_due = None;
+        }
         if ranges.is_empty() {
             return None;
         }
-        ranges.sort_by_key(|r| r.start.value());
 
         let ack_payload = AckNackPayload { ranges };
         tracing::trace!("ack_payload");
@@ -146,14 +301,14 @@ impl Session {
     }
 
     pub(crate) fn build_nak_datagram(&mut self) -> Option<Datagram> {
-        let mut ranges = self.outgoing_naks.pop_for_mtu(
+        let ranges = self.outgoing_naks.pop_for_mtu(
             self.mtu,
             constants::IPV4_HEADER_SIZE + constants::UDP_HEADER_SIZE + 2 + 1,
+            constants::MAX_ACK_SEQUENCES as usize,
         );
         if ranges.is_empty() {
             return None;
         }
-        ranges.sort_by_key(|r| r.start.value());
 
         let nak_payload = AckNackPayload { ranges };
         tracing::trace!("nak_payload");
@@ -170,20 +325,6 @@ impl Session {
         Some(dgram)
     }
 
-    /// Maximum payload size for an encapsulated packet given the current MTU.
-    /// Uses the actual header footprint for the reliability/flags instead of worst-case.
-    fn max_encapsulated_payload_len(&self, reliability: Reliability, is_split: bool) -> usize {
-        let header = self.encapsulated_header_overhead(reliability, is_split);
-        // Only subtract the encapsulated header; datagram/IP/UDP headers are accounted for
-        // once when packing the datagram in `fill_datagram`.
-        self.mtu.saturating_sub(
-            header
-                + constants::IPV4_HEADER_SIZE
-                + constants::UDP_HEADER_SIZE
-                + constants::RAKNET_DATAGRAM_HEADER_SIZE,
-        )
-    }
-
     fn next_reliable_index(&mut self) -> Sequence24 {
         let idx = self.reliability_write_index;
         self.reliability_write_index = self.reliability_write_index.next();
@@ -206,6 +347,7 @@ impl Session {
         reliability: Reliability,
         channel: u8,
         priority: RakPriority,
+        tags: FrameTags,
     ) -> usize {
         let header = EncapsulatedPacketHeader {
             reliability,
@@ -243,28 +385,20 @@ impl Session {
         };
 
         let size = encapsulated.size();
-        self.push_outgoing_encap(encapsulated, priority);
+        self.push_outgoing_encap(encapsulated, priority, tags);
         if reliability.is_reliable() { size } else { 0 }
     }
 
-    fn normalize_reliability_for_split(&self, reliability: Reliability) -> Reliability {
-        match reliability {
-            Reliability::Unreliable => Reliability::Reliable,
-            Reliability::UnreliableSequenced => Reliability::ReliableSequenced,
-            Reliability::UnreliableWithAckReceipt => Reliability::ReliableWithAckReceipt,
-            other => other,
-        }
-    }
-
     fn enqueue_fragmented_encaps(
         &mut self,
         mut payload: bytes::Bytes,
         reliability: Reliability,
         channel: u8,
         priority: RakPriority,
+        tags: FrameTags,
     ) -> usize {
-        let reliability = self.normalize_reliability_for_split(reliability);
-        let max_len = self.max_encapsulated_payload_len(reliability, true).max(1);
+        let reliability = normalize_reliability_for_split(reliability);
+        let max_len = max_encapsulated_payload_len(self.mtu, reliability, true).max(1);
 
         let total = payload.len();
         let parts = ((total - 1) / max_len) + 1;
@@ -326,7 +460,7 @@ impl Session {
 
             let size = encapsulated.size();
 
-            self.push_outgoing_encap(encapsulated, priority);
+            self.push_outgoing_encap(encapsulated, priority, tags);
             if reliability.is_reliable() {
                 reliable_bytes += size;
             }
@@ -334,43 +468,58 @@ impl Session {
         reliable_bytes
     }
 
-    fn push_outgoing_encap(&mut self, pkt: EncapsulatedPacket, priority: RakPriority) {
-        let weight = self.get_next_weight(priority);
-        self.outgoing_heap.push(QueuedEncap { weight, pkt });
-    }
-
-    /// Compute the header overhead (flags + indexes + split metadata) for an encapsulated packet.
-    fn encapsulated_header_overhead(&self, reliability: Reliability, is_split: bool) -> usize {
-        let mut size = 3; // flags + bit_length
-        if reliability.is_reliable() {
-            size += 3; // reliable_index
-        }
-        if reliability.is_sequenced() {
-            size += 3; // sequence_index
-        }
-        if reliability.is_ordered() || reliability.is_sequenced() {
-            size += 3; // ordering_index
-            size += 1; // ordering_channel
-        }
-        if is_split {
-            size += 4; // partCount
-            size += 2; // partId
-            size += 4; // partIndex
+    fn push_outgoing_encap(
+        &mut self,
+        pkt: EncapsulatedPacket,
+        priority: RakPriority,
+        mut tags: FrameTags,
+    ) {
+        if pkt.reliable_index.is_some() {
+            tags.expires = None;
         }
-        size
+        let weight = self.get_next_weight(priority);
+        let bypasses_window = tags.control
+            && priority == RakPriority::Immediate
+            && !pkt.header.reliability.is_reliable();
+        self.outgoing_queue_bytes += pkt.size();
+        let number = self.frames_queued;
+        self.frames_queued += 1;
+        self.outgoing_heap.push(QueuedEncap {
+            weight,
+            number,
+            pkt,
+            bypasses_window,
+            tags,
+        });
     }
 
-    fn track_sent_datagram(&mut self, dgram: Datagram, seq: Sequence24, now: Instant) -> Datagram {
+    fn track_sent_datagram(
+        &mut self,
+        dgram: Datagram,
+        seq: Sequence24,
+        receipts: Vec<u64>,
+        first_frame: u64,
+        now: Instant,
+    ) -> Datagram {
         let rto = self.sliding.get_rto_for_retransmission();
+        let mut resend = dgram.clone();
+        // `UnreliableWithAckReceipt` frames are reported lost, never resent.
+        if let DatagramPayload::EncapsulatedPackets(frames) = &mut resend.payload {
+            frames.retain(|f| f.header.reliability != Reliability::UnreliableWithAckReceipt);
+        }
         let tracked = TrackedDatagram {
-            datagram: dgram,
+            datagram: resend,
             send_time: now,
             next_send: now + rto,
+            resends: 0,
+            receipts,
+            earlier: Vec::new(),
+            first_frame,
         };
         if let DatagramPayload::EncapsulatedPackets(_) = &tracked.datagram.payload {
             self.sliding.on_reliable_send(&tracked.datagram);
         }
-        let dgram = tracked.datagram.clone();
+        self.resend_bytes += tracked.datagram.size();
         self.sent_datagrams.insert(seq, tracked);
         dgram
     }
@@ -421,9 +570,13 @@ impl Session {
 
         for seq in to_resend {
             if let Some(tracked) = self.sent_datagrams.get_mut(&seq) {
-                let rto = self.sliding.get_rto_for_retransmission();
+                if tracked.resends >= self.max_resends {
+                    self.resends_exhausted = true;
+                    continue;
+                }
+                tracked.resends += 1;
                 tracked.send_time = now;
-                tracked.next_send = now + rto;
+                tracked.next_send = now + self.sliding.backoff_rto(tracked.resends);
                 resent_any = true;
                 out.push(tracked.datagram.clone());
             }
@@ -433,6 +586,102 @@ impl Session {
             self.sliding.on_resend(self.datagram_write_index);
         }
     }
+
+    /// Repackage the datagram tracked under `seq` with a fresh sequence
+    /// number and queue it ahead of new data. The old sequence stays mapped
+    /// to the new one, so whichever copy is ACKed first clears it.
+    pub(crate) fn retransmit_now(&mut self, seq: Sequence24, now: Instant) {
+        let Some(mut tracked) = self.sent_datagrams.remove(&seq) else {
+            return;
+        };
+        if tracked.resends >= self.max_resends {
+            self.resends_exhausted = true;
+            self.sent_datagrams.insert(seq, tracked);
+            return;
+        }
+        let new_seq = self.datagram_write_index;
+        self.datagram_write_index = new_seq.next();
+
+        tracked.resends += 1;
+        tracked.send_time = now;
+        tracked.next_send = now + self.sliding.backoff_rto(tracked.resends);
+        tracked.earlier.push(seq);
+        for old in &tracked.earlier {
+            self.resent_as.insert(*old, new_seq);
+        }
+        tracked.datagram.header = crate::protocol::types::DatagramHeader {
+            flags: crate::protocol::constants::DatagramFlags::VALID
+                | crate::protocol::constants::DatagramFlags::CONTINUOUS_SEND,
+            sequence: new_seq,
+        };
+        self.retransmits.push_back(tracked.datagram.clone());
+        self.sent_datagrams.insert(new_seq, tracked);
+        // A loss shrinks the window just as a timed resend does.
+        self.sliding.on_resend(self.datagram_write_index);
+    }
+
+    /// The next datagram queued by `retransmit_now`.
+    pub(crate) fn take_retransmit(&mut self) -> Option<Datagram> {
+        self.retransmits.pop_front()
+    }
+}
+
+/// Frames and queue bytes a `len`-byte packet costs a session with `mtu`,
+/// counting every part of a split.
+pub(crate) fn queue_cost(mtu: usize, len: usize, reliability: Reliability) -> (usize, usize) {
+    let max_len = max_encapsulated_payload_len(mtu, reliability, false).max(1);
+    if len <= max_len {
+        return (1, len + encapsulated_header_overhead(reliability, false));
+    }
+    let reliability = normalize_reliability_for_split(reliability);
+    let max_len = max_encapsulated_payload_len(mtu, reliability, true).max(1);
+    let parts = ((len - 1) / max_len) + 1;
+    let overhead = encapsulated_header_overhead(reliability, true);
+    (parts, len + parts * overhead)
+}
+
+/// Maximum payload size for an encapsulated packet given the MTU.
+/// Uses the actual header footprint for the reliability/flags instead of worst-case.
+fn max_encapsulated_payload_len(mtu: usize, reliability: Reliability, is_split: bool) -> usize {
+    let header = encapsulated_header_overhead(reliability, is_split);
+    // Only subtract the encapsulated header; datagram/IP/UDP headers are accounted for
+    // once when packing the datagram in `fill_datagram`.
+    mtu.saturating_sub(
+        header
+            + constants::IPV4_HEADER_SIZE
+            + constants::UDP_HEADER_SIZE
+            + constants::RAKNET_DATAGRAM_HEADER_SIZE,
+    )
+}
+
+fn normalize_reliability_for_split(reliability: Reliability) -> Reliability {
+    match reliability {
+        Reliability::Unreliable => Reliability::Reliable,
+        Reliability::UnreliableSequenced => Reliability::ReliableSequenced,
+        Reliability::UnreliableWithAckReceipt => Reliability::ReliableWithAckReceipt,
+        other => other,
+    }
+}
+
+/// Compute the header overhead (flags + indexes + split metadata) for an encapsulated packet.
+fn encapsulated_header_overhead(reliability: Reliability, is_split: bool) -> usize {
+    let mut size = 3; // flags + bit_length
+    if reliability.is_reliable() {
+        size += 3; // reliable_index
+    }
+    if reliability.is_sequenced() {
+        size += 3; // sequence_index
+    }
+    if reliability.is_ordered() || reliability.is_sequenced() {
+        size += 3; // ordering_index
+        size += 1; // ordering_channel
+    }
+    if is_split {
+        size += 4; // partCount
+        size += 2; // partId
+        size += 4; // partIndex
+    }
+    size
 }
 
 #[cfg(test)]
@@ -499,6 +748,42 @@ mod tests {
         assert_eq!(ids, vec![0x83, 0x82, 0x81]);
     }
 
+    #[test]
+    fn only_control_packets_skip_a_full_congestion_window() {
+        let mut session = Session::new(1400);
+        let now = Instant::now();
+        let user = |id| RaknetPacket::UserData {
+            id,
+            payload: Bytes::from_static(&[0; 1000]),
+        };
+        // Fill the window with reliable data.
+        loop {
+            session.queue_packet(user(0x80), Reliability::Reliable, 0, RakPriority::Normal);
+            if session.build_data_datagram(now).is_none() {
+                break;
+            }
+        }
+
+        session.queue_packet(
+            user(0x81),
+            Reliability::Unreliable,
+            0,
+            RakPriority::Immediate,
+        );
+        assert!(session.build_data_datagram(now).is_none());
+
+        let ping = RaknetPacket::ConnectedPing(crate::protocol::packet::ConnectedPing {
+            ping_time: crate::protocol::types::RaknetTime(0),
+        });
+        session.queue_control(ping, Reliability::Unreliable, 0, RakPriority::Immediate);
+        let dgram = session.build_data_datagram(now).expect("the ping goes out");
+        let DatagramPayload::EncapsulatedPackets(pkts) = dgram.payload else {
+            panic!("expected encapsulated datagram");
+        };
+        assert_eq!(pkts.len(), 1);
+        assert!(session.build_data_datagram(now).is_none());
+    }
+
     #[test]
     fn priority_weights_reset_when_queue_drains() {
         let mut session = Session::new(1200);
@@ -579,8 +864,17 @@ mod tests {
         };
 
         assert_eq!(pkts.len(), 1);
-        assert!(pkts[0].sequence_index.is_some(), "sequence index should be set");
-        assert!(pkts[0].ordering_index.is_some(), "ordering index should be set for sequenced reliabilities");
-        assert!(pkts[0].ordering_channel.is_some(), "ordering channel should be set for sequenced reliabilities");
+        assert!(
+            pkts[0].sequence_index.is_some(),
+            "sequence index should be set"
+        );
+        assert!(
+            pkts[0].ordering_index.is_some(),
+            "ordering index should be set for sequenced reliabilities"
+        );
+        assert!(
+            pkts[0].ordering_channel.is_some(),
+            "ordering channel should be set for sequenced reliabilities"
+        );
     }
 }
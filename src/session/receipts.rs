@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use tokio::sync::oneshot;
+
+use crate::protocol::{ack::SequenceRange, types::Sequence24};
+
+/// How a message sent with a receipt fared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptOutcome {
+    /// The peer ACKed every frame of it.
+    Acked,
+    /// An `UnreliableWithAckReceipt` frame of it was NACKed, or not ACKed
+    /// before its deadline; or it expired before being sent.
+    Lost,
+}
+
+/// Delivery receipts waiting on the ACKs for their message's frames.
+///
+/// Each frame of a message sent with a receipt carries the receipt's id;
+/// the datagrams those frames go out in remember the ids, and every ACKed
+/// datagram counts its frames off. A split message only completes once all
+/// of its parts have been ACKed.
+///
+/// Unreliable frames are never resent, so the datagrams carrying them are
+/// watched here instead of in the resend buffer: a NACK or a missed
+/// deadline reports the message lost.
+#[derive(Default)]
+pub struct Receipts {
+    next_id: u64,
+    pending: HashMap<u64, PendingReceipt>,
+    unreliable: BTreeMap<Sequence24, WatchedDatagram>,
+}
+
+struct PendingReceipt {
+    /// Frames not yet ACKed.
+    remaining: usize,
+    done: oneshot::Sender<ReceiptOutcome>,
+}
+
+struct WatchedDatagram {
+    deadline: Instant,
+    /// Receipt ids of its unreliable frames, one entry per frame.
+    receipts: Vec<u64>,
+}
+
+impl Receipts {
+    /// Id for the frames of the next message sent with a receipt.
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+
+    /// Start waiting on `frames` frames tagged with `id`. With no frames
+    /// there is nothing to wait for, and `done` is dropped.
+    pub fn track(&mut self, id: u64, frames: usize, done: oneshot::Sender<ReceiptOutcome>) {
+        if frames > 0 {
+            self.pending.insert(
+                id,
+                PendingReceipt {
+                    remaining: frames,
+                    done,
+                },
+            );
+        }
+    }
+
+    /// Datagram `seq` went out carrying unreliable frames tagged with
+    /// `receipts`, which count as lost unless it is ACKed by `deadline`.
+    pub fn watch(&mut self, seq: Sequence24, receipts: Vec<u64>, deadline: Instant) {
+        if !receipts.is_empty() {
+            self.unreliable
+                .insert(seq, WatchedDatagram { deadline, receipts });
+        }
+    }
+
+    /// One frame tagged with `id` was ACKed.
+    pub fn frame_acked(&mut self, id: u64) {
+        let Some(pending) = self.pending.get_mut(&id) else {
+            return;
+        };
+        pending.remaining -= 1;
+        if pending.remaining == 0 {
+            self.resolve(id, ReceiptOutcome::Acked);
+        }
+    }
+
+    /// Watched datagrams in `range` were ACKed.
+    pub fn datagrams_acked(&mut self, range: SequenceRange) {
+        for watched in self.take_in_range(range) {
+            for id in watched.receipts {
+                self.frame_acked(id);
+            }
+        }
+    }
+
+    /// Watched datagrams in `range` were NACKed.
+    pub fn datagrams_lost(&mut self, range: SequenceRange) {
+        for watched in self.take_in_range(range) {
+            self.lost(watched);
+        }
+    }
+
+    /// Report every watched datagram past its deadline as lost.
+    pub fn expire(&mut self, now: Instant) {
+        let due: Vec<_> = self
+            .unreliable
+            .iter()
+            .filter(|(_, watched)| watched.deadline <= now)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for seq in due {
+            if let Some(watched) = self.unreliable.remove(&seq) {
+                self.lost(watched);
+            }
+        }
+    }
+
+    /// Earliest deadline of a watched datagram, if any.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.unreliable.values().map(|w| w.deadline).min()
+    }
+
+    /// Number of receipts still waiting.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The message tagged with `id` was dropped unsent.
+    pub fn message_lost(&mut self, id: u64) {
+        self.resolve(id, ReceiptOutcome::Lost);
+    }
+
+    fn lost(&mut self, watched: WatchedDatagram) {
+        for id in watched.receipts {
+            self.resolve(id, ReceiptOutcome::Lost);
+        }
+    }
+
+    fn resolve(&mut self, id: u64, outcome: ReceiptOutcome) {
+        if let Some(pending) = self.pending.remove(&id) {
+            let _ = pending.done.send(outcome);
+        }
+    }
+
+    fn take_in_range(&mut self, range: SequenceRange) -> Vec<WatchedDatagram> {
+        let len = range.start.distance_to(range.end);
+        let seqs: Vec<_> = self
+            .unreliable
+            .keys()
+            .copied()
+            .filter(|seq| range.start.distance_to(*seq) <= len)
+            .collect();
+        seqs.into_iter()
+            .filter_map(|seq| self.unreliable.remove(&seq))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn one(seq: u32) -> SequenceRange {
+        SequenceRange {
+            start: Sequence24::new(seq),
+            end: Sequence24::new(seq),
+        }
+    }
+
+    #[test]
+    fn resolves_once_every_frame_is_acked() {
+        let mut receipts = Receipts::default();
+        let (done, mut rx) = oneshot::channel();
+        let id = receipts.next_id();
+        receipts.track(id, 2, done);
+
+        receipts.frame_acked(id);
+        assert!(rx.try_recv().is_err());
+        receipts.frame_acked(id);
+        assert_eq!(rx.try_recv(), Ok(ReceiptOutcome::Acked));
+        assert_eq!(receipts.len(), 0);
+    }
+
+    #[test]
+    fn acks_for_unknown_ids_are_ignored() {
+        let mut receipts = Receipts::default();
+        let (done, mut rx) = oneshot::channel();
+        let id = receipts.next_id();
+        receipts.track(id, 1, done);
+
+        receipts.frame_acked(id + 1);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(receipts.len(), 1);
+    }
+
+    #[test]
+    fn watched_datagrams_resolve_on_ack_nack_or_deadline() {
+        let mut receipts = Receipts::default();
+        let now = Instant::now();
+        let deadline = now + Duration::from_millis(100);
+        let mut rxs = Vec::new();
+        for seq in 0..3 {
+            let (done, rx) = oneshot::channel();
+            let id = receipts.next_id();
+            receipts.track(id, 1, done);
+            receipts.watch(Sequence24::new(seq), vec![id], deadline);
+            rxs.push(rx);
+        }
+        assert_eq!(receipts.next_expiry(), Some(deadline));
+
+        receipts.datagrams_acked(one(0));
+        receipts.datagrams_lost(one(1));
+        receipts.expire(now);
+        assert_eq!(rxs[0].try_recv(), Ok(ReceiptOutcome::Acked));
+        assert_eq!(rxs[1].try_recv(), Ok(ReceiptOutcome::Lost));
+        assert!(rxs[2].try_recv().is_err());
+
+        receipts.expire(deadline);
+        assert_eq!(rxs[2].try_recv(), Ok(ReceiptOutcome::Lost));
+        assert_eq!(receipts.next_expiry(), None);
+        // An ACK after the deadline changes nothing.
+        receipts.datagrams_acked(one(2));
+        assert_eq!(receipts.len(), 0);
+    }
+}
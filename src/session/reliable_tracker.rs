@@ -2,7 +2,22 @@ use std::collections::VecDeque;
 
 use crate::protocol::types::Sequence24;
 
+/// Where an inbound reliable index falls relative to what has been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexStatus {
+    /// Not received yet, and inside the window.
+    New,
+    /// Received before, or so far behind the window that it must have been.
+    Duplicate,
+    /// More than the window ahead of the oldest index still missing.
+    TooFarAhead,
+}
+
 /// Tracks inbound reliable sequence numbers and filters duplicates.
+///
+/// Everything before `base` has been received; `window` flags the indexes
+/// after it that arrived early, up to `max_window` of them. All comparisons
+/// go through `Sequence24`, so the window slides across the 24-bit wrap.
 pub struct ReliableTracker {
     base: Sequence24,
     window: VecDeque<bool>,
@@ -18,12 +33,26 @@ impl ReliableTracker {
         }
     }
 
+    /// A tracker that has already received everything before `base`.
+    #[cfg(test)]
+    pub fn starting_at(base: Sequence24, max_window: usize) -> Self {
+        Self {
+            base,
+            ..Self::new(max_window)
+        }
+    }
+
     /// Returns true if this reliable index is new and should be processed.
     /// Returns false for duplicates or indexes too far ahead.
     pub fn see(&mut self, ridx: Sequence24) -> bool {
         if ridx == self.base {
             self.base = self.base.next();
-            self.advance_base();
+            // The window starts one past `base`, so its front slot now
+            // describes the new base itself.
+            if self.window.pop_front() == Some(true) {
+                self.base = self.base.next();
+                self.advance_base();
+            }
             return true;
         }
 
@@ -44,32 +73,30 @@ impl ReliableTracker {
         true
     }
 
-    /// Checks if a reliable index has already been seen/processed without updating the state.
-    /// Returns true if it has been seen (duplicate).
-    pub fn has_seen(&self, ridx: Sequence24) -> bool {
+    /// Classify a reliable index without updating the state.
+    pub fn status(&self, ridx: Sequence24) -> IndexStatus {
         if ridx == self.base {
-            return false; // Expecting base, so we haven't seen it.
+            return IndexStatus::New;
+        }
+        if ridx < self.base {
+            return IndexStatus::Duplicate;
         }
 
         let dist = self.base.distance_to(ridx);
-        if dist == 0 {
-            return true; // Behind base, so seen.
-        }
         if dist as usize > self.max_window {
-            return false; // Too far ahead, treat as not seen (or invalid, but not duplicate).
+            return IndexStatus::TooFarAhead;
         }
 
         let offset = dist as usize - 1;
-        if offset < self.window.len() {
-            return self.window[offset];
+        if self.window.get(offset) == Some(&true) {
+            IndexStatus::Duplicate
+        } else {
+            IndexStatus::New
         }
-
-        false
     }
 
     fn advance_base(&mut self) {
-        while let Some(true) = self.window.front().copied() {
-            self.window.pop_front();
+        while self.window.pop_front() == Some(true) {
             self.base = self.base.next();
         }
     }
@@ -100,9 +127,51 @@ mod tests {
         assert!(t.see(Sequence24::new(0))); // filling gap advances base
     }
 
+    #[test]
+    fn filling_a_gap_keeps_later_indexes_aligned() {
+        let mut t = ReliableTracker::new(16);
+        assert!(t.see(Sequence24::new(2)));
+        assert!(t.see(Sequence24::new(0)));
+
+        assert_eq!(t.status(Sequence24::new(1)), IndexStatus::New);
+        assert_eq!(t.status(Sequence24::new(2)), IndexStatus::Duplicate);
+        assert_eq!(t.status(Sequence24::new(3)), IndexStatus::New);
+
+        assert!(t.see(Sequence24::new(1)));
+        assert!(t.see(Sequence24::new(3)));
+        assert!(!t.see(Sequence24::new(2)));
+    }
+
+    #[test]
+    fn indexes_behind_the_base_count_as_seen() {
+        let mut t = ReliableTracker::new(16);
+        assert!(t.see(Sequence24::new(0)));
+        assert!(t.see(Sequence24::new(1)));
+        assert_eq!(t.status(Sequence24::new(0)), IndexStatus::Duplicate);
+        assert_eq!(t.status(Sequence24::new(1)), IndexStatus::Duplicate);
+        assert_eq!(t.status(Sequence24::new(2)), IndexStatus::New);
+    }
+
     #[test]
     fn rejects_too_far_ahead() {
         let mut t = ReliableTracker::new(2);
+        assert_eq!(t.status(Sequence24::new(5)), IndexStatus::TooFarAhead);
         assert!(!t.see(Sequence24::new(5)));
     }
+
+    #[test]
+    fn slides_across_the_wrap() {
+        let mut t = ReliableTracker::starting_at(Sequence24::new(0xff_fffe), 4);
+        assert!(t.see(Sequence24::new(0)));
+        assert_eq!(t.status(Sequence24::new(0xff_ffff)), IndexStatus::New);
+        assert!(t.see(Sequence24::new(0xff_fffe)));
+        assert!(t.see(Sequence24::new(0xff_ffff)));
+
+        for old in [0xff_fffd, 0xff_fffe, 0xff_ffff, 0] {
+            assert_eq!(t.status(Sequence24::new(old)), IndexStatus::Duplicate);
+            assert!(!t.see(Sequence24::new(old)));
+        }
+        assert_eq!(t.status(Sequence24::new(5)), IndexStatus::New);
+        assert_eq!(t.status(Sequence24::new(6)), IndexStatus::TooFarAhead);
+    }
 }
@@ -1,30 +1,37 @@
 use std::time::{Duration, Instant};
 
-use crate::protocol::constants::{CC_ADDITIONAL_VARIANCE, CC_MAXIMUM_THRESHOLD};
+use crate::protocol::constants::CC_ADDITIONAL_VARIANCE;
 use crate::protocol::datagram::Datagram;
 use crate::protocol::types::Sequence24;
 
+/// RTO before any round trip has been measured (RFC 6298, 2.1).
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
 pub struct SlidingWindow {
     mtu: usize,
     cwnd: f64,
     ss_thresh: f64,
-    estimated_rtt: f64, // -1 = uninitialized
-    last_rtt: f64,      // -1 = none
-    deviation_rtt: f64, // -1 = none
+    /// Smoothed RTT in milliseconds, `None` until the first sample.
+    srtt: Option<f64>,
+    /// RTT variation in milliseconds.
+    rttvar: f64,
+    min_rto: Duration,
+    max_rto: Duration,
     next_congestion_block: Sequence24,
     backoff_this_block: bool,
     unacked_bytes: i64,
 }
 
 impl SlidingWindow {
-    pub fn new(mtu: usize) -> Self {
+    pub fn new(mtu: usize, min_rto: Duration, max_rto: Duration) -> Self {
         Self {
             mtu,
             cwnd: mtu as f64,
             ss_thresh: 0.0,
-            estimated_rtt: -1.0,
-            last_rtt: -1.0,
-            deviation_rtt: -1.0,
+            srtt: None,
+            rttvar: 0.0,
+            min_rto,
+            max_rto,
             next_congestion_block: Sequence24::new(0),
             backoff_this_block: false,
             unacked_bytes: 0,
@@ -39,31 +46,14 @@ impl SlidingWindow {
         self.unacked_bytes += dgram.size() as i64;
     }
 
-    pub fn on_ack(
-        &mut self,
-        now: Instant,
-        dgram: &Datagram,
-        acked_seq: Sequence24,
-        send_time: Instant,
-    ) {
-        let rtt = now.saturating_duration_since(send_time);
-        let rtt_ms = rtt.as_millis() as f64;
-        self.last_rtt = rtt_ms;
+    /// Account for an ACKed datagram. Its round trip is sampled separately,
+    /// and only if it was never resent (Karn's algorithm).
+    pub fn on_ack(&mut self, dgram: &Datagram, acked_seq: Sequence24) {
         self.unacked_bytes -= dgram.size() as i64;
         if self.unacked_bytes < 0 {
             self.unacked_bytes = 0;
         }
 
-        if self.estimated_rtt < 0.0 {
-            self.estimated_rtt = rtt_ms;
-            self.deviation_rtt = rtt_ms;
-        } else {
-            let gain = 0.05;
-            let diff = rtt_ms - self.estimated_rtt;
-            self.estimated_rtt += gain * diff;
-            self.deviation_rtt += gain * (diff.abs() - self.deviation_rtt);
-        }
-
         let is_new_block = acked_seq > self.next_congestion_block;
         if is_new_block {
             self.backoff_this_block = false;
@@ -111,16 +101,54 @@ impl SlidingWindow {
         self.ss_thresh == 0.0 || self.cwnd <= self.ss_thresh
     }
 
+    /// Retransmission timeout per RFC 6298: `SRTT + max(G, 4 * RTTVAR)`,
+    /// with the tick-bound ACK delay standing in for the clock granularity
+    /// `G`, clamped to the configured bounds.
     pub fn get_rto_for_retransmission(&self) -> Duration {
-        if self.estimated_rtt < 0.0 {
-            return Duration::from_millis(CC_MAXIMUM_THRESHOLD as u64);
+        let rto = match self.srtt {
+            None => INITIAL_RTO,
+            Some(srtt) => {
+                let variance = (4.0 * self.rttvar).max(CC_ADDITIONAL_VARIANCE as f64);
+                Duration::from_secs_f64((srtt + variance) / 1000.0)
+            }
+        };
+        rto.clamp(self.min_rto, self.max_rto)
+    }
+
+    /// Timeout before the next resend of a datagram already sent `attempts`
+    /// extra times: the RTO doubled for each, up to the maximum.
+    pub fn backoff_rto(&self, attempts: u32) -> Duration {
+        self.get_rto_for_retransmission()
+            .saturating_mul(1 << attempts.min(16))
+            .min(self.max_rto)
+    }
+
+    /// Start the estimate from `rtt`, measured some other way, unless an
+    /// ACK has already been sampled.
+    pub fn seed_rtt(&mut self, rtt: Duration) {
+        if self.srtt.is_none() {
+            self.sample_rtt(rtt);
         }
-        let mut threshold =
-            (2.0 * self.estimated_rtt + 4.0 * self.deviation_rtt) + CC_ADDITIONAL_VARIANCE as f64;
-        if threshold > CC_MAXIMUM_THRESHOLD as f64 {
-            threshold = CC_MAXIMUM_THRESHOLD as f64;
+    }
+
+    /// Fold one measured round trip into the estimate (RFC 6298, 2.2-2.3).
+    pub fn sample_rtt(&mut self, rtt: Duration) {
+        let r = rtt.as_secs_f64() * 1000.0;
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2.0;
+            }
+            Some(srtt) => {
+                self.rttvar = 0.75 * self.rttvar + 0.25 * (srtt - r).abs();
+                self.srtt = Some(0.875 * srtt + 0.125 * r);
+            }
         }
-        Duration::from_millis(threshold as u64)
+    }
+
+    /// Smoothed RTT estimate, `None` until the first ACK has been sampled.
+    pub fn estimated_rtt(&self) -> Option<Duration> {
+        self.srtt.map(|ms| Duration::from_secs_f64(ms / 1000.0))
     }
 
     pub fn on_send_ack(&mut self) {
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
@@ -16,20 +16,35 @@ struct SplitEntry {
     ordering_channel: Option<u8>,
     needs_bas: bool,
 
-    parts: Vec<Option<bytes::Bytes>>,
-    received: usize,
+    count: u32,
+    /// Parts received so far by index; kept sparse so the peer's claimed
+    /// `count` costs nothing until parts actually arrive.
+    parts: BTreeMap<u32, bytes::Bytes>,
     last_update: Instant,
 }
 
+/// Reassembles split packets, within limits on parts per split, splits in
+/// progress and bytes buffered. Breaking any of them, or sending parts that
+/// contradict each other, is an error the session treats as a bad packet.
+///
+/// A split that goes `ttl` without a new part is given up on by `prune`.
 pub struct SplitAssembler {
     entries: HashMap<u16, SplitEntry>,
+    /// Ids of splits `prune` gave up on, and when. Parts still in flight for
+    /// them are dropped for another `ttl` instead of starting a split that
+    /// could never complete.
+    purged: HashMap<u16, Instant>,
     ttl: Duration,
     max_parts: u32,
     max_concurrent: usize,
+    max_bytes: usize,
+    buffered_bytes: usize,
+    /// Parts received again after their split already had them.
+    duplicate_parts: u64,
 }
 
 impl SplitAssembler {
-    pub fn new(ttl: Duration, max_parts: u32, max_concurrent: usize) -> Self {
+    pub fn new(ttl: Duration, max_parts: u32, max_concurrent: usize, max_bytes: usize) -> Self {
         let ttl = if ttl.is_zero() {
             Duration::from_secs(30)
         } else {
@@ -37,12 +52,26 @@ impl SplitAssembler {
         };
         Self {
             entries: HashMap::new(),
+            purged: HashMap::new(),
             ttl,
             max_parts,
             max_concurrent,
+            max_bytes,
+            buffered_bytes: 0,
+            duplicate_parts: 0,
         }
     }
 
+    /// Payload bytes held in partially reassembled splits.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Parts dropped because their split already had them.
+    pub fn duplicate_parts(&self) -> u64 {
+        self.duplicate_parts
+    }
+
     pub fn add(
         &mut self,
         pkt: EncapsulatedPacket,
@@ -59,11 +88,47 @@ impl SplitAssembler {
         if split.count > self.max_parts {
             return Err(DecodeError::SplitTooLarge);
         }
-
-        if self.entries.len() >= self.max_concurrent && !self.entries.contains_key(&split.id) {
+        if split.index >= split.count {
+            return Err(DecodeError::SplitIndexOutOfRange);
+        }
+        // Duplicate part, just ignore it. Checked before the byte budget, as
+        // a part the split already holds takes no more room.
+        // Returning an error here causes connection drops/lag in some implementations
+        // if the sender aggressively retransmits parts.
+        if self.entries.get(&split.id).is_some_and(|entry| {
+            entry.count == split.count && entry.parts.contains_key(&split.index)
+        }) {
+            tracing::debug!(
+                id = split.id,
+                index = split.index,
+                count = split.count,
+                "duplicate_split_part"
+            );
+            self.duplicate_parts += 1;
+            return Ok(None);
+        }
+        if self.buffered_bytes + pkt.payload.len() > self.max_bytes {
             return Err(DecodeError::SplitBufferFull);
         }
 
+        if !self.entries.contains_key(&split.id) {
+            if self
+                .purged
+                .get(&split.id)
+                .is_some_and(|&at| now.duration_since(at) < self.ttl)
+            {
+                tracing::debug!(
+                    id = split.id,
+                    index = split.index,
+                    "late_part_of_expired_split"
+                );
+                return Ok(None);
+            }
+            if self.entries.len() >= self.max_concurrent {
+                return Err(DecodeError::SplitBufferFull);
+            }
+        }
+
         let entry = self.entries.entry(split.id).or_insert_with(|| SplitEntry {
             reliability: pkt.header.reliability,
             reliable_index: pkt.reliable_index,
@@ -71,43 +136,26 @@ impl SplitAssembler {
             ordering_index: pkt.ordering_index,
             ordering_channel: pkt.ordering_channel,
             needs_bas: pkt.header.needs_bas,
-            parts: vec![None; split.count as usize],
-            received: 0,
+            count: split.count,
+            parts: BTreeMap::new(),
             last_update: now,
         });
 
-        if entry.parts.len() != split.count as usize {
+        if entry.count != split.count {
             return Err(DecodeError::SplitCountMismatch);
         }
-        let idx = split.index as usize;
-        if idx >= entry.parts.len() {
-            return Err(DecodeError::SplitIndexOutOfRange);
-        }
-        if entry.parts[idx].is_some() {
-            // Duplicate part, just ignore it.
-            // Returning an error here causes connection drops/lag in some implementations
-            // if the sender aggressively retransmits parts.
-            tracing::warn!(
-                id = split.id,
-                index = split.index,
-                count = split.count,
-                "duplicate_split_part"
-            );
-            return Ok(None);
-        }
 
-        entry.parts[idx] = Some(pkt.payload.clone());
-        entry.received += 1;
+        self.buffered_bytes += pkt.payload.len();
+        entry.parts.insert(split.index, pkt.payload.clone());
         entry.last_update = now;
 
-        if entry.received != entry.parts.len() {
+        if entry.parts.len() != entry.count as usize {
             return Ok(None);
         }
 
         // All parts present: reassemble
-        let mut buf = BytesMut::new();
-        for part in &entry.parts {
-            let part = part.as_ref().ok_or(DecodeError::SplitCountMismatch)?; // Should not happen given entry.received check
+        let mut buf = BytesMut::with_capacity(entry.parts.values().map(|p| p.len()).sum());
+        for part in entry.parts.values() {
             buf.extend_from_slice(part);
         }
         let payload = buf.freeze();
@@ -133,24 +181,42 @@ impl SplitAssembler {
         };
 
         self.entries.remove(&split.id);
+        self.buffered_bytes = self.buffered_bytes.saturating_sub(assembled.payload.len());
         Ok(Some(assembled))
     }
 
+    /// Earliest instant at which an in-progress split will expire.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.entries
+            .values()
+            .map(|e| e.last_update + self.ttl)
+            .min()
+    }
+
+    /// Give up on splits that have gone `ttl` without a new part, returning
+    /// the ordering channel and index each would have been delivered under.
     pub fn prune(&mut self, now: Instant) -> Vec<(Option<u8>, Option<Sequence24>)> {
+        let ttl = self.ttl;
+        self.purged.retain(|_, at| now.duration_since(*at) < ttl);
+
         let mut dropped = Vec::new();
-        self.entries.retain(|id, entry| {
-            if now.duration_since(entry.last_update) >= self.ttl {
+        let mut freed = 0usize;
+        self.entries.retain(|&id, entry| {
+            if now.duration_since(entry.last_update) >= ttl {
+                freed += entry.parts.values().map(|p| p.len()).sum::<usize>();
                 tracing::warn!(
                     id = id,
                     age = ?now.duration_since(entry.last_update),
                     "dropping_expired_split_packet"
                 );
                 dropped.push((entry.ordering_channel, entry.ordering_index));
+                self.purged.insert(id, now);
                 false
             } else {
                 true
             }
         });
+        self.buffered_bytes = self.buffered_bytes.saturating_sub(freed);
         dropped
     }
 }
@@ -184,7 +250,7 @@ mod tests {
 
     #[test]
     fn rejects_too_many_parts() {
-        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 256);
+        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 256, usize::MAX);
         let pkt = make_split_encap(129, 0);
         let now = Instant::now();
         let res = assembler.add(pkt, now);
@@ -193,7 +259,7 @@ mod tests {
 
     #[test]
     fn rejects_when_buffer_full() {
-        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 4);
+        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 4, usize::MAX);
         let now = Instant::now();
 
         // Fill the buffer with max entries
@@ -212,4 +278,173 @@ mod tests {
         let res = assembler.add(overflow, now);
         assert!(matches!(res, Err(DecodeError::SplitBufferFull)));
     }
+
+    #[test]
+    fn rejects_parts_outside_their_count() {
+        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 256, usize::MAX);
+        let now = Instant::now();
+
+        let res = assembler.add(make_split_encap(0, 0), now);
+        assert!(matches!(res, Err(DecodeError::SplitIndexOutOfRange)));
+        let res = assembler.add(make_split_encap(4, 4), now);
+        assert!(matches!(res, Err(DecodeError::SplitIndexOutOfRange)));
+        // Neither left a split behind to wait for.
+        assert_eq!(assembler.next_expiry(), None);
+    }
+
+    #[test]
+    fn drops_and_counts_a_part_sent_twice() {
+        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 256, usize::MAX);
+        let now = Instant::now();
+
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 1), now),
+            Ok(None)
+        ));
+        let res = assembler.add(make_split_encap(3, 1), now);
+        assert!(matches!(res, Ok(None)));
+        assert_eq!(assembler.duplicate_parts(), 1);
+        assert_eq!(assembler.buffered_bytes(), 4);
+
+        // The split still completes from the remaining parts.
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 0), now),
+            Ok(None)
+        ));
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 2), now),
+            Ok(Some(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_parts_past_the_byte_limit() {
+        // Room for two 4-byte parts.
+        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 256, 8);
+        let now = Instant::now();
+
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 0), now),
+            Ok(None)
+        ));
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 1), now),
+            Ok(None)
+        ));
+        let res = assembler.add(make_split_encap(3, 2), now);
+        assert!(matches!(res, Err(DecodeError::SplitBufferFull)));
+        assert_eq!(assembler.buffered_bytes(), 8);
+    }
+
+    #[test]
+    fn a_part_sent_twice_at_the_byte_limit_is_only_a_duplicate() {
+        // Room for exactly the two parts held.
+        let mut assembler = SplitAssembler::new(Duration::from_secs(30), 128, 256, 8);
+        let now = Instant::now();
+
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 0), now),
+            Ok(None)
+        ));
+        assert!(matches!(
+            assembler.add(make_split_encap(3, 1), now),
+            Ok(None)
+        ));
+        let res = assembler.add(make_split_encap(3, 1), now);
+        assert!(matches!(res, Ok(None)));
+        assert_eq!(assembler.duplicate_parts(), 1);
+        assert_eq!(assembler.buffered_bytes(), 8);
+    }
+
+    #[test]
+    fn random_split_info_stays_within_the_limits() {
+        const MAX_PARTS: u32 = 64;
+        const MAX_CONCURRENT: usize = 16;
+        const MAX_BYTES: usize = 4096;
+
+        // xorshift64*, so a failure can be replayed.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        };
+
+        let mut assembler = SplitAssembler::new(
+            Duration::from_secs(30),
+            MAX_PARTS,
+            MAX_CONCURRENT,
+            MAX_BYTES,
+        );
+        let now = Instant::now();
+        for _ in 0..100_000 {
+            let r = next();
+            // Mostly small counts and ids so splits collide and complete,
+            // now and then anything a u32 can claim.
+            let count = match r % 8 {
+                0 => next() as u32,
+                _ => (r >> 8) as u32 % (MAX_PARTS + 8),
+            };
+            let index = match r % 5 {
+                0 => next() as u32,
+                _ => (r >> 24) as u32 % (count.max(1) + 2),
+            };
+            let mut pkt = make_split_encap(count, index);
+            if let Some(split) = pkt.split.as_mut() {
+                split.id = (r >> 40) as u16 % (MAX_CONCURRENT as u16 * 2);
+            }
+            pkt.payload = Bytes::from(vec![0; (r >> 56) as usize]);
+            let _ = assembler.add(pkt, now);
+
+            assert!(assembler.buffered_bytes() <= MAX_BYTES);
+            assert!(assembler.entries.len() <= MAX_CONCURRENT);
+            for entry in assembler.entries.values() {
+                assert!(entry.count <= MAX_PARTS);
+                assert!(entry.parts.len() < entry.count as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn expired_splits_free_their_parts_and_ignore_late_ones() {
+        let ttl = Duration::from_secs(5);
+        let mut assembler = SplitAssembler::new(ttl, 128, 256, usize::MAX);
+        let start = Instant::now();
+
+        for index in 0..9 {
+            assert!(matches!(
+                assembler.add(make_split_encap(10, index), start),
+                Ok(None)
+            ));
+        }
+        assert_eq!(assembler.buffered_bytes(), 36);
+        assert_eq!(assembler.next_expiry(), Some(start + ttl));
+
+        assert!(
+            assembler
+                .prune(start + ttl - Duration::from_millis(1))
+                .is_empty()
+        );
+        let dropped = assembler.prune(start + ttl);
+        assert_eq!(dropped, [(Some(0), Some(Sequence24::new(0)))]);
+        assert_eq!(assembler.buffered_bytes(), 0);
+        assert_eq!(assembler.next_expiry(), None);
+
+        // The last part turning up late starts nothing.
+        let late = start + ttl + Duration::from_secs(1);
+        assert!(matches!(
+            assembler.add(make_split_encap(10, 9), late),
+            Ok(None)
+        ));
+        assert_eq!(assembler.buffered_bytes(), 0);
+
+        // Once the id has been forgotten it can be used again.
+        let reused = start + ttl * 2;
+        assembler.prune(reused);
+        assert!(matches!(
+            assembler.add(make_split_encap(1, 0), reused),
+            Ok(Some(_))
+        ));
+    }
 }
@@ -0,0 +1,449 @@
+//! Lock-free statistics counters shared between a session and its handles.
+//!
+//! The muxer task owns the `ManagedSession` and updates these counters with
+//! relaxed atomics on the hot path; application threads read them through
+//! `snapshot()` without ever round-tripping through the muxer.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::protocol::reliability::Reliability;
+use crate::protocol::state::DisconnectReason;
+use crate::session::manager::{ConnectionState, SendQueueLimit};
+
+/// Per-session counters, updated by the muxer and readable from any thread.
+#[derive(Debug, Default)]
+pub struct SharedStats {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    datagrams_sent: AtomicU64,
+    datagrams_received: AtomicU64,
+    datagrams_resent: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    acks_received: AtomicU64,
+    naks_received: AtomicU64,
+    packets_out_of_state: AtomicU64,
+    datagrams_undecodable: AtomicU64,
+    rtt_micros: AtomicU64,
+    rto_micros: AtomicU64,
+    outgoing_queue_len: AtomicU64,
+    reorder_frames: AtomicU64,
+    unacked_datagrams: AtomicU64,
+    resend_bytes: AtomicU64,
+    reorder_bytes: AtomicU64,
+    split_reassembly_bytes: AtomicU64,
+    outgoing_queue_bytes: AtomicU64,
+    incoming_channel_bytes: AtomicU64,
+    duplicate_datagrams: AtomicU64,
+    duplicate_frames: AtomicU64,
+    messages_expired: AtomicU64,
+    splits_expired: AtomicU64,
+    /// Set once the muxer has dropped the session.
+    closed: AtomicBool,
+    /// Set once a message is turned away under the session's
+    /// `send_queue_limit`, here or by the muxer, until something leaves the
+    /// queue.
+    send_queue_full: AtomicBool,
+    /// A `Reject` send queue limit and the session's MTU, which `admit`
+    /// checks sends against before they reach the muxer.
+    send_limit: OnceLock<(SendQueueLimit, usize)>,
+    /// Frames and bytes `admit` let through that the muxer hasn't queued yet.
+    admitted_frames: AtomicU64,
+    admitted_bytes: AtomicU64,
+    /// The session's state as of the muxer's last pass over it.
+    state: watch::Sender<ConnectionState>,
+    /// Why the session ended, published once when the muxer drops it.
+    ended: watch::Sender<Option<DisconnectReason>>,
+    #[cfg(any(test, feature = "debug-log"))]
+    debug_log: Option<super::debug_log::DebugLog>,
+}
+
+/// Point-in-time copy of a session's `SharedStats`.
+///
+/// Individual fields are read independently, so the snapshot is coherent
+/// enough for display and metrics but not a transactional view.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub datagrams_sent: u64,
+    pub datagrams_received: u64,
+    pub datagrams_resent: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub acks_received: u64,
+    pub naks_received: u64,
+    /// Packets dropped for arriving in the wrong connection state, e.g. game
+    /// data before the handshake finished.
+    pub packets_out_of_state: u64,
+    /// Datagrams from the peer that failed to decode and were dropped.
+    pub datagrams_undecodable: u64,
+    /// Smoothed round-trip time, `None` until the first ACK is sampled.
+    pub rtt: Option<Duration>,
+    /// Current retransmission timeout, derived from the RTT and its
+    /// variation.
+    pub rto: Duration,
+    /// Frames queued but not yet packed into a datagram.
+    pub outgoing_queue_len: u64,
+    /// Reliable datagrams sent but not yet acknowledged.
+    pub unacked_datagrams: u64,
+    /// Ordered messages held back waiting for an earlier one, a measure of
+    /// how much reordering (or loss) the session is riding out.
+    pub reorder_frames: u64,
+    /// Data datagrams received more than once under the same sequence
+    /// number: network duplication, or our ACKs not reaching the peer.
+    pub duplicate_datagrams: u64,
+    /// Reliable frames received more than once inside otherwise new
+    /// datagrams: the peer retransmitting under fresh sequence numbers.
+    pub duplicate_frames: u64,
+    /// Messages dropped unsent because their `Message::ttl` ran out.
+    pub messages_expired: u64,
+    /// Split messages given up on because their missing parts never came.
+    pub splits_expired: u64,
+}
+
+/// Bytes buffered on behalf of a session, broken down by where they sit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Reliable datagrams kept around until ACKed, in case they need resending.
+    pub resend_bytes: usize,
+    /// Ordered packets held back waiting for an earlier index.
+    pub reorder_bytes: usize,
+    /// Parts of split packets that are not fully reassembled yet.
+    pub split_reassembly_bytes: usize,
+    /// Frames queued for sending but not yet packed into a datagram.
+    pub outgoing_queue_bytes: usize,
+    /// Messages delivered by the muxer but not yet read by the application.
+    pub incoming_channel_bytes: usize,
+}
+
+impl MemoryUsage {
+    /// Sum of every category.
+    pub fn total(&self) -> usize {
+        self.resend_bytes
+            + self.reorder_bytes
+            + self.split_reassembly_bytes
+            + self.outgoing_queue_bytes
+            + self.incoming_channel_bytes
+    }
+}
+
+impl std::ops::AddAssign for MemoryUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.resend_bytes += rhs.resend_bytes;
+        self.reorder_bytes += rhs.reorder_bytes;
+        self.split_reassembly_bytes += rhs.split_reassembly_bytes;
+        self.outgoing_queue_bytes += rhs.outgoing_queue_bytes;
+        self.incoming_channel_bytes += rhs.incoming_channel_bytes;
+    }
+}
+
+impl SharedStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counters plus a `DebugLog` holding the last `capacity` events.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn with_debug_log(capacity: usize) -> Self {
+        Self {
+            debug_log: Some(super::debug_log::DebugLog::new(capacity)),
+            ..Self::default()
+        }
+    }
+
+    /// The session's debug log, if it was created with one.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn debug_log(&self) -> Option<&super::debug_log::DebugLog> {
+        self.debug_log.as_ref()
+    }
+
+    pub(crate) fn record_datagram_sent(&self, bytes: usize) {
+        self.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_datagram_received(&self, bytes: usize) {
+        self.datagrams_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_datagram_resent(&self) {
+        self.datagrams_resent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_messages_received(&self, count: usize) {
+        self.messages_received
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_ack_received(&self) {
+        self.acks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_nak_received(&self) {
+        self.naks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_out_of_state_packet(&self) {
+        self.packets_out_of_state.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_undecodable_datagram(&self) {
+        self.datagrams_undecodable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_rtt(&self, rtt: Option<Duration>) {
+        // 0 doubles as "not yet measured"; a genuine sub-microsecond RTT is
+        // rounded up so it stays distinguishable.
+        let micros = rtt.map(|d| (d.as_micros() as u64).max(1)).unwrap_or(0);
+        self.rtt_micros.store(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_rto(&self, rto: Duration) {
+        self.rto_micros
+            .store(rto.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_queue_depths(&self, outgoing: usize, unacked: usize, reorder: usize) {
+        self.outgoing_queue_len
+            .store(outgoing as u64, Ordering::Relaxed);
+        self.unacked_datagrams
+            .store(unacked as u64, Ordering::Relaxed);
+        self.reorder_frames.store(reorder as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_duplicates(&self, datagrams: u64, frames: u64) {
+        self.duplicate_datagrams.store(datagrams, Ordering::Relaxed);
+        self.duplicate_frames.store(frames, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_messages_expired(&self, count: u64) {
+        self.messages_expired.store(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_splits_expired(&self, count: u64) {
+        self.splits_expired.store(count, Ordering::Relaxed);
+    }
+
+    /// Publish the session-owned part of `MemoryUsage`.
+    ///
+    /// `incoming_channel_bytes` is maintained separately by the transport,
+    /// since it changes on the application side of the channel.
+    pub(crate) fn set_memory_usage(&self, usage: &MemoryUsage) {
+        self.resend_bytes
+            .store(usage.resend_bytes as u64, Ordering::Relaxed);
+        self.reorder_bytes
+            .store(usage.reorder_bytes as u64, Ordering::Relaxed);
+        self.split_reassembly_bytes
+            .store(usage.split_reassembly_bytes as u64, Ordering::Relaxed);
+        self.outgoing_queue_bytes
+            .store(usage.outgoing_queue_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_incoming_channel_bytes(&self, bytes: usize) {
+        self.incoming_channel_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub_incoming_channel_bytes(&self, bytes: usize) {
+        // Saturate rather than wrap if a read races ahead of the matching add.
+        let _ =
+            self.incoming_channel_bytes
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                    Some(v.saturating_sub(bytes as u64))
+                });
+    }
+
+    pub(crate) fn mark_closed(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Whether the muxer has dropped the session; nothing sent to it from
+    /// here on goes anywhere.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_send_queue_full(&self, full: bool) {
+        self.send_queue_full.store(full, Ordering::Relaxed);
+    }
+
+    /// Whether the session's outgoing queue turned a message away and has
+    /// not drained since.
+    pub(crate) fn is_send_queue_full(&self) -> bool {
+        self.send_queue_full.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_send_limit(&self, limit: SendQueueLimit, mtu: usize) {
+        let _ = self.send_limit.set((limit, mtu));
+    }
+
+    /// Reserve room in the outgoing queue for a `len`-byte message, counting
+    /// what is queued and what other sends reserved on the way to the
+    /// muxer. `false` if it doesn't fit; the muxer would drop it. Without a
+    /// `Reject` limit everything fits.
+    pub(crate) fn admit(&self, len: usize, reliability: Reliability) -> bool {
+        let Some(&(limit, mtu)) = self.send_limit.get() else {
+            return true;
+        };
+        let (frames, bytes) = super::outbound::queue_cost(mtu, len, reliability);
+        let admitted_frames = self
+            .admitted_frames
+            .fetch_add(frames as u64, Ordering::Relaxed)
+            + frames as u64;
+        let admitted_bytes = self
+            .admitted_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed)
+            + bytes as u64;
+        let queued_frames = self.outgoing_queue_len.load(Ordering::Relaxed);
+        let queued_bytes = self.outgoing_queue_bytes.load(Ordering::Relaxed);
+        if queued_frames + admitted_frames <= limit.max_frames as u64
+            && queued_bytes + admitted_bytes <= limit.max_bytes as u64
+        {
+            return true;
+        }
+        self.release(len, reliability);
+        self.set_send_queue_full(true);
+        false
+    }
+
+    /// Hand back what `admit` reserved for a message once the muxer has
+    /// queued it, or turned it away after all.
+    pub(crate) fn release(&self, len: usize, reliability: Reliability) {
+        let Some(&(_, mtu)) = self.send_limit.get() else {
+            return;
+        };
+        let (frames, bytes) = super::outbound::queue_cost(mtu, len, reliability);
+        self.admitted_frames
+            .fetch_sub(frames as u64, Ordering::Relaxed);
+        self.admitted_bytes
+            .fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+
+    /// The session's state as last published by the muxer.
+    pub(crate) fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Record why the session ended. Only the first reason sticks.
+    pub(crate) fn mark_ended(&self, reason: DisconnectReason) {
+        self.ended.send_if_modified(|ended| match ended {
+            Some(_) => false,
+            None => {
+                *ended = Some(reason);
+                true
+            }
+        });
+    }
+
+    /// Resolves with the reason once the session has ended.
+    pub(crate) async fn ended(&self) -> DisconnectReason {
+        let mut rx = self.ended.subscribe();
+        loop {
+            if let Some(reason) = *rx.borrow_and_update() {
+                return reason;
+            }
+            // `self` holds the sender, so this only returns on a change.
+            let _ = rx.changed().await;
+        }
+    }
+
+    /// Read the memory gauges into a `MemoryUsage`.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            resend_bytes: self.resend_bytes.load(Ordering::Relaxed) as usize,
+            reorder_bytes: self.reorder_bytes.load(Ordering::Relaxed) as usize,
+            split_reassembly_bytes: self.split_reassembly_bytes.load(Ordering::Relaxed) as usize,
+            outgoing_queue_bytes: self.outgoing_queue_bytes.load(Ordering::Relaxed) as usize,
+            incoming_channel_bytes: self.incoming_channel_bytes.load(Ordering::Relaxed) as usize,
+        }
+    }
+
+    /// Read every counter into a plain `StatsSnapshot`.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let rtt_micros = self.rtt_micros.load(Ordering::Relaxed);
+        StatsSnapshot {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            datagrams_sent: self.datagrams_sent.load(Ordering::Relaxed),
+            datagrams_received: self.datagrams_received.load(Ordering::Relaxed),
+            datagrams_resent: self.datagrams_resent.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            acks_received: self.acks_received.load(Ordering::Relaxed),
+            naks_received: self.naks_received.load(Ordering::Relaxed),
+            packets_out_of_state: self.packets_out_of_state.load(Ordering::Relaxed),
+            datagrams_undecodable: self.datagrams_undecodable.load(Ordering::Relaxed),
+            rtt: (rtt_micros != 0).then(|| Duration::from_micros(rtt_micros)),
+            rto: Duration::from_micros(self.rto_micros.load(Ordering::Relaxed)),
+            outgoing_queue_len: self.outgoing_queue_len.load(Ordering::Relaxed),
+            unacked_datagrams: self.unacked_datagrams.load(Ordering::Relaxed),
+            reorder_frames: self.reorder_frames.load(Ordering::Relaxed),
+            duplicate_datagrams: self.duplicate_datagrams.load(Ordering::Relaxed),
+            duplicate_frames: self.duplicate_frames.load(Ordering::Relaxed),
+            messages_expired: self.messages_expired.load(Ordering::Relaxed),
+            splits_expired: self.splits_expired.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn snapshot_reflects_updates_from_other_threads() {
+        let stats = Arc::new(SharedStats::new());
+        let writer = stats.clone();
+
+        std::thread::spawn(move || {
+            for _ in 0..100 {
+                writer.record_datagram_sent(10);
+            }
+            writer.set_rtt(Some(Duration::from_millis(25)));
+        })
+        .join()
+        .unwrap();
+
+        let snap = stats.snapshot();
+        assert_eq!(snap.datagrams_sent, 100);
+        assert_eq!(snap.bytes_sent, 1000);
+        assert_eq!(snap.rtt, Some(Duration::from_millis(25)));
+    }
+
+    #[test]
+    fn incoming_channel_bytes_never_underflow() {
+        let stats = SharedStats::new();
+        stats.add_incoming_channel_bytes(10);
+        stats.sub_incoming_channel_bytes(25);
+        assert_eq!(stats.memory_usage().incoming_channel_bytes, 0);
+    }
+
+    #[test]
+    fn rtt_is_none_until_measured() {
+        let stats = SharedStats::new();
+        assert_eq!(stats.snapshot().rtt, None);
+
+        stats.set_rtt(Some(Duration::ZERO));
+        assert!(stats.snapshot().rtt.is_some());
+    }
+}
@@ -5,16 +5,26 @@ use crate::protocol::datagram::Datagram;
 use super::Session;
 
 impl Session {
-    /// Periodic maintenance: prune splits, schedule resends, and emit ACK/NACK datagrams.
+    /// Periodic maintenance: prune splits, expire receipts, schedule resends,
+    /// and emit ACK/NACK datagrams.
+    ///
+    /// Ordered packets that waited behind a split given up on here are
+    /// handed out by the next `deliver_released_with`.
     pub fn on_tick(&mut self, now: Instant) -> Vec<Datagram> {
         let mut out = Vec::new();
 
         self.process_incoming_acks_naks(now);
+        out.extend(self.retransmits.drain(..));
+        self.receipts.expire(now);
 
         let dropped = self.split_assembler.prune(now);
+        self.expired_splits += dropped.len() as u64;
         for (ch, idx) in dropped {
             if let (Some(ch), Some(idx)) = (ch, idx) {
                 self.ordering.skip_index(ch, idx);
+                while let Some(ready) = self.ordering.pop_ready(ch) {
+                    self.released.push(ready);
+                }
             }
         }
 
@@ -24,7 +34,9 @@ impl Session {
             self.resend_datagrams(to_resend, now, &mut out);
         }
 
-        if let Some(d) = self.build_ack_datagram(now) {
+        if self.ack_deadline(now).is_some_and(|at| at <= now)
+            && let Some(d) = self.build_ack_datagram(now)
+        {
             out.push(d);
         }
 
@@ -33,14 +45,46 @@ impl Session {
         }
         out
     }
+
+    /// Earliest instant at which `on_tick` has work to do, if any.
+    ///
+    /// Returns `now` while NACK work or window-blocked frames are pending;
+    /// queued ACKs count from when their `AckPolicy` makes them due.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        if !self.incoming_acks.is_empty()
+            || !self.incoming_naks.is_empty()
+            || !self.outgoing_naks.is_empty()
+            || !self.outgoing_heap.is_empty()
+            || !self.retransmits.is_empty()
+        {
+            return Some(now);
+        }
+
+        let resend = self.sent_datagrams.values().map(|t| t.next_send).min();
+        [
+            resend,
+            self.ack_deadline(now),
+            self.split_assembler.next_expiry(),
+            self.receipts.next_expiry(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::protocol::{constants::DatagramFlags, datagram::DatagramPayload, types::Sequence24};
+    use crate::protocol::{
+        ack::AckNackPayload,
+        constants::{self, DatagramFlags},
+        datagram::DatagramPayload,
+        types::Sequence24,
+    };
 
     use super::*;
-    use std::time::Instant;
+    use crate::session::{AckPolicy, SessionTunables};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn ack_and_nak_tick_match_expected_ranges() {
@@ -86,4 +130,117 @@ mod tests {
             panic!("expected nak payload");
         }
     }
+
+    #[test]
+    fn idle_session_has_no_deadline() {
+        let mut s = Session::new(1200);
+        let now = Instant::now();
+        assert_eq!(s.next_deadline(now), None);
+
+        s.process_datagram_sequence(Sequence24::new(0));
+        assert_eq!(s.next_deadline(now), Some(now));
+
+        s.on_tick(now);
+        assert_eq!(s.next_deadline(now), None);
+    }
+
+    /// Datagrams covered by `payload`.
+    fn covered(payload: &AckNackPayload) -> u32 {
+        payload
+            .ranges
+            .iter()
+            .map(|r| r.start.distance_to(r.end) + 1)
+            .sum()
+    }
+
+    #[test]
+    fn a_shuffled_burst_is_acked_as_one_range() {
+        let mut s = Session::new(1200);
+        let now = Instant::now();
+        for seq in (0..60).step_by(2).chain((1..60).step_by(2)) {
+            s.process_datagram_sequence(Sequence24::new(seq));
+        }
+
+        let ack = s.build_ack_datagram(now).expect("ack datagram");
+        let DatagramPayload::Ack(payload) = &ack.payload else {
+            panic!("expected ack payload");
+        };
+        assert_eq!(payload.ranges.len(), 1);
+        assert_eq!(covered(payload), 60);
+        assert!(s.build_ack_datagram(now).is_none());
+    }
+
+    #[test]
+    fn scattered_acks_and_naks_are_split_to_fit_the_mtu() {
+        let mtu = 576;
+        let mut s = Session::new(mtu);
+        let now = Instant::now();
+        // Every other datagram: 1000 lone ACK ranges and 999 NAK ranges.
+        for seq in (0..2000).step_by(2) {
+            s.process_datagram_sequence(Sequence24::new(seq));
+        }
+
+        let fits = |d: &Datagram| {
+            let mut buf = bytes::BytesMut::new();
+            d.encode(&mut buf).unwrap();
+            buf.len() + constants::IPV4_HEADER_SIZE + constants::UDP_HEADER_SIZE <= mtu
+        };
+        let (mut acked, mut acks) = (0, 0);
+        while let Some(d) = s.build_ack_datagram(now) {
+            assert!(fits(&d));
+            let DatagramPayload::Ack(payload) = &d.payload else {
+                panic!("expected ack payload");
+            };
+            acked += covered(payload);
+            acks += 1;
+        }
+        let (mut naked, mut naks) = (0, 0);
+        while let Some(d) = s.build_nak_datagram() {
+            assert!(fits(&d));
+            let DatagramPayload::Nak(payload) = &d.payload else {
+                panic!("expected nak payload");
+            };
+            naked += covered(payload);
+            naks += 1;
+        }
+        assert_eq!((acked, naked), (1000, 999));
+        assert!(acks > 1 && naks > 1, "{acks} acks, {naks} naks");
+    }
+
+    #[test]
+    fn delayed_acks_wait_unless_they_fill_a_gap() {
+        let delay = Duration::from_millis(30);
+        let mut s = Session::with_tunables(
+            1200,
+            SessionTunables {
+                ack_policy: AckPolicy::Delayed(delay),
+                ..Default::default()
+            },
+        );
+        let now = Instant::now();
+
+        s.acknowledge(Sequence24::new(0), now);
+        s.acknowledge(Sequence24::new(2), now);
+        assert_eq!(s.ack_deadline(now), Some(now + delay));
+        let out = s.on_tick(now);
+        assert!(
+            out.iter()
+                .all(|d| !d.header.flags.contains(DatagramFlags::ACK))
+        );
+        assert!(
+            out.iter()
+                .any(|d| d.header.flags.contains(DatagramFlags::NACK))
+        );
+
+        // The NAKed datagram turns up: everything goes out at once.
+        let later = now + Duration::from_millis(5);
+        s.acknowledge(Sequence24::new(1), later);
+        assert_eq!(s.ack_deadline(later), Some(later));
+        let ack = s.build_ack_datagram(later).expect("ack datagram");
+        let DatagramPayload::Ack(payload) = &ack.payload else {
+            panic!("expected ack payload");
+        };
+        assert_eq!(covered(payload), 3);
+        assert_eq!(s.ack_deadline(later), None);
+    }
 }
@@ -0,0 +1,391 @@
+//! Raw datagram capture.
+//!
+//! A `Capture` set on `RaknetListenerConfig::capture` or
+//! `RaknetStreamConfig::capture` sees every datagram the socket sends or
+//! receives: inbound before any decoding, outbound after encoding. The
+//! callback gets a borrowed view of the bytes, so nothing is copied unless it
+//! chooses to; without a capture the cost is one `Option` check per datagram.
+//!
+//! `Capture::pcap` writes the taps to a pcap file, wrapping each datagram in
+//! a synthetic IP/UDP header so Wireshark's RakNet dissector can open it.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::socket::DatagramSocket;
+
+/// Which way a captured datagram was travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// One datagram as seen on the socket.
+#[derive(Debug, Clone, Copy)]
+pub struct CapturedDatagram<'a> {
+    pub direction: Direction,
+    /// Our side of the exchange.
+    pub local: SocketAddr,
+    /// The remote side of the exchange.
+    pub peer: SocketAddr,
+    pub timestamp: SystemTime,
+    pub bytes: &'a [u8],
+}
+
+impl CapturedDatagram<'_> {
+    /// Source address as it would appear on the wire.
+    pub fn source(&self) -> SocketAddr {
+        match self.direction {
+            Direction::Inbound => self.peer,
+            Direction::Outbound => self.local,
+        }
+    }
+
+    /// Destination address as it would appear on the wire.
+    pub fn destination(&self) -> SocketAddr {
+        match self.direction {
+            Direction::Inbound => self.local,
+            Direction::Outbound => self.peer,
+        }
+    }
+}
+
+type CaptureFn = dyn Fn(&CapturedDatagram<'_>) + Send + Sync;
+
+/// Callback invoked for every raw datagram.
+///
+/// Runs inline on the muxer task, so it should be quick; hand the bytes off
+/// to a channel if the work is heavy.
+#[derive(Clone)]
+pub struct Capture(Arc<CaptureFn>);
+
+impl Capture {
+    pub fn new(f: impl Fn(&CapturedDatagram<'_>) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Write every datagram to `out` in pcap format.
+    ///
+    /// The file header is written immediately. Write errors after that are
+    /// logged and the datagram is skipped. `out` is written as given: wrap it
+    /// in a `BufWriter` for throughput (flushed once the capture is dropped),
+    /// or pass a `File` directly to have records land as they happen.
+    pub fn pcap<W: Write + Send + 'static>(out: W) -> io::Result<Self> {
+        let writer = Mutex::new(PcapWriter::new(out)?);
+        Ok(Self::new(move |dgram| {
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writer.write(dgram) {
+                tracing::warn!(error = %e, "failed to write pcap record");
+            }
+        }))
+    }
+
+    fn record(&self, dgram: &CapturedDatagram<'_>) {
+        (self.0)(dgram)
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Capture(..)")
+    }
+}
+
+/// `LINKTYPE_RAW`: each record starts with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+/// Writes captured datagrams as a classic (microsecond) pcap stream.
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Start a capture file on `out` by writing the global header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(Self { out })
+    }
+
+    /// Append one datagram behind a synthetic IP/UDP header.
+    ///
+    /// If the two addresses are of different families (an IPv4 peer on a
+    /// dual-stack socket), both are written as IPv6.
+    pub fn write(&mut self, dgram: &CapturedDatagram<'_>) -> io::Result<()> {
+        let packet = ip_udp_packet(dgram.source(), dgram.destination(), dgram.bytes);
+        let ts = dgram
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(ts.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&ts.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.out.write_all(&record)
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(UDP_HEADER_LEN + payload.len());
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&0u16.to_be_bytes()); // checksum: none
+    udp.extend_from_slice(payload);
+
+    let mut out;
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            out = Vec::with_capacity(IPV4_HEADER_LEN + udp.len());
+            let total = (IPV4_HEADER_LEN + udp.len()) as u16;
+            out.extend_from_slice(&[0x45, 0]);
+            out.extend_from_slice(&total.to_be_bytes());
+            out.extend_from_slice(&[0, 0, 0x40, 0]); // id, don't fragment
+            out.extend_from_slice(&[64, IPPROTO_UDP, 0, 0]); // ttl, proto, checksum
+            out.extend_from_slice(&s.octets());
+            out.extend_from_slice(&d.octets());
+            let sum = ipv4_checksum(&out);
+            out[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (s, d) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            out = Vec::with_capacity(IPV6_HEADER_LEN + udp.len());
+            out.extend_from_slice(&[0x60, 0, 0, 0]);
+            out.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+            out.extend_from_slice(&[IPPROTO_UDP, 64]); // next header, hop limit
+            out.extend_from_slice(&to_v6(s).octets());
+            out.extend_from_slice(&to_v6(d).octets());
+        }
+    }
+    out.extend_from_slice(&udp);
+    out
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A socket that reports its traffic to an optional `Capture`.
+pub(crate) struct Tapped<S> {
+    inner: S,
+    local: SocketAddr,
+    capture: Option<Capture>,
+}
+
+impl<S: DatagramSocket> Tapped<S> {
+    pub fn new(inner: S, capture: Option<Capture>) -> io::Result<Self> {
+        let local = inner.local_addr()?;
+        Ok(Self {
+            inner,
+            local,
+            capture,
+        })
+    }
+
+    fn tap(&self, direction: Direction, peer: SocketAddr, bytes: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.record(&CapturedDatagram {
+                direction,
+                local: self.local,
+                peer,
+                timestamp: SystemTime::now(),
+                bytes,
+            });
+        }
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for Tapped<S> {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let sent = self.inner.send_to(buf, target).await?;
+        self.tap(Direction::Outbound, target, &buf[..sent]);
+        Ok(sent)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, peer) = self.inner.recv_from(buf).await?;
+        self.tap(Direction::Inbound, peer, &buf[..len.min(buf.len())]);
+        Ok((len, peer))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.inner.local_addrs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn dgram(src: &str, dst: &str, bytes: &'static [u8]) -> CapturedDatagram<'static> {
+        CapturedDatagram {
+            direction: Direction::Outbound,
+            local: src.parse().unwrap(),
+            peer: dst.parse().unwrap(),
+            timestamp: UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_000),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn pcap_has_global_header_and_ipv4_udp_records() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write(&dgram("10.0.0.2:40000", "10.0.0.1:19132", &[0x05, 0xaa]))
+            .unwrap();
+        let out = writer.into_inner().unwrap();
+
+        assert_eq!(&out[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&out[20..24], &LINKTYPE_RAW.to_le_bytes());
+
+        let record = &out[24..];
+        assert_eq!(&record[0..4], &1_700_000_000u32.to_le_bytes());
+        assert_eq!(&record[4..8], &123_456u32.to_le_bytes());
+        let len = IPV4_HEADER_LEN + UDP_HEADER_LEN + 2;
+        assert_eq!(&record[8..12], &(len as u32).to_le_bytes());
+        assert_eq!(&record[12..16], &(len as u32).to_le_bytes());
+
+        let ip = &record[16..];
+        assert_eq!(ip.len(), len);
+        assert_eq!(ip[0], 0x45);
+        assert_eq!(ip[9], IPPROTO_UDP);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&ip[16..20], &[10, 0, 0, 1]);
+        // A valid header checksums to zero.
+        assert_eq!(ipv4_checksum(&ip[..IPV4_HEADER_LEN]), 0);
+
+        let udp = &ip[IPV4_HEADER_LEN..];
+        assert_eq!(&udp[0..2], &40000u16.to_be_bytes());
+        assert_eq!(&udp[2..4], &19132u16.to_be_bytes());
+        assert_eq!(&udp[4..6], &10u16.to_be_bytes());
+        assert_eq!(&udp[8..], &[0x05, 0xaa]);
+    }
+
+    #[test]
+    fn mixed_families_are_written_as_ipv6() {
+        let packet = ip_udp_packet(
+            "[::1]:19132".parse().unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            &[0x1c],
+        );
+        assert_eq!(packet.len(), IPV6_HEADER_LEN + UDP_HEADER_LEN + 1);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[4..6], &9u16.to_be_bytes());
+        assert_eq!(packet[6], IPPROTO_UDP);
+        assert_eq!(
+            &packet[24..40],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn inbound_records_swap_source_and_destination() {
+        let mut d = dgram("10.0.0.1:19132", "10.0.0.2:40000", &[]);
+        d.direction = Direction::Inbound;
+        assert_eq!(d.source(), "10.0.0.2:40000".parse().unwrap());
+        assert_eq!(d.destination(), "10.0.0.1:19132".parse().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn listener_and_client_see_the_raw_handshake() {
+        use crate::transport::memory::MemoryNetwork;
+        use crate::transport::{RaknetListener, RaknetListenerConfig};
+        use crate::transport::{RaknetStream, RaknetStreamConfig};
+
+        type Log = Arc<Mutex<Vec<(Direction, SocketAddr, u8)>>>;
+        fn logging(log: &Log) -> Option<Capture> {
+            let log = log.clone();
+            Some(Capture::new(move |d| {
+                log.lock().unwrap().push((d.direction, d.peer, d.bytes[0]));
+            }))
+        }
+
+        let server_log = Log::default();
+        let client_log = Log::default();
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind("10.0.0.1:19132".parse().unwrap()).unwrap(),
+            RaknetListenerConfig {
+                capture: logging(&server_log),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client_socket = net.bind_any().unwrap();
+        let client_addr = client_socket.local_addr().unwrap();
+        let (client, server) = tokio::join!(
+            RaknetStream::connect_on(
+                client_socket,
+                listener.local_addr(),
+                RaknetStreamConfig {
+                    capture: logging(&client_log),
+                    ..Default::default()
+                }
+            ),
+            listener.accept()
+        );
+        let (_client, _server) = (client.unwrap(), server.unwrap());
+
+        let server_log = server_log.lock().unwrap();
+        assert_eq!(server_log[0], (Direction::Inbound, client_addr, 0x05));
+        assert_eq!(server_log[1], (Direction::Outbound, client_addr, 0x06));
+        assert!(
+            server_log
+                .iter()
+                .any(|e| e.0 == Direction::Inbound && e.2 == 0x07)
+        );
+        assert!(
+            server_log
+                .iter()
+                .any(|e| e.0 == Direction::Outbound && e.2 == 0x08)
+        );
+
+        let client_log = client_log.lock().unwrap();
+        let server_addr = listener.local_addr();
+        assert_eq!(client_log[0], (Direction::Outbound, server_addr, 0x05));
+        assert_eq!(client_log[1], (Direction::Inbound, server_addr, 0x06));
+        // Connected traffic is captured too, before any decoding.
+        assert!(client_log.iter().any(|e| e.2 & 0x80 != 0));
+    }
+}
@@ -0,0 +1,150 @@
+//! A client socket that can be used before connecting.
+//!
+//! `RaknetStream::connect` binds a fresh socket and starts the handshake
+//! right away. NAT traversal needs the socket first: both peers send each
+//! other a few datagrams (usually addresses learned from a rendezvous
+//! server) so their NATs let the handshake through, and the handshake then
+//! has to go out on the same socket to reuse the mapping. `RaknetClient`
+//! holds that socket until `connect` (or `listen`, for the side that
+//! accepts) takes it over.
+
+use std::io;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+
+use crate::protocol::constants::MAXIMUM_MTU_SIZE;
+
+use super::socket::DatagramSocket;
+use super::{RaknetListener, RaknetListenerConfig, RaknetStream, RaknetStreamConfig};
+
+/// A bound socket that sends and receives raw unconnected datagrams until it
+/// is turned into a connection.
+pub struct RaknetClient<S = UdpSocket> {
+    socket: S,
+}
+
+impl RaknetClient<UdpSocket> {
+    /// Bind a UDP socket at `addr`.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::with_socket(UdpSocket::bind(addr).await?))
+    }
+}
+
+impl<S: DatagramSocket> RaknetClient<S> {
+    /// Use an already bound socket.
+    pub fn with_socket(socket: S) -> Self {
+        Self { socket }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Send `bytes` to `addr` as one datagram, exactly as given.
+    pub async fn send_unconnected(&self, addr: SocketAddr, bytes: &[u8]) -> io::Result<()> {
+        self.socket.send_to(bytes, addr).await?;
+        Ok(())
+    }
+
+    /// Receive the next datagram from anyone, with its sender.
+    ///
+    /// Cancel-safe, so it can be raced against a timer or `send_unconnected`.
+    pub async fn recv_unconnected(&self) -> io::Result<(Bytes, SocketAddr)> {
+        let mut buf = vec![0u8; MAXIMUM_MTU_SIZE as usize];
+        let (len, from) = self.socket.recv_from(&mut buf).await?;
+        buf.truncate(len);
+        Ok((Bytes::from(buf), from))
+    }
+
+    /// Connect to `server` over this socket with the default configuration.
+    pub async fn connect(self, server: SocketAddr) -> Result<RaknetStream, crate::RaknetError> {
+        self.connect_with_config(server, RaknetStreamConfig::default())
+            .await
+    }
+
+    /// Connect to `server` over this socket. Unconnected datagrams still
+    /// queued on it are ignored by the handshake.
+    pub async fn connect_with_config(
+        self,
+        server: SocketAddr,
+        config: RaknetStreamConfig,
+    ) -> Result<RaknetStream, crate::RaknetError> {
+        RaknetStream::connect_on(self.socket, server, config).await
+    }
+
+    /// Accept connections on this socket instead, e.g. on the side of a
+    /// punched-through pair that waits for the other to connect.
+    pub fn listen(self, config: RaknetListenerConfig) -> io::Result<RaknetListener> {
+        RaknetListener::with_socket(self.socket, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory::MemoryNetwork;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn punches_through_two_nats_and_connects() {
+        let net = MemoryNetwork::new();
+        net.add_nat(
+            "192.168.1.1".parse().unwrap(),
+            "203.0.113.1".parse().unwrap(),
+        );
+        net.add_nat(
+            "192.168.2.1".parse().unwrap(),
+            "203.0.113.2".parse().unwrap(),
+        );
+        let rendezvous = net.bind("198.51.100.1:7000".parse().unwrap()).unwrap();
+        let a = RaknetClient::with_socket(net.bind("192.168.1.1:0".parse().unwrap()).unwrap());
+        let b = RaknetClient::with_socket(net.bind("192.168.2.1:0".parse().unwrap()).unwrap());
+
+        // Each side learns the other's public address from the rendezvous.
+        let rv = rendezvous.local_addr().unwrap();
+        a.send_unconnected(rv, b"hello").await.unwrap();
+        let mut buf = [0u8; 16];
+        let (_, a_public) = rendezvous.recv_from(&mut buf).await.unwrap();
+        b.send_unconnected(rv, b"hello").await.unwrap();
+        let (_, b_public) = rendezvous.recv_from(&mut buf).await.unwrap();
+        assert_eq!(a_public.ip().to_string(), "203.0.113.1");
+
+        // The first datagram hits a closed NAT; once both have sent, both
+        // directions are open.
+        a.send_unconnected(b_public, b"punch").await.unwrap();
+        b.send_unconnected(a_public, b"punch").await.unwrap();
+        let (bytes, from) = timeout(WAIT, a.recv_unconnected()).await.unwrap().unwrap();
+        assert_eq!((&bytes[..], from), (&b"punch"[..], b_public));
+        assert!(
+            timeout(Duration::from_millis(100), b.recv_unconnected())
+                .await
+                .is_err()
+        );
+
+        let mut listener = b.listen(Default::default()).unwrap();
+        let (client, server) = tokio::join!(a.connect(b_public), listener.accept());
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+        assert_eq!(server.peer_addr(), a_public);
+
+        client.send(&b"\xfeacross"[..]).await.unwrap();
+        let msg = timeout(WAIT, server.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&msg[..], b"\xfeacross");
+        server.send(&b"\xfeback"[..]).await.unwrap();
+        let msg = timeout(WAIT, client.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&msg[..], b"\xfeback");
+    }
+}
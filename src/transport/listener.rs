@@ -1,22 +1,53 @@
+mod advertisement;
+mod builder;
+mod events;
+mod filter;
 mod offline;
 mod online;
+mod peers;
+mod rate_limit;
+mod schedule;
+mod stats;
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::time::Duration;
 
+use bytes::Bytes;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 use crate::protocol::constants::{self, UDP_HEADER_SIZE};
-use crate::transport::listener_conn::SessionState;
-use crate::transport::mux::new_tick_interval;
-use crate::transport::stream::RaknetStream;
-
-use offline::PendingConnection;
-
-use online::{dispatch_datagram, handle_outgoing_msg, tick_sessions};
+use crate::protocol::state::DisconnectReason;
+use crate::protocol::types::RaknetTime;
+use crate::session::manager::{BacklogLimit, DebugLogConfig, SendQueueLimit, SessionConfig};
+use crate::session::stats::{MemoryUsage, StatsSnapshot};
+use crate::session::{AckPolicy, ReorderLimit};
+use crate::transport::capture::{Capture, Tapped};
+use crate::transport::listener_conn::{NewConnection, SessionState};
+use crate::transport::motd::BedrockMotd;
+use crate::transport::mux::{self, RecvBackoff, RecvBuffer, RecvErrorAction, new_tick_interval};
+use crate::transport::socket::{DatagramSocket, DualStackSocket, bind_udp};
+use crate::transport::stream::{RaknetStream, outbound_msg};
+
+use advertisement::SharedAdvertisement;
+use events::Events;
+use filter::SharedFilter;
+use offline::OfflineState;
+
+use online::{
+    dispatch_datagram, drain_sessions, handle_control_msg, handle_outgoing_msgs, tick_sessions,
+};
+use schedule::TickSchedule;
+
+pub use builder::RaknetListenerBuilder;
+pub use events::{ListenerEvent, RejectReason};
+pub use filter::{FilterDecision, PausePolicy, Rejection};
+pub use peers::PeerInfo;
+pub use stats::{ListenerStats, ListenerStatsSnapshot};
 
 /// Configuration for a `RaknetListener`.
 #[derive(Debug, Clone)]
@@ -31,6 +62,14 @@ pub struct RaknetListenerConfig {
     /// Maximum MTU size to support/advertise.
     pub max_mtu: u16,
 
+    /// RakNet protocol version clients must speak; others are refused with
+    /// `IncompatibleProtocolVersion` naming this one.
+    pub protocol_version: u8,
+
+    /// GUID sent to clients in pongs and handshake replies. `None` uses one
+    /// picked at random, shared by every listener in the process.
+    pub server_guid: Option<u64>,
+
     /// Optional socket receive buffer size.
     pub socket_recv_buffer_size: Option<usize>,
 
@@ -43,19 +82,42 @@ pub struct RaknetListenerConfig {
     /// Duration before a session is considered stale.
     pub session_stale: Duration,
 
+    /// How long a session may go without sending before it pings its peer
+    /// to keep the connection alive; `None` sends no automatic pings.
+    pub keepalive_interval: Option<Duration>,
+
+    /// Old name for `keepalive_interval`; a value other than the default
+    /// overrides it.
+    #[deprecated(note = "use `keepalive_interval`")]
+    pub ping_interval: Duration,
+
     /// Maximum bytes of reliable data to queue for a single session before disconnecting.
     pub max_queued_reliable_bytes: usize,
 
-    /// Initial advertisement string.
+    /// Cap on data waiting in a session's outgoing queue, and what to do
+    /// when a send would exceed it; `None` leaves the queue unbounded.
+    pub send_queue_limit: Option<SendQueueLimit>,
+
+    /// Close a session with `QueueTooLong` once its unsent frames plus
+    /// unacked datagrams stay above the limit past its grace period; `None`
+    /// lets a slow client fall behind indefinitely.
+    pub backlog_limit: Option<BacklogLimit>,
+
+    /// Initial advertisement string. The server GUID field of a Bedrock
+    /// (`MCPE;`/`MCEE;`) MOTD is filled in with the listener's GUID in every
+    /// pong.
     pub advertisement: Vec<u8>,
 
+    /// Start with `set_motd_auto_player_count` on.
+    pub motd_auto_player_count: bool,
+
     /// Maximum number of ordering channels.
     pub max_ordering_channels: usize,
 
     /// Maximum capacity of the ACK queue.
     pub ack_queue_capacity: usize,
 
-    /// Timeout for reassembling split packets.
+    /// How long a split packet may go without a new part before it is given up on.
     pub split_timeout: Duration,
 
     /// Maximum window size for reliable packets.
@@ -66,45 +128,228 @@ pub struct RaknetListenerConfig {
 
     /// Maximum number of concurrent split packets being reassembled.
     pub max_concurrent_splits: usize,
+
+    /// Maximum payload bytes buffered across all split packets being reassembled.
+    pub max_split_bytes: usize,
+
+    /// Cap on ordered messages held back per channel waiting for a missing
+    /// one, and what to do when a peer exceeds it.
+    pub reorder_limit: ReorderLimit,
+
+    /// Lower bound on the retransmission timeout computed from the RTT.
+    pub min_rto: Duration,
+
+    /// Upper bound on the retransmission timeout.
+    pub max_rto: Duration,
+
+    /// Times one datagram is resent unACKed before the session times out.
+    pub max_resends: u32,
+
+    /// Resend what a peer NAKs as soon as the NAK arrives.
+    pub fast_retransmit: bool,
+
+    /// When ACKs for received datagrams are sent.
+    pub ack_policy: AckPolicy,
+
+    /// Maximum encapsulated frames accepted in one datagram; more is treated as a bad packet.
+    pub max_frames_per_datagram: usize,
+
+    /// Consecutive undecodable datagrams tolerated before a session is closed as a bad packet.
+    pub max_consecutive_bad_datagrams: u32,
+
+    /// Total undecodable datagrams tolerated over a session's lifetime before it is closed.
+    pub max_bad_datagrams: u32,
+
+    /// What happens to handshakes while `set_accepting(false)` is in effect.
+    pub pause_policy: PausePolicy,
+
+    /// Let a client GUID that is already connected handshake again from a new
+    /// address. The session at the old address is closed and replaced. When
+    /// disabled, such handshakes get `AlreadyConnected`.
+    pub connection_migration: bool,
+
+    /// Refuse handshakes whose `OpenConnectionRequest2` names a server
+    /// address other than ours, replying `ConnectionRequestFailed`. The port
+    /// is always compared; the IP only when bound to a specific address.
+    /// Off by default, since clients of a server behind NAT see its public
+    /// address; mismatches are then only logged.
+    pub strict_server_addr: bool,
+
+    /// Maximum offline packets (pings, handshake requests) answered per second in total.
+    pub max_offline_replies_per_second: u32,
+
+    /// Maximum offline packets answered per second for a single source IP.
+    pub max_offline_replies_per_ip_per_second: u32,
+
+    /// Handshakes a single source IP may start (or fail the cookie check on)
+    /// within `handshake_attempt_window` before it is ignored for
+    /// `handshake_ban_duration`. Completed handshakes don't count.
+    pub max_handshake_attempts_per_ip: u32,
+
+    /// Sliding window over which handshake attempts are counted.
+    pub handshake_attempt_window: Duration,
+
+    /// How long an IP that exceeded `max_handshake_attempts_per_ip` is ignored.
+    pub handshake_ban_duration: Duration,
+
+    /// How long after a session ends its IP is refused with
+    /// `IpRecentlyConnected`, as vanilla RakNet does against reconnect
+    /// storms. Zero disables it, e.g. behind a proxy that legitimately
+    /// reconnects at once.
+    pub ip_recently_connected_window: Duration,
+
+    /// Receives every raw datagram the listener sends or receives.
+    pub capture: Option<Capture>,
+
+    /// Shuts the listener down once cancelled: handshakes stop, every session
+    /// is sent `ShuttingDown`, and `accept` and the accepted streams end with
+    /// `RaknetError::Shutdown`.
+    pub shutdown: Option<CancellationToken>,
+
+    /// How long a shutdown waits for peers to ACK their disconnect notification.
+    pub shutdown_timeout: Duration,
+
+    /// Messages (or batches) the outbound queue into the muxer holds, shared
+    /// by every accepted stream; `send` waits and `try_send` fails while it
+    /// is full. At least 1.
+    pub outbound_queue_capacity: usize,
+
+    /// Established connections queued for `accept`; while it is full the
+    /// listener stalls until one is taken. At least 1.
+    pub accept_backlog: usize,
+
+    /// Each session's debug log (see `dump_debug_log`); off unless
+    /// `debug_log_capacity` turns it on.
+    pub debug_log: DebugLogConfig,
+
+    /// Settings for every accepted session. When set, the per-session fields
+    /// above (timeouts, tunables, queue limit, debug log) are ignored and
+    /// this is used instead, with the role and GUID filled in by the
+    /// listener. Checked by `bind`, which fails with `InvalidInput` if it
+    /// could never work.
+    pub session_config: Option<SessionConfig>,
 }
 
 impl Default for RaknetListenerConfig {
+    #[allow(deprecated)]
     fn default() -> Self {
         Self {
             max_connections: 1024,
             max_pending_connections: 1024,
             max_mtu: 1400,
+            protocol_version: constants::RAKNET_PROTOCOL_VERSION,
+            server_guid: None,
             socket_recv_buffer_size: None,
             socket_send_buffer_size: None,
             session_timeout: Duration::from_secs(10),
             session_stale: Duration::from_secs(5),
+            keepalive_interval: Some(constants::KEEPALIVE_INTERVAL),
+            ping_interval: constants::KEEPALIVE_INTERVAL,
             max_queued_reliable_bytes: 4 * 1024 * 1024, // 4MB
-            advertisement: b"MCPE;Tokio-Raknet Default Advertisement;527;1.19.1;0;10;13253860892328930865;Tokio Raknet;Survival;1;19132;19133".to_vec(),
+            send_queue_limit: None,
+            backlog_limit: Some(BacklogLimit::default()),
+            advertisement: BedrockMotd::default().to_advertisement_bytes(),
+            motd_auto_player_count: false,
             max_ordering_channels: constants::MAXIMUM_ORDERING_CHANNELS as usize,
             ack_queue_capacity: 1024,
-            split_timeout: Duration::from_secs(30),
+            split_timeout: Duration::from_secs(5),
             reliable_window: constants::MAX_ACK_SEQUENCES as u32,
             max_split_parts: 8192,
             max_concurrent_splits: 4096,
+            max_split_bytes: constants::DEFAULT_MAX_SPLIT_BYTES,
+            reorder_limit: ReorderLimit::default(),
+            min_rto: constants::MIN_RTO,
+            max_rto: constants::MAX_RTO,
+            max_resends: 10,
+            fast_retransmit: true,
+            ack_policy: AckPolicy::default(),
+            max_frames_per_datagram: constants::DEFAULT_MAX_FRAMES_PER_DATAGRAM,
+            max_consecutive_bad_datagrams: 8,
+            max_bad_datagrams: 64,
+            pause_policy: PausePolicy::Reject,
+            connection_migration: false,
+            strict_server_addr: false,
+            max_offline_replies_per_second: 8192,
+            max_offline_replies_per_ip_per_second: 64,
+            max_handshake_attempts_per_ip: 16,
+            handshake_attempt_window: Duration::from_secs(10),
+            handshake_ban_duration: Duration::from_secs(30),
+            ip_recently_connected_window: Duration::from_millis(100),
+            capture: None,
+            shutdown: None,
+            shutdown_timeout: Duration::from_secs(2),
+            outbound_queue_capacity: 1024,
+            accept_backlog: 32,
+            debug_log: DebugLogConfig::default(),
+            session_config: None,
         }
     }
 }
 
+impl RaknetListenerConfig {
+    /// Keep the last `capacity` protocol events in each session's debug log; 0
+    /// keeps none.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn debug_log_capacity(mut self, capacity: usize) -> Self {
+        self.debug_log = DebugLogConfig { capacity };
+        self
+    }
+}
+
+/// A connection from `RaknetListener::accept_with_info`, with what the
+/// handshake told the listener about it.
+pub struct IncomingConnection {
+    pub stream: RaknetStream,
+    /// GUID the client announced in its handshake.
+    pub client_guid: u64,
+    /// MTU negotiated for this peer.
+    pub mtu: u16,
+    /// RakNet protocol version the client handshaked with.
+    pub protocol_version: u8,
+    /// Our address as the client sees it, from its `NewIncomingConnection`;
+    /// differs from `local_addr` behind NAT or on a wildcard bind.
+    pub server_addr: SocketAddr,
+}
+
 /// Server-side RakNet listener that accepts new connections.
 pub struct RaknetListener {
     local_addr: SocketAddr,
-    new_connections: mpsc::Receiver<(
-        SocketAddr,
-        mpsc::Receiver<Result<super::ReceivedMessage, crate::RaknetError>>,
-    )>,
-    outbound_tx: mpsc::Sender<super::OutboundMsg>,
-    advertisement: Arc<RwLock<Vec<u8>>>,
+    local_addrs: Vec<SocketAddr>,
+    guid: u64,
+    new_connections: mpsc::Receiver<Result<NewConnection, crate::RaknetError>>,
+    fatal_error: Option<crate::RaknetError>,
+    outbound_tx: mpsc::Sender<super::Outbound>,
+    control_tx: mpsc::Sender<super::ControlMsg>,
+    advertisement: Arc<SharedAdvertisement>,
+    filter: Arc<SharedFilter>,
+    events: Events,
+    stats: Arc<ListenerStats>,
+    /// Cancelled by `shutdown`; separate from the configured token so the
+    /// streams can tell the two apart.
+    stop: CancellationToken,
+    muxer: JoinHandle<()>,
 }
 
 impl RaknetListener {
     /// Binds a new listener to the specified address using default configuration.
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
-        Self::bind_with_config(addr, RaknetListenerConfig::default()).await
+        let socket = bind_udp(addr)?;
+        Self::from_socket(socket, RaknetListenerConfig::default().max_mtu as usize)
+    }
+
+    /// A builder for a listener on `addr`, for setting options one at a
+    /// time instead of filling in a `RaknetListenerConfig`.
+    pub fn builder(addr: SocketAddr) -> RaknetListenerBuilder {
+        RaknetListenerBuilder::new(addr)
+    }
+
+    /// Binds a listener on `port` for both IPv4 and IPv6, whatever the OS's
+    /// default for `[::]` is; see `DualStackSocket`.
+    pub async fn bind_dual_stack(port: u16) -> std::io::Result<Self> {
+        Self::with_socket(
+            DualStackSocket::bind(port)?,
+            RaknetListenerConfig::default(),
+        )
     }
 
     /// Binds a new listener to the specified address using the provided configuration.
@@ -112,35 +357,84 @@ impl RaknetListener {
         addr: SocketAddr,
         config: RaknetListenerConfig,
     ) -> std::io::Result<Self> {
-        let socket = std::net::UdpSocket::bind(addr)?;
-        socket.set_nonblocking(true)?;
+        Self::with_socket(bind_udp(addr)?, config)
+    }
 
-        // if let Some(size) = config.socket_recv_buffer_size {
-        //     let _ = socket.set_recv_buffer_size(size);
-        // }
-        // if let Some(size) = config.socket_send_buffer_size {
-        //     let _ = socket.set_send_buffer_size(size);
-        // }
+    /// Starts a listener with the default configuration on a socket the
+    /// caller has already bound and set up (`SO_REUSEADDR`, buffer sizes,
+    /// interface), advertising at most `mtu`.
+    ///
+    /// Fails with `InvalidInput` if `mtu` is below `MINIMUM_MTU_SIZE` or
+    /// doesn't fit in a `u16`.
+    pub fn from_socket(socket: UdpSocket, mtu: usize) -> std::io::Result<Self> {
+        let max_mtu = mux::checked_mtu(mtu)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        Self::with_socket(
+            socket,
+            RaknetListenerConfig {
+                max_mtu,
+                ..Default::default()
+            },
+        )
+    }
 
-        let socket = UdpSocket::from_std(socket)?;
+    /// Starts a listener on an already bound socket.
+    pub fn with_socket<S: DatagramSocket>(
+        socket: S,
+        mut config: RaknetListenerConfig,
+    ) -> std::io::Result<Self> {
+        let guid = offline::server_guid(&config);
+        config.server_guid = Some(guid);
+        offline::server_session_config(&config)
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let socket = Tapped::new(socket, config.capture.clone())?;
         let local_addr = socket.local_addr()?;
-        let (new_conn_tx, new_conn_rx) = mpsc::channel(32);
-        let (outbound_tx, outbound_rx) = mpsc::channel(1024);
-        let advertisement = Arc::new(RwLock::new(config.advertisement.clone()));
-
-        tokio::spawn(run_listener_muxer(
+        let local_addrs = socket.local_addrs()?;
+        let (new_conn_tx, new_conn_rx) = mpsc::channel(config.accept_backlog.max(1));
+        let (outbound_tx, outbound_rx) = mpsc::channel(config.outbound_queue_capacity.max(1));
+        let (control_tx, control_rx) = mpsc::channel(64);
+        let mut advertisement = SharedAdvertisement::new(config.advertisement.clone());
+        // A MOTD of the application's own keeps the ports it chose.
+        if local_addrs.len() > 1
+            && config.advertisement == BedrockMotd::default().to_advertisement_bytes()
+        {
+            advertisement = advertisement.with_port(local_addr.port());
+        }
+        let advertisement = Arc::new(advertisement);
+        advertisement.set_auto_player_count(config.motd_auto_player_count);
+        let filter = Arc::new(SharedFilter::default());
+        let events = Events::default();
+        let stats = Arc::new(ListenerStats::default());
+        let stop = CancellationToken::new();
+
+        let muxer = tokio::spawn(run_listener_muxer(
             socket,
             config,
             new_conn_tx,
             outbound_rx,
+            control_rx,
             advertisement.clone(),
+            filter.clone(),
+            events.clone(),
+            stats.clone(),
+            stop.clone(),
         ));
 
         Ok(Self {
             local_addr,
+            local_addrs,
+            guid,
             new_connections: new_conn_rx,
+            fatal_error: None,
             outbound_tx,
+            control_tx,
             advertisement,
+            filter,
+            events,
+            stats,
+            stop,
+            muxer,
         })
     }
 
@@ -148,90 +442,486 @@ impl RaknetListener {
         self.local_addr
     }
 
+    /// Every address the listener takes connections on: `local_addr`, or
+    /// one IPv4 and one IPv6 address when bound dual-stack.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The GUID this listener answers pings and handshakes with:
+    /// `server_guid` from its config, or the one picked for the process.
+    pub fn guid(&self) -> u64 {
+        self.guid
+    }
+
     /// Accepts the next incoming connection.
+    ///
+    /// Returns `None` once the listener has shut down; if that was caused by a
+    /// fatal socket error, it can be retrieved with `take_error`.
     pub async fn accept(&mut self) -> Option<RaknetStream> {
-        let (peer, incoming) = self.new_connections.recv().await?;
+        Some(self.accept_with_info().await?.stream)
+    }
+
+    /// Like `accept`, along with the client's GUID, MTU, protocol version
+    /// and the address it connected to.
+    pub async fn accept_with_info(&mut self) -> Option<IncomingConnection> {
+        let conn = match self.new_connections.recv().await? {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.fatal_error = Some(e);
+                return None;
+            }
+        };
+
+        let (client_guid, mtu, protocol_version) = (conn.guid, conn.mtu, conn.protocol_version);
+        let server_addr = conn.server_addr.unwrap_or(self.local_addr);
+        Some(IncomingConnection {
+            stream: RaknetStream::new(
+                self.local_addr,
+                conn,
+                self.outbound_tx.clone(),
+                self.control_tx.clone(),
+            ),
+            client_guid,
+            mtu,
+            protocol_version,
+            server_addr,
+        })
+    }
 
-        Some(RaknetStream::new(
-            self.local_addr,
-            peer,
-            incoming,
-            self.outbound_tx.clone(),
-        ))
+    /// Disconnects a peer with the given reason.
+    ///
+    /// Goes through the control channel, so it is not delayed by queued
+    /// application data. Unknown peers are ignored.
+    pub async fn disconnect(
+        &self,
+        peer: SocketAddr,
+        reason: DisconnectReason,
+    ) -> Result<(), crate::RaknetError> {
+        self.control_tx
+            .send(super::ControlMsg::Disconnect { peer, reason })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)
     }
 
-    /// Sets the advertisement data (Pong payload) sent in response to UnconnectedPing (0x01) and OpenConnections (0x02).
-    pub fn set_advertisement(&self, data: Vec<u8>) {
-        if let Ok(mut guard) = self.advertisement.write() {
-            *guard = data;
+    /// Disconnect `peer` without holding its `RaknetStream`.
+    ///
+    /// Like `RaknetStream::disconnect`, the peer is sent a
+    /// `DisconnectionNotification` behind any queued data, and the session
+    /// is dropped once that is ACKed or `shutdown_timeout` passes; the
+    /// accepted stream then ends with `RaknetError::Disconnected(reason)`.
+    /// The peer's IP counts as recently connected from the kick on. Fails
+    /// with `UnknownPeer` if there is no session with `peer`.
+    pub async fn kick(
+        &self,
+        peer: SocketAddr,
+        reason: DisconnectReason,
+    ) -> Result<(), crate::RaknetError> {
+        self.kick_target(super::KickTarget::Addr(peer), reason)
+            .await
+    }
+
+    /// `kick` the session of the client with `guid`, wherever it connected from.
+    pub async fn kick_guid(
+        &self,
+        guid: u64,
+        reason: DisconnectReason,
+    ) -> Result<(), crate::RaknetError> {
+        self.kick_target(super::KickTarget::Guid(guid), reason)
+            .await
+    }
+
+    async fn kick_target(
+        &self,
+        target: super::KickTarget,
+        reason: DisconnectReason,
+    ) -> Result<(), crate::RaknetError> {
+        let (done, kicked) = tokio::sync::oneshot::channel();
+        self.control_tx
+            .send(super::ControlMsg::Kick {
+                target,
+                reason,
+                done,
+            })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)?;
+        match kicked.await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(crate::RaknetError::UnknownPeer),
+            Err(_) => Err(crate::RaknetError::ConnectionClosed),
         }
     }
 
+    /// Send `msg` to every connected peer, returning how many it was queued
+    /// for.
+    ///
+    /// The message is queued on each session in one pass through the muxer,
+    /// which is cheaper than sending it through every stream. Peers whose
+    /// send queue is full (see `send_queue_limit`) are skipped rather than
+    /// failing the broadcast. Empty messages are skipped.
+    pub async fn broadcast(
+        &self,
+        msg: impl Into<super::Message>,
+    ) -> Result<usize, crate::RaknetError> {
+        self.broadcast_except(msg, &[]).await
+    }
+
+    /// `broadcast` to every connected peer but those in `except`, such as
+    /// the sender of a chat message.
+    pub async fn broadcast_except(
+        &self,
+        msg: impl Into<super::Message>,
+        except: &[SocketAddr],
+    ) -> Result<usize, crate::RaknetError> {
+        // The peer is filled in for each session by the muxer.
+        let Some(msg) = outbound_msg(self.local_addr, msg.into())? else {
+            return Ok(0);
+        };
+        let (done, sent) = tokio::sync::oneshot::channel();
+        self.outbound_tx
+            .send(super::Outbound::Broadcast {
+                msg,
+                except: except.to_vec(),
+                done,
+            })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)?;
+        sent.await.map_err(|_| crate::RaknetError::ConnectionClosed)
+    }
+
+    /// Sends pending ACKs and queued data for a peer without waiting for the next tick.
+    pub async fn flush(&self, peer: SocketAddr) -> Result<(), crate::RaknetError> {
+        self.control_tx
+            .send(super::ControlMsg::Flush { peer })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)
+    }
+
+    /// Shut the listener down and wait until it has let go of the socket.
+    ///
+    /// No more connections are accepted. Every session is sent
+    /// `DisconnectReason::ShuttingDown`, and the listener waits up to
+    /// `shutdown_timeout` for the notifications to be ACKed before closing
+    /// the socket. Accepted streams end with
+    /// `RaknetError::Disconnected(ShuttingDown)`.
+    ///
+    /// Dropping the listener instead leaves accepted streams running. Once
+    /// they are dropped as well, the remaining sessions are sent
+    /// `ShuttingDown` without waiting for ACKs and the socket is released.
+    pub async fn shutdown(self) {
+        self.stop.cancel();
+        let _ = self.muxer.await;
+    }
+
+    /// Takes the error that shut the listener down, if any.
+    pub fn take_error(&mut self) -> Option<crate::RaknetError> {
+        self.fatal_error.take()
+    }
+
+    /// The peers connected right now, asked of the muxer so the list is
+    /// current; peers still in the handshake are left out.
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, crate::RaknetError> {
+        let (done, peers) = tokio::sync::oneshot::channel();
+        self.control_tx
+            .send(super::ControlMsg::Peers { done })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)?;
+        peers
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)
+    }
+
+    /// How many peers `peers` would list.
+    pub async fn connection_count(&self) -> Result<usize, crate::RaknetError> {
+        Ok(self.peers().await?.len())
+    }
+
+    /// Returns listener-wide counters.
+    pub fn stats(&self) -> ListenerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Returns the bytes buffered across all sessions on this listener.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.stats.memory_usage()
+    }
+
+    /// Returns the counters of a connected peer, without going through the muxer.
+    pub fn peer_stats(&self, peer: SocketAddr) -> Option<StatsSnapshot> {
+        self.stats.peer(&peer)
+    }
+
+    /// The recent protocol events of a connected peer, oldest first.
+    ///
+    /// `None` if the peer is unknown or `debug_log_capacity` is 0.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn dump_debug_log(
+        &self,
+        peer: SocketAddr,
+    ) -> Option<Vec<crate::session::debug_log::DebugEvent>> {
+        self.stats.debug_log(&peer)
+    }
+
+    /// Sets the advertisement data (Pong payload) sent in response to UnconnectedPing (0x01) and OpenConnections (0x02).
+    ///
+    /// The server GUID field of a Bedrock MOTD is replaced with `guid` in pongs.
+    pub fn set_advertisement(&self, data: impl Into<Vec<u8>>) {
+        self.advertisement.set(data.into());
+    }
+
+    /// Advertise a Bedrock MOTD; see `set_advertisement`.
+    pub fn set_motd(&self, motd: &BedrockMotd) {
+        self.advertisement.set(motd.to_advertisement_bytes());
+    }
+
     /// Gets a copy of the current advertisement data.
     pub fn get_advertisement(&self) -> Vec<u8> {
-        self.advertisement
-            .read()
-            .unwrap_or_else(|e| e.into_inner())
-            .clone()
+        self.advertisement.get()
+    }
+
+    /// Build the pong payload per ping instead: `f` is called with the
+    /// pinger's address and ping time for every `UnconnectedPing` and
+    /// `UnconnectedPingOpenConnections`, and its payload is sent as is,
+    /// cut to `u16::MAX` bytes. Returning `None` sends no pong at all.
+    ///
+    /// It runs on the listener's task for every ping, so it must be quick
+    /// and never block: format from state kept up to date elsewhere.
+    pub fn set_advertisement_fn(
+        &self,
+        f: impl Fn(SocketAddr, RaknetTime) -> Option<Bytes> + Send + Sync + 'static,
+    ) {
+        self.advertisement.set_dynamic(Some(Arc::new(f)));
+    }
+
+    /// Remove the advertisement callback, going back to the data from
+    /// `set_advertisement`.
+    pub fn clear_advertisement_fn(&self) {
+        self.advertisement.set_dynamic(None);
+    }
+
+    /// Decide, before any session state exists, whether a client may
+    /// connect: `filter` is called with the client's address and GUID for
+    /// every `OpenConnectionRequest2` that passes the cookie check, and
+    /// replaces any filter set before.
+    ///
+    /// It runs on the listener's task, in the middle of the handshake, so it
+    /// must be quick and never block: look up a precomputed ban list rather
+    /// than query a database.
+    pub fn set_connection_filter(
+        &self,
+        filter: impl Fn(SocketAddr, u64) -> FilterDecision + Send + Sync + 'static,
+    ) {
+        self.filter.set(Some(Arc::new(filter)));
+    }
+
+    /// Remove the connection filter, accepting every client again.
+    pub fn clear_connection_filter(&self) {
+        self.filter.set(None);
+    }
+
+    /// Stop or resume accepting new connections, e.g. during a world save.
+    ///
+    /// While not accepting, pings are still answered but handshakes are
+    /// refused or ignored according to `pause_policy`. Established sessions
+    /// are left alone, and accepting again takes effect with the next
+    /// handshake packet.
+    pub fn set_accepting(&self, accepting: bool) {
+        self.filter.set_accepting(accepting);
+    }
+
+    /// Whether new connections are being accepted; see `set_accepting`.
+    pub fn is_accepting(&self) -> bool {
+        self.filter.is_accepting()
+    }
+
+    /// Subscribe to connection lifecycle events: connects, disconnects,
+    /// pings and refused handshakes, across every session.
+    ///
+    /// Only events after the call are seen. The muxer never waits for a
+    /// receiver: one that falls more than 256 events behind loses the
+    /// oldest, and its next `recv` reports how many with
+    /// `RecvError::Lagged`.
+    pub fn events(&self) -> broadcast::Receiver<ListenerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Keep the player counts of a Bedrock (`MCPE;`/`MCEE;`) advertisement
+    /// current.
+    ///
+    /// While enabled, every pong carries the number of connected peers (not
+    /// counting those still in the handshake) and `max_connections` in place
+    /// of the online and maximum player fields.
+    /// The advertisement itself is left as set; other formats are sent as is.
+    pub fn set_motd_auto_player_count(&self, enabled: bool) {
+        self.advertisement.set_auto_player_count(enabled);
     }
 }
 
-async fn run_listener_muxer(
-    socket: UdpSocket,
+#[allow(clippy::too_many_arguments)]
+async fn run_listener_muxer<S: DatagramSocket>(
+    socket: S,
 
     config: RaknetListenerConfig,
 
-    new_conn_tx: mpsc::Sender<(
-        SocketAddr,
-        mpsc::Receiver<Result<super::ReceivedMessage, crate::RaknetError>>,
-    )>,
+    new_conn_tx: mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+
+    mut outbound_rx: mpsc::Receiver<super::Outbound>,
+
+    mut control_rx: mpsc::Receiver<super::ControlMsg>,
+
+    advertisement: Arc<SharedAdvertisement>,
+
+    filter: Arc<SharedFilter>,
+
+    events: Events,
 
-    mut outbound_rx: mpsc::Receiver<super::OutboundMsg>,
+    stats: Arc<ListenerStats>,
 
-    advertisement: Arc<RwLock<Vec<u8>>>,
+    stop: CancellationToken,
 ) {
     // Allocate a receive buffer large enough to avoid OS "message too long" errors even if a peer
-    // sends a slightly larger probe than our configured MTU.
-    let mut buf = vec![0u8; (config.max_mtu as usize + UDP_HEADER_SIZE + 64).max(2048)];
+    // sends a slightly larger probe than our configured MTU. One spare byte lets us tell a
+    // datagram that exactly fills the buffer apart from one the OS truncated.
+    let recv_len = (config.max_mtu as usize + UDP_HEADER_SIZE + 64).max(2048);
+    let mut buf = RecvBuffer::new(recv_len + 1);
     let mut sessions: HashMap<SocketAddr, SessionState> = HashMap::new();
-    let mut pending: HashMap<SocketAddr, PendingConnection> = HashMap::new();
+    let mut offline = OfflineState::new(&config, filter);
+    let mut schedule = TickSchedule::default();
+    let mut backoff = RecvBackoff::default();
     let mut tick = new_tick_interval();
 
     loop {
+        // Control commands jump the queue: drain them before looking at data.
+        while let Ok(ctrl) = control_rx.try_recv() {
+            handle_control_msg(
+                &socket,
+                &config,
+                ctrl,
+                &mut sessions,
+                &mut schedule,
+                &mut offline.recent,
+                &events,
+                &stats,
+            )
+            .await;
+        }
+
         tokio::select! {
-            res = socket.recv_from(&mut buf) => {
+            Some(ctrl) = control_rx.recv() => {
+                handle_control_msg(
+                    &socket,
+                    &config,
+                    ctrl,
+                    &mut sessions,
+                    &mut schedule,
+                    &mut offline.recent,
+                    &events,
+                    &stats,
+                )
+                .await;
+            }
+            res = socket.recv_from(buf.spare()) => {
                 match res  {
                     Ok((len, peer)) => {
+                        backoff.on_success();
+                        stats.record_datagram_received(len);
+                        if len > recv_len {
+                            tracing::debug!(%peer, "dropping truncated datagram");
+                            stats.record_oversized_datagram();
+                            continue;
+                        }
                         dispatch_datagram(
                             &socket,
                             &config,
-                            &buf[..len],
+                            buf.take(len),
                             peer,
                             &mut sessions,
-                            &mut pending,
+                            &mut offline,
                             &new_conn_tx,
                             &advertisement,
-
+                            &events,
+                            &stats,
                         ).await;
-                    }
-                    Err(e) => {
-                        if e.kind() == std::io::ErrorKind::ConnectionReset {
-                            // Windows ICMP port unreachable - ignore
-                            continue;
+                        if sessions.contains_key(&peer) {
+                            schedule.mark_dirty(peer);
                         }
-                        tracing::error!("UDP socket error: {}", e);
-                        // Don't break on transient errors
-                        continue;
                     }
+                    Err(e) => match backoff.on_error(e.kind()) {
+                        // Windows ICMP port unreachable - ignore
+                        RecvErrorAction::Ignore => continue,
+                        RecvErrorAction::Retry(delay) => {
+                            tracing::warn!(error = %e, ?delay, "UDP socket error, backing off");
+                            tokio::time::sleep(delay).await;
+                        }
+                        RecvErrorAction::Fatal => {
+                            tracing::error!(error = %e, "UDP socket keeps failing, shutting listener down");
+                            let _ = new_conn_tx.send(Err(crate::RaknetError::Io(e))).await;
+                            return;
+                        }
+                    },
                 }
             }
-            Some(msg) = outbound_rx.recv() => {
-                handle_outgoing_msg(&socket, config.max_mtu as usize, msg, &mut sessions, &config).await;
+            out = outbound_rx.recv() => {
+                let Some(out) = out else {
+                    // The listener and every stream it accepted are gone, so
+                    // nobody can reach the remaining sessions any more.
+                    shut_down(&socket, &config, &mut sessions, &new_conn_tx, &events, &stats, Duration::ZERO, || {
+                        crate::RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+                    })
+                    .await;
+                    return;
+                };
+                handle_outgoing_msgs(&socket, &config, out, &mut outbound_rx, &mut sessions, &mut schedule).await;
             }
             _ = tick.tick() => {
-                tick_sessions(&socket, &mut sessions).await;
+                tick_sessions(&socket, &mut sessions, &mut schedule, &mut offline.recent, &events, &stats).await;
 
             }
+            _ = mux::cancelled(config.shutdown.as_ref()) => {
+                shut_down(&socket, &config, &mut sessions, &new_conn_tx, &events, &stats, config.shutdown_timeout, || {
+                    crate::RaknetError::Shutdown
+                })
+                .await;
+                return;
+            }
+            _ = stop.cancelled() => {
+                shut_down(&socket, &config, &mut sessions, &new_conn_tx, &events, &stats, config.shutdown_timeout, || {
+                    crate::RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+                })
+                .await;
+                return;
+            }
         }
     }
 }
+
+/// Stop accepting, then send every session `ShuttingDown` and wait (up to
+/// `timeout`) for them to drain. Their streams end with `error`.
+#[allow(clippy::too_many_arguments)]
+async fn shut_down<S: DatagramSocket>(
+    socket: &S,
+    config: &RaknetListenerConfig,
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    new_conn_tx: &mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+    events: &Events,
+    stats: &ListenerStats,
+    timeout: Duration,
+    error: impl Fn() -> crate::RaknetError,
+) {
+    tracing::debug!(sessions = sessions.len(), "shutting listener down");
+    // A pending `accept` sees this straight away; one nobody is waiting on
+    // must not hold the shutdown up.
+    let _ = new_conn_tx.try_send(Err(crate::RaknetError::Shutdown));
+    let deadline = tokio::time::Instant::now() + timeout;
+    drain_sessions(
+        socket,
+        config,
+        sessions,
+        events,
+        stats,
+        DisconnectReason::ShuttingDown,
+        deadline,
+        error,
+    )
+    .await;
+}
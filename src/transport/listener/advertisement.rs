@@ -0,0 +1,266 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, RwLock};
+
+use bytes::Bytes;
+
+use crate::protocol::types::RaknetTime;
+use crate::transport::motd::split_fields;
+
+type AdvertisementFn = dyn Fn(SocketAddr, RaknetTime) -> Option<Bytes> + Send + Sync;
+
+/// The pong payload, shared between a `RaknetListener` and its muxer.
+#[derive(Default)]
+pub(crate) struct SharedAdvertisement {
+    data: RwLock<Vec<u8>>,
+    auto_player_count: AtomicBool,
+    /// Called for every ping instead of using `data`, when set.
+    dynamic: RwLock<Option<Arc<AdvertisementFn>>>,
+    /// Port of a dual-stack listener, which serves both families on it; 0
+    /// for none.
+    port: AtomicU16,
+}
+
+impl SharedAdvertisement {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+            auto_player_count: AtomicBool::new(false),
+            dynamic: RwLock::new(None),
+            port: AtomicU16::new(0),
+        }
+    }
+
+    /// Report `port` in both port fields of a Bedrock MOTD, for a listener
+    /// taking IPv4 and IPv6 on the same socket. Only until `set` replaces
+    /// the advertisement: ports the application chose are left alone.
+    pub fn with_port(self, port: u16) -> Self {
+        self.port.store(port, Ordering::Relaxed);
+        self
+    }
+
+    pub fn set_dynamic(&self, f: Option<Arc<AdvertisementFn>>) {
+        *self.dynamic.write().unwrap_or_else(|e| e.into_inner()) = f;
+    }
+
+    /// The advertisement to answer a ping from `peer` with: the callback's
+    /// if one is set, capped at `u16::MAX` bytes, else `pong_payload`. The
+    /// outer `None` means the callback wants no pong sent at all.
+    pub fn pong_for(
+        &self,
+        peer: SocketAddr,
+        ping_time: RaknetTime,
+        guid: u64,
+        players: impl FnOnce() -> usize,
+        max_players: usize,
+    ) -> Option<Option<Bytes>> {
+        // Cloned out so a callback that replaces itself can't deadlock.
+        let dynamic = self
+            .dynamic
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let Some(dynamic) = dynamic else {
+            return Some(self.pong_payload(guid, players, max_players));
+        };
+        let mut payload = dynamic(peer, ping_time)?;
+        payload.truncate(u16::MAX as usize);
+        Some((!payload.is_empty()).then_some(payload))
+    }
+
+    pub fn set(&self, data: Vec<u8>) {
+        *self.data.write().unwrap_or_else(|e| e.into_inner()) = data;
+        self.port.store(0, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Vec<u8> {
+        self.data.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_auto_player_count(&self, enabled: bool) {
+        self.auto_player_count.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The advertisement to put in an `UnconnectedPong`, or `None` if empty.
+    ///
+    /// A Bedrock MOTD always gets `guid` substituted in, so it agrees with
+    /// the pong itself; with the automatic player count on, so are `players`
+    /// and `max_players`. `players` is only counted then. A dual-stack
+    /// listener's port goes into both port fields.
+    pub fn pong_payload(
+        &self,
+        guid: u64,
+        players: impl FnOnce() -> usize,
+        max_players: usize,
+    ) -> Option<Bytes> {
+        let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+        if data.is_empty() {
+            return None;
+        }
+        let counts = self
+            .auto_player_count
+            .load(Ordering::Relaxed)
+            .then(|| (players(), max_players));
+        let port = Some(self.port.load(Ordering::Relaxed)).filter(|&port| port != 0);
+        if let Some(motd) = with_live_fields(&data, guid, counts, port) {
+            return Some(Bytes::from(motd));
+        }
+        Some(Bytes::copy_from_slice(&data))
+    }
+}
+
+/// Index of the online player count in a `;`-separated Bedrock MOTD; the
+/// maximum follows it.
+const PLAYERS_FIELD: usize = 4;
+
+/// Index of the server GUID in a `;`-separated Bedrock MOTD.
+const GUID_FIELD: usize = 6;
+
+/// Index of the IPv4 port in a `;`-separated Bedrock MOTD; the IPv6 port
+/// follows it.
+const PORT_V4_FIELD: usize = 10;
+
+/// `motd` with its GUID field, and its player-count and port fields if
+/// `counts` and `port` are given, replaced; `None` if it is not a Bedrock
+/// (`MCPE;`/`MCEE;`) MOTD or has none of those fields.
+fn with_live_fields(
+    motd: &[u8],
+    guid: u64,
+    counts: Option<(usize, usize)>,
+    port: Option<u16>,
+) -> Option<Vec<u8>> {
+    if !(motd.starts_with(b"MCPE;") || motd.starts_with(b"MCEE;")) {
+        return None;
+    }
+    let mut fields = split_fields(motd);
+    let counts = counts
+        .filter(|_| fields.len() > PLAYERS_FIELD + 1)
+        .map(|(players, max)| (players.to_string(), max.to_string()));
+    let guid = (fields.len() > GUID_FIELD).then(|| guid.to_string());
+    let port = port
+        .filter(|_| fields.len() > PORT_V4_FIELD + 1)
+        .map(|port| port.to_string());
+    if counts.is_none() && guid.is_none() && port.is_none() {
+        return None;
+    }
+    if let Some((players, max_players)) = &counts {
+        fields[PLAYERS_FIELD] = players.as_bytes();
+        fields[PLAYERS_FIELD + 1] = max_players.as_bytes();
+    }
+    if let Some(guid) = &guid {
+        fields[GUID_FIELD] = guid.as_bytes();
+    }
+    if let Some(port) = &port {
+        fields[PORT_V4_FIELD] = port.as_bytes();
+        fields[PORT_V4_FIELD + 1] = port.as_bytes();
+    }
+    Some(fields.join(&b';'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_counts_into_bedrock_motds() {
+        let ad = SharedAdvertisement::new(b"MCPE;Hello;527;1.19.1;0;10;123;Sub;Survival;".to_vec());
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Hello;527;1.19.1;0;10;77;Sub;Survival;"
+        );
+
+        ad.set_auto_player_count(true);
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Hello;527;1.19.1;3;20;77;Sub;Survival;"
+        );
+        // The stored MOTD is untouched; counts are filled in per pong.
+        assert_eq!(ad.get(), b"MCPE;Hello;527;1.19.1;0;10;123;Sub;Survival;");
+
+        // An escaped `;` in a name doesn't shift the fields.
+        ad.set(b"MCPE;Hi\\; there;527;1.19.1;0;10;123;Sub;".to_vec());
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Hi\\; there;527;1.19.1;3;20;77;Sub;"
+        );
+    }
+
+    #[test]
+    fn dual_stack_port_fills_both_port_fields() {
+        let ad = SharedAdvertisement::new(
+            b"MCPE;Hello;527;1.19.1;0;10;123;Sub;Survival;1;19132;19133;".to_vec(),
+        )
+        .with_port(20000);
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Hello;527;1.19.1;0;10;77;Sub;Survival;1;20000;20000;"
+        );
+
+        // An advertisement set afterwards keeps its own ports.
+        ad.set(b"MCPE;Hello;527;1.19.1;0;10;123;Sub;Survival;1;19132;19133;".to_vec());
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Hello;527;1.19.1;0;10;77;Sub;Survival;1;19132;19133;"
+        );
+    }
+
+    #[test]
+    fn leaves_other_advertisements_alone() {
+        let ad = SharedAdvertisement::new(b"My Server;1;2;3;4;5;6;7".to_vec());
+        ad.set_auto_player_count(true);
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"My Server;1;2;3;4;5;6;7"
+        );
+
+        ad.set(b"MCPE;Too;Short;0".to_vec());
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Too;Short;0"
+        );
+
+        // Counts but no GUID field.
+        ad.set(b"MCPE;Hello;527;1.19.1;0;10".to_vec());
+        assert_eq!(
+            &ad.pong_payload(77, || 3, 20).unwrap()[..],
+            b"MCPE;Hello;527;1.19.1;3;20"
+        );
+
+        ad.set(Vec::new());
+        assert!(ad.pong_payload(77, || 3, 20).is_none());
+    }
+
+    #[test]
+    fn callback_overrides_the_static_advertisement() {
+        let peer: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let ad = SharedAdvertisement::new(b"static".to_vec());
+        assert_eq!(
+            ad.pong_for(peer, RaknetTime(1), 77, || 0, 10),
+            Some(Some(Bytes::from_static(b"static")))
+        );
+
+        ad.set_dynamic(Some(Arc::new(
+            |peer: SocketAddr, time: RaknetTime| match time.0 {
+                0 => None,
+                1 => Some(Bytes::from(peer.to_string())),
+                _ => Some(Bytes::from(vec![b'x'; 100_000])),
+            },
+        )));
+        assert_eq!(ad.pong_for(peer, RaknetTime(0), 77, || 0, 10), None);
+        assert_eq!(
+            ad.pong_for(peer, RaknetTime(1), 77, || 0, 10),
+            Some(Some(Bytes::from_static(b"10.0.0.2:5000")))
+        );
+        let capped = ad
+            .pong_for(peer, RaknetTime(2), 77, || 0, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(capped.len(), u16::MAX as usize);
+
+        ad.set_dynamic(None);
+        assert_eq!(
+            ad.pong_for(peer, RaknetTime(0), 77, || 0, 10),
+            Some(Some(Bytes::from_static(b"static")))
+        );
+    }
+}
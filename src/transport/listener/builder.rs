@@ -0,0 +1,179 @@
+//! Step-by-step construction of a `RaknetListener`.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::session::manager::{BacklogLimit, SendQueueLimit, SessionConfig};
+use crate::transport::socket::{DualStackSocket, bind_udp};
+
+use super::{RaknetListener, RaknetListenerConfig};
+
+/// Builds a `RaknetListener`, from `RaknetListener::builder`.
+///
+/// Anything not set keeps its `RaknetListenerConfig` default; `config`
+/// covers the settings without a setter of their own.
+#[derive(Debug, Clone)]
+pub struct RaknetListenerBuilder {
+    addr: SocketAddr,
+    dual_stack: bool,
+    config: RaknetListenerConfig,
+}
+
+impl RaknetListenerBuilder {
+    pub(super) fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            dual_stack: false,
+            config: RaknetListenerConfig::default(),
+        }
+    }
+
+    /// Listen on both IPv4 and IPv6 at the address's port; see
+    /// `RaknetListener::bind_dual_stack`. The address's IP must then be
+    /// unspecified (`0.0.0.0` or `[::]`), as every address is bound.
+    pub fn dual_stack(mut self, enabled: bool) -> Self {
+        self.dual_stack = enabled;
+        self
+    }
+
+    /// Largest MTU to negotiate with clients.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.config.max_mtu = mtu;
+        self
+    }
+
+    /// RakNet protocol version to accept.
+    pub fn protocol_version(mut self, version: u8) -> Self {
+        self.config.protocol_version = version;
+        self
+    }
+
+    /// GUID to answer pings and handshakes with.
+    pub fn server_guid(mut self, guid: u64) -> Self {
+        self.config.server_guid = Some(guid);
+        self
+    }
+
+    /// Payload of the pongs answering unconnected pings.
+    pub fn advertisement(mut self, advertisement: impl Into<Vec<u8>>) -> Self {
+        self.config.advertisement = advertisement.into();
+        self
+    }
+
+    /// Fill the live player count into a Bedrock advertisement's pongs; see
+    /// `RaknetListener::set_motd_auto_player_count`.
+    pub fn motd_auto_player_count(mut self, enabled: bool) -> Self {
+        self.config.motd_auto_player_count = enabled;
+        self
+    }
+
+    /// How long a silent session lives before it times out.
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.config.session_timeout = timeout;
+        self
+    }
+
+    /// How long a silent session stays `Connected` before going `Stale`.
+    pub fn session_stale(mut self, stale: Duration) -> Self {
+        self.config.session_stale = stale;
+        self
+    }
+
+    /// Idle time after which sessions ping their peer; `None` turns
+    /// automatic pings off.
+    pub fn keepalive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.keepalive_interval = interval;
+        self
+    }
+
+    /// Old name for `keepalive_interval`.
+    #[deprecated(note = "use `keepalive_interval`")]
+    pub fn ping_interval(self, interval: Duration) -> Self {
+        self.keepalive_interval(Some(interval))
+    }
+
+    /// Most sessions alive at once; further clients are refused.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = max;
+        self
+    }
+
+    /// Established connections queued for `accept`.
+    pub fn accept_backlog(mut self, backlog: usize) -> Self {
+        self.config.accept_backlog = backlog;
+        self
+    }
+
+    /// Messages the outbound queue shared by every accepted stream holds.
+    pub fn outbound_capacity(mut self, capacity: usize) -> Self {
+        self.config.outbound_queue_capacity = capacity;
+        self
+    }
+
+    /// Reliable bytes a session may queue before it is closed with
+    /// `QueueTooLong`.
+    pub fn max_queued_reliable_bytes(mut self, max: usize) -> Self {
+        self.config.max_queued_reliable_bytes = max;
+        self
+    }
+
+    /// Cap on each session's outgoing queue.
+    pub fn send_queue_limit(mut self, limit: SendQueueLimit) -> Self {
+        self.config.send_queue_limit = Some(limit);
+        self
+    }
+
+    /// How far behind a session may fall before it is closed with
+    /// `QueueTooLong`; `None` never closes it for that.
+    pub fn backlog_limit(mut self, limit: Option<BacklogLimit>) -> Self {
+        self.config.backlog_limit = limit;
+        self
+    }
+
+    /// Settings for every accepted session, replacing the per-session ones
+    /// above; see `RaknetListenerConfig::session_config`.
+    pub fn session_config(mut self, session: SessionConfig) -> Self {
+        self.config.session_config = Some(session);
+        self
+    }
+
+    /// Events kept in each session's debug log; see
+    /// `RaknetListener::dump_debug_log`.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn debug_log_capacity(mut self, capacity: usize) -> Self {
+        self.config = self.config.debug_log_capacity(capacity);
+        self
+    }
+
+    /// Token that shuts the listener down once cancelled.
+    pub fn shutdown(mut self, token: CancellationToken) -> Self {
+        self.config.shutdown = Some(token);
+        self
+    }
+
+    /// Adjust any other setting of the underlying config.
+    pub fn config(mut self, f: impl FnOnce(&mut RaknetListenerConfig)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Bind the address and start listening.
+    ///
+    /// Fails with `InvalidInput` if the settings could never work, or if
+    /// `dual_stack` is on for a specific IP.
+    pub async fn bind(self) -> std::io::Result<RaknetListener> {
+        if self.dual_stack {
+            if !self.addr.ip().is_unspecified() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "dual-stack listening binds every address; use an unspecified IP",
+                ));
+            }
+            let socket = DualStackSocket::bind(self.addr.port())?;
+            return RaknetListener::with_socket(socket, self.config);
+        }
+        RaknetListener::with_socket(bind_udp(self.addr)?, self.config)
+    }
+}
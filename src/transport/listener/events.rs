@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast;
+
+use crate::protocol::state::DisconnectReason;
+
+/// Events each receiver buffers before the oldest are dropped.
+const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened to one of a listener's connections; see
+/// `RaknetListener::events`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ListenerEvent {
+    /// A handshake completed and the connection was queued for `accept`.
+    Connected {
+        peer: SocketAddr,
+        guid: u64,
+        mtu: u16,
+    },
+    /// A connection that was queued for `accept` ended.
+    Disconnected {
+        peer: SocketAddr,
+        reason: DisconnectReason,
+    },
+    /// An unconnected ping arrived, e.g. from a server list.
+    Pinged { peer: SocketAddr },
+    /// A handshake was turned away, whether or not the client was told.
+    HandshakeRejected {
+        peer: SocketAddr,
+        reason: RejectReason,
+    },
+}
+
+/// Why a handshake was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// The client speaks another RakNet protocol version.
+    IncompatibleProtocol,
+    /// `set_accepting(false)` is in effect.
+    NotAccepting,
+    /// The connection filter dropped or rejected it.
+    Filtered,
+    /// `max_connections` sessions are already alive.
+    ServerFull,
+    /// The client's IP disconnected within `ip_recently_connected_window`.
+    RecentlyConnected,
+    /// The client's IP started too many handshakes and is now ignored.
+    Throttled,
+    /// The client's GUID is already connected from another address.
+    AlreadyConnected,
+    /// `strict_server_addr` is on and the client named another server address.
+    ServerAddrMismatch,
+}
+
+/// Sending half of the event channel, shared by the listener and its muxer.
+#[derive(Clone)]
+pub(crate) struct Events(broadcast::Sender<ListenerEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENT_CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn subscribe(&self) -> broadcast::Receiver<ListenerEvent> {
+        self.0.subscribe()
+    }
+
+    /// Never waits: with nobody subscribed the event is dropped, and a
+    /// receiver that falls behind loses its oldest events instead.
+    pub fn emit(&self, event: ListenerEvent) {
+        let _ = self.0.send(event);
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// What a connection filter decides about a handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Go on with the handshake.
+    Accept,
+    /// Ignore the request; the client eventually times out.
+    Drop,
+    /// Refuse the client, telling it why.
+    Reject(Rejection),
+}
+
+/// How a rejected client is told.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// `ConnectionRequestFailed`; `connect` fails with
+    /// `HandshakeFailure::Rejected`.
+    Failed,
+    /// `ConnectionBanned`; `connect` fails with `RaknetError::Banned`.
+    Banned,
+}
+
+/// What a listener that isn't accepting does with handshakes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PausePolicy {
+    /// Refuse with `ConnectionRequestFailed`; `connect` fails straight away.
+    #[default]
+    Reject,
+    /// Ignore them; the client eventually times out.
+    Drop,
+}
+
+type Filter = dyn Fn(SocketAddr, u64) -> FilterDecision + Send + Sync;
+
+/// The connection filter and whether handshakes are accepted at all, shared
+/// between a `RaknetListener` and its muxer.
+pub(crate) struct SharedFilter {
+    filter: RwLock<Option<Arc<Filter>>>,
+    accepting: AtomicBool,
+}
+
+impl Default for SharedFilter {
+    fn default() -> Self {
+        Self {
+            filter: RwLock::new(None),
+            accepting: AtomicBool::new(true),
+        }
+    }
+}
+
+impl SharedFilter {
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, filter: Option<Arc<Filter>>) {
+        *self.filter.write().unwrap_or_else(|e| e.into_inner()) = filter;
+    }
+
+    /// The filter's decision on `guid` connecting from `peer`; `Accept`
+    /// without one.
+    pub fn check(&self, peer: SocketAddr, guid: u64) -> FilterDecision {
+        // Cloned out so a filter that replaces itself can't deadlock.
+        let filter = self
+            .filter
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        match filter {
+            Some(filter) => filter(peer, guid),
+            None => FilterDecision::Accept,
+        }
+    }
+}
@@ -1,33 +1,122 @@
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{HashMap, hash_map::DefaultHasher},
     hash::{Hash, Hasher},
-    net::SocketAddr,
-    sync::{Arc, OnceLock, RwLock},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, OnceLock},
     time::{Duration, Instant},
 };
 
-use bytes::{Bytes, BytesMut};
-use tokio::net::UdpSocket;
+use bytes::{BufMut, BytesMut};
 use tokio::sync::mpsc;
 
-use super::online::maybe_announce_connection;
+use super::online::{close_session, maybe_announce_connection};
 use crate::protocol::{
-    constants::{
-        DEFAULT_UNCONNECTED_MAGIC, MAXIMUM_MTU_SIZE, MINIMUM_MTU_SIZE, RAKNET_PROTOCOL_VERSION,
-        UDP_HEADER_SIZE,
-    },
+    constants::{DEFAULT_UNCONNECTED_MAGIC, UDP_HEADER_SIZE},
     packet::{
-        AlreadyConnected, IncompatibleProtocolVersion, OpenConnectionReply1, OpenConnectionReply2,
-        RaknetPacket, UnconnectedPong,
+        AlreadyConnected, ConnectionBanned, ConnectionRequestFailed, IncompatibleProtocolVersion,
+        OpenConnectionReply1, OpenConnectionReply2, RaknetPacket, UnconnectedPong,
     },
+    state::DisconnectReason,
+    types::RaknetTime,
 };
 use crate::session::manager::{ManagedSession, SessionConfig};
-use crate::transport::listener_conn::SessionState;
+use crate::transport::listener_conn::{NewConnection, SessionState};
+use crate::transport::mux::{self, negotiate_mtu};
+use crate::transport::socket::DatagramSocket;
+
+use super::ListenerStats;
+use super::advertisement::SharedAdvertisement;
+use super::events::{Events, ListenerEvent, RejectReason};
+use super::filter::{FilterDecision, PausePolicy, Rejection, SharedFilter};
+use super::rate_limit::{Attempt, HandshakeGuard, RecentDisconnects, ReplyLimiter};
 
 pub(super) struct PendingConnection {
     pub mtu: u16,
     pub expires_at: Instant,
     pub cookie: u32,
+    /// RakNet protocol version from `OpenConnectionRequest1`.
+    pub protocol_version: u8,
+}
+
+/// Muxer-owned state for the offline (pre-session) path.
+pub(super) struct OfflineState {
+    pub pending: HashMap<SocketAddr, PendingConnection>,
+    pub limiter: ReplyLimiter,
+    pub guard: HandshakeGuard,
+    /// Last address each client GUID completed a handshake from. Entries are
+    /// checked against `sessions` on lookup, so stale ones are harmless.
+    pub guids: HashMap<u64, SocketAddr>,
+    pub recent: RecentDisconnects,
+    pub filter: Arc<SharedFilter>,
+}
+
+impl OfflineState {
+    pub fn new(config: &RaknetListenerConfig, filter: Arc<SharedFilter>) -> Self {
+        Self {
+            pending: HashMap::new(),
+            limiter: ReplyLimiter::new(
+                config.max_offline_replies_per_second,
+                config.max_offline_replies_per_ip_per_second,
+            ),
+            guard: HandshakeGuard::new(
+                config.max_handshake_attempts_per_ip,
+                config.handshake_attempt_window,
+                config.handshake_ban_duration,
+            ),
+            guids: HashMap::new(),
+            recent: RecentDisconnects::new(config.ip_recently_connected_window),
+            filter,
+        }
+    }
+
+    /// Address of another live session already using `guid`, if any.
+    fn duplicate_of(
+        &self,
+        guid: u64,
+        peer: SocketAddr,
+        sessions: &HashMap<SocketAddr, SessionState>,
+    ) -> Option<SocketAddr> {
+        let addr = *self.guids.get(&guid)?;
+        let live = sessions.get(&addr).is_some_and(|s| s.client_guid == guid);
+        (addr != peer && live).then_some(addr)
+    }
+
+    fn track_guid(
+        &mut self,
+        guid: u64,
+        peer: SocketAddr,
+        sessions: &HashMap<SocketAddr, SessionState>,
+    ) {
+        self.guids.insert(guid, peer);
+        if self.guids.len() > 2 * sessions.len() + 64 {
+            self.guids.retain(|_, addr| sessions.contains_key(addr));
+        }
+    }
+}
+
+/// Whether the server address a client echoed in `OpenConnectionRequest2`
+/// could be `local`. A wildcard bind can't tell which of its IPs the client
+/// used, so only the port is compared then.
+fn server_addr_matches(echoed: SocketAddr, local: SocketAddr) -> bool {
+    echoed.port() == local.port()
+        && (local.ip().is_unspecified() || echoed.ip().to_canonical() == local.ip().to_canonical())
+}
+
+/// Our address as `peer` should see it in `OpenConnectionReply2`: in its
+/// own family, so an IPv4 client of a dual-stack socket isn't told an IPv6
+/// wildcard.
+fn reply_server_addr(socket: &impl DatagramSocket, peer: SocketAddr) -> SocketAddr {
+    // Fall back to the peer's address rather than fail the handshake.
+    let Ok(local) = socket.local_addr() else {
+        return peer;
+    };
+    match local.ip() {
+        IpAddr::V6(ip) if peer.is_ipv4() => {
+            let ip = ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED);
+            SocketAddr::from((ip, local.port()))
+        }
+        _ => local,
+    }
 }
 
 pub(super) fn is_offline_packet_id(id: u8) -> bool {
@@ -35,15 +124,47 @@ pub(super) fn is_offline_packet_id(id: u8) -> bool {
     x
 }
 
+/// Whether an offline packet carries the unconnected magic where its id says it should.
+///
+/// Checked before any decoding so junk sharing a first byte with an offline
+/// packet costs nothing and never gets a reply.
+pub(super) fn has_offline_magic(bytes: &[u8]) -> bool {
+    let offset = match bytes.first() {
+        // id + ping time
+        Some(0x01 | 0x02) => 9,
+        Some(0x05 | 0x07) => 1,
+        _ => return false,
+    };
+    bytes.get(offset..offset + DEFAULT_UNCONNECTED_MAGIC.len())
+        == Some(&DEFAULT_UNCONNECTED_MAGIC[..])
+}
+
 use crate::transport::listener::RaknetListenerConfig;
 
+/// The config for a newly accepted session: `config.session_config` if set,
+/// otherwise built from the listener's own per-session fields.
 pub(super) fn server_session_config(config: &RaknetListenerConfig) -> SessionConfig {
+    let base = config
+        .session_config
+        .clone()
+        .unwrap_or_else(|| session_config_from_fields(config));
     SessionConfig {
         role: crate::session::manager::SessionRole::Server,
-        guid: server_guid(),
+        guid: server_guid(config),
+        ..base
+    }
+}
+
+fn session_config_from_fields(config: &RaknetListenerConfig) -> SessionConfig {
+    SessionConfig {
         session_timeout: config.session_timeout,
         session_stale: config.session_stale,
+        keepalive_interval: config.keepalive_interval,
+        #[allow(deprecated)]
+        ping_interval: config.ping_interval,
         max_queued_reliable_bytes: Some(config.max_queued_reliable_bytes),
+        send_queue_limit: config.send_queue_limit,
+        backlog_limit: config.backlog_limit,
         session: crate::session::SessionTunables {
             max_ordering_channels: config.max_ordering_channels,
             ack_queue_capacity: config.ack_queue_capacity,
@@ -51,89 +172,89 @@ pub(super) fn server_session_config(config: &RaknetListenerConfig) -> SessionCon
             reliable_window: config.reliable_window,
             max_split_parts: config.max_split_parts,
             max_concurrent_splits: config.max_concurrent_splits,
+            max_split_bytes: config.max_split_bytes,
+            reorder_limit: config.reorder_limit,
+            min_rto: config.min_rto,
+            max_rto: config.max_rto,
+            max_resends: config.max_resends,
+            fast_retransmit: config.fast_retransmit,
+            ack_policy: config.ack_policy,
         },
+        debug_log: config.debug_log,
         ..Default::default()
     }
 }
 
 #[allow(clippy::too_many_arguments)]
 pub(super) async fn handle_offline(
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     config: &RaknetListenerConfig,
     bytes: &[u8],
     peer: SocketAddr,
-    sessions: &mut std::collections::HashMap<SocketAddr, SessionState>,
-    pending: &mut std::collections::HashMap<SocketAddr, PendingConnection>,
-    new_conn_tx: &mpsc::Sender<(
-        SocketAddr,
-        mpsc::Receiver<Result<crate::transport::ReceivedMessage, crate::RaknetError>>,
-    )>,
-    advertisement: &Arc<RwLock<Vec<u8>>>,
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    offline: &mut OfflineState,
+    new_conn_tx: &mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+    advertisement: &SharedAdvertisement,
+    events: &Events,
+    stats: &ListenerStats,
 ) {
-    let now = Instant::now();
+    let now = mux::now();
+    let reject = |reason| events.emit(ListenerEvent::HandshakeRejected { peer, reason });
+    let pending = &mut offline.pending;
     pending.retain(|_, p| p.expires_at > now);
+    stats.set_pending_handshakes(pending.len());
+
+    if !has_offline_magic(bytes) {
+        stats.record_offline_rejected();
+        return;
+    }
+    if offline.guard.is_banned(peer.ip(), now) {
+        stats.record_handshake_throttled();
+        return;
+    }
+    if !offline.limiter.allow(peer.ip(), now) {
+        stats.record_offline_rate_limited();
+        return;
+    }
 
     let mut slice = bytes;
     let pkt = match RaknetPacket::decode(&mut slice) {
         Ok(p) => p,
-        Err(_) => return,
+        Err(_) => {
+            stats.record_offline_rejected();
+            return;
+        }
     };
+    stats.record_offline_packet();
 
     match pkt {
         RaknetPacket::UnconnectedPing(req) => {
             if req.magic != DEFAULT_UNCONNECTED_MAGIC {
                 return;
             }
-
-            let ad_data = advertisement.read().unwrap().clone();
-            let ad_bytes = if ad_data.is_empty() {
-                None
-            } else {
-                Some(Bytes::from(ad_data))
-            };
-
-            let reply = RaknetPacket::UnconnectedPong(UnconnectedPong {
-                ping_time: req.ping_time,
-                server_guid: server_guid(),
-                magic: DEFAULT_UNCONNECTED_MAGIC,
-                advertisement: crate::protocol::types::Advertisement(ad_bytes),
-            });
-
-            send_unconnected_packet(socket, peer, reply).await;
+            events.emit(ListenerEvent::Pinged { peer });
+            send_pong(socket, config, peer, req.ping_time, advertisement, sessions).await;
         }
         RaknetPacket::UnconnectedPingOpenConnections(req) => {
             if req.magic != DEFAULT_UNCONNECTED_MAGIC {
                 return;
             }
-
-            let ad_data = advertisement.read().unwrap().clone();
-            let ad_bytes = if ad_data.is_empty() {
-                None
-            } else {
-                Some(Bytes::from(ad_data))
-            };
-
-            let reply = RaknetPacket::UnconnectedPong(UnconnectedPong {
-                ping_time: req.ping_time,
-                server_guid: server_guid(),
-                magic: DEFAULT_UNCONNECTED_MAGIC,
-                advertisement: crate::protocol::types::Advertisement(ad_bytes),
-            });
-
-            send_unconnected_packet(socket, peer, reply).await;
+            events.emit(ListenerEvent::Pinged { peer });
+            send_pong(socket, config, peer, req.ping_time, advertisement, sessions).await;
         }
         RaknetPacket::OpenConnectionRequest1(req) => {
             if req.magic != DEFAULT_UNCONNECTED_MAGIC {
                 return;
             }
 
-            if req.protocol_version != RAKNET_PROTOCOL_VERSION {
+            if req.protocol_version != config.protocol_version {
                 let reply =
                     RaknetPacket::IncompatibleProtocolVersion(IncompatibleProtocolVersion {
-                        protocol: RAKNET_PROTOCOL_VERSION,
+                        protocol: config.protocol_version,
                         magic: DEFAULT_UNCONNECTED_MAGIC,
-                        server_guid: server_guid(),
+                        server_guid: server_guid(config),
                     });
+                reject(RejectReason::IncompatibleProtocol);
                 send_unconnected_packet(socket, peer, reply).await;
                 return;
             }
@@ -142,10 +263,43 @@ pub(super) async fn handle_offline(
             let padding_len = req.padding.0;
             let mtu_guess =
                 padding_len + 1 + DEFAULT_UNCONNECTED_MAGIC.len() + 1 + ip_header + UDP_HEADER_SIZE;
-            let mtu_clamped = clamp_mtu(mtu_guess as u16, MINIMUM_MTU_SIZE, config.max_mtu);
+            let mtu_guess = u16::try_from(mtu_guess).unwrap_or(u16::MAX);
+            let Some(mtu_clamped) = negotiate_mtu(mtu_guess, config.max_mtu) else {
+                tracing::debug!(%peer, mtu = mtu_guess, "refusing handshake below minimum MTU");
+                return;
+            };
             let cookie = generate_cookie(peer);
 
+            // A retry from a peer that already has a session is answered
+            // with the session's MTU; the session itself is never touched.
+            if let Some(state) = sessions.get(&peer) {
+                let reply = RaknetPacket::OpenConnectionReply1(OpenConnectionReply1 {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    server_guid: server_guid(config),
+                    cookie: Some(cookie),
+                    mtu: state.managed.mtu() as u16,
+                });
+                send_unconnected_packet(socket, peer, reply).await;
+                return;
+            }
+
+            if !accepting(socket, config, &offline.filter, peer).await {
+                reject(RejectReason::NotAccepting);
+                return;
+            }
+
+            if offline.recent.is_recent(peer.ip(), now) {
+                tracing::debug!(%peer, "reconnect right after a disconnect, refusing");
+                stats.record_handshake_recently_connected();
+                reject(RejectReason::RecentlyConnected);
+                let pkt =
+                    RaknetPacket::IpRecentlyConnected(crate::protocol::packet::IpRecentlyConnected);
+                send_unconnected_packet(socket, peer, pkt).await;
+                return;
+            }
+
             if sessions.len() >= config.max_connections {
+                reject(RejectReason::ServerFull);
                 let reply = RaknetPacket::NoFreeIncomingConnections(
                     crate::protocol::packet::NoFreeIncomingConnections,
                 );
@@ -157,18 +311,36 @@ pub(super) async fn handle_offline(
                 return;
             }
 
+            // Retries of a handshake already pending are the same attempt.
+            if !pending.contains_key(&peer)
+                && offline.guard.record_attempt(peer.ip(), now) == Attempt::Banned
+            {
+                reject(RejectReason::Throttled);
+                ban(socket, peer, stats).await;
+                return;
+            }
+
+            // A retry keeps the cookie already handed out, so a request 2
+            // answering an earlier reply still matches, and the largest probe
+            // that got through.
+            let (cookie, pending_mtu) = match pending.get(&peer) {
+                Some(pc) => (pc.cookie, pc.mtu.max(mtu_clamped)),
+                None => (cookie, mtu_clamped),
+            };
             pending.insert(
                 peer,
                 PendingConnection {
-                    mtu: mtu_clamped,
+                    mtu: pending_mtu,
                     expires_at: now + Duration::from_secs(10),
                     cookie,
+                    protocol_version: req.protocol_version,
                 },
             );
+            stats.set_pending_handshakes(pending.len());
 
             let reply = RaknetPacket::OpenConnectionReply1(OpenConnectionReply1 {
                 magic: DEFAULT_UNCONNECTED_MAGIC,
-                server_guid: server_guid(),
+                server_guid: server_guid(config),
                 cookie: Some(cookie),
                 mtu: mtu_clamped,
             });
@@ -180,61 +352,147 @@ pub(super) async fn handle_offline(
                 return;
             }
 
-            let pc = match pending.remove(&peer) {
-                Some(pc) => pc,
-                None => {
-                    // If the peer is not pending but we have a session, it means the client
-                    // missed the OpenConnectionReply2 and is retrying. We should resend it.
-                    if let Some(state) = sessions.get(&peer) {
-                        let server_addr = socket.local_addr().unwrap_or(peer);
-                        let reply = RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
-                            magic: DEFAULT_UNCONNECTED_MAGIC,
-                            server_guid: server_guid(),
-                            server_addr,
-                            mtu: state.managed.mtu() as u16,
-                            security: true,
-                        });
-                        send_unconnected_packet(socket, peer, reply).await;
+            // The peer already has a session: the client missed our
+            // OpenConnectionReply2 and is retrying, or someone is spoofing
+            // it. Either way the live session is left as it is.
+            if let Some(state) = sessions.get(&peer) {
+                if state.client_guid != req.client_guid {
+                    reject(RejectReason::AlreadyConnected);
+                    send_already_connected(socket, config, peer).await;
+                    return;
+                }
+                let server_addr = reply_server_addr(socket, peer);
+                let reply = RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    server_guid: server_guid(config),
+                    server_addr,
+                    mtu: state.managed.mtu() as u16,
+                    security: true,
+                });
+                send_unconnected_packet(socket, peer, reply).await;
+                return;
+            }
+
+            if !accepting(socket, config, &offline.filter, peer).await {
+                reject(RejectReason::NotAccepting);
+                return;
+            }
+
+            // Only a matching cookie consumes the pending entry; anything
+            // else leaves it for the real client's retry.
+            let pc = match pending.get(&peer) {
+                Some(pc) if req.cookie == Some(pc.cookie) => {
+                    let pc = pending.remove(&peer).unwrap();
+                    stats.set_pending_handshakes(pending.len());
+                    pc
+                }
+                Some(_) => {
+                    if offline.guard.record_attempt(peer.ip(), now) == Attempt::Banned {
+                        reject(RejectReason::Throttled);
+                        ban(socket, peer, stats).await;
                     }
                     return;
                 }
+                None => return,
             };
 
-            if req.cookie != Some(pc.cookie) {
-                return;
+            if let Ok(local) = socket.local_addr()
+                && !server_addr_matches(req.server_addr, local)
+            {
+                tracing::debug!(
+                    %peer,
+                    echoed = %req.server_addr,
+                    %local,
+                    strict = config.strict_server_addr,
+                    "OpenConnectionRequest2 names a different server address"
+                );
+                if config.strict_server_addr {
+                    reject(RejectReason::ServerAddrMismatch);
+                    let reply = RaknetPacket::ConnectionRequestFailed(ConnectionRequestFailed {
+                        magic: DEFAULT_UNCONNECTED_MAGIC,
+                        server_guid: server_guid(config),
+                    });
+                    send_unconnected_packet(socket, peer, reply).await;
+                    return;
+                }
             }
 
-            if req.mtu < MINIMUM_MTU_SIZE || req.mtu > MAXIMUM_MTU_SIZE {
-                send_already_connected(socket, peer).await;
-                return;
+            match offline.filter.check(peer, req.client_guid) {
+                FilterDecision::Accept => {}
+                FilterDecision::Drop => {
+                    tracing::debug!(%peer, guid = req.client_guid, "handshake dropped by filter");
+                    stats.record_handshake_filtered();
+                    reject(RejectReason::Filtered);
+                    return;
+                }
+                FilterDecision::Reject(rejection) => {
+                    tracing::debug!(%peer, guid = req.client_guid, ?rejection, "handshake rejected by filter");
+                    stats.record_handshake_filtered();
+                    reject(RejectReason::Filtered);
+                    send_rejection(socket, config, peer, rejection).await;
+                    return;
+                }
             }
 
-            let mtu_final = pc.mtu.min(req.mtu);
+            // pc.mtu was already negotiated against our limit in OpenConnectionRequest1.
+            let Some(mtu_final) = negotiate_mtu(req.mtu, pc.mtu) else {
+                tracing::debug!(%peer, mtu = req.mtu, "refusing handshake below minimum MTU");
+                return;
+            };
+
+            if let Some(existing) = offline.duplicate_of(req.client_guid, peer, sessions) {
+                if !config.connection_migration {
+                    tracing::debug!(%peer, %existing, guid = req.client_guid, "guid already connected");
+                    reject(RejectReason::AlreadyConnected);
+                    send_already_connected(socket, config, peer).await;
+                    return;
+                }
+                tracing::debug!(%peer, %existing, guid = req.client_guid, "migrating connection");
+                close_session(
+                    socket,
+                    existing,
+                    DisconnectReason::Disconnected,
+                    sessions,
+                    events,
+                    stats,
+                )
+                .await;
+            }
 
             let (tx, rx) =
                 mpsc::channel::<Result<crate::transport::ReceivedMessage, crate::RaknetError>>(128);
             let sess_config = server_session_config(config);
             let managed = ManagedSession::with_config(peer, mtu_final as usize, now, sess_config);
+            tracing::debug!(%peer, mtu = mtu_final, guid = req.client_guid, "session created");
+            stats.register(peer, managed.stats());
             sessions.insert(
                 peer,
                 SessionState {
                     managed,
+                    client_guid: req.client_guid,
+                    protocol_version: pc.protocol_version,
                     to_app: tx,
                     pending_rx: Some(rx),
                     announced: false,
+                    bad_datagram_streak: 0,
+                    bad_datagrams: 0,
+                    next_deadline: None,
+                    flush_waiters: Default::default(),
+                    created_at: now,
+                    kicked: false,
                 },
             );
+            offline.track_guid(req.client_guid, peer, sessions);
+            offline.guard.record_success(peer.ip());
             if let Some(state) = sessions.get_mut(&peer) {
-                maybe_announce_connection(peer, state, new_conn_tx).await;
+                maybe_announce_connection(peer, state, new_conn_tx, events).await;
             }
 
-            // Fallback to peer address if local address cannot be determined.
-            // This is a best-effort approach to avoid crashing.
-            let server_addr = socket.local_addr().unwrap_or(peer);
+            let server_addr = reply_server_addr(socket, peer);
 
             let reply = RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
                 magic: DEFAULT_UNCONNECTED_MAGIC,
-                server_guid: server_guid(),
+                server_guid: server_guid(config),
                 server_addr,
                 mtu: mtu_final,
                 security: true,
@@ -246,10 +504,6 @@ pub(super) async fn handle_offline(
     }
 }
 
-fn clamp_mtu(v: u16, min: u16, max: u16) -> u16 {
-    v.clamp(min, max)
-}
-
 fn generate_cookie(peer: SocketAddr) -> u32 {
     use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -268,7 +522,43 @@ fn generate_cookie(peer: SocketAddr) -> u32 {
     cookie
 }
 
-fn server_guid() -> u64 {
+/// Answer a ping from `peer`, unless the advertisement callback says not to.
+async fn send_pong(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    peer: SocketAddr,
+    ping_time: RaknetTime,
+    advertisement: &SharedAdvertisement,
+    sessions: &HashMap<SocketAddr, SessionState>,
+) {
+    // Peers still in the handshake aren't players yet.
+    let players = || sessions.values().filter(|state| state.announced).count();
+    let Some(ad_bytes) = advertisement.pong_for(
+        peer,
+        ping_time,
+        server_guid(config),
+        players,
+        config.max_connections,
+    ) else {
+        tracing::trace!(%peer, "pong suppressed by advertisement callback");
+        return;
+    };
+    let reply = RaknetPacket::UnconnectedPong(UnconnectedPong {
+        ping_time,
+        server_guid: server_guid(config),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+        advertisement: crate::protocol::types::Advertisement(ad_bytes),
+    });
+    send_unconnected_packet(socket, peer, reply).await;
+}
+
+/// The GUID this listener answers with: `config.server_guid`, or one picked
+/// once per process.
+pub(super) fn server_guid(config: &RaknetListenerConfig) -> u64 {
+    config.server_guid.unwrap_or_else(default_server_guid)
+}
+
+fn default_server_guid() -> u64 {
     static GUID: OnceLock<u64> = OnceLock::new();
     *GUID.get_or_init(|| {
         use std::time::{SystemTime, UNIX_EPOCH};
@@ -279,17 +569,332 @@ fn server_guid() -> u64 {
     })
 }
 
-async fn send_unconnected_packet(socket: &UdpSocket, peer: SocketAddr, pkt: RaknetPacket) {
+async fn send_unconnected_packet(
+    socket: &impl DatagramSocket,
+    peer: SocketAddr,
+    pkt: RaknetPacket,
+) {
     let mut buf = BytesMut::new();
     if pkt.encode(&mut buf).is_ok() {
         let _ = socket.send_to(&buf, peer).await;
     }
 }
 
-async fn send_already_connected(socket: &UdpSocket, peer: SocketAddr) {
+/// Tell a peer that just crossed the handshake threshold why it is about to
+/// be ignored. Sent once per ban; nothing else is answered until it lifts.
+async fn ban(socket: &impl DatagramSocket, peer: SocketAddr, stats: &ListenerStats) {
+    tracing::debug!(%peer, "too many handshake attempts, ignoring ip");
+    stats.record_handshake_ban();
+    let pkt = RaknetPacket::IpRecentlyConnected(crate::protocol::packet::IpRecentlyConnected);
+    send_unconnected_packet(socket, peer, pkt).await;
+}
+
+async fn send_already_connected(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    peer: SocketAddr,
+) {
     let pkt = RaknetPacket::AlreadyConnected(AlreadyConnected {
         magic: DEFAULT_UNCONNECTED_MAGIC,
-        server_guid: server_guid(),
+        server_guid: server_guid(config),
     });
     send_unconnected_packet(socket, peer, pkt).await;
 }
+
+/// Whether the listener is taking handshakes; if not, `peer` is turned away
+/// according to `pause_policy`.
+async fn accepting(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    filter: &SharedFilter,
+    peer: SocketAddr,
+) -> bool {
+    if filter.is_accepting() {
+        return true;
+    }
+    tracing::debug!(%peer, policy = ?config.pause_policy, "handshake while not accepting");
+    if config.pause_policy == PausePolicy::Reject {
+        send_rejection(socket, config, peer, Rejection::Failed).await;
+    }
+    false
+}
+
+async fn send_rejection(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    peer: SocketAddr,
+    rejection: Rejection,
+) {
+    let pkt = match rejection {
+        Rejection::Failed => RaknetPacket::ConnectionRequestFailed(ConnectionRequestFailed {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: server_guid(config),
+        }),
+        Rejection::Banned => {
+            // Vanilla RakNet's body: magic, then the server GUID.
+            let mut payload = BytesMut::new();
+            payload.put_slice(&DEFAULT_UNCONNECTED_MAGIC);
+            payload.put_u64(server_guid(config));
+            RaknetPacket::ConnectionBanned(ConnectionBanned {
+                payload: payload.freeze(),
+            })
+        }
+    };
+    send_unconnected_packet(socket, peer, pkt).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::constants::{IPV4_HEADER_SIZE, MINIMUM_MTU_SIZE, RAKNET_PROTOCOL_VERSION};
+    use crate::protocol::packet::{
+        OpenConnectionRequest1, OpenConnectionRequest2, UnconnectedPing,
+        UnconnectedPingOpenConnections,
+    };
+    use crate::protocol::types::{EoBPadding, RaknetTime};
+    use tokio::net::UdpSocket;
+
+    /// Everything `handle_offline` touches, wired to a real loopback client.
+    struct Harness {
+        server: UdpSocket,
+        client: UdpSocket,
+        config: RaknetListenerConfig,
+        sessions: HashMap<SocketAddr, SessionState>,
+        offline: OfflineState,
+        new_conn_tx: mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+        _new_conn_rx: mpsc::Receiver<Result<NewConnection, crate::RaknetError>>,
+        advertisement: SharedAdvertisement,
+        events: Events,
+        stats: ListenerStats,
+    }
+
+    impl Harness {
+        async fn new() -> Self {
+            let config = RaknetListenerConfig {
+                max_offline_replies_per_second: u32::MAX,
+                max_offline_replies_per_ip_per_second: u32::MAX,
+                ..Default::default()
+            };
+            let (new_conn_tx, _new_conn_rx) = mpsc::channel(4);
+            Self {
+                server: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                client: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                offline: OfflineState::new(&config, Default::default()),
+                config,
+                sessions: HashMap::new(),
+                new_conn_tx,
+                _new_conn_rx,
+                advertisement: SharedAdvertisement::default(),
+                events: Events::default(),
+                stats: ListenerStats::default(),
+            }
+        }
+
+        fn peer(&self) -> SocketAddr {
+            self.client.local_addr().unwrap()
+        }
+
+        async fn feed(&mut self, bytes: &[u8]) {
+            let peer = self.peer();
+            handle_offline(
+                &self.server,
+                &self.config,
+                bytes,
+                peer,
+                &mut self.sessions,
+                &mut self.offline,
+                &self.new_conn_tx,
+                &self.advertisement,
+                &self.events,
+                &self.stats,
+            )
+            .await;
+        }
+
+        /// The first datagram the client has received, if any.
+        async fn first_reply(&self) -> Option<RaknetPacket> {
+            let mut buf = [0u8; 2048];
+            let recv = self.client.recv_from(&mut buf);
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), recv)
+                .await
+                .ok()?
+                .ok()?;
+            RaknetPacket::decode(&mut &buf[..len]).ok()
+        }
+    }
+
+    fn encode(pkt: RaknetPacket) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        pkt.encode(&mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    const COOKIE: u32 = 0x0012_3456;
+
+    /// One well-formed packet per offline id. The OCR1 is sized just under
+    /// the minimum MTU so no prefix of it is a handshake we would accept.
+    fn full_packets(server: SocketAddr) -> Vec<Vec<u8>> {
+        let ping_time = RaknetTime(42);
+        vec![
+            encode(RaknetPacket::UnconnectedPing(UnconnectedPing {
+                ping_time,
+                magic: DEFAULT_UNCONNECTED_MAGIC,
+            })),
+            encode(RaknetPacket::UnconnectedPingOpenConnections(
+                UnconnectedPingOpenConnections {
+                    ping_time,
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                },
+            )),
+            encode(RaknetPacket::OpenConnectionRequest1(
+                OpenConnectionRequest1 {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    protocol_version: RAKNET_PROTOCOL_VERSION,
+                    padding: EoBPadding(
+                        MINIMUM_MTU_SIZE as usize - IPV4_HEADER_SIZE - UDP_HEADER_SIZE - 19,
+                    ),
+                },
+            )),
+            encode(RaknetPacket::OpenConnectionRequest2(
+                OpenConnectionRequest2 {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    cookie: Some(COOKIE),
+                    client_proof: true,
+                    server_addr: server,
+                    mtu: 1400,
+                    client_guid: 7,
+                },
+            )),
+        ]
+    }
+
+    #[tokio::test]
+    async fn every_truncation_of_every_offline_packet_is_ignored() {
+        let mut h = Harness::new().await;
+        let server = h.server.local_addr().unwrap();
+        let expires_at = Instant::now() + Duration::from_secs(60);
+        h.offline.pending.insert(
+            h.peer(),
+            PendingConnection {
+                mtu: 1400,
+                expires_at,
+                cookie: COOKIE,
+                protocol_version: RAKNET_PROTOCOL_VERSION,
+            },
+        );
+
+        for full in full_packets(server) {
+            for len in 0..full.len() {
+                h.feed(&full[..len]).await;
+
+                assert!(h.sessions.is_empty(), "id {:#04x} len {len}", full[0]);
+                assert!(h.offline.guids.is_empty(), "id {:#04x} len {len}", full[0]);
+                assert_eq!(h.offline.pending.len(), 1, "id {:#04x} len {len}", full[0]);
+                assert_eq!(h.offline.pending[&h.peer()].cookie, COOKIE);
+            }
+        }
+
+        // Replies arrive in order on loopback, so a pong first means nothing
+        // above was answered.
+        let ping = full_packets(server).remove(0);
+        h.feed(&ping).await;
+        assert!(matches!(
+            h.first_reply().await,
+            Some(RaknetPacket::UnconnectedPong(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_cookie_does_not_consume_pending_handshake() {
+        let mut h = Harness::new().await;
+        let server = h.server.local_addr().unwrap();
+        h.offline.pending.insert(
+            h.peer(),
+            PendingConnection {
+                mtu: 1400,
+                expires_at: Instant::now() + Duration::from_secs(60),
+                cookie: COOKIE + 1,
+                protocol_version: RAKNET_PROTOCOL_VERSION,
+            },
+        );
+
+        let ocr2 = full_packets(server).remove(3);
+        h.feed(&ocr2).await;
+        assert!(h.sessions.is_empty());
+        assert!(h.offline.pending.contains_key(&h.peer()));
+    }
+
+    #[tokio::test]
+    async fn retried_request1_keeps_cookie_and_largest_probe() {
+        let mut h = Harness::new().await;
+        let probe = |mtu: usize| {
+            encode(RaknetPacket::OpenConnectionRequest1(
+                OpenConnectionRequest1 {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    protocol_version: RAKNET_PROTOCOL_VERSION,
+                    padding: EoBPadding(mtu - IPV4_HEADER_SIZE - UDP_HEADER_SIZE - 18),
+                },
+            ))
+        };
+
+        h.feed(&probe(1400)).await;
+        let first = h.offline.pending[&h.peer()].cookie;
+        h.feed(&probe(1200)).await;
+        let pending = &h.offline.pending[&h.peer()];
+        assert_eq!(pending.cookie, first);
+        assert_eq!(pending.mtu, 1400);
+    }
+
+    #[test]
+    fn echoed_server_addr_must_match_our_bind() {
+        let local: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        assert!(server_addr_matches(local, local));
+        assert!(!server_addr_matches(
+            "10.0.0.1:19133".parse().unwrap(),
+            local
+        ));
+        assert!(!server_addr_matches(
+            "10.0.0.2:19132".parse().unwrap(),
+            local
+        ));
+        assert!(server_addr_matches(
+            "[::ffff:10.0.0.1]:19132".parse().unwrap(),
+            local
+        ));
+    }
+
+    #[test]
+    fn unspecified_bind_only_compares_the_port() {
+        let local: SocketAddr = "0.0.0.0:19132".parse().unwrap();
+        assert!(server_addr_matches(
+            "203.0.113.9:19132".parse().unwrap(),
+            local
+        ));
+        assert!(!server_addr_matches(
+            "203.0.113.9:19133".parse().unwrap(),
+            local
+        ));
+    }
+
+    #[test]
+    fn magic_must_sit_at_the_packet_specific_offset() {
+        let mut ping = vec![0x01];
+        ping.extend_from_slice(&[0u8; 8]);
+        ping.extend_from_slice(&DEFAULT_UNCONNECTED_MAGIC);
+        assert!(has_offline_magic(&ping));
+
+        let mut ocr1 = vec![0x05];
+        ocr1.extend_from_slice(&DEFAULT_UNCONNECTED_MAGIC);
+        ocr1.push(RAKNET_PROTOCOL_VERSION);
+        assert!(has_offline_magic(&ocr1));
+
+        // Magic right after the id is wrong for a ping.
+        let mut misplaced = vec![0x01];
+        misplaced.extend_from_slice(&DEFAULT_UNCONNECTED_MAGIC);
+        assert!(!has_offline_magic(&misplaced));
+
+        assert!(!has_offline_magic(&[0x01]));
+        assert!(!has_offline_magic(&ping[..20]));
+        assert!(!has_offline_magic(&[]));
+    }
+}
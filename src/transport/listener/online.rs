@@ -1,248 +1,567 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::time::Instant;
 
-use tokio::net::UdpSocket;
+use bytes::Bytes;
 use tokio::sync::mpsc;
+use tokio::time;
 
-use crate::protocol::{datagram::Datagram, packet::RaknetPacket};
-use crate::session::manager::{ConnectionState, ManagedSession};
-use crate::transport::listener_conn::SessionState;
-use crate::transport::mux::flush_managed;
-use bytes::BufMut;
-
-use super::offline::{
-    PendingConnection, handle_offline, is_offline_packet_id, server_session_config,
+use crate::protocol::{
+    constants::{IPV4_HEADER_SIZE, UDP_HEADER_SIZE},
+    datagram::Datagram,
+    packet::DecodeError,
+    state::DisconnectReason,
 };
+use crate::session::manager::ConnectionState;
+use crate::transport::listener_conn::{NewConnection, SessionState};
+use crate::transport::mux::{self, AppDelivery, flush_managed};
+use crate::transport::socket::DatagramSocket;
+use crate::transport::{ControlMsg, KickTarget, Outbound, OutboundMsg};
 
-use std::sync::{Arc, RwLock};
+use super::offline::{OfflineState, handle_offline, has_offline_magic, is_offline_packet_id};
 
 use crate::transport::listener::RaknetListenerConfig;
 
+use super::ListenerStats;
+use super::advertisement::SharedAdvertisement;
+use super::events::{Events, ListenerEvent};
+use super::peers::PeerInfo;
+use super::rate_limit::RecentDisconnects;
+use super::schedule::TickSchedule;
+
+/// What became of a datagram handed to an established session.
+enum Incoming {
+    Handled,
+    /// The session reached `Closed` and should be dropped.
+    Closed,
+    /// A well-formed offline packet, e.g. a handshake retry.
+    Offline,
+}
+
 #[allow(clippy::too_many_arguments)]
 pub(super) async fn dispatch_datagram(
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     config: &RaknetListenerConfig,
-    bytes: &[u8],
+    bytes: Bytes,
     peer: SocketAddr,
     sessions: &mut HashMap<SocketAddr, SessionState>,
-    pending: &mut HashMap<SocketAddr, PendingConnection>,
-    new_conn_tx: &mpsc::Sender<(
-        SocketAddr,
-        mpsc::Receiver<Result<crate::transport::ReceivedMessage, crate::RaknetError>>,
-    )>,
-    advertisement: &Arc<RwLock<Vec<u8>>>,
+    offline: &mut OfflineState,
+    new_conn_tx: &mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+    advertisement: &SharedAdvertisement,
+    events: &Events,
+    stats: &ListenerStats,
 ) {
-    if sessions.contains_key(&peer) {
-        if !handle_incoming_udp(socket, config, bytes, peer, sessions, pending, new_conn_tx).await {
-            // If decoding failed, check if it is an offline packet (e.g. handshake retry).
-            // If so, don't kill the session; let handle_offline deal with it.
-            if is_offline_packet_id(bytes[0]) {
-                handle_offline(
-                    socket,
-                    config,
-                    bytes,
-                    peer,
-                    sessions,
-                    pending,
-                    new_conn_tx,
-                    advertisement,
-                )
-                .await;
-            } else {
-                // Garbage or unexpected packet; drop session.
-                sessions.remove(&peer);
-                handle_offline(
-                    socket,
-                    config,
-                    bytes,
-                    peer,
-                    sessions,
-                    pending,
-                    new_conn_tx,
-                    advertisement,
-                )
-                .await;
-            }
-        }
+    if bytes.is_empty() {
         return;
     }
 
-    if bytes.is_empty() {
+    let Some(state) = sessions.get_mut(&peer) else {
+        // Sessions are only ever created by the handshake; anything but an
+        // offline packet from an unknown peer is ignored.
+        if is_offline_packet_id(bytes[0]) {
+            handle_offline(
+                socket,
+                config,
+                &bytes,
+                peer,
+                sessions,
+                offline,
+                new_conn_tx,
+                advertisement,
+                events,
+                stats,
+            )
+            .await;
+        }
         return;
+    };
+
+    match handle_incoming_udp(
+        socket,
+        config,
+        bytes.clone(),
+        peer,
+        state,
+        new_conn_tx,
+        events,
+        stats,
+    )
+    .await
+    {
+        Incoming::Handled => {}
+        Incoming::Closed => {
+            let state = sessions.remove(&peer);
+            if let Some(state) = &state {
+                emit_disconnected(events, peer, state);
+            }
+            stats.unregister(&peer);
+            if !state.is_some_and(|state| state.kicked) {
+                offline.recent.record(peer.ip(), mux::now());
+            }
+        }
+        Incoming::Offline => {
+            handle_offline(
+                socket,
+                config,
+                &bytes,
+                peer,
+                sessions,
+                offline,
+                new_conn_tx,
+                advertisement,
+                events,
+                stats,
+            )
+            .await;
+        }
     }
+}
 
-    if is_offline_packet_id(bytes[0]) {
-        handle_offline(
-            socket,
-            config,
-            bytes,
-            peer,
-            sessions,
-            pending,
-            new_conn_tx,
-            advertisement,
-        )
-        .await;
-    } else {
-        // Unexpected packet from unknown peer; ignore.
+/// Queue `first` and whatever else is already waiting on `outbound_rx`, up
+/// to `mux::MAX_OUTBOUND_BATCH`, then flush each peer they were for once,
+/// so small messages sent back to back share datagrams.
+#[tracing::instrument(skip_all, level = "trace")]
+pub(super) async fn handle_outgoing_msgs(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    first: Outbound,
+    outbound_rx: &mut mpsc::Receiver<Outbound>,
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    schedule: &mut TickSchedule,
+) {
+    let mut dirty = Vec::new();
+    let mut next = Some(first);
+    let mut taken = 0;
+    while let Some(out) = next.take() {
+        if let Some(peer) = queue_outgoing_msg(socket, config, out, sessions, schedule).await
+            && !dirty.contains(&peer)
+        {
+            dirty.push(peer);
+        }
+        taken += 1;
+        if taken < mux::MAX_OUTBOUND_BATCH {
+            next = outbound_rx.try_recv().ok();
+        }
+    }
+
+    let now = mux::now();
+    for peer in dirty {
+        if let Some(state) = sessions.get_mut(&peer) {
+            flush_managed(&mut state.managed, socket, peer, now, false).await;
+            state.flush_waiters.notify(&state.managed);
+        }
     }
 }
 
-#[tracing::instrument(skip(socket, sessions), level = "trace")]
-pub(super) async fn handle_outgoing_msg(
-    socket: &UdpSocket,
-    mtu: usize,
-    msg: crate::transport::OutboundMsg,
+/// Queue one outbound item, returning the peer whose session now needs a
+/// flush. Broadcasts are flushed here.
+async fn queue_outgoing_msg(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    out: Outbound,
     sessions: &mut HashMap<SocketAddr, SessionState>,
+    schedule: &mut TickSchedule,
+) -> Option<SocketAddr> {
+    let Some(peer) = out.peer() else {
+        if let Outbound::Broadcast { msg, except, done } = out {
+            let sent = broadcast(socket, msg, &except, sessions, schedule).await;
+            let _ = done.send(sent);
+        }
+        return None;
+    };
+    schedule.mark_dirty(peer);
+    // Sessions only come from the handshake; a send for a peer that is gone
+    // (disconnected, timed out) has nowhere to go.
+    let Some(state) = sessions.get_mut(&peer) else {
+        tracing::trace!("outbound for unknown peer dropped");
+        return None;
+    };
+
+    let now = mux::now();
+    match out {
+        Outbound::Receipt { msg, done } => {
+            let _ = msg.queue_admitted(&mut state.managed, Some(done), now);
+        }
+        Outbound::Flush { done, .. } => state.flush_waiters.push(&state.managed, done),
+        Outbound::Disconnect { reason, done, .. } => {
+            let deadline = now + config.shutdown_timeout;
+            let _ = state.managed.disconnect_gracefully(reason, deadline);
+            state.flush_waiters.push(&state.managed, done);
+        }
+        out => {
+            for msg in out.into_messages() {
+                let _ = msg.queue_admitted(&mut state.managed, None, now);
+            }
+        }
+    }
+
+    tracing::trace!("outbound queued");
+    Some(peer)
+}
+
+/// Queue `msg` for every connected peer not in `except` and flush it,
+/// returning how many peers that was. A peer whose send queue is full is
+/// skipped.
+async fn broadcast(
+    socket: &impl DatagramSocket,
+    msg: OutboundMsg,
+    except: &[SocketAddr],
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    schedule: &mut TickSchedule,
+) -> usize {
+    let now = mux::now();
+    let mut sent = 0;
+    for (&peer, state) in sessions.iter_mut() {
+        if !state.announced || except.contains(&peer) {
+            continue;
+        }
+        let msg = OutboundMsg {
+            peer,
+            ..msg.clone()
+        };
+        if msg.queue(&mut state.managed, None, now).is_err() {
+            continue;
+        }
+        sent += 1;
+        flush_managed(&mut state.managed, socket, peer, now, false).await;
+        state.flush_waiters.notify(&state.managed);
+        schedule.mark_dirty(peer);
+    }
+    tracing::trace!(sent, "broadcast queued");
+    sent
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    skip(socket, config, sessions, schedule, recent, events, stats),
+    level = "trace"
+)]
+pub(super) async fn handle_control_msg(
+    socket: &impl DatagramSocket,
     config: &RaknetListenerConfig,
+    msg: ControlMsg,
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    schedule: &mut TickSchedule,
+    recent: &mut RecentDisconnects,
+    events: &Events,
+    stats: &ListenerStats,
 ) {
-    let now = Instant::now();
-    let state = sessions.entry(msg.peer).or_insert_with(|| {
-        let (tx, rx) = mpsc::channel(128);
-        let sess_config = server_session_config(config);
-        SessionState {
-            managed: ManagedSession::with_config(msg.peer, mtu, now, sess_config),
-            to_app: tx,
-            pending_rx: Some(rx),
-            announced: false,
+    match msg {
+        ControlMsg::Disconnect { peer, reason } => {
+            let kicked = sessions.get(&peer).is_some_and(|state| state.kicked);
+            if close_session(socket, peer, reason, sessions, events, stats).await && !kicked {
+                recent.record(peer.ip(), mux::now());
+            }
+        }
+        ControlMsg::Flush { peer } => {
+            if let Some(state) = sessions.get_mut(&peer) {
+                flush_managed(&mut state.managed, socket, peer, mux::now(), true).await;
+            }
+        }
+        ControlMsg::Kick {
+            target,
+            reason,
+            done,
+        } => {
+            let peer = match target {
+                KickTarget::Addr(peer) => Some(peer),
+                KickTarget::Guid(guid) => sessions
+                    .iter()
+                    .find(|(_, state)| state.client_guid == guid)
+                    .map(|(peer, _)| *peer),
+            };
+            // Closed like `RaknetStream::disconnect`: `tick_sessions` drops
+            // the session once the notification is ACKed or the deadline
+            // passes.
+            let session = peer.and_then(|peer| Some((peer, sessions.get_mut(&peer)?)));
+            let kicked = session.is_some();
+            if let Some((peer, state)) = session {
+                let now = mux::now();
+                let deadline = now + config.shutdown_timeout;
+                let _ = state.managed.disconnect_gracefully(reason, deadline);
+                flush_managed(&mut state.managed, socket, peer, now, false).await;
+                schedule.mark_dirty(peer);
+                if !state.kicked {
+                    state.kicked = true;
+                    recent.record(peer.ip(), now);
+                }
+            }
+            let _ = done.send(kicked);
         }
-    });
+        ControlMsg::Peers { done } => {
+            // Sessions still in the handshake haven't connected yet.
+            let now = mux::now();
+            let peers = sessions
+                .iter()
+                .filter(|(_, state)| state.announced)
+                .map(|(peer, state)| PeerInfo::of(*peer, state, now))
+                .collect();
+            let _ = done.send(peers);
+        }
+    }
+}
 
-    let _ = state
-        .managed
-        .queue_app_packet(msg.packet, msg.reliability, msg.channel, msg.priority);
+/// Notify `peer` with a `DisconnectionNotification` and drop its session.
+/// Returns whether there was one.
+pub(super) async fn close_session(
+    socket: &impl DatagramSocket,
+    peer: SocketAddr,
+    reason: DisconnectReason,
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    events: &Events,
+    stats: &ListenerStats,
+) -> bool {
+    let Some(mut state) = sessions.remove(&peer) else {
+        return false;
+    };
+    stats.unregister(&peer);
 
-    tracing::trace!("outbound queued");
-    flush_managed(&mut state.managed, socket, msg.peer, now, false).await;
+    let _ = state.managed.send_disconnect(reason);
+    mux::flush_final(&mut state.managed, socket, peer, mux::now()).await;
+
+    // Never block the muxer on a slow reader; if the app channel is full it
+    // still observes the close when `to_app` drops.
+    if state.announced {
+        events.emit(ListenerEvent::Disconnected { peer, reason });
+        let _ = state
+            .to_app
+            .try_send(Err(crate::RaknetError::Disconnected(reason)));
+    }
+    true
+}
+
+/// Send every session a `DisconnectionNotification` with `reason` and keep
+/// them serviced until each has been ACKed (or has closed) or `deadline`
+/// passes. Each announced application is then handed `error()` and all
+/// sessions are dropped.
+///
+/// Only ACKs and retransmits happen meanwhile: data still arriving from the
+/// peers is discarded, and datagrams from anyone else are ignored.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn drain_sessions(
+    socket: &impl DatagramSocket,
+    config: &RaknetListenerConfig,
+    sessions: &mut HashMap<SocketAddr, SessionState>,
+    events: &Events,
+    stats: &ListenerStats,
+    reason: DisconnectReason,
+    deadline: time::Instant,
+    error: impl Fn() -> crate::RaknetError,
+) {
+    let now = mux::now();
+    for (peer, state) in sessions.iter_mut() {
+        let _ = state.managed.send_disconnect(reason);
+        flush_managed(&mut state.managed, socket, *peer, now, false).await;
+    }
+
+    let settled = |state: &SessionState| {
+        state.managed.state() == ConnectionState::Closed || state.managed.is_drained()
+    };
+    let mut buf = vec![0u8; (config.max_mtu as usize + UDP_HEADER_SIZE + 64).max(2048)];
+    let mut tick = mux::new_tick_interval();
+    while !sessions.values().all(settled) {
+        tokio::select! {
+            _ = time::sleep_until(deadline) => {
+                tracing::debug!("shutdown deadline reached with sessions still draining");
+                break;
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, peer) = match res {
+                    Ok(v) => v,
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                    Err(_) => break,
+                };
+                let Some(state) = sessions.get_mut(&peer) else {
+                    continue;
+                };
+                let mut slice = &buf[..len];
+                let Ok(dgram) =
+                    Datagram::decode_with_limit(&mut slice, config.max_frames_per_datagram)
+                else {
+                    continue;
+                };
+                let now = mux::now();
+                let _ = state.managed.handle_datagram_with(dgram, now, |_| {});
+                flush_managed(&mut state.managed, socket, peer, now, false).await;
+            }
+            _ = tick.tick() => {
+                let now = mux::now();
+                for (peer, state) in sessions.iter_mut() {
+                    if !settled(state) {
+                        flush_managed(&mut state.managed, socket, *peer, now, true).await;
+                    }
+                }
+            }
+        }
+    }
+
+    let now = mux::now();
+    for (peer, mut state) in sessions.drain() {
+        mux::flush_final(&mut state.managed, socket, peer, now).await;
+        stats.unregister(&peer);
+        if state.announced {
+            events.emit(ListenerEvent::Disconnected { peer, reason });
+            let _ = state.to_app.try_send(Err(error()));
+        }
+    }
 }
 
-#[tracing::instrument(skip(socket, sessions), level = "trace")]
+#[tracing::instrument(
+    skip(socket, sessions, schedule, recent, events, stats),
+    level = "trace"
+)]
 pub(super) async fn tick_sessions(
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     sessions: &mut HashMap<SocketAddr, SessionState>,
+    schedule: &mut TickSchedule,
+    recent: &mut RecentDisconnects,
+    events: &Events,
+    stats: &ListenerStats,
 ) {
-    let now = Instant::now();
+    let now = mux::now();
+    recent.expire(now);
     let mut dead = Vec::new();
 
-    for (&peer, state) in sessions.iter_mut() {
+    for peer in schedule.take_due(now, sessions) {
+        let Some(state) = sessions.get_mut(&peer) else {
+            continue;
+        };
         flush_managed(&mut state.managed, socket, peer, now, true).await;
+        let mut delivery = AppDelivery::new(&state.to_app, state.managed.stats().clone());
+        state
+            .managed
+            .deliver_released_with(now, |pkt| delivery.push(pkt));
+        delivery.finish().await;
 
         if matches!(state.managed.state(), ConnectionState::Closed) {
+            mux::flush_final(&mut state.managed, socket, peer, now).await;
             // Inform app of disconnection if it was connected/announced
             if state.announced {
-                if let Some(reason) = state.managed.last_disconnect_reason() {
-                    let _ = state
-                        .to_app
-                        .send(Err(crate::RaknetError::Disconnected(reason)))
-                        .await;
-                } else {
-                    let _ = state
-                        .to_app
-                        .send(Err(crate::RaknetError::ConnectionClosed))
-                        .await;
-                }
+                let _ = state.to_app.send(Err(state.managed.close_error())).await;
             }
+            emit_disconnected(events, peer, state);
             dead.push(peer);
+            continue;
         }
+
+        state.flush_waiters.notify(&state.managed);
+
+        // The popped heap entry is gone; force a fresh one even if the
+        // deadline happens to be unchanged.
+        state.next_deadline = None;
+        let at = state.managed.next_deadline(now);
+        schedule.schedule(peer, state, at);
     }
 
     for peer in dead {
-        sessions.remove(&peer);
+        let kicked = sessions.remove(&peer).is_some_and(|state| state.kicked);
+        stats.unregister(&peer);
+        // A kick started the recently-connected window already.
+        if !kicked {
+            recent.record(peer.ip(), now);
+        }
     }
 }
 
-#[tracing::instrument(skip(socket, sessions, _pending, new_conn_tx), level = "trace")]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(socket, state, new_conn_tx, events, stats), level = "trace")]
 async fn handle_incoming_udp(
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     config: &RaknetListenerConfig,
-    bytes: &[u8],
+    bytes: Bytes,
     peer: SocketAddr,
-    sessions: &mut HashMap<SocketAddr, SessionState>,
-    _pending: &mut HashMap<SocketAddr, PendingConnection>,
-    new_conn_tx: &mpsc::Sender<(
-        SocketAddr,
-        mpsc::Receiver<Result<crate::transport::ReceivedMessage, crate::RaknetError>>,
-    )>,
-) -> bool {
-    let mut slice = bytes;
-    let dgram = match Datagram::decode(&mut slice) {
-        Ok(d) => d,
-        Err(e) => {
-            tracing::debug!(error = ?e, "failed to decode datagram");
-            return false;
-        }
-    };
-    let now = Instant::now();
-    let state = sessions.entry(peer).or_insert_with(|| {
-        tracing::debug!(mtu = config.max_mtu, "create_session");
-        let (tx, rx) = mpsc::channel(128);
-        let sess_config = server_session_config(config);
-        let sess = ManagedSession::with_config(peer, config.max_mtu as usize, now, sess_config);
-        SessionState {
-            managed: sess,
-            to_app: tx,
-            pending_rx: Some(rx),
-            announced: false,
-        }
-    });
+    state: &mut SessionState,
+    new_conn_tx: &mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+    events: &Events,
+    stats: &ListenerStats,
+) -> Incoming {
+    if exceeds_mtu(bytes.len(), state.managed.mtu()) {
+        // Our framing never produces this; the peer is broken or probing us.
+        tracing::debug!(
+            len = bytes.len(),
+            mtu = state.managed.mtu(),
+            "oversized_datagram"
+        );
+        stats.record_oversized_datagram();
+        return Incoming::Handled;
+    }
 
-    let closed_after = if let Ok(pkts) = state.managed.handle_datagram(dgram, now) {
-        if tracing::enabled!(tracing::Level::TRACE) {
-            tracing::trace!("handle_datagram");
-            for _pkt in &pkts {
-                tracing::trace!("pkt");
-            }
+    let mut slice = bytes.clone();
+    let dgram = match Datagram::decode_with_limit(&mut slice, config.max_frames_per_datagram) {
+        Ok(d) => d,
+        Err(DecodeError::TooManyFrames(limit)) => {
+            tracing::warn!(limit, "peer exceeded frame limit, disconnecting");
+            disconnect_bad_packet(socket, peer, state).await;
+            return Incoming::Closed;
         }
-        for pkt in ManagedSession::filter_app_packets(pkts) {
-            if let RaknetPacket::UserData { id, payload } = pkt.packet {
-                // Reassemble original app bytes as go-raknet does: id byte + payload bytes.
-                let mut buf = bytes::BytesMut::with_capacity(1 + payload.len());
-                buf.put_u8(id);
-                buf.extend_from_slice(&payload);
-                let msg = crate::transport::ReceivedMessage {
-                    buffer: buf.freeze(),
-                    reliability: pkt.reliability,
-                    channel: pkt.ordering_channel.unwrap_or(0),
-                };
-                let _ = state.to_app.send(Ok(msg)).await;
+        Err(_) if has_offline_magic(&bytes) => return Incoming::Offline,
+        Err(e) => {
+            // One corrupt or stray datagram must not cost the peer its
+            // session; only a run of them, or too many overall, does.
+            state.managed.stats().record_undecodable_datagram();
+            state.bad_datagram_streak += 1;
+            state.bad_datagrams += 1;
+            tracing::debug!(
+                error = ?e,
+                streak = state.bad_datagram_streak,
+                total = state.bad_datagrams,
+                "dropping undecodable datagram"
+            );
+            if state.bad_datagram_streak >= config.max_consecutive_bad_datagrams
+                || state.bad_datagrams >= config.max_bad_datagrams
+            {
+                tracing::warn!("too many undecodable datagrams, disconnecting");
+                disconnect_bad_packet(socket, peer, state).await;
+                return Incoming::Closed;
             }
+            return Incoming::Handled;
         }
-        false
-    } else {
-        false
     };
+    state.bad_datagram_streak = 0;
+    let now = mux::now();
 
-    maybe_announce_connection(peer, state, new_conn_tx).await;
-    flush_managed(&mut state.managed, socket, peer, now, false).await;
+    let mut delivery = AppDelivery::new(&state.to_app, state.managed.stats().clone());
+    let _ = state
+        .managed
+        .handle_datagram_with(dgram, now, |pkt| delivery.push(pkt));
+    delivery.finish().await;
+
+    maybe_announce_connection(peer, state, new_conn_tx, events).await;
 
-    if closed_after || matches!(state.managed.state(), ConnectionState::Closed) {
+    if matches!(state.managed.state(), ConnectionState::Closed) {
+        mux::flush_final(&mut state.managed, socket, peer, now).await;
         if state.announced {
-            if let Some(reason) = state.managed.last_disconnect_reason() {
-                let _ = state
-                    .to_app
-                    .send(Err(crate::RaknetError::Disconnected(reason)))
-                    .await;
-            } else {
-                let _ = state
-                    .to_app
-                    .send(Err(crate::RaknetError::ConnectionClosed))
-                    .await;
-            }
+            let _ = state.to_app.send(Err(state.managed.close_error())).await;
         }
-        sessions.remove(&peer);
+        return Incoming::Closed;
+    }
+    flush_managed(&mut state.managed, socket, peer, now, false).await;
+    state.flush_waiters.notify(&state.managed);
+    Incoming::Handled
+}
+
+async fn disconnect_bad_packet(
+    socket: &impl DatagramSocket,
+    peer: SocketAddr,
+    state: &mut SessionState,
+) {
+    let _ = state.managed.send_disconnect(DisconnectReason::BadPacket);
+    mux::flush_final(&mut state.managed, socket, peer, mux::now()).await;
+    if state.announced {
+        let _ = state
+            .to_app
+            .send(Err(crate::RaknetError::Disconnected(
+                DisconnectReason::BadPacket,
+            )))
+            .await;
     }
-    true
 }
 
-#[tracing::instrument(skip(state, new_conn_tx), level = "trace")]
+#[tracing::instrument(skip(state, new_conn_tx, events), level = "trace")]
 pub(super) async fn maybe_announce_connection(
     peer: SocketAddr,
     state: &mut SessionState,
-    new_conn_tx: &mpsc::Sender<(
-        SocketAddr,
-        mpsc::Receiver<Result<crate::transport::ReceivedMessage, crate::RaknetError>>,
-    )>,
+    new_conn_tx: &mpsc::Sender<Result<NewConnection, crate::RaknetError>>,
+    events: &Events,
 ) {
     if state.announced || !state.managed.is_connected() {
         tracing::trace!("maybe_announce");
@@ -252,8 +571,40 @@ pub(super) async fn maybe_announce_connection(
     if let Some(rx) = state.pending_rx.take() {
         state.announced = true;
         tracing::info!("announce_connection");
-        if new_conn_tx.send((peer, rx)).await.is_err() {
+        let conn = NewConnection {
+            peer,
+            guid: state.client_guid,
+            mtu: state.managed.mtu() as u16,
+            protocol_version: state.protocol_version,
+            server_addr: state.managed.server_addr(),
+            incoming: rx,
+            stats: state.managed.stats().clone(),
+        };
+        if new_conn_tx.send(Ok(conn)).await.is_err() {
             state.announced = false;
+            return;
         }
+        events.emit(ListenerEvent::Connected {
+            peer,
+            guid: state.client_guid,
+            mtu: state.managed.mtu() as u16,
+        });
+    }
+}
+
+/// Report the end of the session at `peer` if the application was told of it.
+fn emit_disconnected(events: &Events, peer: SocketAddr, state: &SessionState) {
+    if state.announced {
+        let reason = state
+            .managed
+            .last_disconnect_reason()
+            .unwrap_or(DisconnectReason::Disconnected);
+        events.emit(ListenerEvent::Disconnected { peer, reason });
     }
 }
+
+/// Whether a UDP payload of `len` bytes could not have come from a peer
+/// honouring `mtu`, which covers the IP and UDP headers as well.
+pub(super) fn exceeds_mtu(len: usize, mtu: usize) -> bool {
+    len + IPV4_HEADER_SIZE + UDP_HEADER_SIZE > mtu
+}
@@ -0,0 +1,34 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::session::manager::ConnectionState;
+use crate::transport::listener_conn::SessionState;
+
+/// A connected peer, as seen by the listener's muxer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    /// GUID the client announced in its handshake.
+    pub guid: u64,
+    /// MTU negotiated for this peer.
+    pub mtu: u16,
+    pub state: ConnectionState,
+    /// Time since the handshake created the session.
+    pub uptime: Duration,
+    /// When the peer last sent anything or a message was last queued for
+    /// it, whichever is later; the session times out counting from here.
+    pub last_activity: Instant,
+}
+
+impl PeerInfo {
+    pub(super) fn of(addr: SocketAddr, state: &SessionState, now: Instant) -> Self {
+        Self {
+            addr,
+            guid: state.client_guid,
+            mtu: state.managed.mtu() as u16,
+            state: state.managed.state(),
+            uptime: now.saturating_duration_since(state.created_at),
+            last_activity: state.managed.last_activity(),
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Caps how many offline packets the listener answers per second, both in
+/// total and per source IP, so it cannot be used as a reflection amplifier.
+///
+/// Uses fixed one-second windows. The global cap is checked first, so the
+/// per-IP table never holds more entries than the global limit.
+pub(super) struct ReplyLimiter {
+    global_limit: u32,
+    per_ip_limit: u32,
+    window_start: Option<Instant>,
+    global: u32,
+    per_ip: HashMap<IpAddr, u32>,
+}
+
+impl ReplyLimiter {
+    pub fn new(global_limit: u32, per_ip_limit: u32) -> Self {
+        Self {
+            global_limit,
+            per_ip_limit,
+            window_start: None,
+            global: 0,
+            per_ip: HashMap::new(),
+        }
+    }
+
+    /// Whether a reply to `ip` fits in the current window; counts it if so.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < WINDOW => {}
+            _ => {
+                self.window_start = Some(now);
+                self.global = 0;
+                self.per_ip.clear();
+            }
+        }
+
+        if self.global >= self.global_limit {
+            return false;
+        }
+        let count = self.per_ip.entry(ip).or_insert(0);
+        if *count >= self.per_ip_limit {
+            return false;
+        }
+        *count += 1;
+        self.global += 1;
+        true
+    }
+}
+
+/// Most source IPs `HandshakeGuard` remembers; the least recently seen is
+/// forgotten first.
+const MAX_TRACKED_IPS: usize = 4096;
+
+/// Outcome of recording a handshake attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Attempt {
+    Allowed,
+    /// This attempt crossed the threshold; the IP is banned from now on.
+    Banned,
+}
+
+#[derive(Debug)]
+struct Attempts {
+    /// Start times of recent attempts, oldest first, at most `limit` long.
+    recent: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    /// Matches the newest entry for this IP in `HandshakeGuard::order`.
+    touched: u64,
+}
+
+/// Throttles source IPs that keep starting handshakes without finishing
+/// them, by ignoring them entirely for a cooldown once they cross a
+/// threshold within a sliding window.
+///
+/// At most `MAX_TRACKED_IPS` addresses are tracked, evicting the least
+/// recently seen, so a spoofed flood cannot grow the table without bound.
+pub(super) struct HandshakeGuard {
+    limit: u32,
+    window: Duration,
+    cooldown: Duration,
+    peers: HashMap<IpAddr, Attempts>,
+    /// Recency queue with lazy deletion: stale entries are skipped on
+    /// eviction and dropped wholesale when the queue grows too long.
+    order: VecDeque<(IpAddr, u64)>,
+    clock: u64,
+}
+
+impl HandshakeGuard {
+    pub fn new(limit: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            cooldown,
+            peers: HashMap::new(),
+            order: VecDeque::new(),
+            clock: 0,
+        }
+    }
+
+    /// Whether `ip` is currently serving a ban.
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.peers
+            .get(&ip)
+            .and_then(|a| a.banned_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Count a handshake started (or botched) by `ip`.
+    pub fn record_attempt(&mut self, ip: IpAddr, now: Instant) -> Attempt {
+        let limit = self.limit as usize;
+        let window = self.window;
+        let cooldown = self.cooldown;
+        let attempts = self.touch(ip);
+
+        if attempts.banned_until.is_some_and(|until| now >= until) {
+            attempts.banned_until = None;
+            attempts.recent.clear();
+        }
+        while attempts
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= window)
+        {
+            attempts.recent.pop_front();
+        }
+
+        attempts.recent.push_back(now);
+        if attempts.recent.len() > limit {
+            attempts.recent.pop_front();
+            attempts.banned_until = Some(now + cooldown);
+            return Attempt::Banned;
+        }
+        Attempt::Allowed
+    }
+
+    /// Forgive the most recent attempt by `ip`, which finished its handshake.
+    pub fn record_success(&mut self, ip: IpAddr) {
+        if let Some(attempts) = self.peers.get_mut(&ip) {
+            attempts.recent.pop_back();
+        }
+    }
+
+    fn touch(&mut self, ip: IpAddr) -> &mut Attempts {
+        self.clock += 1;
+        let stamp = self.clock;
+
+        if !self.peers.contains_key(&ip) && self.peers.len() >= MAX_TRACKED_IPS {
+            self.evict_oldest();
+        }
+        self.order.push_back((ip, stamp));
+        if self.order.len() > 4 * MAX_TRACKED_IPS {
+            let peers = &self.peers;
+            self.order
+                .retain(|(ip, stamp)| peers.get(ip).is_some_and(|a| a.touched == *stamp));
+        }
+
+        let attempts = self.peers.entry(ip).or_insert_with(|| Attempts {
+            recent: VecDeque::new(),
+            banned_until: None,
+            touched: stamp,
+        });
+        attempts.touched = stamp;
+        attempts
+    }
+
+    fn evict_oldest(&mut self) {
+        while let Some((ip, stamp)) = self.order.pop_front() {
+            if self.peers.get(&ip).is_some_and(|a| a.touched == stamp) {
+                self.peers.remove(&ip);
+                return;
+            }
+        }
+    }
+}
+
+/// Remembers IPs whose session just ended, so a reconnect within `window`
+/// can be refused with `IpRecentlyConnected` like vanilla RakNet does.
+///
+/// A zero window records nothing. Expired entries are dropped by `expire`; at most
+/// `MAX_TRACKED_IPS` are kept, new ones being skipped beyond that.
+pub(super) struct RecentDisconnects {
+    window: Duration,
+    until: HashMap<IpAddr, Instant>,
+}
+
+impl RecentDisconnects {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            until: HashMap::new(),
+        }
+    }
+
+    /// Note that a session from `ip` just ended.
+    pub fn record(&mut self, ip: IpAddr, now: Instant) {
+        let ip = ip.to_canonical();
+        if self.window.is_zero() {
+            return;
+        }
+        if self.until.len() >= MAX_TRACKED_IPS && !self.until.contains_key(&ip) {
+            self.expire(now);
+            if self.until.len() >= MAX_TRACKED_IPS {
+                return;
+            }
+        }
+        self.until.insert(ip, now + self.window);
+    }
+
+    /// Whether a session from `ip` ended less than `window` ago.
+    pub fn is_recent(&self, ip: IpAddr, now: Instant) -> bool {
+        self.until
+            .get(&ip.to_canonical())
+            .is_some_and(|until| now < *until)
+    }
+
+    /// Forget every entry whose window has passed.
+    pub fn expire(&mut self, now: Instant) {
+        self.until.retain(|_, until| now < *until);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn per_ip_limit_leaves_other_sources_alone() {
+        let now = Instant::now();
+        let mut limiter = ReplyLimiter::new(100, 2);
+
+        assert!(limiter.allow(A, now));
+        assert!(limiter.allow(A, now));
+        assert!(!limiter.allow(A, now));
+        assert!(limiter.allow(B, now));
+    }
+
+    #[test]
+    fn global_limit_applies_across_sources() {
+        let now = Instant::now();
+        let mut limiter = ReplyLimiter::new(3, 100);
+
+        let allowed = (0..10u8)
+            .filter(|i| limiter.allow(IpAddr::V4(Ipv4Addr::new(10, 1, 0, *i)), now))
+            .count();
+        assert_eq!(allowed, 3);
+        assert!(limiter.per_ip.len() <= 3);
+    }
+
+    #[test]
+    fn window_resets_after_a_second() {
+        let now = Instant::now();
+        let mut limiter = ReplyLimiter::new(1, 1);
+
+        assert!(limiter.allow(A, now));
+        assert!(!limiter.allow(A, now + Duration::from_millis(999)));
+        assert!(limiter.allow(A, now + Duration::from_secs(1)));
+    }
+
+    fn guard(limit: u32) -> HandshakeGuard {
+        HandshakeGuard::new(limit, Duration::from_secs(10), Duration::from_secs(30))
+    }
+
+    #[test]
+    fn crossing_the_threshold_bans_until_cooldown_ends() {
+        let now = Instant::now();
+        let mut guard = guard(3);
+
+        for _ in 0..3 {
+            assert_eq!(guard.record_attempt(A, now), Attempt::Allowed);
+        }
+        assert_eq!(guard.record_attempt(A, now), Attempt::Banned);
+        assert!(guard.is_banned(A, now + Duration::from_secs(29)));
+        assert!(!guard.is_banned(B, now));
+
+        let later = now + Duration::from_secs(30);
+        assert!(!guard.is_banned(A, later));
+        assert_eq!(guard.record_attempt(A, later), Attempt::Allowed);
+    }
+
+    #[test]
+    fn attempts_slide_out_of_the_window() {
+        let now = Instant::now();
+        let mut guard = guard(2);
+
+        assert_eq!(guard.record_attempt(A, now), Attempt::Allowed);
+        assert_eq!(
+            guard.record_attempt(A, now + Duration::from_secs(5)),
+            Attempt::Allowed
+        );
+        // The first attempt has aged out by now.
+        assert_eq!(
+            guard.record_attempt(A, now + Duration::from_secs(11)),
+            Attempt::Allowed
+        );
+        assert_eq!(
+            guard.record_attempt(A, now + Duration::from_secs(12)),
+            Attempt::Banned
+        );
+    }
+
+    #[test]
+    fn completed_handshakes_are_forgiven() {
+        let now = Instant::now();
+        let mut guard = guard(2);
+
+        for _ in 0..10 {
+            assert_eq!(guard.record_attempt(A, now), Attempt::Allowed);
+            guard.record_success(A);
+        }
+    }
+
+    #[test]
+    fn table_is_capped_evicting_least_recently_seen() {
+        let now = Instant::now();
+        let mut guard = guard(1);
+
+        guard.record_attempt(A, now);
+        guard.record_attempt(A, now);
+        assert!(guard.is_banned(A, now));
+
+        for i in 0..MAX_TRACKED_IPS as u32 {
+            guard.record_attempt(IpAddr::V4(Ipv4Addr::from(0x0b00_0000 + i)), now);
+        }
+        assert_eq!(guard.peers.len(), MAX_TRACKED_IPS);
+        assert!(guard.order.len() <= 4 * MAX_TRACKED_IPS);
+        assert!(!guard.is_banned(A, now), "oldest entry should be evicted");
+    }
+
+    #[test]
+    fn reconnects_are_refused_until_the_window_passes() {
+        let now = Instant::now();
+        let mut recent = RecentDisconnects::new(Duration::from_millis(100));
+
+        recent.record(A, now);
+        assert!(recent.is_recent(A, now + Duration::from_millis(99)));
+        assert!(!recent.is_recent(B, now));
+        assert!(!recent.is_recent(A, now + Duration::from_millis(100)));
+
+        recent.expire(now + Duration::from_millis(100));
+        assert!(recent.until.is_empty());
+    }
+
+    #[test]
+    fn zero_window_records_nothing() {
+        let now = Instant::now();
+        let mut recent = RecentDisconnects::new(Duration::ZERO);
+        recent.record(A, now);
+        assert!(!recent.is_recent(A, now));
+
+        // Loopback is no exception; a mapped address is the same IP.
+        let mut recent = RecentDisconnects::new(Duration::from_secs(1));
+        recent.record("::ffff:127.0.0.1".parse().unwrap(), now);
+        assert!(recent.is_recent(IpAddr::V4(Ipv4Addr::LOCALHOST), now));
+    }
+}
@@ -0,0 +1,102 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::transport::listener_conn::SessionState;
+
+/// Tracks which sessions need a tick so idle ones can be skipped.
+///
+/// Deadlines live in a min-heap with lazy invalidation: an entry is only
+/// honoured if it still matches `SessionState::next_deadline`. Activity
+/// between ticks marks a session dirty so it is serviced on the next tick.
+/// Rescheduling leaves the old entry behind, so once stale entries outnumber
+/// live ones the heap is rebuilt from the live deadlines alone.
+#[derive(Default)]
+pub(super) struct TickSchedule {
+    deadlines: BinaryHeap<Reverse<(Instant, SocketAddr)>>,
+    dirty: HashSet<SocketAddr>,
+}
+
+impl TickSchedule {
+    pub fn mark_dirty(&mut self, peer: SocketAddr) {
+        self.dirty.insert(peer);
+    }
+
+    pub fn schedule(&mut self, peer: SocketAddr, state: &mut SessionState, at: Instant) {
+        if state.next_deadline == Some(at) {
+            return;
+        }
+        state.next_deadline = Some(at);
+        self.deadlines.push(Reverse((at, peer)));
+    }
+
+    /// Drain every session that is dirty or whose deadline has passed.
+    pub fn take_due(
+        &mut self,
+        now: Instant,
+        sessions: &HashMap<SocketAddr, SessionState>,
+    ) -> Vec<SocketAddr> {
+        let mut due: HashSet<SocketAddr> = self
+            .dirty
+            .drain()
+            .filter(|p| sessions.contains_key(p))
+            .collect();
+
+        while let Some(Reverse((at, peer))) = self.deadlines.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.deadlines.pop();
+            if sessions.get(&peer).and_then(|s| s.next_deadline) == Some(at) {
+                due.insert(peer);
+            }
+        }
+
+        self.compact(sessions.len(), |peer| {
+            sessions.get(peer).and_then(|s| s.next_deadline)
+        });
+        due.into_iter().collect()
+    }
+
+    /// Drop stale entries once the heap holds more than twice as many as
+    /// there are sessions, so steady rescheduling can't grow it forever.
+    fn compact(&mut self, live: usize, current: impl Fn(&SocketAddr) -> Option<Instant>) {
+        if self.deadlines.len() <= 2 * live + COMPACT_SLACK {
+            return;
+        }
+        self.deadlines
+            .retain(|Reverse((at, peer))| current(peer) == Some(*at));
+    }
+}
+
+/// Stale entries tolerated on top of two per session before compacting, so
+/// a handful of sessions doesn't rebuild the heap on every tick.
+const COMPACT_SLACK: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn rescheduling_does_not_grow_the_heap_without_bound() {
+        let peer: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let start = Instant::now();
+        let mut schedule = TickSchedule::default();
+        let mut latest = start;
+        for i in 0..10_000 {
+            latest = start + Duration::from_millis(i);
+            schedule.deadlines.push(Reverse((latest, peer)));
+            schedule.compact(1, |_| Some(latest));
+        }
+        assert!(schedule.deadlines.len() <= 2 + COMPACT_SLACK + 1);
+        // The live deadline survives every rebuild.
+        assert!(
+            schedule
+                .deadlines
+                .iter()
+                .any(|Reverse((at, p))| *at == latest && *p == peer)
+        );
+    }
+}
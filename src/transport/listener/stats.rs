@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::session::stats::{MemoryUsage, SharedStats, StatsSnapshot};
+
+/// Listener-wide counters plus a registry of every live session's `SharedStats`.
+///
+/// The registry lock is only taken when sessions come and go; per-packet
+/// updates go straight to the atomics.
+#[derive(Debug, Default)]
+pub struct ListenerStats {
+    datagrams_received: AtomicU64,
+    bytes_received: AtomicU64,
+    offline_packets_received: AtomicU64,
+    oversized_datagrams: AtomicU64,
+    offline_packets_rejected: AtomicU64,
+    offline_replies_rate_limited: AtomicU64,
+    handshake_bans: AtomicU64,
+    handshakes_throttled: AtomicU64,
+    handshakes_recently_connected: AtomicU64,
+    handshakes_filtered: AtomicU64,
+    session_count: AtomicUsize,
+    pending_handshakes: AtomicUsize,
+    /// Duplicate counters of sessions that have gone, so the totals don't
+    /// drop when a session is unregistered.
+    retired_duplicate_datagrams: AtomicU64,
+    retired_duplicate_frames: AtomicU64,
+    sessions: RwLock<HashMap<SocketAddr, Arc<SharedStats>>>,
+}
+
+/// Point-in-time copy of a listener's `ListenerStats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListenerStatsSnapshot {
+    /// Raw UDP datagrams read from the socket, online and offline.
+    pub datagrams_received: u64,
+    /// Raw UDP bytes read from the socket.
+    pub bytes_received: u64,
+    /// Offline (handshake / ping) packets handled.
+    pub offline_packets_received: u64,
+    /// Datagrams dropped for exceeding the sender's negotiated MTU.
+    pub oversized_datagrams: u64,
+    /// Offline packets dropped for a missing/misplaced magic or failing to decode.
+    pub offline_packets_rejected: u64,
+    /// Offline packets left unanswered because a reply rate limit was hit.
+    pub offline_replies_rate_limited: u64,
+    /// Source IPs banned for starting too many handshakes.
+    pub handshake_bans: u64,
+    /// Offline packets ignored because their source IP was banned.
+    pub handshakes_throttled: u64,
+    /// Handshakes refused with `IpRecentlyConnected` for reconnecting within
+    /// `ip_recently_connected_window` of a disconnect.
+    pub handshakes_recently_connected: u64,
+    /// Handshakes the connection filter dropped or rejected.
+    pub handshakes_filtered: u64,
+    /// Number of sessions currently tracked by the muxer.
+    pub sessions: usize,
+    /// Handshakes between `OpenConnectionRequest1` and `2`, as of the last
+    /// offline packet; expired ones are only dropped when one arrives.
+    pub pending_handshakes: usize,
+    /// `StatsSnapshot::duplicate_datagrams` summed over every session so far.
+    pub duplicate_datagrams: u64,
+    /// `StatsSnapshot::duplicate_frames` summed over every session so far.
+    pub duplicate_frames: u64,
+}
+
+impl ListenerStats {
+    pub(crate) fn record_datagram_received(&self, bytes: usize) {
+        self.datagrams_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_offline_packet(&self) {
+        self.offline_packets_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_oversized_datagram(&self) {
+        self.oversized_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_offline_rejected(&self) {
+        self.offline_packets_rejected
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_offline_rate_limited(&self) {
+        self.offline_replies_rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_handshake_ban(&self) {
+        self.handshake_bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_handshake_throttled(&self) {
+        self.handshakes_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_handshake_recently_connected(&self) {
+        self.handshakes_recently_connected
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_handshake_filtered(&self) {
+        self.handshakes_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_pending_handshakes(&self, count: usize) {
+        self.pending_handshakes.store(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn register(&self, peer: SocketAddr, stats: &Arc<SharedStats>) {
+        let previous = self
+            .sessions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(peer, stats.clone());
+        match previous {
+            None => {
+                self.session_count.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(old) if !Arc::ptr_eq(&old, stats) => old.mark_closed(),
+            Some(_) => {}
+        }
+    }
+
+    pub(crate) fn unregister(&self, peer: &SocketAddr) {
+        let removed = self
+            .sessions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(peer);
+        if let Some(stats) = removed {
+            stats.mark_closed();
+            self.session_count.fetch_sub(1, Ordering::Relaxed);
+            let snap = stats.snapshot();
+            self.retired_duplicate_datagrams
+                .fetch_add(snap.duplicate_datagrams, Ordering::Relaxed);
+            self.retired_duplicate_frames
+                .fetch_add(snap.duplicate_frames, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of live sessions, without taking the registry lock.
+    pub(crate) fn session_count(&self) -> usize {
+        self.session_count.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of a single session's counters, if the peer is still live.
+    pub fn peer(&self, peer: &SocketAddr) -> Option<StatsSnapshot> {
+        self.sessions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(peer)
+            .map(|s| s.snapshot())
+    }
+
+    /// Snapshot of a single session's debug log, if it is live and keeps one.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub(crate) fn debug_log(
+        &self,
+        peer: &SocketAddr,
+    ) -> Option<Vec<crate::session::debug_log::DebugEvent>> {
+        self.sessions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(peer)?
+            .debug_log()
+            .map(|log| log.snapshot())
+    }
+
+    /// Sum of `MemoryUsage` across every live session.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let sessions = self.sessions.read().unwrap_or_else(|e| e.into_inner());
+        let mut total = MemoryUsage::default();
+        for stats in sessions.values() {
+            total += stats.memory_usage();
+        }
+        total
+    }
+
+    /// Read the listener-wide counters.
+    pub fn snapshot(&self) -> ListenerStatsSnapshot {
+        let mut duplicate_datagrams = self.retired_duplicate_datagrams.load(Ordering::Relaxed);
+        let mut duplicate_frames = self.retired_duplicate_frames.load(Ordering::Relaxed);
+        for stats in self
+            .sessions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+        {
+            let snap = stats.snapshot();
+            duplicate_datagrams += snap.duplicate_datagrams;
+            duplicate_frames += snap.duplicate_frames;
+        }
+        ListenerStatsSnapshot {
+            datagrams_received: self.datagrams_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            offline_packets_received: self.offline_packets_received.load(Ordering::Relaxed),
+            oversized_datagrams: self.oversized_datagrams.load(Ordering::Relaxed),
+            offline_packets_rejected: self.offline_packets_rejected.load(Ordering::Relaxed),
+            offline_replies_rate_limited: self.offline_replies_rate_limited.load(Ordering::Relaxed),
+            handshake_bans: self.handshake_bans.load(Ordering::Relaxed),
+            handshakes_throttled: self.handshakes_throttled.load(Ordering::Relaxed),
+            handshakes_recently_connected: self
+                .handshakes_recently_connected
+                .load(Ordering::Relaxed),
+            handshakes_filtered: self.handshakes_filtered.load(Ordering::Relaxed),
+            sessions: self.session_count(),
+            pending_handshakes: self.pending_handshakes.load(Ordering::Relaxed),
+            duplicate_datagrams,
+            duplicate_frames,
+        }
+    }
+}
@@ -1,12 +1,49 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
 use tokio::sync::mpsc;
 
 use crate::session::manager::ManagedSession;
+use crate::session::stats::SharedStats;
+use crate::transport::mux::FlushWaiters;
 
 /// Internal per-peer session state.
 pub struct SessionState {
     pub managed: ManagedSession,
+    /// GUID the client announced in `OpenConnectionRequest2`.
+    pub client_guid: u64,
+    /// RakNet protocol version the client handshaked with.
+    pub protocol_version: u8,
     pub to_app: mpsc::Sender<Result<crate::transport::ReceivedMessage, crate::RaknetError>>,
     pub pending_rx:
         Option<mpsc::Receiver<Result<crate::transport::ReceivedMessage, crate::RaknetError>>>,
     pub announced: bool,
+    /// Undecodable datagrams received since the last good one.
+    pub bad_datagram_streak: u32,
+    /// Undecodable datagrams received over the session's lifetime.
+    pub bad_datagrams: u32,
+    /// Deadline currently queued in the listener's tick schedule.
+    pub next_deadline: Option<Instant>,
+    pub flush_waiters: FlushWaiters,
+    /// When the handshake created the session.
+    pub created_at: Instant,
+    /// Set by a kick, which counts the IP as recently disconnected right
+    /// away; dropping the session later must not restart that window.
+    pub kicked: bool,
+}
+
+/// Freshly connected peer handed from a muxer to the `RaknetStream` that
+/// wraps it.
+pub struct NewConnection {
+    pub peer: SocketAddr,
+    pub guid: u64,
+    /// MTU negotiated for this peer.
+    pub mtu: u16,
+    /// RakNet protocol version the handshake was carried out in.
+    pub protocol_version: u8,
+    /// Our address as the client sees it, from its `NewIncomingConnection`.
+    pub server_addr: Option<SocketAddr>,
+    pub incoming: mpsc::Receiver<Result<crate::transport::ReceivedMessage, crate::RaknetError>>,
+    pub stats: Arc<SharedStats>,
 }
@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Random variation added on top of a link's fixed latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Jitter {
+    /// Every datagram takes exactly the link latency.
+    #[default]
+    None,
+    /// Extra delay drawn uniformly from `0..=max`.
+    Uniform(Duration),
+    /// Extra delay drawn from an exponential distribution with this mean;
+    /// mostly small, with an occasional long tail.
+    Exponential(Duration),
+}
+
+/// Conditions applied to datagrams travelling one way between two sockets.
+///
+/// The default is a perfect link: no loss, no delay, unlimited bandwidth.
+/// Every knob is applied per datagram, in this order: size, loss, bandwidth
+/// (queueing behind earlier datagrams), latency plus jitter, reordering,
+/// then duplication of whatever survived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimulatedLink {
+    /// Probability in `0.0..=1.0` that a datagram is dropped.
+    pub loss: f64,
+    /// Fixed delay before a datagram is delivered.
+    pub latency: Duration,
+    /// Random delay added on top of `latency`.
+    pub jitter: Jitter,
+    /// Probability that a datagram is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a datagram is held back by `reorder_delay`, letting
+    /// later ones overtake it.
+    pub reorder: f64,
+    /// How long a reordered datagram is held back.
+    pub reorder_delay: Duration,
+    /// Bytes per second the link carries, or `None` for unlimited. Datagrams
+    /// queue behind each other; the queue itself is unbounded.
+    pub bandwidth: Option<u64>,
+    /// Largest datagram the link carries, or `None` for any size. Bigger
+    /// ones are dropped, like on a path whose MTU is this plus the IP and
+    /// UDP headers.
+    pub max_datagram: Option<usize>,
+}
+
+impl Default for SimulatedLink {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            latency: Duration::ZERO,
+            jitter: Jitter::None,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(50),
+            bandwidth: None,
+            max_datagram: None,
+        }
+    }
+}
+
+impl SimulatedLink {
+    /// A perfect link.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub fn reorder(mut self, reorder: f64, delay: Duration) -> Self {
+        self.reorder = reorder;
+        self.reorder_delay = delay;
+        self
+    }
+
+    pub fn bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    pub fn max_datagram(mut self, len: usize) -> Self {
+        self.max_datagram = Some(len);
+        self
+    }
+}
+
+/// A link's conditions plus the state they need between datagrams.
+#[derive(Debug)]
+pub(super) struct LinkState {
+    pub conditions: SimulatedLink,
+    /// When the last queued datagram finishes going onto the wire.
+    busy_until: Option<Instant>,
+}
+
+impl LinkState {
+    pub fn new(conditions: SimulatedLink) -> Self {
+        Self {
+            conditions,
+            busy_until: None,
+        }
+    }
+
+    /// Delays after which copies of a `len`-byte datagram sent at `now`
+    /// arrive. Empty if it is lost.
+    pub fn plan(&mut self, len: usize, now: Instant, rng: &mut Rng) -> Vec<Duration> {
+        let link = self.conditions;
+        if link.max_datagram.is_some_and(|max| len > max) || rng.chance(link.loss) {
+            return Vec::new();
+        }
+
+        let queued = match link.bandwidth {
+            Some(bps) if bps > 0 => {
+                let start = self.busy_until.map_or(now, |busy| busy.max(now));
+                let done = start + Duration::from_secs_f64(len as f64 / bps as f64);
+                self.busy_until = Some(done);
+                done - now
+            }
+            _ => Duration::ZERO,
+        };
+
+        let copies = if rng.chance(link.duplicate) { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                let mut delay = queued + link.latency + self.sample_jitter(rng);
+                if rng.chance(link.reorder) {
+                    delay += link.reorder_delay;
+                }
+                delay
+            })
+            .collect()
+    }
+
+    fn sample_jitter(&self, rng: &mut Rng) -> Duration {
+        match self.conditions.jitter {
+            Jitter::None => Duration::ZERO,
+            Jitter::Uniform(max) => max.mul_f64(rng.next_f64()),
+            Jitter::Exponential(mean) => {
+                // 1 - u is in (0, 1], so the log is finite.
+                mean.mul_f64(-(1.0 - rng.next_f64()).ln())
+            }
+        }
+    }
+}
+
+/// xorshift64*; good enough to roll the dice and fully reproducible.
+#[derive(Debug)]
+pub(super) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    /// Uniform in `0.0..1.0`.
+    pub fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let x = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plans(link: SimulatedLink, n: usize) -> Vec<Vec<Duration>> {
+        let mut state = LinkState::new(link);
+        let mut rng = Rng::new(7);
+        let now = Instant::now();
+        (0..n).map(|_| state.plan(100, now, &mut rng)).collect()
+    }
+
+    #[test]
+    fn perfect_link_delivers_once_immediately() {
+        for plan in plans(SimulatedLink::new(), 100) {
+            assert_eq!(plan, vec![Duration::ZERO]);
+        }
+    }
+
+    #[test]
+    fn loss_and_duplication_follow_their_probabilities() {
+        let n = 10_000;
+        let lossy = plans(SimulatedLink::new().loss(0.3), n);
+        let lost = lossy.iter().filter(|p| p.is_empty()).count();
+        assert!((2700..3300).contains(&lost), "lost {lost}");
+
+        let dupey = plans(SimulatedLink::new().duplicate(0.2), n);
+        let doubled = dupey.iter().filter(|p| p.len() == 2).count();
+        assert!((1700..2300).contains(&doubled), "doubled {doubled}");
+    }
+
+    #[test]
+    fn jitter_stays_within_its_distribution() {
+        let latency = Duration::from_millis(40);
+        let max = Duration::from_millis(20);
+        let link = SimulatedLink::new()
+            .latency(latency)
+            .jitter(Jitter::Uniform(max));
+        let delays: Vec<_> = plans(link, 1000).into_iter().flatten().collect();
+        assert!(delays.iter().all(|d| *d >= latency && *d <= latency + max));
+        assert!(delays.iter().any(|d| *d > latency + max / 2));
+
+        let mean = Duration::from_millis(10);
+        let link = SimulatedLink::new().jitter(Jitter::Exponential(mean));
+        let total: Duration = plans(link, 10_000).into_iter().flatten().sum();
+        let avg = total / 10_000;
+        assert!(
+            avg > mean.mul_f64(0.9) && avg < mean.mul_f64(1.1),
+            "{avg:?}"
+        );
+    }
+
+    #[test]
+    fn reordered_datagrams_are_held_back() {
+        let hold = Duration::from_millis(75);
+        let link = SimulatedLink::new().reorder(0.5, hold);
+        let delays: Vec<_> = plans(link, 1000).into_iter().flatten().collect();
+        assert!(delays.iter().all(|d| *d == Duration::ZERO || *d == hold));
+        assert!(delays.contains(&hold) && delays.contains(&Duration::ZERO));
+    }
+
+    #[test]
+    fn bandwidth_queues_datagrams_back_to_back() {
+        let mut state = LinkState::new(SimulatedLink::new().bandwidth(1000));
+        let mut rng = Rng::new(1);
+        let now = Instant::now();
+
+        assert_eq!(
+            state.plan(100, now, &mut rng),
+            vec![Duration::from_millis(100)]
+        );
+        assert_eq!(
+            state.plan(100, now, &mut rng),
+            vec![Duration::from_millis(200)]
+        );
+        // Once the queue has drained, the next datagram only pays its own time.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            state.plan(50, later, &mut rng),
+            vec![Duration::from_millis(50)]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! In-memory datagram network for deterministic tests.
+//!
+//! A `MemoryNetwork` hands out `MemorySocket`s that deliver to each other
+//! through channels instead of the OS. Each direction between two addresses
+//! can be given its own `SimulatedLink` (loss, latency, jitter, duplication,
+//! reordering, bandwidth, datagram size). Delays are applied with `tokio::time::sleep`, so
+//! under a paused runtime (`#[tokio::test(start_paused = true)]`) whole
+//! sessions — handshake, retransmission, timeouts — run in simulated time.
+//!
+//! Plug the sockets into `RaknetListener::with_socket` and
+//! `RaknetStream::connect_on`:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use tokio_raknet::transport::memory::{MemoryNetwork, SimulatedLink};
+//! use tokio_raknet::transport::{RaknetListener, RaknetStream};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let net = MemoryNetwork::new();
+//! net.set_default_link(
+//!     SimulatedLink::new()
+//!         .latency(Duration::from_millis(50))
+//!         .loss(0.05),
+//! );
+//!
+//! let server = net.bind("10.0.0.1:19132".parse()?)?;
+//! let mut listener = RaknetListener::with_socket(server, Default::default())?;
+//! let client = RaknetStream::connect_on(net.bind_any()?, listener.local_addr(), Default::default());
+//! let (client, server) = tokio::join!(client, listener.accept());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `add_nat` puts sockets behind a simulated NAT, for testing hole punching
+//! with `RaknetClient::send_unconnected`.
+
+mod link;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{Mutex as AsyncMutex, mpsc};
+
+use super::socket::DatagramSocket;
+use link::{LinkState, Rng};
+
+pub use link::{Jitter, SimulatedLink};
+
+/// Datagrams a socket buffers before further ones are dropped, like a full
+/// kernel receive buffer.
+const SOCKET_QUEUE: usize = 4096;
+
+type Inbox = mpsc::Sender<(Bytes, SocketAddr)>;
+
+struct NetworkInner {
+    sockets: HashMap<SocketAddr, Inbox>,
+    links: HashMap<(SocketAddr, SocketAddr), LinkState>,
+    default_link: SimulatedLink,
+    next_port: u16,
+    rng: Rng,
+    /// Public IP of the NAT in front of each private IP.
+    nats: HashMap<IpAddr, IpAddr>,
+    /// NAT mappings by public address.
+    mappings: HashMap<SocketAddr, NatMapping>,
+    /// Public address of each socket bound behind a NAT.
+    public_addrs: HashMap<SocketAddr, SocketAddr>,
+}
+
+struct NatMapping {
+    private: SocketAddr,
+    /// Addresses the private socket has sent to, and so may hear from.
+    permitted: HashSet<SocketAddr>,
+}
+
+impl NetworkInner {
+    /// A port on `ip` that no socket or NAT mapping uses yet.
+    fn free_port(&mut self, ip: IpAddr) -> u16 {
+        loop {
+            let candidate = SocketAddr::new(ip, self.next_port);
+            self.next_port = self.next_port.wrapping_add(1).max(1024);
+            if !self.sockets.contains_key(&candidate) && !self.mappings.contains_key(&candidate) {
+                return candidate.port();
+            }
+        }
+    }
+}
+
+/// A set of in-memory sockets that can reach each other.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<NetworkInner>>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::with_seed(0x5eed)
+    }
+
+    /// A network whose random decisions (loss, jitter, ...) follow `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(NetworkInner {
+                sockets: HashMap::new(),
+                links: HashMap::new(),
+                default_link: SimulatedLink::default(),
+                next_port: 40000,
+                rng: Rng::new(seed),
+                nats: HashMap::new(),
+                mappings: HashMap::new(),
+                public_addrs: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Bind a socket at `addr`. Port 0 picks a free port.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemorySocket> {
+        let mut inner = self.lock();
+        let mut addr = addr;
+        if addr.port() == 0 {
+            let port = inner.free_port(addr.ip());
+            addr.set_port(port);
+        }
+        if inner.sockets.contains_key(&addr) || inner.mappings.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{addr} is already bound"),
+            ));
+        }
+
+        if let Some(&public_ip) = inner.nats.get(&addr.ip()) {
+            let public = SocketAddr::new(public_ip, inner.free_port(public_ip));
+            inner.mappings.insert(
+                public,
+                NatMapping {
+                    private: addr,
+                    permitted: HashSet::new(),
+                },
+            );
+            inner.public_addrs.insert(addr, public);
+        }
+        let (tx, rx) = mpsc::channel(SOCKET_QUEUE);
+        inner.sockets.insert(addr, tx);
+        Ok(MemorySocket {
+            addr,
+            network: self.clone(),
+            inbox: AsyncMutex::new(rx),
+        })
+    }
+
+    /// Bind a socket on a free port of `127.0.0.1`.
+    pub fn bind_any(&self) -> io::Result<MemorySocket> {
+        self.bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    }
+
+    /// Set the conditions for datagrams sent from `from` to `to`.
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, link: SimulatedLink) {
+        let mut inner = self.lock();
+        match inner.links.get_mut(&(from, to)) {
+            Some(state) => state.conditions = link,
+            None => {
+                inner.links.insert((from, to), LinkState::new(link));
+            }
+        }
+    }
+
+    /// Set the same conditions in both directions between `a` and `b`.
+    pub fn set_link_both(&self, a: SocketAddr, b: SocketAddr, link: SimulatedLink) {
+        self.set_link(a, b, link);
+        self.set_link(b, a, link);
+    }
+
+    /// Put sockets bound on `private_ip` from now on behind a NAT whose
+    /// public side is `public_ip`.
+    ///
+    /// Each such socket gets one port on `public_ip`, used for every
+    /// destination: what it sends appears to come from there, and only
+    /// addresses it has already sent to get through to it, like a
+    /// port-restricted cone NAT. `local_addr` still reports the private
+    /// address, so the public one has to be learned from a peer.
+    pub fn add_nat(&self, private_ip: IpAddr, public_ip: IpAddr) {
+        self.lock().nats.insert(private_ip, public_ip);
+    }
+
+    /// Conditions for every pair of addresses without a link of its own.
+    pub fn set_default_link(&self, link: SimulatedLink) {
+        self.lock().default_link = link;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, NetworkInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn deliver(&self, from: SocketAddr, to: SocketAddr, payload: Bytes) {
+        let (from, inbox, delays) = {
+            let mut inner = self.lock();
+            let inner = &mut *inner;
+            // Leaving through a NAT: rewrite the source and open a pinhole.
+            let from = match inner.public_addrs.get(&from) {
+                Some(&public) => {
+                    if let Some(mapping) = inner.mappings.get_mut(&public) {
+                        mapping.permitted.insert(to);
+                    }
+                    public
+                }
+                None => from,
+            };
+            // Arriving at a NAT: only through a pinhole.
+            let local = match inner.mappings.get(&to) {
+                Some(mapping) if mapping.permitted.contains(&from) => mapping.private,
+                Some(_) => return,
+                None => to,
+            };
+            let Some(inbox) = inner.sockets.get(&local).cloned() else {
+                return;
+            };
+            let default_link = inner.default_link;
+            let state = inner
+                .links
+                .entry((from, to))
+                .or_insert_with(|| LinkState::new(default_link));
+            let now = tokio::time::Instant::now();
+            (from, inbox, state.plan(payload.len(), now, &mut inner.rng))
+        };
+
+        for delay in delays {
+            let payload = payload.clone();
+            if delay.is_zero() {
+                let _ = inbox.try_send((payload, from));
+            } else {
+                let inbox = inbox.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = inbox.try_send((payload, from));
+                });
+            }
+        }
+    }
+}
+
+/// A socket on a `MemoryNetwork`. Unbinds itself when dropped.
+pub struct MemorySocket {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: AsyncMutex<mpsc::Receiver<(Bytes, SocketAddr)>>,
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        let mut inner = self.network.lock();
+        inner.sockets.remove(&self.addr);
+        if let Some(public) = inner.public_addrs.remove(&self.addr) {
+            inner.mappings.remove(&public);
+        }
+    }
+}
+
+impl DatagramSocket for MemorySocket {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.network
+            .deliver(self.addr, target, Bytes::copy_from_slice(buf));
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut inbox = self.inbox.lock().await;
+        let Some((payload, from)) = inbox.recv().await else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        };
+        // Like UDP, a datagram larger than the buffer is truncated.
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+        Ok((len, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn binding_a_taken_address_fails() {
+        let net = MemoryNetwork::new();
+        let addr: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        let socket = net.bind(addr).unwrap();
+        assert_eq!(
+            net.bind(addr).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+        drop(socket);
+        assert!(net.bind(addr).is_ok());
+    }
+}
@@ -12,16 +12,40 @@
 
 use bytes::Bytes;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use crate::protocol::{packet::RaknetPacket, reliability::Reliability, state::RakPriority};
+use crate::protocol::{
+    reliability::Reliability,
+    state::{DisconnectReason, RakPriority},
+};
+use crate::session::manager::{ManagedSession, SessionError};
+use crate::session::{ReceiptOutcome, SendOptions};
 
+pub mod capture;
+pub mod client;
 pub mod listener;
 mod listener_conn;
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
+pub mod motd;
 pub mod mux;
+mod ping;
+pub mod socket;
 pub mod stream;
 
-pub use listener::{RaknetListener, RaknetListenerConfig};
-pub use stream::{RaknetStream, RaknetStreamConfig};
+pub use crate::session::manager::ConnectionState;
+pub use capture::{Capture, CapturedDatagram, Direction, PcapWriter};
+pub use client::RaknetClient;
+pub use listener::{
+    FilterDecision, IncomingConnection, ListenerEvent, ListenerStatsSnapshot, PausePolicy,
+    PeerInfo, RaknetListener, RaknetListenerBuilder, RaknetListenerConfig, RejectReason, Rejection,
+};
+pub use motd::BedrockMotd;
+pub use ping::{PingResponse, ping, ping_with_socket};
+pub use socket::{DatagramSocket, DualStackSocket};
+pub use stream::{
+    HandshakeBackoff, HandshakeRetry, RaknetSender, RaknetStream, RaknetStreamConfig, Receipt,
+};
 
 /// High-level message object for sending data.
 /// Wraps the payload and delivery options (reliability, channel, priority).
@@ -31,6 +55,9 @@ pub struct Message {
     pub reliability: Reliability,
     pub channel: u8,
     pub priority: RakPriority,
+    /// How long the message may wait to be sent before it is dropped; set
+    /// with `ttl`.
+    ttl: Option<Duration>,
 }
 
 impl Message {
@@ -40,6 +67,7 @@ impl Message {
             reliability: Reliability::ReliableOrdered,
             channel: 0,
             priority: RakPriority::Normal,
+            ttl: None,
         }
     }
 
@@ -48,6 +76,8 @@ impl Message {
         self
     }
 
+    /// The ordering channel, below `MAXIMUM_ORDERING_CHANNELS`. Sending a
+    /// message on any other fails with `RaknetError::InvalidChannel`.
     pub fn channel(mut self, channel: u8) -> Self {
         self.channel = channel;
         self
@@ -57,6 +87,18 @@ impl Message {
         self.priority = priority;
         self
     }
+
+    /// Drop the message instead of sending it late, if it is still queued
+    /// `ttl` after being sent (counted in `StatsSnapshot::messages_expired`).
+    ///
+    /// Any message can expire before it is handed to the session. After
+    /// that, a reliable message has taken a reliable index the peer waits
+    /// for, so only unreliable ones that fit in one datagram are dropped;
+    /// the rest go out however late.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
 }
 
 impl From<Bytes> for Message {
@@ -66,6 +108,7 @@ impl From<Bytes> for Message {
             reliability: Reliability::UnreliableSequenced,
             channel: 0,
             priority: RakPriority::Normal,
+            ttl: None,
         }
     }
 }
@@ -96,25 +139,192 @@ impl From<String> for Message {
 
 /// Inbound message surfaced to applications.
 /// Carries the full user payload (ID + body) and metadata from the transport.
+///
+/// Unless it was reassembled from a split packet, `buffer` shares the
+/// allocation of the datagram it arrived in; copy it out before holding on
+/// to many small messages for long.
 #[derive(Debug, Clone)]
 pub struct ReceivedMessage {
     pub buffer: Bytes,
     pub reliability: Reliability,
     pub channel: u8,
+    /// When the datagram carrying the message was received, as read once per
+    /// muxer iteration. Ordered messages held back behind a gap keep the
+    /// time they actually arrived.
+    pub recv_time: Instant,
+    /// Sequence number of that datagram. For a split message, the datagram
+    /// that delivered its last part. `None` only for messages that reached
+    /// the session without one.
+    pub datagram_sequence: Option<u32>,
+}
+
+/// Resend a received message as it arrived: same buffer, reliability and
+/// channel, normal priority. The payload is not copied.
+impl From<ReceivedMessage> for Message {
+    fn from(msg: ReceivedMessage) -> Self {
+        Self {
+            buffer: msg.buffer,
+            reliability: msg.reliability,
+            channel: msg.channel,
+            priority: RakPriority::Normal,
+            ttl: None,
+        }
+    }
 }
 
 /// Message sent from a connection handle to the transport muxer,
 /// representing an outbound logical RakNet packet.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OutboundMsg {
     /// Remote peer this logical packet should be sent to.
     pub peer: SocketAddr,
-    /// High-level RakNet packet to send.
-    pub packet: RaknetPacket,
+    /// Application message to send (ID byte + body), queued without copying.
+    pub buffer: Bytes,
     /// Desired reliability semantics for this send.
     pub reliability: Reliability,
     /// Ordering channel, typically 0 unless using multiple streams.
     pub channel: u8,
     /// Priority for the RakNet scheduler; lower index sends sooner.
     pub priority: RakPriority,
+    /// Dropped instead of sent if still queued at this time.
+    pub expires: Option<Instant>,
+}
+
+impl OutboundMsg {
+    /// Queue the message on `managed`, telling `receipt` how it fared.
+    pub(crate) fn queue(
+        self,
+        managed: &mut ManagedSession,
+        receipt: Option<tokio::sync::oneshot::Sender<ReceiptOutcome>>,
+        now: Instant,
+    ) -> Result<(), SessionError> {
+        managed.queue_app_message_with(
+            self.buffer,
+            self.reliability,
+            self.channel,
+            self.priority,
+            SendOptions {
+                receipt,
+                expires: self.expires,
+            },
+            now,
+        )
+    }
+
+    /// Like `queue`, for a message a connection handle reserved room for
+    /// with `SharedStats::admit`, handing the reservation back once queued.
+    pub(crate) fn queue_admitted(
+        self,
+        managed: &mut ManagedSession,
+        receipt: Option<tokio::sync::oneshot::Sender<ReceiptOutcome>>,
+        now: Instant,
+    ) -> Result<(), SessionError> {
+        let stats = managed.stats().clone();
+        let (len, reliability) = (self.buffer.len(), self.reliability);
+        let queued = self.queue(managed, receipt, now);
+        stats.release(len, reliability);
+        queued
+    }
+}
+
+/// What a connection handle puts on the muxer's outbound channel.
+#[derive(Debug)]
+pub(crate) enum Outbound {
+    Message(OutboundMsg),
+    /// Messages for one peer, queued together and flushed once.
+    Batch {
+        peer: SocketAddr,
+        msgs: Vec<OutboundMsg>,
+    },
+    /// One message, reporting on `done` once the peer has ACKed all of it
+    /// or it was lost.
+    Receipt {
+        msg: OutboundMsg,
+        done: tokio::sync::oneshot::Sender<ReceiptOutcome>,
+    },
+    /// Signal `done` once everything queued before it has been sent and
+    /// ACKed.
+    Flush {
+        peer: SocketAddr,
+        done: tokio::sync::oneshot::Sender<()>,
+    },
+    /// Disconnect with `reason` once everything queued before it is sent,
+    /// signalling `done` when the peer has ACKed it all.
+    Disconnect {
+        peer: SocketAddr,
+        reason: DisconnectReason,
+        done: tokio::sync::oneshot::Sender<()>,
+    },
+    /// `msg` for every connected peer of a listener but those in `except`;
+    /// its own `peer` is unused. `done` gets the number of peers it was
+    /// queued for.
+    Broadcast {
+        msg: OutboundMsg,
+        except: Vec<SocketAddr>,
+        done: tokio::sync::oneshot::Sender<usize>,
+    },
+}
+
+impl Outbound {
+    /// The peer this is for; `None` for a broadcast.
+    pub(crate) fn peer(&self) -> Option<SocketAddr> {
+        match self {
+            Outbound::Message(msg) | Outbound::Receipt { msg, .. } => Some(msg.peer),
+            Outbound::Batch { peer, .. }
+            | Outbound::Flush { peer, .. }
+            | Outbound::Disconnect { peer, .. } => Some(*peer),
+            Outbound::Broadcast { .. } => None,
+        }
+    }
+
+    /// The messages, in the order they were sent.
+    pub(crate) fn into_messages(self) -> impl Iterator<Item = OutboundMsg> {
+        let (one, batch) = match self {
+            Outbound::Message(msg) | Outbound::Receipt { msg, .. } => (Some(msg), Vec::new()),
+            Outbound::Batch { msgs, .. } => (None, msgs),
+            Outbound::Flush { .. } | Outbound::Disconnect { .. } | Outbound::Broadcast { .. } => {
+                (None, Vec::new())
+            }
+        };
+        one.into_iter().chain(batch)
+    }
+}
+
+impl From<OutboundMsg> for Outbound {
+    fn from(msg: OutboundMsg) -> Self {
+        Outbound::Message(msg)
+    }
+}
+
+/// Session control command for the transport muxer.
+///
+/// Travels on its own small channel that the muxer drains ahead of
+/// `OutboundMsg`, so admin actions are not stuck behind bulk data.
+#[derive(Debug)]
+pub(crate) enum ControlMsg {
+    /// Send a `DisconnectionNotification` and tear the session down.
+    Disconnect {
+        peer: SocketAddr,
+        reason: DisconnectReason,
+    },
+    /// Run maintenance and push out any pending ACKs/data immediately.
+    Flush { peer: SocketAddr },
+    /// `Disconnect` for a listener session picked by address or GUID;
+    /// `done` reports whether there was one.
+    Kick {
+        target: KickTarget,
+        reason: DisconnectReason,
+        done: tokio::sync::oneshot::Sender<bool>,
+    },
+    /// Report the listener's connected peers.
+    Peers {
+        done: tokio::sync::oneshot::Sender<Vec<PeerInfo>>,
+    },
+}
+
+/// Which session a `ControlMsg::Kick` is aimed at.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum KickTarget {
+    Addr(SocketAddr),
+    Guid(u64),
 }
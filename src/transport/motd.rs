@@ -0,0 +1,303 @@
+//! Bedrock Edition MOTDs.
+//!
+//! Bedrock servers advertise themselves with a `;`-separated string in the
+//! pong payload:
+//!
+//! ```text
+//! MCPE;<line 1>;<protocol>;<version>;<online>;<max>;<guid>;<line 2>;<game mode>;<game mode id>;<port v4>;<port v6>;
+//! ```
+//!
+//! `BedrockMotd` builds and parses that string so the field order only has
+//! to be right once. A `;` inside a text field is written as `\;` and a `\`
+//! as `\\`, which `parse` undoes.
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// Which Bedrock product the server is for; the MOTD's first field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Edition {
+    /// `MCPE`, the regular game.
+    #[default]
+    Pocket,
+    /// `MCEE`, Education Edition.
+    Education,
+}
+
+impl Edition {
+    fn tag(self) -> &'static str {
+        match self {
+            Edition::Pocket => "MCPE",
+            Edition::Education => "MCEE",
+        }
+    }
+}
+
+/// A Bedrock server's MOTD, field by field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BedrockMotd {
+    pub edition: Edition,
+    /// Server name, the first line shown in the server list.
+    pub line1: String,
+    /// Bedrock network protocol version, e.g. 527 for 1.19.1.
+    pub protocol_version: u32,
+    /// Game version shown next to the server, e.g. `1.19.1`.
+    pub game_version: String,
+    pub online_players: u32,
+    pub max_players: u32,
+    /// Should match the listener's GUID; pongs fill it in either way.
+    pub server_guid: u64,
+    /// Second line, usually the world name.
+    pub line2: String,
+    /// Game mode name, e.g. `Survival`.
+    pub game_mode: String,
+    /// Numeric game mode; vanilla servers send 1 alongside `Survival`.
+    pub game_mode_id: u8,
+    pub port_v4: Option<u16>,
+    pub port_v6: Option<u16>,
+}
+
+impl Default for BedrockMotd {
+    fn default() -> Self {
+        Self {
+            edition: Edition::Pocket,
+            line1: "Tokio-Raknet Default Advertisement".to_owned(),
+            protocol_version: 527,
+            game_version: "1.19.1".to_owned(),
+            online_players: 0,
+            max_players: 10,
+            server_guid: 0,
+            line2: "Tokio Raknet".to_owned(),
+            game_mode: "Survival".to_owned(),
+            game_mode_id: 1,
+            port_v4: Some(19132),
+            port_v6: Some(19133),
+        }
+    }
+}
+
+/// Why `BedrockMotd::parse` refused a MOTD.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseMotdError {
+    #[error("not a Bedrock MOTD")]
+    NotBedrock,
+    #[error("MOTD has no {0} field")]
+    MissingField(&'static str),
+    #[error("MOTD field {0} is not a number")]
+    InvalidField(&'static str),
+}
+
+impl BedrockMotd {
+    /// The advertisement string, ready for `RaknetListener::set_advertisement`.
+    pub fn to_advertisement_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// Read a MOTD from a pong's advertisement.
+    ///
+    /// The six fields up to the maximum player count must be there; older
+    /// servers leave out the rest, which then get empty or zero values. A
+    /// GUID written as a signed number is accepted too.
+    pub fn parse(advertisement: &[u8]) -> Result<Self, ParseMotdError> {
+        let text = String::from_utf8_lossy(advertisement);
+        let fields: Vec<String> = split_fields(text.as_bytes())
+            .into_iter()
+            .map(|field| unescape(&String::from_utf8_lossy(field)))
+            .collect();
+        let edition = match fields.first().map(String::as_str) {
+            Some("MCPE") => Edition::Pocket,
+            Some("MCEE") => Edition::Education,
+            _ => return Err(ParseMotdError::NotBedrock),
+        };
+        let required = |i: usize, name| fields.get(i).ok_or(ParseMotdError::MissingField(name));
+        let text = |i: usize| fields.get(i).cloned().unwrap_or_default();
+
+        Ok(Self {
+            edition,
+            line1: required(1, "line1")?.clone(),
+            protocol_version: number(&fields, 2, "protocol_version")?
+                .ok_or(ParseMotdError::MissingField("protocol_version"))?,
+            game_version: required(3, "game_version")?.clone(),
+            online_players: number(&fields, 4, "online_players")?
+                .ok_or(ParseMotdError::MissingField("online_players"))?,
+            max_players: number(&fields, 5, "max_players")?
+                .ok_or(ParseMotdError::MissingField("max_players"))?,
+            server_guid: match fields.get(6).map(String::as_str) {
+                None | Some("") => 0,
+                Some(guid) => guid
+                    .parse::<u64>()
+                    .or_else(|_| guid.parse::<i64>().map(|guid| guid as u64))
+                    .map_err(|_| ParseMotdError::InvalidField("server_guid"))?,
+            },
+            line2: text(7),
+            game_mode: text(8),
+            game_mode_id: number(&fields, 9, "game_mode_id")?.unwrap_or(0),
+            port_v4: number(&fields, 10, "port_v4")?,
+            port_v6: number(&fields, 11, "port_v6")?,
+        })
+    }
+}
+
+impl fmt::Display for BedrockMotd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let port = |port: Option<u16>| port.map(|p| p.to_string()).unwrap_or_default();
+        write!(
+            f,
+            "{};{};{};{};{};{};{};{};{};{};{};{};",
+            self.edition.tag(),
+            escape(&self.line1),
+            self.protocol_version,
+            escape(&self.game_version),
+            self.online_players,
+            self.max_players,
+            self.server_guid,
+            escape(&self.line2),
+            escape(&self.game_mode),
+            self.game_mode_id,
+            port(self.port_v4),
+            port(self.port_v6),
+        )
+    }
+}
+
+impl From<BedrockMotd> for Vec<u8> {
+    fn from(motd: BedrockMotd) -> Self {
+        motd.to_advertisement_bytes()
+    }
+}
+
+/// Field `i` as a number; `None` if it is missing or empty.
+fn number<T: std::str::FromStr>(
+    fields: &[String],
+    i: usize,
+    name: &'static str,
+) -> Result<Option<T>, ParseMotdError> {
+    match fields.get(i).map(String::as_str) {
+        None | Some("") => Ok(None),
+        Some(field) => field
+            .parse()
+            .map(Some)
+            .map_err(|_| ParseMotdError::InvalidField(name)),
+    }
+}
+
+fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace(';', "\\;")
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next @ ('\\' | ';'))) => {
+                out.push(next);
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// The fields of a MOTD, split on every `;` not escaped with `\`, like
+/// `split` would: a trailing `;` leaves an empty last field. Fields are left
+/// escaped.
+pub(crate) fn split_fields(motd: &[u8]) -> Vec<&[u8]> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, &b) in motd.iter().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b';' => {
+                fields.push(&motd[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&motd[start..]);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shaped like what vanilla Bedrock Dedicated Server sends.
+    const BDS: &str = "MCPE;Dedicated Server;527;1.19.1;0;10;13253860892328930865;Bedrock level;Survival;1;19132;19133;";
+
+    #[test]
+    fn dedicated_server_motd_round_trips() {
+        let motd = BedrockMotd::parse(BDS.as_bytes()).unwrap();
+        assert_eq!(
+            motd,
+            BedrockMotd {
+                edition: Edition::Pocket,
+                line1: "Dedicated Server".into(),
+                protocol_version: 527,
+                game_version: "1.19.1".into(),
+                online_players: 0,
+                max_players: 10,
+                server_guid: 13253860892328930865,
+                line2: "Bedrock level".into(),
+                game_mode: "Survival".into(),
+                game_mode_id: 1,
+                port_v4: Some(19132),
+                port_v6: Some(19133),
+            }
+        );
+        assert_eq!(motd.to_string(), BDS);
+    }
+
+    #[test]
+    fn short_and_signed_guid_motds_parse() {
+        // Older servers stop after the player counts.
+        let motd = BedrockMotd::parse(b"MCPE;Old Server;113;1.1.0;3;20").unwrap();
+        assert_eq!(motd.line1, "Old Server");
+        assert_eq!((motd.online_players, motd.max_players), (3, 20));
+        assert_eq!((motd.server_guid, motd.port_v4), (0, None));
+
+        let motd =
+            BedrockMotd::parse(b"MCEE;Class;390;1.14.60;1;40;-42;World;Creative;1;;;").unwrap();
+        assert_eq!(motd.edition, Edition::Education);
+        assert_eq!(motd.server_guid, -42i64 as u64);
+        assert_eq!((motd.port_v4, motd.port_v6), (None, None));
+    }
+
+    #[test]
+    fn semicolons_in_text_fields_are_escaped() {
+        let motd = BedrockMotd {
+            line1: "Fun; Games \\ More".into(),
+            line2: "a;b".into(),
+            ..Default::default()
+        };
+        let bytes = motd.to_advertisement_bytes();
+        assert!(
+            bytes.starts_with(b"MCPE;Fun\\; Games \\\\ More;527;"),
+            "{}",
+            String::from_utf8_lossy(&bytes)
+        );
+        assert_eq!(split_fields(&bytes).len(), 13);
+        assert_eq!(BedrockMotd::parse(&bytes).unwrap(), motd);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_bedrock_motd() {
+        assert_eq!(
+            BedrockMotd::parse(b"My Server;1;2"),
+            Err(ParseMotdError::NotBedrock)
+        );
+        assert_eq!(
+            BedrockMotd::parse(b"MCPE;Name;527"),
+            Err(ParseMotdError::MissingField("game_version"))
+        );
+        assert_eq!(
+            BedrockMotd::parse(b"MCPE;Name;new;1.19.1;0;10"),
+            Err(ParseMotdError::InvalidField("protocol_version"))
+        );
+    }
+}
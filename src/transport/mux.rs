@@ -1,26 +1,188 @@
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use bytes::{BufMut, BytesMut};
-use tokio::net::UdpSocket;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
 use tokio::time::{self, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
 
+use crate::error::ConfigError;
+use crate::protocol::constants::MINIMUM_MTU_SIZE;
 use crate::protocol::packet::RaknetPacket;
 use crate::session::manager::ManagedSession;
+use crate::session::stats::SharedStats;
 use crate::transport::ReceivedMessage;
+use crate::transport::socket::DatagramSocket;
 
 const TICK_INTERVAL_MS: u64 = 20;
 
+/// Outbound items a muxer takes off its channel in one turn before
+/// flushing, so small messages sent back to back share datagrams without
+/// starving the socket.
+pub(crate) const MAX_OUTBOUND_BATCH: usize = 64;
+const RECV_BACKOFF_BASE: Duration = Duration::from_millis(10);
+const RECV_BACKOFF_CAP: Duration = Duration::from_secs(1);
+/// Consecutive identical receive errors after which the socket is treated as dead.
+const RECV_FATAL_THRESHOLD: u32 = 20;
+
+/// Current time for session bookkeeping.
+///
+/// Read from tokio's clock so that, with time paused in tests, session
+/// deadlines advance together with the muxer's timers.
+pub(crate) fn now() -> Instant {
+    time::Instant::now().into_std()
+}
+
+/// Socket receive buffer that hands each datagram out as its own `Bytes`.
+///
+/// Frames decoded from it, and the messages delivered from those, share the
+/// datagram's allocation instead of being copied out. The allocation is
+/// reused once everything sliced from it has been dropped.
+pub(crate) struct RecvBuffer {
+    buf: BytesMut,
+    len: usize,
+}
+
+impl RecvBuffer {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(len),
+            len,
+        }
+    }
+
+    /// Space for the next datagram.
+    pub(crate) fn spare(&mut self) -> &mut [u8] {
+        self.buf.resize(self.len, 0);
+        &mut self.buf[..]
+    }
+
+    /// The datagram received into the first `len` bytes of `spare`.
+    pub(crate) fn take(&mut self, len: usize) -> Bytes {
+        let datagram = self.buf.split_to(len).freeze();
+        self.buf.clear();
+        datagram
+    }
+}
+
 pub fn new_tick_interval() -> Interval {
     let mut tick = time::interval(Duration::from_millis(TICK_INTERVAL_MS));
     tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
     tick
 }
 
+/// An MTU given as a plain size, checked against what the handshake can
+/// negotiate.
+pub(crate) fn checked_mtu(mtu: usize) -> Result<u16, ConfigError> {
+    match u16::try_from(mtu) {
+        Ok(mtu) if mtu >= MINIMUM_MTU_SIZE => Ok(mtu),
+        _ => Err(ConfigError::OutOfRange {
+            field: "mtu",
+            value: mtu as u64,
+            min: MINIMUM_MTU_SIZE as u64,
+            max: u16::MAX as u64,
+        }),
+    }
+}
+
+/// Resolves once `token` is cancelled; never, if there is none.
+pub(crate) async fn cancelled(token: Option<&CancellationToken>) {
+    match token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// What the muxer should do after a failed `recv_from`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecvErrorAction {
+    /// Benign (e.g. Windows ICMP port unreachable); carry on immediately.
+    Ignore,
+    /// Sleep before polling the socket again.
+    Retry(Duration),
+    /// The socket is not coming back; shut the muxer down.
+    Fatal,
+}
+
+/// Exponential backoff over consecutive identical socket receive errors.
+#[derive(Debug, Default)]
+pub(crate) struct RecvBackoff {
+    consecutive: u32,
+    last_kind: Option<std::io::ErrorKind>,
+}
+
+impl RecvBackoff {
+    pub fn on_success(&mut self) {
+        self.consecutive = 0;
+        self.last_kind = None;
+    }
+
+    pub fn on_error(&mut self, kind: std::io::ErrorKind) -> RecvErrorAction {
+        if kind == std::io::ErrorKind::ConnectionReset {
+            return RecvErrorAction::Ignore;
+        }
+
+        if self.last_kind == Some(kind) {
+            self.consecutive += 1;
+        } else {
+            self.last_kind = Some(kind);
+            self.consecutive = 1;
+        }
+
+        if self.consecutive >= RECV_FATAL_THRESHOLD {
+            return RecvErrorAction::Fatal;
+        }
+
+        let shift = (self.consecutive - 1).min(16);
+        RecvErrorAction::Retry((RECV_BACKOFF_BASE * (1u32 << shift)).min(RECV_BACKOFF_CAP))
+    }
+}
+
+/// Callers of `RaknetStream::flush` waiting on one session, each with the
+/// flush mark taken when its request arrived.
+///
+/// Dropping it (with the session) wakes them with an error.
+#[derive(Debug, Default)]
+pub(crate) struct FlushWaiters(Vec<(u64, oneshot::Sender<()>)>);
+
+impl FlushWaiters {
+    pub fn push(&mut self, managed: &ManagedSession, done: oneshot::Sender<()>) {
+        self.0.push((managed.flush_mark(), done));
+    }
+
+    /// Wake every waiter whose frames `managed` has all sent and had ACKed.
+    pub fn notify(&mut self, managed: &ManagedSession) {
+        if self.0.is_empty() {
+            return;
+        }
+        let flushed = managed.flushed_to();
+        let (done, waiting) = std::mem::take(&mut self.0)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(mark, _)| *mark <= flushed);
+        self.0 = waiting;
+        for (_, done) in done {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Reconcile an MTU received from a peer during the handshake with our own limit.
+///
+/// Anything above `max` is clamped down to it. Anything below
+/// `MINIMUM_MTU_SIZE` cannot fit our framing and is refused with `None`.
+pub(crate) fn negotiate_mtu(peer_mtu: u16, max: u16) -> Option<u16> {
+    if peer_mtu < MINIMUM_MTU_SIZE || max < MINIMUM_MTU_SIZE {
+        return None;
+    }
+    Some(peer_mtu.min(max))
+}
+
 /// Flushes any pending maintenance and outbound datagrams for a managed session.
 #[tracing::instrument(skip_all, fields(peer= %peer.to_string()), level = "trace")]
 pub async fn flush_managed(
     managed: &mut ManagedSession,
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     peer: std::net::SocketAddr,
     now: Instant,
     run_tick: bool,
@@ -42,21 +204,232 @@ pub async fn flush_managed(
     }
 }
 
+/// Last flush for a session about to be dropped: whatever is queued (e.g. a
+/// `DisconnectionNotification`) plus ACKs for everything received, so the
+/// peer does not keep resending data that was already delivered.
+pub async fn flush_final(
+    managed: &mut ManagedSession,
+    socket: &impl DatagramSocket,
+    peer: std::net::SocketAddr,
+    now: Instant,
+) {
+    flush_managed(managed, socket, peer, now, false).await;
+    for d in managed.final_acks(now) {
+        tracing::trace!("send_final_ack");
+        let mut out = BytesMut::new();
+        d.encode(&mut out).expect("Bad datagram in queue.");
+        let _ = socket.send_to(&out, peer).await;
+    }
+}
+
 /// Convert a batch of decoded session packets into application messages
 /// (ID byte + payload) with transport metadata.
 pub fn into_received_messages(pkts: Vec<crate::session::IncomingPacket>) -> Vec<ReceivedMessage> {
-    let mut out = Vec::new();
-    for pkt in pkts {
-        if let RaknetPacket::UserData { id, payload } = pkt.packet {
-            let mut buf = BytesMut::with_capacity(1 + payload.len());
-            buf.put_u8(id);
-            buf.extend_from_slice(&payload);
-            out.push(ReceivedMessage {
-                buffer: buf.freeze(),
-                reliability: pkt.reliability,
-                channel: pkt.ordering_channel.unwrap_or(0),
-            });
-        }
-    }
-    out
+    pkts.into_iter().filter_map(into_received_message).collect()
+}
+
+/// Convert one decoded session packet into an application message, if it is user data.
+pub fn into_received_message(pkt: crate::session::IncomingPacket) -> Option<ReceivedMessage> {
+    if !matches!(pkt.packet, RaknetPacket::UserData { .. }) {
+        return None;
+    }
+    Some(ReceivedMessage {
+        buffer: pkt.raw,
+        reliability: pkt.reliability,
+        channel: pkt.ordering_channel.unwrap_or(0),
+        recv_time: pkt.recv_time,
+        datagram_sequence: pkt.datagram_sequence,
+    })
+}
+
+type AppSender = mpsc::Sender<Result<ReceivedMessage, crate::RaknetError>>;
+
+/// Forwards packets released by `ManagedSession::handle_datagram_with` to a
+/// session's application channel.
+///
+/// Messages go out with `try_send` while the channel has room, so the common
+/// case needs no intermediate buffer. Once it fills up, the rest are held
+/// back in order and `finish` sends them with backpressure.
+pub(crate) struct AppDelivery<'a> {
+    to_app: &'a AppSender,
+    stats: Arc<SharedStats>,
+    backlog: Vec<ReceivedMessage>,
+    closed: bool,
+}
+
+impl<'a> AppDelivery<'a> {
+    pub fn new(to_app: &'a AppSender, stats: Arc<SharedStats>) -> Self {
+        Self {
+            to_app,
+            stats,
+            backlog: Vec::new(),
+            closed: false,
+        }
+    }
+
+    pub fn push(&mut self, pkt: crate::session::IncomingPacket) {
+        if self.closed {
+            return;
+        }
+        let Some(msg) = into_received_message(pkt) else {
+            return;
+        };
+        let len = msg.buffer.len();
+        self.stats.add_incoming_channel_bytes(len);
+        if !self.backlog.is_empty() {
+            self.backlog.push(msg);
+            return;
+        }
+        match self.to_app.try_send(Ok(msg)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Ok(msg))) => self.backlog.push(msg),
+            Err(_) => {
+                self.stats.sub_incoming_channel_bytes(len);
+                self.closed = true;
+            }
+        }
+    }
+
+    /// Send anything held back. Returns `false` if the application side is gone.
+    pub async fn finish(self) -> bool {
+        if self.closed {
+            return false;
+        }
+        let mut backlog = self.backlog.into_iter();
+        while let Some(msg) = backlog.next() {
+            let len = msg.buffer.len();
+            if self.to_app.send(Ok(msg)).await.is_err() {
+                let dropped: usize = backlog.map(|m| m.buffer.len()).sum();
+                self.stats.sub_incoming_channel_bytes(len + dropped);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn negotiated_mtu_stays_within_bounds() {
+        let max = 1400;
+        assert_eq!(negotiate_mtu(0, max), None);
+        assert_eq!(negotiate_mtu(100, max), None);
+        assert_eq!(negotiate_mtu(575, max), None);
+        assert_eq!(negotiate_mtu(576, max), Some(576));
+        assert_eq!(negotiate_mtu(1400, max), Some(1400));
+        assert_eq!(negotiate_mtu(1500, max), Some(1400));
+        assert_eq!(negotiate_mtu(65535, max), Some(1400));
+        // A misconfigured limit below the minimum refuses everything.
+        assert_eq!(negotiate_mtu(1400, 500), None);
+    }
+
+    #[test]
+    fn backoff_grows_caps_and_escalates() {
+        let mut backoff = RecvBackoff::default();
+
+        assert_eq!(
+            backoff.on_error(ErrorKind::NetworkDown),
+            RecvErrorAction::Retry(RECV_BACKOFF_BASE)
+        );
+        assert_eq!(
+            backoff.on_error(ErrorKind::NetworkDown),
+            RecvErrorAction::Retry(RECV_BACKOFF_BASE * 2)
+        );
+
+        let mut last = RecvErrorAction::Ignore;
+        for _ in 2..RECV_FATAL_THRESHOLD - 1 {
+            last = backoff.on_error(ErrorKind::NetworkDown);
+        }
+        assert_eq!(last, RecvErrorAction::Retry(RECV_BACKOFF_CAP));
+        assert_eq!(
+            backoff.on_error(ErrorKind::NetworkDown),
+            RecvErrorAction::Fatal
+        );
+    }
+
+    #[test]
+    fn success_or_different_error_resets_backoff() {
+        let mut backoff = RecvBackoff::default();
+        for _ in 0..5 {
+            backoff.on_error(ErrorKind::NetworkDown);
+        }
+
+        backoff.on_success();
+        assert_eq!(
+            backoff.on_error(ErrorKind::NetworkDown),
+            RecvErrorAction::Retry(RECV_BACKOFF_BASE)
+        );
+
+        backoff.on_error(ErrorKind::NetworkDown);
+        assert_eq!(
+            backoff.on_error(ErrorKind::PermissionDenied),
+            RecvErrorAction::Retry(RECV_BACKOFF_BASE)
+        );
+    }
+
+    #[test]
+    fn connection_reset_is_ignored() {
+        let mut backoff = RecvBackoff::default();
+        for _ in 0..RECV_FATAL_THRESHOLD * 2 {
+            assert_eq!(
+                backoff.on_error(ErrorKind::ConnectionReset),
+                RecvErrorAction::Ignore
+            );
+        }
+    }
+
+    #[test]
+    fn relayed_messages_share_the_received_datagram() {
+        use crate::protocol::datagram::{Datagram, DatagramPayload};
+        use crate::protocol::reliability::Reliability;
+        use crate::protocol::state::RakPriority;
+        use crate::session::Session;
+        use crate::transport::Message;
+
+        let now = Instant::now();
+        let mut sender = Session::new(1400);
+        sender.queue_encoded(
+            Bytes::from_static(b"\xfeforwarded"),
+            Reliability::ReliableOrdered,
+            0,
+            RakPriority::Normal,
+        );
+        let mut wire = BytesMut::new();
+        sender
+            .build_data_datagram(now)
+            .unwrap()
+            .encode(&mut wire)
+            .unwrap();
+        let wire = wire.freeze();
+
+        let mut received = None;
+        let dgram = Datagram::decode(&mut wire.clone()).unwrap();
+        let DatagramPayload::EncapsulatedPackets(frames) = dgram.payload else {
+            panic!("expected data");
+        };
+        Session::new(1400)
+            .handle_data_datagram_with(dgram.header.sequence, frames, now, |pkt| {
+                received = into_received_message(pkt);
+            })
+            .unwrap();
+        let msg = received.unwrap();
+        assert_eq!(&msg.buffer[..], b"\xfeforwarded");
+        assert!(wire.as_ptr_range().contains(&msg.buffer.as_ptr()));
+
+        let msg = Message::from(msg);
+        let buffer = msg.buffer.clone();
+        let mut relay = Session::new(1400);
+        relay.queue_encoded(msg.buffer, msg.reliability, msg.channel, msg.priority);
+        let DatagramPayload::EncapsulatedPackets(frames) =
+            relay.build_data_datagram(now).unwrap().payload
+        else {
+            panic!("expected data");
+        };
+        assert_eq!(frames[0].payload.as_ptr(), buffer.as_ptr());
+        assert_eq!(frames[0].header.reliability, Reliability::ReliableOrdered);
+    }
 }
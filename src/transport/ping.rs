@@ -0,0 +1,98 @@
+//! Querying a server with an unconnected ping, without connecting.
+//!
+//! This is what server lists do: one `UnconnectedPing` out, one
+//! `UnconnectedPong` back carrying the server's GUID and advertisement (the
+//! MOTD on Bedrock servers, see `BedrockMotd`).
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use tokio::time::Instant;
+
+use crate::error::PingError;
+use crate::protocol::constants::{DEFAULT_UNCONNECTED_MAGIC, MAXIMUM_MTU_SIZE};
+use crate::protocol::packet::{Packet, RaknetPacket, UnconnectedPing, UnconnectedPong};
+use crate::protocol::types::RaknetTime;
+
+use super::socket::DatagramSocket;
+use super::socket::bind_udp;
+
+/// A server's answer to an unconnected ping.
+#[derive(Debug, Clone)]
+pub struct PingResponse {
+    pub server_guid: u64,
+    /// Time from sending the ping to receiving the pong.
+    pub latency: Duration,
+    /// The server's advertisement, empty if it sent none.
+    pub advertisement: Bytes,
+}
+
+/// Ping `addr` from a fresh ephemeral socket, waiting up to `timeout` for
+/// the pong.
+pub async fn ping(addr: SocketAddr, timeout: Duration) -> Result<PingResponse, PingError> {
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let socket = bind_udp(local)?;
+    ping_with_socket(&socket, addr, timeout).await
+}
+
+/// Ping `addr` from an existing socket, so many pings can share one port.
+///
+/// Datagrams that aren't this ping's pong are read and dropped, so pings on
+/// one socket have to run one after another: concurrent calls would take
+/// each other's replies.
+pub async fn ping_with_socket<S: DatagramSocket>(
+    socket: &S,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> Result<PingResponse, PingError> {
+    // Servers echo the time back; it tells this ping's pong from stale ones.
+    let ping_time = RaknetTime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+    );
+    let mut out = BytesMut::new();
+    RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time,
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    })
+    .encode(&mut out)
+    .expect("an unconnected ping always encodes");
+
+    let sent = Instant::now();
+    let deadline = sent + timeout;
+    socket.send_to(&out, addr).await?;
+
+    let mut buf = vec![0u8; MAXIMUM_MTU_SIZE as usize];
+    loop {
+        let (len, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| PingError::Timeout)??;
+        if !same_peer(from, addr) || buf[..len].first() != Some(&UnconnectedPong::ID) {
+            continue;
+        }
+        // Decoding checks the magic.
+        let RaknetPacket::UnconnectedPong(pong) = RaknetPacket::decode(&mut &buf[..len])? else {
+            continue;
+        };
+        if pong.ping_time.0 != ping_time.0 {
+            continue;
+        }
+        return Ok(PingResponse {
+            server_guid: pong.server_guid,
+            latency: sent.elapsed(),
+            advertisement: pong.advertisement.0.unwrap_or_default(),
+        });
+    }
+}
+
+/// Whether a datagram from `from` came from `addr`, allowing for a
+/// dual-stack socket reporting IPv4 senders as v4-mapped IPv6.
+fn same_peer(from: SocketAddr, addr: SocketAddr) -> bool {
+    from.port() == addr.port() && from.ip().to_canonical() == addr.ip().to_canonical()
+}
@@ -0,0 +1,120 @@
+//! The datagram socket the listener and client muxers run on.
+//!
+//! Production code uses tokio's `UdpSocket`; tests can substitute an
+//! in-memory implementation (see `transport::memory`) to drive the full
+//! stack deterministically under simulated time.
+
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// A non-blocking tokio socket bound to `addr`.
+pub(crate) fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Minimal unconnected datagram socket.
+///
+/// `recv_from` must be cancel-safe: the muxers poll it inside `select!` and
+/// drop the future whenever another branch wins.
+pub trait DatagramSocket: Send + Sync + 'static {
+    /// Send one datagram to `target`, returning the number of bytes sent.
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Receive one datagram into `buf`, returning its length and sender.
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// The address this socket is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Every address this socket listens on; more than one for a
+    /// dual-stack socket.
+    fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![self.local_addr()?])
+    }
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// An IPv6 socket that takes IPv4 traffic too, with `IPV6_V6ONLY` off
+/// whatever the OS default.
+///
+/// IPv4 peers show up as plain IPv4 addresses rather than v4-mapped IPv6
+/// ones, and IPv4 targets are mapped on the way out, so the rest of the
+/// stack never sees the difference.
+pub struct DualStackSocket {
+    socket: UdpSocket,
+}
+
+impl DualStackSocket {
+    /// Bind `[::]:port`; port 0 picks a free one. Must be called inside a
+    /// tokio runtime.
+    pub fn bind(port: u16) -> io::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+        })
+    }
+}
+
+impl DatagramSocket for DualStackSocket {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let target = match target {
+            SocketAddr::V4(v4) => SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port())),
+            v6 => v6,
+        };
+        self.socket.send_to(buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, peer) = self.socket.recv_from(buf).await?;
+        Ok((len, SocketAddr::new(peer.ip().to_canonical(), peer.port())))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let port = self.socket.local_addr()?.port();
+        Ok(vec![
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        ])
+    }
+}
@@ -1,48 +1,74 @@
 use std::net::SocketAddr;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
 use std::time::{Duration, Instant};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 use tokio::net::UdpSocket;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::{self, MissedTickBehavior, timeout};
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::PollSender;
 
+use crate::error::{HandshakeFailure, HandshakePhase, RecvTimeoutError, TrySendError};
 use crate::protocol::{
     constants::{
-        DEFAULT_UNCONNECTED_MAGIC, MAXIMUM_MTU_SIZE, MINIMUM_MTU_SIZE, RAKNET_PROTOCOL_VERSION,
-        UDP_HEADER_SIZE,
+        DEFAULT_UNCONNECTED_MAGIC, MAXIMUM_MTU_SIZE, MINIMUM_MTU_SIZE, MTU_SIZES,
+        RAKNET_PROTOCOL_VERSION, UDP_HEADER_SIZE,
     },
     datagram::Datagram,
-    packet::RaknetPacket,
-    types::EoBPadding,
+    packet::{DecodeError, RaknetPacket},
+    state::DisconnectReason,
+    types::{EoBPadding, RaknetTime},
 };
-use crate::session::manager::{ConnectionState, ManagedSession, SessionConfig, SessionRole};
+use crate::session::manager::{
+    BacklogLimit, ConnectionState, DebugLogConfig, HandshakeTiming, ManagedSession, SendQueueLimit,
+    SessionConfig, SessionRole,
+};
+use crate::session::stats::{MemoryUsage, SharedStats, StatsSnapshot};
+use crate::session::{AckPolicy, ReceiptOutcome, ReorderLimit};
 
-use super::{OutboundMsg, ReceivedMessage};
+use super::capture::{Capture, Tapped};
+use super::listener_conn::NewConnection;
+use super::mux::{self, AppDelivery, RecvBuffer, negotiate_mtu};
+use super::socket::{DatagramSocket, bind_udp};
+use super::{ControlMsg, Outbound, OutboundMsg, ReceivedMessage};
 
 use crate::protocol::constants::{self};
 
 const TICK_INTERVAL: Duration = Duration::from_millis(20);
-const HANDSHAKE_RETRIES: usize = 3;
-const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(400);
-
 /// Configuration for a `RaknetStream`.
 #[derive(Debug, Clone)]
 pub struct RaknetStreamConfig {
-    /// MTU size to attempt negotiation with.
+    /// Largest MTU to probe the path with; the smaller `MTU_SIZES` are
+    /// tried in turn if it gets no answer.
     pub mtu: u16,
+    /// RakNet protocol version sent in `OpenConnectionRequest1`. Older
+    /// Bedrock servers speak 8, 9 or 10.
+    pub protocol_version: u8,
     /// Optional socket receive buffer size.
     pub socket_recv_buffer_size: Option<usize>,
     /// Optional socket send buffer size.
     pub socket_send_buffer_size: Option<usize>,
-    /// Timeout for the initial connection handshake.
+    /// How `connect` resends the offline handshake requests
+    /// (`OpenConnectionRequest1`/`2`) that get no answer.
+    pub handshake_retry: HandshakeRetry,
+    /// How long `connect` waits for the server to accept the connection
+    /// (`ConnectionRequest`) once the offline handshake is done.
     pub connection_timeout: Duration,
     /// Timeout for an active session.
     pub session_timeout: Duration,
+    /// How long the connection may go without sending before it pings the
+    /// server to keep it alive; `None` sends no automatic pings. Capped at a
+    /// quarter of `session_timeout`.
+    pub keepalive_interval: Option<Duration>,
     /// Maximum number of ordering channels.
     pub max_ordering_channels: usize,
     /// Maximum capacity of the ACK queue.
     pub ack_queue_capacity: usize,
-    /// Timeout for reassembling split packets.
+    /// How long a split packet may go without a new part before it is given up on.
     pub split_timeout: Duration,
     /// Maximum window size for reliable packets.
     pub reliable_window: u32,
@@ -50,26 +76,168 @@ pub struct RaknetStreamConfig {
     pub max_split_parts: u32,
     /// Maximum number of concurrent split packets being reassembled.
     pub max_concurrent_splits: usize,
+    /// Maximum payload bytes buffered across all split packets being reassembled.
+    pub max_split_bytes: usize,
+    /// Cap on ordered messages held back per channel waiting for a missing
+    /// one, and what to do when a peer exceeds it.
+    pub reorder_limit: ReorderLimit,
+    /// Lower bound on the retransmission timeout computed from the RTT.
+    pub min_rto: Duration,
+    /// Upper bound on the retransmission timeout.
+    pub max_rto: Duration,
+    /// Times one datagram is resent unACKed before the session times out.
+    pub max_resends: u32,
+    /// Resend what the server NAKs as soon as the NAK arrives.
+    pub fast_retransmit: bool,
+    /// When ACKs for received datagrams are sent.
+    pub ack_policy: AckPolicy,
+    /// Maximum encapsulated frames accepted in one datagram; more is treated as a bad packet.
+    pub max_frames_per_datagram: usize,
+    /// Cap on data waiting in the session's outgoing queue, and what to do
+    /// when a send would exceed it; `None` leaves the queue unbounded.
+    pub send_queue_limit: Option<SendQueueLimit>,
+    /// Close the connection with `QueueTooLong` once unsent frames plus
+    /// unacked datagrams stay above the limit past its grace period. Off by
+    /// default: a client is rarely the side that outpaces its peer.
+    pub backlog_limit: Option<BacklogLimit>,
+    /// Receives every raw datagram the client sends or receives, handshake included.
+    pub capture: Option<Capture>,
+    /// Shuts the connection down once cancelled: the server is sent
+    /// `ShuttingDown`, and a pending `connect` or `recv` ends with
+    /// `RaknetError::Shutdown`.
+    pub shutdown: Option<CancellationToken>,
+    /// How long a shutdown waits for the server to ACK the disconnect notification.
+    pub shutdown_timeout: Duration,
+    /// Messages (or batches) the outbound queue between the stream and its
+    /// muxer holds; `send` waits and `try_send` fails while it is full. At
+    /// least 1.
+    pub outbound_queue_capacity: usize,
+    /// The connection's debug log (see `dump_debug_log`); off unless
+    /// `debug_log_capacity` turns it on.
+    pub debug_log: DebugLogConfig,
+    /// Settings for the session. When set, the per-session fields above
+    /// (timeout, tunables, queue limit, debug log) are ignored and this is used instead,
+    /// with the role and GUID filled in by the client. `connect` fails with
+    /// `RaknetError::InvalidConfig` if it could never work.
+    pub session_config: Option<SessionConfig>,
 }
 
 impl Default for RaknetStreamConfig {
     fn default() -> Self {
         Self {
             mtu: 1400,
+            protocol_version: RAKNET_PROTOCOL_VERSION,
             socket_recv_buffer_size: None,
             socket_send_buffer_size: None,
+            handshake_retry: HandshakeRetry::default(),
             connection_timeout: Duration::from_secs(10),
             session_timeout: Duration::from_secs(10),
+            keepalive_interval: Some(constants::KEEPALIVE_INTERVAL),
             max_ordering_channels: constants::MAXIMUM_ORDERING_CHANNELS as usize,
             ack_queue_capacity: 1024,
-            split_timeout: Duration::from_secs(30),
+            split_timeout: Duration::from_secs(5),
             reliable_window: constants::MAX_ACK_SEQUENCES as u32,
             max_split_parts: 8192,
             max_concurrent_splits: 4096,
+            max_split_bytes: constants::DEFAULT_MAX_SPLIT_BYTES,
+            reorder_limit: ReorderLimit::default(),
+            min_rto: constants::MIN_RTO,
+            max_rto: constants::MAX_RTO,
+            max_resends: 10,
+            fast_retransmit: true,
+            ack_policy: AckPolicy::default(),
+            max_frames_per_datagram: constants::DEFAULT_MAX_FRAMES_PER_DATAGRAM,
+            send_queue_limit: None,
+            backlog_limit: None,
+            capture: None,
+            shutdown: None,
+            shutdown_timeout: Duration::from_secs(2),
+            outbound_queue_capacity: 1024,
+            debug_log: DebugLogConfig::default(),
+            session_config: None,
+        }
+    }
+}
+
+impl RaknetStreamConfig {
+    /// Keep the last `capacity` protocol events in the connection's debug log; 0
+    /// keeps none.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn debug_log_capacity(mut self, capacity: usize) -> Self {
+        self.debug_log = DebugLogConfig { capacity };
+        self
+    }
+}
+
+/// How `connect` resends an offline handshake request that gets no answer.
+///
+/// `OpenConnectionRequest1` is sent up to `attempts` times in all, stepping
+/// down through the MTU sizes it probes with so each gets an even share,
+/// largest first; `OpenConnectionRequest2` is then sent up to `attempts`
+/// times. The defaults are `MAXIMUM_CONNECTION_ATTEMPTS` sends,
+/// `TIME_BETWEEN_SEND_CONNECTION_ATTEMPTS` apart. When none is answered, `connect` fails with `RaknetError::Timeout` naming the
+/// phase: `MtuDiscovery` if no probe got a reply (usually the server is
+/// offline), `OpenConnection` if the second request didn't. A server that
+/// answers but turns the client away gives `HandshakeFailed`, `ServerFull`,
+/// `Banned` or `IncompatibleProtocol` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeRetry {
+    /// Sends of each request before giving up on it. At least 1.
+    pub attempts: usize,
+    /// How long each send waits for a reply.
+    pub attempt_timeout: Duration,
+    /// Pause after an unanswered send before the next one.
+    pub backoff: HandshakeBackoff,
+    /// `AlreadyConnected` and `IpRecentlyConnected` mostly mean the server
+    /// hasn't let go of an earlier connection from this client yet. With a
+    /// delay set, the handshake is started over once after it; `None` fails
+    /// with the refusal straight away.
+    pub recently_connected_delay: Option<Duration>,
+}
+
+impl Default for HandshakeRetry {
+    fn default() -> Self {
+        Self {
+            attempts: constants::MAXIMUM_CONNECTION_ATTEMPTS,
+            attempt_timeout: constants::TIME_BETWEEN_SEND_CONNECTION_ATTEMPTS,
+            backoff: HandshakeBackoff::Fixed(Duration::ZERO),
+            recently_connected_delay: None,
+        }
+    }
+}
+
+/// The pause between two sends of a handshake request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeBackoff {
+    /// The same pause every time.
+    Fixed(Duration),
+    /// `initial`, doubling after every unanswered send up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl HandshakeBackoff {
+    /// The pause after the `retry`th unanswered send, counting from 0.
+    fn delay(&self, retry: u32) -> Duration {
+        match *self {
+            HandshakeBackoff::Fixed(delay) => delay,
+            HandshakeBackoff::Exponential { initial, max } => initial
+                .checked_mul(1u32.checked_shl(retry).unwrap_or(u32::MAX))
+                .unwrap_or(max)
+                .min(max),
         }
     }
 }
 
+/// What the client muxer reports once the server accepted the connection.
+type ClientReady = (Arc<SharedStats>, Option<HandshakeTiming>);
+
+/// What a client stream was connected with.
+struct ConnectParams {
+    server: SocketAddr,
+    config: RaknetStreamConfig,
+    client_guid: u64,
+}
+
 /// A unified RakNet connection stream.
 ///
 /// This struct represents a connection to a remote peer, whether initiated locally (client)
@@ -77,29 +245,119 @@ impl Default for RaknetStreamConfig {
 pub struct RaknetStream {
     local: SocketAddr,
     peer: SocketAddr,
+    /// GUID the peer announced during the handshake.
+    peer_guid: u64,
+    /// MTU negotiated for this session.
+    mtu: u16,
+    /// Set on streams from `connect`, where the peer is the server.
+    server_guid: Option<u64>,
+    /// The security flag of the server's `OpenConnectionReply2`, on streams
+    /// from `connect`.
+    security: Option<bool>,
+    /// The online handshake's round trip, on streams from `connect`.
+    handshake_timing: Option<HandshakeTiming>,
+    /// What `connect` was called with, for `reconnect`.
+    connected_with: Option<Box<ConnectParams>>,
     incoming: mpsc::Receiver<Result<ReceivedMessage, crate::RaknetError>>,
-    outbound_tx: mpsc::Sender<OutboundMsg>,
+    outbound_tx: mpsc::Sender<Outbound>,
+    control_tx: mpsc::Sender<ControlMsg>,
+    stats: Arc<SharedStats>,
+    /// An error `recv_many` reached after draining messages, returned by
+    /// the next receive.
+    pending_error: Option<crate::RaknetError>,
+    /// Outbound slot reserved by `poll_send_ready`.
+    reserve: PollSender<Outbound>,
+    /// Whether `reserve` holds a slot; `PollSender` panics if used without.
+    reserved: bool,
 }
 
 impl RaknetStream {
     /// Internal constructor for creating a stream from an established connection.
     pub(crate) fn new(
         local: SocketAddr,
-        peer: SocketAddr,
-        incoming: mpsc::Receiver<Result<ReceivedMessage, crate::RaknetError>>,
-        outbound_tx: mpsc::Sender<OutboundMsg>,
+        conn: NewConnection,
+        outbound_tx: mpsc::Sender<Outbound>,
+        control_tx: mpsc::Sender<ControlMsg>,
     ) -> Self {
         Self {
             local,
-            peer,
-            incoming,
+            peer: conn.peer,
+            peer_guid: conn.guid,
+            server_guid: None,
+            security: None,
+            handshake_timing: None,
+            connected_with: None,
+            mtu: conn.mtu,
+            incoming: conn.incoming,
+            reserve: PollSender::new(outbound_tx.clone()),
+            reserved: false,
             outbound_tx,
+            control_tx,
+            stats: conn.stats,
+            pending_error: None,
         }
     }
 
     /// Connect to a RakNet server at the given address using default configuration.
     pub async fn connect(server: SocketAddr) -> Result<Self, crate::RaknetError> {
-        Self::connect_with_config(server, RaknetStreamConfig::default()).await
+        let socket = bind_any_udp()?;
+        Self::connect_with_socket(socket, server, RaknetStreamConfig::default().mtu as usize).await
+    }
+
+    /// Connect to `server` with the default configuration over a socket the
+    /// caller has already bound and set up, proposing at most `mtu`.
+    ///
+    /// This is the way to connect over a hole punched through a NAT: the
+    /// socket is never rebound, and nothing is ever sent on it except to
+    /// `server`. Datagrams from any other address are dropped, during the
+    /// handshake and after it, so a third party can't answer in the
+    /// server's place; anything read before this call is the caller's.
+    /// `local_addr` is the socket's own bound address, not the mapping a
+    /// NAT gave it.
+    ///
+    /// Fails with `RaknetError::InvalidConfig` if `mtu` is below
+    /// `MINIMUM_MTU_SIZE` or doesn't fit in a `u16`.
+    pub async fn connect_with_socket(
+        socket: UdpSocket,
+        server: SocketAddr,
+        mtu: usize,
+    ) -> Result<Self, crate::RaknetError> {
+        let config = RaknetStreamConfig {
+            mtu: mux::checked_mtu(mtu)?,
+            ..Default::default()
+        };
+        Self::connect_on(socket, server, config).await
+    }
+
+    /// Connect to `server` from `local`, proposing at most `mtu`: to pick
+    /// the source IP on a multi-homed host, or a fixed source port for NAT
+    /// traversal. Port 0 picks a free port, which `local_addr` then reports.
+    ///
+    /// Fails with `RaknetError::Io` if `local` can't be bound.
+    pub async fn connect_from(
+        local: SocketAddr,
+        server: SocketAddr,
+        mtu: usize,
+    ) -> Result<Self, crate::RaknetError> {
+        Self::connect_with_socket(bind_udp(local)?, server, mtu).await
+    }
+
+    /// Connect to `server` proposing at most `mtu`, giving up with
+    /// `RaknetError::Shutdown` as soon as `cancel` is cancelled.
+    ///
+    /// The token stays with the connection once it is up, shutting it down
+    /// like `RaknetStreamConfig::shutdown`.
+    pub async fn connect_with_cancel(
+        server: SocketAddr,
+        mtu: usize,
+        cancel: CancellationToken,
+    ) -> Result<Self, crate::RaknetError> {
+        let config = RaknetStreamConfig {
+            mtu: mux::checked_mtu(mtu)?,
+            shutdown: Some(cancel),
+            ..Default::default()
+        };
+        Self::connect_with_config(server, config).await
     }
 
     /// Connect to a RakNet server with a custom configuration.
@@ -107,29 +365,108 @@ impl RaknetStream {
         server: SocketAddr,
         config: RaknetStreamConfig,
     ) -> Result<Self, crate::RaknetError> {
-        let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_nonblocking(true)?;
+        let socket = bind_any_udp()?;
+        Self::connect_on(socket, server, config).await
+    }
+
+    /// Connect to `server` over an already bound socket.
+    ///
+    /// Like every `connect`, this can be dropped or cancelled at any point:
+    /// the socket is closed and nothing is left running. If the online
+    /// handshake had started, the server is sent a disconnect on the way
+    /// out.
+    pub async fn connect_on<S: DatagramSocket>(
+        socket: S,
+        server: SocketAddr,
+        config: RaknetStreamConfig,
+    ) -> Result<Self, crate::RaknetError> {
+        Self::connect_as(socket, server, config, client_guid()).await
+    }
 
-        // if let Some(size) = config.socket_recv_buffer_size {
-        //     let _ = socket.set_recv_buffer_size(size);
-        // }
-        // if let Some(size) = config.socket_send_buffer_size {
-        //     let _ = socket.set_send_buffer_size(size);
-        // }
+    /// Connect again with what this stream was connected with, including
+    /// its client GUID, so the server sees the same client. A fresh socket
+    /// is bound on the same local IP.
+    ///
+    /// This session is disconnected first. If the server still knows it
+    /// (`AlreadyConnected`) or refuses the IP for having just disconnected
+    /// (`IpRecentlyConnected`), the handshake is retried once after
+    /// `HandshakeRetry::recently_connected_delay` if the stream was connected
+    /// with one set.
+    ///
+    /// Fails with `RaknetError::NotReconnectable` on a stream from `accept`,
+    /// which has nothing to reconnect to.
+    pub async fn reconnect(&self) -> Result<RaknetStream, crate::RaknetError> {
+        let Some(params) = self.connected_with.as_deref() else {
+            return Err(crate::RaknetError::NotReconnectable);
+        };
+        let _ = self.control_tx.try_send(ControlMsg::Disconnect {
+            peer: self.peer,
+            reason: DisconnectReason::Disconnected,
+        });
+
+        let socket = bind_udp(SocketAddr::new(self.local.ip(), 0))?;
+        let config = params.config.clone();
+        Self::connect_as(socket, params.server, config, params.client_guid).await
+    }
 
-        let socket = UdpSocket::from_std(socket)?;
+    async fn connect_as<S: DatagramSocket>(
+        socket: S,
+        server: SocketAddr,
+        config: RaknetStreamConfig,
+        client_guid: u64,
+    ) -> Result<Self, crate::RaknetError> {
+        let connected_with = Box::new(ConnectParams {
+            server,
+            config: config.clone(),
+            client_guid,
+        });
+        client_session_config(&config, client_guid).validate()?;
+        let socket = Tapped::new(socket, config.capture.clone())?;
         let local = socket.local_addr()?;
 
         // Perform offline handshake using OpenConnectionRequest1/2.
-        let client_guid = client_guid();
-        let handshake =
-            perform_offline_handshake(&socket, server, config.mtu as usize, client_guid).await?;
+        let offline = async {
+            let mut retry_after = config.handshake_retry.recently_connected_delay;
+            loop {
+                let res = perform_offline_handshake(
+                    &socket,
+                    server,
+                    config.mtu as usize,
+                    client_guid,
+                    config.protocol_version,
+                    &config.handshake_retry,
+                )
+                .await;
+                let still_known = matches!(
+                    &res,
+                    Err(crate::RaknetError::HandshakeFailed {
+                        cause: HandshakeFailure::AlreadyConnected { .. }
+                            | HandshakeFailure::IpRecentlyConnected,
+                        ..
+                    })
+                );
+                match retry_after.take() {
+                    Some(delay) if still_known => {
+                        tracing::debug!(%server, ?delay, "server still knows us, retrying");
+                        time::sleep(delay).await;
+                    }
+                    _ => break res,
+                }
+            }
+        };
+        let handshake = tokio::select! {
+            res = offline => res?,
+            _ = mux::cancelled(config.shutdown.as_ref()) => return Err(crate::RaknetError::Shutdown),
+        };
 
         // Use negotiated MTU
         let mut config = config;
         config.mtu = handshake.mtu;
+        let connection_timeout = config.connection_timeout;
 
-        let (outbound_tx, outbound_rx) = mpsc::channel::<OutboundMsg>(1024);
+        let (outbound_tx, outbound_rx) =
+            mpsc::channel::<Outbound>(config.outbound_queue_capacity.max(1));
+        let (control_tx, control_rx) = mpsc::channel::<ControlMsg>(64);
         let (to_app_tx, to_app_rx) =
             mpsc::channel::<Result<ReceivedMessage, crate::RaknetError>>(128);
         let (ready_tx, ready_rx) = oneshot::channel();
@@ -140,6 +477,7 @@ impl RaknetStream {
             client_guid, // Use the same guid generated above
             secure_connection_established: handshake.secure_connection_established,
             outbound_rx,
+            control_rx,
             to_app: to_app_tx,
             ready: ready_tx,
             config,
@@ -147,19 +485,44 @@ impl RaknetStream {
 
         tokio::spawn(run_client_muxer(socket, context));
 
-        match ready_rx.await {
-            Ok(Ok(())) => Ok(Self {
-                local,
+        let Ok(ready) = timeout(connection_timeout, ready_rx).await else {
+            // The muxer stops on this and takes the half-open session with it.
+            let _ = control_tx.try_send(ControlMsg::Disconnect {
                 peer: server,
-                incoming: to_app_rx,
-                outbound_tx,
+                reason: DisconnectReason::Disconnected,
+            });
+            return Err(crate::RaknetError::Timeout {
+                phase: HandshakePhase::ConnectionRequest,
+            });
+        };
+        match ready {
+            Ok(Ok((stats, timing))) => Ok(Self {
+                server_guid: Some(handshake.server_guid),
+                security: Some(handshake.secure_connection_established),
+                handshake_timing: timing,
+                connected_with: Some(connected_with),
+                ..Self::new(
+                    local,
+                    NewConnection {
+                        peer: server,
+                        guid: handshake.server_guid,
+                        mtu: handshake.mtu,
+                        protocol_version: handshake.protocol_version,
+                        server_addr: None,
+                        incoming: to_app_rx,
+                        stats,
+                    },
+                    outbound_tx,
+                    control_tx,
+                )
             }),
             Ok(Err(e)) => Err(e),
-            Err(_) => Err(crate::RaknetError::ConnectionAborted),
+            Err(_) => Err(crate::RaknetError::ConnectionClosed),
         }
     }
 
-    /// Returns the local address this stream is bound to.
+    /// Returns the local address this stream is bound to: the socket's own
+    /// address, which behind a NAT is not the one the peer sees.
     pub fn local_addr(&self) -> SocketAddr {
         self.local
     }
@@ -169,6 +532,71 @@ impl RaknetStream {
         self.peer
     }
 
+    /// Returns the MTU negotiated with the peer during the handshake, which
+    /// may be lower than the one configured.
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Returns the RakNet GUID the peer sent during the handshake: the
+    /// client's from `OpenConnectionRequest2` on an accepted stream, the
+    /// server's from `OpenConnectionReply2` on a connected one.
+    pub fn peer_guid(&self) -> u64 {
+        self.peer_guid
+    }
+
+    /// Returns the server's GUID on a stream from `connect`, and `None` on
+    /// one from `accept`, where the peer is a client.
+    pub fn server_guid(&self) -> Option<u64> {
+        self.server_guid
+    }
+
+    /// Returns the round trip from our `ConnectionRequest` to the server's
+    /// `ConnectionRequestAccepted` on a stream from `connect`, and `None` on
+    /// one from `accept`. The session's RTT estimate starts from it.
+    pub fn handshake_rtt(&self) -> Option<Duration> {
+        self.handshake_timing.map(|t| t.rtt)
+    }
+
+    /// Returns the timestamps of the server's `ConnectionRequestAccepted`
+    /// on a stream from `connect`: ours from the `ConnectionRequest`, echoed
+    /// back, and the server's own, on its clock.
+    pub fn handshake_timestamps(&self) -> Option<(RaknetTime, RaknetTime)> {
+        self.handshake_timing
+            .map(|t| (t.request_timestamp, t.accepted_timestamp))
+    }
+
+    /// Returns whether the server set the security flag in its
+    /// `OpenConnectionReply2` on a stream from `connect`, and `None` on one
+    /// from `accept`. The client echoes it in its `ConnectionRequest`.
+    pub fn security(&self) -> Option<bool> {
+        self.security
+    }
+
+    /// Returns a snapshot of this connection's counters.
+    ///
+    /// Reads shared atomics directly; never waits on the muxer task.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Returns the bytes currently buffered for this connection, by area.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.stats.memory_usage()
+    }
+
+    /// The recent protocol events of this connection, oldest first.
+    ///
+    /// Empty unless the connection was configured with a
+    /// `debug_log_capacity`. Reads the log directly; never waits on the muxer.
+    #[cfg(any(test, feature = "debug-log"))]
+    pub fn dump_debug_log(&self) -> Vec<crate::session::debug_log::DebugEvent> {
+        self.stats
+            .debug_log()
+            .map(|log| log.snapshot())
+            .unwrap_or_default()
+    }
+
     pub async fn recv(&mut self) -> Option<Result<Bytes, crate::RaknetError>> {
         match self.recv_msg().await? {
             Ok(msg) => Some(Ok(msg.buffer)),
@@ -177,33 +605,622 @@ impl RaknetStream {
     }
 
     pub async fn recv_msg(&mut self) -> Option<Result<ReceivedMessage, crate::RaknetError>> {
-        self.incoming.recv().await
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Like `recv_msg`, but gives up after `dur` with
+    /// `RecvTimeoutError::TimedOut`.
+    ///
+    /// Giving up takes nothing off the queue, so a message that arrives just
+    /// after the deadline is returned by the next receive. `Ok(None)` means
+    /// the connection is closed.
+    pub async fn recv_timeout(
+        &mut self,
+        dur: Duration,
+    ) -> Result<Option<ReceivedMessage>, RecvTimeoutError> {
+        match timeout(dur, self.recv_msg()).await {
+            Ok(Some(Ok(msg))) => Ok(Some(msg)),
+            Ok(Some(Err(e))) => Err(e.into()),
+            Ok(None) => Ok(None),
+            Err(_) => Err(RecvTimeoutError::TimedOut),
+        }
+    }
+
+    /// Polls for the next received message, for hand-written futures and
+    /// combinators.
+    ///
+    /// `Poll::Pending` registers `cx`'s waker and takes nothing, so dropping
+    /// it loses no message. `Ready(None)` means the connection is closed.
+    pub fn poll_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<ReceivedMessage, crate::RaknetError>>> {
+        if let Some(e) = self.pending_error.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let res = ready!(self.incoming.poll_recv(cx));
+        if let Some(Ok(msg)) = &res {
+            self.stats.sub_incoming_channel_bytes(msg.buffer.len());
+        }
+        Poll::Ready(res)
+    }
+
+    /// Takes the next received message without waiting.
+    ///
+    /// Returns `Ok(None)` if nothing is queued right now, and an error once
+    /// the connection has closed, with the reason first if there is one and
+    /// `RaknetError::ConnectionClosed` after that. Mixes freely with
+    /// `recv_msg`.
+    ///
+    /// Suits a loop that polls the network once per frame:
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use tokio_raknet::transport::RaknetStream;
+    ///
+    /// # async fn run(mut stream: RaknetStream) -> Result<(), tokio_raknet::RaknetError> {
+    /// let mut tick = tokio::time::interval(Duration::from_millis(50));
+    /// loop {
+    ///     tick.tick().await;
+    ///     while let Some(msg) = stream.try_recv()? {
+    ///         // Apply `msg` to the game state.
+    ///         # let _ = msg;
+    ///     }
+    ///     // Advance the simulation and send updates.
+    /// }
+    /// # }
+    /// ```
+    pub fn try_recv(&mut self) -> Result<Option<ReceivedMessage>, crate::RaknetError> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(e);
+        }
+        match self.incoming.try_recv() {
+            Ok(Ok(msg)) => {
+                self.stats.sub_incoming_channel_bytes(msg.buffer.len());
+                Ok(Some(msg))
+            }
+            Ok(Err(e)) => Err(e),
+            Err(mpsc::error::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::error::TryRecvError::Disconnected) => {
+                Err(crate::RaknetError::ConnectionClosed)
+            }
+        }
+    }
+
+    /// Waits for at least one message, then moves it and whatever else is
+    /// already queued, up to `limit` in all, into `buf`. Returns how many
+    /// were added.
+    ///
+    /// Like `mpsc::Receiver::recv_many`, a burst costs one wakeup instead of
+    /// one per message. `Ok(0)` means the connection is closed (or `limit` is
+    /// 0). An error queued behind messages ends the batch and is returned by
+    /// the next receive, so the disconnect reason is not lost.
+    pub async fn recv_many(
+        &mut self,
+        buf: &mut Vec<ReceivedMessage>,
+        limit: usize,
+    ) -> Result<usize, crate::RaknetError> {
+        if limit == 0 {
+            return Ok(0);
+        }
+        match self.recv_msg().await {
+            Some(Ok(msg)) => buf.push(msg),
+            Some(Err(e)) => return Err(e),
+            None => return Ok(0),
+        }
+        let mut added = 1;
+        while added < limit {
+            match self.incoming.try_recv() {
+                Ok(Ok(msg)) => {
+                    self.stats.sub_incoming_channel_bytes(msg.buffer.len());
+                    buf.push(msg);
+                    added += 1;
+                }
+                Ok(Err(e)) => {
+                    self.pending_error = Some(e);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        Ok(added)
+    }
+
+    /// Polls for room in the outbound queue, reserving it for the next
+    /// `start_send`.
+    ///
+    /// `Poll::Pending` registers `cx`'s waker; a reservation, once made, is
+    /// kept until used. Fails with `RaknetError::ConnectionClosed` once the
+    /// session is gone.
+    pub fn poll_send_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), crate::RaknetError>> {
+        if self.stats.is_closed() {
+            return Poll::Ready(Err(crate::RaknetError::ConnectionClosed));
+        }
+        ready!(self.reserve.poll_reserve(cx)).map_err(|_| crate::RaknetError::ConnectionClosed)?;
+        self.reserved = true;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queues `msg` in the room reserved by `poll_send_ready`, without
+    /// waiting. Empty messages are skipped and give the room back, as do
+    /// ones failing with `RaknetError::InvalidChannel`.
+    ///
+    /// Each call needs its own `Poll::Ready(Ok(()))` from `poll_send_ready`
+    /// first; without one it fails with `RaknetError::SendNotReserved` and
+    /// queues nothing.
+    pub fn start_send(&mut self, msg: impl Into<super::Message>) -> Result<(), crate::RaknetError> {
+        if !std::mem::take(&mut self.reserved) {
+            return Err(crate::RaknetError::SendNotReserved);
+        }
+        match outbound_msg(self.peer, msg.into()) {
+            Ok(Some(msg)) if !self.stats.admit(msg.buffer.len(), msg.reliability) => {
+                self.reserve.abort_send();
+                Err(crate::RaknetError::SendQueueFull)
+            }
+            Ok(Some(msg)) => self
+                .reserve
+                .send_item(msg.into())
+                .map_err(|_| crate::RaknetError::ConnectionClosed),
+            res => {
+                self.reserve.abort_send();
+                res.map(drop)
+            }
+        }
+    }
+
+    pub async fn send(&self, msg: impl Into<super::Message>) -> Result<(), crate::RaknetError> {
+        self.sender().send(msg).await
+    }
+
+    /// Queue `msg` without waiting; see `RaknetSender::try_send`.
+    pub fn try_send(&self, msg: impl Into<super::Message>) -> Result<(), TrySendError> {
+        self.sender().try_send(msg)
+    }
+
+    /// Send `msg` and get a `Receipt` that resolves once the peer has ACKed
+    /// it; see `RaknetSender::send_with_receipt`.
+    pub async fn send_with_receipt(
+        &self,
+        msg: impl Into<super::Message>,
+    ) -> Result<Receipt, crate::RaknetError> {
+        self.sender().send_with_receipt(msg).await
+    }
+
+    /// Send several messages at once; see `RaknetSender::send_batch`.
+    pub async fn send_batch(
+        &self,
+        msgs: impl IntoIterator<Item = super::Message>,
+    ) -> Result<(), crate::RaknetError> {
+        self.sender().send_batch(msgs).await
+    }
+
+    /// Wait until every message sent before this call has gone out and, if
+    /// reliable, been ACKed; see `RaknetSender::flush`.
+    pub async fn flush(&self) -> Result<(), crate::RaknetError> {
+        self.sender().flush().await
     }
 
+    /// Send a message received on another stream on to this one, keeping its
+    /// reliability and channel.
+    ///
+    /// The received buffer goes out as is, so a relay copies nothing beyond
+    /// what reassembling split packets requires.
+    pub async fn forward(&self, msg: ReceivedMessage) -> Result<(), crate::RaknetError> {
+        self.send(msg).await
+    }
+
+    /// A cloneable handle for sending to this peer from other tasks.
+    ///
+    /// Senders don't keep the connection open: it lives as long as the
+    /// stream (or until either side disconnects), after which every send
+    /// fails with `RaknetError::ConnectionClosed`.
+    pub fn sender(&self) -> RaknetSender {
+        RaknetSender {
+            peer: self.peer,
+            tx: self.outbound_tx.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// The session's state as the muxer last saw it, including a peer
+    /// going `Stale` or timing out without any packet saying so.
+    pub fn state(&self) -> ConnectionState {
+        self.stats.state()
+    }
+
+    /// Whether the session is still up, i.e. `Connected` or `Stale`.
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self.state(),
+            ConnectionState::Connected | ConnectionState::Stale
+        )
+    }
+
+    /// Resolves with the reason once the connection has ended, however it
+    /// ends: the peer disconnecting or going silent, or this side closing
+    /// it. Resolves straight away if it already has.
+    ///
+    /// Doesn't depend on anyone calling `recv`; `RaknetSender::closed` does
+    /// the same from a task that doesn't own the stream.
+    pub async fn closed(&self) -> DisconnectReason {
+        self.stats.ended().await
+    }
+
+    /// Disconnect from the peer gracefully.
+    ///
+    /// Everything sent before is delivered first; then a
+    /// `DisconnectionNotification` with `reason` follows it, and this waits
+    /// until the peer has ACKed it all or the configured `shutdown_timeout`
+    /// passes. Sends through any `RaknetSender` of this stream fail with
+    /// `RaknetError::ConnectionClosed` from the start.
+    ///
+    /// Fails with `RaknetError::ConnectionClosed` only if the connection was
+    /// already gone.
+    pub async fn disconnect(mut self, reason: DisconnectReason) -> Result<(), crate::RaknetError> {
+        if self.stats.is_closed() {
+            return Err(crate::RaknetError::ConnectionClosed);
+        }
+        // Nothing more will be read, and the muxer must never wait on us.
+        self.incoming.close();
+        let (done, acked) = oneshot::channel();
+        let res = self
+            .outbound_tx
+            .send(Outbound::Disconnect {
+                peer: self.peer,
+                reason,
+                done,
+            })
+            .await;
+        self.stats.mark_closed();
+        if res.is_err() {
+            return Err(crate::RaknetError::ConnectionClosed);
+        }
+        // Dropped instead if the session closes at the deadline.
+        let _ = acked.await;
+        Ok(())
+    }
+
+    /// Send a `DisconnectionNotification` with `reason` and tear the session down.
+    ///
+    /// Does not wait for queued data or for the notification to be ACKed.
+    #[cfg(test)]
+    pub(crate) async fn close(&self, reason: DisconnectReason) -> Result<(), crate::RaknetError> {
+        self.control_tx
+            .send(ControlMsg::Disconnect {
+                peer: self.peer,
+                reason,
+            })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)
+    }
+}
+
+/// A cloneable handle for sending to one peer, from `RaknetStream::sender`.
+#[derive(Clone)]
+pub struct RaknetSender {
+    peer: SocketAddr,
+    tx: mpsc::Sender<Outbound>,
+    /// Shared with the session, which marks it closed when torn down. A
+    /// listener's muxer outlives its sessions, so the channel alone can't
+    /// tell.
+    stats: Arc<SharedStats>,
+}
+
+impl RaknetSender {
+    /// Returns the address of the peer this sends to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Queue `msg` for the peer. Empty messages are skipped; one on an
+    /// ordering channel the protocol doesn't have fails with
+    /// `RaknetError::InvalidChannel`.
+    ///
+    /// With a `send_queue_limit` whose policy is `Reject`, a message that
+    /// doesn't fit in the session's outgoing queue, alongside those still on
+    /// their way to it, fails with `RaknetError::SendQueueFull` and is not
+    /// sent; sends keep failing that way until the queue drains.
     pub async fn send(&self, msg: impl Into<super::Message>) -> Result<(), crate::RaknetError> {
+        self.check_sendable()?;
+        let Some(msg) = self.message(msg.into())? else {
+            return Ok(());
+        };
+        self.admit(&msg)?;
+        self.tx
+            .send(msg.into())
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)
+    }
+
+    /// Queue `msg` for the peer if there is room right now, without
+    /// waiting. Empty messages are skipped.
+    ///
+    /// The outbound queue holds `outbound_queue_capacity` entries (see the
+    /// listener and stream configs); a listener's queue is shared by all of
+    /// its connections. When it is full this fails with
+    /// `TrySendError::Full`, as it does while the session's own outgoing
+    /// queue is full (see `send`), and once the session is gone with
+    /// `TrySendError::Closed`, either way handing `msg` back. A channel out
+    /// of range is `TrySendError::InvalidChannel`.
+    pub fn try_send(&self, msg: impl Into<super::Message>) -> Result<(), TrySendError> {
         let msg = msg.into();
-        let bytes = msg.buffer;
+        if msg.channel >= constants::MAXIMUM_ORDERING_CHANNELS {
+            return Err(TrySendError::InvalidChannel(msg));
+        }
+        if self.stats.is_closed() {
+            return Err(TrySendError::Closed(msg));
+        }
+        if self.stats.is_send_queue_full() {
+            return Err(TrySendError::Full(msg));
+        }
+        let admitted = !msg.buffer.is_empty();
+        if admitted && !self.stats.admit(msg.buffer.len(), msg.reliability) {
+            return Err(TrySendError::Full(msg));
+        }
+        let permit = match self.tx.try_reserve() {
+            Ok(permit) => permit,
+            Err(e) => {
+                if admitted {
+                    self.stats.release(msg.buffer.len(), msg.reliability);
+                }
+                return Err(match e {
+                    mpsc::error::TrySendError::Full(()) => TrySendError::Full(msg),
+                    mpsc::error::TrySendError::Closed(()) => TrySendError::Closed(msg),
+                });
+            }
+        };
+        if let Ok(Some(msg)) = self.message(msg) {
+            permit.send(msg.into());
+        }
+        Ok(())
+    }
+
+    /// Queue `msg` for the peer, returning a `Receipt` that resolves once
+    /// every datagram carrying it has been ACKed. A split message resolves
+    /// only after all of its parts have been.
+    ///
+    /// An `UnreliableWithAckReceipt` message is sent unreliably and never
+    /// resent: its receipt fails with `RaknetError::MessageLost` if the
+    /// datagram carrying it is NACKed or not ACKed within the retransmission
+    /// timeout. Other unreliable messages, and unreliable ones too large for
+    /// one datagram, are sent with the matching reliable reliability, since
+    /// only those are ACKed. An empty message is skipped and its receipt
+    /// resolves straight away. A message the session turns away, for a full
+    /// outgoing queue or a channel beyond its `max_ordering_channels`,
+    /// counts as lost.
+    pub async fn send_with_receipt(
+        &self,
+        msg: impl Into<super::Message>,
+    ) -> Result<Receipt, crate::RaknetError> {
+        self.check_sendable()?;
+        let Some(msg) = self.message(msg.into())? else {
+            return Ok(Receipt::acked());
+        };
+        self.admit(&msg)?;
+        let (done, outcome) = oneshot::channel();
+        self.tx
+            .send(Outbound::Receipt { msg, done })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)?;
+        Ok(Receipt::new(outcome, self.stats.clone()))
+    }
+
+    /// Wait until every message sent to the peer before this call, through
+    /// this sender or any clone, has gone out and, if reliable, been ACKed.
+    ///
+    /// Messages sent after it don't hold it up, so it resolves even while
+    /// other tasks keep the queue busy. Unreliable messages dropped for
+    /// expiring in the queue count as gone out.
+    ///
+    /// Fails with `RaknetError::ConnectionClosed` if the session goes away
+    /// first.
+    pub async fn flush(&self) -> Result<(), crate::RaknetError> {
+        if self.stats.is_closed() {
+            return Err(crate::RaknetError::ConnectionClosed);
+        }
+        let (done, flushed) = oneshot::channel();
+        self.tx
+            .send(Outbound::Flush {
+                peer: self.peer,
+                done,
+            })
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)?;
+        flushed
+            .await
+            .map_err(|_| crate::RaknetError::ConnectionClosed)
+    }
+
+    /// Resolves with the reason once the connection has ended. See
+    /// `RaknetStream::closed`.
+    pub async fn closed(&self) -> DisconnectReason {
+        self.stats.ended().await
+    }
 
-        if bytes.is_empty() {
+    /// Queue `msgs` for the peer in one go. They are flushed together, so
+    /// small ones can share a datagram, and keep their order. Empty messages
+    /// are skipped.
+    pub async fn send_batch(
+        &self,
+        msgs: impl IntoIterator<Item = super::Message>,
+    ) -> Result<(), crate::RaknetError> {
+        self.check_sendable()?;
+        let msgs = msgs
+            .into_iter()
+            .filter_map(|msg| self.message(msg).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        if msgs.is_empty() {
             return Ok(());
         }
-        let id = bytes[0];
-        let body = bytes.slice(1..);
-        self.outbound_tx
-            .send(OutboundMsg {
+        for (i, msg) in msgs.iter().enumerate() {
+            if let Err(e) = self.admit(msg) {
+                for admitted in &msgs[..i] {
+                    self.stats
+                        .release(admitted.buffer.len(), admitted.reliability);
+                }
+                return Err(e);
+            }
+        }
+        self.tx
+            .send(Outbound::Batch {
                 peer: self.peer,
-                packet: RaknetPacket::UserData { id, payload: body },
-                reliability: msg.reliability,
-                channel: msg.channel,
-                priority: msg.priority,
+                msgs,
             })
             .await
             .map_err(|_| crate::RaknetError::ConnectionClosed)
     }
+
+    /// Fail a send up front if the session is gone or its outgoing queue
+    /// is full.
+    fn check_sendable(&self) -> Result<(), crate::RaknetError> {
+        if self.stats.is_closed() {
+            return Err(crate::RaknetError::ConnectionClosed);
+        }
+        if self.stats.is_send_queue_full() {
+            return Err(crate::RaknetError::SendQueueFull);
+        }
+        Ok(())
+    }
+
+    /// Reserve room for `msg` in the session's outgoing queue; see
+    /// `SharedStats::admit`.
+    pub(crate) fn admit(&self, msg: &OutboundMsg) -> Result<(), crate::RaknetError> {
+        if self.stats.admit(msg.buffer.len(), msg.reliability) {
+            Ok(())
+        } else {
+            Err(crate::RaknetError::SendQueueFull)
+        }
+    }
+
+    /// The muxer message carrying `msg`, or `None` if it is empty.
+    pub(crate) fn message(
+        &self,
+        msg: super::Message,
+    ) -> Result<Option<OutboundMsg>, crate::RaknetError> {
+        outbound_msg(self.peer, msg)
+    }
+
+    #[cfg(any(test, feature = "codec"))]
+    pub(crate) fn channel(&self) -> mpsc::Sender<Outbound> {
+        self.tx.clone()
+    }
+}
+
+/// Delivery receipt for a message, from `RaknetSender::send_with_receipt`.
+///
+/// Resolves with `Ok(())` once the peer has ACKed the whole message, with
+/// `RaknetError::MessageLost` if it was sent `UnreliableWithAckReceipt` and
+/// lost, or with `RaknetError::Disconnected` and the reason if the
+/// connection ends first. Dropping it doesn't affect the message.
+pub struct Receipt {
+    inner: Pin<Box<dyn Future<Output = Result<(), crate::RaknetError>> + Send>>,
+}
+
+impl Receipt {
+    fn new(outcome: oneshot::Receiver<ReceiptOutcome>, stats: Arc<SharedStats>) -> Self {
+        Self {
+            inner: Box::pin(async move {
+                match outcome.await {
+                    Ok(ReceiptOutcome::Acked) => Ok(()),
+                    Ok(ReceiptOutcome::Lost) => Err(crate::RaknetError::MessageLost),
+                    // The session let go of the receipt: it is gone, or about to be.
+                    Err(_) => Err(crate::RaknetError::Disconnected(stats.ended().await)),
+                }
+            }),
+        }
+    }
+
+    fn acked() -> Self {
+        Self {
+            inner: Box::pin(std::future::ready(Ok(()))),
+        }
+    }
+}
+
+impl Future for Receipt {
+    type Output = Result<(), crate::RaknetError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+impl std::fmt::Debug for Receipt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Receipt").finish_non_exhaustive()
+    }
+}
+
+pub(crate) fn outbound_msg(
+    peer: SocketAddr,
+    msg: super::Message,
+) -> Result<Option<OutboundMsg>, crate::RaknetError> {
+    if msg.channel >= constants::MAXIMUM_ORDERING_CHANNELS {
+        return Err(crate::RaknetError::InvalidChannel {
+            channel: msg.channel,
+        });
+    }
+    if msg.buffer.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(OutboundMsg {
+        peer,
+        buffer: msg.buffer,
+        reliability: msg.reliability,
+        channel: msg.channel,
+        priority: msg.priority,
+        expires: msg.ttl.map(|ttl| mux::now() + ttl),
+    }))
+}
+
+/// Received messages, ending after the error that closes the connection.
+#[cfg(any(test, feature = "futures"))]
+impl futures_core::Stream for RaknetStream {
+    type Item = Result<ReceivedMessage, crate::RaknetError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+/// Sends messages like `RaknetStream::send`, waiting for room in the outbound
+/// queue rather than failing when it is full.
+///
+/// Flushing has nothing to wait for: messages are handed to the muxer as
+/// they are sent. Closing only stops this sink; the connection stays up
+/// until the stream is dropped.
+#[cfg(any(test, feature = "futures"))]
+impl futures_sink::Sink<super::Message> for RaknetStream {
+    type Error = crate::RaknetError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: super::Message) -> Result<(), Self::Error> {
+        RaknetStream::start_send(self.get_mut(), msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.reserve.close();
+        this.reserved = false;
+        Poll::Ready(Ok(()))
+    }
 }
 
 struct OfflineHandshake {
     mtu: u16,
+    /// Version in the `OpenConnectionRequest1` the server answered, and so
+    /// accepted.
+    protocol_version: u8,
     server_guid: u64,
     secure_connection_established: bool,
 }
@@ -216,25 +1233,29 @@ struct ClientMuxerContext {
     secure_connection_established: bool,
 
     // Communication channels
-    outbound_rx: mpsc::Receiver<OutboundMsg>,
+    outbound_rx: mpsc::Receiver<Outbound>,
+    control_rx: mpsc::Receiver<ControlMsg>,
     to_app: mpsc::Sender<Result<ReceivedMessage, crate::RaknetError>>,
-    ready: oneshot::Sender<Result<(), crate::RaknetError>>,
+    ready: oneshot::Sender<Result<ClientReady, crate::RaknetError>>,
     config: RaknetStreamConfig,
 }
 
 #[tracing::instrument(skip(socket, context), fields(server = %context.server, mtu = context.config.mtu), level = "debug")]
-async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
-    let mut buf = vec![0u8; context.config.mtu as usize + UDP_HEADER_SIZE + 64];
+async fn run_client_muxer<S: DatagramSocket>(socket: S, mut context: ClientMuxerContext) {
+    let mut buf = RecvBuffer::new(context.config.mtu as usize + UDP_HEADER_SIZE + 64);
     let mut managed: Option<ManagedSession> = None;
     let mut handshake_started = false;
     // We move the `ready` sender into a local Option
     let mut ready_signal = Some(context.ready);
     let mut tick = time::interval(TICK_INTERVAL);
     tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut flush_waiters = mux::FlushWaiters::default();
+    // Sent to the server if the loop ends because the stream went away.
+    let mut farewell = DisconnectReason::Disconnected;
 
     // Initial handshake ensure
     {
-        let now = Instant::now();
+        let now = mux::now();
         let ms = ensure_client_session(
             &mut managed,
             context.server,
@@ -256,8 +1277,13 @@ async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
     }
 
     loop {
+        // Delayed ACKs may be due before the next tick.
+        let ack_at = managed
+            .as_ref()
+            .and_then(|ms| ms.ack_deadline(mux::now()))
+            .map(time::Instant::from_std);
         tokio::select! {
-            res = socket.recv_from(&mut buf) => {
+            res = socket.recv_from(buf.spare()) => {
                 let (len, peer) = match res {
                     Ok(v) => v,
                     Err(e) => {
@@ -284,38 +1310,28 @@ async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
                 // Filter out non-datagram packets (offline packets, e.g. OpenConnectionReply2)
                 // Valid datagrams must have VALID (0x80), ACK (0x40), or NACK (0x20) flags.
                 // Offline packets are typically ID < 0x20.
-                let header_byte = buf[0];
+                let bytes = buf.take(len);
+                let header_byte = bytes[0];
                 if header_byte < 0x80 && (header_byte & 0x60) == 0 {
                     // Try to decode as a control packet to see if it's a connection failure
-                    let mut slice = &buf[..len];
+                    let mut slice = &bytes[..];
                     match RaknetPacket::decode(&mut slice) {
                         Ok(pkt) => {
-                            let error = match pkt {
-                                RaknetPacket::ConnectionRequestFailed(_) => {
-                                    Some(crate::RaknetError::ConnectionRequestFailed)
-                                }
-                                RaknetPacket::AlreadyConnected(_) => {
-                                    Some(crate::RaknetError::AlreadyConnected)
-                                }
-                                RaknetPacket::IncompatibleProtocolVersion(_) => {
-                                    Some(crate::RaknetError::IncompatibleProtocolVersion)
-                                }
-                                RaknetPacket::NoFreeIncomingConnections(_) => {
-                                    Some(crate::RaknetError::ServerFull)
-                                }
-                                RaknetPacket::ConnectionBanned(_) => {
-                                    Some(crate::RaknetError::ConnectionBanned)
-                                }
-                                RaknetPacket::IpRecentlyConnected(_) => {
-                                    Some(crate::RaknetError::IpRecentlyConnected)
-                                }
-                                _ => None,
-                            };
+                            let error = refusal(
+                                &pkt,
+                                HandshakePhase::ConnectionRequest,
+                                context.config.protocol_version,
+                            );
 
                             if let Some(e) = error {
                                 tracing::debug!(error = ?e, "received connection failure packet");
-                                if let Some(tx) = ready_signal.take() {
-                                    let _ = tx.send(Err(e));
+                                match ready_signal.take() {
+                                    Some(tx) => {
+                                        let _ = tx.send(Err(e));
+                                    }
+                                    None => {
+                                        let _ = context.to_app.send(Err(e)).await;
+                                    }
                                 }
                                 return;
                             } else {
@@ -329,9 +1345,22 @@ async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
                     continue;
                 }
 
-                let mut slice = &buf[..len];
-                if let Ok(dgram) = Datagram::decode(&mut slice) {
-                    let now = Instant::now();
+                let mut slice = bytes;
+                let decoded = Datagram::decode_with_limit(&mut slice, context.config.max_frames_per_datagram);
+                if let Err(DecodeError::TooManyFrames(limit)) = decoded {
+                    tracing::warn!(limit, "server exceeded frame limit, disconnecting");
+                    if let Some(ms) = managed.as_mut() {
+                        let _ = ms.send_disconnect(DisconnectReason::BadPacket);
+                        mux::flush_final(ms, &socket, context.server, mux::now()).await;
+                    }
+                    let _ = context
+                        .to_app
+                        .send(Err(crate::RaknetError::Disconnected(DisconnectReason::BadPacket)))
+                        .await;
+                    return;
+                }
+                if let Ok(dgram) = decoded {
+                    let now = mux::now();
                     // Use context fields
                     let ms = ensure_client_session(
                         &mut managed,
@@ -351,62 +1380,56 @@ async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
                         context.server
                     ).await;
 
-                    // This logic remains the same, but was already correct
-                    match ms.handle_datagram(dgram, now) {
-                        Ok(pkts) => {
-                            for p in ManagedSession::filter_app_packets(pkts) {
-                                if let RaknetPacket::UserData { id, payload } = p.packet {
-                                    tracing::trace!("received user packet");
-                                    // Reconstruct full packet: [ID] + [Payload]
-                                    let mut full = BytesMut::with_capacity(1 + payload.len());
-                                    full.put_u8(id);
-                                    full.extend_from_slice(&payload);
-                                    let msg = ReceivedMessage {
-                                        buffer: full.freeze(),
-                                        reliability: p.reliability,
-                                        channel: p.ordering_channel.unwrap_or(0),
-                                    };
-                                    if context.to_app.send(Ok(msg)).await.is_err() {
-                                        tracing::debug!("app channel closed");
-                                        return;
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            if matches!(
-                                e,
-                                crate::session::manager::SessionError::Protocol(
-                                    crate::protocol::packet::DecodeError::InvalidAddrVersion(_)
-                                )
-                            ) {
-                                tracing::debug!(error = ?e, "ignoring datagram with invalid addr version");
-                                continue;
-                            }
-                            tracing::debug!(error = ?e, "failed to handle datagram");
+                    let mut delivery = AppDelivery::new(&context.to_app, ms.stats().clone());
+                    let res = ms.handle_datagram_with(dgram, now, |p| delivery.push(p));
+                    // A graceful disconnect carries on without a reader.
+                    if !delivery.finish().await && ms.state() != ConnectionState::Closing {
+                        tracing::debug!("app channel closed");
+                        return;
+                    }
+                    if let Err(e) = res {
+                        if matches!(
+                            e,
+                            crate::session::manager::SessionError::Protocol(
+                                crate::protocol::packet::DecodeError::InvalidAddrVersion(_)
+                            )
+                        ) {
+                            tracing::debug!(error = ?e, "ignoring datagram with invalid addr version");
+                            continue;
                         }
+                        tracing::debug!(error = ?e, "failed to handle datagram");
                     }
                     notify_client_ready(ms, &mut ready_signal);
 
                     if ms.state() == ConnectionState::Closed {
-                        if let Some(reason) = ms.last_disconnect_reason() {
-                            tracing::info!(reason = ?reason, "session disconnected");
-                            let _ = context.to_app.send(Err(crate::RaknetError::Disconnected(reason))).await;
-                        } else {
-                            let _ = context.to_app.send(Err(crate::RaknetError::ConnectionClosed)).await;
-                        }
+                        mux::flush_final(ms, &socket, context.server, now).await;
+                        report_client_closed(ms, &mut ready_signal, &context.to_app).await;
                         return;
                     }
 
                     flush_built_datagrams(ms, &socket, context.server, now, false).await;
+                    flush_waiters.notify(ms);
                 } else {
                     tracing::debug!("failed to decode datagram");
+                    if let Some(ms) = managed.as_ref() {
+                        ms.stats().record_undecodable_datagram();
+                    }
                 }
             }
 
-            // Use context field
-            Some(msg) = context.outbound_rx.recv() => {
-                let now = Instant::now();
+            out = context.outbound_rx.recv() => {
+                // The stream and its senders are gone, or `connect` was
+                // dropped before it finished. A `close` sent just before
+                // still picks the reason.
+                let Some(out) = out else {
+                    while let Ok(ctrl) = context.control_rx.try_recv() {
+                        if let ControlMsg::Disconnect { reason, .. } = ctrl {
+                            farewell = reason;
+                        }
+                    }
+                    break;
+                };
+                let now = mux::now();
                 let ms = ensure_client_session(
                     &mut managed,
                     context.server,
@@ -424,35 +1447,123 @@ async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
                     &socket,
                     context.server
                 ).await;
-                let _ = ms.queue_app_packet(
-                    msg.packet,
-                    msg.reliability,
-                    msg.channel,
-                    msg.priority,
-                );
+                // Take what else is already waiting before flushing, so
+                // small messages sent back to back share datagrams.
+                let mut next = Some(out);
+                let mut taken = 0;
+                while let Some(out) = next.take() {
+                    match out {
+                        Outbound::Receipt { msg, done } => {
+                            let _ = msg.queue_admitted(ms, Some(done), now);
+                        }
+                        Outbound::Flush { done, .. } => flush_waiters.push(ms, done),
+                        Outbound::Disconnect { reason, done, .. } => {
+                            let deadline = now + context.config.shutdown_timeout;
+                            let _ = ms.disconnect_gracefully(reason, deadline);
+                            flush_waiters.push(ms, done);
+                        }
+                        out => {
+                            for msg in out.into_messages() {
+                                let _ = msg.queue_admitted(ms, None, now);
+                            }
+                        }
+                    }
+                    taken += 1;
+                    if taken < mux::MAX_OUTBOUND_BATCH {
+                        next = context.outbound_rx.try_recv().ok();
+                    }
+                }
                 flush_built_datagrams(ms, &socket, context.server, now, false).await;
                 notify_client_ready(ms, &mut ready_signal);
+                flush_waiters.notify(ms);
+            }
+
+            Some(ctrl) = context.control_rx.recv() => {
+                let Some(ms) = managed.as_mut() else { continue };
+                match ctrl {
+                    ControlMsg::Disconnect { reason, .. } => {
+                        tracing::debug!(?reason, "closing connection");
+                        let _ = ms.send_disconnect(reason);
+                        mux::flush_final(ms, &socket, context.server, mux::now()).await;
+                        let _ = context
+                            .to_app
+                            .try_send(Err(crate::RaknetError::Disconnected(reason)));
+                        return;
+                    }
+                    ControlMsg::Flush { .. } => {
+                        flush_built_datagrams(ms, &socket, context.server, mux::now(), true).await;
+                    }
+                    // Only listeners have sessions to kick.
+                    ControlMsg::Kick { done, .. } => {
+                        let _ = done.send(false);
+                    }
+                    ControlMsg::Peers { done } => {
+                        let _ = done.send(Vec::new());
+                    }
+                }
+            }
+
+            _ = time::sleep_until(ack_at.unwrap_or_else(time::Instant::now)), if ack_at.is_some() => {
+                if let Some(ms) = managed.as_mut() {
+                    flush_built_datagrams(ms, &socket, context.server, mux::now(), false).await;
+                }
             }
 
             _ = tick.tick() => {
                 if let Some(ms) = managed.as_mut() {
-                    let now = Instant::now();
+                    let now = mux::now();
                     flush_built_datagrams(ms, &socket, context.server, now, true).await;
+                    let mut delivery = AppDelivery::new(&context.to_app, ms.stats().clone());
+                    ms.deliver_released_with(now, |p| delivery.push(p));
+                    if !delivery.finish().await && ms.state() != ConnectionState::Closing {
+                        tracing::debug!("app channel closed");
+                        return;
+                    }
                     notify_client_ready(ms, &mut ready_signal);
+                    flush_waiters.notify(ms);
+
+                    // The tick is where an unresponsive server times out.
+                    if ms.state() == ConnectionState::Closed {
+                        mux::flush_final(ms, &socket, context.server, now).await;
+                        report_client_closed(ms, &mut ready_signal, &context.to_app).await;
+                        return;
+                    }
+                }
+            }
+
+            _ = mux::cancelled(context.config.shutdown.as_ref()) => {
+                tracing::debug!("shutting connection down");
+                if let Some(ms) = managed.as_mut() {
+                    if ms.is_connected() {
+                        let deadline = time::Instant::now() + context.config.shutdown_timeout;
+                        drain_client(ms, &socket, context.server, &context.config, deadline, buf.spare()).await;
+                    } else {
+                        // A cancelled `connect` doesn't wait on the server.
+                        let _ = ms.send_disconnect(DisconnectReason::ShuttingDown);
+                    }
+                    mux::flush_final(ms, &socket, context.server, mux::now()).await;
+                }
+                match ready_signal.take() {
+                    Some(tx) => {
+                        let _ = tx.send(Err(crate::RaknetError::Shutdown));
+                    }
+                    None => {
+                        let _ = context.to_app.try_send(Err(crate::RaknetError::Shutdown));
+                    }
                 }
+                return;
             }
 
-            else => break,
         }
     }
 
-    // Graceful shutdown check using match to avoid nesting if-lets
+    // Tell the server, best effort, unless it never heard of the session.
     match managed {
-        Some(mut ms) if ms.is_connected() => {
+        Some(mut ms) if ms.is_connected() || ms.state() == ConnectionState::OnlineHandshake => {
             tracing::debug!("channel closed, sending disconnect notification");
-            let _ = ms.send_disconnect(crate::protocol::state::DisconnectReason::Disconnected);
+            let _ = ms.send_disconnect(farewell);
             // Flush the disconnect packet
-            flush_built_datagrams(&mut ms, &socket, context.server, Instant::now(), true).await;
+            flush_built_datagrams(&mut ms, &socket, context.server, mux::now(), true).await;
         }
         _ => {}
     }
@@ -460,24 +1571,105 @@ async fn run_client_muxer(socket: UdpSocket, mut context: ClientMuxerContext) {
     tracing::debug!("client muxer terminated");
 }
 
+/// The MTUs to probe, largest first: `limit` (capped at
+/// `MAXIMUM_MTU_SIZE`), then each of `MTU_SIZES` below it.
+fn probe_sizes(limit: usize) -> Vec<u16> {
+    let limit = limit.min(MAXIMUM_MTU_SIZE as usize) as u16;
+    let mut sizes = vec![limit];
+    sizes.extend(MTU_SIZES.iter().copied().filter(|&size| size < limit));
+    sizes
+}
+
+/// The share of `attempts` sends that goes to the `index`th of `probes` MTU
+/// sizes.
+fn probe_attempts(index: usize, probes: usize, attempts: usize) -> Range<usize> {
+    (index * attempts).div_ceil(probes)..((index + 1) * attempts).div_ceil(probes)
+}
+
+/// Send `request` to `server` until `reply` picks an answer out of what
+/// comes back, once for each of the `attempts` of `retry`. `Ok(None)` if
+/// every attempt went unanswered; a refusal for `phase` or a failed send is
+/// an error.
+#[allow(clippy::too_many_arguments)]
+async fn request_with_retry<T>(
+    socket: &impl DatagramSocket,
+    server: SocketAddr,
+    request: &[u8],
+    retry: &HandshakeRetry,
+    attempts: Range<usize>,
+    phase: HandshakePhase,
+    protocol_version: u8,
+    mut reply: impl FnMut(RaknetPacket) -> Option<T>,
+) -> Result<Option<T>, crate::RaknetError> {
+    let mut tmp = [0u8; 2048];
+    for attempt in attempts {
+        if attempt > 0 {
+            time::sleep(retry.backoff.delay(attempt as u32 - 1)).await;
+        }
+        socket.send_to(request, server).await?;
+
+        let deadline = time::Instant::now() + retry.attempt_timeout;
+        while let Ok(res) = time::timeout_at(deadline, socket.recv_from(&mut tmp)).await {
+            let Ok((len, from)) = res else {
+                break;
+            };
+            if from != server {
+                tracing::debug!(?phase, "ignoring reply from non-server");
+                continue;
+            }
+            let mut slice = &tmp[..len];
+            let Ok(pkt) = RaknetPacket::decode(&mut slice) else {
+                tracing::debug!(?phase, "ignoring malformed packet");
+                continue;
+            };
+            if let Some(e) = refusal(&pkt, phase, protocol_version) {
+                tracing::debug!(?phase, error = ?e, "server refused handshake");
+                return Err(e);
+            }
+            match reply(pkt) {
+                Some(answer) => return Ok(Some(answer)),
+                None => tracing::debug!(?phase, "ignoring unexpected packet"),
+            }
+        }
+        tracing::debug!(?phase, attempt, "timeout waiting for reply");
+    }
+    Ok(None)
+}
+
 #[tracing::instrument(skip_all, level = "debug")]
 async fn perform_offline_handshake(
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     server: SocketAddr,
-    _mtu_hint: usize,
+    mtu_limit: usize,
     client_guid: u64,
-) -> std::io::Result<OfflineHandshake> {
+    protocol_version: u8,
+    retry: &HandshakeRetry,
+) -> Result<OfflineHandshake, crate::RaknetError> {
     let mut reply1 = None;
-    let mut used_mtu = 0;
-
-    for &mtu in crate::protocol::constants::MTU_SIZES {
+    // Probes can fail to send when they exceed the path MTU; only give up on
+    // the socket if none of them went out.
+    let mut sent_any = false;
+    let mut send_error = None;
+
+    let attempts = retry.attempts.max(1);
+    let sizes = probe_sizes(mtu_limit);
+    for (index, &mtu) in sizes.iter().enumerate() {
+        // A probe that doesn't fit the path is silently lost, so each size
+        // gets a few tries before falling back to the next.
+        let share = probe_attempts(index, sizes.len(), attempts);
+        if share.is_empty() {
+            continue;
+        }
         tracing::debug!(mtu = mtu, "probing mtu");
+        // Padded so the whole IP packet is `mtu` bytes; the server works the
+        // size back out the same way.
+        let ip_header = if server.is_ipv4() { 20 } else { 40 };
+        let overhead = ip_header + UDP_HEADER_SIZE + 1 + DEFAULT_UNCONNECTED_MAGIC.len() + 1;
         let req1 =
             RaknetPacket::OpenConnectionRequest1(crate::protocol::packet::OpenConnectionRequest1 {
                 magic: DEFAULT_UNCONNECTED_MAGIC,
-                protocol_version: RAKNET_PROTOCOL_VERSION,
-                // IP (20) + UDP (8) + Packet ID (1) + Magic (16) + Protocol (1) = 46 bytes overhead
-                padding: EoBPadding(mtu.saturating_sub(46).into()),
+                protocol_version,
+                padding: EoBPadding((mtu as usize).saturating_sub(overhead)),
             });
 
         let mut buf = BytesMut::new();
@@ -485,68 +1677,65 @@ async fn perform_offline_handshake(
             tracing::warn!(mtu = mtu, "failed to encode OpenConnectionRequest1");
             continue;
         }
-        if let Err(e) = socket.send_to(&buf, server).await {
-            tracing::warn!(mtu = mtu, error = ?e, "failed to send OpenConnectionRequest1");
-            continue;
-        }
 
-        let mut tmp = [0u8; 2048];
-        let mut attempts = 0;
-        while attempts < HANDSHAKE_RETRIES {
-            // Short timeout for each attempt so we don't stall the whole probe sequence.
-            let res = timeout(HANDSHAKE_TIMEOUT, socket.recv_from(&mut tmp)).await;
-
-            if let Ok(Ok((len, from))) = res {
-                if from == server {
-                    let mut slice = &tmp[..len];
-                    // Cleaner pattern match without let_chains or nesting
-                    if let Ok(RaknetPacket::OpenConnectionReply1(r)) =
-                        RaknetPacket::decode(&mut slice)
-                    {
-                        tracing::debug!(
-                            mtu = mtu,
-                            server_mtu = r.mtu,
-                            "received OpenConnectionReply1"
-                        );
-                        reply1 = Some(r);
-                        used_mtu = mtu;
-                        break;
-                    } else {
-                        tracing::debug!("ignoring non-reply1 packet during probe");
-                    }
-                } else {
-                    tracing::debug!("ignoring reply from non-server during probe");
-                }
-            } else {
-                tracing::debug!("timeout waiting for reply1 attempt");
+        let res = request_with_retry(
+            socket,
+            server,
+            &buf,
+            retry,
+            share,
+            HandshakePhase::MtuDiscovery,
+            protocol_version,
+            |pkt| match pkt {
+                RaknetPacket::OpenConnectionReply1(r) => Some(r),
+                _ => None,
+            },
+        )
+        .await;
+        match res {
+            Ok(Some(r)) => {
+                tracing::debug!(
+                    mtu = mtu,
+                    server_mtu = r.mtu,
+                    "received OpenConnectionReply1"
+                );
+                reply1 = Some((r, mtu));
+                break;
             }
-            attempts += 1;
-        }
-
-        if reply1.is_some() {
-            break;
+            Ok(None) => {
+                sent_any = true;
+                tracing::debug!(
+                    mtu = mtu,
+                    "no valid OpenConnectionReply1 received for probe"
+                );
+            }
+            Err(crate::RaknetError::Io(e)) => {
+                tracing::warn!(mtu = mtu, error = ?e, "failed to send OpenConnectionRequest1");
+                send_error = Some(e);
+            }
+            Err(e) => return Err(e),
         }
-        tracing::debug!(
-            mtu = mtu,
-            "no valid OpenConnectionReply1 received for probe"
-        );
     }
 
-    let reply1 = reply1.ok_or_else(|| {
+    let Some((reply1, used_mtu)) = reply1 else {
         tracing::error!("failed to receive any OpenConnectionReply1");
-        std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            "timeout waiting for OpenConnectionReply1 after probing MTUs",
-        )
-    })?;
+        return Err(match send_error {
+            Some(e) if !sent_any => e.into(),
+            _ => crate::RaknetError::Timeout {
+                phase: HandshakePhase::MtuDiscovery,
+            },
+        });
+    };
 
     let server_mtu = reply1.mtu;
     let cookie = reply1.cookie;
 
     // Negotiate final MTU: min(client_probed, server_reported)
-    let mtu_final = used_mtu
-        .clamp(MINIMUM_MTU_SIZE, MAXIMUM_MTU_SIZE)
-        .min(server_mtu);
+    let mtu_final =
+        negotiate_mtu(server_mtu, used_mtu).ok_or(crate::RaknetError::HandshakeFailed {
+            phase: HandshakePhase::MtuDiscovery,
+            cause: HandshakeFailure::MtuTooSmall(server_mtu),
+        })?;
 
     tracing::debug!(negotiated_mtu = mtu_final, "sending OpenConnectionRequest2");
 
@@ -561,36 +1750,51 @@ async fn perform_offline_handshake(
         });
 
     let mut buf2 = BytesMut::new();
-    req2.encode(&mut buf2).map_err(|_| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "failed to encode request2")
-    })?;
-    socket.send_to(&buf2, server).await?;
-
-    let mut tmp = [0u8; 2048];
-    let reply2 = loop {
-        let res = timeout(Duration::from_secs(2), socket.recv_from(&mut tmp)).await;
-        let (len, from) = match res {
-            Ok(Ok(v)) => v,
-            _ => {
-                tracing::error!("timeout waiting for OpenConnectionReply2");
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "timeout waiting for OpenConnectionReply2",
-                ));
-            }
-        };
-        if from != server {
-            continue;
-        }
-        let mut slice = &tmp[..len];
-        if let Ok(RaknetPacket::OpenConnectionReply2(r)) = RaknetPacket::decode(&mut slice) {
-            tracing::debug!(server_guid = r.server_guid, "handshake complete");
-            break r;
+    req2.encode(&mut buf2)?;
+
+    // The server answers a repeated request for a session it already made
+    // with the same reply, so resending is safe.
+    let reply2 = request_with_retry(
+        socket,
+        server,
+        &buf2,
+        retry,
+        0..attempts,
+        HandshakePhase::OpenConnection,
+        protocol_version,
+        |pkt| match pkt {
+            RaknetPacket::OpenConnectionReply2(r) => Some(r),
+            _ => None,
+        },
+    )
+    .await?
+    .ok_or_else(|| {
+        tracing::error!("timeout waiting for OpenConnectionReply2");
+        crate::RaknetError::Timeout {
+            phase: HandshakePhase::OpenConnection,
         }
+    })?;
+    // The server may have settled lower, on a probe it saw after ours, but
+    // never above what we asked for or below what the framing needs.
+    let cause = if reply2.mtu < MINIMUM_MTU_SIZE {
+        Some(HandshakeFailure::MtuTooSmall(reply2.mtu))
+    } else if reply2.mtu > mtu_final {
+        Some(HandshakeFailure::MtuTooLarge(reply2.mtu))
+    } else {
+        None
     };
+    if let Some(cause) = cause {
+        tracing::debug!(mtu = reply2.mtu, "OpenConnectionReply2 MTU out of range");
+        return Err(crate::RaknetError::HandshakeFailed {
+            phase: HandshakePhase::OpenConnection,
+            cause,
+        });
+    }
+    tracing::debug!(server_guid = reply2.server_guid, "handshake complete");
 
     Ok(OfflineHandshake {
-        mtu: mtu_final,
+        mtu: reply2.mtu,
+        protocol_version,
         server_guid: reply2.server_guid,
         secure_connection_established: reply2.security,
     })
@@ -613,35 +1817,63 @@ fn ensure_client_session<'a>(
     config: &RaknetStreamConfig,
 ) -> &'a mut ManagedSession {
     managed.get_or_insert_with(|| {
-        ManagedSession::with_config(
-            server,
-            mtu,
-            now,
-            SessionConfig {
-                role: SessionRole::Client,
-                guid: client_guid,
-                session_timeout: config.session_timeout,
-                session: crate::session::SessionTunables {
-                    max_ordering_channels: config.max_ordering_channels,
-                    ack_queue_capacity: config.ack_queue_capacity,
-                    split_timeout: config.split_timeout,
-                    reliable_window: config.reliable_window,
-                    max_split_parts: config.max_split_parts,
-                    max_concurrent_splits: config.max_concurrent_splits,
-                },
-                ..SessionConfig::default()
-            },
-        )
+        tracing::debug!(%server, mtu, "session created");
+        ManagedSession::with_config(server, mtu, now, client_session_config(config, client_guid))
     })
 }
 
+/// A non-blocking tokio socket on an ephemeral port.
+fn bind_any_udp() -> std::io::Result<UdpSocket> {
+    bind_udp(SocketAddr::from(([0, 0, 0, 0], 0)))
+}
+
+/// `config.session_config` if set, otherwise built from the client's own
+/// per-session fields.
+fn client_session_config(config: &RaknetStreamConfig, client_guid: u64) -> SessionConfig {
+    let base = config
+        .session_config
+        .clone()
+        .unwrap_or_else(|| SessionConfig {
+            session_timeout: config.session_timeout,
+            // Keep a short timeout valid instead of rejecting it.
+            session_stale: constants::SESSION_STALE.min(config.session_timeout / 2),
+            keepalive_interval: config
+                .keepalive_interval
+                .map(|keepalive| keepalive.min(config.session_timeout / 4)),
+            send_queue_limit: config.send_queue_limit,
+            backlog_limit: config.backlog_limit,
+            session: crate::session::SessionTunables {
+                max_ordering_channels: config.max_ordering_channels,
+                ack_queue_capacity: config.ack_queue_capacity,
+                split_timeout: config.split_timeout,
+                reliable_window: config.reliable_window,
+                max_split_parts: config.max_split_parts,
+                max_concurrent_splits: config.max_concurrent_splits,
+                max_split_bytes: config.max_split_bytes,
+                reorder_limit: config.reorder_limit,
+                min_rto: config.min_rto,
+                max_rto: config.max_rto,
+                max_resends: config.max_resends,
+                fast_retransmit: config.fast_retransmit,
+                ack_policy: config.ack_policy,
+            },
+            debug_log: config.debug_log,
+            ..SessionConfig::default()
+        });
+    SessionConfig {
+        role: SessionRole::Client,
+        guid: client_guid,
+        ..base
+    }
+}
+
 async fn ensure_client_handshake(
     managed: &mut ManagedSession,
     handshake_started: &mut bool,
     server_guid: u64,
     now: Instant,
     secure_connection_established: bool,
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     server: SocketAddr,
 ) {
     if !*handshake_started
@@ -657,7 +1889,7 @@ async fn ensure_client_handshake(
 #[tracing::instrument(skip_all, fields(peer= %peer, on_tick = %run_tick), level = "trace")]
 async fn flush_built_datagrams(
     managed: &mut ManagedSession,
-    socket: &UdpSocket,
+    socket: &impl DatagramSocket,
     peer: SocketAddr,
     now: Instant,
     run_tick: bool,
@@ -683,17 +1915,1071 @@ async fn flush_built_datagrams(
     // Delivery of any unblocked packets happens via drain_ready_to_app() at call sites.
 }
 
+/// Disconnect with `ShuttingDown` and keep the session serviced until the
+/// notification is ACKed, the server closes, or `deadline` passes. Data still
+/// arriving is discarded.
+async fn drain_client(
+    managed: &mut ManagedSession,
+    socket: &impl DatagramSocket,
+    server: SocketAddr,
+    config: &RaknetStreamConfig,
+    deadline: time::Instant,
+    buf: &mut [u8],
+) {
+    let _ = managed.send_disconnect(DisconnectReason::ShuttingDown);
+    flush_built_datagrams(managed, socket, server, mux::now(), false).await;
+
+    let mut tick = time::interval(TICK_INTERVAL);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    while managed.state() != ConnectionState::Closed && !managed.is_drained() {
+        tokio::select! {
+            _ = time::sleep_until(deadline) => {
+                tracing::debug!("shutdown deadline reached before the server ACKed");
+                return;
+            }
+            res = socket.recv_from(buf) => {
+                let (len, peer) = match res {
+                    Ok(v) => v,
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                    Err(_) => return,
+                };
+                if peer != server {
+                    continue;
+                }
+                let mut slice = &buf[..len];
+                let Ok(dgram) = Datagram::decode_with_limit(&mut slice, config.max_frames_per_datagram) else {
+                    continue;
+                };
+                let now = mux::now();
+                let _ = managed.handle_datagram_with(dgram, now, |_| {});
+                flush_built_datagrams(managed, socket, server, now, false).await;
+            }
+            _ = tick.tick() => {
+                flush_built_datagrams(managed, socket, server, mux::now(), true).await;
+            }
+        }
+    }
+}
+
+/// Tell whoever is waiting why the session ended: `connect` if the online
+/// handshake never finished, the application otherwise.
+async fn report_client_closed(
+    managed: &ManagedSession,
+    ready: &mut Option<oneshot::Sender<Result<ClientReady, crate::RaknetError>>>,
+    to_app: &mpsc::Sender<Result<ReceivedMessage, crate::RaknetError>>,
+) {
+    let phase = HandshakePhase::ConnectionRequest;
+    if let Some(tx) = ready.take() {
+        let err = match managed.last_disconnect_reason() {
+            Some(DisconnectReason::TimedOut) => crate::RaknetError::Timeout { phase },
+            Some(DisconnectReason::ConnectionRequestFailed) => {
+                crate::RaknetError::HandshakeFailed {
+                    phase,
+                    cause: HandshakeFailure::Rejected,
+                }
+            }
+            _ => managed.close_error(),
+        };
+        tracing::debug!(error = ?err, "online handshake failed");
+        let _ = tx.send(Err(err));
+        return;
+    }
+    tracing::info!(reason = ?managed.last_disconnect_reason(), "session disconnected");
+    let _ = to_app.send(Err(managed.close_error())).await;
+}
+
+/// The error `connect` fails with when the server answers with `pkt`, if it
+/// is a refusal. `protocol_version` is the one we asked for.
+fn refusal(
+    pkt: &RaknetPacket,
+    phase: HandshakePhase,
+    protocol_version: u8,
+) -> Option<crate::RaknetError> {
+    let failed = |cause| crate::RaknetError::HandshakeFailed { phase, cause };
+    Some(match pkt {
+        RaknetPacket::ConnectionRequestFailed(_) => failed(HandshakeFailure::Rejected),
+        RaknetPacket::AlreadyConnected(reply) => failed(HandshakeFailure::AlreadyConnected {
+            server_guid: reply.server_guid,
+        }),
+        RaknetPacket::IpRecentlyConnected(_) => failed(HandshakeFailure::IpRecentlyConnected),
+        RaknetPacket::IncompatibleProtocolVersion(reply) => {
+            crate::RaknetError::IncompatibleProtocol {
+                ours: protocol_version,
+                theirs: reply.protocol,
+                server_guid: reply.server_guid,
+            }
+        }
+        RaknetPacket::NoFreeIncomingConnections(_) => crate::RaknetError::ServerFull,
+        RaknetPacket::ConnectionBanned(_) => crate::RaknetError::Banned,
+        _ => return None,
+    })
+}
+
 #[tracing::instrument(skip(managed, ready), level = "trace")]
 fn notify_client_ready(
     managed: &ManagedSession,
 
-    ready: &mut Option<oneshot::Sender<Result<(), crate::RaknetError>>>,
+    ready: &mut Option<oneshot::Sender<Result<ClientReady, crate::RaknetError>>>,
 ) {
     if managed.is_connected()
         && let Some(tx) = ready.take()
     {
         tracing::trace!("sending ready signal");
 
-        let _ = tx.send(Ok(()));
+        let _ = tx.send(Ok((managed.stats().clone(), managed.handshake_timing())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RaknetError;
+    use crate::protocol::packet::{
+        AlreadyConnected, ConnectionBanned, ConnectionRequestFailed, IncompatibleProtocolVersion,
+        IpRecentlyConnected, NoFreeIncomingConnections, OpenConnectionReply1, OpenConnectionReply2,
+    };
+    use crate::transport::memory::MemoryNetwork;
+    use crate::transport::{
+        FilterDecision, PausePolicy, RaknetListener, RaknetListenerConfig, Rejection,
+    };
+    use std::io;
+
+    const SERVER: &str = "10.0.0.1:19132";
+    const GUID: u64 = 0x5e4e;
+    /// Upper bound on simulated time a step may take before a test fails
+    /// instead of hanging.
+    const WAIT: Duration = Duration::from_secs(60);
+
+    /// A server that answers each offline packet with whatever `answer` returns.
+    fn fake_server(
+        net: &MemoryNetwork,
+        answer: impl Fn(&RaknetPacket) -> Option<Bytes> + Send + 'static,
+    ) {
+        let socket = net.bind(SERVER.parse().unwrap()).unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(pkt) = RaknetPacket::decode(&mut &buf[..len]) else {
+                    continue;
+                };
+                if let Some(reply) = answer(&pkt) {
+                    let _ = socket.send_to(&reply, peer).await;
+                }
+            }
+        });
+    }
+
+    fn encoded(pkt: RaknetPacket) -> Bytes {
+        let mut out = BytesMut::new();
+        pkt.encode(&mut out).unwrap();
+        out.freeze()
+    }
+
+    fn reply1(mtu: u16) -> Bytes {
+        encoded(RaknetPacket::OpenConnectionReply1(OpenConnectionReply1 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: GUID,
+            cookie: None,
+            mtu,
+        }))
+    }
+
+    /// Completes the offline handshake, then answers with `after` (if any).
+    fn after_reply1(after: Option<RaknetPacket>) -> impl Fn(&RaknetPacket) -> Option<Bytes> {
+        let after = after.map(encoded);
+        move |pkt| match pkt {
+            RaknetPacket::OpenConnectionRequest1(_) => Some(reply1(1400)),
+            RaknetPacket::OpenConnectionRequest2(_) => after.clone(),
+            _ => None,
+        }
+    }
+
+    async fn connect(net: &MemoryNetwork) -> Result<RaknetStream, RaknetError> {
+        RaknetStream::connect_on(
+            net.bind_any().unwrap(),
+            SERVER.parse().unwrap(),
+            RaknetStreamConfig::default(),
+        )
+        .await
+    }
+
+    #[test]
+    fn probes_start_at_the_configured_mtu() {
+        assert_eq!(probe_sizes(1400), [1400, 1200, 576]);
+        assert_eq!(probe_sizes(9000), [1400, 1200, 576]);
+        assert_eq!(probe_sizes(1300), [1300, 1200, 576]);
+        assert_eq!(probe_sizes(576), [576]);
+    }
+
+    #[test]
+    fn probes_share_the_attempts_largest_first() {
+        let shares: Vec<_> = (0..3).map(|i| probe_attempts(i, 3, 10)).collect();
+        assert_eq!(shares, [0..4, 4..7, 7..10]);
+        let shares: Vec<_> = (0..3).map(|i| probe_attempts(i, 3, 1)).collect();
+        assert_eq!(shares, [0..1, 1..1, 1..1]);
+    }
+
+    #[test]
+    fn keepalive_interval_reaches_the_client_session() {
+        let off = RaknetStreamConfig {
+            keepalive_interval: None,
+            ..Default::default()
+        };
+        assert_eq!(client_session_config(&off, 1).keepalive(), None);
+
+        let long = RaknetStreamConfig {
+            keepalive_interval: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let capped = client_session_config(&long, 1).keepalive();
+        assert_eq!(capped, Some(long.session_timeout / 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unreachable_server_times_out_in_mtu_discovery() {
+        let net = MemoryNetwork::new();
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout {
+                phase: HandshakePhase::MtuDiscovery
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_reply2_times_out_in_open_connection() {
+        let net = MemoryNetwork::new();
+        fake_server(&net, after_reply1(None));
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout {
+                phase: HandshakePhase::OpenConnection
+            })
+        ));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_its_cap() {
+        let backoff = HandshakeBackoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(250),
+        };
+        let delays: Vec<_> = [0, 1, 2, 40].map(|n| backoff.delay(n).as_millis()).into();
+        assert_eq!(delays, [100, 200, 250, 250]);
+        let fixed = HandshakeBackoff::Fixed(Duration::from_millis(30));
+        assert_eq!(fixed.delay(5), Duration::from_millis(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_retry_sets_attempts_and_pauses() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let net = MemoryNetwork::new();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        fake_server(&net, move |pkt| {
+            if let RaknetPacket::OpenConnectionRequest1(_) = pkt {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            None
+        });
+        let config = RaknetStreamConfig {
+            mtu: MINIMUM_MTU_SIZE,
+            handshake_retry: HandshakeRetry {
+                attempts: 4,
+                attempt_timeout: Duration::from_millis(100),
+                backoff: HandshakeBackoff::Exponential {
+                    initial: Duration::from_millis(100),
+                    max: Duration::from_millis(250),
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let start = time::Instant::now();
+        let res =
+            RaknetStream::connect_on(net.bind_any().unwrap(), SERVER.parse().unwrap(), config)
+                .await;
+        assert!(matches!(
+            res,
+            Err(RaknetError::Timeout {
+                phase: HandshakePhase::MtuDiscovery
+            })
+        ));
+        assert_eq!(sent.load(Ordering::Relaxed), 4);
+        // Four 100ms waits, plus pauses of 100, 200 and 250ms between them.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(950), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_request2_is_resent() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let net = MemoryNetwork::new();
+        let reply2 = encoded(RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: GUID,
+            server_addr: SERVER.parse().unwrap(),
+            mtu: 1400,
+            security: false,
+        }));
+        let dropped_one = AtomicBool::new(false);
+        fake_server(&net, move |pkt| match pkt {
+            RaknetPacket::OpenConnectionRequest1(_) => Some(reply1(1400)),
+            RaknetPacket::OpenConnectionRequest2(_)
+                if dropped_one.swap(true, Ordering::Relaxed) =>
+            {
+                Some(reply2.clone())
+            }
+            _ => None,
+        });
+        // Past the offline handshake: nothing answers the ConnectionRequest.
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout {
+                phase: HandshakePhase::ConnectionRequest
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request2_echoes_the_cookie_from_reply1() {
+        // Starts with 4, like an encoded IPv4 address.
+        const COOKIE: u32 = 0x0400_0a0b;
+        let net = MemoryNetwork::new();
+        let reply2 = encoded(RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: GUID,
+            server_addr: SERVER.parse().unwrap(),
+            mtu: 1400,
+            security: true,
+        }));
+        let failed = encoded(RaknetPacket::ConnectionRequestFailed(
+            ConnectionRequestFailed {
+                magic: DEFAULT_UNCONNECTED_MAGIC,
+                server_guid: GUID,
+            },
+        ));
+        fake_server(&net, move |pkt| match pkt {
+            RaknetPacket::OpenConnectionRequest1(_) => Some(encoded(
+                RaknetPacket::OpenConnectionReply1(OpenConnectionReply1 {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    server_guid: GUID,
+                    cookie: Some(COOKIE),
+                    mtu: 1400,
+                }),
+            )),
+            RaknetPacket::OpenConnectionRequest2(req) => {
+                if req.cookie == Some(COOKIE) && req.client_proof {
+                    Some(reply2.clone())
+                } else {
+                    Some(failed.clone())
+                }
+            }
+            _ => None,
+        });
+        // Past the offline handshake: nothing answers the ConnectionRequest.
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout {
+                phase: HandshakePhase::ConnectionRequest
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connected_stream_reports_the_security_flag() {
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let (client, server) = tokio::join!(connect(&net), listener.accept());
+        assert_eq!(client.unwrap().security(), Some(true));
+        assert_eq!(server.unwrap().security(), None);
+    }
+
+    /// A server that completes the offline handshake, then ignores the
+    /// `ConnectionRequest` and reports every online packet it gets.
+    fn stalling_server(net: &MemoryNetwork) -> mpsc::UnboundedReceiver<RaknetPacket> {
+        let socket = net.bind(SERVER.parse().unwrap()).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let reply = match RaknetPacket::decode(&mut &buf[..len]) {
+                    Ok(RaknetPacket::OpenConnectionRequest1(_)) => reply1(1400),
+                    Ok(RaknetPacket::OpenConnectionRequest2(_)) => {
+                        encoded(RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+                            magic: DEFAULT_UNCONNECTED_MAGIC,
+                            server_guid: GUID,
+                            server_addr: SERVER.parse().unwrap(),
+                            mtu: 1400,
+                            security: false,
+                        }))
+                    }
+                    _ => {
+                        if let Ok(Datagram {
+                            payload:
+                                crate::protocol::datagram::DatagramPayload::EncapsulatedPackets(frames),
+                            ..
+                        }) = Datagram::decode(&mut &buf[..len])
+                        {
+                            for frame in frames {
+                                if let Ok(pkt) = RaknetPacket::decode(&mut &frame.payload[..]) {
+                                    let _ = tx.send(pkt);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+        rx
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_connect_leaves_nothing_behind() {
+        const CLIENT: &str = "10.0.0.2:5000";
+        // Offline, no server answers; online, one stalls after the offline
+        // handshake. Each run gives up after 100ms.
+        for online in [false, true] {
+            for by_token in [false, true] {
+                let net = MemoryNetwork::new();
+                let mut seen = match online {
+                    true => stalling_server(&net),
+                    false => mpsc::unbounded_channel().1,
+                };
+                let cancel = CancellationToken::new();
+                let config = RaknetStreamConfig {
+                    shutdown: by_token.then(|| cancel.clone()),
+                    ..Default::default()
+                };
+                let socket = net.bind(CLIENT.parse().unwrap()).unwrap();
+                let connect = RaknetStream::connect_on(socket, SERVER.parse().unwrap(), config);
+                if by_token {
+                    let (res, ()) = tokio::join!(connect, async {
+                        time::sleep(Duration::from_millis(100)).await;
+                        cancel.cancel();
+                    });
+                    assert!(matches!(res, Err(RaknetError::Shutdown)));
+                } else {
+                    assert!(
+                        time::timeout(Duration::from_millis(100), connect)
+                            .await
+                            .is_err()
+                    );
+                }
+
+                time::sleep(Duration::from_millis(10)).await;
+                assert!(
+                    net.bind(CLIENT.parse().unwrap()).is_ok(),
+                    "socket still bound"
+                );
+                let mut told = false;
+                while let Ok(pkt) = seen.try_recv() {
+                    told |= matches!(pkt, RaknetPacket::DisconnectionNotification(_));
+                }
+                assert_eq!(told, online, "online: {online}, by token: {by_token}");
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_stream_stops_its_muxer() {
+        const CLIENT: &str = "10.0.0.2:5000";
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let socket = net.bind(CLIENT.parse().unwrap()).unwrap();
+        let (client, server) = tokio::join!(
+            RaknetStream::connect_on(socket, SERVER.parse().unwrap(), Default::default()),
+            listener.accept()
+        );
+        let mut server = server.unwrap();
+        drop(client.unwrap());
+
+        assert!(matches!(
+            time::timeout(Duration::from_secs(1), server.recv()).await,
+            Ok(Some(Err(RaknetError::Disconnected(
+                DisconnectReason::Disconnected
+            ))))
+        ));
+        assert!(
+            net.bind(CLIENT.parse().unwrap()).is_ok(),
+            "socket still bound"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_connection_request_times_out() {
+        let net = MemoryNetwork::new();
+        let reply2 = RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: GUID,
+            server_addr: SERVER.parse().unwrap(),
+            mtu: 1400,
+            security: false,
+        });
+        fake_server(&net, after_reply1(Some(reply2)));
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout {
+                phase: HandshakePhase::ConnectionRequest
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refusals_to_request1_fail_fast() {
+        type Check = fn(&RaknetError) -> bool;
+        let cases: Vec<(Bytes, Check)> = vec![
+            (
+                encoded(RaknetPacket::IncompatibleProtocolVersion(
+                    IncompatibleProtocolVersion {
+                        protocol: 10,
+                        magic: DEFAULT_UNCONNECTED_MAGIC,
+                        server_guid: GUID,
+                    },
+                )),
+                |e| {
+                    matches!(
+                        e,
+                        RaknetError::IncompatibleProtocol {
+                            ours: RAKNET_PROTOCOL_VERSION,
+                            theirs: 10,
+                            server_guid: GUID,
+                        }
+                    )
+                },
+            ),
+            (
+                encoded(RaknetPacket::NoFreeIncomingConnections(
+                    NoFreeIncomingConnections,
+                )),
+                |e| matches!(e, RaknetError::ServerFull),
+            ),
+            (
+                encoded(RaknetPacket::ConnectionBanned(ConnectionBanned {
+                    payload: Bytes::new(),
+                })),
+                |e| matches!(e, RaknetError::Banned),
+            ),
+            (
+                encoded(RaknetPacket::IpRecentlyConnected(IpRecentlyConnected)),
+                |e| {
+                    matches!(
+                        e,
+                        RaknetError::HandshakeFailed {
+                            phase: HandshakePhase::MtuDiscovery,
+                            cause: HandshakeFailure::IpRecentlyConnected
+                        }
+                    )
+                },
+            ),
+            (reply1(400), |e| {
+                matches!(
+                    e,
+                    RaknetError::HandshakeFailed {
+                        phase: HandshakePhase::MtuDiscovery,
+                        cause: HandshakeFailure::MtuTooSmall(400)
+                    }
+                )
+            }),
+        ];
+
+        for (reply, expected) in cases {
+            let net = MemoryNetwork::new();
+            let id = reply[0];
+            fake_server(&net, move |_| Some(reply.clone()));
+            let start = time::Instant::now();
+            let err = connect(&net).await.err().unwrap();
+            assert!(expected(&err), "reply {id:#04x}: {err:?}");
+            assert!(start.elapsed() < HandshakeRetry::default().attempt_timeout);
+        }
+    }
+
+    fn reply2_with_mtu(mtu: u16) -> RaknetPacket {
+        RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: GUID,
+            server_addr: SERVER.parse().unwrap(),
+            mtu,
+            security: false,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refusals_to_request2_name_the_phase() {
+        let cases = [
+            (
+                RaknetPacket::AlreadyConnected(AlreadyConnected {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    server_guid: GUID,
+                }),
+                HandshakeFailure::AlreadyConnected { server_guid: GUID },
+            ),
+            (
+                RaknetPacket::ConnectionRequestFailed(ConnectionRequestFailed {
+                    magic: DEFAULT_UNCONNECTED_MAGIC,
+                    server_guid: GUID,
+                }),
+                HandshakeFailure::Rejected,
+            ),
+            (reply2_with_mtu(400), HandshakeFailure::MtuTooSmall(400)),
+            (reply2_with_mtu(1500), HandshakeFailure::MtuTooLarge(1500)),
+        ];
+
+        for (reply, expected) in cases {
+            let net = MemoryNetwork::new();
+            fake_server(&net, after_reply1(Some(reply)));
+            match connect(&net).await {
+                Err(RaknetError::HandshakeFailed {
+                    phase: HandshakePhase::OpenConnection,
+                    cause,
+                }) => assert_eq!(cause, expected),
+                other => panic!("{:?}", other.err()),
+            }
+        }
+    }
+
+    /// A server that answers everything with `AlreadyConnected`, counting
+    /// what it was sent.
+    fn already_connected_server(net: &MemoryNetwork) -> Arc<std::sync::atomic::AtomicUsize> {
+        let seen = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = seen.clone();
+        let reply = encoded(RaknetPacket::AlreadyConnected(AlreadyConnected {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: GUID,
+        }));
+        fake_server(net, move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Some(reply.clone())
+        });
+        seen
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn already_connected_fails_fast_with_the_server_guid() {
+        let net = MemoryNetwork::new();
+        let seen = already_connected_server(&net);
+        let start = time::Instant::now();
+        match connect(&net).await {
+            Err(RaknetError::HandshakeFailed {
+                phase: HandshakePhase::MtuDiscovery,
+                cause: HandshakeFailure::AlreadyConnected { server_guid: GUID },
+            }) => {}
+            other => panic!("{:?}", other.err()),
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(seen.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn already_connected_is_retried_once_after_the_delay() {
+        let net = MemoryNetwork::new();
+        let seen = already_connected_server(&net);
+        let config = RaknetStreamConfig {
+            handshake_retry: HandshakeRetry {
+                recently_connected_delay: Some(Duration::from_secs(2)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let start = time::Instant::now();
+        let res =
+            RaknetStream::connect_on(net.bind_any().unwrap(), SERVER.parse().unwrap(), config)
+                .await;
+        assert!(matches!(
+            res,
+            Err(RaknetError::HandshakeFailed {
+                cause: HandshakeFailure::AlreadyConnected { .. },
+                ..
+            })
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(seen.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_listener_refuses_with_server_full() {
+        let net = MemoryNetwork::new();
+        let config = RaknetListenerConfig {
+            max_connections: 0,
+            ..Default::default()
+        };
+        let _listener =
+            RaknetListener::with_socket(net.bind(SERVER.parse().unwrap()).unwrap(), config)
+                .unwrap();
+        assert!(matches!(connect(&net).await, Err(RaknetError::ServerFull)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_filter_runs_before_any_session_exists() {
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let decision = Arc::new(std::sync::Mutex::new(FilterDecision::Drop));
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let (decision, seen) = (decision.clone(), seen.clone());
+            listener.set_connection_filter(move |peer, guid| {
+                seen.lock().unwrap().push((peer, guid));
+                *decision.lock().unwrap()
+            });
+        }
+
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout { .. })
+        ));
+        *decision.lock().unwrap() = FilterDecision::Reject(Rejection::Failed);
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::HandshakeFailed {
+                cause: HandshakeFailure::Rejected,
+                ..
+            })
+        ));
+        *decision.lock().unwrap() = FilterDecision::Reject(Rejection::Banned);
+        assert!(matches!(connect(&net).await, Err(RaknetError::Banned)));
+        let stats = listener.stats();
+        assert_eq!(stats.sessions, 0);
+        let filtered = stats.handshakes_filtered;
+        assert!(filtered >= 3, "{filtered}");
+
+        // The filter sees who is connecting.
+        *decision.lock().unwrap() = FilterDecision::Accept;
+        let (client, server) = tokio::join!(connect(&net), listener.accept());
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(
+            seen.lock().unwrap().last(),
+            Some(&(client.local_addr(), server.peer_guid()))
+        );
+
+        listener.clear_connection_filter();
+        *decision.lock().unwrap() = FilterDecision::Drop;
+        let (client, _server) = tokio::join!(connect(&net), listener.accept());
+        client.unwrap();
+        assert_eq!(listener.stats().handshakes_filtered, filtered);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_listener_refuses_handshakes_but_keeps_sessions() {
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let (client, server) = tokio::join!(connect(&net), listener.accept());
+        let (client, mut server) = (client.unwrap(), server.unwrap());
+
+        listener.set_accepting(false);
+        assert!(!listener.is_accepting());
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::HandshakeFailed {
+                cause: HandshakeFailure::Rejected,
+                ..
+            })
+        ));
+        client.send(&b"\xfestill here"[..]).await.unwrap();
+        let msg = server.recv().await.unwrap().unwrap();
+        assert_eq!(&msg[..], b"\xfestill here");
+        assert_eq!(listener.stats().sessions, 1);
+
+        listener.set_accepting(true);
+        let (client, _server) = tokio::join!(connect(&net), listener.accept());
+        client.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_listener_can_ignore_handshakes() {
+        let net = MemoryNetwork::new();
+        let listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig {
+                pause_policy: PausePolicy::Drop,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        listener.set_accepting(false);
+        assert!(matches!(
+            connect(&net).await,
+            Err(RaknetError::Timeout { .. })
+        ));
+        assert_eq!(listener.stats().sessions, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_right_after_a_disconnect_is_refused() {
+        let connect_from = |net: &MemoryNetwork| {
+            RaknetStream::connect_on(
+                net.bind("10.0.0.2:0".parse().unwrap()).unwrap(),
+                SERVER.parse().unwrap(),
+                RaknetStreamConfig::default(),
+            )
+        };
+        for window in [Duration::from_millis(200), Duration::ZERO] {
+            let net = MemoryNetwork::new();
+            let mut listener = RaknetListener::with_socket(
+                net.bind(SERVER.parse().unwrap()).unwrap(),
+                RaknetListenerConfig {
+                    ip_recently_connected_window: window,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let (client, server) = time::timeout(WAIT, async {
+                tokio::join!(connect_from(&net), listener.accept())
+            })
+            .await
+            .expect("first connect never finished");
+            let (_client, server) = (client.unwrap(), server.unwrap());
+            listener
+                .kick(server.peer_addr(), DisconnectReason::Disconnected)
+                .await
+                .unwrap();
+
+            let (res, _) = time::timeout(WAIT, async {
+                tokio::join!(connect_from(&net), async {
+                    if window.is_zero() {
+                        time::timeout(WAIT, listener.accept())
+                            .await
+                            .expect("reconnect was never accepted");
+                    }
+                })
+            })
+            .await
+            .expect("reconnect never finished");
+            if window.is_zero() {
+                res.unwrap();
+                continue;
+            }
+            assert!(matches!(
+                res,
+                Err(RaknetError::HandshakeFailed {
+                    cause: HandshakeFailure::IpRecentlyConnected,
+                    ..
+                })
+            ));
+            let stats = listener.stats();
+            assert_eq!(stats.handshakes_recently_connected, 1);
+            assert_eq!(stats.handshakes_throttled, 0);
+            tokio::time::sleep(window).await;
+            let (client, _server) = time::timeout(WAIT, async {
+                tokio::join!(connect_from(&net), listener.accept())
+            })
+            .await
+            .expect("connect after the window never finished");
+            client.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn listener_lists_its_connected_peers() {
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        assert!(listener.peers().await.unwrap().is_empty());
+
+        let (a, a_conn) = tokio::join!(connect(&net), listener.accept());
+        let (a, a_conn) = (a.unwrap(), a_conn.unwrap());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let (b, _b_conn) = tokio::join!(connect(&net), listener.accept());
+        let b = b.unwrap();
+
+        let mut peers = listener.peers().await.unwrap();
+        peers.sort_by_key(|p| p.uptime);
+        assert_eq!(peers.len(), 2);
+        assert_eq!(listener.connection_count().await.unwrap(), 2);
+        let (newest, oldest) = (peers[0], peers[1]);
+        assert_eq!(newest.addr, b.local_addr());
+        assert_eq!(oldest.addr, a.local_addr());
+        assert_eq!(oldest.guid, a_conn.peer_guid());
+        assert_eq!(oldest.mtu, a_conn.mtu());
+        assert_eq!(oldest.state, ConnectionState::Connected);
+        assert!(
+            oldest.uptime >= Duration::from_secs(3),
+            "{:?}",
+            oldest.uptime
+        );
+        assert!(oldest.last_activity <= mux::now());
+
+        a.disconnect(DisconnectReason::Disconnected).await.unwrap();
+        let peers = listener.peers().await.unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, b.local_addr());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_kick_is_resent_until_the_peer_has_it() {
+        use crate::transport::memory::SimulatedLink;
+
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let (client, conn) = tokio::join!(connect(&net), listener.accept());
+        let (mut client, mut conn) = (client.unwrap(), conn.unwrap());
+
+        // The first copy of the notification is lost.
+        let down = |link| net.set_link(SERVER.parse().unwrap(), client.local_addr(), link);
+        down(SimulatedLink::new().loss(1.0));
+        listener
+            .kick(conn.peer_addr(), DisconnectReason::ShuttingDown)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        down(SimulatedLink::new());
+
+        assert!(matches!(
+            client.recv().await,
+            Some(Err(RaknetError::Disconnected(
+                DisconnectReason::ShuttingDown
+            )))
+        ));
+        assert!(matches!(
+            conn.recv().await,
+            Some(Err(RaknetError::Disconnected(
+                DisconnectReason::ShuttingDown
+            )))
+        ));
+        assert_eq!(listener.stats().sessions, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn listener_kicks_peers_by_address_or_guid() {
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let (a, a_conn) = tokio::join!(connect(&net), listener.accept());
+        let (mut a, mut a_conn) = (a.unwrap(), a_conn.unwrap());
+        let (b, b_conn) = tokio::join!(connect(&net), listener.accept());
+        let (mut b, mut b_conn) = (b.unwrap(), b_conn.unwrap());
+
+        listener
+            .kick(a.local_addr(), DisconnectReason::Disconnected)
+            .await
+            .unwrap();
+        listener
+            .kick_guid(b_conn.peer_guid(), DisconnectReason::ShuttingDown)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            a_conn.recv().await,
+            Some(Err(RaknetError::Disconnected(
+                DisconnectReason::Disconnected
+            )))
+        ));
+        assert!(matches!(
+            b_conn.recv().await,
+            Some(Err(RaknetError::Disconnected(
+                DisconnectReason::ShuttingDown
+            )))
+        ));
+        assert!(matches!(
+            a.recv().await,
+            Some(Err(RaknetError::Disconnected(_)))
+        ));
+        assert!(matches!(
+            b.recv().await,
+            Some(Err(RaknetError::Disconnected(_)))
+        ));
+        assert_eq!(listener.stats().sessions, 0);
+
+        assert!(matches!(
+            listener
+                .kick(a.local_addr(), DisconnectReason::Disconnected)
+                .await,
+            Err(RaknetError::UnknownPeer)
+        ));
+        assert!(matches!(
+            listener
+                .kick_guid(b_conn.peer_guid(), DisconnectReason::Disconnected)
+                .await,
+            Err(RaknetError::UnknownPeer)
+        ));
+    }
+
+    struct Unroutable(SocketAddr);
+
+    impl DatagramSocket for Unroutable {
+        async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+            Err(io::ErrorKind::NetworkUnreachable.into())
+        }
+
+        async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            std::future::pending().await
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.0)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn socket_that_cannot_send_reports_io() {
+        let socket = Unroutable("127.0.0.1:40000".parse().unwrap());
+        let res = RaknetStream::connect_on(
+            socket,
+            SERVER.parse().unwrap(),
+            RaknetStreamConfig::default(),
+        )
+        .await;
+        match res {
+            Err(RaknetError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NetworkUnreachable),
+            other => panic!("{:?}", other.err()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_stream_reports_once_then_refuses_sends() {
+        let net = MemoryNetwork::new();
+        let mut listener = RaknetListener::with_socket(
+            net.bind(SERVER.parse().unwrap()).unwrap(),
+            RaknetListenerConfig::default(),
+        )
+        .unwrap();
+        let (client, _server) = tokio::join!(connect(&net), listener.accept());
+        let mut client = client.unwrap();
+
+        client.close(DisconnectReason::ShuttingDown).await.unwrap();
+        assert!(matches!(
+            client.recv().await,
+            Some(Err(RaknetError::Disconnected(
+                DisconnectReason::ShuttingDown
+            )))
+        ));
+        assert!(client.recv_msg().await.is_none());
+        assert!(matches!(
+            client.send("late").await,
+            Err(RaknetError::ConnectionClosed)
+        ));
+        assert!(matches!(
+            client.close(DisconnectReason::Disconnected).await,
+            Err(RaknetError::ConnectionClosed)
+        ));
     }
 }
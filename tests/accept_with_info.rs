@@ -0,0 +1,61 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio::time::timeout;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::RAKNET_PROTOCOL_VERSION;
+use tokio_raknet::transport::{RaknetStream, RaknetStreamConfig};
+
+const GUID: u64 = 0x0123_4567_89ab_cdef;
+
+#[tokio::test]
+async fn accept_with_info_reports_the_handshake() {
+    let mut listener = RaknetListener::bind("0.0.0.0:0".parse().unwrap())
+        .await
+        .unwrap();
+    let port = listener.local_addr().port();
+    let server = format!("127.0.0.1:{port}").parse().unwrap();
+    let peer = RawPeer::new(server).await;
+
+    peer.connect(GUID).await;
+    let incoming = timeout(Duration::from_secs(2), listener.accept_with_info())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.client_guid, GUID);
+    assert_eq!(incoming.stream.peer_guid(), GUID);
+    assert_eq!(incoming.mtu, 1400);
+    assert_eq!(incoming.stream.mtu(), 1400);
+    assert_eq!(incoming.protocol_version, RAKNET_PROTOCOL_VERSION);
+    // The wildcard bind doesn't say which address the client used; its
+    // NewIncomingConnection does.
+    assert!(listener.local_addr().ip().is_unspecified());
+    assert_eq!(incoming.server_addr, server);
+}
+
+#[tokio::test]
+async fn accept_with_info_matches_what_the_client_negotiated() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let config = RaknetStreamConfig {
+        mtu: 1200,
+        ..Default::default()
+    };
+
+    let (client, incoming) = tokio::join!(
+        RaknetStream::connect_with_config(listener.local_addr(), config),
+        listener.accept_with_info()
+    );
+    let (client, incoming) = (client.unwrap(), incoming.unwrap());
+    assert_eq!(incoming.mtu, client.mtu());
+    assert!(incoming.mtu <= 1200, "{}", incoming.mtu);
+    assert_eq!(
+        incoming.stream.peer_addr().port(),
+        client.local_addr().port()
+    );
+    assert_eq!(incoming.server_addr, client.peer_addr());
+    assert_eq!(incoming.protocol_version, RAKNET_PROTOCOL_VERSION);
+}
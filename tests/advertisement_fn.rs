@@ -0,0 +1,59 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{
+    RaknetPacket, UnconnectedPing, UnconnectedPingOpenConnections,
+};
+use tokio_raknet::protocol::types::RaknetTime;
+
+/// The advertisement in the listener's answer to a ping at `time`, or
+/// `None` if it didn't answer.
+async fn pong(peer: &RawPeer, time: u64, open_connections: bool) -> Option<Vec<u8>> {
+    let ping_time = RaknetTime(time);
+    let magic = DEFAULT_UNCONNECTED_MAGIC;
+    peer.send_packet(if open_connections {
+        RaknetPacket::UnconnectedPingOpenConnections(UnconnectedPingOpenConnections {
+            ping_time,
+            magic,
+        })
+    } else {
+        RaknetPacket::UnconnectedPing(UnconnectedPing { ping_time, magic })
+    })
+    .await;
+    match peer.recv_packet(Duration::from_millis(500)).await? {
+        RaknetPacket::UnconnectedPong(pong) => {
+            assert_eq!(pong.ping_time.0, time);
+            Some(pong.advertisement.0.unwrap_or_default().to_vec())
+        }
+        other => panic!("expected UnconnectedPong, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn callback_builds_or_suppresses_each_pong() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    listener.set_advertisement(b"static".to_vec());
+    let peer = RawPeer::new(listener.local_addr()).await;
+    let me = peer.socket.local_addr().unwrap();
+
+    listener.set_advertisement_fn(|from, time| {
+        (time.0 != 0).then(|| Bytes::from(format!("{from};{}", time.0)))
+    });
+    for open_connections in [false, true] {
+        assert_eq!(
+            pong(&peer, 42, open_connections).await.unwrap(),
+            format!("{me};42").into_bytes()
+        );
+        assert_eq!(pong(&peer, 0, open_connections).await, None);
+    }
+
+    listener.clear_advertisement_fn();
+    assert_eq!(pong(&peer, 0, false).await.unwrap(), b"static");
+}
@@ -0,0 +1,240 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use common::{RawPeer, data_datagram};
+use tokio::time::timeout;
+use tokio_raknet::protocol::constants::DatagramFlags;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::session::ReorderLimit;
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+use tokio_raknet::{RaknetError, RaknetListener};
+
+#[tokio::test]
+async fn garbage_does_not_kill_an_established_session() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(0x22).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+
+    // Unrelated junk from the same address: not a datagram, not an offline
+    // packet, and an offline id without the magic.
+    peer.send_raw(&[0x13, 0x37]).await;
+    peer.send_raw(&[0x05, 0xff]).await;
+    peer.send_raw(&[0x00]).await;
+
+    peer.send_raw(&data_datagram(2, Bytes::from_static(&[0x86, 1, 2, 3])))
+        .await;
+    let msg = timeout(Duration::from_secs(2), conn.recv())
+        .await
+        .expect("data flow stopped")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 1, 2, 3]);
+
+    let local = peer.socket.local_addr().unwrap();
+    let stats = listener.peer_stats(local).expect("session dropped");
+    assert_eq!(stats.datagrams_undecodable, 3);
+}
+
+#[tokio::test]
+async fn out_of_range_ordering_channel_counts_as_a_bad_datagram() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(0x25).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+
+    // VALID datagram, one ReliableOrdered frame on channel 200.
+    let mut bad = vec![DatagramFlags::VALID.bits(), 2, 0, 0];
+    bad.extend_from_slice(&[3 << 5, 0, 8, 0, 0, 0, 0, 0, 0, 200, 0x86]);
+    peer.send_raw(&bad).await;
+
+    peer.send_raw(&data_datagram(3, Bytes::from_static(&[0x86, 4])))
+        .await;
+    let msg = timeout(Duration::from_secs(2), conn.recv())
+        .await
+        .expect("data flow stopped")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 4]);
+
+    let local = peer.socket.local_addr().unwrap();
+    let stats = listener.peer_stats(local).expect("session dropped");
+    assert_eq!(stats.datagrams_undecodable, 1);
+}
+
+#[tokio::test]
+async fn channel_15_is_the_last_ordering_channel() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(0x26).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+
+    // One ReliableOrdered frame on `channel`, reliable and ordering index 0.
+    let frame = |sequence: u8, channel: u8, id: u8| {
+        let mut dgram = vec![DatagramFlags::VALID.bits(), sequence, 0, 0];
+        dgram.extend_from_slice(&[3 << 5, 0, 8, 0, 0, 0, 0, 0, 0, channel, id]);
+        dgram
+    };
+    peer.send_raw(&frame(2, 16, 0x86)).await;
+    peer.send_raw(&frame(3, 15, 0x87)).await;
+    let msg = timeout(Duration::from_secs(2), conn.recv_msg())
+        .await
+        .expect("data flow stopped")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!((&msg.buffer[..], msg.channel), (&[0x87][..], 15));
+
+    let local = peer.socket.local_addr().unwrap();
+    let stats = listener.peer_stats(local).expect("session dropped");
+    assert_eq!(stats.datagrams_undecodable, 1);
+}
+
+#[tokio::test]
+async fn a_run_of_garbage_closes_the_session() {
+    let config = RaknetListenerConfig {
+        max_consecutive_bad_datagrams: 4,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.offline_handshake(1400, 0x23).await;
+    let local = peer.socket.local_addr().unwrap();
+
+    for _ in 0..3 {
+        peer.send_raw(&[0x42]).await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(listener.peer_stats(local).is_some());
+
+    let started = Instant::now();
+    peer.send_raw(&[0x42]).await;
+    while listener.peer_stats(local).is_some() {
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "session survived a run of garbage"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn good_datagrams_reset_the_streak_but_not_the_total() {
+    let config = RaknetListenerConfig {
+        max_consecutive_bad_datagrams: 2,
+        max_bad_datagrams: 4,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.offline_handshake(1400, 0x24).await;
+    let local = peer.socket.local_addr().unwrap();
+
+    for seq in 0..3 {
+        peer.send_raw(&[0x42]).await;
+        peer.send_raw(&data_datagram(seq, Bytes::from_static(&[0x86])))
+            .await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(listener.peer_stats(local).is_some());
+
+    let started = Instant::now();
+    peer.send_raw(&[0x42]).await;
+    while listener.peer_stats(local).is_some() {
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "session survived its bad datagram budget"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn a_gap_that_never_fills_closes_the_session() {
+    let config = RaknetListenerConfig {
+        reorder_limit: ReorderLimit {
+            max_frames: 64,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(0x27).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+    let local = peer.socket.local_addr().unwrap();
+
+    // ReliableOrdered frames on channel 0 that never send index 0, so each
+    // one has to wait behind the gap.
+    let frame = |index: u32| {
+        let [a, b, c, _] = (index + 1).to_le_bytes();
+        let mut dgram = vec![DatagramFlags::VALID.bits(), a, b, c];
+        dgram.extend_from_slice(&[3 << 5, 0, 8, a, b, c, a, b, c, 0, 0x86]);
+        dgram
+    };
+    for index in 1..=64 {
+        peer.send_raw(&frame(index)).await;
+    }
+    let started = Instant::now();
+    while listener.peer_stats(local).map(|s| s.reorder_frames) != Some(64) {
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "frames were not held back"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    peer.send_raw(&frame(65)).await;
+    match timeout(Duration::from_secs(2), conn.recv()).await {
+        Ok(Some(Err(RaknetError::Disconnected(DisconnectReason::BadPacket)))) => {}
+        other => panic!("expected a BadPacket disconnect, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn a_split_bomb_closes_the_session() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(0x28).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+
+    // One Reliable split part claiming 0xffffffff parts in all.
+    let mut dgram = vec![DatagramFlags::VALID.bits(), 2, 0, 0];
+    dgram.extend_from_slice(&[2 << 5 | 0x10, 0, 8, 0, 0, 0]);
+    dgram.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 1, 0, 0, 0, 0, 0x86]);
+    peer.send_raw(&dgram).await;
+    match timeout(Duration::from_secs(2), conn.recv()).await {
+        Ok(Some(Err(RaknetError::Disconnected(DisconnectReason::BadPacket)))) => {}
+        other => panic!("expected a BadPacket disconnect, got {other:?}"),
+    }
+}
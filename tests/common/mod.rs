@@ -0,0 +1,165 @@
+//! Raw-socket helpers for driving a listener without `RaknetStream`.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_raknet::protocol::{
+    constants::LOCAL_IP_ADDRESSES_V4,
+    constants::{DEFAULT_UNCONNECTED_MAGIC, DatagramFlags, RAKNET_PROTOCOL_VERSION},
+    datagram::{Datagram, DatagramPayload},
+    encapsulated_packet::EncapsulatedPacket,
+    packet::{
+        ConnectionRequest, NewIncomingConnection, OpenConnectionRequest1, OpenConnectionRequest2,
+        RaknetPacket,
+    },
+    reliability::Reliability,
+    types::{DatagramHeader, EncapsulatedPacketHeader, EoBPadding, RaknetTime, Sequence24},
+};
+
+pub mod vanilla;
+
+/// A bare UDP socket speaking just enough RakNet to poke at a listener.
+pub struct RawPeer {
+    pub socket: UdpSocket,
+    pub server: SocketAddr,
+}
+
+impl RawPeer {
+    pub async fn new(server: SocketAddr) -> Self {
+        Self::bind("127.0.0.1:0", server).await
+    }
+
+    /// Like `new`, but from a chosen local address, e.g. another loopback IP.
+    pub async fn bind(local: &str, server: SocketAddr) -> Self {
+        let socket = UdpSocket::bind(local).await.unwrap();
+        Self { socket, server }
+    }
+
+    pub async fn send_packet(&self, pkt: RaknetPacket) {
+        let mut buf = BytesMut::new();
+        pkt.encode(&mut buf).unwrap();
+        self.socket.send_to(&buf, self.server).await.unwrap();
+    }
+
+    pub async fn send_raw(&self, bytes: &[u8]) {
+        self.socket.send_to(bytes, self.server).await.unwrap();
+    }
+
+    /// Receive the next datagram from the server, or `None` on timeout.
+    pub async fn recv_raw(&self, wait: Duration) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 2048];
+        match timeout(wait, self.socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, _))) => Some(buf[..len].to_vec()),
+            _ => None,
+        }
+    }
+
+    pub async fn recv_packet(&self, wait: Duration) -> Option<RaknetPacket> {
+        let raw = self.recv_raw(wait).await?;
+        RaknetPacket::decode(&mut raw.as_slice()).ok()
+    }
+
+    /// Run OpenConnectionRequest1/2 for `mtu`, returning the negotiated MTU.
+    pub async fn offline_handshake(&self, mtu: u16, guid: u64) -> u16 {
+        match self.open_connection(mtu, guid).await {
+            Some(RaknetPacket::OpenConnectionReply2(r)) => r.mtu,
+            other => panic!("expected OpenConnectionReply2, got {other:?}"),
+        }
+    }
+
+    /// Run the offline and online handshakes, leaving the session connected.
+    /// Online datagrams use sequence numbers 0 and 1.
+    pub async fn connect(&self, guid: u64) {
+        self.offline_handshake(1400, guid).await;
+        self.send_raw(&packet_datagram(
+            0,
+            RaknetPacket::ConnectionRequest(ConnectionRequest {
+                client_guid: guid,
+                timestamp: RaknetTime(0),
+                secure: false,
+            }),
+        ))
+        .await;
+        self.recv_raw(Duration::from_secs(2))
+            .await
+            .expect("no ConnectionRequestAccepted");
+        self.send_raw(&packet_datagram(
+            1,
+            RaknetPacket::NewIncomingConnection(NewIncomingConnection {
+                server_address: self.server,
+                system_addresses: LOCAL_IP_ADDRESSES_V4.map(SocketAddr::V4),
+                request_timestamp: RaknetTime(0),
+                accepted_timestamp: RaknetTime(0),
+            }),
+        ))
+        .await;
+    }
+
+    /// Run OpenConnectionRequest1/2 for `mtu`, returning whatever the server
+    /// answered the second request with.
+    pub async fn open_connection(&self, mtu: u16, guid: u64) -> Option<RaknetPacket> {
+        self.send_packet(RaknetPacket::OpenConnectionRequest1(
+            OpenConnectionRequest1 {
+                magic: DEFAULT_UNCONNECTED_MAGIC,
+                protocol_version: RAKNET_PROTOCOL_VERSION,
+                padding: EoBPadding(mtu as usize - 46),
+            },
+        ))
+        .await;
+        let reply1 = match self.recv_packet(Duration::from_secs(2)).await {
+            Some(RaknetPacket::OpenConnectionReply1(r)) => r,
+            other => panic!("expected OpenConnectionReply1, got {other:?}"),
+        };
+
+        self.send_packet(RaknetPacket::OpenConnectionRequest2(
+            OpenConnectionRequest2 {
+                magic: DEFAULT_UNCONNECTED_MAGIC,
+                cookie: reply1.cookie,
+                client_proof: reply1.cookie.is_some(),
+                server_addr: self.server,
+                mtu: reply1.mtu,
+                client_guid: guid,
+            },
+        ))
+        .await;
+        self.recv_packet(Duration::from_secs(2)).await
+    }
+}
+
+/// Encode `pkt` into a single-frame data datagram.
+pub fn packet_datagram(sequence: u32, pkt: RaknetPacket) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    pkt.encode(&mut buf).unwrap();
+    data_datagram(sequence, buf.freeze())
+}
+
+/// Encode a VALID data datagram carrying one unreliable frame of `payload`.
+pub fn data_datagram(sequence: u32, payload: Bytes) -> Vec<u8> {
+    let dgram = Datagram {
+        header: DatagramHeader {
+            flags: DatagramFlags::VALID,
+            sequence: Sequence24::new(sequence),
+        },
+        payload: DatagramPayload::EncapsulatedPackets(vec![EncapsulatedPacket {
+            header: EncapsulatedPacketHeader {
+                reliability: Reliability::Unreliable,
+                is_split: false,
+                needs_bas: false,
+            },
+            bit_length: (payload.len() as u16) << 3,
+            reliable_index: None,
+            sequence_index: None,
+            ordering_index: None,
+            ordering_channel: None,
+            split: None,
+            payload,
+        }]),
+    };
+    let mut buf = BytesMut::new();
+    dgram.encode(&mut buf).unwrap();
+    buf.to_vec()
+}
@@ -0,0 +1,210 @@
+//! Byte-exact packets of one vanilla RakNet handshake, as a Bedrock client
+//! and server put them on the wire.
+//!
+//! Client 192.168.1.20:50214 joins server 192.168.1.10:19132 with an MTU of
+//! 1492. Each packet is spelled out field by field so a diff against a real
+//! capture points straight at the field that moved.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+pub const CLIENT: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 20), 50214));
+pub const SERVER: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 19132));
+/// The client seen over IPv6, for `open_connection_reply_2_v6`.
+pub const CLIENT_V6: SocketAddr = SocketAddr::V6(SocketAddrV6::new(
+    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x20),
+    50214,
+    0,
+    0,
+));
+pub const CLIENT_GUID: u64 = 0x4d2a_91c7_03e5_b86f;
+pub const SERVER_GUID: u64 = 0x1c7e_5f02_9b3d_a481;
+pub const MTU: u16 = 1492;
+
+pub const PING_TIME: u64 = 0x2b67;
+/// Client clock when it sent `ConnectionRequest`.
+pub const REQUEST_TIME: u64 = 0x05dc;
+/// Server clock when it sent `ConnectionRequestAccepted`.
+pub const ACCEPTED_TIME: u64 = 0x0001_2a3b;
+/// Client clock when it sent `NewIncomingConnection`.
+pub const NEW_INCOMING_TIME: u64 = 0x0611;
+
+pub const MOTD: &[u8] =
+    b"MCPE;Dedicated Server;671;1.21.2;0;10;2053182944926606465;Bedrock level;Survival;1;19132;19133;";
+
+const MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+const CLIENT_GUID_BYTES: [u8; 8] = [0x4d, 0x2a, 0x91, 0xc7, 0x03, 0xe5, 0xb8, 0x6f];
+const SERVER_GUID_BYTES: [u8; 8] = [0x1c, 0x7e, 0x5f, 0x02, 0x9b, 0x3d, 0xa4, 0x81];
+/// Address family 4, then each octet inverted, then the port big-endian.
+const CLIENT_ADDR: [u8; 7] = [0x04, 0x3f, 0x57, 0xfe, 0xeb, 0xc4, 0x26];
+const SERVER_ADDR: [u8; 7] = [0x04, 0x3f, 0x57, 0xfe, 0xf5, 0x4a, 0xbc];
+const LOOPBACK_ADDR: [u8; 7] = [0x04, 0x80, 0xff, 0xff, 0xfe, 0x00, 0x00];
+/// 255.255.255.255:0, vanilla's unassigned system address.
+const UNASSIGNED_ADDR: [u8; 7] = [0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+/// The ten internal addresses both sides send: loopback, the host's LAN
+/// address, then unassigned.
+pub fn system_addresses(lan: SocketAddr) -> [SocketAddr; 10] {
+    let unassigned = SocketAddr::from((Ipv4Addr::BROADCAST, 0));
+    let mut addrs = [unassigned; 10];
+    addrs[0] = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    addrs[1] = lan;
+    addrs
+}
+
+fn system_addresses_bytes(lan: [u8; 7]) -> Vec<u8> {
+    let mut out = [LOOPBACK_ADDR, lan].concat();
+    for _ in 0..8 {
+        out.extend_from_slice(&UNASSIGNED_ADDR);
+    }
+    out
+}
+
+/// 0x01 UnconnectedPing, client to server.
+pub fn unconnected_ping() -> Vec<u8> {
+    [
+        &[0x01][..],
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2b, 0x67], // ping time
+        &MAGIC,
+        &CLIENT_GUID_BYTES,
+    ]
+    .concat()
+}
+
+/// 0x1c UnconnectedPong, server to client.
+pub fn unconnected_pong() -> Vec<u8> {
+    [
+        &[0x1c][..],
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2b, 0x67], // echoed ping time
+        &SERVER_GUID_BYTES,
+        &MAGIC,
+        &[0x00, 0x5f], // MOTD length
+        MOTD,
+    ]
+    .concat()
+}
+
+/// 0x05 OpenConnectionRequest1, client to server, zero-padded so the whole
+/// IPv4/UDP packet is `MTU` bytes.
+pub fn open_connection_request_1() -> Vec<u8> {
+    let mut out = [
+        &[0x05][..],
+        &MAGIC,
+        &[0x0b], // protocol version 11
+    ]
+    .concat();
+    out.resize(MTU as usize - 20 - 8, 0x00);
+    out
+}
+
+/// 0x06 OpenConnectionReply1, server to client.
+pub fn open_connection_reply_1() -> Vec<u8> {
+    [
+        &[0x06][..],
+        &MAGIC,
+        &SERVER_GUID_BYTES,
+        &[0x00],       // no security
+        &[0x05, 0xd4], // MTU
+    ]
+    .concat()
+}
+
+/// 0x07 OpenConnectionRequest2, client to server.
+pub fn open_connection_request_2() -> Vec<u8> {
+    [
+        &[0x07][..],
+        &MAGIC,
+        &SERVER_ADDR,
+        &[0x05, 0xd4], // MTU
+        &CLIENT_GUID_BYTES,
+    ]
+    .concat()
+}
+
+/// 0x08 OpenConnectionReply2, server to client.
+pub fn open_connection_reply_2() -> Vec<u8> {
+    [
+        &[0x08][..],
+        &MAGIC,
+        &SERVER_GUID_BYTES,
+        &CLIENT_ADDR,
+        &[0x05, 0xd4], // MTU
+        &[0x00],       // no encryption
+    ]
+    .concat()
+}
+
+/// 0x08 OpenConnectionReply2 to a client on IPv6. The address is a Windows
+/// `sockaddr_in6`, so the family is `AF_INET6` = 23, little-endian.
+pub fn open_connection_reply_2_v6() -> Vec<u8> {
+    [
+        &[0x08][..],
+        &MAGIC,
+        &SERVER_GUID_BYTES,
+        &[0x06],
+        &[0x17, 0x00],             // family
+        &[0xc4, 0x26],             // port
+        &[0x00, 0x00, 0x00, 0x00], // flow info
+        &[
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x20,
+        ],
+        &[0x00, 0x00, 0x00, 0x00], // scope id
+        &[0x05, 0xd4],             // MTU
+        &[0x00],                   // no encryption
+    ]
+    .concat()
+}
+
+/// Datagram 0 from the client: one `Reliable` frame (reliable index 0)
+/// carrying 0x09 ConnectionRequest.
+pub fn connection_request() -> Vec<u8> {
+    [
+        &[0x84, 0x00, 0x00, 0x00][..], // valid | B&AS, sequence 0 (LE)
+        &[0x40, 0x00, 0x90],           // Reliable, 144 bits
+        &[0x00, 0x00, 0x00],           // reliable index 0
+        &[0x09],
+        &CLIENT_GUID_BYTES,
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xdc], // request time
+        &[0x00],                                           // no security
+    ]
+    .concat()
+}
+
+/// Datagram 0 from the server: one `ReliableOrdered` frame on channel 0
+/// carrying 0x10 ConnectionRequestAccepted.
+pub fn connection_request_accepted() -> Vec<u8> {
+    [
+        &[0x84, 0x00, 0x00, 0x00][..], // valid | B&AS, sequence 0 (LE)
+        &[0x60, 0x03, 0x00],           // ReliableOrdered, 768 bits
+        &[0x00, 0x00, 0x00],           // reliable index 0
+        &[0x00, 0x00, 0x00, 0x00],     // ordering index 0, channel 0
+        &[0x10],
+        &CLIENT_ADDR,
+        &[0x00, 0x00], // system index
+        &system_addresses_bytes(SERVER_ADDR),
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xdc], // echoed request time
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x2a, 0x3b], // accepted time
+    ]
+    .concat()
+}
+
+/// Datagram 1 from the client: one `ReliableOrdered` frame on channel 0
+/// carrying 0x13 NewIncomingConnection.
+pub fn new_incoming_connection() -> Vec<u8> {
+    [
+        &[0x84, 0x01, 0x00, 0x00][..], // valid | B&AS, sequence 1 (LE)
+        &[0x60, 0x02, 0xf0],           // ReliableOrdered, 752 bits
+        &[0x01, 0x00, 0x00],           // reliable index 1
+        &[0x00, 0x00, 0x00, 0x00],     // ordering index 0, channel 0
+        &[0x13],
+        &SERVER_ADDR,
+        &system_addresses_bytes(CLIENT_ADDR),
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x2a, 0x3b], // echoed accepted time
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x11], // client time
+    ]
+    .concat()
+}
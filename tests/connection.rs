@@ -0,0 +1,186 @@
+//! Connecting, exchanging data and configuring sessions end to end over
+//! the in-memory network.
+
+mod pair;
+
+use std::io;
+use std::time::Duration;
+
+use pair::{Pair, SERVER, WAIT, number_of, numbered, recv};
+use tokio::time::{Instant, timeout};
+use tokio_raknet::RaknetError;
+use tokio_raknet::error::ConfigError;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::session::manager::SessionConfig;
+use tokio_raknet::transport::memory::{MemoryNetwork, SimulatedLink};
+use tokio_raknet::transport::{
+    RaknetListener, RaknetListenerConfig, RaknetStream, RaknetStreamConfig,
+};
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test(start_paused = true)]
+async fn handshake_and_exchange_in_memory() {
+    let mut pair = Pair::connect().await;
+    assert_eq!(pair.server.peer_addr(), pair.client.local_addr());
+    assert_eq!(pair.client.peer_addr(), SERVER.parse().unwrap());
+
+    pair.client.send(&b"\xfeping"[..]).await.unwrap();
+    assert_eq!(&recv(&mut pair.server).await.buffer[..], b"\xfeping");
+
+    pair.server.send(&b"\xfepong"[..]).await.unwrap();
+    assert_eq!(&recv(&mut pair.client).await.buffer[..], b"\xfepong");
+    assert_eq!(pair.listener.stats().sessions, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn bulk_transfer_arrives_in_order() {
+    let mut pair = Pair::connect().await;
+    let count = 500;
+
+    let client = pair.client;
+    let sender = tokio::spawn(async move {
+        for i in 0..count {
+            client.send(numbered(i, 1000)).await.unwrap();
+        }
+        // Larger than the MTU, so it has to be split and reassembled.
+        client.send(numbered(count, 20_000)).await.unwrap();
+        client
+    });
+
+    for i in 0..count {
+        let msg = recv(&mut pair.server).await;
+        assert_eq!(number_of(&msg), i);
+        assert_eq!(msg.buffer.len(), 1000);
+    }
+    let big = recv(&mut pair.server).await;
+    assert_eq!(number_of(&big), count);
+    assert_eq!(big.buffer.len(), 20_000);
+    assert!(big.buffer[5..].iter().all(|&b| b == count as u8));
+
+    sender.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn shared_session_config_applies_to_both_ends() {
+    let session = SessionConfig {
+        session_stale: Duration::from_millis(500),
+        session_timeout: Duration::from_secs(1),
+        keepalive_interval: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let listener_config = RaknetListenerConfig {
+        session_config: Some(session.clone()),
+        ..Default::default()
+    };
+    let client_config = RaknetStreamConfig {
+        session_config: Some(session),
+        ..Default::default()
+    };
+    let mut pair = Pair::connect_with(SimulatedLink::new(), listener_config, client_config).await;
+    pair.set_link(SimulatedLink::new().loss(1.0));
+
+    let start = Instant::now();
+    for stream in [&mut pair.client, &mut pair.server] {
+        assert!(matches!(
+            timeout(WAIT, stream.recv()).await.unwrap(),
+            Some(Err(RaknetError::Disconnected(DisconnectReason::TimedOut)))
+        ));
+    }
+    // Well before the 10s default.
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn unworkable_session_config_fails_bind_and_connect() {
+    let session = SessionConfig {
+        session_stale: Duration::from_secs(20),
+        ..Default::default()
+    };
+    let net = MemoryNetwork::new();
+
+    let config = RaknetListenerConfig {
+        session_config: Some(session.clone()),
+        ..Default::default()
+    };
+    let err = RaknetListener::with_socket(net.bind(SERVER.parse().unwrap()).unwrap(), config)
+        .err()
+        .expect("bind fails");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    // The flat fields are checked the same way.
+    let config = RaknetListenerConfig {
+        session_stale: Duration::from_secs(20),
+        ..Default::default()
+    };
+    assert!(RaknetListener::with_socket(net.bind_any().unwrap(), config).is_err());
+
+    let config = RaknetStreamConfig {
+        session_config: Some(session),
+        ..Default::default()
+    };
+    // Refused before the handshake starts.
+    let res =
+        RaknetStream::connect_on(net.bind_any().unwrap(), SERVER.parse().unwrap(), config).await;
+    assert!(matches!(
+        res,
+        Err(RaknetError::InvalidConfig(
+            ConfigError::StaleNotBeforeTimeout { .. }
+        ))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn streams_report_the_negotiated_mtu() {
+    let listener_config = RaknetListenerConfig {
+        max_mtu: 1000,
+        ..Default::default()
+    };
+    let pair = Pair::connect_with(
+        SimulatedLink::new(),
+        listener_config,
+        RaknetStreamConfig::default(),
+    )
+    .await;
+    assert_ne!(RaknetStreamConfig::default().mtu, 1000);
+    assert_eq!(pair.client.mtu(), 1000);
+    assert_eq!(pair.server.mtu(), 1000);
+    assert_eq!(pair.client.local_addr(), pair.server.peer_addr());
+    assert_eq!(pair.server.local_addr(), pair.client.peer_addr());
+}
+
+#[tokio::test(start_paused = true)]
+async fn mtu_discovery_falls_back_to_what_the_path_carries() {
+    // 1200 bytes with IPv4 and UDP headers; the 1400 probe is lost.
+    let link = SimulatedLink::new().max_datagram(1200 - 28);
+    let mut pair = Pair::connect_with(
+        link,
+        RaknetListenerConfig::default(),
+        RaknetStreamConfig::default(),
+    )
+    .await;
+    assert_eq!(pair.client.mtu(), 1200);
+    assert_eq!(pair.server.mtu(), 1200);
+
+    // Every datagram of the session fits the path too.
+    pair.client.send(numbered(0, 5000)).await.unwrap();
+    assert_eq!(recv(&mut pair.server).await.buffer.len(), 5000);
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelling_before_the_handshake_fails_connect() {
+    let net = MemoryNetwork::new();
+    let token = CancellationToken::new();
+    token.cancel();
+    let config = RaknetStreamConfig {
+        shutdown: Some(token),
+        ..Default::default()
+    };
+    // Nobody is listening, so only the token can end this.
+    let res =
+        RaknetStream::connect_on(net.bind_any().unwrap(), SERVER.parse().unwrap(), config).await;
+    assert!(matches!(res, Err(RaknetError::Shutdown)));
+}
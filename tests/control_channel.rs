@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+#[tokio::test]
+async fn disconnect_is_not_delayed_by_saturated_data_channel() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .expect("failed to bind listener");
+    let local_addr = listener.local_addr();
+
+    let client = tokio::spawn(async move {
+        let mut client = RaknetStream::connect(local_addr)
+            .await
+            .expect("failed to connect to server");
+        // Drain until the server kicks us.
+        while let Ok(Some(Ok(_))) = timeout(Duration::from_secs(5), client.recv()).await {}
+    });
+
+    let conn = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("timeout waiting for connection")
+        .expect("listener closed unexpectedly");
+    let peer = conn.peer_addr();
+
+    // Keep the data channel full for the whole test.
+    let flood = tokio::spawn(async move {
+        let payload = bytes::Bytes::from(vec![0xfe; 512]);
+        for _ in 0..100_000 {
+            if conn.send(payload.clone()).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(listener.peer_stats(peer).is_some());
+
+    let started = Instant::now();
+    listener
+        .disconnect(peer, DisconnectReason::Disconnected)
+        .await
+        .expect("muxer gone");
+    while listener.peer_stats(peer).is_some() {
+        assert!(
+            started.elapsed() < Duration::from_millis(100),
+            "disconnect stuck behind data"
+        );
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    flood.abort();
+    let _ = client.await;
+}
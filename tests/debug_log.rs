@@ -0,0 +1,96 @@
+//! The per-session debug log, end to end over the in-memory network.
+
+mod pair;
+
+use std::time::Duration;
+
+use pair::{Pair, number_of, numbered, recv, settle};
+use tokio::time::sleep;
+use tokio_raknet::session::manager::ConnectionState;
+use tokio_raknet::transport::memory::SimulatedLink;
+use tokio_raknet::transport::{RaknetListenerConfig, RaknetStreamConfig};
+
+#[tokio::test(start_paused = true)]
+async fn debug_log_reconstructs_a_loss_and_its_retransmission() {
+    use tokio_raknet::session::debug_log::DebugEventKind as Ev;
+
+    let listener_config = RaknetListenerConfig::default().debug_log_capacity(512);
+    let client_config = RaknetStreamConfig::default().debug_log_capacity(512);
+    let mut pair = Pair::connect_with(SimulatedLink::new(), listener_config, client_config).await;
+
+    // The first message is lost; the second one exposes the gap.
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    settle().await;
+    pair.uplink(SimulatedLink::new());
+    pair.client.send(numbered(1, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+    assert_eq!(number_of(&recv(&mut pair.server).await), 1);
+    // Let the ACK for the retransmission make it back.
+    sleep(Duration::from_millis(200)).await;
+
+    let client: Vec<Ev> = pair
+        .client
+        .dump_debug_log()
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    let (resent, again) = client
+        .iter()
+        .enumerate()
+        .find_map(|(i, e)| match *e {
+            Ev::DatagramResent { sequence, .. } => Some((i, sequence)),
+            _ => None,
+        })
+        .expect("a retransmission was logged");
+    let at = |log: &[Ev], pred: &dyn Fn(&Ev) -> bool| {
+        log.iter()
+            .position(pred)
+            .unwrap_or_else(|| panic!("missing event in {log:#?}"))
+    };
+
+    // Client: sent, reported missing, sent again under a new sequence
+    // number, finally acknowledged.
+    let nak = at(&client, &|e| matches!(e, Ev::NakReceived { .. }));
+    let Ev::NakReceived { start: lost, .. } = client[nak] else {
+        unreachable!()
+    };
+    assert_ne!(again, lost);
+    let covers = |seq: u32| move |start: u32, end: u32| (start..=end).contains(&seq);
+    let sent = at(
+        &client,
+        &|e| matches!(*e, Ev::DatagramSent { sequence, .. } if sequence == lost),
+    );
+    let ack = at(
+        &client,
+        &|e| matches!(*e, Ev::AckReceived { start, end } if covers(again)(start, end)),
+    );
+    assert!(sent < nak && nak < resent && resent < ack, "{client:#?}");
+
+    // Server: the later message waits behind the gap until it is filled.
+    let server: Vec<Ev> = pair
+        .listener
+        .dump_debug_log(pair.server.peer_addr())
+        .expect("server keeps a log")
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    let nak_sent = at(
+        &server,
+        &|e| matches!(*e, Ev::NakSent { start, end } if covers(lost)(start, end)),
+    );
+    let stalled = at(&server, &|e| matches!(e, Ev::OrderingStalled { .. }));
+    let received = at(
+        &server,
+        &|e| matches!(*e, Ev::DatagramReceived { sequence, .. } if sequence == again),
+    );
+    let resumed = at(&server, &|e| matches!(e, Ev::OrderingResumed));
+    assert!(stalled < nak_sent && nak_sent < received, "{server:#?}");
+    assert!(received <= resumed, "{server:#?}");
+
+    // The handshake shows up as state changes.
+    assert!(client.contains(&Ev::StateChanged {
+        from: ConnectionState::OnlineHandshake,
+        to: ConnectionState::Connected,
+    }));
+}
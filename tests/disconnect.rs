@@ -0,0 +1,396 @@
+//! How connections end, gracefully or not, end to end over the in-memory
+//! network: disconnects, shutdowns, timeouts and dead sockets.
+
+mod pair;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use pair::{Pair, SERVER, WAIT, number_of, numbered, recv, recv_error, settle};
+use tokio::time::{Instant, sleep, timeout};
+use tokio_raknet::RaknetError;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::session::manager::{ConnectionState, SessionConfig};
+use tokio_raknet::transport::memory::SimulatedLink;
+use tokio_raknet::transport::{
+    DatagramSocket, RaknetListener, RaknetListenerConfig, RaknetStream, RaknetStreamConfig,
+};
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test(start_paused = true)]
+async fn silent_peer_times_out_on_both_sides() {
+    let mut pair = Pair::connect().await;
+    pair.set_link(SimulatedLink::new().loss(1.0));
+
+    let client_end = timeout(WAIT, pair.client.recv()).await.unwrap();
+    assert!(matches!(
+        client_end,
+        Some(Err(RaknetError::Disconnected(DisconnectReason::TimedOut)))
+    ));
+    let server_end = timeout(WAIT, pair.server.recv()).await.unwrap();
+    assert!(matches!(
+        server_end,
+        Some(Err(RaknetError::Disconnected(DisconnectReason::TimedOut)))
+    ));
+    assert_eq!(pair.listener.stats().sessions, 0);
+}
+
+/// A socket whose receive side is permanently broken.
+struct FailingSocket(SocketAddr);
+
+impl DatagramSocket for FailingSocket {
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::Error::from(io::ErrorKind::NetworkDown))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.0)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn dead_socket_shuts_listener_down() {
+    let socket = FailingSocket(SERVER.parse().unwrap());
+    let mut listener =
+        RaknetListener::with_socket(socket, RaknetListenerConfig::default()).unwrap();
+
+    assert!(timeout(WAIT, listener.accept()).await.unwrap().is_none());
+    assert!(matches!(listener.take_error(), Some(RaknetError::Io(_))));
+    let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    assert!(matches!(
+        listener
+            .disconnect(peer, DisconnectReason::ShuttingDown)
+            .await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+    assert!(matches!(
+        listener.flush(peer).await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelling_the_listener_disconnects_sessions_and_ends_accept() {
+    let token = CancellationToken::new();
+    let listener_config = RaknetListenerConfig {
+        shutdown: Some(token.clone()),
+        ..Default::default()
+    };
+    let mut pair = Pair::connect_with(
+        SimulatedLink::new(),
+        listener_config,
+        RaknetStreamConfig::default(),
+    )
+    .await;
+
+    for i in 0..10 {
+        pair.client.send(numbered(i, 2000)).await.unwrap();
+    }
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+    token.cancel();
+
+    // A pending `accept` resolves instead of hanging.
+    assert!(
+        timeout(WAIT, pair.listener.accept())
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(matches!(
+        pair.listener.take_error(),
+        Some(RaknetError::Shutdown)
+    ));
+    assert!(matches!(
+        recv_error(&mut pair.server).await,
+        RaknetError::Shutdown
+    ));
+    assert!(pair.server.recv_msg().await.is_none());
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+    ));
+
+    // The muxer task is gone.
+    assert!(matches!(
+        pair.server.send("late").await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+    assert!(matches!(
+        pair.listener
+            .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+            .await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn listener_shutdown_disconnects_sessions_and_stops_the_muxer() {
+    let mut pair = Pair::connect().await;
+    let mut other = RaknetStream::connect_on(
+        pair.net.bind_any().unwrap(),
+        SERVER.parse().unwrap(),
+        RaknetStreamConfig::default(),
+    )
+    .await
+    .unwrap();
+    let mut other_server = timeout(WAIT, pair.listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let start = Instant::now();
+    timeout(WAIT, pair.listener.shutdown()).await.unwrap();
+    // Both peers ACKed their notification, so nobody waited out the
+    // shutdown timeout.
+    assert!(
+        start.elapsed() < RaknetListenerConfig::default().shutdown_timeout,
+        "{:?}",
+        start.elapsed()
+    );
+
+    for client in [&mut pair.client, &mut other] {
+        assert!(matches!(
+            recv_error(client).await,
+            RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+        ));
+    }
+    for server in [&mut pair.server, &mut other_server] {
+        assert!(matches!(
+            recv_error(server).await,
+            RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+        ));
+        assert!(matches!(
+            server.send("late").await,
+            Err(RaknetError::ConnectionClosed)
+        ));
+    }
+
+    // The socket is closed, so its address is free again.
+    assert!(pair.net.bind(SERVER.parse().unwrap()).is_ok());
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelling_a_client_notifies_the_server() {
+    let token = CancellationToken::new();
+    let client_config = RaknetStreamConfig {
+        shutdown: Some(token.clone()),
+        ..Default::default()
+    };
+    let mut pair = Pair::connect_with(
+        SimulatedLink::new(),
+        RaknetListenerConfig::default(),
+        client_config,
+    )
+    .await;
+
+    for i in 0..10 {
+        pair.server.send(numbered(i, 2000)).await.unwrap();
+    }
+    assert_eq!(number_of(&recv(&mut pair.client).await), 0);
+    token.cancel();
+
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Shutdown
+    ));
+    assert!(pair.client.recv_msg().await.is_none());
+    assert!(matches!(
+        recv_error(&mut pair.server).await,
+        RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+    ));
+    assert!(matches!(
+        pair.client.send("late").await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn graceful_disconnect_delivers_queued_data_first() {
+    let mut pair = Pair::connect().await;
+    pair.set_link(
+        SimulatedLink::new()
+            .loss(0.2)
+            .latency(Duration::from_millis(10)),
+    );
+    let sender = pair.client.sender();
+
+    for i in 0..20 {
+        pair.client.send(numbered(i, 500)).await.unwrap();
+    }
+    timeout(WAIT, pair.client.disconnect(DisconnectReason::Disconnected))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        sender.send("late").await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+
+    for i in 0..20 {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+    assert!(matches!(
+        recv_error(&mut pair.server).await,
+        RaknetError::Disconnected(DisconnectReason::Disconnected)
+    ));
+    settle().await;
+    assert_eq!(pair.listener.stats().sessions, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn graceful_disconnect_from_the_server_side() {
+    let mut pair = Pair::connect().await;
+    let sender = pair.server.sender();
+
+    pair.server.send(numbered(0, 3000)).await.unwrap();
+    timeout(WAIT, pair.server.disconnect(DisconnectReason::ShuttingDown))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        sender.send("late").await,
+        Err(RaknetError::ConnectionClosed)
+    ));
+
+    assert_eq!(number_of(&recv(&mut pair.client).await), 0);
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Disconnected(DisconnectReason::ShuttingDown)
+    ));
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(pair.listener.stats().sessions, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn graceful_disconnect_gives_up_on_a_silent_peer() {
+    let pair = Pair::connect().await;
+    pair.set_link(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(0, 100)).await.unwrap();
+
+    let start = Instant::now();
+    timeout(WAIT, pair.client.disconnect(DisconnectReason::Disconnected))
+        .await
+        .unwrap()
+        .unwrap();
+    let waited = start.elapsed();
+    let limit = RaknetStreamConfig::default().shutdown_timeout;
+    assert!(waited <= limit + Duration::from_millis(100), "{waited:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn closed_resolves_without_anyone_receiving() {
+    let pair = Pair::connect().await;
+    let sender = pair.client.sender();
+    let watcher = tokio::spawn(async move { sender.closed().await });
+    settle().await;
+    assert!(!watcher.is_finished());
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::ShuttingDown)
+        .await
+        .unwrap();
+    assert!(matches!(
+        timeout(WAIT, watcher).await.unwrap().unwrap(),
+        DisconnectReason::ShuttingDown
+    ));
+    assert!(matches!(
+        timeout(WAIT, pair.server.closed()).await.unwrap(),
+        DisconnectReason::ShuttingDown
+    ));
+    // Still resolves, with the same reason, once it has happened.
+    assert!(matches!(
+        pair.client.closed().await,
+        DisconnectReason::ShuttingDown
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn closed_reports_a_timed_out_peer() {
+    let pair = Pair::connect().await;
+    pair.set_link(SimulatedLink::new().loss(1.0));
+    assert!(matches!(
+        timeout(WAIT, pair.server.closed()).await.unwrap(),
+        DisconnectReason::TimedOut
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn state_follows_a_peer_going_silent() {
+    let pair = Pair::connect().await;
+    assert_eq!(pair.server.state(), ConnectionState::Connected);
+    assert_eq!(pair.client.state(), ConnectionState::Connected);
+
+    pair.set_link(SimulatedLink::new().loss(1.0));
+    let config = SessionConfig::default();
+    sleep(config.session_stale + Duration::from_millis(100)).await;
+    assert_eq!(pair.server.state(), ConnectionState::Stale);
+    assert!(pair.server.is_connected());
+
+    timeout(WAIT, pair.server.closed()).await.unwrap();
+    assert_eq!(pair.server.state(), ConnectionState::Closed);
+    assert!(!pair.server.is_connected());
+}
+
+#[tokio::test(start_paused = true)]
+async fn listener_acks_a_disconnect_before_dropping_the_session() {
+    let token = CancellationToken::new();
+    let client_config = RaknetStreamConfig {
+        shutdown: Some(token.clone()),
+        shutdown_timeout: Duration::from_secs(5),
+        ..Default::default()
+    };
+    let mut pair =
+        Pair::connect_with(SimulatedLink::new(), Default::default(), client_config).await;
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    recv(&mut pair.server).await;
+    let resent = pair.client.stats().datagrams_resent;
+
+    // The client waits for its notification to be ACKed. The listener
+    // drops the session on delivering it, so the ACK has to go out first
+    // or the client keeps resending until its deadline.
+    let start = Instant::now();
+    token.cancel();
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Shutdown
+    ));
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(pair.client.stats().datagrams_resent, resent);
+}
+
+#[tokio::test(start_paused = true)]
+async fn client_acks_a_disconnect_before_dropping_the_session() {
+    let token = CancellationToken::new();
+    let listener_config = RaknetListenerConfig {
+        shutdown: Some(token.clone()),
+        shutdown_timeout: Duration::from_secs(5),
+        ..Default::default()
+    };
+    let mut pair =
+        Pair::connect_with(SimulatedLink::new(), listener_config, Default::default()).await;
+    pair.server.send(numbered(0, 100)).await.unwrap();
+    recv(&mut pair.client).await;
+    let resent = pair.server.stats().datagrams_resent;
+
+    let start = Instant::now();
+    token.cancel();
+    assert!(matches!(
+        timeout(WAIT, pair.server.closed()).await.unwrap(),
+        DisconnectReason::ShuttingDown
+    ));
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(pair.server.stats().datagrams_resent, resent);
+}
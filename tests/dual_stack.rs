@@ -0,0 +1,87 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio::time::timeout;
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::types::RaknetTime;
+use tokio_raknet::transport::BedrockMotd;
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+/// Whether this host has IPv6 loopback; containers and CI runners often
+/// don't, and these tests are skipped there.
+fn ipv6_loopback() -> bool {
+    let available = std::net::UdpSocket::bind("[::1]:0").is_ok();
+    if !available {
+        eprintln!("skipping: no IPv6 loopback");
+    }
+    available
+}
+
+#[tokio::test]
+async fn one_listener_takes_ipv4_and_ipv6_clients() {
+    if !ipv6_loopback() {
+        return;
+    }
+    let mut listener = RaknetListener::bind_dual_stack(0).await.unwrap();
+    let port = listener.local_addr().port();
+    assert_eq!(
+        listener.local_addrs(),
+        [
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0u16; 8], port)),
+        ]
+    );
+
+    for server in [
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port)),
+    ] {
+        let local = SocketAddr::new(server.ip(), 0);
+        let socket = tokio::net::UdpSocket::bind(local).await.unwrap();
+        let (client, conn) = tokio::join!(
+            RaknetStream::connect_with_socket(socket, server, 1400),
+            timeout(Duration::from_secs(5), listener.accept_with_info()),
+        );
+        let client = client.expect("connect failed");
+        let conn = conn.expect("no connection").expect("listener closed");
+
+        // IPv4 clients aren't reported as v4-mapped IPv6 addresses.
+        assert_eq!(conn.stream.peer_addr(), client.local_addr());
+
+        client.send(vec![0x86, 1]).await.unwrap();
+        let mut stream = conn.stream;
+        let msg = timeout(Duration::from_secs(5), stream.recv())
+            .await
+            .expect("message lost")
+            .expect("connection closed")
+            .expect("connection errored");
+        assert_eq!(&msg[..], &[0x86, 1]);
+    }
+}
+
+#[tokio::test]
+async fn pongs_advertise_the_shared_port_for_both_families() {
+    if !ipv6_loopback() {
+        return;
+    }
+    let listener = RaknetListener::bind_dual_stack(0).await.unwrap();
+    let port = listener.local_addr().port();
+
+    let peer = RawPeer::new(SocketAddr::from(([127, 0, 0, 1], port))).await;
+    peer.send_packet(RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(1),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    }))
+    .await;
+    let Some(RaknetPacket::UnconnectedPong(pong)) =
+        peer.recv_packet(Duration::from_millis(500)).await
+    else {
+        panic!("no pong");
+    };
+    let motd = BedrockMotd::parse(&pong.advertisement.0.unwrap()).unwrap();
+    assert_eq!((motd.port_v4, motd.port_v6), (Some(port), Some(port)));
+}
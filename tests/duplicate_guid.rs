@@ -0,0 +1,79 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::packet::RaknetPacket;
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+
+const GUID: u64 = 0xdead_beef;
+
+#[tokio::test]
+async fn second_address_with_same_guid_gets_already_connected() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let first = RawPeer::new(listener.local_addr()).await;
+    let second = RawPeer::new(listener.local_addr()).await;
+
+    first.offline_handshake(1400, GUID).await;
+    assert!(matches!(
+        second.open_connection(1400, GUID).await,
+        Some(RaknetPacket::AlreadyConnected(_))
+    ));
+
+    assert_eq!(listener.stats().sessions, 1);
+    assert!(
+        listener
+            .peer_stats(first.socket.local_addr().unwrap())
+            .is_some()
+    );
+
+    // A different GUID from the second address is unaffected.
+    second.offline_handshake(1400, GUID + 1).await;
+    assert_eq!(listener.stats().sessions, 2);
+}
+
+#[tokio::test]
+async fn same_address_may_repeat_the_handshake() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    peer.offline_handshake(1400, GUID).await;
+    peer.offline_handshake(1400, GUID).await;
+    assert_eq!(listener.stats().sessions, 1);
+}
+
+#[tokio::test]
+async fn migration_mode_moves_the_session_to_the_new_address() {
+    let config = RaknetListenerConfig {
+        connection_migration: true,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let first = RawPeer::new(listener.local_addr()).await;
+    let second = RawPeer::new(listener.local_addr()).await;
+
+    first.offline_handshake(1400, GUID).await;
+    second.offline_handshake(1400, GUID).await;
+
+    assert!(
+        listener
+            .peer_stats(first.socket.local_addr().unwrap())
+            .is_none()
+    );
+    assert!(
+        listener
+            .peer_stats(second.socket.local_addr().unwrap())
+            .is_some()
+    );
+    assert_eq!(listener.stats().sessions, 1);
+
+    // The old address is told it was replaced.
+    assert!(first.recv_raw(Duration::from_secs(1)).await.is_some());
+}
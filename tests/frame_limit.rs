@@ -0,0 +1,47 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::DatagramFlags;
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+
+/// VALID datagram packed with `frames` empty unreliable frames, 3 bytes each.
+fn empty_frames(frames: usize) -> Vec<u8> {
+    let mut buf = vec![DatagramFlags::VALID.bits(), 0, 0, 0];
+    for _ in 0..frames {
+        buf.extend_from_slice(&[0x00, 0x00, 0x00]);
+    }
+    buf
+}
+
+#[tokio::test]
+async fn datagram_over_frame_limit_closes_session_as_bad_packet() {
+    let config = RaknetListenerConfig {
+        max_frames_per_datagram: 64,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.offline_handshake(1400, 0x51).await;
+    let local = peer.socket.local_addr().unwrap();
+    assert!(listener.peer_stats(local).is_some());
+
+    // At the limit is fine.
+    peer.send_raw(&empty_frames(64)).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(listener.peer_stats(local).is_some());
+
+    let started = Instant::now();
+    peer.send_raw(&empty_frames(400)).await;
+    while listener.peer_stats(local).is_some() {
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "session survived a datagram over the frame limit"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
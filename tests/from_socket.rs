@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout};
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::types::RaknetTime;
+use tokio_raknet::{RaknetError, RaknetListener, RaknetStream};
+
+#[tokio::test]
+async fn listener_and_client_run_on_sockets_bound_by_the_caller() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    assert_ne!(server_addr.port(), 0);
+    let mut listener = RaknetListener::from_socket(socket, 1400).unwrap();
+    assert_eq!(listener.local_addr(), server_addr);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    let (client, conn) = tokio::join!(
+        RaknetStream::connect_with_socket(socket, server_addr, 1200),
+        timeout(Duration::from_secs(5), listener.accept()),
+    );
+    let client = client.expect("connect failed");
+    let mut conn = conn.expect("no connection").expect("listener closed");
+
+    assert_eq!(client.local_addr(), client_addr);
+    assert_eq!(client.peer_addr(), server_addr);
+    assert_eq!(conn.peer_addr(), client_addr);
+    assert!(client.mtu() <= 1200, "{}", client.mtu());
+
+    client.send(vec![0x86, 1, 2, 3]).await.unwrap();
+    let msg = timeout(Duration::from_secs(5), conn.recv())
+        .await
+        .expect("message lost")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 1, 2, 3]);
+}
+
+#[tokio::test]
+async fn a_callers_socket_only_talks_to_the_server() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let server_addr = listener.local_addr();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = socket.local_addr().unwrap();
+
+    // Someone else keeps pinging the client and sending it junk that starts
+    // like an `OpenConnectionReply1` for as long as the handshake runs.
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut ping = BytesMut::new();
+    RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(1),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    })
+    .encode(&mut ping)
+    .unwrap();
+    let noise = async {
+        for _ in 0..20 {
+            stranger.send_to(&ping, client_addr).await.unwrap();
+            stranger.send_to(b"\x06punch", client_addr).await.unwrap();
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    let (client, conn, ()) = tokio::join!(
+        RaknetStream::connect_with_socket(socket, server_addr, 1400),
+        timeout(Duration::from_secs(5), listener.accept()),
+        noise,
+    );
+    let client = client.expect("connect failed");
+    let mut conn = conn.expect("no connection").expect("listener closed");
+    assert_eq!(client.local_addr(), client_addr);
+
+    client.send(vec![0x86, 9]).await.unwrap();
+    let msg = timeout(Duration::from_secs(5), conn.recv())
+        .await
+        .expect("message lost")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 9]);
+
+    let mut buf = [0u8; 64];
+    assert!(
+        timeout(Duration::from_millis(200), stranger.recv_from(&mut buf))
+            .await
+            .is_err(),
+        "the client answered a stranger"
+    );
+}
+
+#[tokio::test]
+async fn clients_connect_from_a_chosen_local_address() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let server_addr = listener.local_addr();
+
+    let (client, conn) = tokio::join!(
+        RaknetStream::connect_from("127.0.0.1:0".parse().unwrap(), server_addr, 1400),
+        timeout(Duration::from_secs(5), listener.accept()),
+    );
+    let client = client.expect("connect failed");
+    let conn = conn.expect("no connection").expect("listener closed");
+    assert_ne!(client.local_addr().port(), 0);
+    assert_eq!(conn.peer_addr(), client.local_addr());
+
+    let res = RaknetStream::connect_from(client.local_addr(), server_addr, 1400).await;
+    assert!(
+        matches!(&res, Err(RaknetError::Io(e)) if e.kind() == std::io::ErrorKind::AddrInUse),
+        "{:?}",
+        res.err()
+    );
+}
+
+#[tokio::test]
+async fn unusable_mtus_are_refused() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let err = RaknetListener::from_socket(socket, 100)
+        .err()
+        .expect("tiny mtu accepted");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    let res = RaknetStream::connect_with_socket(socket, server, 1 << 20).await;
+    assert!(matches!(res, Err(RaknetError::InvalidConfig(_))));
+}
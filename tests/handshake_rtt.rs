@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+#[tokio::test]
+async fn connect_reports_the_handshake_round_trip() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (client, server) = tokio::join!(
+        RaknetStream::connect(listener.local_addr()),
+        listener.accept()
+    );
+    let (client, server) = (client.unwrap(), server.unwrap());
+
+    let rtt = client.handshake_rtt().expect("no handshake RTT");
+    assert!(rtt > Duration::ZERO);
+    assert!(rtt < Duration::from_secs(1), "{rtt:?}");
+    assert!(client.handshake_timestamps().is_some());
+
+    assert_eq!(server.handshake_rtt(), None);
+    assert!(server.handshake_timestamps().is_none());
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::{DEFAULT_UNCONNECTED_MAGIC, RAKNET_PROTOCOL_VERSION};
+use tokio_raknet::protocol::packet::{OpenConnectionRequest1, RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::types::{EoBPadding, RaknetTime};
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+
+fn ocr1() -> RaknetPacket {
+    RaknetPacket::OpenConnectionRequest1(OpenConnectionRequest1 {
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+        protocol_version: RAKNET_PROTOCOL_VERSION,
+        padding: EoBPadding(1400 - 46),
+    })
+}
+
+#[tokio::test]
+async fn aggressive_reconnector_is_banned_without_affecting_others() {
+    let config = RaknetListenerConfig {
+        max_handshake_attempts_per_ip: 3,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let server = listener.local_addr();
+
+    // Start a handshake from a fresh port each time and walk away.
+    for _ in 0..3 {
+        let peer = RawPeer::new(server).await;
+        peer.send_packet(ocr1()).await;
+        assert!(matches!(
+            peer.recv_packet(Duration::from_secs(1)).await,
+            Some(RaknetPacket::OpenConnectionReply1(_))
+        ));
+    }
+
+    let peer = RawPeer::new(server).await;
+    peer.send_packet(ocr1()).await;
+    assert!(matches!(
+        peer.recv_packet(Duration::from_secs(1)).await,
+        Some(RaknetPacket::IpRecentlyConnected(_))
+    ));
+
+    // From here on the IP gets nothing at all, pings included.
+    peer.send_packet(ocr1()).await;
+    peer.send_packet(RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(1),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    }))
+    .await;
+    assert!(peer.recv_raw(Duration::from_millis(200)).await.is_none());
+
+    let stats = listener.stats();
+    assert_eq!(stats.handshake_bans, 1);
+    assert_eq!(stats.handshakes_throttled, 2);
+
+    // Another source IP handshakes normally.
+    let other = RawPeer::bind("127.0.0.2:0", server).await;
+    other.offline_handshake(1400, 0x77).await;
+    assert_eq!(listener.stats().sessions, 1);
+}
+
+#[tokio::test]
+async fn completed_handshakes_do_not_count_towards_a_ban() {
+    let config = RaknetListenerConfig {
+        max_handshake_attempts_per_ip: 2,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+
+    for guid in 0..6 {
+        let peer = RawPeer::new(listener.local_addr()).await;
+        peer.offline_handshake(1400, guid).await;
+    }
+    assert_eq!(listener.stats().sessions, 6);
+    assert_eq!(listener.stats().handshake_bans, 0);
+}
@@ -63,3 +63,46 @@ async fn test_basic_handshake_and_exchange() {
     server_res.unwrap();
     client_res.unwrap();
 }
+
+#[tokio::test]
+async fn test_stats_visible_without_muxer_roundtrip() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .expect("failed to bind listener");
+    let local_addr = listener.local_addr();
+
+    let client_handle = tokio::spawn(async move {
+        let mut client = RaknetStream::connect(local_addr)
+            .await
+            .expect("failed to connect to server");
+        client.send("ping".as_bytes()).await.unwrap();
+        let _ = timeout(Duration::from_secs(2), client.recv()).await;
+        client
+    });
+
+    let mut conn = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("timeout waiting for connection")
+        .expect("listener closed unexpectedly");
+    let packet = conn.recv().await.unwrap().unwrap();
+    assert_eq!(packet, "ping");
+    conn.send("pong".as_bytes()).await.unwrap();
+
+    let client = client_handle.await.unwrap();
+
+    let server_side = conn.stats();
+    assert!(server_side.datagrams_received > 0);
+    assert!(server_side.messages_received > 0);
+    assert!(server_side.bytes_sent > 0);
+    assert_eq!(
+        listener
+            .peer_stats(conn.peer_addr())
+            .map(|s| s.datagrams_received > 0),
+        Some(true)
+    );
+    assert_eq!(listener.stats().sessions, 1);
+
+    let client_side = client.stats();
+    assert!(client_side.messages_sent >= 1);
+    assert!(client_side.bytes_received > 0);
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::transport::Message;
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn messages_far_larger_than_the_mtu_are_split_and_reassembled() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client, conn) = tokio::join!(
+        RaknetStream::connect_with_socket(socket, listener.local_addr(), 1200),
+        timeout(WAIT, listener.accept()),
+    );
+    let mut client = client.expect("connect failed");
+    let mut server = conn.expect("no connection").expect("listener closed");
+    assert!(client.mtu() <= 1200, "{}", client.mtu());
+
+    let payload: Vec<u8> = std::iter::once(0xfe)
+        .chain((0..100 * 1024).map(|i| (i % 251) as u8))
+        .collect();
+    // Sent unreliably, which a split can't be: the parts go out reliably.
+    client
+        .send(Message::new(payload.clone()).reliability(Reliability::Unreliable))
+        .await
+        .unwrap();
+    let msg = timeout(WAIT, server.recv())
+        .await
+        .expect("message lost")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(msg[..], payload[..]);
+
+    server.send(msg).await.unwrap();
+    let echo = timeout(WAIT, client.recv())
+        .await
+        .expect("echo lost")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(echo[..], payload[..]);
+    assert_eq!(listener.stats().oversized_datagrams, 0);
+}
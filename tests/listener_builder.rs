@@ -0,0 +1,75 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::RawPeer;
+use tokio::time::timeout;
+use tokio_raknet::protocol::packet::RaknetPacket;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::{RaknetError, RaknetListener};
+
+const SERVER_GUID: u64 = 0x5eed_0000_0000_0001;
+
+#[tokio::test]
+async fn builder_settings_reach_new_sessions() {
+    let mut listener = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+        .server_guid(SERVER_GUID)
+        .session_stale(Duration::from_millis(300))
+        .session_timeout(Duration::from_millis(600))
+        .keepalive_interval(Some(Duration::from_millis(100)))
+        .accept_backlog(1)
+        .bind()
+        .await
+        .unwrap();
+
+    let peer = RawPeer::new(listener.local_addr()).await;
+    let Some(RaknetPacket::OpenConnectionReply2(reply)) = peer.open_connection(1400, 7).await
+    else {
+        panic!("expected OpenConnectionReply2");
+    };
+    assert_eq!(reply.server_guid, SERVER_GUID);
+
+    // The peer finishes the handshake and then goes silent.
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(8).await;
+    let mut server = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    let start = Instant::now();
+    let res = timeout(Duration::from_secs(5), server.recv())
+        .await
+        .unwrap();
+    assert!(matches!(
+        res,
+        Some(Err(RaknetError::Disconnected(DisconnectReason::TimedOut)))
+    ));
+    // Well before the 10s default.
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn builder_refuses_settings_that_could_never_work() {
+    let res = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+        .session_stale(Duration::from_secs(20))
+        .bind()
+        .await;
+    assert_eq!(
+        res.err().expect("bind succeeded").kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+
+    // Dual-stack binds every address, so a specific one can't be honoured.
+    let res = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+        .dual_stack(true)
+        .bind()
+        .await;
+    assert_eq!(
+        res.err().expect("bind succeeded").kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
@@ -0,0 +1,56 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::{Instant, sleep, timeout};
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+/// Bind `addr`, retrying briefly while a dropped listener's task winds down.
+async fn rebind(addr: SocketAddr) -> std::io::Result<RaknetListener> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        match RaknetListener::bind(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && Instant::now() < deadline => {
+                sleep(Duration::from_millis(10)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[tokio::test]
+async fn dropped_listener_releases_its_port() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+    drop(listener);
+
+    let listener = rebind(addr).await.expect("port still bound");
+    assert_eq!(listener.local_addr(), addr);
+}
+
+#[tokio::test]
+async fn accepted_streams_outlive_the_listener() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+    let (client, conn) = tokio::join!(RaknetStream::connect(addr), listener.accept());
+    let (client, mut conn) = (client.unwrap(), conn.unwrap());
+    drop(listener);
+
+    client.send(vec![0x86, 1]).await.unwrap();
+    let msg = timeout(Duration::from_secs(5), conn.recv())
+        .await
+        .expect("message lost")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 1]);
+    assert_eq!(
+        RaknetListener::bind(addr).await.err().map(|e| e.kind()),
+        Some(std::io::ErrorKind::AddrInUse)
+    );
+
+    drop(conn);
+    rebind(addr).await.expect("port still bound");
+}
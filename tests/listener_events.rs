@@ -0,0 +1,72 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::protocol::types::RaknetTime;
+use tokio_raknet::transport::{ListenerEvent, RejectReason};
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+async fn next(events: &mut broadcast::Receiver<ListenerEvent>) -> ListenerEvent {
+    timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("no event")
+        .expect("event channel closed")
+}
+
+#[tokio::test]
+async fn lifecycle_of_a_connection_is_reported() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let server = listener.local_addr();
+    let mut events = listener.events();
+
+    let pinger = RawPeer::new(server).await;
+    pinger
+        .send_packet(RaknetPacket::UnconnectedPing(UnconnectedPing {
+            ping_time: RaknetTime(1),
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+        }))
+        .await;
+    let me = pinger.socket.local_addr().unwrap();
+    assert!(matches!(next(&mut events).await, ListenerEvent::Pinged { peer } if peer == me));
+
+    listener.set_accepting(false);
+    assert!(RaknetStream::connect(server).await.is_err());
+    assert!(matches!(
+        next(&mut events).await,
+        ListenerEvent::HandshakeRejected {
+            reason: RejectReason::NotAccepting,
+            ..
+        }
+    ));
+    listener.set_accepting(true);
+
+    let (client, conn) = tokio::join!(RaknetStream::connect(server), listener.accept());
+    let (_client, conn) = (client.unwrap(), conn.unwrap());
+    match next(&mut events).await {
+        ListenerEvent::Connected { peer, mtu, .. } => {
+            assert_eq!(peer, conn.peer_addr());
+            assert_eq!(mtu, conn.mtu());
+        }
+        other => panic!("expected Connected, got {other:?}"),
+    }
+
+    listener
+        .kick(conn.peer_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    assert!(matches!(
+        next(&mut events).await,
+        ListenerEvent::Disconnected {
+            peer,
+            reason: DisconnectReason::Disconnected,
+        } if peer == conn.peer_addr()
+    ));
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use common::{RawPeer, data_datagram};
+use tokio::time::timeout;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::ack::{AckNackPayload, SequenceRange};
+use tokio_raknet::protocol::constants::DatagramFlags;
+use tokio_raknet::protocol::datagram::{Datagram, DatagramPayload};
+use tokio_raknet::protocol::types::{DatagramHeader, Sequence24};
+
+/// ACK datagram packed with `count` ranges each of `len` sequences.
+fn wide_ack(count: u32, len: u32) -> Vec<u8> {
+    let ranges = (0..count)
+        .map(|i| SequenceRange {
+            start: Sequence24::new(i),
+            end: Sequence24::new(i + len - 1),
+        })
+        .collect();
+    let dgram = Datagram {
+        header: DatagramHeader {
+            flags: DatagramFlags::VALID | DatagramFlags::ACK,
+            sequence: Sequence24::new(0),
+        },
+        payload: DatagramPayload::Ack(AckNackPayload { ranges }),
+    };
+    let mut buf = BytesMut::new();
+    dgram.encode(&mut buf).unwrap();
+    buf.to_vec()
+}
+
+#[tokio::test]
+async fn huge_ack_ranges_are_cheap_and_harmless() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(0x31).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+
+    // Give the server something in flight to (wrongly) acknowledge.
+    conn.send(Bytes::from_static(&[0xfe, 1])).await.unwrap();
+
+    let started = Instant::now();
+    for _ in 0..20 {
+        // Full 24-bit ranges, and ranges just under the in-flight bound.
+        peer.send_raw(&wide_ack(100, 0xFF_FFFF)).await;
+        peer.send_raw(&wide_ack(100, 8000)).await;
+    }
+
+    peer.send_raw(&data_datagram(2, Bytes::from_static(&[0x86, 9])))
+        .await;
+    let msg = timeout(Duration::from_secs(2), conn.recv())
+        .await
+        .expect("muxer stalled")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 9]);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    let local = peer.socket.local_addr().unwrap();
+    assert!(listener.peer_stats(local).is_some());
+}
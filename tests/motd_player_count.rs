@@ -0,0 +1,137 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::protocol::types::RaknetTime;
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+use tokio_raknet::transport::{BedrockMotd, RaknetStream};
+
+const MOTD: &[u8] = b"MCPE;Counted;527;1.19.1;0;0;1;Sub;Survival;1;19132;19133";
+
+/// The online and maximum player fields of the listener's current pong.
+async fn player_counts(peer: &RawPeer) -> (String, String) {
+    peer.send_packet(RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(1),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    }))
+    .await;
+    let Some(RaknetPacket::UnconnectedPong(pong)) = peer.recv_packet(Duration::from_secs(1)).await
+    else {
+        panic!("no pong");
+    };
+    let motd = String::from_utf8(pong.advertisement.0.unwrap().to_vec()).unwrap();
+    let fields: Vec<&str> = motd.split(';').collect();
+    (fields[4].to_owned(), fields[5].to_owned())
+}
+
+fn counts(online: &str, max: &str) -> (String, String) {
+    (online.to_owned(), max.to_owned())
+}
+
+#[tokio::test]
+async fn pong_follows_connects_and_disconnects() {
+    let config = RaknetListenerConfig {
+        max_connections: 50,
+        advertisement: MOTD.to_vec(),
+        ..Default::default()
+    };
+    let mut listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+    let pinger = RawPeer::new(addr).await;
+
+    // Off by default: the counts go out exactly as set.
+    assert_eq!(player_counts(&pinger).await, counts("0", "0"));
+
+    listener.set_motd_auto_player_count(true);
+    assert_eq!(player_counts(&pinger).await, counts("0", "50"));
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let (client, server) = tokio::join!(RaknetStream::connect(addr), listener.accept());
+        clients.push((client.unwrap(), server.unwrap()));
+    }
+    assert_eq!(player_counts(&pinger).await, counts("2", "50"));
+
+    let (_client, server) = clients.pop().unwrap();
+    listener
+        .disconnect(server.peer_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    assert_eq!(player_counts(&pinger).await, counts("1", "50"));
+
+    listener.set_motd_auto_player_count(false);
+    assert_eq!(player_counts(&pinger).await, counts("0", "0"));
+    assert_eq!(listener.get_advertisement(), MOTD);
+}
+
+#[tokio::test]
+async fn typed_motd_reads_back_from_a_pong() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let motd = BedrockMotd {
+        line1: "Typed; with a semicolon".into(),
+        ..Default::default()
+    };
+    listener.set_motd(&motd);
+    listener.set_motd_auto_player_count(true);
+    let pinger = RawPeer::new(listener.local_addr()).await;
+    let (_client, _server) = tokio::join!(
+        RaknetStream::connect(listener.local_addr()),
+        listener.accept()
+    );
+
+    pinger
+        .send_packet(RaknetPacket::UnconnectedPing(UnconnectedPing {
+            ping_time: RaknetTime(1),
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+        }))
+        .await;
+    let Some(RaknetPacket::UnconnectedPong(pong)) =
+        pinger.recv_packet(Duration::from_secs(1)).await
+    else {
+        panic!("no pong");
+    };
+    let seen = BedrockMotd::parse(&pong.advertisement.0.unwrap()).unwrap();
+    assert_eq!(
+        seen,
+        BedrockMotd {
+            online_players: 1,
+            max_players: 1024,
+            server_guid: listener.guid(),
+            ..motd
+        }
+    );
+}
+
+#[tokio::test]
+async fn peers_mid_handshake_are_not_counted() {
+    let mut listener = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+        .advertisement(MOTD)
+        .max_connections(8)
+        .motd_auto_player_count(true)
+        .bind()
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+    let pinger = RawPeer::new(addr).await;
+    assert_eq!(player_counts(&pinger).await, counts("0", "8"));
+
+    // Past the offline handshake, so it has a session, but never connects.
+    let stuck = RawPeer::new(addr).await;
+    stuck.offline_handshake(1400, 7).await;
+    assert_eq!(listener.stats().sessions, 1);
+    assert_eq!(player_counts(&pinger).await, counts("0", "8"));
+
+    let (client, server) = tokio::join!(RaknetStream::connect(addr), listener.accept());
+    let (_client, _server) = (client.unwrap(), server.unwrap());
+    assert_eq!(listener.stats().sessions, 2);
+    assert_eq!(player_counts(&pinger).await, counts("1", "8"));
+}
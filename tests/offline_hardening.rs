@@ -0,0 +1,100 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::types::RaknetTime;
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+
+fn ping() -> RaknetPacket {
+    RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(7),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    })
+}
+
+async fn count_replies(peer: &RawPeer) -> usize {
+    let mut n = 0;
+    while peer.recv_raw(Duration::from_millis(100)).await.is_some() {
+        n += 1;
+    }
+    n
+}
+
+#[tokio::test]
+async fn truncated_and_wrong_magic_pings_get_no_reply() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    let mut wrong_magic = vec![0x01];
+    wrong_magic.extend_from_slice(&[0u8; 8]);
+    wrong_magic.extend_from_slice(&[0xab; 16]);
+
+    peer.send_raw(&[0x01]).await;
+    peer.send_raw(&[0x01, 0, 0, 0, 0, 0, 0, 0, 7]).await;
+    peer.send_raw(&wrong_magic).await;
+    peer.send_raw(&[0x05, 0xff, 0xff]).await;
+
+    assert_eq!(count_replies(&peer).await, 0);
+    assert_eq!(listener.stats().offline_packets_rejected, 4);
+
+    // The same socket still gets answered once it speaks properly.
+    peer.send_packet(ping()).await;
+    assert!(matches!(
+        peer.recv_packet(Duration::from_secs(1)).await,
+        Some(RaknetPacket::UnconnectedPong(_))
+    ));
+}
+
+#[tokio::test]
+async fn ping_flood_from_one_source_is_capped() {
+    let config = RaknetListenerConfig {
+        max_offline_replies_per_ip_per_second: 5,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    for _ in 0..50 {
+        peer.send_packet(ping()).await;
+    }
+
+    assert_eq!(count_replies(&peer).await, 5);
+    assert_eq!(listener.stats().offline_replies_rate_limited, 45);
+}
+
+#[tokio::test]
+async fn spoofed_looking_flood_is_capped_globally() {
+    let config = RaknetListenerConfig {
+        max_offline_replies_per_second: 8,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+
+    // Many distinct source ports, as a spoofed flood would look.
+    let mut peers = Vec::new();
+    for _ in 0..16 {
+        peers.push(RawPeer::new(listener.local_addr()).await);
+    }
+    for _ in 0..4 {
+        for peer in &peers {
+            peer.send_packet(ping()).await;
+        }
+    }
+
+    let mut replies = 0;
+    for peer in &peers {
+        replies += count_replies(peer).await;
+    }
+    assert_eq!(replies, 8);
+    assert_eq!(listener.stats().offline_replies_rate_limited, 56);
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{RawPeer, data_datagram};
+use tokio_raknet::RaknetListener;
+
+#[tokio::test]
+async fn datagram_larger_than_peer_mtu_is_dropped_and_counted() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    let mtu = peer.offline_handshake(576, 0x42).await;
+    assert_eq!(mtu, 576);
+
+    // Fits comfortably inside the listener's buffer but not inside 576.
+    peer.send_raw(&data_datagram(0, Bytes::from(vec![0x86u8; 1000])))
+        .await;
+
+    let local = peer.socket.local_addr().unwrap();
+    let mut dropped = false;
+    for _ in 0..50 {
+        if listener.stats().oversized_datagrams == 1 {
+            dropped = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(dropped, "oversized datagram was not counted");
+
+    // Nothing was processed: no ACK comes back and the session survives.
+    assert!(peer.recv_raw(Duration::from_millis(200)).await.is_none());
+    let stats = listener.peer_stats(local).expect("session still alive");
+    assert_eq!(stats.datagrams_received, 0);
+}
@@ -0,0 +1,117 @@
+//! Two ends of a connection over the in-memory network, with helpers for
+//! numbered messages.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use tokio::time::{sleep, timeout};
+use tokio_raknet::RaknetError;
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::transport::memory::{MemoryNetwork, SimulatedLink};
+use tokio_raknet::transport::{
+    Message, RaknetListener, RaknetListenerConfig, RaknetStream, RaknetStreamConfig,
+    ReceivedMessage,
+};
+
+pub const SERVER: &str = "10.0.0.1:19132";
+pub const WAIT: Duration = Duration::from_secs(60);
+
+pub struct Pair {
+    pub net: MemoryNetwork,
+    pub listener: RaknetListener,
+    pub client: RaknetStream,
+    pub server: RaknetStream,
+}
+
+impl Pair {
+    pub async fn connect() -> Self {
+        Self::connect_over(SimulatedLink::new()).await
+    }
+
+    /// Connect with `link` in place from the first handshake packet on.
+    pub async fn connect_over(link: SimulatedLink) -> Self {
+        Self::connect_with(
+            link,
+            RaknetListenerConfig::default(),
+            RaknetStreamConfig::default(),
+        )
+        .await
+    }
+
+    pub async fn connect_with(
+        link: SimulatedLink,
+        listener_config: RaknetListenerConfig,
+        client_config: RaknetStreamConfig,
+    ) -> Self {
+        let net = MemoryNetwork::new();
+        net.set_default_link(link);
+        let socket = net.bind(SERVER.parse().unwrap()).unwrap();
+        let mut listener = RaknetListener::with_socket(socket, listener_config).unwrap();
+        let client_socket = net.bind_any().unwrap();
+        let (client, server) = tokio::join!(
+            RaknetStream::connect_on(client_socket, listener.local_addr(), client_config),
+            listener.accept()
+        );
+        Self {
+            net,
+            listener,
+            client: client.expect("client connects"),
+            server: server.expect("listener accepts"),
+        }
+    }
+
+    pub fn set_link(&self, link: SimulatedLink) {
+        self.net
+            .set_link_both(self.client.local_addr(), self.server.local_addr(), link);
+    }
+
+    /// Conditions from client to server only.
+    pub fn uplink(&self, link: SimulatedLink) {
+        self.net
+            .set_link(self.client.local_addr(), self.server.local_addr(), link);
+    }
+
+    /// Conditions from server to client only.
+    pub fn downlink(&self, link: SimulatedLink) {
+        self.net
+            .set_link(self.server.local_addr(), self.client.local_addr(), link);
+    }
+}
+
+/// Let the muxers pick up what was just queued.
+pub async fn settle() {
+    sleep(Duration::from_millis(1)).await;
+}
+
+pub async fn recv(stream: &mut RaknetStream) -> ReceivedMessage {
+    timeout(WAIT, stream.recv_msg())
+        .await
+        .expect("message arrives in time")
+        .expect("stream open")
+        .expect("no error")
+}
+
+/// Read until the stream reports an error, skipping data still in flight.
+pub async fn recv_error(stream: &mut RaknetStream) -> RaknetError {
+    loop {
+        match timeout(WAIT, stream.recv_msg())
+            .await
+            .expect("stream ends in time")
+        {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return e,
+            None => panic!("stream ended without an error"),
+        }
+    }
+}
+
+pub fn numbered(i: u32, len: usize) -> Message {
+    let mut buf = vec![0xfe];
+    buf.extend_from_slice(&i.to_be_bytes());
+    buf.resize(len, i as u8);
+    Message::new(buf).reliability(Reliability::ReliableOrdered)
+}
+
+pub fn number_of(msg: &ReceivedMessage) -> u32 {
+    u32::from_be_bytes(msg.buffer[1..5].try_into().unwrap())
+}
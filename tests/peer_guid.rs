@@ -0,0 +1,50 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio::time::timeout;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::packet::RaknetPacket;
+use tokio_raknet::transport::RaknetStream;
+
+const GUID: u64 = 0x0123_4567_89ab_cdef;
+
+#[tokio::test]
+async fn accepted_stream_carries_the_client_guid() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    peer.connect(GUID).await;
+    let server = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(server.peer_guid(), GUID);
+    assert_eq!(server.server_guid(), None);
+}
+
+#[tokio::test]
+async fn connected_stream_carries_the_server_guid() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+
+    // What the listener puts in its handshake replies.
+    let peer = RawPeer::new(listener.local_addr()).await;
+    let Some(RaknetPacket::OpenConnectionReply2(reply2)) = peer.open_connection(1400, GUID).await
+    else {
+        panic!("expected OpenConnectionReply2");
+    };
+
+    let (client, server) = tokio::join!(
+        RaknetStream::connect(listener.local_addr()),
+        listener.accept()
+    );
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert_eq!(client.server_guid(), Some(reply2.server_guid));
+    assert_eq!(client.peer_guid(), reply2.server_guid);
+    assert_ne!(server.peer_guid(), GUID);
+}
@@ -0,0 +1,97 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::net::UdpSocket;
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPong};
+use tokio_raknet::protocol::types::{Advertisement, RaknetTime};
+use tokio_raknet::transport::ping_with_socket;
+use tokio_raknet::{PingError, RaknetListener, ping};
+
+const WAIT: Duration = Duration::from_secs(2);
+
+/// A server that answers one ping with whatever `pong` makes of its time.
+async fn fake_server(pong: impl FnOnce(u64) -> UnconnectedPong + Send + 'static) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        let Ok(RaknetPacket::UnconnectedPing(ping)) = RaknetPacket::decode(&mut &buf[..len]) else {
+            panic!("expected an unconnected ping");
+        };
+        let mut out = BytesMut::new();
+        RaknetPacket::UnconnectedPong(pong(ping.ping_time.0))
+            .encode(&mut out)
+            .unwrap();
+        socket.send_to(&out, from).await.unwrap();
+    });
+    addr
+}
+
+fn pong(ping_time: u64, magic: [u8; 16], advertisement: Option<&'static [u8]>) -> UnconnectedPong {
+    UnconnectedPong {
+        ping_time: RaknetTime(ping_time),
+        server_guid: 42,
+        magic,
+        advertisement: Advertisement(advertisement.map(bytes::Bytes::from_static)),
+    }
+}
+
+#[tokio::test]
+async fn pings_a_listener_for_its_advertisement() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    listener.set_advertisement(b"hello".to_vec());
+
+    let res = ping(listener.local_addr(), WAIT).await.unwrap();
+    assert_eq!(res.server_guid, listener.guid());
+    assert_eq!(&res.advertisement[..], b"hello");
+    assert!(res.latency < WAIT);
+}
+
+#[tokio::test]
+async fn missing_advertisement_is_empty() {
+    let server = fake_server(|t| pong(t, DEFAULT_UNCONNECTED_MAGIC, None)).await;
+    let res = ping(server, WAIT).await.unwrap();
+    assert_eq!(res.server_guid, 42);
+    assert!(res.advertisement.is_empty());
+}
+
+#[tokio::test]
+async fn wrong_magic_is_rejected() {
+    let server = fake_server(|t| pong(t, [0xAB; 16], Some(b"x"))).await;
+    assert!(matches!(
+        ping(server, WAIT).await,
+        Err(PingError::Decode(_))
+    ));
+}
+
+#[tokio::test]
+async fn silent_server_times_out() {
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let res = ping(silent.local_addr().unwrap(), Duration::from_millis(100)).await;
+    assert!(matches!(res, Err(PingError::Timeout)));
+}
+
+#[tokio::test]
+async fn pings_share_one_socket() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut servers = Vec::new();
+    for guid in [1, 2] {
+        let listener = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+            .server_guid(guid)
+            .bind()
+            .await
+            .unwrap();
+        servers.push(listener);
+    }
+    for listener in &servers {
+        let res = ping_with_socket(&socket, listener.local_addr(), WAIT)
+            .await
+            .unwrap();
+        assert_eq!(res.server_guid, listener.guid());
+    }
+}
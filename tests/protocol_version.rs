@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use tokio::time::timeout;
+use tokio_raknet::protocol::constants::RAKNET_PROTOCOL_VERSION;
+use tokio_raknet::transport::RaknetStreamConfig;
+use tokio_raknet::{RaknetError, RaknetListener, RaknetStream};
+
+#[tokio::test]
+async fn client_and_listener_agree_on_an_older_version() {
+    let mut listener = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+        .protocol_version(10)
+        .bind()
+        .await
+        .unwrap();
+    let server = listener.local_addr();
+
+    match RaknetStream::connect(server).await {
+        Err(RaknetError::IncompatibleProtocol {
+            ours,
+            theirs,
+            server_guid,
+        }) => {
+            assert_eq!((ours, theirs), (RAKNET_PROTOCOL_VERSION, 10));
+            assert_eq!(server_guid, listener.guid());
+        }
+        Err(e) => panic!("expected IncompatibleProtocol, got {e:?}"),
+        Ok(_) => panic!("connected with the wrong protocol version"),
+    }
+
+    let config = RaknetStreamConfig {
+        protocol_version: 10,
+        ..Default::default()
+    };
+    let (client, incoming) = tokio::join!(
+        RaknetStream::connect_with_config(server, config),
+        timeout(Duration::from_secs(5), listener.accept_with_info()),
+    );
+    client.expect("connect failed");
+    let incoming = incoming.expect("no connection").expect("listener closed");
+    assert_eq!(incoming.protocol_version, 10);
+}
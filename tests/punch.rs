@@ -0,0 +1,111 @@
+//! Hole punching between two processes, then a RakNet connection over the
+//! punched sockets.
+//!
+//! The test starts a second copy of this binary for the accepting side, so
+//! it only runs when asked for: `cargo test --test punch -- --ignored`.
+
+use std::net::SocketAddr;
+use std::process::Command;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::{interval, timeout};
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::{RaknetListener, RaknetStream};
+
+/// Set in the child process to the address of the parent's socket.
+const PEER_ENV: &str = "RAKNET_PUNCH_PEER";
+const PUNCH: &[u8] = b"punch";
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Send punches to `peer` until one arrives, returning where it came from.
+/// Only `peer` is listened to when it is known.
+async fn punch(socket: &UdpSocket, peer: Option<SocketAddr>) -> SocketAddr {
+    let mut buf = [0u8; 64];
+    let mut every = interval(Duration::from_millis(50));
+    loop {
+        tokio::select! {
+            _ = every.tick() => {
+                if let Some(peer) = peer {
+                    socket.send_to(PUNCH, peer).await.unwrap();
+                }
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = res.unwrap();
+                if &buf[..len] == PUNCH && peer.is_none_or(|peer| peer == from) {
+                    // One back, so the other side stops waiting too.
+                    socket.send_to(PUNCH, from).await.unwrap();
+                    return from;
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore = "starts a second process"]
+async fn two_processes_punch_then_connect() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local = socket.local_addr().unwrap();
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "punch_peer", "--ignored", "--nocapture"])
+        .env(PEER_ENV, local.to_string())
+        .spawn()
+        .unwrap();
+
+    let peer = timeout(WAIT, punch(&socket, None))
+        .await
+        .expect("no punch from the child");
+    // The child keeps punching until it hears back, and those late punches
+    // arrive during the handshake.
+    let mut client = timeout(WAIT, RaknetStream::connect_with_socket(socket, peer, 1400))
+        .await
+        .expect("connect timed out")
+        .expect("connect failed");
+    assert_eq!(client.local_addr(), local);
+    assert_eq!(client.peer_addr(), peer);
+
+    client.send(vec![0x86, 1, 2, 3]).await.unwrap();
+    let echo = timeout(WAIT, client.recv())
+        .await
+        .expect("no echo")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&echo[..], &[0x86, 1, 2, 3]);
+    client.disconnect(DisconnectReason::Disconnected).await.ok();
+
+    let status = tokio::task::spawn_blocking(move || child.wait())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(status.success(), "child failed: {status}");
+}
+
+/// The accepting side, run in the child process.
+#[tokio::test]
+#[ignore = "run by two_processes_punch_then_connect"]
+async fn punch_peer() {
+    let Ok(peer) = std::env::var(PEER_ENV) else {
+        return;
+    };
+    let peer: SocketAddr = peer.parse().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    timeout(WAIT, punch(&socket, Some(peer)))
+        .await
+        .expect("no punch from the parent");
+
+    let mut listener = RaknetListener::from_socket(socket, 1400).unwrap();
+    let mut conn = timeout(WAIT, listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+    assert_eq!(conn.peer_addr(), peer);
+    let msg = timeout(WAIT, conn.recv())
+        .await
+        .expect("no message")
+        .expect("connection closed")
+        .expect("connection errored");
+    conn.send(msg).await.unwrap();
+    // Wait for the parent to hang up.
+    let _ = timeout(WAIT, conn.closed()).await;
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use tokio::time::timeout;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::transport::RaknetStreamConfig;
+use tokio_raknet::{RaknetError, RaknetListener, RaknetStream};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn reconnect_keeps_the_client_guid() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let server = listener.local_addr();
+
+    // Longer than the listener's `ip_recently_connected_window`, which
+    // refuses the reconnect at first.
+    let mut config = RaknetStreamConfig::default();
+    config.handshake_retry.recently_connected_delay = Some(Duration::from_millis(250));
+    let (client, first) = tokio::join!(
+        RaknetStream::connect_with_config(server, config),
+        timeout(WAIT, listener.accept_with_info())
+    );
+    let client = client.unwrap();
+    let first = first.expect("no connection").expect("listener closed");
+
+    let (again, second) = tokio::join!(
+        client.reconnect(),
+        timeout(WAIT, listener.accept_with_info())
+    );
+    let again = again.expect("reconnect failed");
+    let second = second.expect("no connection").expect("listener closed");
+    assert_eq!(second.client_guid, first.client_guid);
+    assert_ne!(again.local_addr(), client.local_addr());
+    assert_eq!(again.server_guid(), Some(listener.guid()));
+
+    // The old session was replaced, not left running beside the new one.
+    assert!(matches!(
+        timeout(WAIT, client.closed()).await,
+        Ok(DisconnectReason::Disconnected)
+    ));
+    let mut stream = second.stream;
+    again.send(vec![0x86, 1]).await.unwrap();
+    let msg = timeout(WAIT, stream.recv())
+        .await
+        .expect("message lost")
+        .expect("connection closed")
+        .expect("connection errored");
+    assert_eq!(&msg[..], &[0x86, 1]);
+}
+
+#[tokio::test]
+async fn accepted_streams_cannot_reconnect() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (_client, conn) = tokio::join!(
+        RaknetStream::connect(listener.local_addr()),
+        listener.accept()
+    );
+    assert!(matches!(
+        conn.unwrap().reconnect().await,
+        Err(RaknetError::NotReconnectable)
+    ));
+}
@@ -0,0 +1,368 @@
+//! Retransmission and ACK timing end to end over the in-memory network.
+
+mod pair;
+
+use std::time::Duration;
+
+use pair::{Pair, WAIT, number_of, numbered, recv, settle};
+use tokio::time::{Instant, sleep, timeout};
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::session::AckPolicy;
+use tokio_raknet::transport::memory::{Jitter, SimulatedLink};
+use tokio_raknet::transport::{RaknetListenerConfig, RaknetStreamConfig};
+
+#[tokio::test(start_paused = true)]
+async fn resends_wait_for_the_round_trip_of_a_slow_link() {
+    let link = SimulatedLink::new().latency(Duration::from_millis(100));
+    let mut pair = Pair::connect_over(link).await;
+
+    for i in 0..20 {
+        pair.client.send(numbered(i, 100)).await.unwrap();
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+        sleep(Duration::from_millis(50)).await;
+    }
+    sleep(Duration::from_millis(500)).await;
+    let stats = pair.client.stats();
+    // A fixed timeout shorter than 200 ms would have resent everything.
+    assert_eq!(stats.datagrams_resent, 0);
+    let rtt = stats.rtt.expect("rtt measured");
+    assert!(rtt >= Duration::from_millis(200), "{rtt:?}");
+    assert!(
+        stats.rto > rtt && stats.rto < Duration::from_millis(500),
+        "{stats:?}"
+    );
+
+    // With the link down, the one message is resent about once per RTO.
+    pair.set_link(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(20, 100)).await.unwrap();
+    sleep(Duration::from_millis(1000)).await;
+    let resent = pair.client.stats().datagrams_resent;
+    let expected = (Duration::from_millis(1000).as_millis() / stats.rto.as_millis()) as u64;
+    assert!(
+        resent >= 1 && resent <= expected,
+        "{resent} resends, rto {:?}",
+        stats.rto
+    );
+}
+
+/// A pair whose client gives up on a datagram after 3 resends but would
+/// otherwise wait a minute for the server to go quiet.
+async fn impatient_client() -> Pair {
+    let client_config = RaknetStreamConfig {
+        session_timeout: Duration::from_secs(60),
+        max_resends: 3,
+        ..Default::default()
+    };
+    Pair::connect_with(
+        SimulatedLink::new(),
+        RaknetListenerConfig::default(),
+        client_config,
+    )
+    .await
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_peer_that_acks_nothing_times_out_after_the_resends() {
+    let pair = impatient_client().await;
+    pair.downlink(SimulatedLink::new().loss(1.0));
+
+    let start = Instant::now();
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    assert!(matches!(
+        timeout(WAIT, pair.client.closed()).await,
+        Ok(DisconnectReason::TimedOut)
+    ));
+    // Three doubling intervals from a short RTO, not the idle timeout.
+    assert!(
+        start.elapsed() < Duration::from_secs(5),
+        "{:?}",
+        start.elapsed()
+    );
+    assert!(pair.client.stats().datagrams_resent >= 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn acks_after_a_few_resends_keep_the_session() {
+    let mut pair = impatient_client().await;
+    pair.downlink(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+
+    // Restored before the third resend would be due.
+    let rto = pair.client.stats().rto;
+    sleep(rto * 2).await;
+    assert!(pair.client.stats().datagrams_resent > 0);
+    pair.downlink(SimulatedLink::new());
+
+    for i in 1..4 {
+        sleep(Duration::from_secs(1)).await;
+        pair.client.send(numbered(i, 100)).await.unwrap();
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+    assert!(
+        timeout(Duration::from_secs(1), pair.client.closed())
+            .await
+            .is_err()
+    );
+}
+
+/// Mean time from send to delivery for a steady stream of messages over
+/// a link that loses a tenth of what the client sends.
+async fn mean_delivery_over_loss(fast_retransmit: bool) -> Duration {
+    let client_config = RaknetStreamConfig {
+        fast_retransmit,
+        ..Default::default()
+    };
+    let link = SimulatedLink::new().latency(Duration::from_millis(50));
+    let mut pair = Pair::connect_with(link, RaknetListenerConfig::default(), client_config).await;
+    pair.uplink(link.loss(0.1));
+
+    let count = 200;
+    let start = Instant::now();
+    let every = Duration::from_millis(20);
+    let sender = pair.client.sender();
+    tokio::spawn(async move {
+        for i in 0..count {
+            sender
+                .send(numbered(i, 100).reliability(Reliability::Reliable))
+                .await
+                .unwrap();
+            sleep(every).await;
+        }
+    });
+    let mut total = Duration::ZERO;
+    for _ in 0..count {
+        let i = number_of(&recv(&mut pair.server).await);
+        total += start.elapsed() - every * i;
+    }
+    assert!(pair.client.stats().naks_received > 0);
+    total / count
+}
+
+#[tokio::test(start_paused = true)]
+async fn fast_retransmit_delivers_lost_messages_sooner() {
+    let fast = mean_delivery_over_loss(true).await;
+    let timed = mean_delivery_over_loss(false).await;
+    assert!(fast < timed, "fast {fast:?}, on the tick {timed:?}");
+}
+
+/// What the server's `AckPolicy` costs the client over ten bursts of 20
+/// messages, each big enough to fill a datagram: the ACK datagrams it
+/// has to take in, and the round trip it measures.
+async fn acks_under(ack_policy: AckPolicy) -> (u64, Duration) {
+    let listener_config = RaknetListenerConfig {
+        ack_policy,
+        ..Default::default()
+    };
+    let link = SimulatedLink::new().latency(Duration::from_millis(5));
+    let mut pair = Pair::connect_with(link, listener_config, RaknetStreamConfig::default()).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let before = pair.client.stats().acks_received;
+    for burst in 0..10 {
+        for i in 0..20 {
+            pair.client
+                .send(numbered(burst * 20 + i, 1000))
+                .await
+                .unwrap();
+        }
+        for i in 0..20 {
+            assert_eq!(number_of(&recv(&mut pair.server).await), burst * 20 + i);
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let stats = pair.client.stats();
+    (
+        stats.acks_received - before,
+        stats.rtt.expect("rtt measured"),
+    )
+}
+
+#[tokio::test(start_paused = true)]
+async fn delayed_acks_trade_round_trip_time_for_fewer_datagrams() {
+    // Over a 10 ms round trip: acknowledged immediately, every datagram
+    // of a burst gets its own ACK and the client sees the bare round
+    // trip. Batched for 20 ms, a burst costs one ACK or two, and the
+    // client's RTT, so its RTO, takes on the batching delay.
+    let (immediate_acks, immediate_rtt) = acks_under(AckPolicy::Immediate).await;
+    let delay = Duration::from_millis(20);
+    let (delayed_acks, delayed_rtt) = acks_under(AckPolicy::Delayed(delay)).await;
+
+    assert!(immediate_acks >= 200, "{immediate_acks}");
+    assert!(delayed_acks <= 20, "{delayed_acks}");
+    assert!(
+        immediate_rtt < Duration::from_millis(15) && delayed_rtt > immediate_rtt + delay / 2,
+        "immediate {immediate_rtt:?}, delayed {delayed_rtt:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn reliable_data_survives_loss() {
+    let mut pair = Pair::connect().await;
+    pair.set_link(
+        SimulatedLink::new()
+            .latency(Duration::from_millis(30))
+            .loss(0.2),
+    );
+
+    let count = 200;
+    for i in 0..count {
+        pair.client.send(numbered(i, 200)).await.unwrap();
+    }
+    for i in 0..count {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+    assert!(pair.client.stats().datagrams_resent > 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn timer_retransmits_when_no_nak_can_arrive() {
+    let mut pair = Pair::connect().await;
+    // Nothing gets back to the client, so only its own timer can help.
+    pair.downlink(SimulatedLink::new().loss(1.0));
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    settle().await;
+    pair.uplink(SimulatedLink::new());
+
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+    let stats = pair.client.stats();
+    assert!(stats.datagrams_resent > 0);
+    assert_eq!(stats.naks_received, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn nak_recovers_a_gap_before_the_timer_would() {
+    let latency = Duration::from_millis(200);
+    let link = SimulatedLink::new().latency(latency);
+    // A slow handshake pushes the retransmission timeout to its maximum.
+    let mut pair = Pair::connect_over(link).await;
+
+    pair.uplink(link.loss(1.0));
+    let start = Instant::now();
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    settle().await;
+    pair.uplink(link);
+    pair.client.send(numbered(1, 100)).await.unwrap();
+
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+    assert_eq!(number_of(&recv(&mut pair.server).await), 1);
+    // Gap seen, NAK back, resend forward: about three one-way trips.
+    assert!(
+        start.elapsed() < Duration::from_millis(1000),
+        "{:?}",
+        start.elapsed()
+    );
+    assert!(pair.client.stats().naks_received > 0);
+}
+
+async fn arrival_order(reliability: Reliability) -> Vec<u32> {
+    let link = SimulatedLink::new()
+        .latency(Duration::from_millis(10))
+        .reorder(0.3, Duration::from_millis(40));
+    let mut pair = Pair::connect_over(link).await;
+
+    let count = 100;
+    for i in 0..count {
+        let msg = numbered(i, 100).reliability(reliability);
+        pair.client.send(msg).await.unwrap();
+        settle().await;
+    }
+
+    let mut order = Vec::new();
+    for _ in 0..count {
+        order.push(number_of(&recv(&mut pair.server).await));
+    }
+    order
+}
+
+#[tokio::test(start_paused = true)]
+async fn ordering_undoes_network_reordering() {
+    // Without ordering the link visibly shuffles messages...
+    let unordered = arrival_order(Reliability::Reliable).await;
+    assert!(unordered.windows(2).any(|w| w[0] > w[1]), "{unordered:?}");
+
+    // ...and with it they come out exactly as sent.
+    let ordered = arrival_order(Reliability::ReliableOrdered).await;
+    assert_eq!(ordered, (0..100).collect::<Vec<_>>());
+}
+
+#[tokio::test(start_paused = true)]
+async fn split_messages_reassemble_over_a_hostile_link() {
+    let link = SimulatedLink::new()
+        .loss(0.1)
+        .latency(Duration::from_millis(20))
+        .jitter(Jitter::Uniform(Duration::from_millis(10)))
+        .reorder(0.2, Duration::from_millis(30))
+        .duplicate(0.1);
+    let mut pair = Pair::connect_over(link).await;
+
+    let count = 5;
+    for i in 0..count {
+        pair.client.send(numbered(i, 30_000)).await.unwrap();
+    }
+    for i in 0..count {
+        let msg = recv(&mut pair.server).await;
+        assert_eq!(number_of(&msg), i);
+        assert_eq!(msg.buffer.len(), 30_000);
+        assert!(msg.buffer[5..].iter().all(|&b| b == i as u8));
+    }
+    // Exactly once: nothing left over once the link goes quiet.
+    let extra = timeout(Duration::from_secs(5), pair.server.recv_msg()).await;
+    assert!(extra.is_err(), "unexpected {extra:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn bandwidth_cap_paces_a_transfer() {
+    let mut pair = Pair::connect().await;
+    pair.set_link(SimulatedLink::new().bandwidth(50_000));
+
+    let start = Instant::now();
+    for i in 0..100 {
+        pair.client.send(numbered(i, 1000)).await.unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+    // 100 kB of payload through a 50 kB/s pipe.
+    assert!(
+        start.elapsed() >= Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn lost_acks_show_up_as_duplicates_not_loss() {
+    let mut pair = Pair::connect().await;
+    // Everything reaches the server, but none of its ACKs come back.
+    pair.downlink(SimulatedLink::new().loss(1.0));
+
+    for i in 0..5 {
+        pair.client.send(numbered(i, 100)).await.unwrap();
+    }
+    for i in 0..5 {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+
+    sleep(Duration::from_secs(3)).await;
+    let first = pair.server.stats().duplicate_datagrams;
+    assert!(first > 0);
+    sleep(Duration::from_secs(3)).await;
+    let stats = pair.server.stats();
+    assert!(stats.duplicate_datagrams > first, "{stats:?}");
+    assert_eq!(
+        pair.listener.stats().duplicate_datagrams,
+        stats.duplicate_datagrams
+    );
+
+    // Resends reuse their datagram sequence number, so the frames inside
+    // are never looked at again and nothing is delivered twice.
+    assert_eq!(stats.duplicate_frames, 0);
+    pair.downlink(SimulatedLink::new());
+    pair.client.send(numbered(5, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 5);
+    // The server never saw a gap to report.
+    assert_eq!(pair.client.stats().naks_received, 0);
+}
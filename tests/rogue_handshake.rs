@@ -0,0 +1,99 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{RawPeer, data_datagram};
+use tokio::time::timeout;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::{DEFAULT_UNCONNECTED_MAGIC, RAKNET_PROTOCOL_VERSION};
+use tokio_raknet::protocol::packet::{
+    OpenConnectionRequest1, OpenConnectionRequest2, RaknetPacket,
+};
+use tokio_raknet::protocol::types::EoBPadding;
+
+const GUID: u64 = 0x4141;
+
+fn rogue_ocr2(server: SocketAddr, cookie: Option<u32>, mtu: u16, guid: u64) -> RaknetPacket {
+    RaknetPacket::OpenConnectionRequest2(OpenConnectionRequest2 {
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+        cookie,
+        client_proof: true,
+        server_addr: server,
+        mtu,
+        client_guid: guid,
+    })
+}
+
+#[tokio::test]
+async fn duplicate_ocr2_leaves_the_live_session_alone() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+    peer.connect(GUID).await;
+    let mut conn = timeout(Duration::from_secs(2), listener.accept())
+        .await
+        .expect("no connection")
+        .expect("listener closed");
+    let local = peer.socket.local_addr().unwrap();
+
+    peer.send_raw(&data_datagram(2, Bytes::from_static(&[0x86, 1])))
+        .await;
+    assert!(conn.recv().await.unwrap().is_ok());
+    let before = listener.peer_stats(local).unwrap();
+    while peer.recv_raw(Duration::from_millis(50)).await.is_some() {}
+
+    // A full re-handshake at a smaller MTU is answered from the live session.
+    peer.send_packet(RaknetPacket::OpenConnectionRequest1(
+        OpenConnectionRequest1 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            protocol_version: RAKNET_PROTOCOL_VERSION,
+            padding: EoBPadding(576 - 46),
+        },
+    ))
+    .await;
+    let cookie = match peer.recv_packet(Duration::from_secs(1)).await {
+        Some(RaknetPacket::OpenConnectionReply1(r)) => {
+            assert_eq!(r.mtu, 1400);
+            r.cookie
+        }
+        other => panic!("expected OpenConnectionReply1, got {other:?}"),
+    };
+    peer.send_packet(rogue_ocr2(peer.server, cookie, 576, GUID))
+        .await;
+    match peer.recv_packet(Duration::from_secs(1)).await {
+        Some(RaknetPacket::OpenConnectionReply2(r)) => assert_eq!(r.mtu, 1400),
+        other => panic!("expected cached OpenConnectionReply2, got {other:?}"),
+    }
+
+    // Different GUID from the same address.
+    peer.send_packet(rogue_ocr2(peer.server, cookie, 576, GUID + 1))
+        .await;
+    assert!(matches!(
+        peer.recv_packet(Duration::from_secs(1)).await,
+        Some(RaknetPacket::AlreadyConnected(_))
+    ));
+
+    // Traffic carries on in both directions at the original MTU.
+    peer.send_raw(&data_datagram(3, Bytes::from_static(&[0x86, 2])))
+        .await;
+    let msg = timeout(Duration::from_secs(1), conn.recv())
+        .await
+        .expect("data flow stopped")
+        .unwrap()
+        .unwrap();
+    assert_eq!(&msg[..], &[0x86, 2]);
+
+    conn.send(Bytes::from(vec![0xfe; 1000])).await.unwrap();
+    let dgram = peer
+        .recv_raw(Duration::from_secs(1))
+        .await
+        .expect("no data from server");
+    assert!(dgram.len() > 1000, "payload was split for a smaller MTU");
+
+    let after = listener.peer_stats(local).unwrap();
+    assert!(after.datagrams_received > before.datagrams_received);
+    assert_eq!(listener.stats().sessions, 1);
+}
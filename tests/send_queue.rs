@@ -0,0 +1,322 @@
+//! How queued sends are packed, prioritised and bounded, end to end over the
+//! in-memory network.
+
+mod pair;
+
+use std::time::Duration;
+
+use pair::{Pair, WAIT, number_of, numbered, recv, recv_error, settle};
+use tokio::time::{Instant, sleep, timeout};
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::state::{DisconnectReason, RakPriority};
+use tokio_raknet::session::manager::{BacklogLimit, QueueLimitPolicy, SendQueueLimit};
+use tokio_raknet::transport::memory::SimulatedLink;
+use tokio_raknet::transport::{Message, RaknetListenerConfig, RaknetStream, RaknetStreamConfig};
+use tokio_raknet::{RaknetError, TrySendError};
+
+/// Datagrams `from` puts on the wire to send 100 ten-byte messages to
+/// `to` back to back.
+async fn datagrams_for_small_messages(from: &RaknetStream, to: &mut RaknetStream) -> u64 {
+    let before = from.stats().datagrams_sent;
+    for i in 0..100 {
+        from.send(numbered(i, 10)).await.unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(number_of(&recv(to).await), i);
+    }
+    from.stats().datagrams_sent - before
+}
+
+#[tokio::test(start_paused = true)]
+async fn small_messages_sent_back_to_back_share_datagrams() {
+    let mut pair = Pair::connect().await;
+    settle().await;
+
+    let up = datagrams_for_small_messages(&pair.client, &mut pair.server).await;
+    let down = datagrams_for_small_messages(&pair.server, &mut pair.client).await;
+    assert!(up <= 10 && down <= 10, "{up} up, {down} down");
+}
+
+/// Queue 200 KB on `from`, then one small `Immediate` message, and
+/// return how long that message takes to reach `to`. The rest is
+/// drained before returning.
+async fn immediate_latency_behind_a_backlog(
+    from: &RaknetStream,
+    to: &mut RaknetStream,
+) -> Duration {
+    let start = Instant::now();
+    for _ in 0..200 {
+        from.send(vec![0xfd; 1000]).await.unwrap();
+    }
+    let marker = Message::new(vec![0xfe])
+        .reliability(Reliability::Reliable)
+        .priority(RakPriority::Immediate);
+    from.send(marker).await.unwrap();
+
+    let mut took = None;
+    for _ in 0..201 {
+        if recv(to).await.buffer[0] == 0xfe {
+            took = Some(start.elapsed());
+        }
+    }
+    took.expect("marker delivered")
+}
+
+#[tokio::test(start_paused = true)]
+async fn immediate_sends_go_out_in_the_same_turn_ahead_of_a_backlog() {
+    let latency = Duration::from_millis(10);
+    let mut pair = Pair::connect_over(SimulatedLink::new().latency(latency)).await;
+    sleep(Duration::from_millis(100)).await;
+
+    // One trip, with nothing added for the 20 ms tick or the backlog.
+    let up = immediate_latency_behind_a_backlog(&pair.client, &mut pair.server).await;
+    assert_eq!(up, latency);
+    let down = immediate_latency_behind_a_backlog(&pair.server, &mut pair.client).await;
+    assert_eq!(down, latency);
+}
+
+/// A pair whose server gives up on a client that stays more than 64
+/// frames behind for a second, sending it 500 messages over `downlink`.
+async fn backlogged_server(downlink: SimulatedLink) -> Pair {
+    let listener_config = RaknetListenerConfig {
+        backlog_limit: Some(BacklogLimit {
+            max_backlog: 64,
+            grace: Duration::from_secs(1),
+        }),
+        ..Default::default()
+    };
+    let pair = Pair::connect_with(
+        SimulatedLink::new(),
+        listener_config,
+        RaknetStreamConfig::default(),
+    )
+    .await;
+    pair.downlink(downlink);
+    for i in 0..500 {
+        pair.server.send(numbered(i, 1000)).await.unwrap();
+    }
+    pair
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_client_that_stays_behind_is_closed_with_queue_too_long() {
+    // 500kB at 10kB/s would take the client nearly a minute to drain.
+    let mut pair = backlogged_server(SimulatedLink::new().bandwidth(10_000)).await;
+
+    assert!(matches!(
+        recv_error(&mut pair.server).await,
+        RaknetError::Disconnected(DisconnectReason::QueueTooLong)
+    ));
+    // The notification arrives once what was already on the wire has.
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Disconnected(DisconnectReason::QueueTooLong)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_burst_that_drains_within_the_grace_period_is_let_through() {
+    let mut pair = backlogged_server(SimulatedLink::new()).await;
+
+    for i in 0..500 {
+        assert_eq!(number_of(&recv(&mut pair.client).await), i);
+    }
+    assert!(pair.server.is_connected());
+}
+
+#[tokio::test(start_paused = true)]
+async fn send_batch_shares_datagrams_and_keeps_order() {
+    let mut pair = Pair::connect().await;
+    settle().await;
+
+    let before = pair.client.stats().datagrams_sent;
+    let batch = (0..20).map(|i| numbered(i, 40));
+    pair.client.send_batch(batch).await.unwrap();
+    settle().await;
+    let batched = pair.client.stats().datagrams_sent - before;
+    for i in 0..20 {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+
+    let before = pair.client.stats().datagrams_sent;
+    for i in 20..40 {
+        pair.client.send(numbered(i, 40)).await.unwrap();
+        settle().await;
+    }
+    let one_by_one = pair.client.stats().datagrams_sent - before;
+    for i in 20..40 {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+    assert!(batched < one_by_one, "{batched} vs {one_by_one}");
+    assert_eq!(batched, 1);
+
+    // Empty messages, or no messages at all, send nothing.
+    pair.client
+        .send_batch([Message::new(Vec::new())])
+        .await
+        .unwrap();
+    pair.client.send_batch([]).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn expired_messages_are_dropped_instead_of_sent() {
+    let mut pair = Pair::connect().await;
+    pair.client
+        .send(numbered(0, 100).ttl(Duration::ZERO))
+        .await
+        .unwrap();
+    let receipt = pair
+        .client
+        .send_with_receipt(numbered(1, 100).ttl(Duration::ZERO))
+        .await
+        .unwrap();
+    assert!(matches!(
+        timeout(WAIT, receipt).await.unwrap(),
+        Err(RaknetError::MessageLost)
+    ));
+    pair.client
+        .send(numbered(2, 100).ttl(Duration::from_secs(1)))
+        .await
+        .unwrap();
+
+    assert_eq!(number_of(&recv(&mut pair.server).await), 2);
+    assert_eq!(pair.client.stats().messages_expired, 2);
+}
+
+/// A client whose datagrams never reach the server, so nothing it sends
+/// is ever ACKed, with its outgoing queue capped by `policy`.
+async fn unacked_client(policy: QueueLimitPolicy) -> Pair {
+    let client_config = RaknetStreamConfig {
+        send_queue_limit: Some(SendQueueLimit {
+            max_bytes: 16 * 1024,
+            max_frames: 64,
+            policy,
+        }),
+        ..Default::default()
+    };
+    let pair = Pair::connect_with(
+        SimulatedLink::new(),
+        RaknetListenerConfig::default(),
+        client_config,
+    )
+    .await;
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    pair
+}
+
+#[tokio::test(start_paused = true)]
+async fn full_send_queue_rejects_sends_and_stays_bounded() {
+    let pair = unacked_client(QueueLimitPolicy::Reject).await;
+
+    // Every message is split in two, and each part counts.
+    let mut rejected = None;
+    for i in 0..1000 {
+        if let Err(e) = pair.client.send(numbered(i, 2000)).await {
+            rejected = Some(e);
+            break;
+        }
+        settle().await;
+        let usage = pair.client.memory_usage();
+        assert!(usage.outgoing_queue_bytes <= 16 * 1024, "{usage:?}");
+        assert!(pair.client.stats().outgoing_queue_len <= 64);
+    }
+    assert!(matches!(rejected, Some(RaknetError::SendQueueFull)));
+    assert!(matches!(
+        pair.client.try_send(numbered(0, 10)),
+        Err(TrySendError::Full(_))
+    ));
+    assert!(pair.client.is_connected());
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_send_that_overflows_the_queue_is_the_one_that_fails() {
+    let pair = unacked_client(QueueLimitPolicy::Reject).await;
+
+    // Back to back, so the muxer hasn't seen any of them yet.
+    let mut accepted = 0;
+    for i in 0..1000 {
+        match pair.client.send(numbered(i, 2000)).await {
+            Ok(()) => accepted += 1,
+            Err(e) => {
+                assert!(matches!(e, RaknetError::SendQueueFull), "{e:?}");
+                break;
+            }
+        }
+    }
+    settle().await;
+    // Every accepted send was queued; none was dropped behind its back.
+    assert_eq!(pair.client.stats().messages_sent, accepted);
+    assert!(pair.client.stats().outgoing_queue_len <= 64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn full_send_queue_can_close_the_session() {
+    let pair = unacked_client(QueueLimitPolicy::Disconnect).await;
+
+    for i in 0..1000 {
+        if pair.client.send(numbered(i, 2000)).await.is_err() {
+            break;
+        }
+        settle().await;
+    }
+    assert!(matches!(
+        timeout(WAIT, pair.client.closed()).await.unwrap(),
+        DisconnectReason::QueueTooLong
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn broadcast_skips_peers_whose_queue_is_full() {
+    let listener_config = RaknetListenerConfig {
+        send_queue_limit: Some(SendQueueLimit {
+            max_bytes: 16 * 1024,
+            max_frames: 64,
+            policy: QueueLimitPolicy::Reject,
+        }),
+        ..Default::default()
+    };
+    let mut pair = Pair::connect_with(
+        SimulatedLink::new(),
+        listener_config,
+        RaknetStreamConfig::default(),
+    )
+    .await;
+    let (other, _other_conn) = tokio::join!(
+        RaknetStream::connect_on(
+            pair.net.bind_any().unwrap(),
+            pair.listener.local_addr(),
+            RaknetStreamConfig::default(),
+        ),
+        pair.listener.accept()
+    );
+    let mut other = other.unwrap();
+
+    let except = [pair.client.local_addr()];
+    assert_eq!(
+        pair.listener
+            .broadcast_except(numbered(0, 10), &except)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(number_of(&recv(&mut other).await), 0);
+    assert_eq!(pair.listener.broadcast(numbered(1, 10)).await.unwrap(), 2);
+    assert_eq!(number_of(&recv(&mut other).await), 1);
+    assert_eq!(number_of(&recv(&mut pair.client).await), 1);
+
+    // Nothing reaches the client, so its queue fills; the other peer
+    // keeps getting everything.
+    pair.downlink(SimulatedLink::new().loss(1.0));
+    let mut last = None;
+    for i in 2..1000 {
+        let sent = pair.listener.broadcast(numbered(i, 2000)).await.unwrap();
+        assert_eq!(number_of(&recv(&mut other).await), i);
+        if sent == 1 {
+            last = Some(i);
+            break;
+        }
+        assert_eq!(sent, 2);
+    }
+    assert!(last.is_some(), "queue never filled");
+    assert!(pair.server.stats().outgoing_queue_len <= 64);
+}
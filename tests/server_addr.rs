@@ -0,0 +1,107 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::{DEFAULT_UNCONNECTED_MAGIC, RAKNET_PROTOCOL_VERSION};
+use tokio_raknet::protocol::packet::{
+    OpenConnectionRequest1, OpenConnectionRequest2, RaknetPacket,
+};
+use tokio_raknet::protocol::types::EoBPadding;
+use tokio_raknet::transport::listener::RaknetListenerConfig;
+
+/// Handshake claiming to talk to `echoed`; returns the reply to OCR2.
+async fn handshake_echoing(peer: &RawPeer, echoed: SocketAddr) -> Option<RaknetPacket> {
+    peer.send_packet(RaknetPacket::OpenConnectionRequest1(
+        OpenConnectionRequest1 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            protocol_version: RAKNET_PROTOCOL_VERSION,
+            padding: EoBPadding(1400 - 46),
+        },
+    ))
+    .await;
+    let Some(RaknetPacket::OpenConnectionReply1(reply1)) =
+        peer.recv_packet(Duration::from_secs(1)).await
+    else {
+        panic!("expected OpenConnectionReply1");
+    };
+    peer.send_packet(RaknetPacket::OpenConnectionRequest2(
+        OpenConnectionRequest2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            cookie: reply1.cookie,
+            client_proof: true,
+            server_addr: echoed,
+            mtu: reply1.mtu,
+            client_guid: 9,
+        },
+    ))
+    .await;
+    peer.recv_packet(Duration::from_secs(1)).await
+}
+
+async fn listener(strict: bool) -> RaknetListener {
+    let config = RaknetListenerConfig {
+        strict_server_addr: strict,
+        ..Default::default()
+    };
+    RaknetListener::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap()
+}
+
+fn other_port(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip(), addr.port().wrapping_add(1))
+}
+
+#[tokio::test]
+async fn strict_mode_accepts_a_matching_address() {
+    let listener = listener(true).await;
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    let reply = handshake_echoing(&peer, listener.local_addr()).await;
+    assert!(matches!(reply, Some(RaknetPacket::OpenConnectionReply2(_))));
+    assert_eq!(listener.stats().sessions, 1);
+}
+
+#[tokio::test]
+async fn strict_mode_refuses_a_port_mismatch() {
+    let listener = listener(true).await;
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    let reply = handshake_echoing(&peer, other_port(listener.local_addr())).await;
+    assert!(matches!(
+        reply,
+        Some(RaknetPacket::ConnectionRequestFailed(_))
+    ));
+    assert_eq!(listener.stats().sessions, 0);
+}
+
+#[tokio::test]
+async fn unspecified_bind_in_strict_mode_ignores_the_ip() {
+    let config = RaknetListenerConfig {
+        strict_server_addr: true,
+        ..Default::default()
+    };
+    let listener = RaknetListener::bind_with_config("0.0.0.0:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let port = listener.local_addr().port();
+    let peer = RawPeer::new(SocketAddr::from(([127, 0, 0, 1], port))).await;
+
+    // A public address the server can't see but with the right port.
+    let public = SocketAddr::from(([203, 0, 113, 9], port));
+    let reply = handshake_echoing(&peer, public).await;
+    assert!(matches!(reply, Some(RaknetPacket::OpenConnectionReply2(_))));
+}
+
+#[tokio::test]
+async fn lenient_mode_only_logs_a_mismatch() {
+    let listener = listener(false).await;
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    let reply = handshake_echoing(&peer, other_port(listener.local_addr())).await;
+    assert!(matches!(reply, Some(RaknetPacket::OpenConnectionReply2(_))));
+    assert_eq!(listener.stats().sessions, 1);
+}
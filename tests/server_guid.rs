@@ -0,0 +1,93 @@
+mod common;
+
+use std::time::Duration;
+
+use common::RawPeer;
+use tokio_raknet::RaknetListener;
+use tokio_raknet::protocol::constants::{DEFAULT_UNCONNECTED_MAGIC, RAKNET_PROTOCOL_VERSION};
+use tokio_raknet::protocol::packet::{OpenConnectionRequest1, RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::types::{EoBPadding, RaknetTime};
+use tokio_raknet::transport::RaknetStream;
+
+const SERVER_GUID: u64 = 0x0bed_70c4_5e7e_0001;
+
+/// The GUID of the listener's pong, and the one in its Bedrock MOTD.
+async fn pong_guids(peer: &RawPeer) -> (u64, u64) {
+    peer.send_packet(RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(1),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    }))
+    .await;
+    let Some(RaknetPacket::UnconnectedPong(pong)) = peer.recv_packet(Duration::from_secs(1)).await
+    else {
+        panic!("no pong");
+    };
+    let motd = String::from_utf8(pong.advertisement.0.unwrap().to_vec()).unwrap();
+    let motd_guid = motd.split(';').nth(6).unwrap().parse().unwrap();
+    (pong.server_guid, motd_guid)
+}
+
+/// The GUID a listener refuses an unknown protocol version with.
+async fn incompatible_protocol_guid(peer: &RawPeer) -> u64 {
+    peer.send_packet(RaknetPacket::OpenConnectionRequest1(
+        OpenConnectionRequest1 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            protocol_version: RAKNET_PROTOCOL_VERSION + 1,
+            padding: EoBPadding(1000),
+        },
+    ))
+    .await;
+    match peer.recv_packet(Duration::from_secs(1)).await {
+        Some(RaknetPacket::IncompatibleProtocolVersion(reply)) => reply.server_guid,
+        other => panic!("expected IncompatibleProtocolVersion, got {other:?}"),
+    }
+}
+
+/// Every GUID `listener` hands out, from pings, the offline handshake and a
+/// real client connecting.
+async fn advertised_guids(listener: &mut RaknetListener) -> Vec<u64> {
+    let peer = RawPeer::new(listener.local_addr()).await;
+    let (pong, motd) = pong_guids(&peer).await;
+    let refused = incompatible_protocol_guid(&peer).await;
+    let Some(RaknetPacket::OpenConnectionReply2(reply2)) = peer.open_connection(1400, 7).await
+    else {
+        panic!("expected OpenConnectionReply2");
+    };
+
+    let (client, server) = tokio::join!(
+        RaknetStream::connect(listener.local_addr()),
+        listener.accept()
+    );
+    let (client, _server) = (client.unwrap(), server.unwrap());
+    vec![
+        pong,
+        motd,
+        refused,
+        reply2.server_guid,
+        client.server_guid().unwrap(),
+    ]
+}
+
+#[tokio::test]
+async fn configured_guid_is_used_everywhere() {
+    let mut listener = RaknetListener::builder("127.0.0.1:0".parse().unwrap())
+        .server_guid(SERVER_GUID)
+        .bind()
+        .await
+        .unwrap();
+    assert_eq!(listener.guid(), SERVER_GUID);
+    for guid in advertised_guids(&mut listener).await {
+        assert_eq!(guid, SERVER_GUID);
+    }
+}
+
+#[tokio::test]
+async fn default_guid_agrees_with_the_default_advertisement() {
+    let mut listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let guid = listener.guid();
+    for advertised in advertised_guids(&mut listener).await {
+        assert_eq!(advertised, guid);
+    }
+}
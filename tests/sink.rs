@@ -0,0 +1,84 @@
+//! `RaknetStream` as a `futures` `Stream` and `Sink`, end to end over the
+//! in-memory network.
+
+mod pair;
+
+use pair::{Pair, WAIT, number_of, numbered, recv, recv_error, settle};
+use tokio::time::timeout;
+use tokio_raknet::RaknetError;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::transport::Message;
+
+#[tokio::test(start_paused = true)]
+async fn stream_and_sink_drive_a_connection() {
+    use futures::{SinkExt, StreamExt};
+
+    let pair = Pair::connect().await;
+    let (mut sink, client_rx) = pair.client.split();
+    let (mut server_tx, mut server_rx) = pair.server.split();
+
+    let mut outgoing = futures::stream::iter((0..50).map(|i| Ok(numbered(i, 100))));
+    sink.send_all(&mut outgoing).await.unwrap();
+    for i in 0..50 {
+        let msg = timeout(WAIT, server_rx.next()).await.unwrap().unwrap();
+        assert_eq!(number_of(&msg.unwrap()), i);
+    }
+
+    // Empty messages are skipped, as with `send`.
+    sink.send(Message::new(Vec::new())).await.unwrap();
+    sink.send(numbered(50, 100)).await.unwrap();
+    let msg = timeout(WAIT, server_rx.next()).await.unwrap().unwrap();
+    assert_eq!(number_of(&msg.unwrap()), 50);
+
+    // The reply comes back through the server's sink.
+    server_tx.send(numbered(51, 100)).await.unwrap();
+    let mut client = sink.reunite(client_rx).unwrap();
+    assert_eq!(number_of(&recv(&mut client).await), 51);
+}
+
+#[tokio::test(start_paused = true)]
+async fn sink_waits_for_room_in_a_full_queue() {
+    use futures::{FutureExt, SinkExt};
+
+    let mut pair = Pair::connect().await;
+
+    // The muxer can't drain the queue until this task yields, so it
+    // eventually fills and `feed` has to wait instead of failing.
+    let mut fed = 0;
+    loop {
+        match pair.client.feed(numbered(fed, 10)).now_or_never() {
+            Some(res) => res.unwrap(),
+            None => break,
+        }
+        fed += 1;
+        assert!(fed <= 100_000, "the queue never filled");
+    }
+    let last = pair.client.feed(numbered(fed, 10));
+    timeout(WAIT, last).await.unwrap().unwrap();
+
+    for i in 0..=fed {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn sink_fails_once_the_session_is_gone() {
+    use futures::SinkExt;
+
+    let mut pair = Pair::connect().await;
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Disconnected(DisconnectReason::Disconnected)
+    ));
+    settle().await;
+
+    let res = SinkExt::send(&mut pair.server, numbered(0, 10));
+    assert!(matches!(
+        timeout(WAIT, res).await.unwrap(),
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
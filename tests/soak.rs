@@ -0,0 +1,484 @@
+//! Long-running churn over the in-memory transport: one listener and 200
+//! clients that connect, trade traffic, disconnect or simply vanish, and come
+//! back, while the listener's bookkeeping is checked throughout.
+//!
+//! Ignored by default; the nightly CI job runs it with
+//! `cargo test --release --features testing --test soak -- --ignored`.
+//! `SOAK_SECS` sets how much simulated time the churn lasts (default 180).
+
+use std::collections::{HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, interval, sleep, sleep_until, timeout};
+use tokio_raknet::protocol::constants::DEFAULT_UNCONNECTED_MAGIC;
+use tokio_raknet::protocol::packet::{RaknetPacket, UnconnectedPing};
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::types::RaknetTime;
+use tokio_raknet::transport::memory::{Jitter, MemoryNetwork, SimulatedLink};
+use tokio_raknet::transport::{
+    DatagramSocket, Message, RaknetListener, RaknetListenerConfig, RaknetStream, RaknetStreamConfig,
+};
+use tokio_util::sync::CancellationToken;
+
+const CLIENTS: usize = 200;
+const CHANNELS: u8 = 4;
+const SERVER: &str = "10.0.0.1:19132";
+/// Bytes one session may buffer at any time, on either side.
+const MEMORY_BUDGET: usize = 512 * 1024;
+/// How long the listener may keep a session after its client left: the
+/// session timeout for clients that vanished, plus slack.
+const LINGER: Duration = Duration::from_secs(15);
+/// How long a graceful close waits for the server to confirm it got everything.
+const FIN_WAIT: Duration = Duration::from_secs(30);
+
+/// Every message starts with the user-packet ID, then its kind.
+const ORDERED: u8 = 0;
+const RELIABLE: u8 = 1;
+const UNRELIABLE: u8 = 2;
+/// Client is done sending; carries how many reliable messages it sent.
+const FIN: u8 = 3;
+/// Server got everything the FIN announced; sent once on every channel.
+const FIN_ACK: u8 = 4;
+
+#[tokio::test(start_paused = true)]
+#[ignore = "several minutes of simulated churn; run nightly with --ignored"]
+async fn two_hundred_clients_churn_without_leaking() {
+    let soak = Duration::from_secs(
+        std::env::var("SOAK_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(180),
+    );
+    let net = MemoryNetwork::with_seed(0x50a4);
+    net.set_default_link(
+        SimulatedLink::new()
+            .loss(0.02)
+            .latency(Duration::from_millis(15))
+            .jitter(Jitter::Uniform(Duration::from_millis(10)))
+            .reorder(0.02, Duration::from_millis(40)),
+    );
+    let server: SocketAddr = SERVER.parse().unwrap();
+    let shutdown = CancellationToken::new();
+    let mut listener = RaknetListener::with_socket(
+        net.bind(server).unwrap(),
+        RaknetListenerConfig {
+            shutdown: Some(shutdown.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let population = Arc::new(Population::default());
+    let deadline = Instant::now() + soak;
+    let mut clients = JoinSet::new();
+    for index in 0..CLIENTS {
+        clients.spawn(client(net.clone(), index, population.clone(), deadline));
+    }
+    let mut servers = JoinSet::new();
+    let mut checks = interval(Duration::from_millis(100));
+    let mut peak_sessions = 0;
+
+    // Churn until every client has finished its last connection.
+    while !clients.is_empty() {
+        tokio::select! {
+            Some(stream) = listener.accept() => {
+                servers.spawn(serve(stream));
+            }
+            Some(res) = clients.join_next() => propagate(res),
+            Some(res) = servers.join_next() => propagate(res),
+            _ = checks.tick() => {
+                let stats = listener.stats();
+                let bound = population.bound();
+                peak_sessions = peak_sessions.max(stats.sessions);
+                assert!(
+                    stats.sessions <= bound,
+                    "{} sessions for at most {bound} live or recently gone peers",
+                    stats.sessions
+                );
+                assert!(
+                    stats.pending_handshakes <= bound,
+                    "{} pending handshakes for at most {bound} peers",
+                    stats.pending_handshakes
+                );
+                let memory = listener.memory_usage().total();
+                assert!(
+                    memory <= stats.sessions.max(1) * MEMORY_BUDGET,
+                    "{memory} bytes buffered across {} sessions",
+                    stats.sessions
+                );
+            }
+        }
+    }
+
+    // Vanished peers time out and their pending handshakes expire.
+    let quiet = Instant::now() + LINGER;
+    while Instant::now() < quiet {
+        tokio::select! {
+            Some(stream) = listener.accept() => panic!("unexpected connection from {}", stream.peer_addr()),
+            Some(res) = servers.join_next() => propagate(res),
+            _ = sleep_until(quiet) => {}
+        }
+    }
+    // Expired handshakes are only dropped when an offline packet comes in.
+    ping(&net, server).await;
+    let stats = listener.stats();
+    assert_eq!(stats.sessions, 0);
+    assert_eq!(stats.pending_handshakes, 0);
+    assert_eq!(listener.memory_usage().total(), 0);
+
+    shutdown.cancel();
+    assert!(timeout(LINGER, listener.accept()).await.unwrap().is_none());
+    while let Some(res) = servers.join_next().await {
+        propagate(res);
+    }
+    drop(listener);
+    sleep(LINGER).await;
+    assert_eq!(
+        tokio::runtime::Handle::current()
+            .metrics()
+            .num_alive_tasks(),
+        0,
+        "tasks still running after every peer left"
+    );
+
+    let connects = population.connects.load(Ordering::Relaxed);
+    let failed = population.failed_connects.load(Ordering::Relaxed);
+    let verified = population.verified_closes.load(Ordering::Relaxed);
+    eprintln!(
+        "{connects} connections ({failed} failed handshakes, {verified} verified closes), \
+         peak {peak_sessions} sessions"
+    );
+    assert!(connects >= CLIENTS as u64);
+    assert!(
+        failed * 20 <= connects,
+        "{failed} of {connects} handshakes failed"
+    );
+    assert!(verified > 0);
+}
+
+/// Who is connected, and who left recently enough that the listener may
+/// still be holding on to them.
+#[derive(Default)]
+struct Population {
+    live: AtomicUsize,
+    departures: Mutex<VecDeque<Instant>>,
+    connects: AtomicU64,
+    failed_connects: AtomicU64,
+    verified_closes: AtomicU64,
+}
+
+impl Population {
+    fn arrive(&self) {
+        self.live.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn depart(&self) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        self.departures.lock().unwrap().push_back(Instant::now());
+    }
+
+    /// Most sessions the listener may legitimately be tracking.
+    fn bound(&self) -> usize {
+        let mut departures = self.departures.lock().unwrap();
+        while departures.front().is_some_and(|at| at.elapsed() > LINGER) {
+            departures.pop_front();
+        }
+        self.live.load(Ordering::Relaxed) + departures.len()
+    }
+}
+
+/// One client: a series of connections from fresh ports on its own IP until
+/// `deadline`.
+async fn client(net: MemoryNetwork, index: usize, population: Arc<Population>, deadline: Instant) {
+    let mut rng = Rng(index as u64 + 1);
+    let ip = Ipv4Addr::new(10, 1, (index / 250) as u8, (index % 250) as u8 + 1);
+    let server: SocketAddr = SERVER.parse().unwrap();
+    // Don't all knock at once.
+    sleep(rng.millis(0..2_000)).await;
+
+    while Instant::now() < deadline {
+        let socket = net.bind(SocketAddr::from((ip, 0))).unwrap();
+        let local = socket.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let config = RaknetStreamConfig {
+            shutdown: Some(shutdown.clone()),
+            ..Default::default()
+        };
+        population.arrive();
+        population.connects.fetch_add(1, Ordering::Relaxed);
+        let mut stream = match RaknetStream::connect_on(socket, server, config).await {
+            Ok(stream) => stream,
+            Err(_) => {
+                population.failed_connects.fetch_add(1, Ordering::Relaxed);
+                population.depart();
+                sleep(rng.millis(100..1_000)).await;
+                continue;
+            }
+        };
+
+        let mut conn = Connection::new(local);
+        let life = Instant::now() + rng.millis(1_000..20_000);
+        while Instant::now() < life {
+            let next = Instant::now() + rng.millis(10..200);
+            conn.send_random(&stream, &mut rng).await;
+            loop {
+                tokio::select! {
+                    _ = sleep_until(next) => break,
+                    msg = stream.recv_msg() => match msg {
+                        Some(Ok(msg)) => conn.check_echo(msg.buffer),
+                        other => panic!("{local}: connection dropped mid-life: {other:?}"),
+                    },
+                }
+            }
+            let memory = stream.memory_usage().total();
+            assert!(memory <= MEMORY_BUDGET, "{local}: {memory} bytes buffered");
+        }
+
+        match rng.below(10) {
+            // Graceful: make sure the server saw every reliable message.
+            0..6 => {
+                conn.finish(&mut stream).await;
+                population.verified_closes.fetch_add(1, Ordering::Relaxed);
+                close(shutdown, &mut stream).await;
+            }
+            // Abrupt: whatever is still in flight may be lost.
+            6..8 => close(shutdown, &mut stream).await,
+            // Vanish without a word; the listener has to time the session out.
+            _ => {
+                net.set_link_both(local, server, SimulatedLink::new().loss(1.0));
+                drop(stream);
+            }
+        }
+        population.depart();
+        sleep(rng.millis(0..3_000)).await;
+    }
+}
+
+async fn close(shutdown: CancellationToken, stream: &mut RaknetStream) {
+    shutdown.cancel();
+    timeout(LINGER, async { while stream.recv_msg().await.is_some() {} })
+        .await
+        .expect("closed connection never ended");
+}
+
+/// What a client has sent and had echoed back on one connection.
+struct Connection {
+    local: SocketAddr,
+    sent: [u32; CHANNELS as usize],
+    echoed: [u32; CHANNELS as usize],
+    reliable: u32,
+    unreliable: u32,
+    fin_acks: u8,
+}
+
+impl Connection {
+    fn new(local: SocketAddr) -> Self {
+        Self {
+            local,
+            sent: [0; CHANNELS as usize],
+            echoed: [0; CHANNELS as usize],
+            reliable: 0,
+            unreliable: 0,
+            fin_acks: 0,
+        }
+    }
+
+    async fn send_random(&mut self, stream: &RaknetStream, rng: &mut Rng) {
+        // Mostly small messages; now and then one that has to be split.
+        let padding = if rng.below(20) == 0 {
+            1_500 + rng.below(3_000) as usize
+        } else {
+            rng.below(200) as usize
+        };
+        let msg = match rng.below(4) {
+            0 | 1 => {
+                let channel = rng.below(CHANNELS as u64) as u8;
+                let seq = &mut self.sent[channel as usize];
+                *seq += 1;
+                Message::new(frame(ORDERED, channel, *seq - 1, padding))
+                    .reliability(Reliability::ReliableOrdered)
+                    .channel(channel)
+            }
+            2 => {
+                self.reliable += 1;
+                Message::new(frame(RELIABLE, 0, self.reliable - 1, padding))
+                    .reliability(Reliability::Reliable)
+            }
+            _ => {
+                self.unreliable += 1;
+                Message::new(frame(UNRELIABLE, 0, self.unreliable - 1, padding))
+                    .reliability(Reliability::Unreliable)
+            }
+        };
+        stream.send(msg).await.unwrap();
+    }
+
+    fn check_echo(&mut self, buffer: Bytes) {
+        let (kind, channel, seq) = parse(&buffer);
+        match kind {
+            ORDERED => {
+                let expected = &mut self.echoed[channel as usize];
+                assert_eq!(
+                    seq, *expected,
+                    "{}: echo on channel {channel} out of order or repeated",
+                    self.local
+                );
+                *expected += 1;
+            }
+            FIN_ACK => self.fin_acks += 1,
+            other => panic!("{}: unexpected message kind {other}", self.local),
+        }
+    }
+
+    /// Announce what was sent and wait until the server has received all of
+    /// it and every echo has come back.
+    async fn finish(&mut self, stream: &mut RaknetStream) {
+        let mut fin = BytesMut::from(&frame(FIN, 0, 0, 0)[..]);
+        for sent in self.sent {
+            fin.put_u32_le(sent);
+        }
+        fin.put_u32_le(self.reliable);
+        stream
+            .send(Message::new(fin.freeze()).reliability(Reliability::ReliableOrdered))
+            .await
+            .unwrap();
+
+        let local = self.local;
+        timeout(FIN_WAIT, async {
+            // FIN_ACK follows the last echo on each channel.
+            while self.fin_acks < CHANNELS {
+                match stream.recv_msg().await {
+                    Some(Ok(msg)) => self.check_echo(msg.buffer),
+                    other => panic!("{local}: connection dropped before FIN_ACK: {other:?}"),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{local}: server never confirmed the reliable messages"));
+        assert_eq!(self.echoed, self.sent, "{local}: echoes lost");
+    }
+}
+
+/// Server side of one connection: check what arrives and echo ordered
+/// messages back.
+async fn serve(mut stream: RaknetStream) {
+    let peer = stream.peer_addr();
+    let mut next = [0u32; CHANNELS as usize];
+    let mut reliable = HashSet::new();
+    let mut fin: Option<([u32; CHANNELS as usize], usize)> = None;
+    let mut acked = false;
+
+    while let Some(Ok(msg)) = stream.recv_msg().await {
+        let (kind, channel, seq) = parse(&msg.buffer);
+        match kind {
+            ORDERED => {
+                assert_eq!(msg.reliability, Reliability::ReliableOrdered);
+                assert_eq!(msg.channel, channel);
+                let expected = &mut next[channel as usize];
+                assert_eq!(
+                    seq, *expected,
+                    "{peer}: channel {channel} out of order or repeated"
+                );
+                *expected += 1;
+                let echo = Message::new(msg.buffer)
+                    .reliability(Reliability::ReliableOrdered)
+                    .channel(channel);
+                if stream.send(echo).await.is_err() {
+                    break;
+                }
+            }
+            RELIABLE => assert!(reliable.insert(seq), "{peer}: reliable {seq} repeated"),
+            UNRELIABLE => {}
+            FIN => {
+                let mut counts = &msg.buffer[7..];
+                let mut sent = [0; CHANNELS as usize];
+                for sent in &mut sent {
+                    *sent = counts.get_u32_le();
+                }
+                fin = Some((sent, counts.get_u32_le() as usize));
+            }
+            other => panic!("{peer}: unexpected message kind {other}"),
+        }
+
+        if !acked && fin == Some((next, reliable.len())) {
+            acked = true;
+            for channel in 0..CHANNELS {
+                let ack = Message::new(frame(FIN_ACK, channel, 0, 0))
+                    .reliability(Reliability::ReliableOrdered)
+                    .channel(channel);
+                if stream.send(ack).await.is_err() {
+                    return;
+                }
+            }
+        }
+        let memory = stream.memory_usage().total();
+        assert!(memory <= MEMORY_BUDGET, "{peer}: {memory} bytes buffered");
+    }
+}
+
+/// Answer to a ping, which also makes the listener prune expired handshakes.
+async fn ping(net: &MemoryNetwork, server: SocketAddr) {
+    let socket = net.bind("10.2.0.1:0".parse().unwrap()).unwrap();
+    net.set_link_both(socket.local_addr().unwrap(), server, SimulatedLink::new());
+    let mut ping = BytesMut::new();
+    RaknetPacket::UnconnectedPing(UnconnectedPing {
+        ping_time: RaknetTime(0),
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+    })
+    .encode(&mut ping)
+    .unwrap();
+    socket.send_to(&ping, server).await.unwrap();
+    let mut buf = [0u8; 2048];
+    let (_, from) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+        .await
+        .expect("no pong")
+        .unwrap();
+    assert_eq!(from, server);
+}
+
+fn frame(kind: u8, channel: u8, seq: u32, padding: usize) -> Bytes {
+    let mut out = BytesMut::with_capacity(7 + padding);
+    out.put_u8(0xfe);
+    out.put_u8(kind);
+    out.put_u8(channel);
+    out.put_u32_le(seq);
+    out.put_bytes(0, padding);
+    out.freeze()
+}
+
+fn parse(buffer: &[u8]) -> (u8, u8, u32) {
+    assert_eq!(buffer[0], 0xfe);
+    let seq = u32::from_le_bytes(buffer[3..7].try_into().unwrap());
+    (buffer[1], buffer[2], seq)
+}
+
+fn propagate(res: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = res {
+        std::panic::resume_unwind(e.into_panic());
+    }
+}
+
+/// xorshift64*, so every client's behaviour is reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn millis(&mut self, range: std::ops::Range<u64>) -> Duration {
+        Duration::from_millis(range.start + self.below(range.end - range.start))
+    }
+}
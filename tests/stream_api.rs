@@ -0,0 +1,470 @@
+//! The `RaknetStream` and `RaknetSender` send and receive API end to end
+//! over the in-memory network: non-blocking and polled variants, flushes
+//! and delivery receipts.
+
+mod pair;
+
+use std::time::Duration;
+
+use pair::{Pair, WAIT, number_of, numbered, recv, recv_error, settle};
+use tokio::time::{sleep, timeout};
+use tokio_raknet::protocol::constants::MAXIMUM_ORDERING_CHANNELS;
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::state::DisconnectReason;
+use tokio_raknet::transport::memory::SimulatedLink;
+use tokio_raknet::transport::{RaknetListenerConfig, RaknetStreamConfig};
+use tokio_raknet::{RaknetError, RecvTimeoutError, TrySendError};
+
+#[tokio::test(start_paused = true)]
+async fn cloned_senders_share_the_connection() {
+    let mut pair = Pair::connect().await;
+
+    let tasks: Vec<_> = (0..4)
+        .map(|i| {
+            let sender = pair.client.sender();
+            tokio::spawn(async move { sender.send(numbered(i, 100)).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    let mut got: Vec<_> = Vec::new();
+    for _ in 0..4 {
+        got.push(number_of(&recv(&mut pair.server).await));
+    }
+    got.sort();
+    assert_eq!(got, [0, 1, 2, 3]);
+
+    // With every sender gone the stream carries on.
+    settle().await;
+    pair.client.send(numbered(4, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 4);
+    pair.server.send(numbered(5, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.client).await), 5);
+}
+
+#[tokio::test(start_paused = true)]
+async fn senders_fail_once_the_session_is_gone() {
+    let mut pair = Pair::connect().await;
+    let to_client = pair.server.sender();
+    let to_server = pair.client.sender();
+    assert_eq!(to_client.peer_addr(), pair.client.local_addr());
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    assert!(matches!(
+        recv_error(&mut pair.client).await,
+        RaknetError::Disconnected(DisconnectReason::Disconnected)
+    ));
+    settle().await;
+
+    // The listener's muxer is still running, but the session is not.
+    assert!(matches!(
+        timeout(WAIT, to_client.send("late")).await.unwrap(),
+        Err(RaknetError::ConnectionClosed)
+    ));
+    assert!(matches!(
+        timeout(WAIT, to_server.send("late")).await.unwrap(),
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn only_the_protocols_ordering_channels_can_be_sent_on() {
+    let mut pair = Pair::connect().await;
+    let last = MAXIMUM_ORDERING_CHANNELS - 1;
+
+    pair.client
+        .send(numbered(0, 10).channel(last))
+        .await
+        .unwrap();
+    let msg = recv(&mut pair.server).await;
+    assert_eq!((number_of(&msg), msg.channel), (0, last));
+
+    let bad = || numbered(1, 10).channel(MAXIMUM_ORDERING_CHANNELS);
+    let refused = |res: Result<(), RaknetError>| {
+        matches!(
+            res,
+            Err(RaknetError::InvalidChannel { channel }) if channel == MAXIMUM_ORDERING_CHANNELS
+        )
+    };
+    assert!(refused(pair.client.send(bad()).await));
+    assert!(refused(
+        pair.client.send_with_receipt(bad()).await.map(drop)
+    ));
+    assert!(refused(
+        pair.client.send_batch([numbered(2, 10), bad()]).await
+    ));
+    assert!(refused(pair.listener.broadcast(bad()).await.map(drop)));
+    let Err(TrySendError::InvalidChannel(msg)) = pair.client.try_send(bad()) else {
+        panic!("try_send took a message on a missing channel");
+    };
+    assert_eq!(msg.channel, MAXIMUM_ORDERING_CHANNELS);
+
+    // Nothing from the refused batch went out, and the session is fine.
+    pair.client.send(numbered(3, 10)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn try_send_hands_the_message_back_when_full() {
+    let client_config = RaknetStreamConfig {
+        outbound_queue_capacity: 4,
+        ..Default::default()
+    };
+    let mut pair = Pair::connect_with(
+        SimulatedLink::new(),
+        RaknetListenerConfig::default(),
+        client_config,
+    )
+    .await;
+
+    // Nothing drains the queue until this task yields.
+    for i in 0..4 {
+        pair.client.try_send(numbered(i, 10)).unwrap();
+    }
+    let full = pair.client.try_send(numbered(4, 10)).unwrap_err();
+    assert!(matches!(full, TrySendError::Full(_)));
+    let msg = full.into_inner();
+    assert_eq!(msg.buffer, numbered(4, 10).buffer);
+
+    settle().await;
+    pair.client.try_send(msg).unwrap();
+    for i in 0..5 {
+        assert_eq!(number_of(&recv(&mut pair.server).await), i);
+    }
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    settle().await;
+    assert!(matches!(
+        pair.server.try_send(numbered(5, 10)),
+        Err(TrySendError::Closed(_))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn try_recv_polls_without_waiting() {
+    let mut pair = Pair::connect().await;
+
+    // A quiet, healthy connection is just empty.
+    assert!(pair.server.try_recv().unwrap().is_none());
+    sleep(Duration::from_secs(5)).await;
+    assert!(pair.server.try_recv().unwrap().is_none());
+
+    for i in 0..3 {
+        pair.client.send(numbered(i, 100)).await.unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+    let first = pair.server.try_recv().unwrap().unwrap();
+    assert_eq!(number_of(&first), 0);
+    assert_eq!(number_of(&recv(&mut pair.server).await), 1);
+    let third = pair.server.try_recv().unwrap().unwrap();
+    assert_eq!(number_of(&third), 2);
+    assert!(pair.server.try_recv().unwrap().is_none());
+    assert_eq!(pair.server.memory_usage().incoming_channel_bytes, 0);
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert!(matches!(
+        pair.client.try_recv(),
+        Err(RaknetError::Disconnected(DisconnectReason::Disconnected))
+    ));
+    assert!(matches!(
+        pair.client.try_recv(),
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn recv_timeout_keeps_a_message_that_lands_at_the_deadline() {
+    let mut pair = Pair::connect().await;
+    pair.downlink(SimulatedLink::new().latency(Duration::from_millis(100)));
+
+    pair.server.send(numbered(0, 10)).await.unwrap();
+    let res = pair.client.recv_timeout(Duration::from_millis(99)).await;
+    assert!(matches!(res, Err(RecvTimeoutError::TimedOut)));
+    assert_eq!(number_of(&recv(&mut pair.client).await), 0);
+
+    // Whichever of the deadline and the message wins, the message is
+    // either returned or still there for the next receive.
+    for (i, wait) in [(1, 100), (2, 101)] {
+        pair.server.send(numbered(i, 10)).await.unwrap();
+        let msg = match pair.client.recv_timeout(Duration::from_millis(wait)).await {
+            Ok(Some(msg)) => msg,
+            Err(RecvTimeoutError::TimedOut) => recv(&mut pair.client).await,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(number_of(&msg), i);
+    }
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    assert!(matches!(
+        pair.client.recv_timeout(WAIT).await,
+        Err(RecvTimeoutError::Disconnected(
+            DisconnectReason::Disconnected
+        ))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn recv_many_drains_a_burst_in_one_call() {
+    let mut pair = Pair::connect().await;
+
+    for i in 0..60 {
+        pair.client.send(numbered(i, 100)).await.unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+    let mut buf = Vec::new();
+    assert_eq!(pair.server.recv_many(&mut buf, 50).await.unwrap(), 50);
+    assert_eq!(pair.server.recv_many(&mut buf, 50).await.unwrap(), 10);
+    let order: Vec<_> = buf.iter().map(number_of).collect();
+    assert_eq!(order, (0..60).collect::<Vec<_>>());
+    assert_eq!(pair.server.memory_usage().incoming_channel_bytes, 0);
+
+    // A disconnect behind queued data ends the batch and comes next.
+    for i in 0..3 {
+        pair.server.send(numbered(i, 100)).await.unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    buf.clear();
+    assert_eq!(pair.client.recv_many(&mut buf, 50).await.unwrap(), 3);
+    assert!(matches!(
+        pair.client.recv_many(&mut buf, 50).await,
+        Err(RaknetError::Disconnected(DisconnectReason::Disconnected))
+    ));
+    assert_eq!(pair.client.recv_many(&mut buf, 50).await.unwrap(), 0);
+    assert_eq!(buf.len(), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn poll_recv_and_poll_send_ready_by_hand() {
+    use std::task::{Context, Poll, Waker};
+
+    let mut pair = Pair::connect().await;
+    let mut cx = Context::from_waker(Waker::noop());
+
+    // Polling an empty queue and giving up loses nothing.
+    assert!(pair.server.poll_recv(&mut cx).is_pending());
+    assert!(matches!(
+        pair.client.start_send(numbered(0, 100)),
+        Err(RaknetError::SendNotReserved)
+    ));
+    assert!(matches!(
+        pair.client.poll_send_ready(&mut cx),
+        Poll::Ready(Ok(()))
+    ));
+    // A second poll keeps the same reservation.
+    assert!(matches!(
+        pair.client.poll_send_ready(&mut cx),
+        Poll::Ready(Ok(()))
+    ));
+    pair.client.start_send(numbered(0, 100)).unwrap();
+    assert!(matches!(
+        pair.client.start_send(numbered(1, 100)),
+        Err(RaknetError::SendNotReserved)
+    ));
+
+    let msg = std::future::poll_fn(|cx| pair.server.poll_recv(cx)).await;
+    assert_eq!(number_of(&msg.unwrap().unwrap()), 0);
+    assert!(pair.server.poll_recv(&mut cx).is_pending());
+}
+
+#[tokio::test(start_paused = true)]
+async fn flush_waits_until_sent_data_is_acked() {
+    let mut pair = Pair::connect().await;
+    // Let the handshake's own datagrams be ACKed.
+    sleep(Duration::from_millis(100)).await;
+    // Nothing outstanding: done straight away.
+    timeout(Duration::from_millis(10), pair.client.flush())
+        .await
+        .unwrap()
+        .unwrap();
+
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(0, 3000)).await.unwrap();
+    {
+        let flush = pair.client.flush();
+        tokio::pin!(flush);
+        assert!(
+            timeout(Duration::from_millis(500), &mut flush)
+                .await
+                .is_err()
+        );
+
+        pair.uplink(SimulatedLink::new());
+        timeout(WAIT, flush).await.unwrap().unwrap();
+    }
+    let stats = pair.client.stats();
+    assert_eq!((stats.outgoing_queue_len, stats.unacked_datagrams), (0, 0));
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+
+    // The server side too.
+    pair.server.send(numbered(1, 100)).await.unwrap();
+    timeout(WAIT, pair.server.flush()).await.unwrap().unwrap();
+    assert_eq!(number_of(&recv(&mut pair.client).await), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn flush_does_not_wait_for_messages_sent_after_it() {
+    let mut pair = Pair::connect().await;
+    // ACKs take a while, so something is always in flight below.
+    pair.uplink(SimulatedLink::new().latency(Duration::from_millis(20)));
+    pair.client.send(numbered(0, 3000)).await.unwrap();
+    let flush = tokio::spawn({
+        let sender = pair.client.sender();
+        async move { sender.flush().await }
+    });
+    settle().await;
+
+    // Keep the queue and the window busy from then on.
+    let sender = pair.client.sender();
+    let busy = tokio::spawn(async move {
+        for i in 1.. {
+            if sender.send(numbered(i, 3000)).await.is_err() {
+                break;
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+    });
+    timeout(WAIT, flush).await.unwrap().unwrap().unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+    busy.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn flush_fails_if_the_connection_dies_first() {
+    let pair = Pair::connect().await;
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    pair.client.send(numbered(0, 100)).await.unwrap();
+    let sender = pair.client.sender();
+    let flush = tokio::spawn(async move { sender.flush().await });
+    settle().await;
+    assert!(!flush.is_finished());
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::Disconnected)
+        .await
+        .unwrap();
+    assert!(matches!(
+        timeout(WAIT, flush).await.unwrap().unwrap(),
+        Err(RaknetError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn receipt_resolves_once_a_split_message_is_acked() {
+    let mut pair = Pair::connect().await;
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    let receipt = pair
+        .client
+        .send_with_receipt(numbered(0, 5000))
+        .await
+        .unwrap();
+    tokio::pin!(receipt);
+    assert!(
+        timeout(Duration::from_millis(500), &mut receipt)
+            .await
+            .is_err()
+    );
+
+    pair.uplink(SimulatedLink::new());
+    timeout(WAIT, receipt).await.unwrap().unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+
+    // Unreliable messages are sent reliably so they can be ACKed.
+    let unreliable = numbered(1, 100).reliability(Reliability::Unreliable);
+    let receipt = pair.server.send_with_receipt(unreliable).await.unwrap();
+    timeout(WAIT, receipt).await.unwrap().unwrap();
+    assert_eq!(number_of(&recv(&mut pair.client).await), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unreliable_receipt_reports_loss_without_resending() {
+    let mut pair = Pair::connect().await;
+    let telemetry = |i| numbered(i, 100).reliability(Reliability::UnreliableWithAckReceipt);
+
+    let receipt = pair.client.send_with_receipt(telemetry(0)).await.unwrap();
+    timeout(WAIT, receipt).await.unwrap().unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 0);
+
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    let receipt = pair.client.send_with_receipt(telemetry(1)).await.unwrap();
+    assert!(matches!(
+        timeout(WAIT, receipt).await.unwrap(),
+        Err(RaknetError::MessageLost)
+    ));
+
+    // Lost for good: it is not resent once the link recovers.
+    pair.uplink(SimulatedLink::new());
+    pair.client.send(numbered(2, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 2);
+    assert!(pair.server.try_recv().unwrap().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn receipt_of_a_send_the_session_turns_away_resolves() {
+    let client_config = RaknetStreamConfig {
+        max_ordering_channels: 1,
+        ..Default::default()
+    };
+    let mut pair = Pair::connect_with(
+        SimulatedLink::new(),
+        RaknetListenerConfig::default(),
+        client_config,
+    )
+    .await;
+
+    // Valid on the wire, but beyond the channels this session has.
+    let receipt = pair
+        .client
+        .send_with_receipt(numbered(0, 100).channel(5))
+        .await
+        .unwrap();
+    assert!(matches!(
+        timeout(WAIT, receipt).await.expect("receipt resolves"),
+        Err(RaknetError::MessageLost)
+    ));
+    assert!(pair.client.is_connected());
+
+    pair.client.send(numbered(1, 100)).await.unwrap();
+    assert_eq!(number_of(&recv(&mut pair.server).await), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn receipt_fails_with_the_disconnect_reason() {
+    let pair = Pair::connect().await;
+    pair.uplink(SimulatedLink::new().loss(1.0));
+    let receipt = pair
+        .client
+        .send_with_receipt(numbered(0, 100))
+        .await
+        .unwrap();
+    let receipt = tokio::spawn(receipt);
+    settle().await;
+    assert!(!receipt.is_finished());
+
+    pair.listener
+        .disconnect(pair.client.local_addr(), DisconnectReason::ShuttingDown)
+        .await
+        .unwrap();
+    assert!(matches!(
+        timeout(WAIT, receipt).await.unwrap().unwrap(),
+        Err(RaknetError::Disconnected(DisconnectReason::ShuttingDown))
+    ));
+}
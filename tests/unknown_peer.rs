@@ -0,0 +1,37 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{RawPeer, data_datagram};
+use tokio_raknet::RaknetListener;
+
+#[tokio::test]
+async fn data_datagram_without_handshake_creates_no_session() {
+    let listener = RaknetListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let peer = RawPeer::new(listener.local_addr()).await;
+
+    // Well-formed VALID datagram; the muxer decodes it fine but must not
+    // treat it as a reason to open a session.
+    peer.send_raw(&data_datagram(0, Bytes::from_static(&[0x86, 1, 2, 3])))
+        .await;
+
+    for _ in 0..20 {
+        if listener.stats().datagrams_received == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(listener.stats().datagrams_received, 1);
+
+    // No ACK, no session.
+    assert!(peer.recv_raw(Duration::from_millis(200)).await.is_none());
+    assert_eq!(listener.stats().sessions, 0);
+    assert!(
+        listener
+            .peer_stats(peer.socket.local_addr().unwrap())
+            .is_none()
+    );
+}
@@ -0,0 +1,247 @@
+//! Golden tests against the packets of a vanilla handshake.
+//!
+//! Decoding checks that every captured packet parses into the values it
+//! carries; encoding checks that the packets a server sends come out byte
+//! for byte the same given those values. A wire change that breaks vanilla
+//! clients fails here first.
+
+mod common;
+
+use bytes::{Bytes, BytesMut};
+use common::vanilla::{self, *};
+use tokio_raknet::protocol::constants::{
+    DEFAULT_UNCONNECTED_MAGIC, DatagramFlags, RAKNET_PROTOCOL_VERSION,
+};
+use tokio_raknet::protocol::datagram::{Datagram, DatagramPayload};
+use tokio_raknet::protocol::encapsulated_packet::EncapsulatedPacket;
+use tokio_raknet::protocol::packet::{
+    ConnectionRequestAccepted, OpenConnectionReply1, OpenConnectionReply2, RaknetPacket,
+    UnconnectedPong,
+};
+use tokio_raknet::protocol::reliability::Reliability;
+use tokio_raknet::protocol::types::{
+    Advertisement, DatagramHeader, EncapsulatedPacketHeader, RaknetTime, Sequence24,
+};
+
+fn decode(bytes: &[u8]) -> RaknetPacket {
+    let mut slice = bytes;
+    let pkt = RaknetPacket::decode(&mut slice).expect("packet should decode");
+    assert!(slice.is_empty(), "{} bytes left over", slice.len());
+    pkt
+}
+
+/// Vanilla pings end with the client GUID, which we do not read.
+fn decode_prefix(bytes: &[u8]) -> RaknetPacket {
+    RaknetPacket::decode(&mut &bytes[..]).expect("packet should decode")
+}
+
+/// The single frame of a captured datagram, decoded.
+fn decode_datagram(bytes: &[u8]) -> (Datagram, RaknetPacket) {
+    let mut slice = bytes;
+    let dgram = Datagram::decode(&mut slice).expect("datagram should decode");
+    let DatagramPayload::EncapsulatedPackets(frames) = &dgram.payload else {
+        panic!("expected a data datagram");
+    };
+    assert_eq!(frames.len(), 1);
+    let pkt = decode(&frames[0].payload);
+    (dgram, pkt)
+}
+
+fn encode(pkt: RaknetPacket) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    pkt.encode(&mut buf).unwrap();
+    buf.to_vec()
+}
+
+/// A datagram like the captured ones: first in the stream, one frame.
+fn encode_datagram(sequence: u32, frame: EncapsulatedPacket) -> Vec<u8> {
+    let dgram = Datagram {
+        header: DatagramHeader {
+            flags: DatagramFlags::VALID | DatagramFlags::HAS_B_AND_AS,
+            sequence: Sequence24::new(sequence),
+        },
+        payload: DatagramPayload::EncapsulatedPackets(vec![frame]),
+    };
+    let mut buf = BytesMut::new();
+    dgram.encode(&mut buf).unwrap();
+    buf.to_vec()
+}
+
+fn ordered_frame(pkt: RaknetPacket) -> EncapsulatedPacket {
+    let payload = Bytes::from(encode(pkt));
+    EncapsulatedPacket {
+        header: EncapsulatedPacketHeader {
+            reliability: Reliability::ReliableOrdered,
+            is_split: false,
+            needs_bas: false,
+        },
+        bit_length: (payload.len() as u16) << 3,
+        reliable_index: Some(Sequence24::new(0)),
+        sequence_index: None,
+        ordering_index: Some(Sequence24::new(0)),
+        ordering_channel: Some(0),
+        split: None,
+        payload,
+    }
+}
+
+fn pong() -> UnconnectedPong {
+    UnconnectedPong {
+        ping_time: RaknetTime(PING_TIME),
+        server_guid: SERVER_GUID,
+        magic: DEFAULT_UNCONNECTED_MAGIC,
+        advertisement: Advertisement(Some(Bytes::from_static(MOTD))),
+    }
+}
+
+fn connection_request_accepted() -> ConnectionRequestAccepted {
+    ConnectionRequestAccepted {
+        address: CLIENT,
+        system_index: 0,
+        system_addresses: system_addresses(SERVER),
+        request_timestamp: RaknetTime(REQUEST_TIME),
+        accepted_timestamp: RaknetTime(ACCEPTED_TIME),
+    }
+}
+
+#[test]
+fn decodes_ping_and_pong() {
+    let RaknetPacket::UnconnectedPing(ping) = decode_prefix(&vanilla::unconnected_ping()) else {
+        panic!("expected UnconnectedPing");
+    };
+    assert_eq!(ping.ping_time.0, PING_TIME);
+    assert_eq!(ping.magic, DEFAULT_UNCONNECTED_MAGIC);
+
+    let RaknetPacket::UnconnectedPong(pong) = decode(&vanilla::unconnected_pong()) else {
+        panic!("expected UnconnectedPong");
+    };
+    assert_eq!(pong.ping_time.0, PING_TIME);
+    assert_eq!(pong.server_guid, SERVER_GUID);
+    assert_eq!(pong.advertisement.0.as_deref(), Some(MOTD));
+}
+
+#[test]
+fn decodes_offline_handshake() {
+    let RaknetPacket::OpenConnectionRequest1(req1) = decode(&vanilla::open_connection_request_1())
+    else {
+        panic!("expected OpenConnectionRequest1");
+    };
+    assert_eq!(req1.protocol_version, RAKNET_PROTOCOL_VERSION);
+    // The padding plus 1 + 16 + 1 header bytes and 28 of IPv4/UDP is the MTU.
+    assert_eq!(req1.padding.0 + 18 + 28, MTU as usize);
+
+    let RaknetPacket::OpenConnectionReply1(reply1) = decode(&vanilla::open_connection_reply_1())
+    else {
+        panic!("expected OpenConnectionReply1");
+    };
+    assert_eq!(reply1.server_guid, SERVER_GUID);
+    assert_eq!(reply1.cookie, None);
+    assert_eq!(reply1.mtu, MTU);
+
+    let RaknetPacket::OpenConnectionRequest2(req2) = decode(&vanilla::open_connection_request_2())
+    else {
+        panic!("expected OpenConnectionRequest2");
+    };
+    assert_eq!(req2.cookie, None);
+    assert_eq!(req2.server_addr, SERVER);
+    assert_eq!(req2.mtu, MTU);
+    assert_eq!(req2.client_guid, CLIENT_GUID);
+
+    let RaknetPacket::OpenConnectionReply2(reply2) = decode(&vanilla::open_connection_reply_2())
+    else {
+        panic!("expected OpenConnectionReply2");
+    };
+    assert_eq!(reply2.server_guid, SERVER_GUID);
+    assert_eq!(reply2.server_addr, CLIENT);
+    assert_eq!(reply2.mtu, MTU);
+    assert!(!reply2.security);
+
+    let RaknetPacket::OpenConnectionReply2(reply2) = decode(&vanilla::open_connection_reply_2_v6())
+    else {
+        panic!("expected OpenConnectionReply2");
+    };
+    assert_eq!(reply2.server_addr, CLIENT_V6);
+}
+
+#[test]
+fn decodes_online_handshake() {
+    let (dgram, pkt) = decode_datagram(&vanilla::connection_request());
+    assert_eq!(dgram.header.sequence.value(), 0);
+    let RaknetPacket::ConnectionRequest(req) = pkt else {
+        panic!("expected ConnectionRequest");
+    };
+    assert_eq!(req.client_guid, CLIENT_GUID);
+    assert_eq!(req.timestamp.0, REQUEST_TIME);
+    assert!(!req.secure);
+
+    let (dgram, pkt) = decode_datagram(&vanilla::connection_request_accepted());
+    let DatagramPayload::EncapsulatedPackets(frames) = &dgram.payload else {
+        unreachable!()
+    };
+    assert_eq!(frames[0].header.reliability, Reliability::ReliableOrdered);
+    assert_eq!(frames[0].ordering_channel, Some(0));
+    let RaknetPacket::ConnectionRequestAccepted(acc) = pkt else {
+        panic!("expected ConnectionRequestAccepted");
+    };
+    assert_eq!(acc.address, CLIENT);
+    assert_eq!(acc.system_index, 0);
+    assert_eq!(acc.system_addresses, system_addresses(SERVER));
+    assert_eq!(acc.request_timestamp.0, REQUEST_TIME);
+    assert_eq!(acc.accepted_timestamp.0, ACCEPTED_TIME);
+
+    let (dgram, pkt) = decode_datagram(&vanilla::new_incoming_connection());
+    assert_eq!(dgram.header.sequence.value(), 1);
+    let RaknetPacket::NewIncomingConnection(nic) = pkt else {
+        panic!("expected NewIncomingConnection");
+    };
+    assert_eq!(nic.server_address, SERVER);
+    assert_eq!(nic.system_addresses, system_addresses(CLIENT));
+    assert_eq!(nic.request_timestamp.0, ACCEPTED_TIME);
+    assert_eq!(nic.accepted_timestamp.0, NEW_INCOMING_TIME);
+}
+
+#[test]
+fn encodes_server_packets_byte_for_byte() {
+    assert_eq!(
+        encode(RaknetPacket::UnconnectedPong(pong())),
+        vanilla::unconnected_pong()
+    );
+    assert_eq!(
+        encode(RaknetPacket::OpenConnectionReply1(OpenConnectionReply1 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: SERVER_GUID,
+            cookie: None,
+            mtu: MTU,
+        })),
+        vanilla::open_connection_reply_1()
+    );
+    assert_eq!(
+        encode(RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: SERVER_GUID,
+            server_addr: CLIENT,
+            mtu: MTU,
+            security: false,
+        })),
+        vanilla::open_connection_reply_2()
+    );
+    assert_eq!(
+        encode(RaknetPacket::OpenConnectionReply2(OpenConnectionReply2 {
+            magic: DEFAULT_UNCONNECTED_MAGIC,
+            server_guid: SERVER_GUID,
+            server_addr: CLIENT_V6,
+            mtu: MTU,
+            security: false,
+        })),
+        vanilla::open_connection_reply_2_v6()
+    );
+    assert_eq!(
+        encode_datagram(
+            0,
+            ordered_frame(RaknetPacket::ConnectionRequestAccepted(
+                connection_request_accepted()
+            ))
+        ),
+        vanilla::connection_request_accepted()
+    );
+}